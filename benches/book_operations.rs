@@ -0,0 +1,158 @@
+//! Criterion benchmarks of the core book operations.
+//!
+//! Usage: `cargo bench --bench book_operations`
+//!
+//! Books are built before each measurement starts, from fixed seeds, so runs compare like for like.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use optimized_lob::{
+    matching::MatchingEngine,
+    order::OrderId,
+    orderbook_manager::OrderBookManager,
+    quantity::Qty,
+    utils::BookId,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+const SEED: u64 = 1589;
+const RESTING_ORDERS: u64 = 100_000;
+
+/// Rests `count` signed orders on book 0, over 200 levels a side around 1000
+fn deep_book(count: u64) -> OrderBookManager {
+    let mut manager = OrderBookManager::new();
+    manager.create_book(BookId(0)).unwrap();
+    let mut rng = StdRng::seed_from_u64(SEED);
+    for order_id in 0..count {
+        let (price, is_bid) = random_price(&mut rng);
+        add(&mut manager, order_id, price, is_bid, rng.gen_range(1..=100));
+    }
+    manager
+}
+
+/// Picks a side, and a price on it that doesn't cross the other
+fn random_price(rng: &mut StdRng) -> (u32, bool) {
+    let is_bid = rng.gen_bool(0.5);
+    let price = if is_bid { rng.gen_range(800..1000) } else { rng.gen_range(1001..1201) };
+    (price, is_bid)
+}
+
+fn add(manager: &mut OrderBookManager, order_id: u64, price: u32, is_bid: bool, qty: u64) {
+    manager
+        .add_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, Some([1; 20]), Some(order_id), Some(u64::MAX), [2; 65])
+        .unwrap();
+}
+
+/// Rests one 10 lot ask at each of `levels` levels from 1001 up, for a bid to sweep
+fn rest_ask_ladder(engine: &mut MatchingEngine, levels: u32) {
+    for level in 0..levels {
+        let order_id = engine.next_order_id().0;
+        add(&mut engine.orderbook_manager, order_id, 1001 + level, false, 10);
+    }
+}
+
+fn add_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_order");
+
+    // The book is created up front, and each order is cancelled untimed to leave it empty again
+    let mut manager = OrderBookManager::new();
+    manager.create_book(BookId(0)).unwrap();
+    group.bench_function("empty_book", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for order_id in 0..iters {
+                let start = Instant::now();
+                add(&mut manager, order_id, 1000, true, 10);
+                elapsed += start.elapsed();
+                manager.cancel_order(OrderId(order_id), Qty(10)).unwrap();
+            }
+            elapsed
+        });
+    });
+
+    // Each measured batch rests new orders among the existing levels, then takes them back off untimed
+    let mut manager = deep_book(RESTING_ORDERS);
+    let mut rng = StdRng::seed_from_u64(SEED + 1);
+    group.bench_function("book_with_100k_orders", |b| {
+        b.iter_custom(|iters| {
+            let order_ids = RESTING_ORDERS..RESTING_ORDERS + iters;
+            let prices: Vec<(u32, bool)> = order_ids.clone().map(|_| random_price(&mut rng)).collect();
+            let start = Instant::now();
+            for (order_id, &(price, is_bid)) in order_ids.clone().zip(&prices) {
+                add(&mut manager, order_id, price, is_bid, 10);
+            }
+            let elapsed = start.elapsed();
+            for order_id in order_ids {
+                manager.cancel_order(OrderId(order_id), Qty(10)).unwrap();
+            }
+            elapsed
+        });
+    });
+    group.finish();
+}
+
+/// Times `operation` on `iters` orders added to a book of 100k orders just before, untimed
+fn time_on_added(manager: &mut OrderBookManager, iters: u64, operation: fn(&mut OrderBookManager, OrderId)) -> Duration {
+    let order_ids = RESTING_ORDERS..RESTING_ORDERS + iters;
+    for order_id in order_ids.clone() {
+        add(manager, order_id, 900 + (order_id % 100) as u32, true, 10);
+    }
+    let start = Instant::now();
+    for order_id in order_ids {
+        operation(manager, OrderId(order_id));
+    }
+    start.elapsed()
+}
+
+fn cancel_order(c: &mut Criterion) {
+    let mut manager = deep_book(RESTING_ORDERS);
+    c.bench_function("cancel_order", |b| {
+        b.iter_custom(|iters| {
+            time_on_added(&mut manager, iters, |manager, order_id| manager.cancel_order(order_id, Qty(10)).unwrap())
+        });
+    });
+}
+
+fn execute_order(c: &mut Criterion) {
+    let mut manager = deep_book(RESTING_ORDERS);
+    c.bench_function("execute_order", |b| {
+        b.iter_custom(|iters| {
+            time_on_added(&mut manager, iters, |manager, order_id| {
+                black_box(manager.execute_order(order_id, Qty(10)).unwrap());
+            })
+        });
+    });
+}
+
+fn match_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_order_sweep");
+    let mut engine = MatchingEngine::new();
+    engine.orderbook_manager.create_book(BookId(0)).unwrap();
+    engine.set_book_only(BookId(0), true);
+    for levels in [1, 10, 100] {
+        // The ladder is rested again, untimed, before each bid sweeps it
+        group.bench_with_input(BenchmarkId::from_parameter(levels), &levels, |b, &levels| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    rest_ask_ladder(&mut engine, levels);
+                    let (order_id, qty, price) = (engine.next_order_id(), Qty(10 * levels as u64), 1000 + levels);
+                    let start = Instant::now();
+                    let matched = engine.match_order(order_id, BookId(0), qty, price, true, Some([3; 20]), Some(0), Some(u64::MAX), [4; 65]);
+                    elapsed += start.elapsed();
+                    assert_eq!(black_box(matched).map(|(remaining, _)| remaining), Ok(Qty(0)));
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+fn get_depth(c: &mut Criterion) {
+    let manager = deep_book(RESTING_ORDERS);
+    c.bench_function("get_depth_50", |b| b.iter(|| black_box(manager.get_depth(BookId(0), 50))));
+}
+
+criterion_group!(benches, add_order, cancel_order, execute_order, match_order, get_depth);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+// build.rs
+
+/// Generates the gRPC service of proto/numena.proto, with the protoc vendored by protoc-bin-vendored
+#[cfg(feature = "grpc")]
+fn main() {
+    const PROTO: &str = "optimized-lob/proto/numena.proto";
+    println!("cargo:rerun-if-changed={}", PROTO);
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+    std::env::set_var("PROTOC", protoc);
+    tonic_build::configure()
+        .compile_protos(&[PROTO], &["optimized-lob/proto"])
+        .expect("failed to compile the gRPC protocol");
+}
+
+#[cfg(not(feature = "grpc"))]
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,368 @@
+// abi.rs
+
+use crate::{
+    settlement_batcher::NetTransfer,
+    translator::{SettlementOrder, SettlementSignature},
+};
+use alloy_primitives::{Address, Bytes, B256, U256};
+use alloy_sol_types::{sol, SolCall};
+use sha3::{Digest, Keccak256};
+
+sol! {
+    /// A settlement as the settlement contract takes it, field for field in SettlementOrder order.
+    /// Amounts are uint128, as in 0x limit orders; salts are widened to uint256.
+    struct LimitOrder {
+        address makerToken;
+        address takerToken;
+        uint128 makerAmount;
+        uint128 takerAmount;
+        uint128 feeAmount;
+        address maker;
+        address taker;
+        address feeRecipient;
+        address pool;
+        uint64 makerExpiry;
+        uint256 makerSalt;
+        uint64 takerExpiry;
+        uint256 takerSalt;
+        bool makerIsBuyer;
+    }
+
+    struct Signature {
+        uint8 signatureType;
+        uint8 v;
+        bytes32 r;
+        bytes32 s;
+    }
+
+    /// Trading fees withheld from what each side receives.
+    struct Fees {
+        uint128 makerFee;
+        uint128 takerFee;
+    }
+
+    /// One netted token movement of a batch.
+    struct Transfer {
+        address token;
+        address from;
+        address to;
+        uint256 amount;
+    }
+
+    function settle(LimitOrder order, Signature makerSignature, Signature takerSignature);
+
+    /// Settles an order whose maker or taker pays a fee.
+    function settleWithFees(LimitOrder order, Signature makerSignature, Signature takerSignature, Fees fees);
+
+    /// Settles several orders at once: the signatures authorize every order, and only the
+    /// netted transfers move tokens.
+    function settleBatch(
+        LimitOrder[] orders,
+        Signature[] makerSignatures,
+        Signature[] takerSignatures,
+        Transfer[] transfers
+    );
+
+    function multicall(bytes[] data) returns (bytes[] results);
+}
+
+/// Canonical signature of the settlement function, as its selector is derived from.
+pub const SETTLE_SIGNATURE: &str = "settle((address,address,uint128,uint128,uint128,address,address,address,address,uint64,uint256,uint64,uint256,bool),(uint8,uint8,bytes32,bytes32),(uint8,uint8,bytes32,bytes32))";
+
+/// Selector of the default settlement function, `settle` above.
+pub const SETTLE_SELECTOR: [u8; 4] = settleCall::SELECTOR;
+
+/// Canonical signature of the settlement function for orders with fees.
+pub const SETTLE_WITH_FEES_SIGNATURE: &str = "settleWithFees((address,address,uint128,uint128,uint128,address,address,address,address,uint64,uint256,uint64,uint256,bool),(uint8,uint8,bytes32,bytes32),(uint8,uint8,bytes32,bytes32),(uint128,uint128))";
+
+/// Selector of the default settlement function for orders with fees, `settleWithFees` above.
+pub const SETTLE_WITH_FEES_SELECTOR: [u8; 4] = settleWithFeesCall::SELECTOR;
+
+/// Canonical signature of the netted batch settlement function.
+pub const SETTLE_BATCH_SIGNATURE: &str = "settleBatch((address,address,uint128,uint128,uint128,address,address,address,address,uint64,uint256,uint64,uint256,bool)[],(uint8,uint8,bytes32,bytes32)[],(uint8,uint8,bytes32,bytes32)[],(address,address,address,uint256)[])";
+
+/// Selector of the default batch settlement function, `settleBatch` above.
+pub const SETTLE_BATCH_SELECTOR: [u8; 4] = settleBatchCall::SELECTOR;
+
+impl From<&SettlementOrder> for LimitOrder {
+    fn from(order: &SettlementOrder) -> Self {
+        Self {
+            makerToken: Address::from(order.maker_token),
+            takerToken: Address::from(order.taker_token),
+            makerAmount: order.maker_amount,
+            takerAmount: order.taker_amount,
+            feeAmount: order.fee_amount,
+            maker: Address::from(order.maker),
+            taker: Address::from(order.taker),
+            feeRecipient: Address::from(order.fee_recipient),
+            pool: Address::from(order.pool),
+            makerExpiry: order.maker_expiration,
+            makerSalt: U256::from(order.maker_salt),
+            takerExpiry: order.taker_expiration,
+            takerSalt: U256::from(order.taker_salt),
+            makerIsBuyer: order.maker_is_buyer,
+        }
+    }
+}
+
+impl From<&NetTransfer> for Transfer {
+    fn from(transfer: &NetTransfer) -> Self {
+        Self {
+            token: Address::from(transfer.token),
+            from: Address::from(transfer.from),
+            to: Address::from(transfer.to),
+            amount: U256::from(transfer.amount),
+        }
+    }
+}
+
+impl From<&SettlementSignature> for Signature {
+    fn from(signature: &SettlementSignature) -> Self {
+        Self {
+            signatureType: signature.signature_type,
+            v: signature.v,
+            r: B256::from(signature.r),
+            s: B256::from(signature.s),
+        }
+    }
+}
+
+/// Computes the selector of a function from its canonical signature.
+///
+/// ## Arguments:
+/// - `signature`: The function name and its argument types, without spaces or names.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::abi::function_selector;
+/// assert_eq!(function_selector("transfer(address,uint256)"), [0xa9, 0x05, 0x9c, 0xbb]);
+/// ```
+pub fn function_selector(signature: &str) -> [u8; 4] {
+    let hash = Keccak256::digest(signature);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Encodes the calldata settling one order: `selector` followed by the order and both signatures.
+/// The arguments are laid out as `settle` takes them; `selector` lets a contract expose the same
+/// arguments under another name. Fees are left out, see encode_settlement_with_fees.
+///
+/// ## Arguments:
+/// - `order`: The translated settlement.
+/// - `selector`: The function selector, e.g. SETTLE_SELECTOR.
+pub fn encode_settlement(order: &SettlementOrder, selector: [u8; 4]) -> Vec<u8> {
+    let call = settleCall {
+        order: LimitOrder::from(order),
+        makerSignature: Signature::from(&order.maker_signature),
+        takerSignature: Signature::from(&order.taker_signature),
+    };
+    let mut data = Vec::with_capacity(4 + call.abi_encoded_size());
+    data.extend_from_slice(&selector);
+    call.abi_encode_raw(&mut data);
+    data
+}
+
+/// Encodes the calldata settling one order with its fees, laid out as `settleWithFees` takes them.
+///
+/// ## Arguments:
+/// - `order`: The translated settlement.
+/// - `selector`: The function selector, e.g. SETTLE_WITH_FEES_SELECTOR.
+pub fn encode_settlement_with_fees(order: &SettlementOrder, selector: [u8; 4]) -> Vec<u8> {
+    let call = settleWithFeesCall {
+        order: LimitOrder::from(order),
+        makerSignature: Signature::from(&order.maker_signature),
+        takerSignature: Signature::from(&order.taker_signature),
+        fees: Fees {
+            makerFee: order.maker_fee,
+            takerFee: order.taker_fee,
+        },
+    };
+    let mut data = Vec::with_capacity(4 + call.abi_encoded_size());
+    data.extend_from_slice(&selector);
+    call.abi_encode_raw(&mut data);
+    data
+}
+
+/// Encodes a `multicall(bytes[])` that settles every order in one transaction.
+/// Each element is the calldata encode_settlement gives for the order and `selector`, so the
+/// orders must not have fees.
+pub fn encode_settlement_batch(orders: &[SettlementOrder], selector: [u8; 4]) -> Vec<u8> {
+    multicallCall {
+        data: orders
+            .iter()
+            .map(|order| Bytes::from(encode_settlement(order, selector)))
+            .collect(),
+    }
+    .abi_encode()
+}
+
+/// Encodes a netted batch: `selector` followed by the orders, their signatures, and the
+/// transfers that settle all of them, laid out as `settleBatch` takes them.
+///
+/// ## Arguments:
+/// - `orders`: The translated settlements of the batch.
+/// - `transfers`: The netted token movements of the orders, see settlement_batcher::net_transfers.
+/// - `selector`: The function selector, e.g. SETTLE_BATCH_SELECTOR.
+pub fn encode_netted_batch(orders: &[SettlementOrder], transfers: &[NetTransfer], selector: [u8; 4]) -> Vec<u8> {
+    let call = settleBatchCall {
+        orders: orders.iter().map(LimitOrder::from).collect(),
+        makerSignatures: orders.iter().map(|order| Signature::from(&order.maker_signature)).collect(),
+        takerSignatures: orders.iter().map(|order| Signature::from(&order.taker_signature)).collect(),
+        transfers: transfers.iter().map(Transfer::from).collect(),
+    };
+    let mut data = Vec::with_capacity(4 + call.abi_encoded_size());
+    data.extend_from_slice(&selector);
+    call.abi_encode_raw(&mut data);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order() -> SettlementOrder {
+        SettlementOrder {
+            maker_token: [0x11; 20],
+            taker_token: [0x22; 20],
+            maker_amount: 1_000_000_000_000_000_000,
+            taker_amount: u128::MAX,
+            fee_amount: 1,
+            maker_fee: 0,
+            taker_fee: 0,
+            maker: [0x33; 20],
+            taker: [0x44; 20],
+            fee_recipient: [0x55; 20],
+            pool: [0x66; 20],
+            maker_expiration: 1_700_000_000,
+            maker_salt: 42,
+            taker_expiration: 1_700_000_001,
+            taker_salt: 43,
+            maker_is_buyer: true,
+            maker_signature: SettlementSignature { signature_type: 2, v: 27, r: [0xaa; 32], s: [0xbb; 32] },
+            taker_signature: SettlementSignature { signature_type: 2, v: 28, r: [0xcc; 32], s: [0xdd; 32] },
+            chain_id: 1,
+            domain_separator: [0; 32],
+        }
+    }
+
+    /// Splits ABI-encoded data into hex words.
+    fn words(data: &[u8]) -> Vec<String> {
+        data.chunks(32).map(hex::encode).collect()
+    }
+
+    #[test]
+    fn test_selectors() {
+        assert_eq!(SETTLE_SELECTOR, function_selector(SETTLE_SIGNATURE));
+        assert_eq!(SETTLE_BATCH_SELECTOR, function_selector(SETTLE_BATCH_SIGNATURE));
+        assert_eq!(SETTLE_WITH_FEES_SELECTOR, function_selector(SETTLE_WITH_FEES_SIGNATURE));
+        // Well-known selectors
+        assert_eq!(hex::encode(multicallCall::SELECTOR), "ac9650d8");
+    }
+
+    #[test]
+    fn test_encode_settlement() {
+        let data = encode_settlement(&order(), [0xde, 0xad, 0xbe, 0xef]);
+        println!("Calldata: 0x{}", hex::encode(&data));
+        assert_eq!(hex::encode(&data[..4]), "deadbeef");
+
+        // Every member is static, so the tuples are encoded in place, one word per field,
+        // as abi.encodeWithSelector(selector, order, makerSignature, takerSignature) does
+        let address = |byte: &str| format!("{}{}", "00".repeat(12), byte.repeat(20));
+        let uint = |value: &str| format!("{:0>64}", value);
+        let expected = vec![
+            address("11"),
+            address("22"),
+            uint("de0b6b3a7640000"), // makerAmount, 1e18
+            uint(&"ff".repeat(16)), // takerAmount, uint128 max: the high half stays zero
+            uint("1"), // feeAmount
+            address("33"),
+            address("44"),
+            address("55"),
+            address("66"),
+            uint("6553f100"), // makerExpiry
+            uint("2a"), // makerSalt
+            uint("6553f101"), // takerExpiry
+            uint("2b"), // takerSalt
+            uint("1"), // makerIsBuyer
+            uint("2"),
+            uint("1b"),
+            "aa".repeat(32),
+            "bb".repeat(32),
+            uint("2"),
+            uint("1c"),
+            "cc".repeat(32),
+            "dd".repeat(32),
+        ];
+        assert_eq!(words(&data[4..]), expected);
+    }
+
+    #[test]
+    fn test_encode_settlement_with_fees() {
+        let mut order = order();
+        order.maker_fee = 7;
+        order.taker_fee = 9;
+        let data = encode_settlement_with_fees(&order, SETTLE_WITH_FEES_SELECTOR);
+        assert_eq!(data[..4], SETTLE_WITH_FEES_SELECTOR);
+        // The settle arguments, then the fees
+        let settle = encode_settlement(&order, SETTLE_WITH_FEES_SELECTOR);
+        assert_eq!(data[..settle.len()], settle);
+        assert_eq!(words(&data[settle.len()..]), [format!("{:064x}", 7), format!("{:064x}", 9)]);
+    }
+
+    #[test]
+    fn test_encode_settlement_batch() {
+        let mut second = order();
+        second.maker_salt = 44;
+        let orders = [order(), second];
+        let data = encode_settlement_batch(&orders, SETTLE_SELECTOR);
+        assert_eq!(hex::encode(&data[..4]), "ac9650d8");
+
+        // Each element is 4 + 22 * 32 = 708 bytes, padded to 736
+        let uint = |value: usize| format!("{:064x}", value);
+        let words = words(&data[4..]);
+        assert_eq!(words[..4], [uint(0x20), uint(2), uint(0x40), uint(0x40 + 32 + 736)]);
+        assert_eq!(data.len(), 4 + 32 * 4 + 2 * (32 + 736));
+
+        for (idx, order) in orders.iter().enumerate() {
+            // After the selector, the array offset and the array length
+            let start = 4 + 64 + 0x40 + idx * (32 + 736);
+            assert_eq!(hex::encode(&data[start..start + 32]), uint(708));
+            let element = &data[start + 32..start + 32 + 708];
+            assert_eq!(element, encode_settlement(order, SETTLE_SELECTOR));
+            assert!(data[start + 32 + 708..start + 32 + 736].iter().all(|&byte| byte == 0));
+        }
+
+        let decoded = multicallCall::abi_decode(&data).unwrap();
+        assert_eq!(decoded.data.len(), 2);
+        let settle = settleCall::abi_decode(&decoded.data[1]).unwrap();
+        assert_eq!((settle.order.makerSalt, settle.order.takerSalt), (U256::from(44), U256::from(43)));
+    }
+
+    #[test]
+    fn test_encode_netted_batch() {
+        let mut second = order();
+        second.maker_salt = 44;
+        let orders = [order(), second];
+        let transfers = [NetTransfer { token: [0x11; 20], from: [0x33; 20], to: [0x44; 20], amount: 5 }];
+        let data = encode_netted_batch(&orders, &transfers, SETTLE_BATCH_SELECTOR);
+        assert_eq!(data[..4], SETTLE_BATCH_SELECTOR);
+
+        // Four dynamic arrays: offsets, then each array as its length and static elements
+        let uint = |value: usize| format!("{:064x}", value);
+        let words = words(&data[4..]);
+        let orders_at = 4 * 32;
+        let makers_at = orders_at + 32 + 2 * 14 * 32;
+        let takers_at = makers_at + 32 + 2 * 4 * 32;
+        let transfers_at = takers_at + 32 + 2 * 4 * 32;
+        assert_eq!(words[..4], [uint(orders_at), uint(makers_at), uint(takers_at), uint(transfers_at)]);
+        assert_eq!(words[transfers_at / 32], uint(1));
+        assert_eq!(words[transfers_at / 32 + 4], uint(5));
+        assert_eq!(data.len(), 4 + transfers_at + 32 + 4 * 32);
+
+        let decoded = settleBatchCall::abi_decode(&data).unwrap();
+        assert_eq!(decoded.orders.len(), 2);
+        assert_eq!(decoded.orders[1].makerSalt, U256::from(44));
+        assert_eq!(decoded.orders[1].takerExpiry, 1_700_000_001);
+        assert_eq!(decoded.takerSignatures[0].v, 28);
+        assert_eq!(decoded.transfers[0].to, Address::from([0x44; 20]));
+    }
+}
@@ -0,0 +1,365 @@
+// accounting.rs
+
+use crate::{
+    events::{EventSink, OrderBookEvent},
+    market::MarketConfig,
+    risk::notional,
+    trade_tape::Trade,
+    translator::fee_for,
+    utils::{BookId, UtcTime},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+const NANOS_PER_DAY: u64 = 1_440 * NANOS_PER_MINUTE;
+
+/// The rates a market charges its traders, in basis points of the notional traded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeRates {
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+    pub maker_rebate_bps: u16,
+}
+
+impl From<&MarketConfig> for FeeRates {
+    fn from(config: &MarketConfig) -> Self {
+        Self {
+            maker_fee_bps: config.maker_fee_bps,
+            taker_fee_bps: config.taker_fee_bps,
+            maker_rebate_bps: config.maker_rebate_bps,
+        }
+    }
+}
+
+/// Computes the rebate on `amount` at `bps` basis points
+/// Fractions of a unit are rounded down, the other way from fee_for, so neither a fee nor a
+/// rebate is ever in the trader's favour.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::accounting::rebate_for;
+/// assert_eq!(rebate_for(1_000_000, 2), 200);
+/// assert_eq!(rebate_for(4_999, 2), 0); // 0.9998 of a unit
+/// ```
+pub fn rebate_for(amount: u128, bps: u16) -> u128 {
+    amount / 10_000 * u128::from(bps) + amount % 10_000 * u128::from(bps) / 10_000
+}
+
+/// A day of the ledger, counted from 1970-01-01 and written as YYYY-MM-DD
+/// Days start at midnight in the ledger's time zone, a fixed offset from UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Day(pub u32);
+
+impl Day {
+    /// Gets the day `timestamp`, in nanoseconds since the Unix epoch, falls on where clocks are
+    /// `utc_offset_minutes` ahead of UTC
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::accounting::Day;
+    /// let evening = 1_760_738_400_000_000_000; // 2025-10-17 22:00 UTC
+    /// assert_eq!(Day::of(evening, 0).to_string(), "2025-10-17");
+    /// assert_eq!(Day::of(evening, 120).to_string(), "2025-10-18");
+    /// ```
+    pub fn of(timestamp: u64, utc_offset_minutes: i32) -> Self {
+        let offset = i64::from(utc_offset_minutes) * NANOS_PER_MINUTE as i64;
+        let local = timestamp.saturating_add_signed(offset);
+        Self((local / NANOS_PER_DAY) as u32)
+    }
+
+    /// Reads a YYYY-MM-DD date, returning None unless it is a calendar date from 1970 on
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::accounting::Day;
+    /// assert_eq!(Day::parse("1970-01-02"), Some(Day(1)));
+    /// assert!(Day::parse("2025-02-29").is_none());
+    /// assert!(Day::parse("17/10/2025").is_none());
+    /// ```
+    pub fn parse(date: &str) -> Option<Self> {
+        let mut parts = date.splitn(3, '-');
+        let mut part = |len: usize| parts.next().filter(|part| part.len() == len && part.bytes().all(|byte| byte.is_ascii_digit()));
+        let (year, month, day): (u64, u64, u64) = (part(4)?.parse().ok()?, part(2)?.parse().ok()?, part(2)?.parse().ok()?);
+        if year < 1970 || !(1..=12).contains(&month) || day == 0 {
+            return None;
+        }
+        // Days since the epoch from the civil date, after Howard Hinnant's days_from_civil
+        let year_of_march = year - u64::from(month <= 2);
+        let (era, year_of_era) = (year_of_march / 400, year_of_march % 400);
+        let month_index = (month + 9) % 12; // Counted from March
+        let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+        // Days past the end of the month roll into the next one, which the round trip catches
+        let parsed = Self(u32::try_from(days).ok()?);
+        (parsed.to_string() == date).then_some(parsed)
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let date = UtcTime::from_nanos(u64::from(self.0) * NANOS_PER_DAY);
+        write!(f, "{:04}-{:02}-{:02}", date.year, date.month, date.day)
+    }
+}
+
+impl Serialize for Day {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Day {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        Self::parse(&date).ok_or_else(|| serde::de::Error::custom(format!("invalid date {:?}", date)))
+    }
+}
+
+/// What a trader traded and was charged, over a day or a range of them
+/// Volumes are notional, price × quantity in book units, and fees and rebates are in the same units.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeTotals {
+    pub maker_volume: u128,
+    pub taker_volume: u128,
+    pub maker_trades: u64,
+    pub taker_trades: u64,
+    pub fees_paid: u128,
+    pub rebates_earned: u128,
+}
+
+impl VolumeTotals {
+    pub fn add(&mut self, other: &VolumeTotals) {
+        self.maker_volume += other.maker_volume;
+        self.taker_volume += other.taker_volume;
+        self.maker_trades += other.maker_trades;
+        self.taker_trades += other.taker_trades;
+        self.fees_paid += other.fees_paid;
+        self.rebates_earned += other.rebates_earned;
+    }
+}
+
+/// A trader's totals in one book on one day
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DayVolume {
+    pub book_id: BookId,
+    pub day: Day,
+    pub totals: VolumeTotals,
+}
+
+/// A trader's totals in one book, summed over a range of days
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraderVolume {
+    pub trader: [u8; 20],
+    pub book_id: BookId,
+    pub totals: VolumeTotals,
+}
+
+/// Maker and taker volume, fees, and rebates per trader per book per day
+/// Built from trades alone, each charged at the rates of its book, so the same trades give the
+/// same ledger in any order; it is rebuilt from the trade history rather than snapshotted.
+/// Trades in books without rates are counted without fees, and a side without a trader is not
+/// counted at all.
+#[derive(Debug, Default)]
+pub struct VolumeLedger {
+    utc_offset_minutes: i32,
+    rates: HashMap<BookId, FeeRates>,
+    totals: BTreeMap<([u8; 20], BookId, Day), VolumeTotals>,
+}
+
+impl VolumeLedger {
+    /// Creates an empty ledger whose days start at midnight `utc_offset_minutes` ahead of UTC
+    pub fn new(utc_offset_minutes: i32) -> Self {
+        Self { utc_offset_minutes, ..Self::default() }
+    }
+
+    /// Sets the rates trades in a book are charged at from now on
+    pub fn set_rates(&mut self, book_id: BookId, rates: FeeRates) {
+        self.rates.insert(book_id, rates);
+    }
+
+    /// Adds a trade to the totals of its maker and taker
+    /// A trader trading with themself is counted on both sides.
+    pub fn record(&mut self, book_id: BookId, trade: &Trade, (maker_trader, taker_trader): (Option<[u8; 20]>, Option<[u8; 20]>)) {
+        let volume = u128::from(notional(trade.price, trade.qty));
+        let rates = self.rates.get(&book_id).copied().unwrap_or_default();
+        let day = Day::of(trade.timestamp, self.utc_offset_minutes);
+        if let Some(maker) = maker_trader {
+            let totals = self.totals.entry((maker, book_id, day)).or_default();
+            totals.maker_volume += volume;
+            totals.maker_trades += 1;
+            totals.fees_paid += fee_for(volume, rates.maker_fee_bps);
+            totals.rebates_earned += rebate_for(volume, rates.maker_rebate_bps);
+        }
+        if let Some(taker) = taker_trader {
+            let totals = self.totals.entry((taker, book_id, day)).or_default();
+            totals.taker_volume += volume;
+            totals.taker_trades += 1;
+            totals.fees_paid += fee_for(volume, rates.taker_fee_bps);
+        }
+    }
+
+    /// Gets a trader's totals per book per day, from `from` to `to` inclusive, by book and then day
+    pub fn trader_days(&self, trader: [u8; 20], from: Option<Day>, to: Option<Day>) -> Vec<DayVolume> {
+        let (from, to) = (from.unwrap_or(Day(0)), to.unwrap_or(Day(u32::MAX)));
+        self.totals
+            .range((trader, BookId(0), Day(0))..=(trader, BookId(u32::MAX), Day(u32::MAX)))
+            .filter(|((_, _, day), _)| (from..=to).contains(day))
+            .map(|(&(_, book_id, day), totals)| DayVolume { book_id, day, totals: *totals })
+            .collect()
+    }
+
+    /// Gets every trader's totals per book, summed from `from` to `to` inclusive, by trader and then book
+    pub fn roll_up(&self, from: Option<Day>, to: Option<Day>) -> Vec<TraderVolume> {
+        let (from, to) = (from.unwrap_or(Day(0)), to.unwrap_or(Day(u32::MAX)));
+        let mut sums: Vec<TraderVolume> = Vec::new();
+        for (&(trader, book_id, day), totals) in &self.totals {
+            if !(from..=to).contains(&day) {
+                continue;
+            }
+            match sums.last_mut() {
+                Some(sum) if sum.trader == trader && sum.book_id == book_id => sum.totals.add(totals),
+                _ => sums.push(TraderVolume { trader, book_id, totals: *totals }),
+            }
+        }
+        sums
+    }
+}
+
+/// The volume ledger, fed from the engine's event stream
+/// Clones share the same ledger, so a handle can be kept for reading after the sink has been
+/// given to the manager.
+#[derive(Debug, Clone, Default)]
+pub struct FeeLedger {
+    ledger: Arc<Mutex<VolumeLedger>>,
+}
+
+impl FeeLedger {
+    pub fn new(utc_offset_minutes: i32) -> Self {
+        Self { ledger: Arc::new(Mutex::new(VolumeLedger::new(utc_offset_minutes))) }
+    }
+
+    /// Sets the rates of a book from its market
+    pub fn set_market(&self, book_id: BookId, config: &MarketConfig) {
+        self.lock().set_rates(book_id, FeeRates::from(config));
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, VolumeLedger> {
+        self.ledger.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Adds every trade kept in `history` to the ledger, returning how many there were
+    /// Rates must be set first; trades of books whose market is gone are counted without fees.
+    #[cfg(feature = "sqlite")]
+    pub fn rebuild(&self, history: &crate::trade_history::TradeHistory) -> rusqlite::Result<usize> {
+        const PAGE_ROWS: usize = 10_000;
+        let (mut after, mut count) = (None, 0);
+        loop {
+            let page = history.all_trades_after(after, PAGE_ROWS)?;
+            let mut ledger = self.lock();
+            for stored in &page {
+                ledger.record(stored.book_id, &stored.trade, (stored.maker_trader, stored.taker_trader));
+            }
+            count += page.len();
+            match page.last() {
+                Some(last) if page.len() == PAGE_ROWS => after = Some(last.trade.trade_id),
+                _ => return Ok(count),
+            }
+        }
+    }
+}
+
+impl EventSink for FeeLedger {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        if let OrderBookEvent::Trade { book_id, trade, maker_trader, taker_trader, .. } = *event {
+            self.lock().record(book_id, &trade, (maker_trader, taker_trader));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{order::OrderId, quantity::Qty};
+
+    const DAY: u64 = NANOS_PER_DAY;
+
+    #[test]
+    fn test_ledger_replays_trades() {
+        let (maker, taker, other) = ([1; 20], [2; 20], [3; 20]);
+        let trade = |trade_id, timestamp, price, qty| Trade {
+            trade_id,
+            timestamp,
+            price,
+            qty: Qty(qty),
+            aggressor_is_bid: true,
+            maker_order_id: OrderId(trade_id),
+            taker_order_id: OrderId(100 + trade_id),
+        };
+        let event = |book_id, trade, maker_trader, taker_trader| OrderBookEvent::Trade {
+            seq: 0,
+            book_seq: 0,
+            book_id: BookId(book_id),
+            trade,
+            maker_trader: Some(maker_trader),
+            taker_trader: Some(taker_trader),
+            improvement: None,
+        };
+        // Day 20_000 is 2024-10-04; the last trade is at 23:30 UTC, which is the next day at UTC+1
+        let late = 20_000 * DAY + 23 * 60 * NANOS_PER_MINUTE + 30 * NANOS_PER_MINUTE;
+        let events = [
+            event(0, trade(1, 20_000 * DAY, 100, 10), maker, taker), // 1000 notional
+            event(0, trade(2, 20_000 * DAY + 1, 101, 3), maker, taker), // 303
+            event(0, trade(3, 20_001 * DAY, 7, 1), maker, other),    // 7
+            event(1, trade(4, late, 50, 2), taker, maker),           // 100 in a book without fees
+        ];
+        let market = MarketConfig::builder().maker_fee_bps(10).taker_fee_bps(25).maker_rebate_bps(2).build();
+
+        for utc_offset_minutes in [0, 60] {
+            // Any order of the same trades gives the same ledger
+            let mut ledgers = Vec::new();
+            for events in [events.to_vec(), events.iter().rev().copied().collect()] {
+                let mut ledger = FeeLedger::new(utc_offset_minutes);
+                ledger.set_market(BookId(0), &market);
+                for event in &events {
+                    ledger.on_event(event);
+                }
+                ledgers.push(ledger.lock().roll_up(None, None));
+            }
+            assert_eq!(ledgers[0], ledgers[1]);
+        }
+
+        let mut ledger = FeeLedger::new(60);
+        ledger.set_market(BookId(0), &market);
+        events.iter().for_each(|event| ledger.on_event(event));
+        let ledger = ledger.lock();
+        let days = ledger.trader_days(maker, None, None);
+        let days: Vec<(u32, String, VolumeTotals)> =
+            days.into_iter().map(|entry| (entry.book_id.value(), entry.day.to_string(), entry.totals)).collect();
+        assert_eq!(
+            days,
+            vec![
+                // 10 bps of 1000 is 1 and of 303 is 0.303, rounded up to 1; 2 bps of 303 is 0.0606, rounded down
+                (0, "2024-10-04".to_string(), VolumeTotals { maker_volume: 1_303, maker_trades: 2, fees_paid: 2, ..VolumeTotals::default() }),
+                // 0.007 of fee rounds up to 1
+                (0, "2024-10-05".to_string(), VolumeTotals { maker_volume: 7, maker_trades: 1, fees_paid: 1, ..VolumeTotals::default() }),
+                (1, "2024-10-05".to_string(), VolumeTotals { taker_volume: 100, taker_trades: 1, ..VolumeTotals::default() }),
+            ]
+        );
+        // 25 bps of 1000 is 2.5 and of 303 is 0.7575, each rounded up
+        let taker_totals = ledger.roll_up(None, None).into_iter().find(|entry| entry.trader == taker && entry.book_id == BookId(0)).unwrap();
+        assert_eq!((taker_totals.totals.taker_volume, taker_totals.totals.fees_paid), (1_303, 4));
+        assert_eq!(ledger.trader_days(maker, Day::parse("2024-10-05"), None).len(), 2);
+        assert_eq!(ledger.trader_days(maker, None, Day::parse("2024-10-04")).len(), 1);
+
+        // Rebates come to whole units once the notional is large enough
+        let mut ledger = VolumeLedger::new(0);
+        ledger.set_rates(BookId(0), FeeRates::from(&market));
+        ledger.record(BookId(0), &trade(5, 0, 4_999, 20), (Some(maker), None));
+        let totals = ledger.roll_up(None, None)[0].totals;
+        assert_eq!((totals.maker_volume, totals.fees_paid, totals.rebates_earned), (99_980, 100, 19));
+    }
+}
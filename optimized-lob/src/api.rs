@@ -1,301 +1,7022 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Result};
+use actix_web::{
+    body::{BodySize, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, ContentType},
+    middleware::Next,
+    web, App, Error, HttpRequest, HttpResponse, HttpServer, Result,
+};
+use actix_ws::{CloseCode, CloseReason, Message};
 use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::collections::HashMap;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, MutexGuard, RwLock};
 
 use crate::{
-    order_intake::{OrderIntake, OrderSubmission},
-    book_registry::{BookRegistry, BookRegistryError},
-    orderbook::OrderBook,
-    level::PriceLevel,
+    accounting::{Day, FeeLedger, VolumeTotals},
+    api_audit::audit_commands,
+    api_auth::{authenticate, Authenticator, Identity},
+    api_error::ApiError,
+    api_idempotency::{fingerprint, IdempotencyCache, IdempotencyKey, Lookup, IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAY_HEADER},
+    auction::Uncross,
+    audit::{AuditEntry, AuditFilter, AuditLog},
+    auth::{parse_address, verify_signer},
+    client_order_ids::ClientOrderId,
+    command_queue::{CommandQueue, Lane},
+    eip1271::ContractSignatureVerifier,
+    engine::provision_book,
+    events::{EventSink, FanoutSink},
+    export::{settlement_row, trade_row, CsvWriter, SETTLEMENT_COLUMNS, TRADE_COLUMNS},
+    funds::FundsChecker,
+    order_intake::{parse_trader, OrderIntake, OrderSubmission, Side, Verification, VerifiedOrder},
+    order_updates::{OrderStatus, OrderUpdate},
+    book_registry::BookRegistry,
+    candles::{Candle, CandleInterval},
+    config::Config,
+    level::LevelId,
+    market::{MarketConfig, PriceBand, RiskLimits, SizeRules, SIGNATURE_TYPE_EIP1271, SIGNATURE_TYPE_EIP712},
+    market_data::MarketDataEvent,
+    matching::{MatchDetails, MatchingEngine},
+    metrics::Metrics,
+    order::{Order, OrderHandle, OrderId},
+    orderbook_manager::Depth,
+    price::{ApiPrice, PriceScale},
+    quantity::Qty,
+    risk::{notional, OpenUsage},
+    sequencer::TraderSequencer,
+    settlement_manager::TrackedSettlement,
+    stops::StopOrder,
+    pegs::{Peg, PeggedOrder},
+    oco::{OcoLeg, OcoPolicy},
+    mass_quote::{QuoteEntry, QuoteSide},
+    rfq::{BlockOrder, BlockTrade, Quote, Rfq, RfqNotice, RfqState},
+    settlement_submitter::SettlementSubmitter,
+    trade_tape::{PriceImprovement, Trade},
+    utils::{BookId, Clock, CANDLE_HISTORY_CAPACITY, EXPIRY_POLL_INTERVAL, MAX_CLIENT_ORDER_ID_LEN, MAX_MASS_QUOTE_ENTRIES},
+    wal::WalCommand,
 };
+#[cfg(feature = "nats")]
+use crate::bus::{BusSink, NatsPublisher};
+#[cfg(feature = "fix")]
+use crate::fix_gateway::{self, FixGateway, FixSessions};
+#[cfg(feature = "grpc")]
+use crate::grpc::{self, GrpcService};
+#[cfg(feature = "sqlite")]
+use crate::trade_history::{HistoryRange, TradeHistory};
 
 /// API request structure that matches frontend order submission format
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct OrderRequest {
-    book_id: String,
-    price: i32,
-    quantity: u32,
+    pub(crate) book_id: String,
+    /// Book units, signed as they are, or a decimal string in the market's decimal places; negative for asks
+    pub(crate) price: ApiPrice,
+    /// Gives the side apart from the price, which must then be positive; a sell is signed with it negated
+    #[serde(default)]
+    pub(crate) side: Option<Side>,
+    pub(crate) quantity: u64,
+    pub(crate) trader: String,
+    pub(crate) nonce: u64,
+    /// Seconds since the Unix epoch; left out, the order is signed with NO_EXPIRY and never expires
+    pub(crate) expiry: Option<u64>,
+    /// Required on submissions; a preview can go without
+    #[serde(default)]
+    pub(crate) signature: String,
+    #[serde(default)]
+    pub(crate) order_type: OrderType,
+    /// Trade price that fires a stop; required on stop orders and not covered by the signature
+    #[serde(default)]
+    pub(crate) trigger_price: Option<ApiPrice>,
+    /// Most of the order shown in the book at once, making it an iceberg; not covered by the signature
+    #[serde(default)]
+    pub(crate) display_quantity: Option<u64>,
+    /// Ticks a primary peg sits from the best price on its side; not covered by the signature
+    #[serde(default)]
+    pub(crate) peg_offset: Option<i32>,
+    /// Lets a pegged order execute when it moves, rather than stay off the other side
+    #[serde(default)]
+    pub(crate) allow_cross: bool,
+    /// Holds a limit order to trading down the trader's position; not covered by the signature
+    #[serde(default)]
+    pub(crate) reduce_only: bool,
+    /// The trader's own ID for the order, echoed in its updates; not covered by the signature
+    #[serde(default)]
+    pub(crate) client_order_id: Option<String>,
+}
+
+/// How a submitted order goes in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType {
+    #[default]
+    Limit,
+    Stop,      // Waits for its trigger, then goes in as a market order on the side of the signed price
+    StopLimit, // Waits for its trigger, then goes in as a limit order at the signed price
+    MidpointPeg, // Rests at the midpoint, never past the signed price
+    PrimaryPeg,  // Rests at the best price on its side moved by peg_offset, never past the signed price
+}
+
+/// Two signed orders linked as a one-cancels-other pair
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OcoRequest {
+    orders: [OrderRequest; 2], // Limit, stop, or stop-limit orders in one book, each signed on its own
+    #[serde(default)]
+    on_fill: OcoPolicy,
+    /// Cancelling either order cancels the other too
+    #[serde(default)]
+    cancel_together: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OcoResponse {
+    success: bool,
+    message: String,
+    group_id: Option<u64>,
+    order_ids: Vec<u64>,
+}
+
+/// A market maker's two-sided quotes, each replacing the trader's previous quote in its book
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MassQuoteRequest {
     trader: String,
-    nonce: u64,
-    expiry: Option<u64>,
-    signature: String,
+    quotes: Vec<QuoteEntryRequest>,
+}
+
+/// One book's quote: a signed limit bid, a signed limit ask, or both
+/// An entry with neither takes the trader's quote in the book down.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct QuoteEntryRequest {
+    book_id: String,
+    #[serde(default)]
+    bid: Option<OrderRequest>,
+    #[serde(default)]
+    ask: Option<OrderRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MassQuoteResponse {
+    success: bool, // Every entry went in
+    results: Vec<QuoteEntryResult>,
+}
+
+/// What one entry of a mass quote did, in the order the entries were sent
+/// A refused entry has its error's code and message, and left the trader's previous quote in
+/// its book working.
+#[derive(Serialize, Deserialize)]
+pub struct QuoteEntryResult {
+    book_id: String,
+    success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    bid_order_id: Option<u64>,
+    ask_order_id: Option<u64>,
+    bid_remaining: Option<u64>,
+    ask_remaining: Option<u64>,
+    cancelled: Vec<u64>, // Orders of the trader's previous quote in the book that were still working
+    fills: Vec<FillResponse>,
+}
+
+impl QuoteEntryResult {
+    fn refused(book_id: String, error: &ApiError) -> Self {
+        Self {
+            book_id,
+            success: false,
+            code: Some(error.code()),
+            message: Some(error.to_string()),
+            bid_order_id: None,
+            ask_order_id: None,
+            bid_remaining: None,
+            ask_remaining: None,
+            cancelled: Vec::new(),
+            fills: Vec::new(),
+        }
+    }
+}
+
+/// The OCO pair an order is a leg of
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct OcoLink {
+    group_id: u64,
+    sibling_order_id: u64,
 }
 
 /// API response structure
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct OrderResponse {
-    success: bool,
-    message: String,
-    order_id: Option<u32>,
+    pub(crate) success: bool,
+    pub(crate) message: String,
+    pub(crate) order_id: Option<u64>,
+    /// Handle of the order while it rests, packed by OrderHandle::to_u64; a cancel that
+    /// passes it can't hit a later order that reused the slot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) handle: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<OrderUpdate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) client_order_id: Option<ClientOrderId>,
 }
 
-/// Response types for orderbook data
+/// Status of a working order
+#[derive(Serialize, Deserialize)]
+pub struct OrderStatusResponse {
+    pub(crate) success: bool,
+    pub(crate) message: String,
+    pub(crate) order_id: u64,
+    pub(crate) status: Option<OrderStatus>,
+    pub(crate) remaining_quantity: u64,
+    /// Price the order rests at now; for a pegged order, the one it pegs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) price: Option<ApiPrice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) oco: Option<OcoLink>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) client_order_id: Option<ClientOrderId>,
+    /// When the order arrived, in nanoseconds since the Unix epoch; stops and parked pegs have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) received_at: Option<u64>,
+}
+
+/// How long a trader stream waits for the signed challenge before closing
+const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Messages pushed over a private trader stream
 #[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraderStreamMessage {
+    /// Sent on connect; the client answers with the `personal_sign` signature of `challenge`
+    Challenge { challenge: String },
+    Authenticated,
+    OrderUpdate(OrderUpdate),
+    Rfq(RfqNotice), // An RFQ to quote, as a registered maker, or news of one the trader is in
+}
+
+/// Client answer to the challenge of a trader stream
+#[derive(Deserialize)]
+pub struct TraderStreamAuth {
+    signature: String,
+}
+
+/// Default number of levels per side returned by the orderbook endpoint
+const DEFAULT_DEPTH: usize = 20;
+/// Upper bound on the number of levels per side a client can request
+const MAX_DEPTH: usize = 500;
+
+/// Response types for orderbook data
+#[derive(Serialize, Deserialize)]
 pub struct OrderbookResponse {
-    bids: Vec<PriceLevelResponse>,
-    asks: Vec<PriceLevelResponse>,
+    pub(crate) bids: Vec<PriceLevelResponse>,
+    pub(crate) asks: Vec<PriceLevelResponse>,
+    pub(crate) checksum: u32, // CRC32 of the top 25 levels of each side, see orderbook::checksum_levels
+    pub(crate) seq: u64,      // Sequence number of the book's last change; market data updates follow it
 }
 
-#[derive(Serialize)]
+/// A single aggregated level; prices are always positive, the side is implied by the array
+#[derive(Serialize, Deserialize)]
 pub struct PriceLevelResponse {
-    price: i32,
-    size: u32,
+    pub(crate) price: ApiPrice,
+    pub(crate) size: u64,
+    pub(crate) order_count: u32,
 }
 
-/// Shared state between handlers
-pub struct AppState {
-    order_intake: Arc<Mutex<OrderIntake>>,
-    book_registry: Arc<BookRegistry>,
-    orderbooks: Arc<Mutex<HashMap<String, OrderBook>>>,
+/// First message on a market data socket: the book's depth as of `seq`.
+/// Incremental events with a higher sequence number apply on top of it.
+#[derive(Serialize)]
+pub struct BookSnapshotMessage {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub book_id: u32,
+    pub seq: u64,
+    pub bids: Vec<PriceLevelResponse>,
+    pub asks: Vec<PriceLevelResponse>,
+    pub checksum: u32,
 }
 
-/// Add new request/response structures
-#[derive(Deserialize)]
-pub struct CreateBookRequest {
-    book_id: String,
+/// Top of book; fields for an empty side are null
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BboResponse {
+    bid_price: Option<ApiPrice>,
+    bid_size: Option<u64>,
+    ask_price: Option<ApiPrice>,
+    ask_size: Option<u64>,
+    spread: Option<ApiPrice>,
+    mid_price: Option<f64>,
 }
 
-#[derive(Serialize)]
-pub struct CreateBookResponse {
-    success: bool,
-    message: String,
+/// Default number of trades returned by the trades endpoint
+const DEFAULT_TRADES_LIMIT: usize = 100;
+/// Upper bound on the number of trades a client can request at once
+const MAX_TRADES_LIMIT: usize = 1000;
+/// Default number of candles returned by the candles endpoint
+const DEFAULT_CANDLES_LIMIT: usize = 100;
+/// Rows an export reads from its source at a time, so it holds the engine lock only briefly
+const EXPORT_PAGE_ROWS: usize = 1000;
+/// Chunks of an export that may wait for a slow client before its producer waits too
+const EXPORT_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Query parameters for the trades endpoint; `before` pages backwards by trade id
+#[derive(Deserialize)]
+pub struct TradesQuery {
+    limit: Option<usize>,
+    before: Option<u64>,
 }
 
-#[derive(Serialize)]
-pub struct ListBooksResponse {
-    books: Vec<String>,
+#[derive(Serialize, Deserialize)]
+pub struct TradeResponse {
+    trade_id: u64,
+    timestamp: u64,
+    price: ApiPrice,
+    quantity: u64,
+    side: String, // Aggressor side, "buy" or "sell"
+    maker_order_id: u64,
+    taker_order_id: u64,
 }
 
-/// Add new handler for creating books
-async fn create_book(
-    data: web::Json<CreateBookRequest>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    println!("Creating book: {}", data.book_id);
-    match state.book_registry.register_book(data.book_id.clone()) {
-        Ok(_) => {
-            // Initialize orderbook
-            let mut orderbooks = state.orderbooks.lock().await;
-            orderbooks.insert(data.book_id.clone(), OrderBook::new());
-            println!("Book created successfully: {}", data.book_id);
-            
-            Ok(HttpResponse::Ok().json(CreateBookResponse {
-                success: true,
-                message: "Book created successfully".to_string(),
-            }))
-        }
-        Err(BookRegistryError::BookAlreadyExists) => {
-            println!("Book already exists: {}", data.book_id);
-            Ok(HttpResponse::BadRequest().json(CreateBookResponse {
-                success: false,
-                message: "Book already exists".to_string(),
-            }))
-        }
-        Err(_) => {
-            println!("Failed to create book: {}", data.book_id);
-            Ok(HttpResponse::InternalServerError().json(CreateBookResponse {
-                success: false,
-                message: "Failed to create book".to_string(),
-            }))
+impl TradeResponse {
+    fn new(trade: &Trade, scale: PriceScale) -> Self {
+        Self {
+            trade_id: trade.trade_id,
+            timestamp: trade.timestamp,
+            price: scale.write(trade.price),
+            quantity: trade.qty.value(),
+            side: if trade.aggressor_is_bid { "buy" } else { "sell" }.to_string(),
+            maker_order_id: trade.maker_order_id.0,
+            taker_order_id: trade.taker_order_id.0,
         }
     }
 }
 
-/// Add new handler for listing books
-async fn list_books(state: web::Data<AppState>) -> Result<HttpResponse> {
-    let books = state.book_registry.list_books();
-    Ok(HttpResponse::Ok().json(ListBooksResponse { books }))
+#[derive(Serialize, Deserialize)]
+pub struct TradesResponse {
+    trades: Vec<TradeResponse>,
 }
 
-/// Modify the submit_order handler to skip signature verification
-async fn submit_order(
-    data: web::Json<OrderRequest>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    // First verify the book exists
-    if state.book_registry.get_book_id(&data.book_id).is_err() {
-        return Ok(HttpResponse::BadRequest().json(OrderResponse {
-            success: false,
-            message: "Book does not exist".to_string(),
-            order_id: None,
-        }));
+/// Query parameters for the candles endpoint; `interval` is one of 1m, 5m or 1h, and `fill`
+/// carries the previous close through buckets without trades instead of omitting them
+#[derive(Deserialize)]
+pub struct CandlesQuery {
+    interval: Option<String>,
+    limit: Option<usize>,
+    fill: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CandleResponse {
+    start: u64, // Start of the bucket, in nanoseconds since the epoch
+    open: ApiPrice,
+    high: ApiPrice,
+    low: ApiPrice,
+    close: ApiPrice,
+    volume: u64,
+    trade_count: u64,
+}
+
+impl CandleResponse {
+    fn new(candle: &Candle, scale: PriceScale) -> Self {
+        Self {
+            start: candle.start,
+            open: scale.write(candle.open),
+            high: scale.write(candle.high),
+            low: scale.write(candle.low),
+            close: scale.write(candle.close),
+            volume: candle.volume,
+            trade_count: candle.trade_count,
+        }
     }
+}
 
-    // Convert API request to OrderSubmission
-    let submission = OrderSubmission {
-        book_id: data.book_id.clone(),
-        price: data.price,
-        quantity: data.quantity,
-        trader: data.trader.clone(),
-        nonce: data.nonce,
-        expiry: data.expiry,
-        signature: data.signature.clone(),
-    };
+/// Candles of a book, oldest first
+#[derive(Serialize, Deserialize)]
+pub struct CandlesResponse {
+    interval: String,
+    candles: Vec<CandleResponse>,
+}
 
-    // Process the order submission
-    let order_intake = state.order_intake.lock().await;
-    match order_intake.process_submission(submission) {
-        Ok(mut order) => {
-            let mut orderbooks = state.orderbooks.lock().await;
-            if let Some(book) = orderbooks.get_mut(&data.book_id) {
-                let price = order.price();
-                let qty = order.qty();
-                book.add_order(&mut order, price, qty);
-                println!("Order added to book: {}", data.book_id);
-            }
+/// Rolling trade statistics of a book; every field is null until the book trades
+#[derive(Serialize, Deserialize)]
+pub struct StatsResponse {
+    last_price: Option<ApiPrice>,
+    volume_24h: Option<u64>,
+    high: Option<ApiPrice>,
+    low: Option<ApiPrice>,
+    trade_count: Option<u64>,
+    average_improvement_ticks: Option<f64>, // Over the session's limit order fills
+}
 
-            Ok(HttpResponse::Ok().json(OrderResponse {
-                success: true,
-                message: "Order submitted successfully".to_string(),
-                order_id: Some(0), // You might want to generate a real order ID
-            }))
-        }
-        Err(error) => {
-            Ok(HttpResponse::BadRequest().json(OrderResponse {
-                success: false,
-                message: error.to_string(),
-                order_id: None,
-            }))
+/// Query parameters for the orderbook endpoint
+#[derive(Deserialize)]
+pub struct OrderbookQuery {
+    depth: Option<usize>,
+    group: Option<u32>, // Width of the price buckets levels are merged into, in book units; a multiple of the tick size
+}
+
+/// Query parameters for the fill estimate endpoint; `side` is the taker's, "buy" or "sell"
+#[derive(Deserialize)]
+pub struct EstimateQuery {
+    side: String,
+    qty: u64,
+}
+
+/// Expected fill of a taker order against the book as it stands
+#[derive(Serialize, Deserialize)]
+pub struct EstimateResponse {
+    side: String,
+    requested_quantity: u64,
+    filled_quantity: u64,
+    vwap: Option<f64>,
+    worst_price: Option<ApiPrice>,
+    exhausted: bool, // The book can't fill the full quantity
+    levels: Vec<FillResponse>,
+}
+
+/// What a limit order would do if it were submitted now
+#[derive(Serialize, Deserialize)]
+pub struct PreviewResponse {
+    fills: Vec<PreviewFillResponse>,
+    filled_quantity: u64,
+    average_price: Option<ApiPrice>, // None when nothing would fill
+    remaining_quantity: u64,
+    rests: bool, // What is left would rest in the book
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PreviewFillResponse {
+    maker_order_id: u64,
+    price: ApiPrice,
+    quantity: u64,
+}
+
+/// Asks the registered makers to quote a block of `quantity` on `side` of a book
+/// `expiry` is in seconds since the Unix epoch. Nothing is signed until a quote is accepted.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CreateRfqRequest {
+    book_id: String,
+    side: Side,
+    quantity: u64,
+    trader: String,
+    expiry: u64,
+}
+
+/// Query parameters for listing RFQs; `state` is one of open, quoted, accepted or expired
+#[derive(Deserialize)]
+pub struct RfqsQuery {
+    state: Option<RfqState>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RfqResponse {
+    rfq_id: u64,
+    book_id: String,
+    side: Side,
+    quantity: u64,
+    requester: String,
+    expires_at: u64,
+    state: RfqState,
+    quotes: Vec<QuoteResponse>,
+    accepted_quote_id: Option<u64>,
+    trade_id: Option<u64>, // The block's trade, once a quote was accepted
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QuoteResponse {
+    quote_id: u64,
+    maker: String,
+    price: ApiPrice,
+    expires_at: u64, // The sooner of the signed order's expiry and the RFQ's
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListRfqsResponse {
+    rfqs: Vec<RfqResponse>,
+}
+
+/// The block traded when a quote was accepted
+#[derive(Serialize, Deserialize)]
+pub struct BlockTradeResponse {
+    rfq_id: u64,
+    quote_id: u64,
+    trade_id: u64,
+    maker_order_id: u64,
+    taker_order_id: u64,
+    price: ApiPrice,
+    quantity: u64,
+    settlement_id: Option<u64>, // Set in books with a market configuration
+}
+
+impl RfqResponse {
+    fn new(rfq: &Rfq, book: String, scale: PriceScale) -> Self {
+        Self {
+            rfq_id: rfq.rfq_id,
+            book_id: book,
+            side: if rfq.is_bid { Side::Buy } else { Side::Sell },
+            quantity: rfq.qty.value(),
+            requester: format!("0x{}", hex::encode(rfq.requester)),
+            expires_at: rfq.expires_at,
+            state: rfq.state,
+            quotes: rfq.quotes.iter().map(|quote| QuoteResponse::new(quote, scale)).collect(),
+            accepted_quote_id: rfq.accepted.map(|(quote_id, _)| quote_id),
+            trade_id: rfq.accepted.map(|(_, trade_id)| trade_id),
         }
     }
 }
 
-/// Add the new endpoint handler
-async fn get_orderbook(
-    book_id: web::Path<String>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let book_id = book_id.into_inner();
-    
-    // Check if book exists
-    if state.book_registry.get_book_id(&book_id).is_err() {
-        return Ok(HttpResponse::NotFound().json(OrderResponse {
-            success: false,
-            message: "Book not found".to_string(),
-            order_id: None,
-        }));
+impl QuoteResponse {
+    fn new(quote: &Quote, scale: PriceScale) -> Self {
+        Self {
+            quote_id: quote.quote_id,
+            maker: format!("0x{}", hex::encode(quote.maker)),
+            price: scale.write(quote.price),
+            expires_at: quote.expires_at,
+        }
     }
+}
+
+/// Query parameters for the settlements endpoint; `status` is one of pending, submitted,
+/// confirmed or failed
+#[derive(Deserialize)]
+pub struct SettlementsQuery {
+    status: Option<String>,
+    limit: Option<usize>,
+}
 
-    let orderbooks = state.orderbooks.lock().await;
-    let orderbook = orderbooks.get(&book_id);
+#[derive(Serialize, Deserialize)]
+pub struct SettlementsResponse {
+    settlements: Vec<TrackedSettlement>,
+}
 
-    match orderbook {
-        Some(book) => {
-            let bids: Vec<PriceLevelResponse> = book.bids.iter()
-                .filter_map(|level| {
-                    book.level_pool.get(level.level_id()).map(|l| PriceLevelResponse {
-                        price: level.price().value(),
-                        size: l.size().value(),
-                    })
-                })
-                .collect();
-
-            let asks: Vec<PriceLevelResponse> = book.asks.iter()
-                .filter_map(|level| {
-                    book.level_pool.get(level.level_id()).map(|l| PriceLevelResponse {
-                        price: level.price().value(),
-                        size: l.size().value(),
-                    })
-                })
-                .collect();
+/// Shared state between handlers
+pub struct AppState {
+    pub(crate) order_intake: Arc<RwLock<OrderIntake>>, // Read by every verification, written when a market is set
+    pub(crate) book_registry: Arc<BookRegistry>,
+    engine: Arc<Mutex<MatchingEngine>>,
+    pub(crate) commands: Arc<CommandQueue>, // Where handlers queue what changes the engine, for its matching worker
+    pub(crate) metrics: Arc<Metrics>, // The engine's, read without its lock
+    snapshot_dir: PathBuf,
+    signature_verifier: Option<ContractSignatureVerifier>, // Checks contract-wallet signatures when an RPC is configured.
+    funds_checker: Option<Arc<FundsChecker>>, // Checks traders can pay for their orders when an RPC is configured.
+    pub(crate) shutdown: watch::Sender<bool>, // Flipped once the server is shutting down, closing every stream.
+    readiness: Readiness,
+    idempotency: IdempotencyCache, // Responses to accepted submissions, for their retries
+    sequencer: TraderSequencer, // Keeps each trader's submissions in arrival order through verification
+    pub(crate) ledger: FeeLedger, // Volume, fees and rebates per trader, fed by the engine's trades
+}
+
+/// What the readiness probe checks besides the kill switch
+pub struct Readiness {
+    started: Instant,
+    restored: AtomicBool, // Set once the engine is rebuilt from the snapshot and WAL
+    settlement_connected: Option<Arc<AtomicBool>>, // The submitter's link to the node, when settlements are submitted
+}
 
-            Ok(HttpResponse::Ok().json(OrderbookResponse { bids, asks }))
+impl Readiness {
+    fn new(restored: bool, settlement_connected: Option<Arc<AtomicBool>>) -> Self {
+        Self {
+            started: Instant::now(),
+            restored: AtomicBool::new(restored),
+            settlement_connected,
         }
-        None => Ok(HttpResponse::NotFound().json(OrderResponse {
-            success: false,
-            message: "Orderbook not found".to_string(),
-            order_id: None,
-        }))
+    }
+
+    fn is_restored(&self) -> bool {
+        self.restored.load(Ordering::Acquire)
     }
 }
 
-/// Handler for canceling orders
-async fn cancel_order(
-    order_id: web::Path<u32>,
-    _state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    // For now, just return a not implemented response
-    Ok(HttpResponse::NotImplemented().json(OrderResponse {
-        success: false,
-        message: "Order cancellation not yet implemented".to_string(),
-        order_id: Some(order_id.into_inner()),
-    }))
+impl AppState {
+    /// Locks the engine, timing the wait for the lock-wait histogram
+    pub(crate) async fn lock_engine(&self) -> MutexGuard<'_, MatchingEngine> {
+        let started = Instant::now();
+        let engine = self.engine.lock().await;
+        self.metrics.lock_wait.observe(started.elapsed());
+        engine
+    }
 }
 
-/// Configure API routes
-fn configure_app(cfg: &mut web::ServiceConfig) {
-    cfg.service(
-        web::scope("/api")
-            .route("/books", web::post().to(create_book))
-            .route("/books", web::get().to(list_books))
-            .route("/orders", web::post().to(submit_order))
-            .route("/books/{book_id}/orderbook", web::get().to(get_orderbook))
-            .route("/orders/{order_id}", web::delete().to(cancel_order))
-    );
+/// Response for an admin snapshot
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    success: bool,
+    message: String,
+    path: Option<String>,
+    orders: usize,
 }
 
-/// Start the API server
-pub async fn start_server() -> std::io::Result<()> {
-    let state = web::Data::new(AppState {
-        order_intake: Arc::new(Mutex::new(OrderIntake::new())),
-        book_registry: Arc::new(BookRegistry::new()),
-        orderbooks: Arc::new(Mutex::new(HashMap::new())),
-    });
+/// Admin request replacing the price band of a book's market; a null band lifts it
+#[derive(Deserialize, Serialize)]
+pub struct PriceBandRequest {
+    price_band: Option<PriceBand>,
+}
 
-    println!("Starting API server on 127.0.0.1:8080");
+#[derive(Serialize, Deserialize)]
+pub struct PriceBandResponse {
+    success: bool,
+    message: String,
+    price_band: Option<PriceBand>,
+    limits: Option<(u32, u32)>, // Lowest and highest prices the band allows right now
+}
 
-    // Start HTTP server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(state.clone())
-            .wrap(actix_web::middleware::Logger::default())
-            .configure(configure_app)
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+/// Admin request engaging the kill switch, or releasing it with `engaged: false`
+#[derive(Deserialize, Serialize)]
+pub struct KillSwitchRequest {
+    engaged: bool,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test, App};
+#[derive(Serialize, Deserialize)]
+pub struct KillSwitchResponse {
+    success: bool,
+    message: String,
+    engaged: bool,
+}
 
-    #[actix_web::test]
-    async fn test_submit_order() {
-        // Create test app
-        let state = web::Data::new(AppState {
-            order_intake: Arc::new(Mutex::new(OrderIntake::new())),
-            book_registry: Arc::new(BookRegistry::new()),
-            orderbooks: Arc::new(Mutex::new(HashMap::new())),
-        });
+/// Liveness of the server, and whether the kill switch halts trading
+#[derive(Serialize, Deserialize)]
+pub struct HealthResponse {
+    status: String, // "ok", or "halted" while the kill switch is engaged
+    halted: bool,
+}
 
-        let app = test::init_service(
-            App::new()
-                .app_data(state.clone())
-                .configure(configure_app)
-        ).await;
+/// Whether the server should get traffic, and each condition behind the answer
+#[derive(Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    ready: bool,
+    restored: bool,
+    settlement_connected: Option<bool>, // Null when settlements are not submitted
+    halted: bool,
+}
 
-        // Create test order
-        let order = OrderRequest {
-            book_id: "ETH-USD".to_string(),
-            price: 1000,
-            quantity: 100,
-            trader: "0x1234567890123456789012345678901234567890".to_string(),
-            nonce: 1,
-            expiry: None,
-            signature: String::new(),
-        };
+/// Introspection of the engine for monitoring
+#[derive(Serialize, Deserialize)]
+pub struct StatusResponse {
+    uptime_secs: u64,
+    restored: bool,
+    book_count: usize,
+    resting_orders: usize,
+    event_seq: u64, // Sequence number of the last engine event
+    books: Vec<BookStatus>,
+}
 
-        // Send test request
-        let req = test::TestRequest::post()
-            .uri("/api/orders")
-            .set_json(&order)
-            .to_request();
+#[derive(Serialize, Deserialize)]
+pub struct BookStatus {
+    book_id: String,
+    seq: u64,                     // Sequence number of the last change to the book
+    last_trade_time: Option<u64>, // Nanoseconds since the Unix epoch; null until the book trades
+}
 
-        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
-        assert!(resp.success);
+/// Admin request replacing the per-trader risk limits of a book's market
+#[derive(Deserialize, Serialize)]
+pub struct RiskLimitsRequest {
+    risk_limits: RiskLimits,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RiskLimitsResponse {
+    success: bool,
+    message: String,
+    risk_limits: Option<RiskLimits>,
+}
+
+/// Admin request replacing the size rules of a book's market
+#[derive(Deserialize, Serialize)]
+pub struct SizeRulesRequest {
+    size_rules: SizeRules,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SizeRulesResponse {
+    success: bool,
+    message: String,
+    size_rules: Option<SizeRules>,
+}
+
+/// Auction state of a book; `fills` lists what an uncross executed
+#[derive(Serialize, Deserialize)]
+pub struct AuctionResponse {
+    success: bool,
+    message: String,
+    in_auction: bool,
+    indicative: Option<Uncross>,
+    #[serde(default)]
+    fills: Vec<FillResponse>,
+}
+
+/// What repairing a crossed book did; `crossed` is whether the book is still halted for it
+#[derive(Serialize, Deserialize)]
+pub struct RepairResponse {
+    success: bool,
+    message: String,
+    crossed: bool,
+    #[serde(default)]
+    fills: Vec<FillResponse>,
+}
+
+/// Add new request/response structures
+#[derive(Deserialize, Serialize)]
+pub struct CreateBookRequest {
+    book_id: String,
+    /// Settlement and signing parameters; orders are verified against the default domain without one
+    #[serde(default)]
+    market: Option<MarketConfig>,
+    /// Lets a book without a market match anyway, its fills never settled; required without a market
+    #[serde(default)]
+    book_only: bool,
+}
+
+#[derive(Serialize)]
+pub struct CreateBookResponse {
+    success: bool,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CloseBookResponse {
+    success: bool,
+    message: String,
+    book_id: u32,
+    cancelled: Vec<u64>, // Resting orders first, then stops, then parked pegs
+}
+
+/// Request creating a book along with the market it settles in
+/// Addresses are hex, and must carry their EIP-55 checksum when written in mixed case. Fields
+/// left out take MarketConfig's defaults, the signing domain included.
+#[derive(Deserialize, Serialize)]
+pub struct CreateMarketRequest {
+    book_id: String,
+    base_token: String,
+    security_token: String,
+    fee_recipient: String,
+    pool: String,
+    #[serde(default)]
+    signature_type: Option<u8>,
+    #[serde(default)]
+    maker_fee_bps: u16,
+    #[serde(default)]
+    taker_fee_bps: u16,
+    #[serde(default)]
+    maker_rebate_bps: u16,
+    #[serde(default)]
+    base_decimals: u8,
+    #[serde(default)]
+    security_decimals: u8,
+    #[serde(default)]
+    price_decimals: u8,
+    #[serde(default)]
+    tick_size: Option<u32>,
+    #[serde(default)]
+    lot_size: Option<u64>,
+    #[serde(default)]
+    chain_id: Option<u64>,
+    #[serde(default)]
+    verifying_contract: Option<String>,
+}
+
+impl CreateMarketRequest {
+    /// Checks every field and builds the market configuration they give
+    fn to_config(&self) -> Result<MarketConfig, ApiError> {
+        let address = |field: &str, text: &str| {
+            parse_address(text).map_err(|error| ApiError::InvalidAddress { field: field.to_string(), error })
+        };
+        let invalid = |message: &str| Err(ApiError::InvalidParameter(message.to_string()));
+        let signature_type = self.signature_type.unwrap_or(SIGNATURE_TYPE_EIP712);
+        if signature_type != SIGNATURE_TYPE_EIP712 && signature_type != SIGNATURE_TYPE_EIP1271 {
+            return invalid("signature_type must be 2 (EIP-712) or 7 (EIP-1271)");
+        }
+        if self.maker_fee_bps > 10_000 || self.taker_fee_bps > 10_000 || self.maker_rebate_bps > 10_000 {
+            return invalid("Fees and rebates must be at most 10000 bps");
+        }
+        if self.tick_size == Some(0) || self.lot_size == Some(0) {
+            return invalid("tick_size and lot_size must be at least 1");
+        }
+        let mut market = MarketConfig::builder()
+            .base_token(address("base_token", &self.base_token)?)
+            .security_token(address("security_token", &self.security_token)?)
+            .fee_recipient(address("fee_recipient", &self.fee_recipient)?)
+            .pool(address("pool", &self.pool)?)
+            .signature_type(signature_type)
+            .maker_fee_bps(self.maker_fee_bps)
+            .taker_fee_bps(self.taker_fee_bps)
+            .maker_rebate_bps(self.maker_rebate_bps)
+            .base_decimals(self.base_decimals)
+            .security_decimals(self.security_decimals)
+            .price_decimals(self.price_decimals)
+            .tick_size(self.tick_size.unwrap_or(1))
+            .size_rules(SizeRules { lot_size: self.lot_size.unwrap_or(1), ..SizeRules::default() });
+        if let Some(chain_id) = self.chain_id {
+            market = market.chain_id(chain_id);
+        }
+        if let Some(verifying_contract) = &self.verifying_contract {
+            market = market.verifying_contract(address("verifying_contract", verifying_contract)?);
+        }
+        Ok(market.build())
     }
-} 
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateMarketResponse {
+    success: bool,
+    message: String,
+    book_id: u32,
+    market: MarketConfig,
+}
+
+#[derive(Serialize)]
+pub struct ListBooksResponse {
+    books: Vec<String>,
+}
+
+/// A book's market configuration, by BookId and book name
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MarketListing {
+    book_id: u32,
+    name: String,
+    market: MarketConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListMarketsResponse {
+    markets: Vec<MarketListing>,
+}
+
+/// Optional handle a cancel must match, as returned when the order was submitted
+#[derive(Deserialize)]
+pub struct CancelOrderQuery {
+    handle: Option<u64>,
+}
+
+/// Whose client order ID a lookup is for; a trader authenticated as themself may leave it out
+#[derive(Deserialize)]
+pub struct ClientOrderQuery {
+    trader: Option<String>,
+}
+
+/// Optional book scope for mass cancellation
+#[derive(Deserialize)]
+pub struct CancelAllQuery {
+    book_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CancelAllResponse {
+    success: bool,
+    message: String,
+    cancelled: Vec<u64>,
+}
+
+/// A trader's net position in one book; long when positive, short when negative
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PositionEntry {
+    book: String,
+    book_id: u32,
+    qty: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PositionsResponse {
+    success: bool,
+    message: String,
+    positions: Vec<PositionEntry>,
+}
+
+/// Days a volume query covers, each a YYYY-MM-DD date in the ledger's time zone, both inclusive
+#[derive(Deserialize)]
+pub struct VolumeQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl VolumeQuery {
+    fn days(&self) -> Result<(Option<Day>, Option<Day>), ApiError> {
+        let day = |date: &Option<String>| {
+            date.as_deref()
+                .map(|date| Day::parse(date).ok_or_else(|| ApiError::InvalidParameter(format!("{:?} is not a YYYY-MM-DD date", date))))
+                .transpose()
+        };
+        Ok((day(&self.from)?, day(&self.to)?))
+    }
+}
+
+/// A trader's volume, fees and rebates in one book on one day
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VolumeDayEntry {
+    book: String,
+    book_id: u32,
+    day: Day,
+    totals: VolumeTotals,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TraderVolumeResponse {
+    trader: String,
+    days: Vec<VolumeDayEntry>,
+    totals: VolumeTotals, // Over every book and day listed
+}
+
+/// A trader's volume, fees and rebates in one book over the days queried
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VolumeRollUpEntry {
+    trader: String,
+    book: String,
+    book_id: u32,
+    totals: VolumeTotals,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VolumeRollUpResponse {
+    entries: Vec<VolumeRollUpEntry>,
+    totals: VolumeTotals, // Over every trader, which counts each trade once as maker and once as taker
+}
+
+/// What a trader has resting in one book, against the limits of its market
+#[derive(Serialize, Deserialize)]
+pub struct RiskUsageEntry {
+    book: String,
+    book_id: u32,
+    usage: OpenUsage,
+    limits: Option<RiskLimits>, // None when the book has no market configuration, and so no limits
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RiskUsageResponse {
+    success: bool,
+    message: String,
+    books: Vec<RiskUsageEntry>,
+}
+
+/// Nonce bump request, signed by the trader with `personal_sign`
+#[derive(Deserialize, Serialize)]
+pub struct NonceBumpRequest {
+    min_nonce: u64,
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NonceBumpResponse {
+    success: bool,
+    message: String,
+    min_nonce: Option<u64>,
+    cancelled: Vec<u64>,
+}
+
+/// Cancel-replace request; the side and settlement metadata are kept from the original order
+#[derive(Deserialize, Serialize)]
+pub struct ReplaceOrderRequest {
+    price: ApiPrice, // Book units or a decimal string, like a submitted price but never negative
+    quantity: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FillResponse {
+    pub(crate) price: ApiPrice,
+    pub(crate) quantity: u64,
+    /// Nanoseconds since the Unix epoch; an estimated fill has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) executed_at: Option<u64>,
+    /// Set for the fills of limit orders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) improvement: Option<PriceImprovementResponse>,
+}
+
+impl FillResponse {
+    fn new(details: &MatchDetails, scale: PriceScale) -> Self {
+        Self {
+            price: scale.write(details.exec_price),
+            quantity: details.exec_qty.value(),
+            executed_at: Some(details.executed_at),
+            improvement: details.improvement.map(|improvement| PriceImprovementResponse::new(&improvement, scale)),
+        }
+    }
+}
+
+/// How far inside a limit order's price the liquidity it took was quoted, and the spread it found
+#[derive(Serialize, Deserialize)]
+pub struct PriceImprovementResponse {
+    pub(crate) taker_limit: ApiPrice,
+    pub(crate) maker_price: ApiPrice,
+    pub(crate) ticks: i64, // Positive when the maker's price was better than the taker's limit
+    pub(crate) quoted_bid: Option<ApiPrice>,
+    pub(crate) quoted_ask: Option<ApiPrice>,
+    pub(crate) quoted_spread: Option<ApiPrice>,
+}
+
+impl PriceImprovementResponse {
+    fn new(improvement: &PriceImprovement, scale: PriceScale) -> Self {
+        Self {
+            taker_limit: scale.write(improvement.taker_limit),
+            maker_price: scale.write(improvement.maker_price),
+            ticks: improvement.ticks,
+            quoted_bid: improvement.quoted_bid.map(|price| scale.write(price)),
+            quoted_ask: improvement.quoted_ask.map(|price| scale.write(price)),
+            quoted_spread: improvement.quoted_spread().map(|spread| scale.write(spread)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplaceOrderResponse {
+    pub(crate) success: bool,
+    pub(crate) message: String,
+    pub(crate) order_id: Option<u64>,
+    pub(crate) remaining_quantity: u64,
+    pub(crate) fills: Vec<FillResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<OrderUpdate>,
+}
+
+/// Add new handler for creating books
+async fn create_book(
+    data: web::Json<CreateBookRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    tracing::info!(book_id = %data.book_id, "Creating book");
+    if data.market.is_none() && !data.book_only {
+        return Err(ApiError::InvalidParameter("a book without a market must be created book_only".to_string()));
+    }
+    // The intake stays locked until the book's market is set, so no order is verified against a stale domain.
+    let mut order_intake = state.order_intake.write().await;
+    let mut engine = state.lock_engine().await;
+    // Under the engine lock, so the log sees books in BookId order
+    let (market, book_only) = (data.market.as_ref(), data.book_only);
+    if let Err(error) = provision_book(&mut engine, &state.book_registry, &data.book_id, market, book_only) {
+        tracing::warn!(book_id = %data.book_id, ?error, "Failed to create book");
+        return Err(error.into());
+    }
+    if let Some(market) = &data.market {
+        order_intake.set_market(&data.book_id, market);
+        if let Ok(book_id) = state.book_registry.get_book_id(&data.book_id) {
+            state.ledger.set_market(book_id, market);
+        }
+    }
+    tracing::info!(book_id = %data.book_id, "Book created");
+
+    Ok(HttpResponse::Ok().json(CreateBookResponse {
+        success: true,
+        message: "Book created successfully".to_string(),
+    }))
+}
+
+/// Handler creating a book together with its market, from its addresses and parameters
+async fn create_market(
+    data: web::Json<CreateMarketRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let market = data.to_config()?;
+    tracing::info!(book_id = %data.book_id, "Creating market");
+    let mut order_intake = state.order_intake.write().await;
+    let mut engine = state.lock_engine().await;
+    let book_id = match provision_book(&mut engine, &state.book_registry, &data.book_id, Some(&market), false) {
+        Ok(book_id) => book_id,
+        Err(error) => {
+            tracing::warn!(book_id = %data.book_id, ?error, "Failed to create market");
+            return Err(error.into());
+        }
+    };
+    order_intake.set_market(&data.book_id, &market);
+    state.ledger.set_market(book_id, &market);
+    tracing::info!(book_id = %data.book_id, "Market created");
+
+    Ok(HttpResponse::Ok().json(CreateMarketResponse {
+        success: true,
+        message: "Market created successfully".to_string(),
+        book_id: book_id.value(),
+        market,
+    }))
+}
+
+/// Admin handler closing a book
+/// Every working order in it is cancelled, its owner told the book closed, and the book is dropped
+/// with its market. Its name is freed for a new book, which gets a new BookId.
+async fn close_book(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let name = book_id.into_inner();
+    let book_id = state.book_registry.get_book_id(&name)?;
+    tracing::info!(book_id = book_id.value(), "Closing book");
+
+    // Like creation, the intake stays locked until it has forgotten the book's market
+    let mut order_intake = state.order_intake.write().await;
+    let mut engine = state.lock_engine().await;
+    engine.log(&WalCommand::CloseBook { book_id: book_id.value() })?;
+    let cancelled = engine.close_book(book_id)?;
+    state.book_registry.unregister(book_id)?;
+    order_intake.remove_market(&name);
+    tracing::info!(book_id = book_id.value(), cancelled = cancelled.len(), "Book closed");
+
+    Ok(HttpResponse::Ok().json(CloseBookResponse {
+        success: true,
+        message: format!("Book closed; {} orders cancelled", cancelled.len()),
+        book_id: book_id.value(),
+        cancelled: cancelled.iter().map(|order_id| order_id.0).collect(),
+    }))
+}
+
+/// Add new handler for listing books
+async fn list_books(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let books = state.book_registry.list_books();
+    Ok(HttpResponse::Ok().json(ListBooksResponse { books }))
+}
+
+/// Submit an order; OrderIntake rejects it unless the trader signed it (EIP-712)
+#[tracing::instrument(
+    name = "order_intake",
+    skip_all,
+    fields(book_id = %data.book_id, trader = %data.trader, qty = data.quantity, price = ?data.price)
+)]
+async fn submit_order(
+    req: HttpRequest,
+    data: web::Json<OrderRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let signers: Vec<([u8; 20], u64)> = parse_trader(&data.trader).map(|trader| (trader, data.nonce)).into_iter().collect();
+    let result = deduplicated(&req, &state, &signers, &*data, place_order(&data, identity, &state)).await;
+    state.metrics.record_submission(result.as_ref().err().map(ApiError::code));
+    result
+}
+
+/// Answers a submission, or a retry of one from the idempotency cache
+/// A submission is known by its client's Idempotency-Key, if sent, and the nonce of each signed
+/// order in it. A retry with the payload of an accepted submission gets the same response back,
+/// marked with IDEMPOTENT_REPLAY_HEADER, and places nothing; a different payload under any of its
+/// keys is refused with IdempotencyConflict. Without `signers` there is nothing to go by, and the
+/// submission is placed as it is.
+async fn deduplicated<T: Serialize>(
+    req: &HttpRequest,
+    state: &AppState,
+    signers: &[([u8; 20], u64)],
+    payload: &impl Serialize,
+    place: impl Future<Output = Result<T, ApiError>>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(&(trader, _)) = signers.first() else {
+        return Ok(HttpResponse::Ok().json(place.await?));
+    };
+    // A header that isn't text is refused like any other bad key
+    let header = req.headers().get(IDEMPOTENCY_KEY_HEADER).map(|value| value.to_str().unwrap_or_default());
+    let keys: Vec<IdempotencyKey> = IdempotencyKey::client(trader, header)?
+        .into_iter()
+        .chain(signers.iter().map(|&(trader, nonce)| IdempotencyKey::Nonce(trader, nonce)))
+        .collect();
+    let fingerprint = fingerprint(&serde_json::to_vec(payload).unwrap_or_default());
+    match state.idempotency.lookup(&keys, fingerprint, Instant::now()) {
+        Lookup::Replay(body) => {
+            tracing::info!(trader = %hex::encode(trader), "Retried submission answered from the idempotency cache");
+            let mut response = HttpResponse::Ok();
+            response.insert_header((IDEMPOTENT_REPLAY_HEADER, "true")).content_type(ContentType::json());
+            return Ok(response.body(body));
+        }
+        Lookup::Conflict => return Err(ApiError::IdempotencyConflict),
+        Lookup::Miss => {}
+    }
+    let body = serde_json::to_vec(&place.await?).map_err(|error| ApiError::Internal(error.to_string()))?;
+    state.idempotency.store(&keys, fingerprint, &body, Instant::now());
+    Ok(HttpResponse::Ok().content_type(ContentType::json()).body(body))
+}
+
+/// Places a submitted order, for submit_order to count
+pub(crate) async fn place_order(data: &OrderRequest, identity: Identity, state: &AppState) -> Result<OrderResponse, ApiError> {
+    // First verify the book exists
+    let book_id = state.book_registry.get_book_id(&data.book_id)?;
+    let is_stop = matches!(data.order_type, OrderType::Stop | OrderType::StopLimit);
+    let invalid = |message: &str| Err(ApiError::InvalidParameter(message.to_string()));
+    if is_stop != data.trigger_price.is_some() {
+        return invalid("trigger_price is required on stop orders and only allowed on them");
+    }
+    if is_stop && data.display_quantity.is_some() {
+        return invalid("display_quantity is only allowed on limit and pegged orders");
+    }
+    if data.order_type != OrderType::PrimaryPeg && data.peg_offset.is_some() {
+        return invalid("peg_offset is only allowed on primary peg orders");
+    }
+    if data.order_type != OrderType::Limit && data.reduce_only {
+        return invalid("reduce_only is only allowed on limit orders");
+    }
+    let client_order_id = parse_client_order_id(data)?;
+    let scale = state.order_intake.read().await.price_scale(&data.book_id);
+    let trigger = data.trigger_price.as_ref().map(|trigger| scale.read(trigger)).transpose()?;
+
+    let mut ticket = state.sequencer.ticket(&[parse_trader(&data.trader)?]);
+    let order = verify_order_request(state, data, scale).await?;
+    identity.authorize(order.trader())?;
+    let (data, funds_checker) = (data.clone(), state.funds_checker.clone());
+    ticket.wait().await;
+    let entered = state.commands.push(Lane::New, move |engine| {
+        enter_order(engine, &data, order, book_id, trigger, client_order_id, funds_checker.as_deref())
+    })?;
+    drop(ticket);
+    entered.wait().await?
+}
+
+/// Enters a verified order into the engine, run by the matching worker
+/// `trigger` is the request's trigger price in book units, set on stop orders.
+fn enter_order(
+    engine: &mut MatchingEngine,
+    data: &OrderRequest,
+    order: VerifiedOrder,
+    book_id: BookId,
+    trigger: Option<u32>,
+    client_order_id: Option<ClientOrderId>,
+    funds_checker: Option<&FundsChecker>,
+) -> Result<OrderResponse, ApiError> {
+    engine.check_accepting()?;
+    // Each signed order is accepted once; the nonce is only used up once the order is logged
+    let (trader, nonce) = (order.trader().unwrap_or_default(), order.nonce().unwrap_or_default()); // Always set on submissions
+    engine.nonces.check(trader, nonce)?;
+    // Orders that can rest count in full against their trader's limits, as if none of them
+    // filled; checked under the lock they go in with so a burst can't slip past a cap
+    if trigger.is_none() {
+        let price = order.price().absolute() as u32;
+        engine.check_risk_limits(book_id, trader, 1, notional(price, order.qty()))?;
+    }
+    if let Some(client_order_id) = client_order_id {
+        check_client_order_id(engine, trader, client_order_id)?;
+    }
+    let order_id = engine.next_order_id();
+    if let Some(client_order_id) = client_order_id {
+        bind_client_order_id(engine, order_id, trader, client_order_id)?;
+    }
+    if let Some(trigger) = trigger {
+        let is_limit = data.order_type == OrderType::StopLimit;
+        return submit_stop_order(engine, order_id, book_id, &order, trigger, is_limit, client_order_id);
+    }
+    if matches!(data.order_type, OrderType::MidpointPeg | OrderType::PrimaryPeg) {
+        return submit_pegged_order(engine, order_id, book_id, &order, data, client_order_id);
+    }
+    // The sign of the submitted price carries the side: positive bids, negative asks.
+    let price = order.price();
+    let command = WalCommand::Submit {
+        order_id: order_id.0,
+        book_id: book_id.value(),
+        qty: order.qty().value(),
+        price: price.absolute() as u32,
+        is_bid: price.is_bid(),
+        trader: order.trader(),
+        nonce: order.nonce(),
+        expiry: order.expiry(),
+        signature: order.signature(),
+        display: data.display_quantity,
+        reduce_only: data.reduce_only,
+        received_at: order.received_at(),
+    };
+    engine.log(&command)?;
+    let _ = engine.nonces.consume(trader, nonce);
+    let mut taker = Order::new(
+        order.qty(),
+        LevelId(0),
+        book_id,
+        order.trader(),
+        order.nonce(),
+        order.expiry(),
+        order.signature(),
+    )
+    .with_price(price)
+    .with_received_at(order.received_at());
+    if let Some(display) = data.display_quantity {
+        taker = taker.with_display(Qty(display));
+    }
+    if data.reduce_only {
+        taker = taker.with_reduce_only();
+    }
+    let (remaining, fills) = engine.match_limit_order(order_id, taker, price.absolute() as u32, price.is_bid())?;
+    let filled = fills.iter().map(|fill| fill.exec_qty.value()).sum::<u64>();
+    tracing::info!(order_id = order_id.0, book_id = %data.book_id, filled, remaining = remaining.value(), "Order added to book");
+    if filled > 0 {
+        if let Some(checker) = funds_checker {
+            checker.invalidate(trader);
+        }
+    }
+    let received_at = Some(order.received_at());
+    let status = OrderUpdate { client_order_id, received_at, ..taker_status(engine, order_id, book_id, trader, filled, remaining) };
+    let handle = engine.orderbook_manager.oid_map.handle(order_id);
+
+    Ok(OrderResponse {
+        success: true,
+        message: "Order submitted successfully".to_string(),
+        order_id: Some(order_id.0),
+        handle: handle.map(OrderHandle::to_u64),
+        status: Some(status),
+        client_order_id,
+    })
+}
+
+/// Builds the status of an order that just went in, from what it filled and has left
+/// The order's quantity is taken to be the two together, since a reduce-only order may have been
+/// cut down to the trader's position. An order the fill limit cut short is Cancelled.
+fn taker_status(
+    engine: &MatchingEngine,
+    order_id: OrderId,
+    book_id: BookId,
+    trader: [u8; 20],
+    filled: u64,
+    remaining: Qty,
+) -> OrderUpdate {
+    let update = OrderUpdate::taker(order_id, book_id, trader, Qty(filled + remaining.value()), remaining);
+    if engine.cancelled_by_fill_limit(order_id) {
+        OrderUpdate { status: OrderStatus::Cancelled, ..update }
+    } else {
+        update
+    }
+}
+
+/// Handler for submitting two signed orders as a one-cancels-other pair
+/// A fill of either order cancels the other, or with `on_fill: reduce` shrinks it in proportion;
+/// see MatchingEngine::submit_oco. The pair is known by the first order's ID.
+#[tracing::instrument(name = "order_intake", skip_all, fields(book_id = %data.orders[0].book_id, trader = %data.orders[0].trader))]
+async fn submit_oco_pair(
+    req: HttpRequest,
+    data: web::Json<OcoRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let signers: Option<Vec<([u8; 20], u64)>> = data
+        .orders
+        .iter()
+        .map(|order| parse_trader(&order.trader).ok().map(|trader| (trader, order.nonce)))
+        .collect();
+    let signers = signers.unwrap_or_default();
+    let result = deduplicated(&req, &state, &signers, &*data, place_oco_pair(&data, identity, &state)).await;
+    state.metrics.record_submission(result.as_ref().err().map(ApiError::code));
+    result
+}
+
+/// Places a submitted OCO pair, for submit_oco_pair to count
+async fn place_oco_pair(data: &OcoRequest, identity: Identity, state: &AppState) -> Result<OcoResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&data.orders[0].book_id)?;
+    for leg in &data.orders {
+        if leg.book_id != data.orders[0].book_id {
+            return Err(ApiError::InvalidOco);
+        }
+        let is_stop = matches!(leg.order_type, OrderType::Stop | OrderType::StopLimit);
+        if !matches!(leg.order_type, OrderType::Limit | OrderType::Stop | OrderType::StopLimit)
+            || is_stop != leg.trigger_price.is_some()
+            || leg.display_quantity.is_some()
+            || leg.peg_offset.is_some()
+            || leg.reduce_only
+        {
+            let message = "OCO orders must be limit orders, or stop orders with a trigger_price".to_string();
+            return Err(ApiError::InvalidParameter(message));
+        }
+    }
+    let client_order_ids = [parse_client_order_id(&data.orders[0])?, parse_client_order_id(&data.orders[1])?];
+    if client_order_ids[0].is_some() && client_order_ids[0] == client_order_ids[1] {
+        return Err(ApiError::InvalidParameter("Each order of a pair needs its own client_order_id".to_string()));
+    }
+    let scale = state.order_intake.read().await.price_scale(&data.orders[0].book_id);
+    let mut triggers = [None; 2];
+    for (trigger, leg) in triggers.iter_mut().zip(&data.orders) {
+        *trigger = leg.trigger_price.as_ref().map(|trigger| scale.read(trigger)).transpose()?;
+    }
+    let traders = [parse_trader(&data.orders[0].trader)?, parse_trader(&data.orders[1].trader)?];
+    let mut ticket = state.sequencer.ticket(&traders);
+    let mut orders = Vec::new();
+    for leg in &data.orders {
+        let order = verify_order_request(state, leg, scale).await?;
+        identity.authorize(order.trader())?;
+        orders.push(order);
+    }
+    let data = data.clone();
+    ticket.wait().await;
+    let entered = state
+        .commands
+        .push(Lane::New, move |engine| enter_oco_pair(engine, &data, orders, book_id, triggers, client_order_ids))?;
+    drop(ticket);
+    entered.wait().await?
+}
+
+/// Enters a verified OCO pair into the engine, run by the matching worker
+/// `triggers` are the trigger prices of the legs in book units, set on stop legs.
+fn enter_oco_pair(
+    engine: &mut MatchingEngine,
+    data: &OcoRequest,
+    orders: Vec<VerifiedOrder>,
+    book_id: BookId,
+    triggers: [Option<u32>; 2],
+    client_order_ids: [Option<ClientOrderId>; 2],
+) -> Result<OcoResponse, ApiError> {
+    engine.check_accepting()?;
+    let signers: Vec<([u8; 20], u64)> = orders
+        .iter()
+        .map(|order| (order.trader().unwrap_or_default(), order.nonce().unwrap_or_default())) // Always set on submissions
+        .collect();
+    if signers[0] == signers[1] {
+        return Err(ApiError::InvalidParameter("Each order of a pair needs its own nonce".to_string()));
+    }
+    for &(trader, nonce) in &signers {
+        engine.nonces.check(trader, nonce)?;
+    }
+    // Both limit legs rest at once, so they count together against their trader's limits
+    let limit_legs = orders.iter().zip(triggers).filter(|(_, trigger)| trigger.is_none());
+    let (legs, leg_notional) = limit_legs.fold((0, 0u64), |(legs, total), (order, _)| {
+        (legs + 1, total.saturating_add(notional(order.price().absolute() as u32, order.qty())))
+    });
+    if legs > 0 {
+        engine.check_risk_limits(book_id, signers[0].0, legs, leg_notional)?;
+    }
+    for (&(trader, _), client_order_id) in signers.iter().zip(client_order_ids) {
+        if let Some(client_order_id) = client_order_id {
+            check_client_order_id(engine, trader, client_order_id)?;
+        }
+    }
+    let legs = [0, 1].map(|leg| {
+        let (order, request) = (&orders[leg], &data.orders[leg]);
+        let order_id = engine.next_order_id();
+        let price = order.price();
+        match triggers[leg] {
+            Some(trigger) => OcoLeg::Stop(StopOrder {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                qty: order.qty().value(),
+                is_bid: price.is_bid(),
+                trigger,
+                limit: (request.order_type == OrderType::StopLimit).then_some(price.absolute() as u32),
+                trader: order.trader(),
+                nonce: order.nonce(),
+                expiry: order.expiry(),
+                signature: order.signature(),
+            }),
+            None => OcoLeg::Limit {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                qty: order.qty().value(),
+                price: price.absolute() as u32,
+                is_bid: price.is_bid(),
+                trader: order.trader(),
+                nonce: order.nonce(),
+                expiry: order.expiry(),
+                signature: order.signature(),
+            },
+        }
+    });
+    let order_ids: Vec<u64> = legs.iter().map(|leg| leg.order_id().0).collect();
+    for ((leg, &(trader, _)), client_order_id) in legs.iter().zip(&signers).zip(client_order_ids) {
+        if let Some(client_order_id) = client_order_id {
+            bind_client_order_id(engine, leg.order_id(), trader, client_order_id)?;
+        }
+    }
+    let command = WalCommand::SubmitOco {
+        legs: legs.clone(),
+        policy: data.on_fill,
+        cancel_together: data.cancel_together,
+    };
+    engine.log(&command)?;
+    for &(trader, nonce) in &signers {
+        let _ = engine.nonces.consume(trader, nonce);
+    }
+    engine.submit_oco(legs, data.on_fill, data.cancel_together)?;
+    tracing::info!(?order_ids, book_id = %data.orders[0].book_id, "OCO pair submitted");
+
+    Ok(OcoResponse {
+        success: true,
+        message: "OCO pair submitted successfully".to_string(),
+        group_id: order_ids.first().copied(),
+        order_ids,
+    })
+}
+
+/// Handler for a market maker's mass quote: replaces the trader's quote in each book named,
+/// all at once
+/// Each entry is checked on its own and one refused is reported without holding the others
+/// back; see MatchingEngine::mass_quote. Every signed order must be the trader's.
+#[tracing::instrument(name = "order_intake", skip_all, fields(trader = %data.trader, entries = data.quotes.len()))]
+async fn submit_mass_quote(
+    req: HttpRequest,
+    data: web::Json<MassQuoteRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let signers: Vec<([u8; 20], u64)> = data
+        .quotes
+        .iter()
+        .flat_map(|entry| entry.bid.iter().chain(&entry.ask))
+        .filter_map(|order| parse_trader(&order.trader).ok().map(|trader| (trader, order.nonce)))
+        .collect();
+    let result = deduplicated(&req, &state, &signers, &*data, place_mass_quote(&data, identity, &state)).await;
+    state.metrics.record_submission(result.as_ref().err().map(ApiError::code));
+    result
+}
+
+/// A mass quote entry whose signed orders were checked, on its way to the matching worker
+struct VerifiedQuote {
+    book_id: BookId,
+    scale: PriceScale,
+    bid: Option<VerifiedOrder>,
+    ask: Option<VerifiedOrder>,
+}
+
+/// Places a submitted mass quote, for submit_mass_quote to count
+async fn place_mass_quote(data: &MassQuoteRequest, identity: Identity, state: &AppState) -> Result<MassQuoteResponse, ApiError> {
+    let trader = parse_trader(&data.trader)?;
+    identity.authorize(Some(trader))?;
+    if data.quotes.is_empty() || data.quotes.len() > MAX_MASS_QUOTE_ENTRIES {
+        let message = format!("quotes must hold 1 to {} entries", MAX_MASS_QUOTE_ENTRIES);
+        return Err(ApiError::InvalidParameter(message));
+    }
+    let mut ticket = state.sequencer.ticket(&[trader]);
+    let mut verified = Vec::with_capacity(data.quotes.len());
+    for (i, entry) in data.quotes.iter().enumerate() {
+        let repeated = data.quotes[..i].iter().any(|earlier| earlier.book_id == entry.book_id);
+        let quote = match repeated {
+            true => Err(ApiError::InvalidParameter("Each book may be quoted once per request".to_string())),
+            false => verify_quote_entry(state, entry, trader).await,
+        };
+        verified.push(quote);
+    }
+    let (data, funds_checker) = (data.clone(), state.funds_checker.clone());
+    ticket.wait().await;
+    let entered = state
+        .commands
+        .push(Lane::New, move |engine| enter_mass_quote(engine, &data, trader, verified, funds_checker.as_deref()))?;
+    drop(ticket);
+    entered.wait().await?
+}
+
+/// Checks the signed orders of a mass quote entry: plain limit orders of `trader` in the
+/// entry's book, the bid a buy and the ask a sell
+async fn verify_quote_entry(state: &AppState, entry: &QuoteEntryRequest, trader: [u8; 20]) -> Result<VerifiedQuote, ApiError> {
+    let book_id = state.book_registry.get_book_id(&entry.book_id)?;
+    let scale = state.order_intake.read().await.price_scale(&entry.book_id);
+    let mut sides = [None, None];
+    for ((side, request), is_bid) in sides.iter_mut().zip([&entry.bid, &entry.ask]).zip([true, false]) {
+        let Some(request) = request else { continue };
+        if request.book_id != entry.book_id
+            || request.order_type != OrderType::Limit
+            || request.trigger_price.is_some()
+            || request.display_quantity.is_some()
+            || request.peg_offset.is_some()
+            || request.reduce_only
+            || request.client_order_id.is_some()
+        {
+            return Err(ApiError::InvalidParameter("Quotes must be plain limit orders in the entry's book".to_string()));
+        }
+        let order = verify_order_request(state, request, scale).await?;
+        if order.trader() != Some(trader) {
+            return Err(ApiError::Forbidden);
+        }
+        if order.price().is_bid() != is_bid {
+            return Err(ApiError::InvalidParameter("The bid of a quote must be a buy and its ask a sell".to_string()));
+        }
+        *side = Some(order);
+    }
+    let [bid, ask] = sides;
+    if let (Some(bid), Some(ask)) = (&bid, &ask) {
+        if bid.nonce() == ask.nonce() {
+            return Err(ApiError::InvalidParameter("The bid and ask of a quote need their own nonces".to_string()));
+        }
+    }
+    Ok(VerifiedQuote { book_id, scale, bid, ask })
+}
+
+/// Enters the verified entries of a mass quote into the engine, run by the matching worker
+/// Entries refused here, on their nonces or the trader's risk limits, are never logged. A
+/// replacement counts against the limits only by what it adds to the quote it replaces.
+fn enter_mass_quote(
+    engine: &mut MatchingEngine,
+    data: &MassQuoteRequest,
+    trader: [u8; 20],
+    verified: Vec<Result<VerifiedQuote, ApiError>>,
+    funds_checker: Option<&FundsChecker>,
+) -> Result<MassQuoteResponse, ApiError> {
+    engine.check_accepting()?;
+    let mut entries = Vec::new();
+    let mut scales = Vec::new();
+    let mut checked = Vec::with_capacity(verified.len());
+    for quote in verified {
+        let quote = match quote.and_then(|quote| check_quote_entry(engine, trader, &quote).map(|_| quote)) {
+            Ok(quote) => quote,
+            Err(error) => {
+                checked.push(Err(error));
+                continue;
+            }
+        };
+        let side = |engine: &mut MatchingEngine, order: &Option<VerifiedOrder>| {
+            order.as_ref().map(|order| QuoteSide {
+                order_id: engine.next_order_id().0,
+                qty: order.qty().value(),
+                price: order.price().absolute() as u32,
+                nonce: order.nonce().unwrap_or_default(), // Always set on submissions
+                expiry: order.expiry(),
+                signature: order.signature(),
+            })
+        };
+        let (bid, ask) = (side(engine, &quote.bid), side(engine, &quote.ask));
+        entries.push(QuoteEntry { book_id: quote.book_id.value(), bid, ask });
+        scales.push(quote.scale);
+        checked.push(Ok(()));
+    }
+
+    let mut outcomes = Vec::new().into_iter();
+    if !entries.is_empty() {
+        let received_at = engine.clock.now();
+        engine.log(&WalCommand::MassQuote { trader, entries: entries.clone(), received_at })?;
+        for (side, _) in entries.iter().flat_map(QuoteEntry::sides) {
+            let _ = engine.nonces.consume(trader, side.nonce);
+        }
+        outcomes = engine.mass_quote(trader, &entries, received_at)?.into_iter();
+    }
+    let mut entered = entries.iter().zip(scales).zip(outcomes);
+    let mut filled = false;
+    let results: Vec<QuoteEntryResult> = checked
+        .into_iter()
+        .zip(&data.quotes)
+        .map(|(checked, request)| {
+            let book_id = request.book_id.clone();
+            if let Err(error) = checked {
+                return QuoteEntryResult::refused(book_id, &error);
+            }
+            let Some(((entry, scale), outcome)) = entered.next() else {
+                return QuoteEntryResult::refused(book_id, &ApiError::Internal("Quote entry lost".to_string()));
+            };
+            match outcome {
+                Ok(outcome) => {
+                    filled |= !outcome.fills.is_empty();
+                    QuoteEntryResult {
+                        book_id,
+                        success: true,
+                        code: None,
+                        message: None,
+                        bid_order_id: entry.bid.map(|bid| bid.order_id),
+                        ask_order_id: entry.ask.map(|ask| ask.order_id),
+                        bid_remaining: outcome.bid_remaining.map(|qty| qty.value()),
+                        ask_remaining: outcome.ask_remaining.map(|qty| qty.value()),
+                        cancelled: outcome.cancelled.iter().map(|order_id| order_id.0).collect(),
+                        fills: outcome.fills.iter().map(|fill| FillResponse::new(fill, scale)).collect(),
+                    }
+                }
+                Err(error) => QuoteEntryResult::refused(book_id, &ApiError::from(error)),
+            }
+        })
+        .collect();
+    if filled {
+        if let Some(checker) = funds_checker {
+            checker.invalidate(trader);
+        }
+    }
+    let placed = results.iter().filter(|result| result.success).count();
+    tracing::info!(trader = %data.trader, placed, refused = results.len() - placed, "Mass quote entered");
+
+    Ok(MassQuoteResponse { success: placed == results.len(), results })
+}
+
+/// Checks a verified mass quote entry against the engine: its self-cross, its nonces, and the
+/// trader's risk limits net of the quote it replaces
+fn check_quote_entry(engine: &MatchingEngine, trader: [u8; 20], quote: &VerifiedQuote) -> Result<(), ApiError> {
+    let sides: Vec<&VerifiedOrder> = quote.bid.iter().chain(&quote.ask).collect();
+    if let (Some(bid), Some(ask)) = (&quote.bid, &quote.ask) {
+        if bid.price().absolute() >= ask.price().absolute() {
+            return Err(ApiError::CrossedQuote(quote.book_id));
+        }
+    }
+    for order in &sides {
+        engine.nonces.check(trader, order.nonce().unwrap_or_default())?;
+    }
+    let count = |sides: &mut dyn Iterator<Item = (u32, Qty)>| {
+        sides.fold((0u32, 0u64), |(orders, total), (price, qty)| (orders + 1, total.saturating_add(notional(price, qty))))
+    };
+    let (orders, total) = count(&mut sides.iter().map(|order| (order.price().absolute() as u32, order.qty())));
+    // What is left of the replaced quote is cancelled as the new one goes in
+    let replaced = engine.quote_set(trader, quote.book_id).into_iter().flat_map(|set| set.order_ids());
+    let (old_orders, old_total) =
+        count(&mut replaced.filter_map(|order_id| Some((engine.order_price(order_id)?, engine.order_status(order_id)?.1))));
+    let (orders, total) = (orders.saturating_sub(old_orders), total.saturating_sub(old_total));
+    if orders > 0 || total > 0 {
+        engine.check_risk_limits(quote.book_id, trader, orders, total)?;
+    }
+    Ok(())
+}
+
+/// Handler for taking down a trader's mass quotes, in every book or only `book_id`
+/// Only the orders of the trader's quote sets are cancelled; other orders stay.
+async fn cancel_quotes(
+    address: web::Path<String>,
+    query: web::Query<CancelAllQuery>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    identity.authorize(Some(trader))?;
+    let book_id = match &query.book_id {
+        Some(name) => Some(state.book_registry.get_book_id(name)?),
+        None => None,
+    };
+
+    let cancelled = state
+        .commands
+        .run(Lane::Cancel, move |engine| {
+            let command = WalCommand::CancelQuotes {
+                trader,
+                book_id: book_id.map(|book_id| book_id.value()),
+            };
+            engine.log(&command)?;
+            Ok::<_, ApiError>(engine.cancel_quotes(trader, book_id))
+        })
+        .await??;
+    tracing::info!(trader = %address, cancelled = cancelled.len(), "Cancelled quotes of trader");
+
+    Ok(HttpResponse::Ok().json(CancelAllResponse {
+        success: true,
+        message: format!("Cancelled {} quote orders", cancelled.len()),
+        cancelled: cancelled.into_iter().map(|order_id| order_id.0).collect(),
+    }))
+}
+
+/// Parses the client order ID of an order request, if it has one
+fn parse_client_order_id(data: &OrderRequest) -> Result<Option<ClientOrderId>, ApiError> {
+    let Some(client_order_id) = &data.client_order_id else {
+        return Ok(None);
+    };
+    ClientOrderId::parse(client_order_id).map(Some).ok_or_else(|| {
+        let message = format!("client_order_id must be 1 to {} printable ASCII characters", MAX_CLIENT_ORDER_ID_LEN);
+        ApiError::InvalidParameter(message)
+    })
+}
+
+/// Fails with DuplicateClientOrderId while the trader has a working order under the ID
+fn check_client_order_id(engine: &MatchingEngine, trader: [u8; 20], client_order_id: ClientOrderId) -> Result<(), ApiError> {
+    match engine.find_client_order(trader, client_order_id) {
+        Some(_) => Err(ApiError::DuplicateClientOrderId(client_order_id)),
+        None => Ok(()),
+    }
+}
+
+/// Logs and binds a checked client order ID to the order about to be submitted under `order_id`
+fn bind_client_order_id(
+    engine: &mut MatchingEngine,
+    order_id: OrderId,
+    trader: [u8; 20],
+    client_order_id: ClientOrderId,
+) -> Result<(), ApiError> {
+    engine.log(&WalCommand::BindClientOrderId { order_id: order_id.0, trader, client_order_id })?;
+    Ok(engine.bind_client_order_id(order_id, trader, client_order_id)?)
+}
+
+/// Gets the trader and client order ID a by-client-ID request is for
+/// The trader is the query's, which the caller must be allowed to act for, or else the trader
+/// the request was authenticated as.
+fn client_order_key(
+    client_order_id: &str,
+    query: &ClientOrderQuery,
+    identity: Identity,
+) -> Result<([u8; 20], ClientOrderId), ApiError> {
+    let client_order_id = ClientOrderId::parse(client_order_id).ok_or(ApiError::UnknownOrder)?;
+    let trader = match (&query.trader, identity) {
+        (Some(trader), _) => parse_trader(trader)?,
+        (None, Identity::Trader(trader)) => trader,
+        (None, _) => return Err(ApiError::InvalidParameter("trader is required".to_string())),
+    };
+    identity.authorize(Some(trader))?;
+    Ok((trader, client_order_id))
+}
+
+/// Checks the signature of an order request, asking the chain for contract wallets, and that
+/// the trader can pay for the order when funds are checked
+/// The price is read by the `scale` of the book's market into the book units the trader signed.
+/// Neither the intake nor the engine is locked during a call to the chain.
+async fn verify_order_request(state: &AppState, data: &OrderRequest, scale: PriceScale) -> Result<VerifiedOrder, ApiError> {
+    let submission = OrderSubmission {
+        book_id: data.book_id.clone(),
+        price: scale.read_signed(&data.price)?,
+        side: data.side,
+        quantity: data.quantity,
+        trader: data.trader.clone(),
+        nonce: data.nonce,
+        expiry: data.expiry,
+        signature: data.signature.clone(),
+    };
+    // Recovering the signer takes tens of microseconds, so it runs on the blocking pool,
+    // never on an I/O thread or the matching worker
+    let intake = state.order_intake.clone();
+    let verification = tokio::task::spawn_blocking(move || intake.blocking_read().verify_submission(submission))
+        .await
+        .map_err(|error| ApiError::Internal(error.to_string()))?;
+    let order = match verification? {
+        Verification::Verified(order) => order,
+        Verification::NeedsContractCheck(check) => {
+            verify_contract_signature(state, check.order(), check.order_hash()).await?;
+            check.confirmed()
+        }
+    };
+    let now = Clock::System.now() / 1_000_000_000;
+    if order.expiry().is_some_and(|expiry| expiry <= now) {
+        return Err(ApiError::OrderExpired);
+    }
+    check_funds(state, &order).await?;
+    Ok(order)
+}
+
+/// Checks that the trader of a verified order holds and has approved what it would pay if it
+/// filled, for books with a market configuration; see FundsChecker
+/// The engine is only locked to read the market, never across the calls to the chain. No
+/// answer from the node answers 503.
+async fn check_funds(state: &AppState, order: &Order) -> Result<(), ApiError> {
+    let Some(checker) = &state.funds_checker else {
+        return Ok(());
+    };
+    let config = state.lock_engine().await.market_manager.get_config(order.book_id()).cloned();
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let (trader, price) = (order.trader().unwrap_or_default(), order.price()); // Always set on submissions
+    Ok(checker.check(trader, &config, price.is_bid(), price.absolute() as u32, order.qty()).await??)
+}
+
+/// Logs and accepts a verified stop order, answering with its Untriggered status
+/// The signed price's sign gives the side and, on a stop-limit, its magnitude the limit.
+fn submit_stop_order(
+    engine: &mut MatchingEngine,
+    order_id: OrderId,
+    book_id: BookId,
+    order: &Order,
+    trigger: u32,
+    is_limit: bool,
+    client_order_id: Option<ClientOrderId>,
+) -> Result<OrderResponse, ApiError> {
+    let price = order.price();
+    let stop = StopOrder {
+        order_id: order_id.0,
+        book_id: book_id.value(),
+        qty: order.qty().value(),
+        is_bid: price.is_bid(),
+        trigger,
+        limit: is_limit.then_some(price.absolute() as u32),
+        trader: order.trader(),
+        nonce: order.nonce(),
+        expiry: order.expiry(),
+        signature: order.signature(),
+    };
+    engine.log(&WalCommand::SubmitStop(stop.clone()))?;
+    let (trader, nonce) = (order.trader().unwrap_or_default(), order.nonce().unwrap_or_default());
+    let _ = engine.nonces.consume(trader, nonce);
+    engine.submit_stop(stop)?;
+    tracing::info!(order_id = order_id.0, book_id = book_id.value(), trigger, "Stop order waiting for its trigger");
+    Ok(OrderResponse {
+        success: true,
+        message: "Stop order accepted".to_string(),
+        order_id: Some(order_id.0),
+        handle: None,
+        status: Some(OrderUpdate {
+            order_id: order_id.0,
+            book_id: book_id.value(),
+            trader,
+            status: OrderStatus::Untriggered,
+            filled_qty: 0,
+            remaining_qty: order.qty().value(),
+            client_order_id,
+            received_at: None,
+            filled_notional: 0,
+        }),
+        client_order_id,
+    })
+}
+
+/// Logs and accepts a verified pegged order, answering like a limit order
+/// The signed price's sign gives the side and its magnitude the limit the peg never goes past.
+fn submit_pegged_order(
+    engine: &mut MatchingEngine,
+    order_id: OrderId,
+    book_id: BookId,
+    order: &Order,
+    data: &OrderRequest,
+    client_order_id: Option<ClientOrderId>,
+) -> Result<OrderResponse, ApiError> {
+    let price = order.price();
+    let peg = PeggedOrder {
+        order_id: order_id.0,
+        book_id: book_id.value(),
+        qty: order.qty().value(),
+        is_bid: price.is_bid(),
+        limit: price.absolute() as u32,
+        peg: match data.order_type {
+            OrderType::PrimaryPeg => Peg::Primary { offset: data.peg_offset.unwrap_or_default() },
+            _ => Peg::Midpoint,
+        },
+        may_cross: data.allow_cross,
+        display: data.display_quantity,
+        trader: order.trader(),
+        nonce: order.nonce(),
+        expiry: order.expiry(),
+        signature: order.signature(),
+        price: None,
+    };
+    engine.log(&WalCommand::SubmitPegged(peg.clone()))?;
+    let (trader, nonce) = (order.trader().unwrap_or_default(), order.nonce().unwrap_or_default());
+    let _ = engine.nonces.consume(trader, nonce);
+    let (remaining, fills) = engine.submit_pegged(peg)?;
+    let filled = fills.iter().map(|fill| fill.exec_qty.value()).sum::<u64>();
+    tracing::info!(order_id = order_id.0, book_id = book_id.value(), price = ?engine.order_price(order_id), "Pegged order placed");
+    let handle = engine.orderbook_manager.oid_map.handle(order_id);
+    Ok(OrderResponse {
+        success: true,
+        message: "Pegged order submitted successfully".to_string(),
+        order_id: Some(order_id.0),
+        handle: handle.map(OrderHandle::to_u64),
+        status: Some(OrderUpdate { client_order_id, ..taker_status(engine, order_id, book_id, trader, filled, remaining) }),
+        client_order_id,
+    })
+}
+
+/// Asks a contract-wallet trader to confirm an order signature with EIP-1271
+/// A rejection answers 400 like any bad signature; no verdict from the node answers 503.
+async fn verify_contract_signature(state: &AppState, order: &Order, order_hash: [u8; 32]) -> Result<(), ApiError> {
+    let Some(verifier) = &state.signature_verifier else {
+        return Err(ApiError::Unavailable("No Ethereum RPC configured for contract wallet signatures".to_string()));
+    };
+    let (trader, signature) = (order.trader().unwrap_or_default(), order.signature());
+    match verifier.is_valid_signature(trader, order_hash, signature.as_bytes()).await? {
+        true => Ok(()),
+        false => Err(ApiError::InvalidSignature),
+    }
+}
+
+/// The depth of a book as the orderbook endpoint and market data snapshots return it
+impl OrderbookResponse {
+    fn new(depth: Depth, scale: PriceScale) -> Self {
+        let levels = |levels: Vec<(u32, u64, u32)>| -> Vec<PriceLevelResponse> {
+            levels
+                .into_iter()
+                .map(|(price, size, order_count)| PriceLevelResponse { price: scale.write(price), size, order_count })
+                .collect()
+        };
+        OrderbookResponse {
+            bids: levels(depth.bids),
+            asks: levels(depth.asks),
+            checksum: depth.checksum,
+            seq: depth.seq,
+        }
+    }
+}
+
+/// Add the new endpoint handler
+async fn get_orderbook(
+    book_id: web::Path<String>,
+    query: web::Query<OrderbookQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(book_depth(&state, &book_id, query.depth, query.group).await?))
+}
+
+/// Gets up to `depth` levels a side of a book, DEFAULT_DEPTH unless given
+/// With `group`, levels are merged into buckets that many book units wide, see group_levels, and
+/// `depth` counts buckets; the checksum is still that of the book's own levels.
+pub(crate) async fn book_depth(
+    state: &AppState,
+    book: &str,
+    depth: Option<usize>,
+    group: Option<u32>,
+) -> Result<OrderbookResponse, ApiError> {
+    // Check if book exists
+    let book_id = state.book_registry.get_book_id(book)?;
+
+    let depth = depth.unwrap_or(DEFAULT_DEPTH).min(MAX_DEPTH);
+
+    let engine = state.lock_engine().await;
+    let scale = engine.price_scale(book_id);
+    let manager = &engine.orderbook_manager;
+    let mut levels = manager.get_depth(book_id, depth).ok_or(ApiError::UnknownBook)?;
+    if let Some(group) = group {
+        if group == 0 || group % scale.tick_size != 0 {
+            let message = format!("group must be a positive multiple of the tick size {}", scale.tick_size);
+            return Err(ApiError::InvalidParameter(message));
+        }
+        levels.bids = group_levels(manager.iter_depth(book_id, true), group, true).take(depth).collect();
+        levels.asks = group_levels(manager.iter_depth(book_id, false), group, false).take(depth).collect();
+    }
+    Ok(OrderbookResponse::new(levels, scale))
+}
+
+/// Merges the levels of one side, best first, into buckets `group` book units wide
+/// A bucket is priced at the edge of its range away from the spread: bids round down to a multiple
+/// of `group` and asks up, so no bucket shows a better price than any level in it. Sizes and order
+/// counts add up, and buckets stay best first.
+fn group_levels(
+    levels: impl Iterator<Item = (u32, u64, u32)>,
+    group: u32,
+    is_bid: bool,
+) -> impl Iterator<Item = (u32, u64, u32)> {
+    let bucket = move |price: u32| match is_bid {
+        true => price / group * group,
+        false => price.div_ceil(group).saturating_mul(group),
+    };
+    let mut levels = levels.peekable();
+    std::iter::from_fn(move || {
+        let (price, mut size, mut order_count) = levels.next()?;
+        let price = bucket(price);
+        while let Some((_, more_size, more_orders)) = levels.next_if(|&(next, _, _)| bucket(next) == price) {
+            size += more_size;
+            order_count += more_orders;
+        }
+        Some((price, size, order_count))
+    })
+}
+
+/// Handler estimating how a taker order would fill against a book, without placing it
+async fn estimate_fill(
+    book_id: web::Path<String>,
+    query: web::Query<EstimateQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+    let is_bid = match query.side.as_str() {
+        "buy" => true,
+        "sell" => false,
+        _ => return Err(ApiError::InvalidParameter("Side must be buy or sell".to_string())),
+    };
+    if query.qty == 0 {
+        return Err(ApiError::InvalidQuantity);
+    }
+
+    let engine = state.lock_engine().await;
+    let estimate = engine.orderbook_manager.estimate_fill(book_id, is_bid, Qty(query.qty));
+    let scale = engine.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(EstimateResponse {
+        side: query.side.clone(),
+        requested_quantity: query.qty,
+        filled_quantity: estimate.filled_qty.value(),
+        vwap: estimate.vwap.map(|vwap| scale.to_f64(vwap)),
+        worst_price: estimate.worst_price.map(|price| scale.write(price)),
+        exhausted: estimate.exhausted,
+        levels: estimate
+            .levels
+            .into_iter()
+            .map(|(price, quantity)| FillResponse { price: scale.write(price), quantity, executed_at: None, improvement: None })
+            .collect(),
+    }))
+}
+
+/// Handler previewing what a limit order would do if it were submitted now, without placing it
+/// Takes the payload of a submission. The signature can be left out; one that is given is
+/// checked as on a submission. See MatchingEngine::match_preview.
+async fn preview_order(data: web::Json<OrderRequest>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&data.book_id)?;
+    if data.order_type != OrderType::Limit {
+        return Err(ApiError::InvalidParameter("Only limit orders can be previewed".to_string()));
+    }
+    if data.quantity == 0 {
+        return Err(ApiError::InvalidQuantity);
+    }
+    let scale = state.order_intake.read().await.price_scale(&data.book_id);
+    let submission = OrderSubmission {
+        book_id: data.book_id.clone(),
+        price: scale.read_signed(&data.price)?,
+        side: data.side,
+        quantity: data.quantity,
+        trader: data.trader.clone(),
+        nonce: data.nonce,
+        expiry: data.expiry,
+        signature: data.signature.clone(),
+    };
+    let price = submission.signed_price()?;
+    let trader = parse_trader(&data.trader)?;
+    if !data.signature.is_empty() {
+        verify_order_request(&state, &data, scale).await?;
+    }
+
+    let engine = state.lock_engine().await;
+    let preview = engine.match_preview(book_id, Qty(data.quantity), price.unsigned_abs(), price > 0, Some(trader), data.reduce_only)?;
+    let scale = engine.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(PreviewResponse {
+        fills: preview
+            .fills
+            .iter()
+            .map(|fill| PreviewFillResponse {
+                maker_order_id: fill.maker_order_id.0,
+                price: scale.write(fill.price),
+                quantity: fill.qty.value(),
+            })
+            .collect(),
+        filled_quantity: preview.filled_qty.value(),
+        average_price: preview.average_price.map(|price| scale.write(price)),
+        remaining_quantity: preview.remaining_qty.value(),
+        rests: preview.rests,
+    }))
+}
+
+/// Handler for the best bid/offer of a book
+async fn get_bbo(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let engine = state.lock_engine().await;
+    let manager = &engine.orderbook_manager;
+    let bid_price = manager.get_best_bid(book_id).map(|price| price.absolute() as u32);
+    let ask_price = manager.get_best_ask(book_id).map(|price| price.absolute() as u32);
+    let (spread, mid_price) = match (bid_price, ask_price) {
+        (Some(bid), Some(ask)) => (
+            ask.checked_sub(bid),
+            Some((f64::from(bid) + f64::from(ask)) / 2.0),
+        ),
+        _ => (None, None),
+    };
+    let scale = engine.price_scale(book_id);
+
+    Ok(HttpResponse::Ok().json(BboResponse {
+        bid_price: bid_price.map(|price| scale.write(price)),
+        bid_size: manager.get_best_bid_size(book_id).map(|qty| qty.value()),
+        ask_price: ask_price.map(|price| scale.write(price)),
+        ask_size: manager.get_best_ask_size(book_id).map(|qty| qty.value()),
+        spread: spread.map(|spread| scale.write(spread)),
+        mid_price: mid_price.map(|mid| scale.to_f64(mid)),
+    }))
+}
+
+/// Handler for a book's market configuration, including the EIP-712 domain orders are signed under
+async fn get_market(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let engine = state.lock_engine().await;
+    let config = engine.market_manager.get_config(book_id).ok_or(ApiError::UnknownMarket)?;
+    Ok(HttpResponse::Ok().json(config))
+}
+
+/// Handler listing every book's market configuration, in BookId order
+async fn list_markets(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    let markets = engine
+        .market_manager
+        .list_markets()
+        .into_iter()
+        .map(|(book_id, market)| MarketListing {
+            book_id: book_id.value(),
+            name: state.book_registry.resolve_name(book_id).unwrap_or_default(),
+            market: market.clone(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(ListMarketsResponse { markets }))
+}
+
+/// Handler for the most recent trades of a book, newest first
+async fn get_trades(
+    book_id: web::Path<String>,
+    query: web::Query<TradesQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES_LIMIT).min(MAX_TRADES_LIMIT);
+
+    let engine = state.lock_engine().await;
+    let trades = engine
+        .trade_tape(book_id)
+        .map(|tape| tape.recent(limit, query.before))
+        .unwrap_or_default();
+
+    let scale = engine.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(TradesResponse {
+        trades: trades.iter().map(|trade| TradeResponse::new(trade, scale)).collect(),
+    }))
+}
+
+/// Query parameters for the trade history endpoints; `from` and `to` are inclusive, in
+/// nanoseconds since the epoch, and trades come oldest first
+#[cfg(feature = "sqlite")]
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "sqlite")]
+impl HistoryQuery {
+    fn range(&self) -> HistoryRange {
+        HistoryRange { from: self.from, to: self.to, limit: self.limit.unwrap_or(DEFAULT_TRADES_LIMIT).min(MAX_TRADES_LIMIT) }
+    }
+}
+
+/// One of a trader's fills, from their side of it
+#[cfg(feature = "sqlite")]
+#[derive(Serialize, Deserialize)]
+pub struct TraderFillResponse {
+    trade_id: u64,
+    book: String,
+    order_id: u64, // The trader's order
+    timestamp: u64,
+    price: ApiPrice,
+    quantity: u64,
+    side: String, // The trader's side, "buy" or "sell"
+    liquidity: String, // "maker" or "taker"
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(Serialize, Deserialize)]
+pub struct TraderFillsResponse {
+    fills: Vec<TraderFillResponse>,
+}
+
+/// Gets the trade history, or Unavailable if it isn't kept
+#[cfg(feature = "sqlite")]
+fn trade_history(history: Option<web::Data<TradeHistory>>) -> Result<web::Data<TradeHistory>, ApiError> {
+    history.ok_or_else(|| ApiError::Unavailable("Trade history is not kept".to_string()))
+}
+
+/// Handler for the trades of a book kept in the trade history, past the reach of its tape
+#[cfg(feature = "sqlite")]
+async fn get_trade_history(
+    book_id: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+    state: web::Data<AppState>,
+    history: Option<web::Data<TradeHistory>>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+    let history = trade_history(history)?;
+    let range = query.range();
+    let trades = web::block(move || history.trades(book_id, range))
+        .await
+        .map_err(|error| ApiError::Internal(error.to_string()))?
+        .map_err(|error| ApiError::Internal(format!("Cannot read the trade history: {}", error)))?;
+
+    let scale = state.lock_engine().await.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(TradesResponse {
+        trades: trades.iter().map(|stored| TradeResponse::new(&stored.trade, scale)).collect(),
+    }))
+}
+
+/// Handler for a trader's fills kept in the trade history, in every book
+#[cfg(feature = "sqlite")]
+async fn get_trader_fills(
+    address: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+    state: web::Data<AppState>,
+    history: Option<web::Data<TradeHistory>>,
+) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    let history = trade_history(history)?;
+    let range = query.range();
+    let trades = web::block(move || history.trader_fills(trader, range))
+        .await
+        .map_err(|error| ApiError::Internal(error.to_string()))?
+        .map_err(|error| ApiError::Internal(format!("Cannot read the trade history: {}", error)))?;
+
+    let engine = state.lock_engine().await;
+    let fills = trades
+        .iter()
+        .map(|stored| {
+            let trade = &stored.trade;
+            // A trader trading with themself is reported as the taker
+            let is_taker = stored.taker_trader == Some(trader);
+            let is_buy = trade.aggressor_is_bid == is_taker;
+            TraderFillResponse {
+                trade_id: trade.trade_id,
+                book: state.book_registry.resolve_name(stored.book_id).unwrap_or_default(),
+                order_id: if is_taker { trade.taker_order_id.0 } else { trade.maker_order_id.0 },
+                timestamp: trade.timestamp,
+                price: engine.price_scale(stored.book_id).write(trade.price),
+                quantity: trade.qty.value(),
+                side: if is_buy { "buy" } else { "sell" }.to_string(),
+                liquidity: if is_taker { "taker" } else { "maker" }.to_string(),
+            }
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(TraderFillsResponse { fills }))
+}
+
+/// Handler for the rolling trade statistics of a book
+async fn get_stats(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let engine = state.lock_engine().await;
+    let now = engine.clock.now();
+    let stats = engine.stats().get(book_id);
+    let scale = engine.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(StatsResponse {
+        last_price: stats.and_then(|stats| stats.last_price()).map(|price| scale.write(price)),
+        volume_24h: stats.map(|stats| stats.volume(now)),
+        high: stats.and_then(|stats| stats.high()).map(|price| scale.write(price)),
+        low: stats.and_then(|stats| stats.low()).map(|price| scale.write(price)),
+        trade_count: stats.map(|stats| stats.trade_count()),
+        average_improvement_ticks: stats.and_then(|stats| stats.average_improvement()),
+    }))
+}
+
+/// Handler for the OHLCV candles of a book
+async fn get_candles(
+    book_id: web::Path<String>,
+    query: web::Query<CandlesQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+    let Some(interval) = CandleInterval::parse(query.interval.as_deref().unwrap_or("1m")) else {
+        return Err(ApiError::InvalidParameter("Interval must be 1m, 5m or 1h".to_string()));
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_CANDLES_LIMIT).min(CANDLE_HISTORY_CAPACITY);
+
+    let engine = state.lock_engine().await;
+    let now = engine.clock.now();
+    let candles = engine
+        .candles()
+        .get(book_id, interval)
+        .map(|builder| builder.candles(limit, query.fill.unwrap_or(false), now))
+        .unwrap_or_default();
+
+    let scale = engine.price_scale(book_id);
+    Ok(HttpResponse::Ok().json(CandlesResponse {
+        interval: interval.name().to_string(),
+        candles: candles.iter().map(|candle| CandleResponse::new(candle, scale)).collect(),
+    }))
+}
+
+/// Handler for tracked settlements, newest first
+async fn list_settlements(
+    query: web::Query<SettlementsQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_TRADES_LIMIT).min(MAX_TRADES_LIMIT);
+
+    let engine = state.lock_engine().await;
+    let settlements = engine
+        .settlements
+        .settlements()
+        .rev()
+        .filter(|settlement| query.status.as_deref().is_none_or(|status| settlement.status.name() == status))
+        .take(limit)
+        .cloned()
+        .collect();
+
+    Ok(HttpResponse::Ok().json(SettlementsResponse { settlements }))
+}
+
+/// Handler for a single settlement and its status
+async fn get_settlement(
+    settlement_id: web::Path<u64>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    let settlement = engine.settlements.get(*settlement_id).ok_or(ApiError::UnknownSettlement)?;
+    Ok(HttpResponse::Ok().json(settlement))
+}
+
+/// Handler for a settlement batch and its status
+async fn get_settlement_batch(
+    batch_id: web::Path<u64>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    let batch = engine.settlements.batch(*batch_id).ok_or(ApiError::UnknownBatch)?;
+    Ok(HttpResponse::Ok().json(batch))
+}
+
+/// Query parameters for the export endpoints; `from` and `to` are inclusive, in nanoseconds
+/// since the epoch, and `format` may only be csv
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+    format: Option<String>,
+}
+
+impl ExportQuery {
+    fn check_format(&self) -> Result<(), ApiError> {
+        match self.format.as_deref() {
+            None | Some("csv") => Ok(()),
+            Some(format) => Err(ApiError::InvalidParameter(format!("Unknown export format {}, expected csv", format))),
+        }
+    }
+
+    fn contains(&self, timestamp: u64) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp <= to)
+    }
+}
+
+/// Body of an export, sent with chunked transfer as its producer writes it
+struct ExportBody {
+    chunks: mpsc::Receiver<io::Result<web::Bytes>>,
+}
+
+impl MessageBody for ExportBody {
+    type Error = io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        self.chunks.poll_recv(cx)
+    }
+}
+
+/// Hands what an export's CsvWriter writes to its ExportBody, waiting while the client is behind
+/// Writes fail once the client has gone, which ends the export.
+struct ExportChunks(mpsc::Sender<io::Result<web::Bytes>>);
+
+impl io::Write for ExportChunks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(web::Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The client has gone"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams a CSV file of `columns`, whose rows `produce` writes on a blocking thread
+/// A failure part way through aborts the response, so the client sees a truncated transfer
+/// rather than a file that looks whole.
+fn stream_csv<F>(columns: &'static [&'static str], file_name: String, produce: F) -> HttpResponse
+where
+    F: FnOnce(&mut CsvWriter<ExportChunks>) -> io::Result<()> + Send + 'static,
+{
+    let disposition = format!("attachment; filename=\"{}\"", file_name);
+    let (sender, chunks) = mpsc::channel(EXPORT_CHUNKS_IN_FLIGHT);
+    let errors = sender.clone();
+    tokio::task::spawn_blocking(move || {
+        let exported = CsvWriter::new(ExportChunks(sender), columns).and_then(|mut csv| {
+            produce(&mut csv)?;
+            csv.finish().map(drop)
+        });
+        if let Err(error) = exported {
+            tracing::warn!(file_name, %error, "Export ended early");
+            let _ = errors.blocking_send(Err(error));
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((header::CONTENT_DISPOSITION, disposition))
+        .body(ExportBody { chunks })
+}
+
+/// Handler for a CSV export of a book's trades, oldest first, with the columns of TRADE_COLUMNS
+/// Trades come from the trade history when it is kept, and from the book's tape otherwise.
+async fn export_trades(
+    book: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    state: web::Data<AppState>,
+    #[cfg(feature = "sqlite")] history: Option<web::Data<TradeHistory>>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book)?;
+    query.check_format()?;
+    let (book, query, engine) = (book.into_inner(), query.into_inner(), state.engine.clone());
+
+    Ok(stream_csv(&TRADE_COLUMNS, format!("{}-trades.csv", book), move |csv| {
+        let scale = engine.blocking_lock().price_scale(book_id);
+        #[cfg(feature = "sqlite")]
+        if let Some(history) = history {
+            let range = HistoryRange { from: query.from, to: query.to, limit: EXPORT_PAGE_ROWS };
+            let mut after = None;
+            loop {
+                let page = history.trades_after(book_id, after, range).map_err(io::Error::other)?;
+                for stored in &page {
+                    csv.write_row(trade_row(&book, &stored.trade, (stored.maker_trader, stored.taker_trader), scale))?;
+                }
+                match page.last() {
+                    Some(last) if page.len() == EXPORT_PAGE_ROWS => after = Some(last.trade.trade_id),
+                    _ => return Ok(()),
+                }
+            }
+        }
+        // The tape is bounded, so it is copied out whole
+        let trades: Vec<Trade> = engine
+            .blocking_lock()
+            .trade_tape(book_id)
+            .map(|tape| tape.iter_newest().filter(|trade| query.contains(trade.timestamp)).copied().collect())
+            .unwrap_or_default();
+        for trade in trades.iter().rev() {
+            csv.write_row(trade_row(&book, trade, (None, None), scale))?;
+        }
+        Ok(())
+    }))
+}
+
+/// Handler for a CSV export of the tracked settlements of a book created in the range, in ID
+/// order, with the columns of SETTLEMENT_COLUMNS
+async fn export_settlements(
+    book: web::Path<String>,
+    query: web::Query<ExportQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book)?;
+    query.check_format()?;
+    let (book, query, engine) = (book.into_inner(), query.into_inner(), state.engine.clone());
+
+    Ok(stream_csv(&SETTLEMENT_COLUMNS, format!("{}-settlements.csv", book), move |csv| {
+        let mut after = None;
+        loop {
+            let (page, market) = {
+                let engine = engine.blocking_lock();
+                let page: Vec<TrackedSettlement> = engine
+                    .settlements
+                    .settlements_after(after)
+                    .filter(|settlement| settlement.book_id == book_id.value() && query.contains(settlement.created_at))
+                    .take(EXPORT_PAGE_ROWS)
+                    .cloned()
+                    .collect();
+                (page, engine.market_manager.get_config(book_id).cloned().unwrap_or_default())
+            };
+            for settlement in &page {
+                csv.write_row(settlement_row(&book, settlement, &market))?;
+            }
+            match page.last() {
+                Some(last) if page.len() == EXPORT_PAGE_ROWS => after = Some(last.settlement_id),
+                _ => return Ok(()),
+            }
+        }
+    }))
+}
+
+/// Handler for canceling a resting order
+async fn cancel_order(
+    order_id: web::Path<u64>,
+    query: web::Query<CancelOrderQuery>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let response = cancel_owned_order(&state, OrderId(order_id.into_inner()), query.handle, identity).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Cancels a working order, if `identity` may act for its trader
+pub(crate) async fn cancel_owned_order(
+    state: &AppState,
+    order_id: OrderId,
+    handle: Option<u64>,
+    identity: Identity,
+) -> Result<OrderResponse, ApiError> {
+    state
+        .commands
+        .run(Lane::Cancel, move |engine| {
+            // Traders may only cancel their own orders
+            if let Some(owner) = engine.order_owner(order_id) {
+                identity.authorize(owner)?;
+            }
+            cancel_working_order(engine, order_id, handle)
+        })
+        .await?
+}
+
+/// Handler for canceling a working order by its trader's client order ID
+async fn cancel_order_by_client_id(
+    client_order_id: web::Path<String>,
+    query: web::Query<ClientOrderQuery>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (trader, client_order_id) = client_order_key(&client_order_id, &query, identity)?;
+
+    let response = state
+        .commands
+        .run(Lane::Cancel, move |engine| {
+            let order_id = engine.find_client_order(trader, client_order_id).ok_or(ApiError::UnknownOrder)?;
+            cancel_working_order(engine, order_id, None)
+        })
+        .await??;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Cancels a working order the caller may cancel: a stop by ID alone, otherwise a resting, parked,
+/// or waiting order, only if `handle` still refers to it when given
+fn cancel_working_order(engine: &mut MatchingEngine, order_id: OrderId, handle: Option<u64>) -> Result<OrderResponse, ApiError> {
+    let client_order_id = engine.orderbook_manager.client_order_ids.get(order_id);
+    // Stops waiting for their trigger have no handle; they are cancelled by ID alone
+    if handle.is_none() && engine.stops().get(order_id).is_some() {
+        engine.log(&WalCommand::CancelStop { order_id: order_id.0 })?;
+        engine.cancel_stop(order_id)?;
+        tracing::info!(order_id = order_id.0, "Stop order cancelled");
+        return Ok(OrderResponse {
+            success: true,
+            message: "Stop order cancelled successfully".to_string(),
+            order_id: Some(order_id.0),
+            handle: None,
+            status: None,
+            client_order_id,
+        });
+    }
+    // Unknown orders, and orders the handle no longer refers to, are refused before anything is logged
+    let known = match handle {
+        Some(handle) => engine.orderbook_manager.resolve_handle(OrderHandle::from_u64(handle)) == Ok(order_id),
+        None => {
+            engine.orderbook_manager.oid_map.get(order_id).is_some()
+                || engine.pegs().is_parked(order_id)
+                || engine.is_continuing(order_id)
+        }
+    };
+    if !known {
+        return Err(ApiError::UnknownOrder);
+    }
+    engine.log(&WalCommand::Remove { order_id: order_id.0 })?;
+    engine.cancel_resting(order_id, OrderStatus::Cancelled)?;
+    tracing::info!(order_id = order_id.0, "Order cancelled");
+
+    Ok(OrderResponse {
+        success: true,
+        message: "Order cancelled successfully".to_string(),
+        order_id: Some(order_id.0),
+        handle: None,
+        status: None,
+        client_order_id,
+    })
+}
+
+/// Handler for the status of an order that is still working: resting, parked, or a stop waiting for its trigger
+async fn get_order_status(order_id: web::Path<u64>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    Ok(HttpResponse::Ok().json(order_status(&engine, OrderId(order_id.into_inner()))?))
+}
+
+/// Handler for the status of a working order, found by its trader's client order ID
+async fn get_order_by_client_id(
+    client_order_id: web::Path<String>,
+    query: web::Query<ClientOrderQuery>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (trader, client_order_id) = client_order_key(&client_order_id, &query, identity)?;
+
+    let engine = state.lock_engine().await;
+    let order_id = engine.find_client_order(trader, client_order_id).ok_or(ApiError::UnknownOrder)?;
+    Ok(HttpResponse::Ok().json(order_status(&engine, order_id)?))
+}
+
+/// Gets the status of a working order, or UnknownOrder
+pub(crate) fn order_status(engine: &MatchingEngine, order_id: OrderId) -> Result<OrderStatusResponse, ApiError> {
+    let (status, remaining) = engine.order_status(order_id).ok_or(ApiError::UnknownOrder)?;
+    // Only an order on a book has a price; a parked peg or a stop has none yet
+    let price = engine
+        .orderbook_manager
+        .oid_map
+        .get(order_id)
+        .map(|order| engine.price_scale(order.book_id()).write(order.price().absolute() as u32));
+    let order_id = order_id.0;
+    Ok(OrderStatusResponse {
+        success: true,
+        message: "Order is working".to_string(),
+        order_id,
+        status: Some(status),
+        remaining_quantity: remaining.value(),
+        price,
+        oco: engine.oco().group_of(OrderId(order_id)).map(|group| OcoLink {
+            group_id: group.group_id,
+            sibling_order_id: group.sibling(OrderId(order_id)).0,
+        }),
+        client_order_id: engine.orderbook_manager.client_order_ids.get(OrderId(order_id)),
+        received_at: engine
+            .orderbook_manager
+            .oid_map
+            .meta(OrderId(order_id))
+            .map(|meta| meta.received_at)
+            .filter(|received_at| *received_at > 0),
+    })
+}
+
+/// Handler for atomically replacing a resting order with a new price and quantity
+async fn replace_order(
+    order_id: web::Path<u64>,
+    data: web::Json<ReplaceOrderRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let data = data.into_inner();
+    let response = replace_owned_order(&state, OrderId(order_id.into_inner()), data.price, data.quantity, identity).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Replaces a resting order, if `identity` may act for its trader
+pub(crate) async fn replace_owned_order(
+    state: &AppState,
+    order_id: OrderId,
+    price: ApiPrice,
+    quantity: u64,
+    identity: Identity,
+) -> Result<ReplaceOrderResponse, ApiError> {
+    if price == ApiPrice::Units(0) {
+        return Err(ApiError::InvalidPrice);
+    }
+    if quantity == 0 {
+        return Err(ApiError::InvalidQuantity);
+    }
+
+    // The cancel and the re-submission run as one command, with nothing in between.
+    state
+        .commands
+        .run(Lane::New, move |engine| enter_replace(engine, order_id, price, quantity, identity))
+        .await?
+}
+
+/// Replaces a resting order the caller owns, run by the matching worker
+/// The new price is read in the decimal places and ticks of the order's market.
+fn enter_replace(
+    engine: &mut MatchingEngine,
+    order_id: OrderId,
+    price: ApiPrice,
+    quantity: u64,
+    identity: Identity,
+) -> Result<ReplaceOrderResponse, ApiError> {
+    if let Some(owner) = engine.order_owner(order_id) {
+        identity.authorize(owner)?;
+    }
+    let book_id = engine.orderbook_manager.oid_map.get(order_id).map(|order| order.book_id());
+    let scale = book_id.map(|book_id| engine.price_scale(book_id)).unwrap_or_default();
+    let price = scale.read(&price)?;
+    if price == 0 {
+        return Err(ApiError::InvalidPrice);
+    }
+    if let Some(book_id) = book_id {
+        engine.check_size_rules(book_id, price, Qty(quantity))?;
+    }
+    engine.check_accepting()?;
+    let new_order_id = engine.next_order_id();
+    let owner = engine
+        .orderbook_manager
+        .oid_map
+        .get(order_id)
+        .and_then(|order| Some((order.book_id(), order.trader()?)));
+    // The replacement takes the old order's place against the limits, so only added notional counts
+    if let Some((book_id, trader)) = owner {
+        let added = notional(price, Qty(quantity))
+            .saturating_sub(engine.orderbook_manager.open_orders.notional(order_id));
+        engine.check_risk_limits(book_id, trader, 0, added)?;
+    }
+    let command = WalCommand::Replace {
+        order_id: order_id.0,
+        new_order_id: new_order_id.0,
+        new_qty: quantity,
+        new_price: price,
+    };
+    engine.log(&command)?;
+    let client_order_id = engine.orderbook_manager.client_order_ids.get(order_id);
+    let (remaining, matches) = engine.replace_order(order_id, new_order_id, Qty(quantity), price)?;
+    tracing::info!(order_id = order_id.0, new_order_id = new_order_id.0, qty = quantity, price, "Order replaced");
+    let filled = matches.iter().map(|fill| fill.exec_qty.value()).sum::<u64>();
+    let status = owner.map(|(book_id, trader)| {
+        OrderUpdate { client_order_id, ..taker_status(engine, new_order_id, book_id, trader, filled, remaining) }
+    });
+
+    Ok(ReplaceOrderResponse {
+        success: true,
+        message: "Order replaced successfully".to_string(),
+        order_id: Some(new_order_id.0),
+        remaining_quantity: remaining.value(),
+        fills: matches.iter().map(|fill| FillResponse::new(fill, scale)).collect(),
+        status,
+    })
+}
+
+/// Handler for cancelling every resting order of a trader
+async fn cancel_all_orders(
+    address: web::Path<String>,
+    query: web::Query<CancelAllQuery>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    identity.authorize(Some(trader))?;
+    let book_id = match &query.book_id {
+        Some(name) => Some(state.book_registry.get_book_id(name)?),
+        None => None,
+    };
+
+    let cancelled = state
+        .commands
+        .run(Lane::Cancel, move |engine| {
+            let command = WalCommand::CancelAll {
+                trader,
+                book_id: book_id.map(|book_id| book_id.value()),
+            };
+            engine.log(&command)?;
+            Ok::<_, ApiError>(engine.cancel_all_for_trader(trader, book_id))
+        })
+        .await??;
+    tracing::info!(trader = %address, cancelled = cancelled.len(), "Cancelled all orders of trader");
+
+    Ok(HttpResponse::Ok().json(CancelAllResponse {
+        success: true,
+        message: format!("Cancelled {} orders", cancelled.len()),
+        cancelled: cancelled.into_iter().map(|order_id| order_id.0).collect(),
+    }))
+}
+
+/// Handler asking the registered makers for quotes on a block too big for the book
+/// Makers are told on their private streams; the RFQ takes quotes until its expiry.
+async fn create_rfq(
+    data: web::Json<CreateRfqRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&data.book_id)?;
+    let trader = parse_trader(&data.trader)?;
+    identity.authorize(Some(trader))?;
+    if data.quantity == 0 {
+        return Err(ApiError::InvalidQuantity);
+    }
+    let mut engine = state.lock_engine().await;
+    engine.check_accepting()?;
+    let now = engine.clock.now() / 1_000_000_000;
+    let is_bid = data.side == Side::Buy;
+    let scale = engine.price_scale(book_id);
+    let rfq = engine.rfqs.create(book_id, is_bid, Qty(data.quantity), trader, data.expiry, now)?;
+    tracing::info!(rfq_id = rfq.rfq_id, book_id = %data.book_id, qty = data.quantity, "RFQ opened");
+    Ok(HttpResponse::Ok().json(RfqResponse::new(rfq, data.book_id.clone(), scale)))
+}
+
+/// Handler listing the RFQs kept, oldest first, optionally only those in one state
+async fn list_rfqs(query: web::Query<RfqsQuery>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    let rfqs = engine
+        .rfqs
+        .iter()
+        .filter(|rfq| query.state.is_none_or(|wanted| rfq.state == wanted))
+        .map(|rfq| {
+            let book = state.book_registry.resolve_name(rfq.book_id).unwrap_or_default();
+            RfqResponse::new(rfq, book, engine.price_scale(rfq.book_id))
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(ListRfqsResponse { rfqs }))
+}
+
+async fn get_rfq(rfq_id: web::Path<u64>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let engine = state.lock_engine().await;
+    let rfq = engine.rfqs.get(*rfq_id).ok_or(ApiError::UnknownRfq)?;
+    let book = state.book_registry.resolve_name(rfq.book_id).unwrap_or_default();
+    Ok(HttpResponse::Ok().json(RfqResponse::new(rfq, book, engine.price_scale(rfq.book_id))))
+}
+
+/// Handler for a registered maker's quote: its order, signed as any order is, on the other
+/// side of the RFQ for the whole quantity
+/// The signature and the funds behind it are checked as on a submission; see RfqManager::quote.
+async fn quote_rfq(
+    rfq_id: web::Path<u64>,
+    data: web::Json<OrderRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    state.book_registry.get_book_id(&data.book_id)?;
+    let scale = state.order_intake.read().await.price_scale(&data.book_id);
+    let order = verify_order_request(&state, &data, scale).await?;
+    identity.authorize(order.trader())?;
+    let mut engine = state.lock_engine().await;
+    engine.nonces.check(order.trader().unwrap_or_default(), order.nonce().unwrap_or_default())?;
+    let now = engine.clock.now() / 1_000_000_000;
+    let quote = engine.rfqs.quote(*rfq_id, order.into_order(), now)?;
+    tracing::info!(rfq_id = *rfq_id, quote_id = quote.quote_id, price = quote.price, "RFQ quoted");
+    Ok(HttpResponse::Ok().json(QuoteResponse::new(quote, scale)))
+}
+
+/// Handler accepting a quote with the requester's order, signed as any order is, at the quote's
+/// price for the whole block
+/// The two signed orders trade directly, off the book, and settle like any fill; see
+/// MatchingEngine::execute_block. A quote whose expiry has passed, or whose maker has since used
+/// or invalidated its nonce, is refused.
+async fn accept_quote(
+    path: web::Path<(u64, u64)>,
+    data: web::Json<OrderRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (rfq_id, quote_id) = path.into_inner();
+    state.book_registry.get_book_id(&data.book_id)?;
+    let scale = state.order_intake.read().await.price_scale(&data.book_id);
+    let order = verify_order_request(&state, &data, scale).await?;
+    identity.authorize(order.trader())?;
+
+    let mut engine = state.lock_engine().await;
+    engine.check_accepting()?;
+    let now = engine.clock.now() / 1_000_000_000;
+    let maker = engine.rfqs.check_accept(rfq_id, quote_id, &order, now)?.order.clone();
+    for side in [&maker, &*order] {
+        engine.nonces.check(side.trader().unwrap_or_default(), side.nonce().unwrap_or_default())?;
+    }
+    let (maker_order_id, taker_order_id) = (engine.next_order_id(), engine.next_order_id());
+    let sides = BlockOrder::new(maker_order_id, &maker).zip(BlockOrder::new(taker_order_id, &order));
+    let (maker_side, taker_side) = sides.ok_or_else(|| ApiError::Internal("Signed order without a trader or nonce".to_string()))?;
+    let block = BlockTrade {
+        book_id: order.book_id().value(),
+        qty: order.qty().value(),
+        price: order.price().absolute() as u32,
+        maker: maker_side,
+        taker: taker_side,
+    };
+    engine.log(&WalCommand::BlockTrade(block.clone()))?;
+    for side in [&block.maker, &block.taker] {
+        let _ = engine.nonces.consume(side.trader, side.nonce);
+    }
+    let fill = engine.execute_block(&block)?;
+    engine.rfqs.accepted(rfq_id, quote_id, fill.trade_id);
+    drop(engine);
+    if let Some(checker) = &state.funds_checker {
+        checker.invalidate(block.maker.trader);
+        checker.invalidate(block.taker.trader);
+    }
+    tracing::info!(rfq_id, quote_id, trade_id = fill.trade_id, qty = block.qty, price = block.price, "RFQ quote accepted");
+
+    Ok(HttpResponse::Ok().json(BlockTradeResponse {
+        rfq_id,
+        quote_id,
+        trade_id: fill.trade_id,
+        maker_order_id: maker_order_id.0,
+        taker_order_id: taker_order_id.0,
+        price: scale.write(block.price),
+        quantity: block.qty,
+        settlement_id: fill.settlement_id,
+    }))
+}
+
+/// Handler registering a maker to be asked for quotes on new RFQs
+async fn register_rfq_maker(address: web::Path<String>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let maker = parse_trader(&address)?;
+    let added = state.lock_engine().await.rfqs.register_maker(maker);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "success": true, "registered": added })))
+}
+
+/// Handler removing a maker from those asked for quotes; its quotes already given stand
+async fn unregister_rfq_maker(address: web::Path<String>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let maker = parse_trader(&address)?;
+    let removed = state.lock_engine().await.rfqs.unregister_maker(maker);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "success": true, "unregistered": removed })))
+}
+
+/// Handler for the open positions of a trader, in the books whose market tracks them
+async fn get_positions(address: web::Path<String>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    let positions = state.lock_engine().await.positions().trader_positions(trader);
+    let positions: Vec<PositionEntry> = positions
+        .into_iter()
+        .map(|position| PositionEntry {
+            book: state.book_registry.resolve_name(BookId(position.book_id)).unwrap_or_default(),
+            book_id: position.book_id,
+            qty: position.qty,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PositionsResponse {
+        success: true,
+        message: format!("{} open positions", positions.len()),
+        positions,
+    }))
+}
+
+/// Handler for a trader's maker and taker volume, fees and rebates per book per day
+async fn get_trader_volume(
+    address: web::Path<String>,
+    query: web::Query<VolumeQuery>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    let (from, to) = query.days()?;
+    let entries = state.ledger.lock().trader_days(trader, from, to);
+    let mut totals = VolumeTotals::default();
+    let days = entries
+        .into_iter()
+        .map(|entry| {
+            totals.add(&entry.totals);
+            VolumeDayEntry {
+                book: state.book_registry.resolve_name(entry.book_id).unwrap_or_default(),
+                book_id: entry.book_id.value(),
+                day: entry.day,
+                totals: entry.totals,
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(TraderVolumeResponse { trader: format!("0x{}", hex::encode(trader)), days, totals }))
+}
+
+/// Admin handler rolling the volume ledger up per trader per book over a range of days, for
+/// invoicing and incentive tiers
+async fn get_volume_roll_up(query: web::Query<VolumeQuery>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let (from, to) = query.days()?;
+    let sums = state.ledger.lock().roll_up(from, to);
+    let mut totals = VolumeTotals::default();
+    let entries = sums
+        .into_iter()
+        .map(|entry| {
+            totals.add(&entry.totals);
+            VolumeRollUpEntry {
+                trader: format!("0x{}", hex::encode(entry.trader)),
+                book: state.book_registry.resolve_name(entry.book_id).unwrap_or_default(),
+                book_id: entry.book_id.value(),
+                totals: entry.totals,
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(VolumeRollUpResponse { entries, totals }))
+}
+
+/// Handler for what a trader has resting in each book, against the risk limits of its market
+async fn get_risk_usage(address: web::Path<String>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    let engine = state.lock_engine().await;
+    let books: Vec<RiskUsageEntry> = engine
+        .orderbook_manager
+        .open_orders
+        .trader_usage(trader)
+        .into_iter()
+        .map(|(book_id, usage)| RiskUsageEntry {
+            book: state.book_registry.resolve_name(book_id).unwrap_or_default(),
+            book_id: book_id.value(),
+            usage,
+            limits: engine.market_manager.get_config(book_id).map(|config| config.risk_limits),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(RiskUsageResponse {
+        success: true,
+        message: format!("Orders resting in {} books", books.len()),
+        books,
+    }))
+}
+
+/// The message a trader signs with `personal_sign` to raise their minimum nonce
+fn nonce_bump_message(trader: [u8; 20], min_nonce: u64) -> String {
+    format!("Numena nonce bump\nAddress: 0x{}\nMin nonce: {}", hex::encode(trader), min_nonce)
+}
+
+/// Handler for a nonce bump: invalidates every nonce of the trader below `min_nonce`
+/// and cancels their resting orders signed with one, in every book.
+async fn bump_nonce(
+    address: web::Path<String>,
+    data: web::Json<NonceBumpRequest>,
+    identity: Identity,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let trader = parse_trader(&address)?;
+    identity.authorize(Some(trader))?;
+    let signature = hex::decode(data.signature.trim_start_matches("0x")).unwrap_or_default();
+    verify_signer(nonce_bump_message(trader, data.min_nonce).as_bytes(), &signature, trader)
+        .map_err(ApiError::Unauthorized)?;
+
+    let requested = data.min_nonce;
+    let (cancelled, min_nonce) = state
+        .commands
+        .run(Lane::Cancel, move |engine| {
+            engine.log(&WalCommand::BumpNonce { trader, min_nonce: requested })?;
+            let cancelled = engine.bump_nonce(trader, requested);
+            Ok::<_, ApiError>((cancelled, engine.nonces.min_nonce(trader)))
+        })
+        .await??;
+    tracing::info!(trader = %address, min_nonce = data.min_nonce, cancelled = cancelled.len(), "Bumped nonce of trader");
+
+    Ok(HttpResponse::Ok().json(NonceBumpResponse {
+        success: true,
+        message: format!("Cancelled {} orders", cancelled.len()),
+        min_nonce: Some(min_nonce),
+        cancelled: cancelled.into_iter().map(|order_id| order_id.0).collect(),
+    }))
+}
+
+/// Handler for the market data stream of a book
+/// Sends a full depth snapshot, then level updates and trades tagged with the book's sequence number.
+/// Subscribers that fall behind the broadcast channel are disconnected and should resubscribe.
+async fn book_stream(
+    req: HttpRequest,
+    body: web::Payload,
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (book_id, mut events, depth, scale) = subscribe_book(&state, &book_id).await?;
+    let snapshot = BookSnapshotMessage {
+        kind: "snapshot",
+        book_id: book_id.value(),
+        seq: depth.seq,
+        bids: depth.bids,
+        asks: depth.asks,
+        checksum: depth.checksum,
+    };
+
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    let mut shutdown = state.shutdown.subscribe();
+
+    actix_web::rt::spawn(async move {
+        if let Ok(text) = serde_json::to_string(&snapshot) {
+            if session.text(text).await.is_err() {
+                return;
+            }
+        }
+
+        let close_reason = loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if event.book_id() == book_id => {
+                        let Ok(text) = market_data_text(&event, scale) else { continue };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        break Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("Subscriber lagged behind market data".to_string()),
+                        });
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break None,
+                },
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                    Some(Ok(_)) => {}
+                },
+                _ = shutdown.wait_for(|down| *down) => {
+                    break Some(CloseReason {
+                        code: CloseCode::Away,
+                        description: Some("Server shutting down".to_string()),
+                    });
+                }
+            }
+        };
+
+        let _ = session.close(close_reason).await;
+    });
+
+    Ok(response)
+}
+
+/// Subscribes to the market data of a book, along with its full depth as of the subscription
+pub(crate) async fn subscribe_book(
+    state: &AppState,
+    book: &str,
+) -> Result<(BookId, broadcast::Receiver<MarketDataEvent>, OrderbookResponse, PriceScale), ApiError> {
+    let book_id = state.book_registry.get_book_id(book)?;
+
+    // Subscribe and snapshot under the same lock so no event falls between them
+    let engine = state.lock_engine().await;
+    let events = engine.orderbook_manager.market_data.subscribe();
+    let scale = engine.price_scale(book_id);
+    let depth = engine.orderbook_manager.get_depth(book_id, MAX_DEPTH).unwrap_or_default();
+    Ok((book_id, events, OrderbookResponse::new(depth, scale), scale))
+}
+
+/// Writes a market data event for a subscriber, with its price written the way the book's market
+/// writes prices
+fn market_data_text(event: &MarketDataEvent, scale: PriceScale) -> serde_json::Result<String> {
+    let mut value = serde_json::to_value(event)?;
+    if let Some(price) = value.get_mut("price") {
+        if let Some(units) = price.as_u64() {
+            *price = serde_json::to_value(scale.write(units as u32))?;
+        }
+    }
+    serde_json::to_string(&value)
+}
+
+/// Handler for the private order update stream of a trader
+/// The trader proves ownership of the address by signing a one-time challenge,
+/// then receives updates for every order they own across all books.
+async fn trader_stream(
+    req: HttpRequest,
+    body: web::Payload,
+    address: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let address = address.into_inner();
+    let trader = parse_trader(&address).map_err(ApiError::from)?;
+
+    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    let mut shutdown = state.shutdown.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let challenge = format!(
+            "Numena order stream login\nAddress: 0x{}\nChallenge: 0x{}",
+            hex::encode(trader),
+            hex::encode(rand::random::<[u8; 32]>())
+        );
+        let message = TraderStreamMessage::Challenge { challenge: challenge.clone() };
+        let Ok(text) = serde_json::to_string(&message) else { return };
+        if session.text(text).await.is_err() {
+            return;
+        }
+
+        // The first text frame must carry a valid signature of the challenge
+        let authenticated = tokio::time::timeout(AUTH_TIMEOUT, async {
+            loop {
+                match msg_stream.recv().await {
+                    Some(Ok(Message::Text(text))) => {
+                        let Ok(auth) = serde_json::from_str::<TraderStreamAuth>(&text) else {
+                            return false;
+                        };
+                        let Ok(signature) = hex::decode(auth.signature.trim_start_matches("0x")) else {
+                            return false;
+                        };
+                        return verify_signer(challenge.as_bytes(), &signature, trader).is_ok();
+                    }
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return false;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return false,
+                    Some(Ok(_)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap_or(false);
+
+        if !authenticated {
+            let _ = session
+                .close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("Authentication failed".to_string()),
+                }))
+                .await;
+            return;
+        }
+
+        let (mut updates, mut rfqs) = {
+            let engine = state.lock_engine().await;
+            (engine.orderbook_manager.order_updates.subscribe(), engine.rfqs.subscribe())
+        };
+        let Ok(text) = serde_json::to_string(&TraderStreamMessage::Authenticated) else { return };
+        if session.text(text).await.is_err() {
+            return;
+        }
+
+        let close_reason = loop {
+            tokio::select! {
+                update = updates.recv() => match update {
+                    Ok(update) if update.trader == trader => {
+                        let Ok(text) = serde_json::to_string(&TraderStreamMessage::OrderUpdate(update)) else { continue };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        break Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some("Subscriber lagged behind order updates".to_string()),
+                        });
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break None,
+                },
+                notice = rfqs.recv() => match notice {
+                    Ok(notice) if notice.trader == trader => {
+                        let Ok(text) = serde_json::to_string(&TraderStreamMessage::Rfq(notice)) else { continue };
+                        if session.text(text).await.is_err() {
+                            return;
+                        }
+                    }
+                    // RFQ notices are few; one missed is caught up on with GET /api/rfqs
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break None,
+                },
+                msg = msg_stream.recv() => match msg {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                    Some(Ok(_)) => {}
+                },
+                _ = shutdown.wait_for(|down| *down) => {
+                    break Some(CloseReason {
+                        code: CloseCode::Away,
+                        description: Some("Server shutting down".to_string()),
+                    });
+                }
+            }
+        };
+
+        let _ = session.close(close_reason).await;
+    });
+
+    Ok(response)
+}
+
+/// Handler for writing a snapshot of the engine to disk
+/// Once the snapshot is durable, the WAL segments it covers are deleted.
+async fn create_snapshot(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let (path, orders) = write_snapshot(&state, true).await?;
+    Ok(HttpResponse::Ok().json(SnapshotResponse {
+        success: true,
+        message: "Snapshot written".to_string(),
+        path: Some(path.display().to_string()),
+        orders,
+    }))
+}
+
+/// Writes a snapshot of the engine to the snapshot directory, returning its path and order count
+/// The engine is only locked while its state is copied; serializing and writing happen afterwards.
+/// With `purge`, the WAL segments the snapshot covers are deleted once it is durable.
+async fn write_snapshot(state: &AppState, purge: bool) -> Result<(PathBuf, usize), ApiError> {
+    let (mut snapshot, checkpoint) = {
+        let mut engine = state.lock_engine().await;
+        let snapshot = engine.snapshot();
+        let checkpoint = engine.wal.as_mut().map(|wal| wal.checkpoint()).transpose()?;
+        (snapshot, checkpoint)
+    };
+    snapshot.registry = state
+        .book_registry
+        .entries()
+        .into_iter()
+        .map(|(name, book_id)| (name, book_id.value()))
+        .collect();
+    snapshot.wal_segment = checkpoint;
+
+    let orders = snapshot.order_count();
+    let dir = state.snapshot_dir.clone();
+    let written = web::block(move || snapshot.write_to(dir)).await;
+    let path = match written {
+        Ok(Ok(path)) => path,
+        Ok(Err(error)) => return Err(ApiError::Internal(error.to_string())),
+        Err(error) => return Err(ApiError::Internal(error.to_string())),
+    };
+
+    if let Some(checkpoint) = checkpoint.filter(|_| purge) {
+        let mut engine = state.lock_engine().await;
+        if let Some(Err(error)) = engine.wal.as_mut().map(|wal| wal.purge_before(checkpoint)) {
+            tracing::warn!(checkpoint, %error, "Failed to purge WAL segments");
+        }
+    }
+    tracing::info!(orders, path = %path.display(), "Snapshot written");
+    Ok((path, orders))
+}
+
+/// Admin handler adjusting the price band of a book's market, effective from the next order
+async fn set_price_band(
+    book_id: web::Path<String>,
+    data: web::Json<PriceBandRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let mut engine = state.lock_engine().await;
+    if engine.market_manager.get_config(book_id).is_none() {
+        return Err(ApiError::UnknownMarket);
+    }
+    engine.log(&WalCommand::SetPriceBand { book_id: book_id.value(), price_band: data.price_band })?;
+    engine.set_price_band(book_id, data.price_band)?;
+    tracing::info!(book_id = book_id.value(), price_band = ?data.price_band, "Set price band");
+
+    Ok(HttpResponse::Ok().json(PriceBandResponse {
+        success: true,
+        message: "Price band updated".to_string(),
+        price_band: data.price_band,
+        limits: engine.price_band(book_id),
+    }))
+}
+
+/// Handler for the kill switch: while engaged, every book refuses new orders, replaces, and
+/// uncrosses with 423 Locked, and only cancels and queries go through
+/// The switch is logged, and kept in snapshots, so a restarted server comes back as it was.
+async fn set_kill_switch(data: web::Json<KillSwitchRequest>, state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let mut engine = state.lock_engine().await;
+    engine.log(&WalCommand::SetKillSwitch { engaged: data.engaged })?;
+    engine.set_halted(data.engaged);
+    tracing::warn!(engaged = data.engaged, "Kill switch {}", if data.engaged { "engaged" } else { "released" });
+
+    Ok(HttpResponse::Ok().json(KillSwitchResponse {
+        success: true,
+        message: if data.engaged { "Trading halted" } else { "Trading resumed" }.to_string(),
+        engaged: data.engaged,
+    }))
+}
+
+/// Handler for the Prometheus scrape; see Metrics for what is published
+async fn get_metrics(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let books = state.book_registry.entries();
+    let text = state.metrics.render(&*state.lock_engine().await, &books);
+    Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(text))
+}
+
+/// Query parameters for the audit endpoint; `from` and `to` are inclusive, in nanoseconds since the epoch
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    trader: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuditResponse {
+    entries: Vec<AuditEntry>,
+}
+
+/// Handler listing the audited commands still retained, oldest first
+async fn get_audit(query: web::Query<AuditQuery>, audit: Option<web::Data<AuditLog>>) -> Result<HttpResponse, ApiError> {
+    let audit = audit.ok_or_else(|| ApiError::Unavailable("The audit log is not configured".to_string()))?;
+    let trader = query.trader.as_deref().map(parse_trader).transpose()?;
+    let entries = audit
+        .query(AuditFilter { trader, from: query.from, to: query.to })
+        .await
+        .map_err(|error| ApiError::Internal(format!("Cannot read the audit log: {}", error)))?;
+    Ok(HttpResponse::Ok().json(AuditResponse { entries }))
+}
+
+/// Handler for the health check
+async fn health(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let halted = state.lock_engine().await.is_halted();
+    Ok(HttpResponse::Ok().json(HealthResponse {
+        status: if halted { "halted" } else { "ok" }.to_string(),
+        halted,
+    }))
+}
+
+/// Handler for the liveness probe; answers as long as the process serves requests
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+/// Handler for the readiness probe
+/// Ready once the engine is restored, the settlement submitter reaches its node when there is
+/// one, and the kill switch is released; 503 otherwise, so load balancers hold traffic back.
+async fn readyz(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let readiness = &state.readiness;
+    let restored = readiness.is_restored();
+    let settlement_connected = readiness.settlement_connected.as_ref().map(|connected| connected.load(Ordering::Relaxed));
+    let halted = state.lock_engine().await.is_halted();
+    let ready = restored && settlement_connected != Some(false) && !halted;
+    let mut response = if ready { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    Ok(response.json(ReadinessResponse { ready, restored, settlement_connected, halted }))
+}
+
+/// Handler for the engine status: uptime, books, resting orders, and sequence numbers
+async fn get_status(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let entries = state.book_registry.entries();
+    let engine = state.lock_engine().await;
+    let manager = &engine.orderbook_manager;
+    let books = entries
+        .into_iter()
+        .map(|(name, book_id)| BookStatus {
+            book_id: name,
+            seq: manager.book_seq(book_id),
+            last_trade_time: engine.trade_tape(book_id).and_then(|tape| tape.iter_newest().next()).map(|trade| trade.timestamp),
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(StatusResponse {
+        uptime_secs: state.readiness.started.elapsed().as_secs(),
+        restored: state.readiness.is_restored(),
+        book_count: books.len(),
+        resting_orders: manager.oid_map.len(),
+        event_seq: manager.event_seq(),
+        books,
+    }))
+}
+
+/// Paths served while the engine is being restored
+const PROBE_PATHS: [&str; 4] = ["/healthz", "/readyz", "/api/status", "/metrics"];
+
+/// Middleware refusing every request but the probes with NotReady until the engine is restored
+/// The books are half rebuilt until then, and orders taken would miss the WAL being replayed.
+async fn require_restored<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let restored = req.app_data::<web::Data<AppState>>().is_some_and(|state| state.readiness.is_restored());
+    if restored || PROBE_PATHS.contains(&req.path()) {
+        next.call(req).await.map(ServiceResponse::map_into_left_body)
+    } else {
+        Ok(req.error_response(ApiError::NotReady).map_into_right_body())
+    }
+}
+
+/// Handler replacing the per-trader risk limits of a book's market
+/// Orders already resting past the new limits stay; they only hold back new ones.
+async fn set_risk_limits(
+    book_id: web::Path<String>,
+    data: web::Json<RiskLimitsRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let mut engine = state.lock_engine().await;
+    if engine.market_manager.get_config(book_id).is_none() {
+        return Err(ApiError::UnknownMarket);
+    }
+    engine.log(&WalCommand::SetRiskLimits { book_id: book_id.value(), risk_limits: data.risk_limits })?;
+    engine.set_risk_limits(book_id, data.risk_limits)?;
+    tracing::info!(book_id = book_id.value(), risk_limits = ?data.risk_limits, "Set risk limits");
+
+    Ok(HttpResponse::Ok().json(RiskLimitsResponse {
+        success: true,
+        message: "Risk limits updated".to_string(),
+        risk_limits: Some(data.risk_limits),
+    }))
+}
+
+/// Handler replacing the size rules of a book's market, for orders submitted or replaced from now on
+/// Orders already resting that break the new rules stay.
+async fn set_size_rules(
+    book_id: web::Path<String>,
+    data: web::Json<SizeRulesRequest>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let name = book_id.into_inner();
+    let book_id = state.book_registry.get_book_id(&name)?;
+
+    // The intake checks submissions against the rules, so it stays locked until it has the new ones
+    let mut order_intake = state.order_intake.write().await;
+    let mut engine = state.lock_engine().await;
+    if engine.market_manager.get_config(book_id).is_none() {
+        return Err(ApiError::UnknownMarket);
+    }
+    engine.log(&WalCommand::SetSizeRules { book_id: book_id.value(), size_rules: data.size_rules })?;
+    engine.set_size_rules(book_id, data.size_rules)?;
+    if let Some(config) = engine.market_manager.get_config(book_id) {
+        order_intake.set_market(&name, config);
+    }
+    tracing::info!(book_id = book_id.value(), size_rules = ?data.size_rules, "Set size rules");
+
+    Ok(HttpResponse::Ok().json(SizeRulesResponse {
+        success: true,
+        message: "Size rules updated".to_string(),
+        size_rules: Some(data.size_rules),
+    }))
+}
+
+/// Handler for the auction state of a book and, during an auction, where it would uncross
+async fn get_auction(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+    let engine = state.lock_engine().await;
+    let in_auction = engine.in_auction(book_id);
+    Ok(HttpResponse::Ok().json(AuctionResponse {
+        success: true,
+        message: if in_auction { "In auction" } else { "Continuous trading" }.to_string(),
+        in_auction,
+        indicative: if in_auction { engine.indicative(book_id) } else { None },
+        fills: Vec::new(),
+    }))
+}
+
+/// Admin handler putting a book into an auction, where orders rest without matching until uncrossed
+async fn start_auction(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let mut engine = state.lock_engine().await;
+    engine.log(&WalCommand::EnterAuction { book_id: book_id.value() })?;
+    engine.enter_auction(book_id)?;
+    tracing::info!(book_id = book_id.value(), "Book entered an auction");
+
+    Ok(HttpResponse::Ok().json(AuctionResponse {
+        success: true,
+        message: "Auction started".to_string(),
+        in_auction: true,
+        indicative: engine.indicative(book_id),
+        fills: Vec::new(),
+    }))
+}
+
+/// Admin handler uncrossing a book's auction and switching it to continuous trading
+async fn uncross_auction(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let mut engine = state.lock_engine().await;
+    // An uncross that would be refused must not reach the log
+    engine.check_accepting()?;
+    if !engine.in_auction(book_id) {
+        return Err(ApiError::NotInAuction(book_id));
+    }
+    let indicative = engine.indicative(book_id);
+    engine.log(&WalCommand::Uncross { book_id: book_id.value() })?;
+    let fills = engine.uncross(book_id)?;
+    tracing::info!(book_id = book_id.value(), fills = fills.len(), "Book uncrossed");
+    let scale = engine.price_scale(book_id);
+
+    Ok(HttpResponse::Ok().json(AuctionResponse {
+        success: true,
+        message: format!("Uncrossed with {} fills", fills.len()),
+        in_auction: false,
+        indicative,
+        fills: fills.iter().map(|fill| FillResponse::new(fill, scale)).collect(),
+    }))
+}
+
+/// Admin handler uncrossing a book the engine halted for being found crossed, which then trades again
+async fn repair_crossed_book(
+    book_id: web::Path<String>,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let book_id = state.book_registry.get_book_id(&book_id)?;
+
+    let mut engine = state.lock_engine().await;
+    // A repair that would be refused, or do nothing, must not reach the log
+    engine.check_accepting()?;
+    if !engine.is_crossed(book_id) {
+        return Ok(HttpResponse::Ok().json(RepairResponse {
+            success: true,
+            message: "Book is not crossed".to_string(),
+            crossed: false,
+            fills: Vec::new(),
+        }));
+    }
+    engine.log(&WalCommand::RepairCrossed { book_id: book_id.value() })?;
+    let fills = engine.repair_crossed(book_id)?;
+    tracing::warn!(book_id = book_id.value(), fills = fills.len(), "Crossed book repaired by an admin");
+    let scale = engine.price_scale(book_id);
+
+    Ok(HttpResponse::Ok().json(RepairResponse {
+        success: true,
+        message: format!("Repaired with {} fills", fills.len()),
+        crossed: engine.is_crossed(book_id),
+        fills: fills.iter().map(|fill| FillResponse::new(fill, scale)).collect(),
+    }))
+}
+
+/// Configure API routes
+fn configure_app(cfg: &mut web::ServiceConfig) {
+    let api = web::scope("/api")
+        .route("/books", web::post().to(create_book))
+        .route("/books", web::get().to(list_books))
+        .route("/books/{book_id}", web::delete().to(close_book))
+        .route("/orders", web::post().to(submit_order))
+        .route("/orders/oco", web::post().to(submit_oco_pair))
+        .route("/orders/preview", web::post().to(preview_order))
+        .route("/quotes", web::post().to(submit_mass_quote))
+        .route("/rfqs", web::post().to(create_rfq))
+        .route("/rfqs", web::get().to(list_rfqs))
+        .route("/rfqs/{rfq_id}", web::get().to(get_rfq))
+        .route("/rfqs/{rfq_id}/quotes", web::post().to(quote_rfq))
+        .route("/rfqs/{rfq_id}/quotes/{quote_id}/accept", web::post().to(accept_quote))
+        .route("/books/{book_id}/orderbook", web::get().to(get_orderbook))
+        .route("/books/{book_id}/bbo", web::get().to(get_bbo))
+        .route("/books/{book_id}/estimate", web::get().to(estimate_fill))
+        .route("/books/{book_id}/trades", web::get().to(get_trades))
+        .route("/books/{book_id}/trades/export", web::get().to(export_trades))
+        .route("/books/{book_id}/settlements/export", web::get().to(export_settlements))
+        .route("/books/{book_id}/stats", web::get().to(get_stats))
+        .route("/books/{book_id}/candles", web::get().to(get_candles))
+        .route("/books/{book_id}/market", web::get().to(get_market))
+        .route("/books/{book_id}/auction", web::get().to(get_auction))
+        .route("/markets", web::get().to(list_markets))
+        .route("/markets", web::post().to(create_market))
+        .route("/markets/{book_id}", web::get().to(get_market))
+        .route("/orders/by-client-id/{client_order_id}", web::get().to(get_order_by_client_id))
+        .route("/orders/by-client-id/{client_order_id}", web::delete().to(cancel_order_by_client_id))
+        .route("/orders/{order_id}", web::get().to(get_order_status))
+        .route("/orders/{order_id}", web::delete().to(cancel_order))
+        .route("/orders/{order_id}/replace", web::post().to(replace_order))
+        .route("/traders/{address}/orders", web::delete().to(cancel_all_orders))
+        .route("/traders/{address}/quotes", web::delete().to(cancel_quotes))
+        .route("/traders/{address}/nonce", web::post().to(bump_nonce))
+        .route("/traders/{address}/positions", web::get().to(get_positions))
+        .route("/traders/{address}/risk", web::get().to(get_risk_usage))
+        .route("/traders/{address}/volume", web::get().to(get_trader_volume))
+        .route("/settlements", web::get().to(list_settlements))
+        .route("/settlements/batches/{batch_id}", web::get().to(get_settlement_batch))
+        .route("/settlements/{settlement_id}", web::get().to(get_settlement))
+        .route("/health", web::get().to(health))
+        .route("/status", web::get().to(get_status))
+        .route("/admin/audit", web::get().to(get_audit))
+        .route("/admin/volume", web::get().to(get_volume_roll_up))
+        .route("/admin/snapshot", web::post().to(create_snapshot))
+        .route("/admin/killswitch", web::post().to(set_kill_switch))
+        .route("/admin/books/{book_id}/price_band", web::put().to(set_price_band))
+        .route("/admin/books/{book_id}/risk_limits", web::put().to(set_risk_limits))
+        .route("/admin/books/{book_id}/size_rules", web::put().to(set_size_rules))
+        .route("/admin/books/{book_id}/auction", web::post().to(start_auction))
+        .route("/admin/books/{book_id}/uncross", web::post().to(uncross_auction))
+        .route("/admin/books/{book_id}/repair", web::post().to(repair_crossed_book))
+        .route("/admin/rfq/makers/{address}", web::put().to(register_rfq_maker))
+        .route("/admin/rfq/makers/{address}", web::delete().to(unregister_rfq_maker));
+    #[cfg(feature = "sqlite")]
+    let api = api
+        .route("/books/{book_id}/trades/history", web::get().to(get_trade_history))
+        .route("/traders/{address}/fills", web::get().to(get_trader_fills));
+    cfg.service(api);
+    cfg.route("/ws/books/{book_id}", web::get().to(book_stream));
+    cfg.route("/ws/traders/{address}", web::get().to(trader_stream));
+    cfg.route("/metrics", web::get().to(get_metrics));
+    cfg.route("/healthz", web::get().to(healthz));
+    cfg.route("/readyz", web::get().to(readyz));
+}
+
+/// Expires good-til-time orders as they come due, for as long as the server runs
+/// Time comes from the engine's clock, in the seconds orders are signed with.
+async fn expire_orders(engine: Arc<Mutex<MatchingEngine>>) {
+    let mut interval = tokio::time::interval(EXPIRY_POLL_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let mut engine = engine.lock().await;
+        let now = engine.clock.now() / 1_000_000_000;
+        engine.poll_expirations(now);
+        engine.rfqs.expire(now);
+    }
+}
+
+/// Forgets the funds of each maker whose order fills, since settling the fill moves their balances
+/// Takers are forgotten by the handler that submitted them.
+async fn invalidate_funds(checker: Arc<FundsChecker>, mut updates: broadcast::Receiver<OrderUpdate>) {
+    loop {
+        match updates.recv().await {
+            Ok(update) if matches!(update.status, OrderStatus::Filled | OrderStatus::PartiallyFilled) => {
+                checker.invalidate(update.trader);
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Builds the CORS policy for the configured origins; "*" allows any origin
+fn cors(origins: &[String]) -> actix_cors::Cors {
+    let cors = actix_cors::Cors::default().allow_any_method().allow_any_header().max_age(3600);
+    if origins.iter().any(|origin| origin == "*") {
+        return cors.allow_any_origin();
+    }
+    origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin))
+}
+
+/// Start the API server
+/// Takes `recover`, which rebuilds the engine at startup and registers its books in the
+/// registry it is given, the server configuration, the verifier for contract-wallet signatures
+/// and the funds checker, if an Ethereum RPC is configured, and the settlement submitter, if an
+/// operator account is configured as well.
+/// The server listens while `recover` runs, answering the probes and refusing everything else.
+pub async fn start_server(
+    recover: impl FnOnce(&BookRegistry) -> std::io::Result<MatchingEngine> + Send + 'static,
+    config: &Config,
+    signature_verifier: Option<ContractSignatureVerifier>,
+    funds_checker: Option<FundsChecker>,
+    settlement_submitter: Option<SettlementSubmitter>,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    // The registry is loaded before recovery, so the WAL replays onto the BookIds books already have
+    let book_registry = Arc::new(match &config.storage.registry_path {
+        Some(path) => BookRegistry::open(path)?,
+        None => BookRegistry::new(),
+    });
+    // An empty engine stands in until the recovered one replaces it
+    let engine = MatchingEngine::new();
+    let metrics = engine.metrics.clone();
+    let engine = Arc::new(Mutex::new(engine));
+    let settlement_connected = settlement_submitter.as_ref().map(SettlementSubmitter::connection);
+    let state = web::Data::new(AppState {
+        order_intake: Arc::new(RwLock::new(
+            OrderIntake::new().with_registry(book_registry.clone()).with_limits(config.server.intake_limits()),
+        )),
+        book_registry,
+        metrics,
+        commands: CommandQueue::spawn(engine.clone(), config.server.queue_depth),
+        engine,
+        snapshot_dir: config.storage.snapshot_dir.clone(),
+        signature_verifier,
+        funds_checker: funds_checker.map(Arc::new),
+        shutdown: watch::channel(false).0,
+        readiness: Readiness::new(false, settlement_connected),
+        idempotency: IdempotencyCache::new(
+            Duration::from_secs(config.server.idempotency_ttl_secs),
+            config.server.idempotency_capacity,
+        ),
+        sequencer: TraderSequencer::new(),
+        ledger: FeeLedger::new(config.accounting.utc_offset_minutes),
+    });
+
+    let (host, port) = config.bind_address();
+    tracing::info!(%host, port, "Starting API server");
+    let auth_enabled = config.auth.enabled;
+    if !auth_enabled {
+        tracing::warn!("Authentication is disabled; every request is let through");
+    } else if config.auth.admin_keys.is_empty() {
+        tracing::warn!("No admin keys configured; admin endpoints are closed");
+    }
+    let authenticator = web::Data::new(Authenticator::new(&config.auth));
+    let audit = match &config.audit.dir {
+        Some(dir) => Some(web::Data::new(AuditLog::open(dir, config.audit.audit_config(), state.metrics.clone())?)),
+        None => None,
+    };
+
+    // The FIX gateway listens from the start but serves no session until the engine is restored
+    #[cfg(feature = "fix")]
+    let fix_gateway = match config.fix.port {
+        Some(fix_port) => {
+            let sessions = match &config.fix.state_path {
+                Some(path) => FixSessions::open(path)?,
+                None => FixSessions::new(),
+            };
+            let mut gateway = FixGateway::new(state.clone(), config.fix.comp_id.clone(), sessions);
+            if auth_enabled {
+                gateway = gateway.with_authenticator(authenticator.clone());
+            }
+            let listener = tokio::net::TcpListener::bind((config.server.bind_address.as_str(), fix_port)).await?;
+            tracing::info!(port = fix_port, "Starting FIX gateway");
+            Some((listener, gateway))
+        }
+        None => None,
+    };
+    // The gRPC API likewise takes no call until the engine is restored
+    #[cfg(feature = "grpc")]
+    let grpc_api = match config.grpc.port {
+        Some(grpc_port) => {
+            let mut service = GrpcService::new(state.clone());
+            if auth_enabled {
+                service = service.with_authenticator(authenticator.clone());
+            }
+            let listener = tokio::net::TcpListener::bind((config.server.bind_address.as_str(), grpc_port)).await?;
+            tracing::info!(port = grpc_port, "Starting gRPC API");
+            Some((listener, service))
+        }
+        None => None,
+    };
+
+    // Trades and order events are published once the engine is restored; the broker needn't be up
+    #[cfg(feature = "nats")]
+    let bus_publisher = match &config.bus.url {
+        Some(url) => {
+            tracing::info!(%url, "Publishing to the message bus");
+            Some((NatsPublisher::connect(url).await.map_err(std::io::Error::other)?, config.bus.bus_config()))
+        }
+        None => None,
+    };
+
+    // Start HTTP server
+    let server_state = state.clone();
+    let (body_limit, origins) = (config.server.body_limit, config.server.cors_origins.clone());
+    #[cfg(feature = "sqlite")]
+    let trade_history = match &config.storage.trade_db {
+        Some(path) => {
+            let history = TradeHistory::open(path).map_err(std::io::Error::other)?;
+            let last_trade_id = history.last_trade_id().map_err(std::io::Error::other)?;
+            Some((web::Data::new(history), last_trade_id))
+        }
+        None => None,
+    };
+    let server_audit = audit.clone();
+    #[cfg(feature = "sqlite")]
+    let server_history = trade_history.as_ref().map(|(history, _)| history.clone());
+    let mut server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(server_state.clone())
+            .app_data(authenticator.clone())
+            .app_data(web::JsonConfig::default().limit(body_limit));
+        if let Some(audit) = &server_audit {
+            app = app.app_data(audit.clone());
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(history) = &server_history {
+            app = app.app_data(history.clone());
+        }
+        app.wrap(actix_web::middleware::from_fn(require_restored))
+            .wrap(actix_web::middleware::Condition::new(auth_enabled, actix_web::middleware::from_fn(authenticate)))
+            .wrap(actix_web::middleware::from_fn(audit_commands))
+            .wrap(actix_web::middleware::Condition::new(!origins.is_empty(), cors(&origins)))
+            .wrap(actix_web::middleware::Logger::default())
+            .configure(configure_app)
+    });
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+    // Signals are handled by the caller's shutdown future, so cleanup runs after the server stops
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(config.server.shutdown_timeout_secs)
+        .bind((host, port))?
+        .run();
+    let handle = server.handle();
+    let mut running = actix_web::rt::spawn(server);
+
+    // The WAL is replayed off the async runtime, so the probes answer while it runs
+    tokio::pin!(shutdown);
+    let registry = state.book_registry.clone();
+    let recovery = tokio::task::spawn_blocking(move || recover(&registry));
+    let recovered = tokio::select! {
+        recovered = recovery => Some(recovered.map_err(std::io::Error::other).and_then(|engine| engine)),
+        _ = &mut shutdown => None,
+    };
+    let mut engine = match recovered {
+        Some(Ok(engine)) => engine,
+        // Nothing was restored, so there is no state to flush or snapshot
+        recovered => {
+            handle.stop(true).await;
+            let _ = running.await;
+            return match recovered {
+                Some(Err(error)) => Err(error),
+                _ => Ok(()),
+            };
+        }
+    };
+
+    // Books recovered with a market keep verifying orders against its domain
+    {
+        let mut order_intake = state.order_intake.write().await;
+        for (name, book_id) in state.book_registry.entries() {
+            if let Some(config) = engine.market_manager.get_config(book_id) {
+                order_intake.set_market(&name, config);
+                state.ledger.set_market(book_id, config);
+            }
+        }
+    }
+    let invalidations = state
+        .funds_checker
+        .clone()
+        .map(|checker| tokio::spawn(invalidate_funds(checker, engine.orderbook_manager.order_updates.subscribe())));
+    // Trades go on numbered past the last one kept, and every new one is kept
+    #[allow(unused_mut)] // Sinks besides the ledger only come with the sqlite and nats features
+    let mut sinks: Vec<Box<dyn EventSink>> = vec![Box::new(state.ledger.clone())];
+    #[cfg(feature = "sqlite")]
+    if let Some((history, last_trade_id)) = &trade_history {
+        if let Some(trade_id) = *last_trade_id {
+            engine.continue_trade_ids_after(trade_id);
+        }
+        sinks.push(Box::new(history.sink(&engine)));
+        // The ledger is not snapshotted; it is rebuilt from the trades kept
+        let history = history.clone();
+        let ledger = state.ledger.clone();
+        let trades = web::block(move || ledger.rebuild(&history)).await.map_err(std::io::Error::other)?;
+        tracing::info!(trades = trades.map_err(std::io::Error::other)?, "Rebuilt the volume ledger");
+    }
+    #[cfg(feature = "nats")]
+    if let Some((publisher, bus_config)) = bus_publisher {
+        sinks.push(Box::new(BusSink::start(publisher, bus_config, state.book_registry.clone(), state.metrics.clone())));
+    }
+    engine.orderbook_manager.set_event_sink(Box::new(FanoutSink::new(sinks)));
+    engine.metrics = state.metrics.clone();
+    *state.lock_engine().await = engine;
+
+    let submitter = match settlement_submitter {
+        Some(submitter) => Some(submitter.start(state.engine.clone()).await),
+        None => None,
+    };
+    let expirations = tokio::spawn(expire_orders(state.engine.clone()));
+    state.readiness.restored.store(true, Ordering::Release);
+    tracing::info!(books = state.book_registry.entries().len(), "Engine restored");
+    #[cfg(feature = "fix")]
+    let fix_gateway = fix_gateway.map(|(listener, gateway)| tokio::spawn(fix_gateway::serve(listener, Arc::new(gateway))));
+    #[cfg(feature = "grpc")]
+    let grpc_api = grpc_api.map(|(listener, service)| tokio::spawn(grpc::serve(listener, service)));
+
+    let result = tokio::select! {
+        result = &mut running => result,
+        _ = &mut shutdown => {
+            // New orders are refused while in-flight requests finish and streams close
+            state.lock_engine().await.begin_shutdown();
+            state.shutdown.send_replace(true);
+            handle.stop(true).await;
+            running.await
+        }
+    };
+    let result = result.map_err(std::io::Error::other).and_then(|result| result);
+
+    // FIX sessions log out and are saved before the engine is flushed
+    #[cfg(feature = "fix")]
+    if let Some(fix_gateway) = fix_gateway {
+        state.shutdown.send_replace(true);
+        let _ = fix_gateway.await;
+    }
+    // gRPC streams end, and calls in flight finish, before it too
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_api) = grpc_api {
+        state.shutdown.send_replace(true);
+        match grpc_api.await {
+            Ok(Err(error)) => tracing::error!(%error, "gRPC API failed"),
+            Err(error) => tracing::error!(%error, "gRPC API panicked"),
+            Ok(Ok(())) => {}
+        }
+    }
+    expirations.abort();
+    if let Err(error) = state.lock_engine().await.finalize() {
+        tracing::error!(%error, "Failed to flush the WAL on shutdown");
+    }
+    // The WAL is kept, so a restart recovers with or without the snapshot
+    if let Err(error) = write_snapshot(&state, false).await {
+        tracing::error!(%error, "Failed to write a snapshot on shutdown");
+    }
+    if let Some(invalidations) = invalidations {
+        invalidations.abort();
+    }
+    if let Some(audit) = &audit {
+        audit.flush().await;
+    }
+    // Settlements still waiting for their batch are sent before exiting
+    if let Some(submitter) = submitter {
+        submitter.shutdown().await;
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        api_error::ErrorResponse,
+        auth::{address_of, sign_prehash},
+        eip712::{Eip712Domain, Eip712Order},
+        market::SizeRule,
+        matching::FillLimitPolicy,
+        order_intake::{IntakeLimits, NO_EXPIRY},
+        utils::Clock,
+    };
+    use actix_web::{test, App};
+    use k256::ecdsa::SigningKey;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    pub(crate) fn test_state() -> web::Data<AppState> {
+        test_state_with_queue(1_000)
+    }
+
+    fn test_state_with_queue(queue_depth: usize) -> web::Data<AppState> {
+        let book_registry = Arc::new(BookRegistry::new());
+        // Most tests register books by name only, so their orders create the books they go to
+        let engine = MatchingEngine::auto_creating();
+        let metrics = engine.metrics.clone();
+        let engine = Arc::new(Mutex::new(engine));
+        web::Data::new(AppState {
+            order_intake: Arc::new(RwLock::new(OrderIntake::new().with_registry(book_registry.clone()))),
+            book_registry,
+            metrics,
+            commands: CommandQueue::spawn(engine.clone(), queue_depth),
+            engine,
+            snapshot_dir: std::env::temp_dir().join("numena-test-snapshots"),
+            signature_verifier: None,
+            funds_checker: None,
+            shutdown: watch::channel(false).0,
+            readiness: Readiness::new(true, None),
+            idempotency: IdempotencyCache::new(Duration::from_secs(60), 1_000),
+            sequencer: TraderSequencer::new(),
+            ledger: FeeLedger::new(0),
+        })
+    }
+
+    /// A trader's signing key derived from `seed`, and its 0x-prefixed address
+    pub(crate) fn test_trader(seed: u8) -> (SigningKey, String) {
+        let key = SigningKey::from_slice(&[seed; 32]).unwrap();
+        let address = format!("0x{}", hex::encode(address_of(key.verifying_key())));
+        (key, address)
+    }
+
+    /// An ETH-USD order signed by `key` under the default domain, with a fresh nonce
+    pub(crate) fn signed_order(key: &SigningKey, price: i32, quantity: u64) -> OrderRequest {
+        signed_order_in(&Eip712Domain::default(), key, price, quantity)
+    }
+
+    /// An ETH-USD order signed by `key` under `domain`, with a fresh nonce
+    fn signed_order_in(domain: &Eip712Domain, key: &SigningKey, price: i32, quantity: u64) -> OrderRequest {
+        static NONCE: AtomicU64 = AtomicU64::new(1);
+        let trader = address_of(key.verifying_key());
+        let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+        let digest = domain.hash_order(&Eip712Order {
+            book: "ETH-USD",
+            trader,
+            price,
+            quantity,
+            nonce,
+            expiry: NO_EXPIRY,
+        });
+        OrderRequest {
+            book_id: "ETH-USD".to_string(),
+            price: price.into(),
+            side: None,
+            quantity,
+            trader: format!("0x{}", hex::encode(trader)),
+            nonce,
+            expiry: None,
+            signature: format!("0x{}", hex::encode(sign_prehash(key, &digest))),
+            order_type: OrderType::Limit,
+            trigger_price: None,
+            display_quantity: None,
+            peg_offset: None,
+            allow_cross: false,
+            reduce_only: false,
+            client_order_id: None,
+        }
+    }
+
+    fn order_request(key: &SigningKey, price: i32, quantity: u64) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri("/api/orders")
+            .set_json(signed_order(key, price, quantity))
+    }
+
+    #[actix_web::test]
+    async fn test_submit_order() {
+        // Create test app
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        // Create test order
+        let (trader, _) = test_trader(0x12);
+        let order = signed_order(&trader, 1000, 100);
+
+        // Send test request
+        let req = test::TestRequest::post()
+            .uri("/api/orders")
+            .set_json(&order)
+            .to_request();
+
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+    }
+
+    #[actix_web::test]
+    async fn test_create_book_with_market() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder()
+            .base_token([1; 20])
+            .security_token([2; 20])
+            .name("Numena Exchange")
+            .version("2")
+            .chain_id(8453)
+            .verifying_contract([0xab; 20])
+            .build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Frontends read the domain back to build the same typed data
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/market").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        println!("Market: {}", body);
+        assert_eq!(body["chain_id"], 8453);
+        assert_eq!(body["name"], "Numena Exchange");
+        assert_eq!(body["verifying_contract"], format!("0x{}", "ab".repeat(20)));
+
+        // Orders must be signed under the market's domain, not the default one
+        let (trader, _) = test_trader(0x12);
+        let req = order_request(&trader, 1000, 10).to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(!resp.success);
+        assert_eq!(resp.message, "Invalid signature");
+        let req = test::TestRequest::post()
+            .uri("/api/orders")
+            .set_json(signed_order_in(&market.domain(), &trader, 1000, 10))
+            .to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+
+        let req = test::TestRequest::get().uri("/api/books/BTC-USD/market").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        // A book without a market has to be asked for book-only, and nothing is registered otherwise
+        let req = test::TestRequest::post().uri("/api/books").set_json(serde_json::json!({ "book_id": "SOL-USD" })).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        assert!(state.book_registry.get_book_id("SOL-USD").is_err());
+
+        // Books without a market are left out of the listing
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "BTC-USD".to_string(), market: None, book_only: true })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri("/api/markets").to_request();
+        let resp: ListMarketsResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Markets: {:?}", resp.markets);
+        assert_eq!(resp.markets.len(), 1);
+        assert_eq!((resp.markets[0].book_id, resp.markets[0].name.as_str()), (0, "ETH-USD"));
+        assert_eq!(resp.markets[0].market, market);
+    }
+
+    #[actix_web::test]
+    async fn test_create_market() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let create = |body: serde_json::Value| test::TestRequest::post().uri("/api/markets").set_json(body).to_request();
+        let market = |book_id: &str| {
+            serde_json::json!({
+                "book_id": book_id,
+                "base_token": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+                "security_token": "0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359",
+                "fee_recipient": "DBF03B407C01E7CD3CBEA99509D93F8DDDC8C6FB",
+                "pool": format!("0x{}", "44".repeat(20)),
+                "maker_fee_bps": 5,
+                "taker_fee_bps": 10,
+                "base_decimals": 6,
+                "security_decimals": 18,
+                "price_decimals": 2,
+                "tick_size": 5,
+                "lot_size": 10,
+                "chain_id": 8453,
+            })
+        };
+
+        let resp: CreateMarketResponse = test::call_and_read_body_json(&app, create(market("ETH-USD"))).await;
+        assert_eq!((resp.success, resp.book_id), (true, 0));
+        assert_eq!(resp.market.base_token, crate::auth::parse_address("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap());
+        assert_eq!((resp.market.tick_size, resp.market.size_rules.lot_size, resp.market.chain_id), (5, 10, 8453));
+
+        // The stored config is what the translator settles with
+        let req = test::TestRequest::get().uri("/api/markets/ETH-USD").to_request();
+        let stored: MarketConfig = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stored, resp.market);
+        assert_eq!(state.lock_engine().await.market_manager.get_config(BookId(0)), Some(&stored));
+        let req = test::TestRequest::get().uri("/api/markets/BTC-USD").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(&app, create(market("ETH-USD"))).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.code, 2006);
+
+        // Bad addresses are refused by field before anything is registered
+        let mut bad_checksum = market("BTC-USD");
+        bad_checksum["base_token"] = "0x5aaEb6053F3E94C9b9A09f33669435E7Ef1BeAed".into();
+        let mut short = market("BTC-USD");
+        short["pool"] = "0x4444".into();
+        let mut not_hex = market("BTC-USD");
+        not_hex["fee_recipient"] = "fee recipient".into();
+        let mut zero_tick = market("BTC-USD");
+        zero_tick["tick_size"] = 0.into();
+        let cases = [
+            (bad_checksum, 1019, "Invalid base_token: EIP-55 checksum mismatch"),
+            (short, 1019, "Invalid pool: 2 bytes, not 20"),
+            (not_hex, 1019, "Invalid fee_recipient: not a hex address"),
+            (zero_tick, 1006, "tick_size and lot_size must be at least 1"),
+        ];
+        for (body, code, message) in cases {
+            let resp = test::call_service(&app, create(body)).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!((body.code, body.message.as_str()), (code, message));
+        }
+        assert!(state.book_registry.get_book_id("BTC-USD").is_err());
+
+        // A market left behind for the next BookId makes setup fail halfway, and the
+        // registration is taken back rather than left without its market
+        state.lock_engine().await.market_manager.add_market(BookId(1), MarketConfig::default(), false).unwrap();
+        let resp = test::call_service(&app, create(market("BTC-USD"))).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.code, 2007);
+        assert!(state.book_registry.get_book_id("BTC-USD").is_err());
+        assert_eq!(state.book_registry.next_book_id(), BookId(1));
+        assert!(state.lock_engine().await.orderbook_manager.book(BookId(1)).is_none());
+
+        // Once it is cleared the same BookId is handed out
+        state.lock_engine().await.market_manager.remove_market(BookId(1));
+        let resp: CreateMarketResponse = test::call_and_read_body_json(&app, create(market("BTC-USD"))).await;
+        assert_eq!((resp.success, resp.book_id), (true, 1));
+    }
+
+    #[actix_web::test]
+    async fn test_close_book() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let create = || {
+            test::TestRequest::post()
+                .uri("/api/books")
+                .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(MarketConfig::default()), book_only: false })
+                .to_request()
+        };
+        assert!(test::call_service(&app, create()).await.status().is_success());
+
+        let (buyer, _) = test_trader(0x41);
+        let (seller, _) = test_trader(0x42);
+        for (key, price) in [(&buyer, 1000), (&buyer, 990), (&seller, -1010)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(key, price, 10).to_request()).await;
+            assert_eq!(resp.status.map(|status| status.status), Some(OrderStatus::New));
+        }
+        let mut updates = state.lock_engine().await.orderbook_manager.order_updates.subscribe();
+
+        let req = test::TestRequest::delete().uri("/api/books/ETH-USD").to_request();
+        let resp: CloseBookResponse = test::call_and_read_body_json(&app, req).await;
+        println!("{}", resp.message);
+        assert_eq!((resp.success, resp.book_id, resp.cancelled), (true, 0, vec![0, 1, 2]));
+
+        // Each owner hears their order went with the book
+        for order_id in 0..3 {
+            let update = updates.recv().await.unwrap();
+            assert_eq!((update.order_id, update.status), (order_id, OrderStatus::BookClosed));
+        }
+        {
+            let engine = state.lock_engine().await;
+            assert!(engine.orderbook_manager.book(BookId(0)).is_none());
+            assert!(engine.market_manager.get_config(BookId(0)).is_none());
+            assert!(engine.orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        }
+
+        // The book is gone for everything addressed to it by name
+        let resp = test::call_service(&app, order_request(&buyer, 1000, 10).to_request()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, test::TestRequest::delete().uri("/api/books/ETH-USD").to_request()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        // A book created under the same name starts empty, under a new BookId
+        assert!(test::call_service(&app, create()).await.status().is_success());
+        assert_eq!(state.book_registry.get_book_id("ETH-USD").unwrap(), BookId(1));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&seller, -1010, 10).to_request()).await;
+        assert_eq!(resp.status.map(|status| status.status), Some(OrderStatus::New));
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request();
+        let bbo: BboResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((bbo.bid_price, bbo.ask_price), (None, Some(ApiPrice::Units(1010))));
+    }
+
+    #[actix_web::test]
+    async fn test_auction() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: None, book_only: true })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let req = test::TestRequest::post().uri("/api/admin/books/ETH-USD/auction").to_request();
+        let resp: AuctionResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success && resp.in_auction);
+
+        // Crossing orders rest during the auction
+        let (buyer, _) = test_trader(0x31);
+        let (seller, _) = test_trader(0x32);
+        for (key, price) in [(&buyer, 1010), (&seller, -1000), (&seller, -1020)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(key, price, 10).to_request()).await;
+            assert_eq!(resp.status.map(|status| status.status), Some(OrderStatus::New));
+        }
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/auction").to_request();
+        let resp: AuctionResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Auction: {} {:?}", resp.message, resp.indicative);
+        assert!(resp.in_auction);
+        assert_eq!(resp.indicative, Some(Uncross { price: 1000, volume: 10, imbalance: 0 }));
+
+        let req = test::TestRequest::post().uri("/api/admin/books/ETH-USD/uncross").to_request();
+        let resp: AuctionResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success && !resp.in_auction);
+        assert_eq!(resp.fills.iter().map(|fill| (fill.price.clone(), fill.quantity)).collect::<Vec<_>>(), vec![(ApiPrice::Units(1000), 10)]);
+
+        // Uncrossing again is refused
+        let req = test::TestRequest::post().uri("/api/admin/books/ETH-USD/uncross").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/auction").to_request();
+        let resp: AuctionResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.in_auction, resp.indicative), (false, None));
+    }
+
+    #[actix_web::test]
+    async fn test_repair_crossed_book() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (seller, _) = test_trader(0x41);
+        let (buyer, _) = test_trader(0x42);
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&seller, -1000, 10).to_request()).await;
+        state.lock_engine().await.rest_unmatched(OrderId(1_000), BookId(0), Qty(4), 1010, true);
+
+        // The book halted when it was found crossed, and refuses orders until repaired
+        let resp = test::call_service(&app, order_request(&buyer, 1000, 5).to_request()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::LOCKED);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.code, 3015);
+
+        let req = test::TestRequest::post().uri("/api/admin/books/ETH-USD/repair").to_request();
+        let resp: RepairResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success && !resp.crossed);
+        assert_eq!(resp.fills.iter().map(|fill| (fill.price.clone(), fill.quantity)).collect::<Vec<_>>(), vec![(ApiPrice::Units(1000), 4)]);
+
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&buyer, 1000, 5).to_request()).await;
+        assert_eq!(resp.status.map(|status| (status.status, status.filled_qty)), Some((OrderStatus::Filled, 5)));
+        let req = test::TestRequest::post().uri("/api/admin/books/ETH-USD/repair").to_request();
+        let resp: RepairResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.fills.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_fill_limit_cancels_rest() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        state.lock_engine().await.set_fill_limit(Some(2), FillLimitPolicy::Cancel);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (seller, _) = test_trader(0x41);
+        let (buyer, _) = test_trader(0x42);
+        for _ in 0..3 {
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&seller, -1000, 1).to_request()).await;
+        }
+
+        // The order stops at the fill limit, and what is left is cancelled rather than resting
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&buyer, 1000, 3).to_request()).await;
+        let status = resp.status.unwrap();
+        assert_eq!((status.status, status.filled_qty, status.remaining_qty), (OrderStatus::Cancelled, 2, 0));
+        let engine = state.lock_engine().await;
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(1)));
+    }
+
+    #[actix_web::test]
+    async fn test_reduce_only_and_positions() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let market = MarketConfig::builder().track_positions(true).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let (buyer, buyer_address) = test_trader(0x41);
+        let (seller, _) = test_trader(0x42);
+        let reduce_only = |key: &SigningKey, price: i32, quantity: u64| {
+            let order = OrderRequest { reduce_only: true, ..signed_order(key, price, quantity) };
+            test::TestRequest::post().uri("/api/orders").set_json(order).to_request()
+        };
+
+        // Without a position there is nothing to reduce
+        let resp = test::call_service(&app, reduce_only(&buyer, -1000, 5)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&seller, -1000, 4).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&buyer, 1000, 4).to_request()).await;
+        let req = test::TestRequest::get().uri(&format!("/api/traders/{}/positions", buyer_address)).to_request();
+        let resp: PositionsResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Positions: {:?}", resp.positions);
+        assert_eq!(resp.positions.iter().map(|position| (position.book.as_str(), position.qty)).collect::<Vec<_>>(), vec![("ETH-USD", 4)]);
+
+        // Long 4, a reduce-only sell of 10 rests as 4
+        let resp: OrderResponse = test::call_and_read_body_json(&app, reduce_only(&buyer, -1010, 10)).await;
+        let status = resp.status.unwrap();
+        assert_eq!((status.status, status.remaining_qty), (OrderStatus::New, 4));
+
+        // Only limit orders can be reduce-only
+        let order = OrderRequest { order_type: OrderType::Stop, trigger_price: Some(ApiPrice::Units(990)), ..signed_order(&buyer, -990, 4) };
+        let req = test::TestRequest::post().uri("/api/orders").set_json(OrderRequest { reduce_only: true, ..order }).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri("/api/traders/0x1234/positions").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_trader_volume() {
+        let state = test_state();
+        state.lock_engine().await.orderbook_manager.set_event_sink(Box::new(state.ledger.clone()));
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let market = MarketConfig::builder().maker_fee_bps(10).taker_fee_bps(25).maker_rebate_bps(2).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let (maker, maker_address) = test_trader(0x61);
+        let (taker, taker_address) = test_trader(0x62);
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        // Noon on 2024-10-04 and on the day after, in UTC
+        let noon = 20_000 * 86_400_000_000_000 + 43_200_000_000_000;
+        for (day, quantity) in [(0, 3), (1, 7)] {
+            state.lock_engine().await.clock = Clock::Fixed(noon + day * 86_400_000_000_000);
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, quantity).to_request()).await;
+        }
+
+        let volume = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+        let resp: TraderVolumeResponse = test::call_and_read_body_json(&app, volume(format!("/api/traders/{}/volume", maker_address))).await;
+        println!("Volume: {:?}", resp.days);
+        let days: Vec<(String, u128, u128, u128)> = resp
+            .days
+            .iter()
+            .map(|entry| (entry.day.to_string(), entry.totals.maker_volume, entry.totals.fees_paid, entry.totals.rebates_earned))
+            .collect();
+        // 10 bps of 3000 and 7000; 2 bps of 3000 is 0.6 and of 7000 is 1.4, rounded down
+        assert_eq!(days, vec![("2024-10-04".to_string(), 3_000, 3, 0), ("2024-10-05".to_string(), 7_000, 7, 1)]);
+        assert_eq!((resp.totals.maker_volume, resp.totals.maker_trades, resp.totals.fees_paid), (10_000, 2, 10));
+        assert!(resp.days.iter().all(|entry| entry.book == "ETH-USD"));
+
+        let uri = format!("/api/traders/{}/volume?from=2024-10-05", taker_address);
+        let resp: TraderVolumeResponse = test::call_and_read_body_json(&app, volume(uri)).await;
+        // 25 bps of 7000 is 17.5, rounded up
+        assert_eq!(resp.days.len(), 1);
+        assert_eq!((resp.totals.taker_volume, resp.totals.fees_paid, resp.totals.rebates_earned), (7_000, 18, 0));
+
+        let resp: VolumeRollUpResponse = test::call_and_read_body_json(&app, volume("/api/admin/volume?to=2024-10-04".to_string())).await;
+        let entries: Vec<(&str, u128, u128)> =
+            resp.entries.iter().map(|entry| (entry.trader.as_str(), entry.totals.maker_volume, entry.totals.taker_volume)).collect();
+        let mut expected = vec![(maker_address.as_str(), 3_000, 0), (taker_address.as_str(), 0, 3_000)];
+        expected.sort();
+        assert_eq!(entries, expected);
+        // 3 from the maker and 7.5 rounded up to 8 from the taker
+        assert_eq!(resp.totals.fees_paid, 11);
+
+        for uri in ["/api/admin/volume?from=2024-13-01", &format!("/api/traders/{}/volume?to=yesterday", maker_address)] {
+            assert_eq!(test::call_service(&app, volume(uri.to_string())).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_set_price_band() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder().chain_id(8453).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (trader, _) = test_trader(0x21);
+        for price in [990, -1010] {
+            let req = test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(signed_order_in(&market.domain(), &trader, price, 10))
+                .to_request();
+            let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+            assert!(resp.success);
+        }
+
+        // The book has not traded, so the band is centered on the mid price of 1000
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/ETH-USD/price_band")
+            .set_json(PriceBandRequest { price_band: Some(PriceBand::Bps(50)) })
+            .to_request();
+        let resp: PriceBandResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Price band: {} {:?}", resp.message, resp.limits);
+        assert!(resp.success);
+        assert_eq!(resp.limits, Some((995, 1005)));
+
+        let req = test::TestRequest::post()
+            .uri("/api/orders")
+            .set_json(signed_order_in(&market.domain(), &trader, 1006, 10))
+            .to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(!resp.success);
+        assert_eq!(resp.message, "Price 1006 is outside the price band [995, 1005]");
+
+        // Lifting the band lets the order through
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/ETH-USD/price_band")
+            .set_json(PriceBandRequest { price_band: None })
+            .to_request();
+        let resp: PriceBandResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.success, resp.limits), (true, None));
+        let req = test::TestRequest::post()
+            .uri("/api/orders")
+            .set_json(signed_order_in(&market.domain(), &trader, 1006, 10))
+            .to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+
+        // Books without a market have no band to set
+        state.book_registry.register_book("BTC-USD".to_string()).unwrap();
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/BTC-USD/price_band")
+            .set_json(PriceBandRequest { price_band: Some(PriceBand::Ticks(5)) })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_decimal_prices() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        // Prices in cents, on ticks of 0.05
+        let market = MarketConfig::builder().price_decimals(2).tick_size(5).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (maker, _) = test_trader(0x31);
+        let (taker, _) = test_trader(0x32);
+        let submit = |key: &SigningKey, price: &str, units: i32, quantity: u64| {
+            let order = OrderRequest { price: ApiPrice::from(price), ..signed_order_in(&market.domain(), key, units, quantity) };
+            test::TestRequest::post().uri("/api/orders").set_json(order).to_request()
+        };
+
+        // The decimal price is read into the book units the trader signed
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&maker, "-10.3", -1030, 10)).await;
+        assert!(resp.success, "{}", resp.message);
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&maker, "10.05", 1005, 10)).await;
+        assert!(resp.success, "{}", resp.message);
+
+        // Prices between ticks are refused, whether decimal or in book units
+        for price in [ApiPrice::from("10.07"), ApiPrice::from("10.051"), ApiPrice::Units(1007)] {
+            let order = OrderRequest { price, ..signed_order_in(&market.domain(), &taker, 1007, 10) };
+            let req = test::TestRequest::post().uri("/api/orders").set_json(order).to_request();
+            let body: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body.code, 1013, "{}", body.message);
+        }
+        let body: ErrorResponse = test::call_and_read_body_json(&app, submit(&taker, "10.07", 1007, 10)).await;
+        assert_eq!(body.message, "Price 10.07 is not a multiple of the tick size 0.05");
+        assert_eq!(body.details, Some(serde_json::json!({ "price": "10.07", "tick_size": "0.05" })));
+        let body: ErrorResponse = test::call_and_read_body_json(&app, submit(&taker, "1e3", 1000, 10)).await;
+        assert_eq!(body.code, 1002);
+
+        // Market data comes back in decimals
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&taker, "10.30", 1030, 4)).await;
+        assert!(resp.success, "{}", resp.message);
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/orderbook").to_request();
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.bids[0].price.clone(), resp.asks[0].price.clone()), (ApiPrice::from("10.05"), ApiPrice::from("10.30")));
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request();
+        let resp: BboResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.spread, Some(ApiPrice::from("0.25")));
+        assert_eq!(resp.mid_price, Some(10.175));
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.trades[0].price, ApiPrice::from("10.30"));
+
+        // A replacement is read in the market's decimals too
+        let req = test::TestRequest::post()
+            .uri("/api/orders/1/replace")
+            .set_json(ReplaceOrderRequest { price: ApiPrice::from("10.3"), quantity: 2 })
+            .to_request();
+        let resp: ReplaceOrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success, "{}", resp.message);
+        assert_eq!(resp.fills.iter().map(|fill| (fill.price.clone(), fill.quantity)).collect::<Vec<_>>(), vec![(ApiPrice::from("10.30"), 2)]);
+    }
+
+    #[actix_web::test]
+    async fn test_risk_limits() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let limits = RiskLimits { max_open_orders: 2, max_open_notional: 5000 };
+        let market = MarketConfig::builder().chain_id(8453).risk_limits(limits).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (trader, address) = test_trader(0x23);
+        let submit = |price: i32, qty: u64| {
+            test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(signed_order_in(&market.domain(), &trader, price, qty))
+                .to_request()
+        };
+        for (price, qty) in [(1000, 2), (-1100, 2)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, submit(price, qty)).await;
+            assert!(resp.success);
+        }
+
+        // A third order would be one too many, however small
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(100, 1)).await;
+        println!("Third order: {}", resp.message);
+        assert_eq!((resp.success, resp.message.as_str()), (false, "Risk limit exceeded: at most 2 open orders per trader"));
+
+        // Cancelling the bid frees a slot, but not enough notional for 4 at 1000 on top of the ask's 2200
+        let req = test::TestRequest::delete().uri("/api/orders/0").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(1000, 4)).await;
+        assert_eq!((resp.success, resp.message.as_str()), (false, "Risk limit exceeded: at most 5000 open notional per trader"));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(1000, 2)).await;
+        assert_eq!((resp.success, resp.order_id), (true, Some(2)));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/traders/{}/risk", address))
+            .to_request();
+        let resp: RiskUsageResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.books.len(), 1);
+        let usage = &resp.books[0];
+        assert_eq!((usage.book.as_str(), usage.usage, usage.limits), ("ETH-USD", OpenUsage { open_orders: 2, open_notional: 4200 }, Some(limits)));
+
+        // Raising the limits lets the third order in
+        let raised = RiskLimits { max_open_orders: 3, ..limits };
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/ETH-USD/risk_limits")
+            .set_json(RiskLimitsRequest { risk_limits: raised })
+            .to_request();
+        let resp: RiskLimitsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.success, resp.risk_limits), (true, Some(raised)));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(100, 1)).await;
+        assert!(resp.success);
+    }
+
+    #[actix_web::test]
+    async fn test_size_rules() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let rules = SizeRules { min_qty: 10, lot_size: 5, min_notional: 10_000 };
+        let market = MarketConfig::builder().chain_id(8453).size_rules(rules).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (trader, _) = test_trader(0x24);
+        let submit = |price: i32, qty: u64| {
+            test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(signed_order_in(&market.domain(), &trader, price, qty))
+                .to_request()
+        };
+
+        // Frontends read the rules from the market to check orders before sending them
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/market").to_request();
+        let resp: MarketConfig = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.size_rules, rules);
+
+        // Exactly at the minimums is enough
+        for (price, qty) in [(1000, 10), (-2000, 10), (500, 20)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, submit(price, qty)).await;
+            assert!(resp.success, "{}", resp.message);
+        }
+        for (price, qty, rule, message) in [
+            (2000, 5, SizeRule::MinQty(10), "Order must have a quantity of at least 10"),
+            (1000, 12, SizeRule::LotSize(5), "Order must have a quantity in whole lots of 5"),
+            (999, 10, SizeRule::MinNotional(10_000), "Order must have a notional of at least 10000"),
+        ] {
+            let body: ErrorResponse = test::call_and_read_body_json(&app, submit(price, qty)).await;
+            assert_eq!((body.code, body.message.as_str()), (1014, message));
+            assert_eq!(body.details, Some(serde_json::json!({ "rule": rule })));
+        }
+
+        // A replacement is held to the same rules
+        let replace = |price: u32, quantity: u64| {
+            test::TestRequest::post()
+                .uri("/api/orders/0/replace")
+                .set_json(ReplaceOrderRequest { price: ApiPrice::from(price), quantity })
+                .to_request()
+        };
+        let body: ErrorResponse = test::call_and_read_body_json(&app, replace(1000, 11)).await;
+        assert_eq!((body.code, body.details), (1014, Some(serde_json::json!({ "rule": { "lot_size": 5 } }))));
+        let resp: ReplaceOrderResponse = test::call_and_read_body_json(&app, replace(1000, 15)).await;
+        assert!(resp.success, "{}", resp.message);
+
+        // Operators can change the rules, and submissions are held to the new ones at once
+        let lowered = SizeRules { min_qty: 1, lot_size: 1, min_notional: 0 };
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/ETH-USD/size_rules")
+            .set_json(SizeRulesRequest { size_rules: lowered })
+            .to_request();
+        let resp: SizeRulesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.success, resp.size_rules), (true, Some(lowered)));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(999, 3)).await;
+        assert!(resp.success, "{}", resp.message);
+
+        // Books without a market have no rules to set
+        state.book_registry.register_book("BTC-USD".to_string()).unwrap();
+        let req = test::TestRequest::put()
+            .uri("/api/admin/books/BTC-USD/size_rules")
+            .set_json(SizeRulesRequest { size_rules: lowered })
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_order_shapes_rejected() {
+        let state = test_state();
+        let limits = IntakeLimits { max_quantity: 100, min_expiry_margin_secs: 60 };
+        *state.order_intake.write().await = OrderIntake::new().with_registry(state.book_registry.clone()).with_limits(limits);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder().chain_id(8453).require_nonce(true).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (trader, _) = test_trader(0x25);
+        let order = |price: i32, quantity: u64| signed_order_in(&market.domain(), &trader, price, quantity);
+        let submit = |order: OrderRequest| test::TestRequest::post().uri("/api/orders").set_json(order).to_request();
+
+        // A sell given apart from its price is signed at the negated price and rests as an ask
+        let sell = OrderRequest { price: ApiPrice::Units(1000), side: Some(Side::Sell), ..order(-1000, 5) };
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(sell)).await;
+        assert!(resp.success, "{}", resp.message);
+        let buy = OrderRequest { side: Some(Side::Buy), ..order(900, 5) };
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(buy)).await;
+        assert!(resp.success, "{}", resp.message);
+
+        let now = Clock::System.now() / 1_000_000_000;
+        let cases = [
+            // A negative price used to flip a buy into an ask; now it is refused
+            (OrderRequest { price: ApiPrice::Units(-1000), side: Some(Side::Buy), ..order(-1000, 5) }, 1015),
+            (OrderRequest { price: ApiPrice::Units(-1000), side: Some(Side::Sell), ..order(1000, 5) }, 1015),
+            (order(0, 5), 1002),
+            (OrderRequest { price: ApiPrice::from("-0"), side: Some(Side::Sell), ..order(1000, 5) }, 1002),
+            (order(1000, 0), 1001),
+            (order(1000, 101), 1016),
+            (OrderRequest { expiry: Some(now - 1), ..order(1000, 5) }, 1012),
+            (OrderRequest { expiry: Some(now + 5), ..order(1000, 5) }, 1017),
+            (OrderRequest { trader: "0x".to_string() + &"ab".repeat(19), ..order(1000, 5) }, 1003),
+            (OrderRequest { trader: "0x".to_string() + &"ab".repeat(21), ..order(1000, 5) }, 1003),
+            (OrderRequest { nonce: 0, ..order(1000, 5) }, 1018),
+        ];
+        for (request, code) in cases {
+            let resp = test::call_service(&app, submit(request)).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            println!("{}: {}", body.code, body.message);
+            assert_eq!(body.code, code, "{}", body.message);
+        }
+        let body: ErrorResponse = test::call_and_read_body_json(&app, submit(order(1000, 101))).await;
+        assert_eq!(body.details, Some(serde_json::json!({ "max": 100 })));
+
+        // Nothing refused reached the book
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/orderbook").to_request();
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, req).await;
+        let bids: Vec<ApiPrice> = resp.bids.iter().map(|level| level.price.clone()).collect();
+        let asks: Vec<ApiPrice> = resp.asks.iter().map(|level| level.price.clone()).collect();
+        assert_eq!((bids, asks), (vec![ApiPrice::Units(900)], vec![ApiPrice::Units(1000)]));
+    }
+
+    #[actix_web::test]
+    async fn test_funds_checked_at_intake() {
+        use crate::{auth::address_of, funds::MockChain};
+
+        let chain = Arc::new(MockChain::default());
+        let book_registry = Arc::new(BookRegistry::new());
+        let engine = MatchingEngine::auto_creating();
+        let metrics = engine.metrics.clone();
+        let engine = Arc::new(Mutex::new(engine));
+        let state = web::Data::new(AppState {
+            order_intake: Arc::new(RwLock::new(OrderIntake::new().with_registry(book_registry.clone()))),
+            book_registry,
+            metrics,
+            commands: CommandQueue::spawn(engine.clone(), 1_000),
+            engine,
+            snapshot_dir: std::env::temp_dir().join("numena-test-snapshots"),
+            signature_verifier: None,
+            funds_checker: Some(Arc::new(FundsChecker::new(chain.clone()))),
+            shutdown: watch::channel(false).0,
+            readiness: Readiness::new(true, None),
+            idempotency: IdempotencyCache::new(Duration::from_secs(60), 1_000),
+            sequencer: TraderSequencer::new(),
+            ledger: FeeLedger::new(0),
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).chain_id(8453).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (trader, _) = test_trader(0x24);
+        chain.fund([1; 20], address_of(trader.verifying_key()), 10_000);
+        let submit = |price: i32, qty: u64| {
+            test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(signed_order_in(&market.domain(), &trader, price, qty))
+                .to_request()
+        };
+
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(1000, 10)).await;
+        assert!(resp.success);
+        let resp = test::call_service(&app, submit(1000, 11)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let resp: OrderResponse = test::read_body_json(resp).await;
+        println!("Underfunded bid: {}", resp.message);
+        assert_eq!(resp.message, format!("Insufficient funds: order needs 11000 of token 0x{}, 10000 available", "01".repeat(20)));
+
+        // Selling needs the security, which the trader has none of
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(-1000, 1)).await;
+        assert!(!resp.success);
+        assert!(state.engine.lock().await.orderbook_manager.get_best_ask(crate::utils::BookId(0)).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_exports() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let (maker, maker_address) = test_trader(0x41);
+        let (taker, _) = test_trader(0x42);
+        for (key, price, qty) in [(&maker, -1000, 10), (&taker, 1000, 4), (&taker, 1000, 6)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(key, price, qty).to_request()).await;
+            assert!(resp.success);
+        }
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/export?format=csv").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-type").unwrap(), "text/csv; charset=utf-8");
+        let csv = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        println!("Trades:\n{}", csv);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], TRADE_COLUMNS.join(","));
+        assert!(rows[1].starts_with("1,ETH-USD,") && rows[1].contains(",1000,4,buy,"));
+        assert!(rows[2].starts_with("2,ETH-USD,") && rows[2].contains(",1000,6,buy,"));
+
+        // A range past every trade leaves the header alone
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/export?from=18446744073709551615").to_request();
+        let csv = test::call_and_read_body(&app, req).await;
+        assert_eq!(csv, format!("{}\r\n", TRADE_COLUMNS.join(",")).as_bytes());
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/settlements/export").to_request();
+        let csv = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        println!("Settlements:\n{}", csv);
+        let rows: Vec<Vec<&str>> = csv.lines().map(|row| row.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], SETTLEMENT_COLUMNS);
+        assert_eq!((rows[1][0], rows[1][5], rows[1][9], rows[1][12]), ("1", "pending", maker_address.as_str(), "4"));
+        assert_eq!((rows[2][0], rows[2][12]), ("2", "6"));
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/export?format=xlsx").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        let req = test::TestRequest::get().uri("/api/books/BTC-USD/settlements/export").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_settlements() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // One ask swept by two bids gives two pending settlements
+        let (maker, maker_address) = test_trader(0x31);
+        let (taker, _) = test_trader(0x32);
+        for (key, price, qty) in [(&maker, -1000, 10), (&taker, 1000, 4), (&taker, 1000, 6)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(key, price, qty).to_request()).await;
+            assert!(resp.success);
+        }
+
+        let req = test::TestRequest::get().uri("/api/settlements").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        println!("Settlements: {}", body);
+        let settlements = body["settlements"].as_array().unwrap();
+        assert_eq!(settlements.len(), 2);
+        assert_eq!(settlements[0]["settlement_id"], 2);
+        assert_eq!(settlements[0]["status"], "pending");
+        assert_eq!(settlements[0]["exec_qty"], 6);
+        assert_eq!(settlements[0]["order"]["maker"], maker_address);
+
+        state.engine.lock().await.mark_settlement_submitted(1, [0xab; 32]).unwrap();
+        let req = test::TestRequest::get().uri("/api/settlements/1").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "submitted");
+        assert_eq!(body["tx_hash"], format!("0x{}", "ab".repeat(32)));
+        assert!(body.get("batch_id").is_none());
+
+        let req = test::TestRequest::get().uri("/api/settlements?status=pending").to_request();
+        let body: SettlementsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.settlements.iter().map(|s| s.settlement_id).collect::<Vec<_>>(), vec![2]);
+
+        // Both settlements sent together as a batch
+        state.engine.lock().await.mark_settlement_batch_submitted(1, &[1, 2], [0xcd; 32]).unwrap();
+        let req = test::TestRequest::get().uri("/api/settlements/batches/1").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        println!("Batch: {}", body);
+        assert_eq!(body["settlement_ids"], serde_json::json!([1, 2]));
+        assert_eq!(body["status"], "submitted");
+        assert_eq!(body["tx_hash"], format!("0x{}", "cd".repeat(32)));
+        let req = test::TestRequest::get().uri("/api/settlements/2").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["batch_id"], 1);
+        let req = test::TestRequest::get().uri("/api/settlements/batches/2").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get().uri("/api/settlements/3").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_contract_wallet_orders() {
+        use crate::{
+            eip1271::{MockRpc, MockVerdict},
+            market::SIGNATURE_TYPE_EIP1271,
+        };
+
+        let (owner, _) = test_trader(0x12);
+        let wallet = "0x5afe5afe5afe5afe5afe5afe5afe5afe5afe5afe";
+        let cases = [
+            (Some(MockVerdict::Valid), actix_web::http::StatusCode::OK),
+            (Some(MockVerdict::Invalid), actix_web::http::StatusCode::BAD_REQUEST),
+            (Some(MockVerdict::Reverted), actix_web::http::StatusCode::BAD_REQUEST),
+            (Some(MockVerdict::Unavailable), actix_web::http::StatusCode::SERVICE_UNAVAILABLE),
+            (None, actix_web::http::StatusCode::SERVICE_UNAVAILABLE),
+        ];
+        for (verdict, expected) in cases {
+            let book_registry = Arc::new(BookRegistry::new());
+            let engine = MatchingEngine::auto_creating();
+            let metrics = engine.metrics.clone();
+            let engine = Arc::new(Mutex::new(engine));
+            let state = web::Data::new(AppState {
+                order_intake: Arc::new(RwLock::new(OrderIntake::new().with_registry(book_registry.clone()))),
+                book_registry,
+                metrics,
+                commands: CommandQueue::spawn(engine.clone(), 1_000),
+                engine,
+                snapshot_dir: std::env::temp_dir().join("numena-test-snapshots"),
+                signature_verifier: verdict
+                    .map(|verdict| ContractSignatureVerifier::new(Arc::new(MockRpc::new(verdict)))),
+                funds_checker: None,
+                shutdown: watch::channel(false).0,
+                readiness: Readiness::new(true, None),
+                idempotency: IdempotencyCache::new(Duration::from_secs(60), 1_000),
+                sequencer: TraderSequencer::new(),
+                ledger: FeeLedger::new(0),
+            });
+            let app = test::init_service(
+                App::new()
+                    .app_data(state.clone())
+                    .configure(configure_app)
+            ).await;
+            let market = MarketConfig::builder().signature_type(SIGNATURE_TYPE_EIP1271).build();
+            let req = test::TestRequest::post()
+                .uri("/api/books")
+                .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market), book_only: false })
+                .to_request();
+            test::call_service(&app, req).await;
+
+            // The wallet's owner signs, but the order is the wallet's
+            let mut order = signed_order(&owner, 1000, 10);
+            order.trader = wallet.to_string();
+            let digest = Eip712Domain::default().hash_order(&Eip712Order {
+                book: "ETH-USD",
+                trader: parse_trader(wallet).unwrap(),
+                price: 1000,
+                quantity: order.quantity,
+                nonce: order.nonce,
+                expiry: NO_EXPIRY,
+            });
+            order.signature = format!("0x{}", hex::encode(sign_prehash(&owner, &digest)));
+            let req = test::TestRequest::post().uri("/api/orders").set_json(&order).to_request();
+            let resp = test::call_service(&app, req).await;
+            println!("{:?}: {}", verdict, resp.status());
+            assert_eq!(resp.status(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_duplicate_nonce_rejected() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (trader, _) = test_trader(0x12);
+        let order = signed_order(&trader, 1000, 10);
+        let submit = |order: &OrderRequest| test::TestRequest::post().uri("/api/orders").set_json(order).to_request();
+        let first = test::call_service(&app, submit(&order)).await;
+        assert!(first.headers().get(IDEMPOTENT_REPLAY_HEADER).is_none());
+        let first = test::read_body(first).await;
+
+        // A retry gets the first response back, byte for byte, and places nothing
+        let retry = test::call_service(&app, submit(&order)).await;
+        assert_eq!(retry.headers().get(IDEMPOTENT_REPLAY_HEADER).unwrap(), "true");
+        let retry = test::read_body(retry).await;
+        println!("Retry: {}", String::from_utf8_lossy(&retry));
+        assert_eq!(retry, first);
+        assert_eq!(state.engine.lock().await.orderbook_manager.get_best_bid_size(crate::utils::BookId(0)), Some(Qty(10)));
+
+        // Anything else under the same nonce is a conflict
+        let altered = OrderRequest { price: ApiPrice::Units(1005), ..order };
+        let resp = test::call_service(&app, submit(&altered)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.code, 2009);
+        assert_eq!(state.engine.lock().await.orderbook_manager.get_best_bid_size(crate::utils::BookId(0)), Some(Qty(10)));
+    }
+
+    #[actix_web::test]
+    async fn test_idempotency_key() {
+        use crate::api_idempotency::IDEMPOTENCY_KEY_HEADER;
+        use actix_web::http::StatusCode;
+
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x15);
+        let submit = |order: &OrderRequest, key: &str| {
+            test::TestRequest::post().uri("/api/orders").insert_header((IDEMPOTENCY_KEY_HEADER, key.to_string())).set_json(order).to_request()
+        };
+
+        let order = signed_order(&trader, 1000, 10);
+        let first: OrderResponse = test::call_and_read_body_json(&app, submit(&order, "retry-1")).await;
+        let retry: OrderResponse = test::call_and_read_body_json(&app, submit(&order, "retry-1")).await;
+        assert_eq!((first.order_id, retry.order_id), (Some(0), Some(0)));
+
+        // The key can't be reused for another order, even one signed with a fresh nonce
+        let resp = test::call_service(&app, submit(&signed_order(&trader, 1000, 10), "retry-1")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = test::call_service(&app, submit(&signed_order(&trader, 1000, 10), "")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // Keys are the trader's own
+        let (other, _) = test_trader(0x16);
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&signed_order(&other, 1000, 10), "retry-1")).await;
+        assert_eq!(resp.order_id, Some(1));
+
+        // Retries of a pair are answered the same way
+        let pair = OcoRequest {
+            orders: [signed_order(&trader, 900, 5), OrderRequest { order_type: OrderType::Stop, trigger_price: Some(ApiPrice::Units(1100)), ..signed_order(&trader, 1100, 5) }],
+            on_fill: OcoPolicy::Cancel,
+            cancel_together: false,
+        };
+        let oco = || test::TestRequest::post().uri("/api/orders/oco").set_json(&pair).to_request();
+        let first: OcoResponse = test::call_and_read_body_json(&app, oco()).await;
+        let retry: OcoResponse = test::call_and_read_body_json(&app, oco()).await;
+        println!("Pair: {:?} then {:?}", first.order_ids, retry.order_ids);
+        assert_eq!((first.success, first.order_ids), (true, retry.order_ids));
+        let engine = state.engine.lock().await;
+        assert_eq!((engine.orderbook_manager.oid_map.len(), engine.stops().len()), (3, 1));
+    }
+
+    #[actix_web::test]
+    async fn test_queue_backpressure() {
+        use actix_web::http::StatusCode;
+
+        let state = test_state_with_queue(1);
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x17);
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, 1000, 10).to_request()).await;
+        assert_eq!(resp.order_id, Some(0));
+
+        // With the worker held up, one order fills the queue; the next is refused, a cancel still goes in
+        let held = state.engine.lock().await;
+        let queued = test::call_service(&app, order_request(&trader, 990, 10).to_request());
+        // The order is verified off the worker first, so the cancel waits for it to be queued
+        let cancel = async {
+            while state.commands.depth() < 1 {
+                tokio::task::yield_now().await;
+            }
+            test::call_service(&app, test::TestRequest::delete().uri("/api/orders/0").to_request()).await
+        };
+        let refused = async {
+            while state.commands.depth() < 2 {
+                tokio::task::yield_now().await;
+            }
+            let resp = test::call_service(&app, order_request(&trader, 980, 10).to_request()).await;
+            drop(held);
+            resp
+        };
+        let (queued, cancel, refused) = tokio::join!(queued, cancel, refused);
+        assert_eq!((queued.status(), cancel.status()), (StatusCode::OK, StatusCode::OK));
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: ErrorResponse = test::read_body_json(refused).await;
+        println!("Refused: {} {}", body.code, body.message);
+        assert_eq!(body.code, 5005);
+
+        // Only the queued order rests, and the refused one never took an order ID
+        let mut engine = state.engine.lock().await;
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(1)).is_some());
+        assert_eq!((engine.orderbook_manager.oid_map.len(), engine.next_order_id()), (1, OrderId(2)));
+    }
+
+    #[actix_web::test]
+    async fn test_trader_order_kept_through_verification() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let (trader, _) = test_trader(0x18);
+        let orders: Vec<OrderRequest> = (0..8).map(|i| signed_order(&trader, 1000 - i, 10)).collect();
+
+        // Verified side by side on the blocking pool, the orders still enter in the order they came in
+        let placed = futures_util::future::join_all(orders.iter().map(|order| place_order(order, Identity::Unchecked, &state))).await;
+        let order_ids: Vec<Option<u64>> = placed.into_iter().map(|placed| placed.unwrap().order_id).collect();
+        println!("Order IDs: {:?}", order_ids);
+        assert_eq!(order_ids, (0..8).map(Some).collect::<Vec<_>>());
+
+        // A refused order gives its place up, so the trader's next order isn't held behind it
+        let unsigned = OrderRequest { signature: format!("0x{}", "11".repeat(65)), ..signed_order(&trader, 990, 10) };
+        let (refused, placed) = tokio::join!(
+            place_order(&unsigned, Identity::Unchecked, &state),
+            place_order(&orders[0], Identity::Unchecked, &state),
+        );
+        assert_eq!((refused.err(), placed.err()), (Some(ApiError::InvalidSignature), Some(ApiError::InvalidNonce)));
+        assert!(state.sequencer.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_nonce_bump_cancels_older_orders() {
+        use crate::auth::personal_sign;
+
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        // Nonces increase with each signed order, so the last two are the newest
+        let (trader, address) = test_trader(0x12);
+        let orders: Vec<OrderRequest> = [990, 995, 1000, -1010].into_iter().map(|price| signed_order(&trader, price, 10)).collect();
+        for order in &orders {
+            let req = test::TestRequest::post().uri("/api/orders").set_json(order).to_request();
+            let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+            assert!(resp.success);
+        }
+        let min_nonce = orders[2].nonce;
+
+        // A bump signed by someone else is refused
+        let (other, _) = test_trader(0x22);
+        let message = nonce_bump_message(parse_trader(&address).unwrap(), min_nonce);
+        let forged = NonceBumpRequest {
+            min_nonce,
+            signature: format!("0x{}", hex::encode(personal_sign(&other, message.as_bytes()))),
+        };
+        let req = test::TestRequest::post().uri(&format!("/api/traders/{}/nonce", address)).set_json(&forged).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+
+        let bump = NonceBumpRequest {
+            min_nonce,
+            signature: format!("0x{}", hex::encode(personal_sign(&trader, message.as_bytes()))),
+        };
+        let req = test::TestRequest::post().uri(&format!("/api/traders/{}/nonce", address)).set_json(&bump).to_request();
+        let resp: NonceBumpResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Bump: {}", resp.message);
+        assert!(resp.success);
+        assert_eq!(resp.min_nonce, Some(min_nonce));
+        assert_eq!(resp.cancelled, vec![0, 1]);
+
+        {
+            let engine = state.engine.lock().await;
+            let manager = &engine.orderbook_manager;
+            assert!(manager.oid_map.get(OrderId(2)).is_some());
+            assert!(manager.oid_map.get(OrderId(3)).is_some());
+            assert_eq!(manager.get_best_bid_size(crate::utils::BookId(0)), Some(Qty(10)));
+        }
+
+        // Orders signed with an invalidated nonce cannot be resubmitted either: a retry is only
+        // answered with its first response, and an altered order is refused
+        let req = test::TestRequest::post().uri("/api/orders").set_json(&orders[0]).to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.order_id, Some(0));
+        assert!(state.engine.lock().await.orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        let altered = OrderRequest { quantity: 5, ..signed_order(&trader, 990, 10) };
+        let altered = OrderRequest { nonce: orders[0].nonce, ..altered };
+        let req = test::TestRequest::post().uri("/api/orders").set_json(&altered).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_all_for_trader() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (maker, maker_address) = test_trader(0x11);
+        let (other, _) = test_trader(0x22);
+        for (trader, price) in [(&maker, 1000), (&maker, -1010), (&other, 990)] {
+            let req = order_request(trader, price, 10).to_request();
+            let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+            assert!(resp.success);
+        }
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/traders/{}/orders?book_id=ETH-USD", maker_address))
+            .to_request();
+        let resp: CancelAllResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        assert_eq!(resp.cancelled, vec![0, 1]);
+
+        let engine = state.engine.lock().await;
+        assert!(engine.orderbook_manager.oid_map.get(crate::order::OrderId(2)).is_some());
+        assert_eq!(engine.orderbook_manager.get_best_ask(crate::utils::BookId(0)), None);
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_order() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (maker, _) = test_trader(0x11);
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 1000, 10).to_request()).await;
+
+        let req = test::TestRequest::delete().uri("/api/orders/0").to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        assert_eq!(resp.order_id, Some(0));
+
+        // The second cancel finds nothing to cancel rather than pretending to
+        let req = test::TestRequest::delete().uri("/api/orders/0").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        let resp: OrderResponse = test::read_body_json(resp).await;
+        println!("Second cancel: {:?}", resp.message);
+        assert!(!resp.success);
+
+        let engine = state.engine.lock().await;
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(engine.orderbook_manager.get_best_bid(crate::utils::BookId(0)), None);
+    }
+
+    #[actix_web::test]
+    async fn test_stop_orders() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x21);
+        let (trader, _) = test_trader(0x22);
+        let (taker, _) = test_trader(0x23);
+        let stop_request = |price: i32, order_type: OrderType, trigger_price: Option<u32>| {
+            let order = OrderRequest { order_type, trigger_price: trigger_price.map(ApiPrice::from), ..signed_order(&trader, price, 5) };
+            test::TestRequest::post().uri("/api/orders").set_json(order).to_request()
+        };
+        let status_request = |order_id: u64| test::TestRequest::get().uri(&format!("/api/orders/{}", order_id)).to_request();
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1010, 10).to_request()).await;
+
+        // A stop needs a trigger
+        let resp = test::call_service(&app, stop_request(1000, OrderType::Stop, None)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        // A stop-limit buy at 1000, fired by a print at 1005 or above
+        let resp: OrderResponse = test::call_and_read_body_json(&app, stop_request(1000, OrderType::StopLimit, Some(1005))).await;
+        println!("Stop: {:?}", resp.message);
+        assert!(resp.success);
+        assert_eq!(resp.status.map(|update| update.status), Some(OrderStatus::Untriggered));
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(1)).await;
+        assert_eq!((resp.status, resp.remaining_quantity), (Some(OrderStatus::Untriggered), 5));
+
+        // Untriggered stops can be cancelled by ID
+        let _: OrderResponse = test::call_and_read_body_json(&app, stop_request(-900, OrderType::Stop, Some(900))).await;
+        let req = test::TestRequest::delete().uri("/api/orders/2").to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        let resp = test::call_service(&app, status_request(2)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        // A print at 1010 fires the stop-limit, which rests at 1000
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1010, 2).to_request()).await;
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(1)).await;
+        assert_eq!((resp.status, resp.remaining_quantity), (Some(OrderStatus::Triggered), 5));
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(0)).await;
+        assert_eq!((resp.status, resp.remaining_quantity), (Some(OrderStatus::New), 8));
+        assert!(state.engine.lock().await.stops().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_kill_switch() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x43);
+        for price in [990, 980] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, price, 10).to_request()).await;
+            assert!(resp.success);
+        }
+        let kill_switch = |engaged: bool| {
+            test::TestRequest::post()
+                .uri("/api/admin/killswitch")
+                .set_json(KillSwitchRequest { engaged })
+                .to_request()
+        };
+        let resp: KillSwitchResponse = test::call_and_read_body_json(&app, kill_switch(true)).await;
+        assert_eq!((resp.success, resp.engaged), (true, true));
+        let req = test::TestRequest::get().uri("/api/health").to_request();
+        let resp: HealthResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((resp.status.as_str(), resp.halted), ("halted", true));
+
+        // Limit, stop, and pegged orders, OCO pairs, and replaces are all refused with 423
+        let requests = [
+            order_request(&trader, -1010, 5).to_request(),
+            test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(OrderRequest { order_type: OrderType::Stop, trigger_price: Some(ApiPrice::Units(1005)), ..signed_order(&trader, 1000, 5) })
+                .to_request(),
+            test::TestRequest::post()
+                .uri("/api/orders")
+                .set_json(OrderRequest { order_type: OrderType::MidpointPeg, ..signed_order(&trader, 1000, 5) })
+                .to_request(),
+            test::TestRequest::post()
+                .uri("/api/orders/oco")
+                .set_json(OcoRequest {
+                    orders: [signed_order(&trader, -1050, 5), signed_order(&trader, -1060, 5)],
+                    on_fill: OcoPolicy::Cancel,
+                    cancel_together: false,
+                })
+                .to_request(),
+            test::TestRequest::post()
+                .uri("/api/orders/0/replace")
+                .set_json(ReplaceOrderRequest { price: ApiPrice::Units(995), quantity: 10 })
+                .to_request(),
+        ];
+        for req in requests {
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::LOCKED);
+        }
+
+        // Traders can still look at and cancel what they have resting
+        let req = test::TestRequest::get().uri("/api/orders/0").to_request();
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.remaining_quantity, 10);
+        let req = test::TestRequest::delete().uri("/api/orders/0").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/orderbook").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Nothing refused was accepted, and releasing the switch lets orders in again
+        assert_eq!(state.engine.lock().await.orderbook_manager.get_best_ask(BookId(0)), None);
+        let resp: KillSwitchResponse = test::call_and_read_body_json(&app, kill_switch(false)).await;
+        assert_eq!((resp.success, resp.engaged), (true, false));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, -1010, 5).to_request()).await;
+        assert!(resp.success);
+    }
+
+    #[actix_web::test]
+    async fn test_error_codes() {
+        use actix_web::http::StatusCode;
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, address) = test_trader(0x51);
+        let replayed = signed_order(&trader, 1000, 10);
+        let resp: OrderResponse = test::call_and_read_body_json(&app, test::TestRequest::post().uri("/api/orders").set_json(&replayed).to_request()).await;
+        assert!(resp.success);
+        // An order whose nonce was invalidated before it was ever sent
+        let stale = signed_order(&trader, 1000, 5);
+        let message = nonce_bump_message(parse_trader(&address).unwrap(), stale.nonce + 1);
+        let bump = NonceBumpRequest {
+            min_nonce: stale.nonce + 1,
+            signature: format!("0x{}", hex::encode(crate::auth::personal_sign(&trader, message.as_bytes()))),
+        };
+        let req = test::TestRequest::post().uri(&format!("/api/traders/{}/nonce", address)).set_json(&bump).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // An order signed with an expiry that has passed
+        let mut expired = signed_order(&trader, 1000, 5);
+        let digest = Eip712Domain::default().hash_order(&Eip712Order {
+            book: "ETH-USD",
+            trader: parse_trader(&address).unwrap(),
+            price: 1000,
+            quantity: 5,
+            nonce: expired.nonce,
+            expiry: 1,
+        });
+        expired.expiry = Some(1);
+        expired.signature = format!("0x{}", hex::encode(sign_prehash(&trader, &digest)));
+
+        let post = |uri: &str, body: serde_json::Value| test::TestRequest::post().uri(uri).set_json(body).to_request();
+        let order = |order: OrderRequest| serde_json::to_value(order).unwrap();
+        let cases = [
+            (post("/api/orders", order(signed_order(&trader, 1000, 0))), StatusCode::BAD_REQUEST, 1001),
+            (post("/api/orders/0/replace", serde_json::json!({ "price": 0, "quantity": 5 })), StatusCode::BAD_REQUEST, 1002),
+            (test::TestRequest::get().uri("/api/traders/0x12/positions").to_request(), StatusCode::BAD_REQUEST, 1003),
+            (
+                post("/api/orders", order(OrderRequest { signature: format!("0x{}", "11".repeat(65)), ..signed_order(&trader, 1000, 5) })),
+                StatusCode::BAD_REQUEST,
+                1004,
+            ),
+            (post("/api/orders", order(stale)), StatusCode::BAD_REQUEST, 1005),
+            (
+                post("/api/orders", order(OrderRequest { order_type: OrderType::Stop, ..signed_order(&trader, 1000, 5) })),
+                StatusCode::BAD_REQUEST,
+                1006,
+            ),
+            (test::TestRequest::get().uri("/api/books/ETH-USD/estimate?side=up&qty=1").to_request(), StatusCode::BAD_REQUEST, 1006),
+            (
+                post("/api/traders/0x1234567890123456789012345678901234567890/nonce", serde_json::json!({ "min_nonce": 5, "signature": "0x00" })),
+                StatusCode::UNAUTHORIZED,
+                1010,
+            ),
+            (
+                post("/api/orders", order(OrderRequest { book_id: "BTC-USD".to_string(), ..signed_order(&trader, 1000, 5) })),
+                StatusCode::NOT_FOUND,
+                2001,
+            ),
+            (test::TestRequest::get().uri("/api/books/BTC-USD/bbo").to_request(), StatusCode::NOT_FOUND, 2001),
+            (test::TestRequest::delete().uri("/api/orders/99").to_request(), StatusCode::NOT_FOUND, 2002),
+            (test::TestRequest::get().uri("/api/orders/99").to_request(), StatusCode::NOT_FOUND, 2002),
+            (test::TestRequest::get().uri("/api/books/ETH-USD/market").to_request(), StatusCode::NOT_FOUND, 2003),
+            (test::TestRequest::get().uri("/api/settlements/99").to_request(), StatusCode::NOT_FOUND, 2004),
+            (test::TestRequest::get().uri("/api/settlements/batches/99").to_request(), StatusCode::NOT_FOUND, 2005),
+            (post("/api/books", serde_json::json!({ "book_id": "ETH-USD", "book_only": true })), StatusCode::CONFLICT, 2006),
+            (post("/api/orders", order(expired)), StatusCode::BAD_REQUEST, 1012),
+            (post("/api/orders", order(OrderRequest { quantity: 11, ..replayed })), StatusCode::CONFLICT, 2009),
+            (post("/api/admin/books/ETH-USD/uncross", serde_json::json!({})), StatusCode::BAD_REQUEST, 3004),
+        ];
+        for (req, status, code) in cases {
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            println!("{} {}: {}", status, body.code, body.message);
+            assert_eq!((body.success, body.code), (false, code));
+        }
+
+        // Details carry what the client needs to act on the error
+        let req = post("/api/admin/books/ETH-USD/uncross", serde_json::json!({}));
+        let body: ErrorResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.details, Some(serde_json::json!({ "book_id": 0 })));
+
+        let req = post("/api/admin/killswitch", serde_json::json!({ "engaged": true }));
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let resp = test::call_service(&app, order_request(&trader, 1000, 5).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!((body.code, body.details), (3001, None));
+    }
+
+    #[actix_web::test]
+    async fn test_authentication() {
+        use crate::{
+            api_auth::{signed_request_message, ADDRESS_HEADER, API_KEY_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+            auth::personal_sign,
+            config::{ApiKeySetting, AuthSettings},
+        };
+        use actix_web::http::StatusCode;
+
+        let (maker, maker_address) = test_trader(0x12);
+        let (taker, _) = test_trader(0x98);
+        let settings = AuthSettings {
+            admin_keys: vec!["admin-key".to_string()],
+            api_keys: vec![ApiKeySetting { key: "maker-key".to_string(), trader: maker_address.clone() }],
+            ..AuthSettings::default()
+        };
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(web::Data::new(Authenticator::new(&settings)))
+                .wrap(actix_web::middleware::from_fn(authenticate))
+                .configure(configure_app)
+        ).await;
+
+        let book = serde_json::json!({ "book_id": "ETH-USD", "book_only": true });
+        let with_key = |req: test::TestRequest, key: &str| req.insert_header((API_KEY_HEADER, key.to_string()));
+        let signed = |req: test::TestRequest, key: &SigningKey, path: &str, timestamp: u64, nonce: &str| {
+            let message = signed_request_message("DELETE", path, timestamp, nonce, b"");
+            req.insert_header((ADDRESS_HEADER, format!("0x{}", hex::encode(address_of(key.verifying_key())))))
+                .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+                .insert_header((NONCE_HEADER, nonce.to_string()))
+                .insert_header((SIGNATURE_HEADER, format!("0x{}", hex::encode(personal_sign(key, message.as_bytes())))))
+        };
+        let now = Clock::System.now() / 1_000_000_000;
+
+        let resp = test::call_service(&app, with_key(test::TestRequest::post().uri("/api/books").set_json(&book), "admin-key").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, with_key(order_request(&maker, 1000, 10), "maker-key").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Reads need no credentials
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let cases = [
+            (test::TestRequest::post().uri("/api/books").set_json(&book), StatusCode::UNAUTHORIZED, Some("missing_credentials")),
+            (with_key(order_request(&maker, 1000, 10), "guess"), StatusCode::UNAUTHORIZED, Some("invalid_api_key")),
+            (with_key(test::TestRequest::post().uri("/api/books").set_json(&book), "maker-key"), StatusCode::FORBIDDEN, None),
+            (with_key(test::TestRequest::delete().uri("/api/books/ETH-USD"), "maker-key"), StatusCode::FORBIDDEN, None),
+            // A trader's key doesn't act for another trader
+            (with_key(order_request(&taker, 1000, 10), "maker-key"), StatusCode::FORBIDDEN, None),
+            (signed(test::TestRequest::delete().uri("/api/orders/0"), &taker, "/api/orders/0", now, "n1"), StatusCode::FORBIDDEN, None),
+            (signed(test::TestRequest::delete().uri("/api/orders/0"), &maker, "/api/orders/1", now, "n2"), StatusCode::UNAUTHORIZED, Some("invalid_signature")),
+            (signed(test::TestRequest::delete().uri("/api/orders/0"), &maker, "/api/orders/0", now - 3600, "n3"), StatusCode::UNAUTHORIZED, Some("expired")),
+        ];
+        for (req, status, reason) in cases {
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), status);
+            let body: ErrorResponse = test::read_body_json(resp).await;
+            println!("{} {}: {}", status, body.code, body.message);
+            assert_eq!(body.details.map(|details| details["reason"].clone()), reason.map(|reason| serde_json::json!(reason)));
+        }
+
+        // The owner's signed cancel goes through once; the same request again is a replay
+        let cancel = || signed(test::TestRequest::delete().uri("/api/orders/0"), &maker, "/api/orders/0", now, "n4");
+        let resp = test::call_service(&app, cancel().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, cancel().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        println!("Replayed: {}", body.message);
+        assert_eq!(body.details.unwrap()["reason"], "replayed");
+    }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let scrape = || async {
+            let req = test::TestRequest::get().uri("/metrics").to_request();
+            String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap()
+        };
+        let value = |text: &str, metric: &str| {
+            let line = text.lines().find(|line| line.starts_with(&format!("{} ", metric)));
+            line.and_then(|line| line.rsplit(' ').next()?.parse::<f64>().ok())
+        };
+        let before = scrape().await;
+        assert_eq!(value(&before, "numena_orders_received_total"), Some(0.0));
+
+        let (maker, _) = test_trader(0x12);
+        let (taker, _) = test_trader(0x98);
+        for req in [order_request(&maker, -101, 10), order_request(&taker, 101, 4), order_request(&taker, 101, 0)] {
+            test::call_service(&app, req.to_request()).await;
+        }
+
+        let after = scrape().await;
+        println!("{}", after);
+        assert_eq!(value(&after, "numena_orders_received_total"), Some(3.0));
+        assert_eq!(value(&after, "numena_orders_accepted_total"), Some(2.0));
+        assert_eq!(value(&after, "numena_orders_rejected_total{code=\"1001\"}"), Some(1.0));
+        assert_eq!(value(&after, "numena_matches_total{book=\"ETH-USD\"}"), Some(1.0));
+        assert_eq!(value(&after, "numena_traded_volume_total{book=\"ETH-USD\"}"), Some(4.0));
+        assert_eq!(value(&after, "numena_best_ask{book=\"ETH-USD\"}"), Some(101.0));
+        assert_eq!(value(&after, "numena_book_depth{book=\"ETH-USD\",side=\"ask\"}"), Some(6.0));
+        assert_eq!(value(&after, "numena_match_latency_seconds_count"), Some(2.0));
+        assert!(value(&after, "numena_engine_lock_wait_seconds_count") > value(&before, "numena_engine_lock_wait_seconds_count"));
+    }
+
+    #[actix_web::test]
+    async fn test_readiness() {
+        // A restore that hasn't finished: the probes answer and everything else is refused
+        let state = test_state();
+        state.readiness.restored.store(false, Ordering::Release);
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap(actix_web::middleware::from_fn(require_restored))
+                .configure(configure_app)
+        ).await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+        let resp = test::call_service(&app, get("/healthz")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        let resp = test::call_service(&app, get("/readyz")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: ReadinessResponse = test::read_body_json(resp).await;
+        assert_eq!((readiness.ready, readiness.restored, readiness.settlement_connected), (false, false, None));
+        let (maker, _) = test_trader(0x12);
+        let resp = test::call_service(&app, order_request(&maker, -101, 10).to_request()).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        let error: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(error.code, 5004);
+        let status: StatusResponse = test::call_and_read_body_json(&app, get("/api/status")).await;
+        assert_eq!((status.restored, status.resting_orders), (false, 0));
+
+        // Restored, the server takes orders and is ready until the kill switch is engaged
+        state.readiness.restored.store(true, Ordering::Release);
+        let (taker, _) = test_trader(0x98);
+        for req in [order_request(&maker, -101, 10), order_request(&taker, 101, 4)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, req.to_request()).await;
+            assert!(resp.success);
+        }
+        let readiness: ReadinessResponse = test::call_and_read_body_json(&app, get("/readyz")).await;
+        assert_eq!((readiness.ready, readiness.restored, readiness.halted), (true, true, false));
+        let status: StatusResponse = test::call_and_read_body_json(&app, get("/api/status")).await;
+        println!("Status: {}", serde_json::to_string(&status).unwrap());
+        assert_eq!((status.restored, status.book_count, status.resting_orders), (true, 1, 1));
+        assert_eq!(status.books[0].book_id, "ETH-USD");
+        assert!(status.books[0].seq > 0 && status.event_seq > 0);
+        assert!(status.books[0].last_trade_time.is_some());
+
+        state.engine.lock().await.set_halted(true);
+        let resp = test::call_service(&app, get("/readyz")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: ReadinessResponse = test::read_body_json(resp).await;
+        assert_eq!((readiness.ready, readiness.halted), (false, true));
+    }
+
+    #[actix_web::test]
+    async fn test_oco_pair() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x41);
+        let (taker, _) = test_trader(0x42);
+        let pair = |stop_type: OrderType| OcoRequest {
+            orders: [
+                signed_order(&trader, -1050, 5),
+                OrderRequest { order_type: stop_type, trigger_price: Some(ApiPrice::Units(950)), ..signed_order(&trader, -900, 5) },
+            ],
+            on_fill: OcoPolicy::Cancel,
+            cancel_together: false,
+        };
+        let status_request = |order_id: u64| test::TestRequest::get().uri(&format!("/api/orders/{}", order_id)).to_request();
+
+        // Only limit and stop orders pair up
+        let req = test::TestRequest::post().uri("/api/orders/oco").set_json(pair(OrderType::MidpointPeg)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        // A take-profit at 1050 and a stop at 950
+        let req = test::TestRequest::post().uri("/api/orders/oco").set_json(pair(OrderType::Stop)).to_request();
+        let resp: OcoResponse = test::call_and_read_body_json(&app, req).await;
+        println!("OCO: {:?}", resp.message);
+        assert!(resp.success);
+        let (take_profit, stop) = (resp.order_ids[0], resp.order_ids[1]);
+        assert_eq!(resp.group_id, Some(take_profit));
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(stop)).await;
+        assert_eq!(resp.status, Some(OrderStatus::Untriggered));
+        assert_eq!(resp.oco, Some(OcoLink { group_id: take_profit, sibling_order_id: take_profit }));
+
+        // Filling the take-profit cancels the stop
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1050, 5).to_request()).await;
+        let resp = test::call_service(&app, status_request(stop)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        assert!(state.engine.lock().await.oco().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_pegged_orders() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x31);
+        let (trader, _) = test_trader(0x32);
+        let peg_request = |price: i32, order_type: OrderType, peg_offset: Option<i32>| {
+            let order = OrderRequest { order_type, peg_offset, ..signed_order(&trader, price, 5) };
+            test::TestRequest::post().uri("/api/orders").set_json(order).to_request()
+        };
+        let status_request = |order_id: u64| test::TestRequest::get().uri(&format!("/api/orders/{}", order_id)).to_request();
+
+        // Nothing to peg to yet
+        let resp = test::call_service(&app, peg_request(1005, OrderType::MidpointPeg, None)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 990, 10).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1010, 10).to_request()).await;
+
+        // An offset only goes with a primary peg
+        let resp = test::call_service(&app, peg_request(1005, OrderType::MidpointPeg, Some(1))).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        // A midpoint peg buys at 1000, and a primary peg 2 ticks behind the best bid at 988
+        let resp: OrderResponse = test::call_and_read_body_json(&app, peg_request(1005, OrderType::MidpointPeg, None)).await;
+        println!("Peg: {:?}", resp.message);
+        let mid_id = resp.order_id.unwrap();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, peg_request(1005, OrderType::PrimaryPeg, Some(-2))).await;
+        let primary_id = resp.order_id.unwrap();
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(mid_id)).await;
+        assert_eq!((resp.status, resp.price), (Some(OrderStatus::New), Some(ApiPrice::Units(1000))));
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(primary_id)).await;
+        assert_eq!(resp.price, Some(ApiPrice::Units(988)));
+
+        // A new best ask moves the midpoint, which the status shows
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1004, 10).to_request()).await;
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, status_request(mid_id)).await;
+        assert_eq!(resp.price, Some(ApiPrice::Units(997)));
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_order_by_handle() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (maker, _) = test_trader(0x11);
+        let first: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 1000, 10).to_request()).await;
+        let first_handle = first.handle.unwrap();
+        let req = test::TestRequest::delete().uri(&format!("/api/orders/0?handle={}", first_handle)).to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+
+        // The next order takes over the slot, which the first handle must not reach
+        let second: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 1000, 10).to_request()).await;
+        let second_handle = second.handle.unwrap();
+        println!("Handles: {} then {}", first_handle, second_handle);
+        assert_eq!(second_handle as u32, first_handle as u32);
+        let req = test::TestRequest::delete().uri(&format!("/api/orders/1?handle={}", first_handle)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        assert!(state.engine.lock().await.orderbook_manager.oid_map.get(OrderId(1)).is_some());
+
+        let req = test::TestRequest::delete().uri(&format!("/api/orders/1?handle={}", second_handle)).to_request();
+        let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        assert!(state.engine.lock().await.orderbook_manager.oid_map.get(OrderId(1)).is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_client_order_ids() {
+        use crate::config::{ApiKeySetting, AuthSettings};
+        use actix_web::http::StatusCode;
+
+        let (maker, maker_address) = test_trader(0x13);
+        let (other, other_address) = test_trader(0x14);
+        let settings = AuthSettings {
+            api_keys: vec![
+                ApiKeySetting { key: "maker-key".to_string(), trader: maker_address.clone() },
+                ApiKeySetting { key: "other-key".to_string(), trader: other_address.clone() },
+            ],
+            ..AuthSettings::default()
+        };
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(web::Data::new(Authenticator::new(&settings)))
+                .wrap(actix_web::middleware::from_fn(authenticate))
+                .configure(configure_app)
+        ).await;
+        let submit = |key: &SigningKey, api_key: &str, price: i32, client_order_id: &str| {
+            let order = OrderRequest { client_order_id: Some(client_order_id.to_string()), ..signed_order(key, price, 10) };
+            test::TestRequest::post().uri("/api/orders").insert_header(("x-api-key", api_key.to_string())).set_json(order)
+        };
+
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&maker, "maker-key", 1000, "quote-1").to_request()).await;
+        assert_eq!(resp.client_order_id.map(|id| id.to_string()), Some("quote-1".to_string()));
+        assert_eq!(resp.status.unwrap().client_order_id, resp.client_order_id);
+
+        // The ID is taken while its order works, but only for its own trader
+        let resp = test::call_service(&app, submit(&maker, "maker-key", 990, "quote-1").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        println!("Duplicate: {} {}", body.code, body.message);
+        assert_eq!((body.code, body.details.unwrap()["client_order_id"].clone()), (2008, serde_json::json!("quote-1")));
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&other, "other-key", 990, "quote-1").to_request()).await;
+        assert_eq!(resp.order_id, Some(1));
+        let resp = test::call_service(&app, submit(&maker, "maker-key", 990, &"x".repeat(37)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let lookup = |trader: &str| test::TestRequest::get().uri(&format!("/api/orders/by-client-id/quote-1?trader={}", trader)).to_request();
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, lookup(&maker_address)).await;
+        assert_eq!((resp.order_id, resp.remaining_quantity), (0, 10));
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, lookup(&other_address)).await;
+        assert_eq!(resp.order_id, 1);
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/api/orders/by-client-id/quote-1").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // A cancel by client ID is for the trader the request is authenticated as
+        let cancel = |uri: &str, api_key: &str| {
+            test::TestRequest::delete().uri(uri).insert_header(("x-api-key", api_key.to_string())).to_request()
+        };
+        let resp = test::call_service(&app, cancel(&format!("/api/orders/by-client-id/quote-1?trader={}", other_address), "maker-key")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp: OrderResponse = test::call_and_read_body_json(&app, cancel("/api/orders/by-client-id/quote-1", "maker-key")).await;
+        assert_eq!((resp.success, resp.order_id), (true, Some(0)));
+        assert_eq!(resp.client_order_id.map(|id| id.to_string()), Some("quote-1".to_string()));
+        let resp = test::call_service(&app, cancel("/api/orders/by-client-id/quote-1", "maker-key")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, lookup(&other_address)).await;
+        assert_eq!(resp.order_id, 1);
+
+        // Once its order is done the ID is free again
+        let resp: OrderResponse = test::call_and_read_body_json(&app, submit(&maker, "maker-key", 980, "quote-1").to_request()).await;
+        assert!(resp.success);
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, lookup(&maker_address)).await;
+        println!("Rebound to order {}", resp.order_id);
+        assert_eq!(resp.order_id, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_replace_order_rests() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (maker, _) = test_trader(0x11);
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 1000, 10).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1100, 10).to_request()).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/orders/0/replace")
+            .set_json(&ReplaceOrderRequest { price: ApiPrice::Units(1050), quantity: 20 })
+            .to_request();
+        let resp: ReplaceOrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        assert_eq!(resp.order_id, Some(2));
+        assert_eq!(resp.remaining_quantity, 20);
+        assert!(resp.fills.is_empty());
+
+        let engine = state.engine.lock().await;
+        let manager = &engine.orderbook_manager;
+        assert!(manager.oid_map.get(OrderId(0)).is_none());
+        let replaced = manager.oid_map.get_order(OrderId(2)).unwrap();
+        assert_eq!(replaced.qty(), Qty(20));
+        assert_eq!(replaced.trader(), Some(address_of(maker.verifying_key())));
+        assert!(replaced.signature().to_full().is_some());
+        assert_eq!(manager.get_best_bid(crate::utils::BookId(0)).unwrap().absolute(), 1050);
+    }
+
+    #[actix_web::test]
+    async fn test_replace_order_crosses() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (maker, _) = test_trader(0x11);
+        let (seller, _) = test_trader(0x22);
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 1000, 10).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&seller, -1100, 4).to_request()).await;
+
+        // Moving the bid through the offer fills against it and rests the remainder.
+        let req = test::TestRequest::post()
+            .uri("/api/orders/0/replace")
+            .set_json(&ReplaceOrderRequest { price: ApiPrice::Units(1100), quantity: 10 })
+            .to_request();
+        let resp: ReplaceOrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.success);
+        assert_eq!(resp.remaining_quantity, 6);
+        assert_eq!(resp.fills.len(), 1);
+        assert_eq!(resp.fills[0].price, ApiPrice::Units(1100));
+        assert_eq!(resp.fills[0].quantity, 4);
+
+        let engine = state.engine.lock().await;
+        let manager = &engine.orderbook_manager;
+        assert_eq!(manager.get_best_ask(crate::utils::BookId(0)), None);
+        assert_eq!(manager.oid_map.get(OrderId(2)).unwrap().qty(), Qty(6));
+    }
+
+    #[actix_web::test]
+    async fn test_replace_unknown_order() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/orders/42/replace")
+            .set_json(&ReplaceOrderRequest { price: ApiPrice::Units(1000), quantity: 10 })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        let engine = state.engine.lock().await;
+        assert_eq!(engine.orderbook_manager.get_best_bid(crate::utils::BookId(0)), None);
+    }
+
+    #[actix_web::test]
+    async fn test_get_orderbook_depth_and_ordering() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        // Levels arrive out of price order on both sides
+        let (trader, _) = test_trader(0x11);
+        for price in [990, 1000, 970, 980, -1030, -1010, -1040, -1020] {
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, price, 10).to_request()).await;
+        }
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, 1000, 5).to_request()).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/books/ETH-USD/orderbook")
+            .to_request();
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, req).await;
+        let bid_prices: Vec<ApiPrice> = resp.bids.iter().map(|level| level.price.clone()).collect();
+        let ask_prices: Vec<ApiPrice> = resp.asks.iter().map(|level| level.price.clone()).collect();
+        assert_eq!(bid_prices, [1000, 990, 980, 970].map(ApiPrice::Units));
+        assert_eq!(ask_prices, [1010, 1020, 1030, 1040].map(ApiPrice::Units));
+        assert_eq!(resp.bids[0].size, 15);
+        assert_eq!((resp.bids[0].order_count, resp.bids[1].order_count), (2, 1));
+
+        let req = test::TestRequest::get()
+            .uri("/api/books/ETH-USD/orderbook?depth=2")
+            .to_request();
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, req).await;
+        let bid_prices: Vec<ApiPrice> = resp.bids.iter().map(|level| level.price.clone()).collect();
+        let ask_prices: Vec<ApiPrice> = resp.asks.iter().map(|level| level.price.clone()).collect();
+        assert_eq!(bid_prices, [1000, 990].map(ApiPrice::Units));
+        assert_eq!(ask_prices, [1010, 1020].map(ApiPrice::Units));
+    }
+
+    #[actix_web::test]
+    async fn test_get_orderbook_grouped() {
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let market = MarketConfig::builder().tick_size(5).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (maker, _) = test_trader(0x41);
+        let levels = [(1050, 1), (1045, 2), (1010, 3), (1010, 4), (1005, 5), (990, 6), (-1055, 7), (-1060, 8), (-1100, 9), (-1105, 10), (-1150, 11)];
+        for (price, quantity) in levels {
+            let req = test::TestRequest::post().uri("/api/orders").set_json(signed_order_in(&market.domain(), &maker, price, quantity)).to_request();
+            let resp: OrderResponse = test::call_and_read_body_json(&app, req).await;
+            assert!(resp.success, "{}", resp.message);
+        }
+        let orderbook = |query: &str| test::TestRequest::get().uri(&format!("/api/books/ETH-USD/orderbook{}", query)).to_request();
+        let summary = |levels: &[PriceLevelResponse]| -> Vec<(ApiPrice, u64, u32)> {
+            levels.iter().map(|level| (level.price.clone(), level.size, level.order_count)).collect()
+        };
+
+        // Bids round down into their bucket and asks up, so no bucket looks better than its levels
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, orderbook("?group=50")).await;
+        println!("Grouped: {:?} / {:?}", summary(&resp.bids), summary(&resp.asks));
+        assert_eq!(summary(&resp.bids), [(1050, 1, 1), (1000, 14, 4), (950, 6, 1)].map(|(price, size, count)| (ApiPrice::Units(price), size, count)));
+        assert_eq!(summary(&resp.asks), [(1100, 24, 3), (1150, 21, 2)].map(|(price, size, count)| (ApiPrice::Units(price), size, count)));
+
+        // Buckets hold everything the levels did
+        let raw: OrderbookResponse = test::call_and_read_body_json(&app, orderbook("")).await;
+        let totals = |levels: &[PriceLevelResponse]| levels.iter().fold((0, 0), |(size, count), level| (size + level.size, count + level.order_count));
+        assert_eq!((totals(&resp.bids), totals(&resp.asks)), (totals(&raw.bids), totals(&raw.asks)));
+        assert_eq!((resp.checksum, resp.seq), (raw.checksum, raw.seq));
+
+        // Depth counts buckets
+        let resp: OrderbookResponse = test::call_and_read_body_json(&app, orderbook("?group=50&depth=1")).await;
+        assert_eq!((summary(&resp.bids), summary(&resp.asks)), (vec![(ApiPrice::Units(1050), 1, 1)], vec![(ApiPrice::Units(1100), 24, 3)]));
+
+        // Buckets are whole ticks wide
+        for query in ["?group=12", "?group=0"] {
+            let body: ErrorResponse = test::call_and_read_body_json(&app, orderbook(query)).await;
+            assert_eq!((body.code, body.message.as_str()), (1006, "group must be a positive multiple of the tick size 5"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_estimate_fill() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x11);
+        for (price, quantity) in [(-1000, 10), (-1010, 20), (-1030, 30), (990, 5), (980, 5)] {
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, price, quantity).to_request()).await;
+        }
+        let estimate = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        // 10 @ 1000, 20 @ 1010, 10 @ 1030
+        let resp: EstimateResponse = test::call_and_read_body_json(&app, estimate("/api/books/ETH-USD/estimate?side=buy&qty=40")).await;
+        assert_eq!(resp.filled_quantity, 40);
+        assert_eq!(resp.vwap, Some(40_500.0 / 40.0));
+        assert_eq!(resp.worst_price, Some(ApiPrice::Units(1030)));
+        assert!(!resp.exhausted);
+        let levels: Vec<(ApiPrice, u64)> = resp.levels.iter().map(|level| (level.price.clone(), level.quantity)).collect();
+        assert_eq!(levels, vec![(ApiPrice::Units(1000), 10), (ApiPrice::Units(1010), 20), (ApiPrice::Units(1030), 10)]);
+
+        // More than the bids hold
+        let resp: EstimateResponse = test::call_and_read_body_json(&app, estimate("/api/books/ETH-USD/estimate?side=sell&qty=500")).await;
+        assert_eq!((resp.requested_quantity, resp.filled_quantity, resp.exhausted), (500, 10, true));
+        assert_eq!((resp.vwap, resp.worst_price), (Some(985.0), Some(ApiPrice::Units(980))));
+
+        // Estimating leaves the book as it was
+        let engine = state.engine.lock().await;
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(crate::utils::BookId(0)), Some(Qty(10)));
+        drop(engine);
+
+        let resp = test::call_service(&app, estimate("/api/books/ETH-USD/estimate?side=up&qty=5")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let resp = test::call_service(&app, estimate("/api/books/ETH-USD/estimate?side=buy&qty=0")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let resp = test::call_service(&app, estimate("/api/books/BTC-USD/estimate?side=buy&qty=5")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_preview_order() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x13);
+        let (taker, _) = test_trader(0x14);
+        let mut makers = Vec::new();
+        for (price, quantity) in [(-1000, 10), (-1010, 20), (990, 5)] {
+            let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, price, quantity).to_request()).await;
+            makers.push(resp.order_id.unwrap());
+        }
+        let preview = |order: &OrderRequest| test::TestRequest::post().uri("/api/orders/preview").set_json(order).to_request();
+
+        // Fills name their makers and go at the taker's limit
+        let order = signed_order(&taker, 1010, 15);
+        let resp: PreviewResponse = test::call_and_read_body_json(&app, preview(&order)).await;
+        let fills: Vec<(u64, ApiPrice, u64)> = resp.fills.iter().map(|fill| (fill.maker_order_id, fill.price.clone(), fill.quantity)).collect();
+        assert_eq!(fills, vec![(makers[0], ApiPrice::Units(1010), 10), (makers[1], ApiPrice::Units(1010), 5)]);
+        assert_eq!(resp.average_price, Some(ApiPrice::Units(1010)));
+
+        // The preview, unsigned, agrees with the submission that follows it
+        for (price, quantity, filled, rests) in [(1010, 25, 25, false), (1010, 10, 5, true), (-1020, 5, 0, true)] {
+            let order = signed_order(&taker, price, quantity);
+            let unsigned = OrderRequest { signature: String::new(), ..order.clone() };
+            let resp: PreviewResponse = test::call_and_read_body_json(&app, preview(&unsigned)).await;
+            assert_eq!((resp.filled_quantity, resp.remaining_quantity, resp.rests), (filled, quantity - filled, rests));
+            assert_eq!(resp.average_price.is_some(), filled > 0);
+            let req = test::TestRequest::post().uri("/api/orders").set_json(&order).to_request();
+            let submitted: OrderResponse = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(submitted.status.unwrap().remaining_qty, resp.remaining_quantity);
+        }
+
+        // A signature that is given must hold, and only limit orders can be previewed
+        let forged = OrderRequest { quantity: 4, ..order.clone() };
+        let submitted = test::call_service(&app, test::TestRequest::post().uri("/api/orders").set_json(&forged).to_request()).await;
+        assert_eq!(test::call_service(&app, preview(&forged)).await.status(), submitted.status());
+        assert!(submitted.status().is_client_error());
+        let stop = OrderRequest { order_type: OrderType::Stop, trigger_price: Some(ApiPrice::Units(1000)), ..order.clone() };
+        assert_eq!(test::call_service(&app, preview(&stop)).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let empty = OrderRequest { quantity: 0, ..order };
+        assert_eq!(test::call_service(&app, preview(&empty)).await.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_rfq_block_trade() {
+        use actix_web::http::StatusCode;
+        let state = test_state();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        let req = test::TestRequest::post()
+            .uri("/api/books")
+            .set_json(CreateBookRequest { book_id: "ETH-USD".to_string(), market: Some(market.clone()), book_only: false })
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+        let (maker, maker_address) = test_trader(0x61);
+        let (requester, requester_address) = test_trader(0x62);
+        let now = Clock::System.now() / 1_000_000_000;
+        let open_rfq = || CreateRfqRequest {
+            book_id: "ETH-USD".to_string(),
+            side: Side::Buy,
+            quantity: 500,
+            trader: requester_address.clone(),
+            expiry: now + 600,
+        };
+        let post = |uri: String, body: serde_json::Value| test::TestRequest::post().uri(&uri).set_json(body).to_request();
+        let order = |order: OrderRequest| serde_json::to_value(order).unwrap();
+
+        // Only registered makers quote
+        let rfq: RfqResponse = test::call_and_read_body_json(&app, post("/api/rfqs".to_string(), serde_json::to_value(open_rfq()).unwrap())).await;
+        assert_eq!((rfq.state, rfq.quantity), (RfqState::Open, 500));
+        let quote_uri = format!("/api/rfqs/{}/quotes", rfq.rfq_id);
+        let resp = test::call_service(&app, post(quote_uri.clone(), order(signed_order_in(&market.domain(), &maker, -1000, 500)))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::put().uri(&format!("/api/admin/rfq/makers/{}", maker_address)).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // The requester takes the maker's quote, and the block settles without touching the book
+        let quote: QuoteResponse = test::call_and_read_body_json(&app, post(quote_uri.clone(), order(signed_order_in(&market.domain(), &maker, -1000, 500)))).await;
+        assert_eq!((quote.price.clone(), quote.expires_at), (ApiPrice::Units(1000), now + 600));
+        let accept_uri = format!("/api/rfqs/{}/quotes/{}/accept", rfq.rfq_id, quote.quote_id);
+        let resp = test::call_service(&app, post(accept_uri.clone(), order(signed_order_in(&market.domain(), &requester, 990, 500)))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let trade: BlockTradeResponse = test::call_and_read_body_json(&app, post(accept_uri.clone(), order(signed_order_in(&market.domain(), &requester, 1000, 500)))).await;
+        assert_eq!((trade.quantity, trade.price, trade.settlement_id), (500, ApiPrice::Units(1000), Some(1)));
+        let settlement = state.lock_engine().await.settlements.get(1).unwrap().clone();
+        assert_eq!((settlement.maker_order_id, settlement.taker_order_id), (trade.maker_order_id, trade.taker_order_id));
+        assert_eq!(state.lock_engine().await.orderbook_manager.get_best_ask_size(BookId(0)), None);
+        let rfq: RfqResponse = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/api/rfqs/{}", rfq.rfq_id)).to_request()).await;
+        assert_eq!((rfq.state, rfq.accepted_quote_id, rfq.trade_id), (RfqState::Accepted, Some(quote.quote_id), Some(trade.trade_id)));
+        let resp = test::call_service(&app, post(accept_uri, order(signed_order_in(&market.domain(), &requester, 1000, 500)))).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // A quote signed to expire before the RFQ can't be taken once it has
+        let rfq: RfqResponse = test::call_and_read_body_json(&app, post("/api/rfqs".to_string(), serde_json::to_value(open_rfq()).unwrap())).await;
+        let mut short = signed_order_in(&market.domain(), &maker, -1000, 500);
+        let digest = market.domain().hash_order(&Eip712Order {
+            book: "ETH-USD",
+            trader: parse_trader(&maker_address).unwrap(),
+            price: -1000,
+            quantity: 500,
+            nonce: short.nonce,
+            expiry: now + 60,
+        });
+        short.expiry = Some(now + 60);
+        short.signature = format!("0x{}", hex::encode(sign_prehash(&maker, &digest)));
+        let quote: QuoteResponse = test::call_and_read_body_json(&app, post(format!("/api/rfqs/{}/quotes", rfq.rfq_id), order(short))).await;
+        assert_eq!(quote.expires_at, now + 60);
+        state.lock_engine().await.clock = Clock::Fixed((now + 120) * 1_000_000_000);
+        let accept_uri = format!("/api/rfqs/{}/quotes/{}/accept", rfq.rfq_id, quote.quote_id);
+        let resp = test::call_service(&app, post(accept_uri, order(signed_order_in(&market.domain(), &requester, 1000, 500)))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(body.code, 3013);
+        state.lock_engine().await.clock = Clock::System;
+
+        let req = test::TestRequest::get().uri("/api/rfqs?state=accepted").to_request();
+        let body: ListRfqsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body.rfqs.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_mass_quote() {
+        let state = test_state();
+        for book in ["ETH-USD", "BTC-USD"] {
+            state.book_registry.register_book(book.to_string()).unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, maker_address) = test_trader(0x71);
+        let (other, _) = test_trader(0x72);
+        let entry = |book: &str, bid: Option<OrderRequest>, ask: Option<OrderRequest>| QuoteEntryRequest { book_id: book.to_string(), bid, ask };
+        let quotes = |quotes: Vec<QuoteEntryRequest>| {
+            let body = MassQuoteRequest { trader: maker_address.clone(), quotes };
+            test::TestRequest::post().uri("/api/quotes").set_json(body).to_request()
+        };
+        let two_sided = |bid: i32, ask: i32| entry("ETH-USD", Some(signed_order(&maker, bid, 10)), Some(signed_order(&maker, -ask, 10)));
+        let resp: MassQuoteResponse = test::call_and_read_body_json(&app, quotes(vec![two_sided(990, 1010)])).await;
+        assert!(resp.success);
+        let first = [resp.results[0].bid_order_id.unwrap(), resp.results[0].ask_order_id.unwrap()];
+
+        // Each entry stands on its own; refused ones leave the quote they would have replaced
+        let resp: MassQuoteResponse = test::call_and_read_body_json(
+            &app,
+            quotes(vec![
+                two_sided(1010, 1000),
+                entry("BTC-USD", Some(signed_order(&maker, 500, 1)), None),
+                entry("BTC-USD", None, None),
+            ]),
+        ).await;
+        let codes: Vec<Option<u16>> = resp.results.iter().map(|result| result.code).collect();
+        assert_eq!(codes, vec![Some(1021), Some(1006), Some(1006)]);
+        assert!(!resp.success);
+        let resp: MassQuoteResponse = test::call_and_read_body_json(&app, quotes(vec![entry("ETH-USD", Some(signed_order(&other, 500, 1)), None)])).await;
+        assert_eq!(resp.results[0].code, Some(1011));
+        let usage = |book| state.engine.try_lock().unwrap().orderbook_manager.open_orders.usage(parse_trader(&maker_address).unwrap(), book);
+        assert_eq!(usage(BookId(0)).open_orders, 2);
+
+        // Quotes replaced while takers trade against them never leave a replaced quote behind
+        let (taker, _) = test_trader(0x73);
+        let requests = (0..20).map(|i| match i % 2 {
+            0 => quotes(vec![two_sided(900 - i, 1000 + i)]),
+            _ => order_request(&taker, 1100, 3).to_request(),
+        });
+        let responses = futures_util::future::join_all(requests.map(|req| test::call_service(&app, req))).await;
+        assert!(responses.iter().all(|resp| resp.status().is_success()));
+        let working = {
+            let engine = state.lock_engine().await;
+            let set = engine.quote_set(parse_trader(&maker_address).unwrap(), BookId(0)).copied().unwrap();
+            assert!(first.iter().all(|&order_id| engine.order_status(OrderId(order_id)).is_none()));
+            set.order_ids().filter(|&order_id| engine.order_status(order_id).is_some()).count() as u32
+        };
+        assert_eq!(usage(BookId(0)).open_orders, working);
+
+        // Cancelling the quotes leaves the trader's other orders
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, 800, 1).to_request()).await;
+        assert!(resp.success);
+        let req = test::TestRequest::delete().uri(&format!("/api/traders/{}/quotes", maker_address)).to_request();
+        let resp: CancelAllResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.cancelled.len() as u32, working);
+        assert_eq!(usage(BookId(0)).open_orders, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_get_bbo() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (trader, _) = test_trader(0x11);
+
+        // Empty book serializes every field as null
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(
+            body,
+            r#"{"bid_price":null,"bid_size":null,"ask_price":null,"ask_size":null,"spread":null,"mid_price":null}"#
+        );
+
+        // One-sided book only fills in the bid
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, 990, 10).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, 1000, 7).to_request()).await;
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(
+            body,
+            r#"{"bid_price":1000,"bid_size":7,"ask_price":null,"ask_size":null,"spread":null,"mid_price":null}"#
+        );
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, -1005, 3).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&trader, -1010, 4).to_request()).await;
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request();
+        let resp: BboResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp, BboResponse {
+            bid_price: Some(ApiPrice::Units(1000)),
+            bid_size: Some(7),
+            ask_price: Some(ApiPrice::Units(1005)),
+            ask_size: Some(3),
+            spread: Some(ApiPrice::Units(5)),
+            mid_price: Some(1002.5),
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_get_trades() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x11);
+        let (taker, _) = test_trader(0x22);
+
+        // Order 0 rests, then three takers each lift part of it
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        for quantity in [5, 6, 7] {
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, quantity).to_request()).await;
+        }
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<u64> = resp.trades.iter().map(|trade| trade.trade_id).collect();
+        let quantities: Vec<u64> = resp.trades.iter().map(|trade| trade.quantity).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+        assert_eq!(quantities, vec![7, 6, 5]);
+        assert!(resp.trades.iter().all(|trade| trade.side == "buy" && trade.maker_order_id == 0));
+        assert_eq!(resp.trades[0].taker_order_id, 3);
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades?limit=1&before=3").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.trades.len(), 1);
+        assert_eq!(resp.trades[0].trade_id, 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[actix_web::test]
+    async fn test_trade_history() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let history = web::Data::new(TradeHistory::open(dir.path().join("history.db")).unwrap());
+        {
+            let mut engine = state.engine.lock().await;
+            let sink = history.sink(&engine);
+            engine.orderbook_manager.set_event_sink(Box::new(sink));
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(history.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, maker_address) = test_trader(0x11);
+        let (taker, taker_address) = test_trader(0x22);
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        let mut executed_at = Vec::new();
+        for quantity in [5, 6, 7] {
+            state.engine.lock().await.clock = Clock::Fixed(1_000 * quantity);
+            executed_at.push(1_000 * quantity);
+            let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, quantity).to_request()).await;
+        }
+        history.flush();
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/history?from=6000").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        let trades: Vec<(u64, u64, u64)> = resp.trades.iter().map(|trade| (trade.trade_id, trade.quantity, trade.timestamp)).collect();
+        assert_eq!(trades, vec![(2, 6, 6_000), (3, 7, 7_000)]);
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/history?to=6000&limit=1").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.trades.iter().map(|trade| trade.trade_id).collect::<Vec<_>>(), vec![1]);
+
+        let req = test::TestRequest::get().uri(&format!("/api/traders/{}/fills", maker_address)).to_request();
+        let resp: TraderFillsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.fills.iter().map(|fill| fill.timestamp).collect::<Vec<_>>(), executed_at);
+        assert!(resp.fills.iter().all(|fill| fill.liquidity == "maker" && fill.side == "sell" && fill.order_id == 0 && fill.book == "ETH-USD"));
+        let req = test::TestRequest::get().uri(&format!("/api/traders/{}/fills?from=7000", taker_address)).to_request();
+        let resp: TraderFillsResponse = test::call_and_read_body_json(&app, req).await;
+        let fills: Vec<(u64, &str, &str, u64)> =
+            resp.fills.iter().map(|fill| (fill.trade_id, fill.side.as_str(), fill.liquidity.as_str(), fill.order_id)).collect();
+        assert_eq!(fills, vec![(3, "buy", "taker", 3)]);
+
+        // Exports read the history, which knows the traders
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades/export?from=6000").to_request();
+        let csv = String::from_utf8(test::call_and_read_body(&app, req).await.to_vec()).unwrap();
+        let rows: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("2,ETH-USD,1970-01-01T00:00:00.000006000Z,1000,6,buy,0,2,"));
+        assert!(rows.iter().all(|row| row.ends_with(&format!("{},{}", maker_address, taker_address))));
+
+        // The volume ledger is rebuilt from the history
+        let ledger = FeeLedger::new(0);
+        assert_eq!(ledger.rebuild(&history).unwrap(), 3);
+        let maker = ledger.lock().roll_up(None, None).into_iter().find(|entry| entry.totals.maker_trades > 0).unwrap();
+        assert_eq!((maker.book_id, maker.totals.maker_trades, maker.totals.maker_volume), (BookId(0), 3, 18_000));
+    }
+
+    #[actix_web::test]
+    async fn test_arrival_and_execution_times() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        // Intake stamps arrivals and the engine stamps trades, each from its own clock
+        let received_at = Clock::System.now();
+        {
+            let mut intake = state.order_intake.write().await;
+            *intake = std::mem::take(&mut *intake).with_clock(Clock::Fixed(received_at));
+        }
+        state.engine.lock().await.clock = Clock::Fixed(received_at + 500);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x11);
+        let (taker, _) = test_trader(0x22);
+
+        let resp: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        assert_eq!(resp.status.unwrap().received_at, Some(received_at));
+        let req = test::TestRequest::get().uri("/api/orders/0").to_request();
+        let resp: OrderStatusResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.received_at, Some(received_at));
+
+        // Fills carry the time they executed, in responses and on the tape
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 990, 5).to_request()).await;
+        let req = test::TestRequest::post()
+            .uri("/api/orders/1/replace")
+            .set_json(ReplaceOrderRequest { price: ApiPrice::Units(1000), quantity: 5 })
+            .to_request();
+        let resp: ReplaceOrderResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.fills.iter().map(|fill| fill.executed_at).collect::<Vec<_>>(), vec![Some(received_at + 500)]);
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/trades").to_request();
+        let resp: TradesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.trades[0].timestamp, received_at + 500);
+    }
+
+    #[actix_web::test]
+    async fn test_audit_log() {
+        use crate::{
+            api_auth::API_KEY_HEADER,
+            audit::{admin_key_id, AuditConfig, AuditOutcome},
+            config::{ApiKeySetting, AuthSettings},
+        };
+
+        let (maker, maker_address) = test_trader(0x31);
+        let (taker, _) = test_trader(0x32);
+        let settings = AuthSettings {
+            admin_keys: vec!["admin-key".to_string()],
+            api_keys: vec![ApiKeySetting { key: "maker-key".to_string(), trader: maker_address.clone() }],
+            ..AuthSettings::default()
+        };
+        let state = test_state();
+        let dir = tempfile::tempdir().unwrap();
+        let audit = web::Data::new(AuditLog::open(dir.path(), AuditConfig::default(), state.metrics.clone()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(audit.clone())
+                .app_data(web::Data::new(Authenticator::new(&settings)))
+                .wrap(actix_web::middleware::from_fn(authenticate))
+                .wrap(actix_web::middleware::from_fn(audit_commands))
+                .configure(configure_app)
+        ).await;
+        let with_key = |req: test::TestRequest, key: &str| req.insert_header((API_KEY_HEADER, key.to_string())).peer_addr("10.0.0.7:4000".parse().unwrap());
+        let started = Clock::System.now();
+
+        let book = serde_json::json!({ "book_id": "ETH-USD", "book_only": true });
+        test::call_service(&app, with_key(test::TestRequest::post().uri("/api/books").set_json(&book), "admin-key").to_request()).await;
+        test::call_service(&app, with_key(order_request(&maker, 1000, 10), "maker-key").to_request()).await;
+        // Refused by authentication, and by the handler
+        test::call_service(&app, with_key(order_request(&taker, 1000, 10), "maker-key").to_request()).await;
+        test::call_service(&app, with_key(test::TestRequest::delete().uri("/api/orders/42"), "maker-key").to_request()).await;
+        test::call_service(&app, with_key(test::TestRequest::post().uri("/api/admin/killswitch").set_json(serde_json::json!({ "engaged": true })), "admin-key").to_request()).await;
+        // Reads are not audited
+        test::call_service(&app, test::TestRequest::get().uri("/api/books/ETH-USD/bbo").to_request()).await;
+
+        let entries = audit.query(AuditFilter::default()).await.unwrap();
+        let summary: Vec<(u64, &str, &str, u16)> = entries
+            .iter()
+            .map(|entry| {
+                let status = match entry.outcome {
+                    AuditOutcome::Accepted { status } | AuditOutcome::Rejected { status, .. } => status,
+                };
+                (entry.seq, entry.method.as_str(), entry.path.as_str(), status)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, "POST", "/api/books", 200),
+                (1, "POST", "/api/orders", 200),
+                (2, "POST", "/api/orders", 403),
+                (3, "DELETE", "/api/orders/42", 404),
+                (4, "POST", "/api/admin/killswitch", 200),
+            ]
+        );
+        assert!(entries.iter().all(|entry| entry.timestamp >= started && entry.client_ip.as_deref() == Some("10.0.0.7")));
+
+        // Admins are named by their key's fingerprint, traders by their address
+        assert_eq!(entries[0].admin_key_id, Some(admin_key_id("admin-key")));
+        assert_eq!(entries[0].payload, book);
+        assert_eq!((entries[1].trader, entries[1].payload["quantity"].as_u64()), (Some(parse_trader(&maker_address).unwrap()), Some(10)));
+        assert!(!std::fs::read_dir(dir.path()).unwrap().any(|file| std::fs::read_to_string(file.unwrap().path()).unwrap().contains("admin-key")));
+        // The authenticated trader acts, whoever the order names
+        assert_eq!(entries[2].trader, entries[1].trader);
+        let AuditOutcome::Rejected { code, reason, .. } = &entries[3].outcome else {
+            panic!("cancel of an unknown order was accepted");
+        };
+        assert_eq!(*code, Some(ApiError::UnknownOrder.code()));
+        assert_eq!(*reason, ApiError::UnknownOrder.to_string());
+
+        // The endpoint filters by trader and time
+        let path = format!("/api/admin/audit?trader={}&from={}", maker_address, started);
+        let req = with_key(test::TestRequest::get().uri(&path), "admin-key").to_request();
+        let resp: AuditResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        let path = format!("/api/admin/audit?to={}", started - 1);
+        let req = with_key(test::TestRequest::get().uri(&path), "admin-key").to_request();
+        let resp: AuditResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.entries.is_empty());
+        let req = with_key(test::TestRequest::get().uri("/api/admin/audit"), "maker-key").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_get_candles() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        state.engine.lock().await.clock = Clock::Fixed(90_000_000_000);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x11);
+        let (taker, _) = test_trader(0x22);
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/candles").to_request();
+        let resp: CandlesResponse = test::call_and_read_body_json(&app, req).await;
+        assert!(resp.candles.is_empty());
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, 5).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, 7).to_request()).await;
+
+        // Both trades fall in the minute starting at 60s
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/candles?interval=1m&limit=10").to_request();
+        let resp: CandlesResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.interval, "1m");
+        let candles: Vec<_> = resp.candles.iter().map(|candle| (candle.start, candle.open.clone(), candle.close.clone(), candle.volume, candle.trade_count)).collect();
+        assert_eq!(candles, vec![(60_000_000_000, ApiPrice::Units(1000), ApiPrice::Units(1000), 12, 2)]);
+
+        // Two minutes later the filled series carries the close forward
+        state.engine.lock().await.clock = Clock::Fixed(210_000_000_000);
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/candles?fill=true").to_request();
+        let resp: CandlesResponse = test::call_and_read_body_json(&app, req).await;
+        let candles: Vec<_> = resp.candles.iter().map(|candle| (candle.start, candle.close.clone(), candle.volume)).collect();
+        assert_eq!(candles, [60, 120, 180].map(|minute| (minute * 1_000_000_000, ApiPrice::Units(1000), if minute == 60 { 12 } else { 0 })));
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/candles?interval=2m").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_get_stats() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+        let (maker, _) = test_trader(0x11);
+        let (taker, _) = test_trader(0x22);
+
+        // No trades yet, every statistic is null
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/stats").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "last_price": null, "volume_24h": null, "high": null, "low": null, "trade_count": null,
+                "average_improvement_ticks": null,
+            })
+        );
+
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1000, 30).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&maker, -1200, 30).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1000, 5).to_request()).await;
+        let _: OrderResponse = test::call_and_read_body_json(&app, order_request(&taker, 1200, 40).to_request()).await;
+
+        let req = test::TestRequest::get().uri("/api/books/ETH-USD/stats").to_request();
+        let resp: StatsResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            (resp.last_price, resp.volume_24h, resp.high, resp.low, resp.trade_count),
+            (Some(ApiPrice::Units(1200)), Some(45), Some(ApiPrice::Units(1200)), Some(ApiPrice::Units(1000)), Some(3))
+        );
+        // The buy at 1200 took the ask at 1000, 200 ticks inside its limit; the other fills had none
+        assert_eq!(resp.average_improvement_ticks, Some(200.0 / 3.0));
+
+        let req = test::TestRequest::get().uri("/api/books/BTC-USD/stats").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    /// Starts the API on an ephemeral port and returns its address
+    fn spawn_server(state: web::Data<AppState>) -> std::net::SocketAddr {
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        addr
+    }
+
+    /// Sends a raw HTTP/1.1 request to a live server and returns the response body
+    async fn http_request(addr: std::net::SocketAddr, method: &str, path: &str, body: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string()
+    }
+
+    /// Reads the next text frame from a market data socket as JSON
+    async fn next_json<S>(socket: &mut S) -> serde_json::Value
+    where
+        S: futures_util::Stream<Item = Result<tokio_tungstenite::tungstenite::Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        loop {
+            if let WsMessage::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_book_stream() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        state.engine.lock().await.orderbook_manager.create_book(crate::utils::BookId(0)).unwrap();
+        let addr = spawn_server(state.clone());
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/books/ETH-USD", addr))
+            .await
+            .unwrap();
+
+        let snapshot = next_json(&mut socket).await;
+        println!("Snapshot: {}", snapshot);
+        assert_eq!(snapshot["type"], "snapshot");
+        assert_eq!(snapshot["seq"], 0);
+        assert_eq!(snapshot["checksum"], crc32fast::hash(b"|"));
+        assert!(snapshot["bids"].as_array().unwrap().is_empty());
+        assert!(snapshot["asks"].as_array().unwrap().is_empty());
+
+        let (maker, _) = test_trader(0x12);
+        let (taker, _) = test_trader(0x98);
+        for (trader, price, quantity) in [(&maker, -100, 10), (&taker, 100, 4)] {
+            let body = serde_json::to_string(&signed_order(trader, price, quantity)).unwrap();
+            let response = http_request(addr, "POST", "/api/orders", &body).await;
+            println!("Order response: {}", response);
+        }
+
+        // Resting ask, then a partial fill that shrinks the level and prints a trade after it
+        let rested = next_json(&mut socket).await;
+        println!("Event: {}", rested);
+        assert_eq!(rested["type"], "level_update");
+        assert_eq!(rested["side"], "sell");
+        assert_eq!(rested["price"], 100);
+        assert_eq!(rested["size"], 10);
+        assert_eq!((rested["prev_seq"].as_u64(), rested["seq"].as_u64()), (Some(0), Some(1)));
+
+        let mut seen_trade = false;
+        let mut seen_reduce = false;
+        let mut last_seq = 1;
+        while !(seen_trade && seen_reduce) {
+            let event = next_json(&mut socket).await;
+            println!("Event: {}", event);
+            let seq = event["seq"].as_u64().unwrap();
+            match event["type"].as_str().unwrap() {
+                "trade" => {
+                    assert!(seen_reduce);
+                    assert_eq!(seq, last_seq);
+                    assert_eq!(event["quantity"], 4);
+                    assert_eq!(event["side"], "buy");
+                    seen_trade = true;
+                }
+                "level_update" => {
+                    assert_eq!((event["prev_seq"].as_u64(), seq), (Some(last_seq), last_seq + 1));
+                    last_seq = seq;
+                    assert_eq!(event["price"], 100);
+                    assert_eq!(event["size"], 6);
+                    seen_reduce = true;
+                }
+                other => panic!("Unexpected event {}", other),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_trader_stream() {
+        use crate::auth::personal_sign;
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        state.engine.lock().await.orderbook_manager.create_book(crate::utils::BookId(0)).unwrap();
+        let addr = spawn_server(state.clone());
+
+        let (key, maker) = test_trader(7);
+        let (taker, _) = test_trader(0x98);
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/traders/{}", addr, maker))
+            .await
+            .unwrap();
+        let challenge = next_json(&mut socket).await;
+        println!("Challenge: {}", challenge);
+        assert_eq!(challenge["type"], "challenge");
+        let signature = personal_sign(&key, challenge["challenge"].as_str().unwrap().as_bytes());
+        let auth = serde_json::json!({ "signature": format!("0x{}", hex::encode(signature)) });
+        socket.send(WsMessage::text(auth.to_string())).await.unwrap();
+        assert_eq!(next_json(&mut socket).await["type"], "authenticated");
+
+        for (trader, price, quantity) in [(&key, -100, 10), (&taker, 100, 4)] {
+            let body = serde_json::to_string(&signed_order(trader, price, quantity)).unwrap();
+            let response = http_request(addr, "POST", "/api/orders", &body).await;
+            println!("Order response: {}", response);
+        }
+        let response = http_request(addr, "DELETE", &format!("/api/traders/{}/orders", maker), "").await;
+        println!("Cancel response: {}", response);
+
+        // Only the maker's own updates arrive: resting, partially filled by the taker, then cancelled
+        let expected = [("new", 0, 10), ("partially_filled", 4, 6), ("cancelled", 0, 0)];
+        for (status, filled, remaining) in expected {
+            let update = next_json(&mut socket).await;
+            println!("Update: {}", update);
+            assert_eq!(update["type"], "order_update");
+            assert_eq!(update["order_id"], 0);
+            assert_eq!(update["trader"], maker.as_str());
+            assert_eq!(update["status"], status);
+            assert_eq!(update["filled_qty"], filled);
+            assert_eq!(update["remaining_qty"], remaining);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_trader_stream_rejects_bad_signature() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let addr = spawn_server(test_state());
+        let trader = "0x1234567890123456789012345678901234567890";
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/traders/{}", addr, trader))
+            .await
+            .unwrap();
+        assert_eq!(next_json(&mut socket).await["type"], "challenge");
+
+        let auth = serde_json::json!({ "signature": format!("0x{}", "11".repeat(65)) });
+        socket.send(WsMessage::text(auth.to_string())).await.unwrap();
+        match socket.next().await {
+            Some(Ok(WsMessage::Close(Some(frame)))) => {
+                println!("Closed: {}", frame.reason);
+                assert_eq!(u16::from(frame.code), 1008);
+            }
+            other => panic!("Expected close frame, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_create_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let book_registry = Arc::new(BookRegistry::new());
+        let engine = MatchingEngine::auto_creating();
+        let metrics = engine.metrics.clone();
+        let engine = Arc::new(Mutex::new(engine));
+        let state = web::Data::new(AppState {
+            order_intake: Arc::new(RwLock::new(OrderIntake::new().with_registry(book_registry.clone()))),
+            book_registry,
+            metrics,
+            commands: CommandQueue::spawn(engine.clone(), 1_000),
+            engine,
+            snapshot_dir: dir.path().to_path_buf(),
+            signature_verifier: None,
+            funds_checker: None,
+            shutdown: watch::channel(false).0,
+            readiness: Readiness::new(true, None),
+            idempotency: IdempotencyCache::new(Duration::from_secs(60), 1_000),
+            sequencer: TraderSequencer::new(),
+            ledger: FeeLedger::new(0),
+        });
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(configure_app)
+        ).await;
+
+        let (trader, _) = test_trader(0x12);
+        for price in [99, 98, -101] {
+            let req = order_request(&trader, price, 10).to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::post().uri("/api/admin/snapshot").to_request();
+        let resp: SnapshotResponse = test::call_and_read_body_json(&app, req).await;
+        println!("Snapshot written to {:?}", resp.path);
+        assert!(resp.success);
+        assert_eq!(resp.orders, 3);
+
+        let snapshot = crate::snapshot::EngineSnapshot::load_latest(dir.path()).unwrap().unwrap();
+        assert_eq!(snapshot.registry, vec![("ETH-USD".to_string(), 0)]);
+        assert_eq!(snapshot.books[0].bids.len(), 2);
+        assert_eq!(snapshot.books[0].asks[0].price, 101);
+        assert_eq!(snapshot.next_order_id, 3);
+    }
+
+    #[actix_web::test]
+    async fn test_graceful_shutdown() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode as WsCloseCode, Message as WsMessage};
+
+        let (wal_dir, snapshot_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut config = Config::default();
+        config.auth.enabled = false; // Covered by test_authentication
+        config.server.port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        config.server.workers = Some(1);
+        config.storage.wal_dir = Some(wal_dir.path().to_path_buf());
+        config.storage.snapshot_dir = snapshot_dir.path().to_path_buf();
+        let addr: std::net::SocketAddr = ([127, 0, 0, 1], config.server.port).into();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = actix_web::rt::spawn({
+            let config = config.clone();
+            async move {
+                let shutdown = async { stopped.await.unwrap_or(()) };
+                let recover_config = config.clone();
+                let recover = move |book_registry: &BookRegistry| crate::engine::recover(&recover_config, false, book_registry);
+                start_server(recover, &config, None, None, None, shutdown).await
+            }
+        });
+        while tokio::net::TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        while !http_request(addr, "GET", "/readyz", "").await.contains("\"ready\":true") {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let response = http_request(addr, "POST", "/api/books", r#"{"book_id":"ETH-USD","book_only":true}"#).await;
+        println!("Create book response: {}", response);
+        let (maker, _) = test_trader(0x12);
+        let (taker, _) = test_trader(0x98);
+        for (trader, price, quantity) in [(&maker, 99, 10), (&maker, 98, 5), (&maker, -101, 10), (&taker, 101, 4)] {
+            let body = serde_json::to_string(&signed_order(trader, price, quantity)).unwrap();
+            let response = http_request(addr, "POST", "/api/orders", &body).await;
+            println!("Order response: {}", response);
+        }
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws/books/ETH-USD", addr))
+            .await
+            .unwrap();
+        let before = next_json(&mut socket).await;
+        println!("Book before shutdown: {}", before);
+
+        // Streams are told the server is going away, then the server stops
+        stop.send(()).unwrap();
+        let close = loop {
+            match socket.next().await {
+                Some(Ok(WsMessage::Close(frame))) => break frame,
+                Some(Ok(_)) => {}
+                other => panic!("Expected a close frame, got {:?}", other),
+            }
+        };
+        println!("Close frame: {:?}", close);
+        assert_eq!(close.map(|frame| frame.code), Some(WsCloseCode::Away));
+        drop(socket);
+        server.await.unwrap().unwrap();
+
+        // Both the WAL and the shutdown snapshot rebuild the same book
+        for restore_snapshot in [false, true] {
+            let book_registry = BookRegistry::new();
+            let engine = crate::engine::recover(&config, restore_snapshot, &book_registry).unwrap();
+            let book_id = book_registry.get_book_id("ETH-USD").unwrap();
+            let depth = engine.orderbook_manager.get_depth(book_id, 10).unwrap();
+            println!("Recovered with snapshot {}: {:?} / {:?}", restore_snapshot, depth.bids, depth.asks);
+            assert_eq!((depth.seq, depth.checksum), (before["seq"].as_u64().unwrap(), before["checksum"].as_u64().unwrap() as u32));
+            assert_eq!((depth.bids.len(), depth.asks[0].0, depth.asks[0].1), (2, 101, 6));
+            assert!(!engine.is_halted());
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// api_audit.rs
+
+use crate::{
+    api_auth::{Identity, API_KEY_HEADER},
+    api_error::ApiError,
+    audit::{admin_key_id, AuditEntry, AuditLog, AuditOutcome},
+    auth::parse_address,
+    utils::Clock,
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+
+/// Middleware writing every command sent to the API to the audit log, with who sent it and
+/// what became of it
+/// Reads are not audited. It runs outside authentication, so commands refused for their
+/// credentials are audited too. Without an AuditLog in the app data, requests pass untouched.
+pub async fn audit_commands<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let audit = req.app_data::<web::Data<AuditLog>>().cloned();
+    let is_read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let Some(audit) = audit.filter(|_| !is_read && req.path().starts_with("/api/")) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    let mut entry = AuditEntry {
+        seq: 0, // Numbered by the log
+        timestamp: Clock::System.now(),
+        trader: path_trader(req.path()),
+        admin_key_id: None,
+        client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        method: req.method().to_string(),
+        path: req.uri().path_and_query().map_or(req.path(), |path| path.as_str()).to_string(),
+        payload: serde_json::Value::Null,
+        outcome: AuditOutcome::Accepted { status: 0 },
+    };
+    // The body is read to log it, then put back for the handler
+    let body = match req.extract::<web::Bytes>().await {
+        Ok(body) => body,
+        Err(error) => {
+            let error = ApiError::InvalidParameter(format!("Cannot read the request body: {}", error));
+            entry.outcome = rejected(400, Some(error.code()), error.to_string());
+            audit.record(entry);
+            return Ok(req.error_response(error).map_into_right_body());
+        }
+    };
+    req.set_payload(body.clone().into());
+    entry.payload = payload(&body);
+    if let Some(trader) = entry.payload.get("trader").and_then(|trader| trader.as_str()) {
+        entry.trader = parse_address(trader).ok().or(entry.trader);
+    }
+    let api_key = req.headers().get(API_KEY_HEADER).and_then(|key| key.to_str().ok()).map(str::to_string);
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(error) => {
+            let status = error.as_response_error().status_code().as_u16();
+            entry.outcome = rejected(status, error.as_error::<ApiError>().map(ApiError::code), error.to_string());
+            audit.record(entry);
+            return Err(error);
+        }
+    };
+    match response.request().extensions().get::<Identity>() {
+        Some(Identity::Trader(trader)) => entry.trader = Some(*trader),
+        Some(Identity::Admin) => entry.admin_key_id = api_key.as_deref().map(admin_key_id),
+        Some(Identity::Unchecked) | None => {}
+    }
+    let status = response.status();
+    entry.outcome = match response.response().error() {
+        _ if status.is_success() => AuditOutcome::Accepted { status: status.as_u16() },
+        Some(error) => rejected(status.as_u16(), error.as_error::<ApiError>().map(ApiError::code), error.to_string()),
+        None => rejected(status.as_u16(), None, status.canonical_reason().unwrap_or_default().to_string()),
+    };
+    audit.record(entry);
+    Ok(response.map_into_left_body())
+}
+
+fn rejected(status: u16, code: Option<u16>, reason: String) -> AuditOutcome {
+    AuditOutcome::Rejected { status, code, reason }
+}
+
+/// Gets the body as JSON, as a string if it isn't JSON, or null if there is none
+fn payload(body: &[u8]) -> serde_json::Value {
+    if body.is_empty() {
+        return serde_json::Value::Null;
+    }
+    serde_json::from_slice(body).unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+/// Gets the trader a `/api/traders/{address}/...` path acts for
+fn path_trader(path: &str) -> Option<[u8; 20]> {
+    let address = path.strip_prefix("/api/traders/")?.split('/').next()?;
+    parse_address(address).ok()
+}
@@ -0,0 +1,294 @@
+// api_auth.rs
+
+use crate::{
+    api_error::ApiError,
+    auth::{verify_signer, AuthError},
+    config::AuthSettings,
+    utils::Clock,
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web, Error, FromRequest, HttpMessage, HttpRequest,
+};
+use sha3::{Digest, Keccak256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::{ready, Ready};
+use std::sync::Mutex;
+
+/// Header carrying an admin or trader API key
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Headers of a signed request; see signed_request_message
+pub const ADDRESS_HEADER: &str = "x-numena-address";
+pub const TIMESTAMP_HEADER: &str = "x-numena-timestamp"; // Seconds since the Unix epoch
+pub const NONCE_HEADER: &str = "x-numena-nonce";
+pub const SIGNATURE_HEADER: &str = "x-numena-signature";
+
+const MAX_NONCE_LEN: usize = 64;
+
+/// Who a request was authenticated as; handlers take it as an extractor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identity {
+    Admin,
+    Trader([u8; 20]),
+    Unchecked, // Authentication is disabled
+}
+
+impl Identity {
+    /// Fails with Forbidden unless the identity may act for `trader`
+    /// Admins may act for anyone; a trader only for themself, and never for orders entered
+    /// without a trader.
+    pub fn authorize(&self, trader: Option<[u8; 20]>) -> Result<(), ApiError> {
+        match self {
+            Identity::Admin | Identity::Unchecked => Ok(()),
+            Identity::Trader(address) if trader == Some(*address) => Ok(()),
+            Identity::Trader(_) => Err(ApiError::Forbidden),
+        }
+    }
+}
+
+/// Reads the identity the middleware stored on the request
+/// Requests without one are Unchecked: the middleware only lets them reach a handler that
+/// needs an identity when authentication is disabled.
+impl FromRequest for Identity {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(req.extensions().get::<Identity>().copied().unwrap_or(Identity::Unchecked)))
+    }
+}
+
+/// What a request needs to be let through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Public,
+    Trading,
+    Admin,
+}
+
+/// Admin endpoints and creating or closing a book need an admin key, reads need nothing, and every other
+/// request needs a trader's credentials or an admin key
+/// WebSocket streams are reads; the trader stream authenticates on its own.
+fn required_access(method: &Method, path: &str) -> Access {
+    let closes_book = method == Method::DELETE && path.starts_with("/api/books/");
+    if path.starts_with("/api/admin/") || (method == Method::POST && path == "/api/books") || closes_book {
+        Access::Admin
+    } else if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+        Access::Public
+    } else {
+        Access::Trading
+    }
+}
+
+/// The message a trader signs with `personal_sign` (EIP-191) to authenticate a request
+/// `path` includes the query string; the body is bound by its Keccak-256 hash.
+pub fn signed_request_message(method: &str, path: &str, timestamp: u64, nonce: &str, body: &[u8]) -> String {
+    format!(
+        "Numena request\n{} {}\nTimestamp: {}\nNonce: {}\nBody: 0x{}",
+        method,
+        path,
+        timestamp,
+        nonce,
+        hex::encode(Keccak256::digest(body))
+    )
+}
+
+/// The parts of a signed request, as sent in its headers
+pub struct SignedRequest<'a> {
+    pub address: &'a str,
+    pub timestamp: &'a str,
+    pub nonce: &'a str,
+    pub signature: &'a str,
+}
+
+/// Checks request credentials against the configured keys
+/// The nonces of signed requests are remembered until their timestamps leave the window, so
+/// none is accepted twice.
+pub struct Authenticator {
+    admin_keys: HashSet<String>,
+    api_keys: HashMap<String, [u8; 20]>,
+    window: u64, // Seconds
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<([u8; 20], String)>,
+    expiries: VecDeque<(u64, [u8; 20], String)>, // In the order they were seen
+}
+
+impl Authenticator {
+    pub fn new(settings: &AuthSettings) -> Self {
+        Self {
+            admin_keys: settings.admin_keys.iter().cloned().collect(),
+            api_keys: settings
+                .api_keys
+                .iter()
+                .filter_map(|api_key| Some((api_key.key.clone(), api_key.trader_address()?)))
+                .collect(),
+            window: settings.signature_window_secs,
+            seen: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    /// Gets the identity an API key belongs to
+    pub fn authenticate_key(&self, key: &str) -> Result<Identity, AuthError> {
+        if self.admin_keys.contains(key) {
+            return Ok(Identity::Admin);
+        }
+        self.api_keys.get(key).map(|&trader| Identity::Trader(trader)).ok_or(AuthError::InvalidApiKey)
+    }
+
+    /// Checks a signed request at `now`, in seconds since the Unix epoch, using up its nonce
+    /// `message` is the signed_request_message of the request as received.
+    pub fn authenticate_signed(
+        &self,
+        request: &SignedRequest,
+        message: impl FnOnce(u64) -> String,
+        now: u64,
+    ) -> Result<Identity, AuthError> {
+        let address: [u8; 20] = hex::decode(request.address.trim_start_matches("0x"))
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(AuthError::MalformedSignature)?;
+        let timestamp: u64 = request.timestamp.trim().parse().map_err(|_| AuthError::MalformedSignature)?;
+        if request.nonce.is_empty() || request.nonce.len() > MAX_NONCE_LEN {
+            return Err(AuthError::MalformedSignature);
+        }
+        if timestamp.abs_diff(now) > self.window {
+            return Err(AuthError::Expired);
+        }
+        let signature = hex::decode(request.signature.trim_start_matches("0x")).map_err(|_| AuthError::MalformedSignature)?;
+        verify_signer(message(timestamp).as_bytes(), &signature, address)?;
+
+        // Only a valid signature uses up its nonce, so nobody else can burn a trader's nonces
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while seen.expiries.front().is_some_and(|(expiry, _, _)| *expiry < now) {
+            if let Some((_, trader, nonce)) = seen.expiries.pop_front() {
+                seen.nonces.remove(&(trader, nonce));
+            }
+        }
+        if !seen.nonces.insert((address, request.nonce.to_string())) {
+            return Err(AuthError::Replayed);
+        }
+        seen.expiries.push_back((timestamp + self.window, address, request.nonce.to_string()));
+        Ok(Identity::Trader(address))
+    }
+}
+
+/// Middleware authenticating every request that needs it before it reaches a handler
+/// An API key goes in API_KEY_HEADER; a signed request sends its address, timestamp, nonce,
+/// and signature in the other headers. The identity is stored on the request for handlers to
+/// read; missing or bad credentials get 401, and credentials that don't open the endpoint 403.
+pub async fn authenticate<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    match identify(&mut req).await {
+        Ok(identity) => {
+            if let Some(identity) = identity {
+                req.extensions_mut().insert(identity);
+            }
+            next.call(req).await.map(ServiceResponse::map_into_left_body)
+        }
+        Err(error) => Ok(req.error_response(error).map_into_right_body()),
+    }
+}
+
+/// Gets the identity a request is authenticated as, or None if its endpoint is public
+async fn identify(req: &mut ServiceRequest) -> Result<Option<Identity>, ApiError> {
+    let access = required_access(req.method(), req.path());
+    if access == Access::Public {
+        return Ok(None);
+    }
+    let authenticator = req
+        .app_data::<web::Data<Authenticator>>()
+        .cloned()
+        .ok_or_else(|| ApiError::Internal("Authentication is not configured".to_string()))?;
+    let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let (api_key, address, timestamp, nonce, signature) = (
+        header(API_KEY_HEADER),
+        header(ADDRESS_HEADER),
+        header(TIMESTAMP_HEADER),
+        header(NONCE_HEADER),
+        header(SIGNATURE_HEADER),
+    );
+
+    let identity = match (api_key, address, timestamp, nonce, signature) {
+        (Some(key), ..) => authenticator.authenticate_key(&key),
+        (None, Some(address), Some(timestamp), Some(nonce), Some(signature)) => {
+            // The body is read to check its hash, then put back for the handler
+            let body = req
+                .extract::<web::Bytes>()
+                .await
+                .map_err(|error| ApiError::InvalidParameter(format!("Cannot read the request body: {}", error)))?;
+            req.set_payload(body.clone().into());
+            let method = req.method().to_string();
+            let path = req.uri().path_and_query().map_or(req.path(), |path| path.as_str()).to_string();
+            let request = SignedRequest { address: &address, timestamp: &timestamp, nonce: &nonce, signature: &signature };
+            let message = |timestamp| signed_request_message(&method, &path, timestamp, &nonce, &body);
+            authenticator.authenticate_signed(&request, message, Clock::System.now() / 1_000_000_000)
+        }
+        _ => Err(AuthError::MissingCredentials),
+    };
+    let identity = identity.map_err(ApiError::Unauthorized)?;
+    if access == Access::Admin && identity != Identity::Admin {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(Some(identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{address_of, personal_sign},
+        config::ApiKeySetting,
+    };
+    use k256::ecdsa::SigningKey;
+
+    #[test]
+    fn test_authenticator() {
+        let settings = AuthSettings {
+            admin_keys: vec!["admin".to_string()],
+            api_keys: vec![ApiKeySetting { key: "trader".to_string(), trader: format!("0x{}", hex::encode([2; 20])) }],
+            ..AuthSettings::default()
+        };
+        let authenticator = Authenticator::new(&settings);
+        assert_eq!(authenticator.authenticate_key("admin"), Ok(Identity::Admin));
+        assert_eq!(authenticator.authenticate_key("trader"), Ok(Identity::Trader([2; 20])));
+        assert_eq!(authenticator.authenticate_key("guess"), Err(AuthError::InvalidApiKey));
+
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let address = format!("0x{}", hex::encode(address_of(key.verifying_key())));
+        let now = 1_700_000_000;
+        let sign = |timestamp: u64, nonce: &str| {
+            let message = signed_request_message("DELETE", "/api/orders/1", timestamp, nonce, b"");
+            format!("0x{}", hex::encode(personal_sign(&key, message.as_bytes())))
+        };
+        let check = |timestamp: u64, nonce: &str, signature: &str, now: u64| {
+            let timestamp_text = timestamp.to_string();
+            let request = SignedRequest { address: &address, timestamp: &timestamp_text, nonce, signature };
+            let message = |timestamp| signed_request_message("DELETE", "/api/orders/1", timestamp, nonce, b"");
+            authenticator.authenticate_signed(&request, message, now)
+        };
+
+        let signature = sign(now, "a");
+        assert_eq!(check(now, "a", &signature, now), Ok(Identity::Trader(address_of(key.verifying_key()))));
+        assert_eq!(check(now, "a", &signature, now + 1), Err(AuthError::Replayed));
+        // A signature for another request, or one outside the window, is refused
+        assert_eq!(check(now, "b", &signature, now), Err(AuthError::AddressMismatch));
+        let stale = sign(now - 31, "c");
+        assert_eq!(check(now - 31, "c", &stale, now), Err(AuthError::Expired));
+        // Once a nonce's timestamp has left the window it is forgotten, and its request stays refused
+        let later = now + 100;
+        assert_eq!(check(now, "a", &signature, later), Err(AuthError::Expired));
+        let signature = sign(later, "d");
+        assert!(check(later, "d", &signature, later).is_ok());
+        println!("Nonces remembered: {}", authenticator.seen.lock().unwrap().nonces.len());
+        assert_eq!(authenticator.seen.lock().unwrap().nonces.len(), 1);
+    }
+}
@@ -0,0 +1,501 @@
+// api_error.rs
+
+use crate::{
+    auth::{AddressError, AuthError},
+    book_registry::BookRegistryError,
+    client_order_ids::ClientOrderId,
+    command_queue::QueueError,
+    eip1271::RpcError,
+    engine::EngineError,
+    market::{MarketError, SizeRule},
+    order_intake::OrderIntakeError,
+    orderbook_manager::OrderBookError,
+    price::PriceError,
+    rfq::{RfqError, RfqState},
+    risk::RiskLimit,
+    utils::BookId,
+    wal::WalError,
+};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A failed API request, as the client sees it
+/// Each variant has a stable numeric code clients can branch on, grouped by what went wrong:
+/// 1xxx the request itself is invalid, 2xxx it refers to something that doesn't exist,
+/// 3xxx the market's state refuses it, 5xxx the server couldn't complete it. Codes are never
+/// reused or renumbered; messages may change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    InvalidQuantity,
+    InvalidPrice,
+    InvalidTrader,
+    InvalidSignature,
+    InvalidNonce,
+    InvalidParameter(String), // A field or query parameter the endpoint doesn't accept as given
+    InvalidOco,
+    QtyOverflow { qty: u64, size: u64 },
+    QtyExceedsRemaining { requested: u64, remaining: u64 },
+    Unauthorized(AuthError),
+    Forbidden, // Authenticated, but not as someone the request may act for
+    OrderExpired, // The signed expiry has already passed
+    InvalidTick { price: String, tick_size: String }, // Decimal prices, as the market writes them
+    SizeRuleBroken(SizeRule), // Too small for the market, or not in whole lots
+    NegativePrice, // Negative although the side was given apart from it
+    QuantityTooLarge { max: u64 },
+    ExpiryTooSoon { min_expiry: u64 }, // In seconds since the Unix epoch
+    NonceRequired,
+    InvalidAddress { field: String, error: AddressError }, // Named by its field in the request
+    RfqMismatch, // The signed order doesn't match the RFQ, or the quote it takes
+    CrossedQuote(BookId), // A two-sided quote whose bid is at or above its ask
+    UnknownBook,
+    UnknownOrder,
+    UnknownMarket,
+    UnknownSettlement,
+    UnknownBatch,
+    BookExists,
+    MarketExists,
+    DuplicateClientOrderId(ClientOrderId), // The trader has a working order under the ID
+    IdempotencyConflict, // The idempotency key or signed nonce was used for a different submission
+    UnknownRfq,
+    UnknownQuote,
+    Halted,
+    BookCrossed(BookId), // Halted on its own until an admin repairs it
+    PriceOutsideBand { price: u32, low: u32, high: u32 },
+    BookInAuction(BookId),
+    NotInAuction(BookId),
+    NoPegReference(BookId),
+    PositionsNotTracked(BookId),
+    NoPositionToReduce(BookId),
+    RiskLimitExceeded(RiskLimit),
+    InsufficientFunds { token: [u8; 20], required: u128, available: u128 }, // In token base units
+    TooManyBooks,
+    BookFull,
+    RfqClosed(RfqState), // Accepted or expired, so it takes no more quotes
+    QuoteExpired,
+    NotRfqMaker, // Only registered makers are asked for quotes
+    Internal(String),
+    Unavailable(String), // A service the request depends on, such as the Ethereum node, didn't answer
+    ShuttingDown,
+    NotReady, // The engine is still being restored at startup
+    Overloaded, // Too many commands wait for the matching worker to take a new order
+}
+
+/// Body of every error response
+/// `success` is always false; it is kept so clients that branch on it still work.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponse {
+    pub success: bool,
+    pub code: u16,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    /// Gets the stable code of the error
+    pub fn code(&self) -> u16 {
+        match self {
+            ApiError::InvalidQuantity => 1001,
+            ApiError::InvalidPrice => 1002,
+            ApiError::InvalidTrader => 1003,
+            ApiError::InvalidSignature => 1004,
+            ApiError::InvalidNonce => 1005,
+            ApiError::InvalidParameter(_) => 1006,
+            ApiError::InvalidOco => 1007,
+            ApiError::QtyOverflow { .. } => 1008,
+            ApiError::QtyExceedsRemaining { .. } => 1009,
+            ApiError::Unauthorized(_) => 1010,
+            ApiError::Forbidden => 1011,
+            ApiError::OrderExpired => 1012,
+            ApiError::InvalidTick { .. } => 1013,
+            ApiError::SizeRuleBroken(_) => 1014,
+            ApiError::NegativePrice => 1015,
+            ApiError::QuantityTooLarge { .. } => 1016,
+            ApiError::ExpiryTooSoon { .. } => 1017,
+            ApiError::NonceRequired => 1018,
+            ApiError::InvalidAddress { .. } => 1019,
+            ApiError::RfqMismatch => 1020,
+            ApiError::CrossedQuote(_) => 1021,
+            ApiError::UnknownBook => 2001,
+            ApiError::UnknownOrder => 2002,
+            ApiError::UnknownMarket => 2003,
+            ApiError::UnknownSettlement => 2004,
+            ApiError::UnknownBatch => 2005,
+            ApiError::BookExists => 2006,
+            ApiError::MarketExists => 2007,
+            ApiError::DuplicateClientOrderId(_) => 2008,
+            ApiError::IdempotencyConflict => 2009,
+            ApiError::UnknownRfq => 2010,
+            ApiError::UnknownQuote => 2011,
+            ApiError::Halted => 3001,
+            ApiError::PriceOutsideBand { .. } => 3002,
+            ApiError::BookInAuction(_) => 3003,
+            ApiError::NotInAuction(_) => 3004,
+            ApiError::NoPegReference(_) => 3005,
+            ApiError::PositionsNotTracked(_) => 3006,
+            ApiError::NoPositionToReduce(_) => 3007,
+            ApiError::RiskLimitExceeded(_) => 3008,
+            ApiError::InsufficientFunds { .. } => 3009,
+            ApiError::TooManyBooks => 3010,
+            ApiError::BookFull => 3011,
+            ApiError::RfqClosed(_) => 3012,
+            ApiError::QuoteExpired => 3013,
+            ApiError::NotRfqMaker => 3014,
+            ApiError::BookCrossed(_) => 3015,
+            ApiError::Internal(_) => 5001,
+            ApiError::Unavailable(_) => 5002,
+            ApiError::ShuttingDown => 5003,
+            ApiError::NotReady => 5004,
+            ApiError::Overloaded => 5005,
+        }
+    }
+
+    /// Gets the values behind the error a client may need, such as the band a price fell outside
+    /// Amounts that may not fit a JSON number are given as decimal strings.
+    pub fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            ApiError::QtyOverflow { qty, size } => Some(serde_json::json!({ "qty": qty, "size": size })),
+            ApiError::QtyExceedsRemaining { requested, remaining } => {
+                Some(serde_json::json!({ "requested": requested, "remaining": remaining }))
+            }
+            ApiError::PriceOutsideBand { price, low, high } => {
+                Some(serde_json::json!({ "price": price, "low": low, "high": high }))
+            }
+            ApiError::InvalidTick { price, tick_size } => {
+                Some(serde_json::json!({ "price": price, "tick_size": tick_size }))
+            }
+            ApiError::BookInAuction(book_id)
+            | ApiError::BookCrossed(book_id)
+            | ApiError::CrossedQuote(book_id)
+            | ApiError::NotInAuction(book_id)
+            | ApiError::NoPegReference(book_id)
+            | ApiError::PositionsNotTracked(book_id)
+            | ApiError::NoPositionToReduce(book_id) => Some(serde_json::json!({ "book_id": book_id.value() })),
+            ApiError::Unauthorized(error) => {
+                let reason = match error {
+                    AuthError::MalformedSignature | AuthError::RecoveryFailed | AuthError::AddressMismatch => "invalid_signature",
+                    AuthError::MissingCredentials => "missing_credentials",
+                    AuthError::InvalidApiKey => "invalid_api_key",
+                    AuthError::Expired => "expired",
+                    AuthError::Replayed => "replayed",
+                };
+                Some(serde_json::json!({ "reason": reason }))
+            }
+            ApiError::RiskLimitExceeded(limit) => Some(serde_json::json!({ "limit": limit })),
+            ApiError::SizeRuleBroken(rule) => Some(serde_json::json!({ "rule": rule })),
+            ApiError::QuantityTooLarge { max } => Some(serde_json::json!({ "max": max })),
+            ApiError::ExpiryTooSoon { min_expiry } => Some(serde_json::json!({ "min_expiry": min_expiry })),
+            ApiError::InvalidAddress { field, .. } => Some(serde_json::json!({ "field": field })),
+            ApiError::RfqClosed(state) => Some(serde_json::json!({ "state": state })),
+            ApiError::DuplicateClientOrderId(client_order_id) => {
+                Some(serde_json::json!({ "client_order_id": client_order_id }))
+            }
+            ApiError::InsufficientFunds { token, required, available } => Some(serde_json::json!({
+                "token": format!("0x{}", hex::encode(token)),
+                "required": required.to_string(),
+                "available": available.to_string(),
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::InvalidQuantity => write!(f, "Invalid quantity"),
+            ApiError::InvalidPrice => write!(f, "Invalid price"),
+            ApiError::InvalidTrader => write!(f, "Invalid trader address"),
+            ApiError::InvalidSignature => write!(f, "Invalid signature"),
+            ApiError::InvalidNonce => write!(f, "Invalid nonce"),
+            ApiError::InvalidParameter(message) => write!(f, "{}", message),
+            ApiError::InvalidOco => write!(f, "{}", OrderBookError::InvalidOco),
+            ApiError::QtyOverflow { qty, size } => write!(f, "Quantity {} doesn't fit on a level of size {}", qty, size),
+            ApiError::QtyExceedsRemaining { requested, remaining } => {
+                write!(f, "Quantity {} exceeds the remaining quantity {}", requested, remaining)
+            }
+            ApiError::Unauthorized(error) => write!(f, "{}", error),
+            ApiError::Forbidden => write!(f, "The credentials do not allow this request"),
+            ApiError::OrderExpired => write!(f, "The order has already expired"),
+            ApiError::InvalidTick { price, tick_size } => {
+                write!(f, "{}", PriceError::InvalidTick { price: price.clone(), tick_size: tick_size.clone() })
+            }
+            ApiError::UnknownBook => write!(f, "Book not found"),
+            ApiError::UnknownOrder => write!(f, "Unknown order"),
+            ApiError::UnknownMarket => write!(f, "Book has no market configuration"),
+            ApiError::UnknownSettlement => write!(f, "Settlement not found"),
+            ApiError::UnknownBatch => write!(f, "Settlement batch not found"),
+            ApiError::BookExists => write!(f, "Book already exists"),
+            ApiError::MarketExists => write!(f, "Book already has a market configuration"),
+            ApiError::DuplicateClientOrderId(client_order_id) => {
+                write!(f, "{}", OrderBookError::DuplicateClientOrderId(*client_order_id))
+            }
+            ApiError::IdempotencyConflict => {
+                write!(f, "The idempotency key or nonce was already used for a different submission")
+            }
+            ApiError::Halted => write!(f, "{}", OrderBookError::Halted),
+            ApiError::BookCrossed(book_id) => write!(f, "{}", OrderBookError::BookCrossed(*book_id)),
+            ApiError::PriceOutsideBand { price, low, high } => {
+                write!(f, "{}", OrderBookError::PriceOutsideBand { price: *price, low: *low, high: *high })
+            }
+            ApiError::BookInAuction(book_id) => write!(f, "{}", OrderBookError::BookInAuction(*book_id)),
+            ApiError::NotInAuction(book_id) => write!(f, "{}", OrderBookError::NotInAuction(*book_id)),
+            ApiError::NoPegReference(book_id) => write!(f, "{}", OrderBookError::NoPegReference(*book_id)),
+            ApiError::PositionsNotTracked(book_id) => write!(f, "{}", OrderBookError::PositionsNotTracked(*book_id)),
+            ApiError::NoPositionToReduce(book_id) => write!(f, "{}", OrderBookError::NoPositionToReduce(*book_id)),
+            ApiError::RiskLimitExceeded(limit) => write!(f, "{}", OrderIntakeError::LimitExceeded(*limit)),
+            ApiError::SizeRuleBroken(rule) => write!(f, "{}", OrderIntakeError::SizeRuleBroken(*rule)),
+            ApiError::NegativePrice => write!(f, "{}", OrderIntakeError::NegativePrice),
+            ApiError::QuantityTooLarge { max } => write!(f, "{}", OrderIntakeError::QuantityTooLarge { max: *max }),
+            ApiError::ExpiryTooSoon { min_expiry } => {
+                write!(f, "{}", OrderIntakeError::ExpiryTooSoon { min_expiry: *min_expiry })
+            }
+            ApiError::NonceRequired => write!(f, "{}", OrderIntakeError::NonceRequired),
+            ApiError::InvalidAddress { field, error } => write!(f, "Invalid {}: {}", field, error),
+            ApiError::RfqMismatch => write!(f, "{}", RfqError::Mismatch),
+            ApiError::CrossedQuote(book_id) => write!(f, "{}", OrderBookError::CrossedQuote(*book_id)),
+            ApiError::UnknownRfq => write!(f, "RFQ not found"),
+            ApiError::UnknownQuote => write!(f, "Quote not found"),
+            ApiError::RfqClosed(state) => write!(f, "{}", RfqError::Closed(*state)),
+            ApiError::QuoteExpired => write!(f, "The quote has expired"),
+            ApiError::NotRfqMaker => write!(f, "{}", RfqError::NotMaker),
+            ApiError::InsufficientFunds { token, required, available } => write!(
+                f,
+                "{}",
+                OrderIntakeError::InsufficientFunds { token: *token, required: *required, available: *available }
+            ),
+            ApiError::TooManyBooks => write!(f, "Too many books"),
+            ApiError::BookFull => write!(f, "{}", OrderBookError::BookFull),
+            ApiError::Internal(message) | ApiError::Unavailable(message) => write!(f, "{}", message),
+            ApiError::ShuttingDown => write!(f, "{}", OrderBookError::ShuttingDown),
+            ApiError::NotReady => write!(f, "The engine is still being restored"),
+            ApiError::Overloaded => write!(f, "{}", QueueError::Full),
+        }
+    }
+}
+
+/// Lets handlers return ApiError with `?`; every error answers with an ErrorResponse
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden | ApiError::NotRfqMaker => StatusCode::FORBIDDEN,
+            ApiError::UnknownBook
+            | ApiError::UnknownOrder
+            | ApiError::UnknownMarket
+            | ApiError::UnknownSettlement
+            | ApiError::UnknownBatch
+            | ApiError::UnknownRfq
+            | ApiError::UnknownQuote => StatusCode::NOT_FOUND,
+            ApiError::BookExists
+            | ApiError::MarketExists
+            | ApiError::DuplicateClientOrderId(_)
+            | ApiError::IdempotencyConflict
+            | ApiError::RfqClosed(_) => StatusCode::CONFLICT,
+            ApiError::Halted | ApiError::BookCrossed(_) => StatusCode::LOCKED,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) | ApiError::ShuttingDown | ApiError::NotReady | ApiError::Overloaded => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            success: false,
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+        })
+    }
+}
+
+impl From<OrderIntakeError> for ApiError {
+    fn from(error: OrderIntakeError) -> Self {
+        match error {
+            OrderIntakeError::InvalidQuantity => ApiError::InvalidQuantity,
+            OrderIntakeError::InvalidPrice => ApiError::InvalidPrice,
+            OrderIntakeError::InvalidBookId => ApiError::UnknownBook,
+            OrderIntakeError::InvalidTrader => ApiError::InvalidTrader,
+            OrderIntakeError::InvalidSignature => ApiError::InvalidSignature,
+            OrderIntakeError::InvalidNonce => ApiError::InvalidNonce,
+            OrderIntakeError::LimitExceeded(limit) => ApiError::RiskLimitExceeded(limit),
+            OrderIntakeError::SizeRuleBroken(rule) => ApiError::SizeRuleBroken(rule),
+            OrderIntakeError::NegativePrice => ApiError::NegativePrice,
+            OrderIntakeError::QuantityTooLarge { max } => ApiError::QuantityTooLarge { max },
+            OrderIntakeError::Expired => ApiError::OrderExpired,
+            OrderIntakeError::ExpiryTooSoon { min_expiry } => ApiError::ExpiryTooSoon { min_expiry },
+            OrderIntakeError::NonceRequired => ApiError::NonceRequired,
+            OrderIntakeError::InsufficientFunds { token, required, available } => {
+                ApiError::InsufficientFunds { token, required, available }
+            }
+        }
+    }
+}
+
+/// A price that can't be read at all is an InvalidPrice; one between two ticks says which ticks
+impl From<PriceError> for ApiError {
+    fn from(error: PriceError) -> Self {
+        match error {
+            PriceError::Malformed(_) | PriceError::OutOfRange(_) => ApiError::InvalidPrice,
+            PriceError::InvalidTick { price, tick_size } => ApiError::InvalidTick { price, tick_size },
+        }
+    }
+}
+
+impl From<BookRegistryError> for ApiError {
+    fn from(error: BookRegistryError) -> Self {
+        match error {
+            BookRegistryError::BookAlreadyExists => ApiError::BookExists,
+            BookRegistryError::BookNotFound | BookRegistryError::InvalidBookId => ApiError::UnknownBook,
+            BookRegistryError::TooManyBooks => ApiError::TooManyBooks,
+            BookRegistryError::Persistence(_) => ApiError::Internal(error.to_string()),
+        }
+    }
+}
+
+/// Errors that only a bug in the engine could cause, such as a duplicate order ID, are internal
+impl From<OrderBookError> for ApiError {
+    fn from(error: OrderBookError) -> Self {
+        match error {
+            OrderBookError::UnknownOrder => ApiError::UnknownOrder,
+            OrderBookError::UnknownBook(_) => ApiError::UnknownBook,
+            OrderBookError::NoMarket(_) => ApiError::UnknownMarket,
+            OrderBookError::BookFull => ApiError::BookFull,
+            OrderBookError::QtyExceedsRemaining { requested, remaining } => ApiError::QtyExceedsRemaining {
+                requested: requested.value(),
+                remaining: remaining.value(),
+            },
+            OrderBookError::QtyOverflow { qty, size } => ApiError::QtyOverflow { qty: qty.value(), size: size.value() },
+            OrderBookError::InvalidPrice(_) => ApiError::InvalidPrice,
+            OrderBookError::PriceOutsideBand { price, low, high } => ApiError::PriceOutsideBand { price, low, high },
+            OrderBookError::BookInAuction(book_id) => ApiError::BookInAuction(book_id),
+            OrderBookError::NotInAuction(book_id) => ApiError::NotInAuction(book_id),
+            OrderBookError::NoPegReference(book_id) => ApiError::NoPegReference(book_id),
+            OrderBookError::InvalidOco => ApiError::InvalidOco,
+            OrderBookError::CrossedQuote(book_id) => ApiError::CrossedQuote(book_id),
+            OrderBookError::PositionsNotTracked(book_id) => ApiError::PositionsNotTracked(book_id),
+            OrderBookError::NoPositionToReduce(book_id) => ApiError::NoPositionToReduce(book_id),
+            OrderBookError::Halted => ApiError::Halted,
+            OrderBookError::BookCrossed(book_id) => ApiError::BookCrossed(book_id),
+            OrderBookError::ShuttingDown => ApiError::ShuttingDown,
+            OrderBookError::DuplicateClientOrderId(client_order_id) => ApiError::DuplicateClientOrderId(client_order_id),
+            OrderBookError::DuplicateOrder(_) | OrderBookError::BookOutOfRange(_) | OrderBookError::UnknownLevel(_) => {
+                ApiError::Internal(error.to_string())
+            }
+        }
+    }
+}
+
+impl From<RfqError> for ApiError {
+    fn from(error: RfqError) -> Self {
+        match error {
+            RfqError::UnknownRfq(_) => ApiError::UnknownRfq,
+            RfqError::UnknownQuote(_) => ApiError::UnknownQuote,
+            RfqError::Closed(state) => ApiError::RfqClosed(state),
+            RfqError::ExpiryPassed => ApiError::InvalidParameter(error.to_string()),
+            RfqError::QuoteExpired(_) => ApiError::QuoteExpired,
+            RfqError::NotMaker => ApiError::NotRfqMaker,
+            RfqError::NotRequester => ApiError::Forbidden,
+            RfqError::Mismatch => ApiError::RfqMismatch,
+        }
+    }
+}
+
+impl From<MarketError> for ApiError {
+    fn from(error: MarketError) -> Self {
+        match error {
+            MarketError::MarketExists(_) => ApiError::MarketExists,
+            MarketError::UnknownMarket(_) => ApiError::UnknownMarket,
+        }
+    }
+}
+
+impl From<EngineError> for ApiError {
+    fn from(error: EngineError) -> Self {
+        match error {
+            EngineError::Registry(error) => error.into(),
+            EngineError::Book(error) => error.into(),
+            EngineError::Market(error) => error.into(),
+            EngineError::Wal(error) => error.into(),
+        }
+    }
+}
+
+impl From<WalError> for ApiError {
+    fn from(error: WalError) -> Self {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+impl From<RpcError> for ApiError {
+    fn from(error: RpcError) -> Self {
+        ApiError::Unavailable(error.to_string())
+    }
+}
+
+/// A command the worker never ran was refused, or the server is going down
+impl From<QueueError> for ApiError {
+    fn from(error: QueueError) -> Self {
+        match error {
+            QueueError::Full => ApiError::Overloaded,
+            QueueError::Closed => ApiError::ShuttingDown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quantity::Qty;
+
+    #[test]
+    fn test_error_codes() {
+        let cases = [
+            (ApiError::from(OrderIntakeError::InvalidQuantity), 1001, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderBookError::InvalidPrice(0)), 1002, StatusCode::BAD_REQUEST),
+            (ApiError::OrderExpired, 1012, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderIntakeError::Expired), 1012, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderIntakeError::NegativePrice), 1015, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderIntakeError::QuantityTooLarge { max: 10 }), 1016, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderIntakeError::ExpiryTooSoon { min_expiry: 60 }), 1017, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderIntakeError::NonceRequired), 1018, StatusCode::BAD_REQUEST),
+            (ApiError::from(RfqError::Mismatch), 1020, StatusCode::BAD_REQUEST),
+            (ApiError::from(OrderBookError::CrossedQuote(BookId(0))), 1021, StatusCode::BAD_REQUEST),
+            (ApiError::from(PriceError::Malformed("1,5".to_string())), 1002, StatusCode::BAD_REQUEST),
+            (ApiError::from(BookRegistryError::BookNotFound), 2001, StatusCode::NOT_FOUND),
+            (ApiError::from(OrderBookError::UnknownBook(BookId(3))), 2001, StatusCode::NOT_FOUND),
+            (ApiError::from(OrderBookError::NoMarket(BookId(3))), 2003, StatusCode::NOT_FOUND),
+            (ApiError::from(OrderBookError::UnknownOrder), 2002, StatusCode::NOT_FOUND),
+            (ApiError::from(BookRegistryError::BookAlreadyExists), 2006, StatusCode::CONFLICT),
+            (
+                ApiError::from(OrderBookError::DuplicateClientOrderId(crate::client_order_ids::ClientOrderId::parse("a").unwrap())),
+                2008,
+                StatusCode::CONFLICT,
+            ),
+            (ApiError::IdempotencyConflict, 2009, StatusCode::CONFLICT),
+            (ApiError::from(RfqError::UnknownQuote(2)), 2011, StatusCode::NOT_FOUND),
+            (ApiError::from(OrderBookError::Halted), 3001, StatusCode::LOCKED),
+            (ApiError::from(RfqError::Closed(RfqState::Accepted)), 3012, StatusCode::CONFLICT),
+            (ApiError::from(RfqError::NotMaker), 3014, StatusCode::FORBIDDEN),
+            (ApiError::from(OrderBookError::BookCrossed(BookId(1))), 3015, StatusCode::LOCKED),
+            (ApiError::from(OrderBookError::DuplicateOrder(crate::order::OrderId(1))), 5001, StatusCode::INTERNAL_SERVER_ERROR),
+            (ApiError::from(RpcError::Unavailable("down".to_string())), 5002, StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::NotReady, 5004, StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::from(QueueError::Full), 5005, StatusCode::SERVICE_UNAVAILABLE),
+        ];
+        for (error, code, status) in cases {
+            println!("{}: {}", error.code(), error);
+            assert_eq!((error.code(), error.status_code()), (code, status));
+        }
+
+        // Messages match the errors they came from
+        let error = OrderBookError::QtyOverflow { qty: Qty(5), size: Qty(u64::MAX) };
+        assert_eq!(ApiError::from(error).to_string(), error.to_string());
+        let funds = || OrderIntakeError::InsufficientFunds { token: [1; 20], required: u128::MAX, available: 0 };
+        let api_error = ApiError::from(funds());
+        assert_eq!(api_error.to_string(), funds().to_string());
+        assert_eq!(api_error.details().unwrap()["required"], u128::MAX.to_string());
+    }
+}
@@ -0,0 +1,171 @@
+// api_idempotency.rs
+
+use crate::api_error::ApiError;
+use sha3::{Digest, Keccak256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Header a client sends to make retries of a submission safe
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Header set on a response answered from the cache rather than by placing the order again
+pub const IDEMPOTENT_REPLAY_HEADER: &str = "idempotent-replayed";
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+
+/// What a submission is deduplicated by, always within the trader's own submissions
+/// Every submission has a Nonce key, since a signed order is only accepted once; a client may
+/// add a key of its own in IDEMPOTENCY_KEY_HEADER.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IdempotencyKey {
+    Client([u8; 20], String),
+    Nonce([u8; 20], u64),
+}
+
+impl IdempotencyKey {
+    /// Gets the client's key for a trader's submission from the header value, if one was sent
+    /// Fails with InvalidParameter unless it is 1 to MAX_IDEMPOTENCY_KEY_LEN printable ASCII characters.
+    pub fn client(trader: [u8; 20], header: Option<&str>) -> Result<Option<Self>, ApiError> {
+        let Some(key) = header else {
+            return Ok(None);
+        };
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN || !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+            let message = format!("{} must be 1 to {} printable ASCII characters", IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LEN);
+            return Err(ApiError::InvalidParameter(message));
+        }
+        Ok(Some(IdempotencyKey::Client(trader, key.to_string())))
+    }
+}
+
+/// Hashes a submission's payload, so a retry can be told from a different order under the same key
+pub fn fingerprint(payload: &[u8]) -> [u8; 32] {
+    Keccak256::digest(payload).into()
+}
+
+/// What the cache knows about a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Miss,
+    Replay(Vec<u8>), // The body the first submission was answered with
+    Conflict,        // The key was used for a different payload
+}
+
+/// Responses to accepted submissions, kept for `ttl` so retries get them back
+/// Holds at most `capacity` keys, dropping the least recently used; a capacity of 0 turns
+/// deduplication off. Only accepted submissions are kept: a refused one changed nothing, so a
+/// retry of it is simply placed again.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<IdempotencyKey, Entry>,
+    by_use: BTreeMap<u64, IdempotencyKey>, // Keys by when they were last used, oldest first
+    uses: u64,
+}
+
+struct Entry {
+    fingerprint: [u8; 32],
+    body: Vec<u8>,
+    stored_at: Instant,
+    last_use: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &IdempotencyKey) -> Option<Entry> {
+        let entry = self.by_key.remove(key)?;
+        self.by_use.remove(&entry.last_use);
+        Some(entry)
+    }
+
+    fn touch(&mut self, key: &IdempotencyKey) {
+        self.uses += 1;
+        if let Some(entry) = self.by_key.get_mut(key) {
+            self.by_use.remove(&entry.last_use);
+            entry.last_use = self.uses;
+            self.by_use.insert(self.uses, key.clone());
+        }
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { ttl, capacity, entries: Mutex::new(Entries::default()) }
+    }
+
+    /// Looks the keys of a submission up at `now`, the client's key first
+    /// An exact retry under any of them is a Replay; a different payload under any is a Conflict.
+    pub fn lookup(&self, keys: &[IdempotencyKey], fingerprint: [u8; 32], now: Instant) -> Lookup {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for key in keys {
+            let Some(entry) = entries.by_key.get(key) else {
+                continue;
+            };
+            if now.saturating_duration_since(entry.stored_at) > self.ttl {
+                entries.remove(key);
+                continue;
+            }
+            if entry.fingerprint != fingerprint {
+                return Lookup::Conflict;
+            }
+            let body = entry.body.clone();
+            entries.touch(key);
+            return Lookup::Replay(body);
+        }
+        Lookup::Miss
+    }
+
+    /// Keeps the response to an accepted submission under each of its keys
+    pub fn store(&self, keys: &[IdempotencyKey], fingerprint: [u8; 32], body: &[u8], now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for key in keys {
+            entries.remove(key);
+            while entries.by_key.len() >= self.capacity {
+                let Some((_, oldest)) = entries.by_use.pop_first() else {
+                    break;
+                };
+                entries.by_key.remove(&oldest);
+            }
+            let entry = Entry { fingerprint, body: body.to_vec(), stored_at: now, last_use: 0 };
+            entries.by_key.insert(key.clone(), entry);
+            entries.touch(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_cache() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        let keys = |nonce| [IdempotencyKey::Nonce([1; 20], nonce)];
+        cache.store(&keys(1), fingerprint(b"a"), b"first", now);
+        assert_eq!(cache.lookup(&keys(1), fingerprint(b"a"), now), Lookup::Replay(b"first".to_vec()));
+        assert_eq!(cache.lookup(&keys(1), fingerprint(b"b"), now), Lookup::Conflict);
+        assert_eq!(cache.lookup(&keys(2), fingerprint(b"a"), now), Lookup::Miss);
+
+        // The least recently used key goes first, and keys expire after the TTL
+        cache.store(&keys(2), fingerprint(b"b"), b"second", now);
+        assert!(matches!(cache.lookup(&keys(1), fingerprint(b"a"), now), Lookup::Replay(_)));
+        cache.store(&keys(3), fingerprint(b"c"), b"third", now);
+        let held = cache.entries.lock().unwrap().by_key.len();
+        println!("Keys held: {}", held);
+        assert_eq!((held, cache.lookup(&keys(2), fingerprint(b"b"), now)), (2, Lookup::Miss));
+        assert_eq!(cache.lookup(&keys(1), fingerprint(b"a"), now + Duration::from_secs(61)), Lookup::Miss);
+
+        // A client key must be reasonable
+        assert!(IdempotencyKey::client([1; 20], Some("retry-1")).unwrap().is_some());
+        assert!(IdempotencyKey::client([1; 20], Some("")).is_err());
+        assert!(IdempotencyKey::client([1; 20], Some(&"k".repeat(65))).is_err());
+        assert_eq!(IdempotencyKey::client([1; 20], None).unwrap(), None);
+    }
+}
@@ -0,0 +1,124 @@
+// auction.rs
+
+use serde::{Deserialize, Serialize};
+
+/// Outcome of uncrossing a book at one price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uncross {
+    pub price: u32,
+    pub volume: u64,    // Quantity that executes at `price`
+    pub imbalance: i64, // Bid quantity minus ask quantity willing to trade at `price`
+}
+
+/// Finds the single price at which a crossed book executes the most quantity.
+/// `bids` and `asks` are (price, size) levels, best first. The clearing price is the level
+/// price that:
+/// 1. maximizes the executed volume,
+/// 2. then minimizes the absolute imbalance,
+/// 3. then follows the market pressure: the highest candidate when every one left has more
+///    bids than asks, the lowest when every one has more asks,
+/// 4. then is closest to `reference`, the lower of two equally close, or the lowest without one.
+///
+/// Returns None when no bid reaches any ask.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::auction::clearing_price;
+/// let uncross = clearing_price(&[(101, 10), (100, 5)], &[(99, 8), (100, 4)], None).unwrap();
+/// assert_eq!((uncross.price, uncross.volume, uncross.imbalance), (100, 12, 3));
+/// ```
+pub fn clearing_price(bids: &[(u32, u64)], asks: &[(u32, u64)], reference: Option<u32>) -> Option<Uncross> {
+    let mut prices: Vec<u32> = bids.iter().chain(asks).map(|&(price, _)| price).collect();
+    prices.sort_unstable();
+    prices.dedup();
+
+    // Walk the prices upwards: bids below the price drop out, asks at or below it join
+    let total_bids: u64 = bids.iter().map(|&(_, size)| size).sum();
+    let mut lower_bids = bids.iter().rev().peekable();
+    let mut lower_asks = asks.iter().peekable();
+    let (mut bids_below, mut asks_at) = (0u64, 0u64);
+    let mut candidates = Vec::new();
+    for price in prices {
+        while let Some(&&(bid, size)) = lower_bids.peek() {
+            if bid >= price {
+                break;
+            }
+            bids_below += size;
+            lower_bids.next();
+        }
+        while let Some(&&(ask, size)) = lower_asks.peek() {
+            if ask > price {
+                break;
+            }
+            asks_at += size;
+            lower_asks.next();
+        }
+        let bids_at = total_bids - bids_below;
+        let volume = bids_at.min(asks_at);
+        if volume > 0 {
+            candidates.push(Uncross {
+                price,
+                volume,
+                imbalance: bids_at as i64 - asks_at as i64,
+            });
+        }
+    }
+
+    let volume = candidates.iter().map(|uncross| uncross.volume).max()?;
+    candidates.retain(|uncross| uncross.volume == volume);
+    let imbalance = candidates.iter().map(|uncross| uncross.imbalance.unsigned_abs()).min()?;
+    candidates.retain(|uncross| uncross.imbalance.unsigned_abs() == imbalance);
+
+    if candidates.iter().all(|uncross| uncross.imbalance > 0) {
+        return candidates.last().copied();
+    }
+    if candidates.iter().all(|uncross| uncross.imbalance < 0) {
+        return candidates.first().copied();
+    }
+    match reference {
+        Some(reference) => candidates.into_iter().min_by_key(|uncross| uncross.price.abs_diff(reference)),
+        None => candidates.first().copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_volume_price() {
+        // Cumulative bids and asks at each price:
+        //   price   98  99 100 101 102
+        //   bids    70  70  60  30  10
+        //   asks    15  40  60  90  90
+        //   volume  15  40  60  30  10
+        let bids = [(102, 10), (101, 20), (100, 30), (99, 10)];
+        let asks = [(98, 15), (99, 25), (100, 20), (101, 30)];
+        let uncross = clearing_price(&bids, &asks, None).unwrap();
+        println!("Uncross: {:?}", uncross);
+        assert_eq!(uncross, Uncross { price: 100, volume: 60, imbalance: 0 });
+
+        // Nothing crosses
+        assert_eq!(clearing_price(&[(99, 10)], &[(100, 10)], Some(100)), None);
+        assert_eq!(clearing_price(&[(99, 10)], &[], None), None);
+    }
+
+    #[test]
+    fn test_ties() {
+        // 99 and 101 both clear 10 with no imbalance: the one nearer the reference wins
+        let (bids, asks) = ([(101, 10)], [(99, 10)]);
+        assert_eq!(clearing_price(&bids, &asks, Some(101)).map(|uncross| uncross.price), Some(101));
+        assert_eq!(clearing_price(&bids, &asks, Some(100)).map(|uncross| uncross.price), Some(99));
+        assert_eq!(clearing_price(&bids, &asks, None).map(|uncross| uncross.price), Some(99));
+
+        // The smaller imbalance wins a tie in volume
+        let uncross = clearing_price(&[(101, 10), (100, 5)], &[(99, 10), (101, 2)], Some(99)).unwrap();
+        assert_eq!(uncross, Uncross { price: 101, volume: 10, imbalance: -2 });
+
+        // Left-over bids at every candidate push the price up, left-over asks push it down
+        let uncross = clearing_price(&[(101, 20)], &[(99, 10)], Some(99)).unwrap();
+        assert_eq!(uncross, Uncross { price: 101, volume: 10, imbalance: 10 });
+        let uncross = clearing_price(&[(101, 10)], &[(99, 20)], Some(101)).unwrap();
+        assert_eq!(uncross, Uncross { price: 99, volume: 10, imbalance: -10 });
+    }
+}
@@ -0,0 +1,343 @@
+// audit.rs
+
+use crate::{metrics::Metrics, utils::hex_bytes};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+/// Segment files are named `audit-<index>.jsonl`, with the index zero-padded so they sort.
+const SEGMENT_PREFIX: &str = "audit-";
+const SEGMENT_SUFFIX: &str = ".jsonl";
+
+/// One command as it came in at the API, who sent it and what became of it
+/// `seq` increases by one per command across restarts; an entry dropped because the writer
+/// fell behind leaves a gap.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64, // Nanoseconds since the Unix epoch, when the command came in
+    #[serde(with = "hex_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub trader: Option<[u8; 20]>, // Who the command acts for: its authenticated trader, or the one it names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_key_id: Option<String>, // See admin_key_id; the key itself is never written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    pub method: String,
+    pub path: String, // With the query string
+    pub payload: serde_json::Value, // The body as JSON, a string if it isn't JSON, or null if empty
+    pub outcome: AuditOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Accepted { status: u16 },
+    Rejected {
+        status: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<u16>, // The API error code, when the API gave one
+        reason: String,
+    },
+}
+
+/// Names an admin key in the log without giving it away: the first 8 bytes of its Keccak-256 hash, in hex
+pub fn admin_key_id(key: &str) -> String {
+    use sha3::{Digest, Keccak256};
+    hex::encode(&Keccak256::digest(key.as_bytes())[..8])
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub segment_bytes: u64, // A new file is started once the current one would grow past this size
+    pub retained_segments: usize, // Files kept, the current one included; older ones are deleted
+    pub buffer: usize, // Entries waiting for the writer past which new ones are dropped
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            segment_bytes: 64 << 20,
+            retained_segments: 16,
+            buffer: 10_000,
+        }
+    }
+}
+
+/// Which entries a query returns; every bound is optional and inclusive
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuditFilter {
+    pub trader: Option<[u8; 20]>,
+    pub from: Option<u64>, // Nanoseconds since the Unix epoch
+    pub to: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.trader.is_none_or(|trader| entry.trader == Some(trader))
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp <= to)
+    }
+}
+
+enum AuditMessage {
+    Entry(AuditEntry),
+    Flush(oneshot::Sender<()>), // Answered once every entry before it is written
+}
+
+/// Append-only log of AuditEntries, as JSON lines in numbered files kept to a retained window
+/// Like an EventSink, recording never blocks: entries are queued for a writer on a blocking
+/// task, and once `buffer` of them wait, new ones are dropped and counted in the metrics.
+pub struct AuditLog {
+    dir: PathBuf,
+    sender: mpsc::Sender<AuditMessage>,
+    next_seq: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    /// Opens the log in `dir`, creating the directory if needed, and starts its writer
+    /// Must be called within a Tokio runtime.
+    pub fn open(dir: impl AsRef<Path>, config: AuditConfig, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let segment = segments(&dir)?.last().copied().unwrap_or(0);
+        let path = segment_path(&dir, segment);
+        // Numbering carries on from the last entry written
+        let mut entries = Vec::new();
+        if path.exists() {
+            read_segment(&path, &AuditFilter::default(), &mut entries)?;
+        }
+        let next_seq = entries.last().map_or(0, |entry| entry.seq + 1);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // A line torn by a crash is ended, so the next entry starts a line of its own
+        let written = file.metadata()?.len();
+        if written > 0 && fs::read(&path)?.last() != Some(&b'\n') {
+            file.write_all(b"\n")?;
+        }
+        let writer = AuditWriter {
+            dir: dir.clone(),
+            written: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            segment,
+            config: config.clone(),
+        };
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        tokio::task::spawn_blocking(move || writer.run(receiver));
+        Ok(Self { dir, sender, next_seq: AtomicU64::new(next_seq), metrics })
+    }
+
+    /// Queues an entry for the writer, numbering it; it is dropped if the writer is too far behind
+    pub fn record(&self, mut entry: AuditEntry) {
+        entry.seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        if self.sender.try_send(AuditMessage::Entry(entry)).is_err() {
+            self.metrics.audit_dropped.inc();
+        }
+    }
+
+    /// Returns once every entry recorded so far is written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(AuditMessage::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Gets the retained entries `filter` matches, oldest first, including every one recorded so far
+    pub async fn query(&self, filter: AuditFilter) -> io::Result<Vec<AuditEntry>> {
+        self.flush().await;
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || read_entries(&dir, &filter)).await.map_err(io::Error::other)?
+    }
+}
+
+/// Owns the files; runs on a blocking task until every AuditLog sender is gone
+struct AuditWriter {
+    dir: PathBuf,
+    config: AuditConfig,
+    segment: u64,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl AuditWriter {
+    fn run(mut self, mut receiver: mpsc::Receiver<AuditMessage>) {
+        // Whatever is queued is written together, then flushed to the OS
+        while let Some(message) = receiver.blocking_recv() {
+            self.handle(message);
+            while let Ok(message) = receiver.try_recv() {
+                self.handle(message);
+            }
+            if let Err(error) = self.file.flush() {
+                tracing::error!(%error, "Failed to write the audit log");
+            }
+        }
+    }
+
+    fn handle(&mut self, message: AuditMessage) {
+        match message {
+            AuditMessage::Entry(entry) => {
+                if let Err(error) = self.append(&entry) {
+                    tracing::error!(%error, seq = entry.seq, "Failed to write an audit entry");
+                }
+            }
+            AuditMessage::Flush(done) => {
+                if let Err(error) = self.file.flush() {
+                    tracing::error!(%error, "Failed to write the audit log");
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn append(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // An entry larger than a whole file still gets one of its own
+        if self.written > 0 && self.written + line.len() as u64 > self.config.segment_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.segment += 1;
+        self.file = BufWriter::new(OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, self.segment))?);
+        self.written = 0;
+        let first_retained = (self.segment + 1).saturating_sub(self.config.retained_segments.max(1) as u64);
+        for old in segments(&self.dir)? {
+            if old < first_retained {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, segment, SEGMENT_SUFFIX))
+}
+
+/// Lists the segment indexes present in `dir`, ascending.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix(SEGMENT_PREFIX)?
+                .strip_suffix(SEGMENT_SUFFIX)?
+                .parse()
+                .ok()
+        })
+        .collect();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Reads the entries of every retained segment that `filter` matches, oldest first
+fn read_entries(dir: &Path, filter: &AuditFilter) -> io::Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+    for segment in segments(dir)? {
+        read_segment(&segment_path(dir, segment), filter, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+/// A line torn by a crash doesn't parse and is skipped
+fn read_segment(path: &Path, filter: &AuditFilter, entries: &mut Vec<AuditEntry>) -> io::Result<()> {
+    for line in BufReader::new(File::open(path)?).lines() {
+        if let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) {
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, trader: Option<[u8; 20]>) -> AuditEntry {
+        AuditEntry {
+            seq: 0,
+            timestamp,
+            trader,
+            admin_key_id: None,
+            client_ip: Some("127.0.0.1".to_string()),
+            method: "POST".to_string(),
+            path: "/api/orders".to_string(),
+            payload: serde_json::json!({ "quantity": 10 }),
+            outcome: AuditOutcome::Accepted { status: 200 },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_filters_by_trader_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::open(dir.path(), AuditConfig::default(), Arc::new(Metrics::new())).unwrap();
+        log.record(entry(100, Some([1; 20])));
+        log.record(entry(200, Some([2; 20])));
+        log.record(entry(300, Some([1; 20])));
+
+        let all = log.query(AuditFilter::default()).await.unwrap();
+        assert_eq!(all.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![0, 1, 2]);
+        let filter = AuditFilter { trader: Some([1; 20]), from: Some(150), to: None };
+        let entries = log.query(filter).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(), vec![300]);
+        let filter = AuditFilter { trader: None, from: Some(100), to: Some(200) };
+        assert_eq!(log.query(filter).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_keeps_the_retained_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_vec(&entry(100, None)).unwrap().len() as u64 + 1;
+        // Two entries per file, three files kept
+        let config = AuditConfig { segment_bytes: line_len * 2, retained_segments: 3, buffer: 100 };
+        let log = AuditLog::open(dir.path(), config.clone(), Arc::new(Metrics::new())).unwrap();
+        for timestamp in 100..110 {
+            log.record(entry(timestamp, None));
+        }
+        log.flush().await;
+
+        assert_eq!(segments(dir.path()).unwrap(), vec![2, 3, 4]);
+        let entries = log.query(AuditFilter::default()).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), (4..10).collect::<Vec<_>>());
+
+        // Reopened, the log appends to the last file and numbers on from its last entry
+        drop(log);
+        let log = AuditLog::open(dir.path(), config, Arc::new(Metrics::new())).unwrap();
+        log.record(entry(110, None));
+        let entries = log.query(AuditFilter::default()).await.unwrap();
+        assert_eq!(entries.last().map(|entry| (entry.seq, entry.timestamp)), Some((10, 110)));
+        assert_eq!(segments(dir.path()).unwrap(), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn test_overflow_is_counted() {
+        let metrics = Arc::new(Metrics::new());
+        // A writer that never takes anything off the queue
+        let (sender, _receiver) = mpsc::channel(2);
+        let log = AuditLog { dir: PathBuf::new(), sender, next_seq: AtomicU64::new(0), metrics: metrics.clone() };
+        for timestamp in 0..5 {
+            log.record(entry(timestamp, None));
+        }
+        assert_eq!(metrics.audit_dropped.get(), 3);
+    }
+
+    #[test]
+    fn test_admin_key_id_hides_the_key() {
+        let id = admin_key_id("admin-secret");
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, admin_key_id("admin-secret"));
+        assert_ne!(id, admin_key_id("admin-secret2"));
+        assert!(!id.contains("secret"));
+    }
+}
@@ -0,0 +1,207 @@
+// auth.rs
+
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    MalformedSignature,
+    RecoveryFailed,
+    AddressMismatch,
+    MissingCredentials,
+    InvalidApiKey,
+    Expired,  // A signed request whose timestamp is outside the allowed window
+    Replayed, // A signed request whose nonce was already used
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::MalformedSignature => write!(f, "Malformed signature"),
+            AuthError::RecoveryFailed => write!(f, "Could not recover signer"),
+            AuthError::AddressMismatch => write!(f, "Signature does not match address"),
+            AuthError::MissingCredentials => write!(f, "Missing credentials; send an API key or a signed request"),
+            AuthError::InvalidApiKey => write!(f, "Unknown API key"),
+            AuthError::Expired => write!(f, "Request timestamp is outside the allowed window"),
+            AuthError::Replayed => write!(f, "Request nonce was already used"),
+        }
+    }
+}
+
+/// Why an address was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressError {
+    NotHex,
+    WrongLength(usize), // Bytes the hex decoded to
+    BadChecksum,        // Mixed case that is not the address's EIP-55 casing
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::NotHex => write!(f, "not a hex address"),
+            AddressError::WrongLength(length) => write!(f, "{} bytes, not 20", length),
+            AddressError::BadChecksum => write!(f, "EIP-55 checksum mismatch"),
+        }
+    }
+}
+
+/// Writes an address in its EIP-55 mixed-case checksum form
+pub fn checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = Keccak256::digest(lower.as_bytes());
+    let digits = lower.chars().enumerate().map(|(i, digit)| {
+        // A letter is upper case where the matching nibble of the hash is 8 or more
+        let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0xf;
+        if nibble >= 8 { digit.to_ascii_uppercase() } else { digit }
+    });
+    format!("0x{}", digits.collect::<String>())
+}
+
+/// Parses a 0x-prefixed (or bare) hex address
+/// An address in mixed case must carry its EIP-55 checksum; one all in lower or upper case has
+/// none to check.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::auth::{parse_address, AddressError};
+/// assert!(parse_address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").is_ok());
+/// assert!(parse_address("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").is_ok());
+/// assert_eq!(parse_address("0x5AAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"), Err(AddressError::BadChecksum));
+/// ```
+pub fn parse_address(text: &str) -> Result<[u8; 20], AddressError> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    let bytes = hex::decode(digits).map_err(|_| AddressError::NotHex)?;
+    let address: [u8; 20] = bytes.as_slice().try_into().map_err(|_| AddressError::WrongLength(bytes.len()))?;
+    let mixed_case = digits.chars().any(|digit| digit.is_ascii_lowercase())
+        && digits.chars().any(|digit| digit.is_ascii_uppercase());
+    if mixed_case && checksum_address(&address)[2..] != *digits {
+        return Err(AddressError::BadChecksum);
+    }
+    Ok(address)
+}
+
+/// Hashes a message the way `personal_sign` does (EIP-191 version 0x45).
+pub fn personal_message_hash(message: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(format!("\x19Ethereum Signed Message:\n{}", message.len()).as_bytes());
+    hasher.update(message);
+    hasher.finalize().into()
+}
+
+/// Derives the Ethereum address of a public key.
+pub fn address_of(key: &VerifyingKey) -> [u8; 20] {
+    let point = key.to_encoded_point(false);
+    let hash = Keccak256::digest(&point.as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// Recovers the address that produced a 65-byte `personal_sign` signature (r || s || v).
+/// `v` may be given either as 0/1 or as 27/28.
+pub fn recover_signer(message: &[u8], signature: &[u8]) -> Result<[u8; 20], AuthError> {
+    recover_prehash(&personal_message_hash(message), signature)
+}
+
+/// Recovers the address that signed a 32-byte digest, such as an EIP-712 hash.
+pub fn recover_prehash(hash: &[u8; 32], signature: &[u8]) -> Result<[u8; 20], AuthError> {
+    if signature.len() != 65 {
+        return Err(AuthError::MalformedSignature);
+    }
+    let sig = Signature::from_slice(&signature[..64]).map_err(|_| AuthError::MalformedSignature)?;
+    let v = match signature[64] {
+        v @ (0 | 1) => v,
+        v @ (27 | 28) => v - 27,
+        _ => return Err(AuthError::MalformedSignature),
+    };
+    let recovery_id = RecoveryId::from_byte(v).ok_or(AuthError::MalformedSignature)?;
+    let key = VerifyingKey::recover_from_prehash(hash, &sig, recovery_id)
+        .map_err(|_| AuthError::RecoveryFailed)?;
+    Ok(address_of(&key))
+}
+
+/// Checks that `signature` over `message` was produced by `address`.
+pub fn verify_signer(message: &[u8], signature: &[u8], address: [u8; 20]) -> Result<(), AuthError> {
+    if recover_signer(message, signature)? == address {
+        Ok(())
+    } else {
+        Err(AuthError::AddressMismatch)
+    }
+}
+
+/// Signs a message like a wallet's `personal_sign`, returning r || s || v with v in 27/28
+#[cfg(test)]
+pub fn personal_sign(key: &k256::ecdsa::SigningKey, message: &[u8]) -> Vec<u8> {
+    sign_prehash(key, &personal_message_hash(message))
+}
+
+/// Signs a 32-byte digest, returning r || s || v with v in 27/28
+#[cfg(test)]
+pub fn sign_prehash(key: &k256::ecdsa::SigningKey, hash: &[u8; 32]) -> Vec<u8> {
+    let (sig, recovery_id) = key.sign_prehash_recoverable(hash).unwrap();
+    let mut bytes = sig.to_bytes().to_vec();
+    bytes.push(recovery_id.to_byte() + 27);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+
+    #[test]
+    fn test_recover_signer() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let address = address_of(key.verifying_key());
+        let signature = personal_sign(&key, b"hello");
+        println!("Signer: 0x{}", hex::encode(address));
+
+        assert_eq!(recover_signer(b"hello", &signature), Ok(address));
+        assert_eq!(verify_signer(b"hello", &signature, address), Ok(()));
+        assert_eq!(
+            verify_signer(b"goodbye", &signature, address),
+            Err(AuthError::AddressMismatch)
+        );
+        assert_eq!(
+            recover_signer(b"hello", &signature[..64]),
+            Err(AuthError::MalformedSignature)
+        );
+    }
+
+    #[test]
+    fn test_known_address() {
+        // Well-known development key whose address is 0xf39F...2266
+        let key = SigningKey::from_slice(
+            &hex::decode("ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(address_of(key.verifying_key())),
+            "f39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+        );
+    }
+
+    #[test]
+    fn test_checksum_address() {
+        // Test vectors from EIP-55
+        let vectors = [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ];
+        for vector in vectors {
+            let address = parse_address(vector).unwrap();
+            assert_eq!(checksum_address(&address), vector);
+            assert_eq!(parse_address(&vector.to_lowercase()), Ok(address));
+            assert_eq!(parse_address(&vector[2..].to_uppercase()), Ok(address));
+        }
+
+        // Flipping the case of one letter breaks the checksum
+        assert_eq!(parse_address("0x5aaEb6053F3E94C9b9A09f33669435E7Ef1BeAed"), Err(AddressError::BadChecksum));
+        assert_eq!(parse_address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeA"), Err(AddressError::WrongLength(19)));
+        assert_eq!(parse_address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeg"), Err(AddressError::NotHex));
+    }
+}
@@ -0,0 +1,61 @@
+//! Drives order books with the order messages of an ITCH 5.0 file and checks them against it.
+//!
+//! Usage: `cargo run --release --bin itch_replay -- <file.itch> [--pace recorded|fast]`
+//!
+//! Add, execute, cancel, delete and replace messages are applied to the book of their stock
+//! locate; other messages are skipped. Divergences go to stderr and a report of throughput,
+//! latency and each book's final depth checksum to stdout. The exit status is a failure if
+//! the engine diverged from the feed.
+
+use optimized_lob::{itch_replay::ItchReplayer, replay::Pace};
+use std::fs::File;
+use std::io::BufReader;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut path = None;
+    let mut pace = Pace::Fast;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--pace" => match iter.next().map(String::as_str) {
+                Some("recorded") => pace = Pace::Recorded,
+                Some("fast") => pace = Pace::Fast,
+                _ => return usage(),
+            },
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return usage(),
+        }
+    }
+    let Some(path) = path else { return usage() };
+
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Failed to open {}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut replayer = ItchReplayer::new();
+    match replayer.run(BufReader::with_capacity(1 << 20, file), pace, |divergence| eprintln!("{}", divergence)) {
+        Ok(report) => {
+            print!("{}", report);
+            if report.divergences == 0 {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(error) => {
+            eprintln!("Replay failed: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: itch_replay <file.itch> [--pace recorded|fast]");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,54 @@
+//! Matches a reproducible stream of random orders and reports throughput and latency percentiles.
+//!
+//! Usage: `cargo run --release --bin loadtest -- --orders 1000000 --seed 42 [--books N] [--prices LOW..HIGH] [--verbose]`
+//!
+//! The same seed generates the same orders, so runs of two builds can be compared directly.
+//! With --verbose every order and settlement is logged at debug level, as RUST_LOG allows,
+//! which adds to the measured time.
+
+use optimized_lob::throughput_latency_test::{run_matching_test, MatchingTestConfig};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut config = MatchingTestConfig::default();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().map(String::as_str);
+        let parsed = match arg.as_str() {
+            "--orders" => value().and_then(|orders| orders.parse().ok()).map(|orders| config.order_count = orders),
+            "--seed" => value().and_then(|seed| seed.parse().ok()).map(|seed| config.seed = seed),
+            "--books" => value().and_then(|books| books.parse().ok()).map(|books| config.book_count = books),
+            "--prices" => value().and_then(parse_range).map(|prices| config.price_range = prices),
+            "--verbose" => {
+                config.verbose = true;
+                Some(())
+            }
+            _ => None,
+        };
+        if parsed.is_none() {
+            return usage();
+        }
+    }
+    if config.book_count == 0 || config.price_range.is_empty() {
+        return usage();
+    }
+    if config.verbose {
+        env_logger::init();
+    }
+
+    println!("Matching {} orders in {} books, seed {}\n", config.order_count, config.book_count, config.seed);
+    print!("{}", run_matching_test(&config));
+    ExitCode::SUCCESS
+}
+
+/// Parses a price range written `LOW..HIGH`, both ends included
+fn parse_range(range: &str) -> Option<std::ops::RangeInclusive<u32>> {
+    let (low, high) = range.split_once("..")?;
+    Some(low.parse().ok()?..=high.parse().ok()?)
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: loadtest [--orders N] [--seed N] [--books N] [--prices LOW..HIGH] [--verbose]");
+    ExitCode::FAILURE
+}
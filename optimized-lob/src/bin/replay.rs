@@ -0,0 +1,72 @@
+//! Replays a recorded command log through the matching engine and prints the resulting trades.
+//!
+//! Usage: `cargo run --bin replay -- <file.jsonl> [--pace recorded|fast]`
+//!
+//! Trades are written to stdout as JSON lines, rejected records and a summary to stderr,
+//! so two runs over the same file can be diffed directly.
+
+use optimized_lob::replay::{Pace, Replayer};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut path = None;
+    let mut pace = Pace::Fast;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--pace" => match iter.next().map(String::as_str) {
+                Some("recorded") => pace = Pace::Recorded,
+                Some("fast") => pace = Pace::Fast,
+                _ => return usage(),
+            },
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return usage(),
+        }
+    }
+    let Some(path) = path else { return usage() };
+
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Failed to open {}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut trades = 0;
+    let mut rejected = 0;
+    let mut replayer = Replayer::new();
+    let result = replayer.run(
+        BufReader::new(file),
+        pace,
+        |trade| {
+            trades += 1;
+            let _ = writeln!(out, "{}", serde_json::to_string(trade).unwrap_or_default());
+        },
+        |error| {
+            rejected += 1;
+            eprintln!("{}", error);
+        },
+    );
+
+    match result {
+        Ok(records) => {
+            eprintln!("Replayed {} records: {} trades, {} rejected", records, trades, rejected);
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Replay failed: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: replay <file.jsonl> [--pace recorded|fast]");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,54 @@
+//! Runs concurrent producers against a sharded engine, then checks every book's invariants.
+//!
+//! Usage: `cargo run --release --bin stress -- [--producers N] [--books N] [--shards N] [--seconds N] [--seed N]`
+//!
+//! On a violation the offending book's event log is printed and the exit status is a failure.
+
+use optimized_lob::stress::{run_stress, StressConfig};
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut config = StressConfig::default();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = iter.next().map(String::as_str);
+        let parsed = match arg.as_str() {
+            "--producers" => value.and_then(|producers| producers.parse().ok()).map(|producers| config.producers = producers),
+            "--books" => value.and_then(|books| books.parse().ok()).map(|books| config.books = books),
+            "--shards" => value.and_then(|shards| shards.parse().ok()).map(|shards| config.shards = shards),
+            "--seconds" => value.and_then(|seconds| seconds.parse().ok()).map(|seconds| config.duration = Duration::from_secs(seconds)),
+            "--seed" => value.and_then(|seed| seed.parse().ok()).map(|seed| config.seed = seed),
+            _ => None,
+        };
+        if parsed.is_none() {
+            return usage();
+        }
+    }
+    if config.producers == 0 || config.books == 0 || config.shards == 0 {
+        return usage();
+    }
+
+    println!(
+        "Stressing {} books on {} shards with {} producers for {:?}, seed {}\n",
+        config.books, config.shards, config.producers, config.duration, config.seed
+    );
+    match run_stress(&config).await {
+        Ok(report) => {
+            print!("{}", report);
+            println!("\nAll invariants held");
+            ExitCode::SUCCESS
+        }
+        Err(violation) => {
+            eprint!("{}", violation);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("Usage: stress [--producers N] [--books N] [--shards N] [--seconds N] [--seed N]");
+    ExitCode::FAILURE
+}
@@ -1,43 +1,190 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::RwLock;
-use crate::utils::BookId;
+use serde::{Deserialize, Serialize};
+use crate::utils::{BookId, MAX_BOOKS};
 
 #[derive(Debug)]
 pub enum BookRegistryError {
     BookAlreadyExists,
     BookNotFound,
     InvalidBookId,
+    TooManyBooks,
+    Persistence(String), // The registry file couldn't be written; the change was not made
 }
 
+impl fmt::Display for BookRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BookRegistryError::BookAlreadyExists => write!(f, "Book already exists"),
+            BookRegistryError::BookNotFound => write!(f, "Book not found"),
+            BookRegistryError::InvalidBookId => write!(f, "No book has that BookId"),
+            BookRegistryError::TooManyBooks => write!(f, "Too many books"),
+            BookRegistryError::Persistence(error) => write!(f, "Cannot save the book registry: {}", error),
+        }
+    }
+}
+
+/// Contents of the registry file
+#[derive(Serialize, Deserialize)]
+struct RegistryFile {
+    next_id: u32,
+    books: Vec<(String, u32)>, // In BookId order
+}
+
+/// Maps book names to BookIds and back.
+/// BookIds are handed out in registration order from 0, so they are unique and always index
+/// within the engine's MAX_BOOKS book slots. They are dense until a book is closed; a closed
+/// book's BookId is never handed out again.
+///
+/// A registry opened on a file keeps it up to date: every change is written to the file before
+/// it is made, and one that can't be written is not made at all. Reopening the file after a
+/// restart gives every book the BookId it had.
 pub struct BookRegistry {
     books: RwLock<HashMap<String, BookId>>,
+    names: RwLock<HashMap<BookId, String>>,
+    next_id: AtomicU32,
+    path: Option<PathBuf>, // The registry file, when the registry is persisted
+}
+
+impl Default for BookRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BookRegistry {
     pub fn new() -> Self {
         Self {
             books: RwLock::new(HashMap::new()),
+            names: RwLock::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            path: None,
         }
     }
 
+    /// Opens the registry kept in the file at `path`, starting empty if there is no file yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut registry = Self::new();
+        if path.exists() {
+            let file: RegistryFile = serde_json::from_slice(&fs::read(path)?)?;
+            let names: HashMap<BookId, String> = file.books.into_iter().map(|(name, id)| (BookId(id), name)).collect();
+            *registry.books.get_mut().unwrap() = names.iter().map(|(book_id, name)| (name.clone(), *book_id)).collect();
+            *registry.names.get_mut().unwrap() = names;
+            registry.next_id = AtomicU32::new(file.next_id);
+        }
+        registry.path = Some(path.to_path_buf());
+        Ok(registry)
+    }
+
+    /// Writes the registry as it would be with `names` and `next_id` to its file, if it has one
+    /// The file is written under a temporary name and renamed once synced, so a crash leaves
+    /// either the old registry or the new one.
+    fn persist(&self, names: &HashMap<BookId, String>, next_id: u32) -> Result<(), BookRegistryError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut books: Vec<(String, u32)> = names.iter().map(|(book_id, name)| (name.clone(), book_id.value())).collect();
+        books.sort_unstable_by_key(|(_, book_id)| *book_id);
+        let write = || -> io::Result<()> {
+            let tmp = path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec(&RegistryFile { next_id, books })?)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        write().map_err(|error| BookRegistryError::Persistence(error.to_string()))
+    }
+
     pub fn register_book(&self, book_name: String) -> Result<BookId, BookRegistryError> {
         let mut books = self.books.write().unwrap();
+        let mut names = self.names.write().unwrap();
         if books.contains_key(&book_name) {
             return Err(BookRegistryError::BookAlreadyExists);
         }
 
-        // Create a new BookId using a deterministic method
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        book_name.hash(&mut hasher);
-        let book_id = BookId(hasher.finish() as u32);
-
+        // BookIds index directly into the engine's book slots, so hand them out densely.
+        // The write locks are held, so no other registration can take the same id.
+        let book_id = self.next_book_id();
+        if book_id.value() as usize >= MAX_BOOKS {
+            return Err(BookRegistryError::TooManyBooks);
+        }
+        names.insert(book_id, book_name.clone());
+        if let Err(error) = self.persist(&names, book_id.value() + 1) {
+            names.remove(&book_id);
+            return Err(error);
+        }
+        self.next_id.store(book_id.value() + 1, Ordering::SeqCst);
         books.insert(book_name, book_id);
         Ok(book_id)
     }
 
+    /// Takes back the most recent registration, e.g. of a book that couldn't be set up.
+    /// Its BookId goes to the next book registered. Only the latest book can be taken back, so
+    /// BookIds stay dense; any other fails with InvalidBookId.
+    pub fn unregister_last(&self, book_id: BookId) -> Result<(), BookRegistryError> {
+        let mut books = self.books.write().unwrap();
+        let mut names = self.names.write().unwrap();
+        if book_id.value().checked_add(1) != Some(self.next_id.load(Ordering::SeqCst)) {
+            return Err(BookRegistryError::InvalidBookId);
+        }
+        let name = names.remove(&book_id).ok_or(BookRegistryError::InvalidBookId)?;
+        if let Err(error) = self.persist(&names, book_id.value()) {
+            names.insert(book_id, name);
+            return Err(error);
+        }
+        books.remove(&name);
+        self.next_id.store(book_id.value(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Registers `book_name` under the BookId it was given before, e.g. by a logged RegisterBook
+    /// being replayed. Later registrations get BookIds above it.
+    pub fn restore_book(&self, book_name: String, book_id: BookId) -> Result<(), BookRegistryError> {
+        let mut books = self.books.write().unwrap();
+        let mut names = self.names.write().unwrap();
+        if book_id.value() as usize >= MAX_BOOKS {
+            return Err(BookRegistryError::TooManyBooks);
+        }
+        if books.contains_key(&book_name) || names.contains_key(&book_id) {
+            return Err(BookRegistryError::BookAlreadyExists);
+        }
+        let next_id = self.next_id.load(Ordering::SeqCst).max(book_id.value() + 1);
+        names.insert(book_id, book_name.clone());
+        if let Err(error) = self.persist(&names, next_id) {
+            names.remove(&book_id);
+            return Err(error);
+        }
+        self.next_id.store(next_id, Ordering::SeqCst);
+        books.insert(book_name, book_id);
+        Ok(())
+    }
+
+    /// Removes a closed book, freeing its name for a new book
+    /// Its BookId is retired rather than handed out again, so nothing still holding it can
+    /// mistake a later book for it.
+    pub fn unregister(&self, book_id: BookId) -> Result<String, BookRegistryError> {
+        let mut books = self.books.write().unwrap();
+        let mut names = self.names.write().unwrap();
+        let name = names.remove(&book_id).ok_or(BookRegistryError::InvalidBookId)?;
+        if let Err(error) = self.persist(&names, self.next_id.load(Ordering::SeqCst)) {
+            names.insert(book_id, name);
+            return Err(error);
+        }
+        books.remove(&name);
+        Ok(name)
+    }
+
+    /// Gets the BookId the next registered book will get.
+    pub fn next_book_id(&self) -> BookId {
+        BookId(self.next_id.load(Ordering::SeqCst))
+    }
+
     pub fn get_book_id(&self, book_name: &str) -> Result<BookId, BookRegistryError> {
         let books = self.books.read().unwrap();
         books.get(book_name)
@@ -45,8 +192,138 @@ impl BookRegistry {
             .ok_or(BookRegistryError::BookNotFound)
     }
 
+    /// Gets the name a book was registered under, e.g. to render a trade or settlement of it
+    pub fn resolve_name(&self, book_id: BookId) -> Option<String> {
+        self.names.read().unwrap().get(&book_id).cloned()
+    }
+
     pub fn list_books(&self) -> Vec<String> {
         let books = self.books.read().unwrap();
         books.keys().cloned().collect()
     }
-} 
\ No newline at end of file
+
+    /// Lists every book name with its BookId, in BookId order.
+    /// Restoring each entry rebuilds the same mapping, see restore_book.
+    pub fn entries(&self) -> Vec<(String, BookId)> {
+        let books = self.books.read().unwrap();
+        let mut entries: Vec<(String, BookId)> = books
+            .iter()
+            .map(|(name, book_id)| (name.clone(), *book_id))
+            .collect();
+        entries.sort_unstable_by_key(|(_, book_id)| book_id.value());
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential_book_ids() {
+        let registry = BookRegistry::new();
+        let names: Vec<String> = (0..1_000).map(|i| format!("BOOK{}-USD", i)).collect();
+        for name in &names {
+            registry.register_book(name.clone()).unwrap();
+        }
+        println!("Next BookId: {:?}", registry.next_book_id());
+
+        // Small, dense, and unique, in registration order
+        for (i, name) in names.iter().enumerate() {
+            let book_id = registry.get_book_id(name).unwrap();
+            assert_eq!(book_id, BookId(i as u32));
+            assert_eq!(registry.resolve_name(book_id).as_ref(), Some(name));
+            assert_eq!(BookId::from_str(name, &registry).unwrap(), book_id);
+        }
+        assert_eq!(registry.next_book_id(), BookId(1_000));
+        assert_eq!(registry.list_books().len(), 1_000);
+
+        assert_eq!(registry.entries().into_iter().map(|(name, _)| name).collect::<Vec<_>>(), names);
+
+        assert!(matches!(registry.register_book(names[7].clone()), Err(BookRegistryError::BookAlreadyExists)));
+        assert_eq!(registry.next_book_id(), BookId(1_000));
+        assert!(matches!(registry.get_book_id("DOGE-USD"), Err(BookRegistryError::BookNotFound)));
+        assert_eq!(registry.resolve_name(BookId(1_000)), None);
+        assert!(BookId::from_str("DOGE-USD", &registry).is_err());
+
+        // Only the latest registration can be taken back, and its BookId is handed out again
+        assert!(matches!(registry.unregister_last(BookId(998)), Err(BookRegistryError::InvalidBookId)));
+        registry.unregister_last(BookId(999)).unwrap();
+        assert!(registry.get_book_id(&names[999]).is_err());
+        assert_eq!(registry.resolve_name(BookId(999)), None);
+        assert_eq!(registry.register_book("ETH-USD".to_string()).unwrap(), BookId(999));
+    }
+
+    #[test]
+    fn test_unregister_and_restore() {
+        let registry = BookRegistry::new();
+        for name in ["BTC-USD", "ETH-USD", "SOL-USD"] {
+            registry.register_book(name.to_string()).unwrap();
+        }
+
+        // A closed book frees its name but not its BookId
+        assert_eq!(registry.unregister(BookId(1)).unwrap(), "ETH-USD");
+        assert!(matches!(registry.unregister(BookId(1)), Err(BookRegistryError::InvalidBookId)));
+        assert!(registry.get_book_id("ETH-USD").is_err());
+        assert_eq!(registry.register_book("ETH-USD".to_string()).unwrap(), BookId(3));
+        assert_eq!(registry.resolve_name(BookId(3)).as_deref(), Some("ETH-USD"));
+
+        // Restoring the entries rebuilds the mapping, gaps and all
+        let restored = BookRegistry::new();
+        for (name, book_id) in registry.entries() {
+            restored.restore_book(name, book_id).unwrap();
+        }
+        assert_eq!(restored.entries(), registry.entries());
+        assert_eq!(restored.next_book_id(), BookId(4));
+        assert!(matches!(restored.restore_book("BTC-USD".to_string(), BookId(5)), Err(BookRegistryError::BookAlreadyExists)));
+        assert!(matches!(restored.restore_book("DOGE-USD".to_string(), BookId(2)), Err(BookRegistryError::BookAlreadyExists)));
+        assert!(matches!(
+            restored.restore_book("DOGE-USD".to_string(), BookId(MAX_BOOKS as u32)),
+            Err(BookRegistryError::TooManyBooks)
+        ));
+    }
+
+    #[test]
+    fn test_registry_is_bounded() {
+        let registry = BookRegistry::new();
+        for i in 0..MAX_BOOKS {
+            registry.register_book(i.to_string()).unwrap();
+        }
+        let result = registry.register_book("ONE-MORE".to_string());
+        assert!(matches!(result, Err(BookRegistryError::TooManyBooks)));
+        assert_eq!(registry.get_book_id(&(MAX_BOOKS - 1).to_string()).unwrap().value() as usize, MAX_BOOKS - 1);
+    }
+
+    #[test]
+    fn test_persisted_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("books.json");
+
+        let registry = BookRegistry::open(&path).unwrap();
+        for name in ["BTC-USD", "ETH-USD", "SOL-USD", "XRP-USD"] {
+            registry.register_book(name.to_string()).unwrap();
+        }
+        registry.unregister(BookId(1)).unwrap();
+        registry.unregister_last(BookId(3)).unwrap();
+        registry.register_book("ETH-USD".to_string()).unwrap();
+        println!("{}", fs::read_to_string(&path).unwrap());
+
+        // A restart finds every book under the BookId it had, and hands out the next one
+        drop(registry);
+        let reopened = BookRegistry::open(&path).unwrap();
+        let entries = vec![("BTC-USD".to_string(), BookId(0)), ("SOL-USD".to_string(), BookId(2)), ("ETH-USD".to_string(), BookId(3))];
+        assert_eq!(reopened.entries(), entries);
+        assert_eq!(reopened.resolve_name(BookId(3)).as_deref(), Some("ETH-USD"));
+        assert_eq!(reopened.resolve_name(BookId(1)), None);
+        assert_eq!(reopened.next_book_id(), BookId(4));
+
+        // A change that can't be written is not made
+        fs::remove_dir_all(dir.path()).unwrap();
+        assert!(matches!(reopened.register_book("XRP-USD".to_string()), Err(BookRegistryError::Persistence(_))));
+        assert!(matches!(reopened.unregister(BookId(0)), Err(BookRegistryError::Persistence(_))));
+        assert!(matches!(reopened.restore_book("XRP-USD".to_string(), BookId(9)), Err(BookRegistryError::Persistence(_))));
+        assert_eq!(reopened.entries(), entries);
+        assert_eq!(reopened.next_book_id(), BookId(4));
+        assert!(reopened.get_book_id("XRP-USD").is_err());
+    }
+}
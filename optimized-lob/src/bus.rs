@@ -0,0 +1,472 @@
+// bus.rs
+
+use crate::{
+    book_registry::BookRegistry,
+    events::{CancelReason, EventSink, OrderBookEvent},
+    metrics::Metrics,
+    order::OrderId,
+    utils::BookId,
+};
+use serde_json::{json, Value};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Delays between attempts to publish a message the bus refused, doubling up to the last
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusConfig {
+    pub trades_topic: String, // `{book}` is replaced by the name of the book, or its ID if it has none
+    pub orders_topic: String,
+    pub buffer: usize, // Messages waiting for the bus past which new ones are dropped
+    pub flush_timeout: Duration, // Longest a flush waits for the bus to take what is buffered
+}
+
+impl Default for BusConfig {
+    fn default() -> Self {
+        Self {
+            trades_topic: "trades.{book}".to_string(),
+            orders_topic: "orders.{book}".to_string(),
+            buffer: 100_000,
+            flush_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A JSON payload for a topic, keyed by the ID of its book so a partitioned bus keeps each
+/// book's messages in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusMessage {
+    pub topic: String,
+    pub key: String,
+    pub id: String, // Unique to the event, so a bus can drop a message published twice
+    pub payload: Vec<u8>,
+}
+
+impl BusMessage {
+    /// Parses the payload
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.payload).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusError(pub String);
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Bus error: {}", self.0)
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Where a BusSink's messages go
+pub trait Publisher: Send + 'static {
+    /// Resolves once the bus has stored the message; on an error, the message is published again.
+    fn publish(&mut self, message: &BusMessage) -> impl Future<Output = Result<(), BusError>> + Send;
+}
+
+/// Keeps published messages in memory, refusing them while set down. Clones share the same
+/// buffer, so tests can see what a sink published without a broker.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPublisher {
+    published: Arc<Mutex<Vec<BusMessage>>>,
+    down: Arc<Mutex<bool>>,
+}
+
+impl MemoryPublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of every message published so far.
+    pub fn published(&self) -> Vec<BusMessage> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Makes the publisher refuse messages, as a broker that is down would, or take them again.
+    pub fn set_down(&self, down: bool) {
+        *self.down.lock().unwrap() = down;
+    }
+}
+
+impl Publisher for MemoryPublisher {
+    async fn publish(&mut self, message: &BusMessage) -> Result<(), BusError> {
+        if *self.down.lock().unwrap() {
+            return Err(BusError("broker is down".to_string()));
+        }
+        self.published.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
+
+/// Publishes to NATS JetStream, each message on the subject its topic names
+/// A message carries its ID in `Nats-Msg-Id`, so the stream drops one published twice within
+/// its duplicate window, and its key in `Numena-Key`. The stream must take the subjects.
+#[cfg(feature = "nats")]
+pub struct NatsPublisher {
+    jetstream: async_nats::jetstream::Context,
+}
+
+#[cfg(feature = "nats")]
+impl NatsPublisher {
+    /// Connects to the server at `url`; it needn't be up yet, as the client keeps trying.
+    pub async fn connect(url: &str) -> Result<Self, BusError> {
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(url)
+            .await
+            .map_err(|error| BusError(error.to_string()))?;
+        Ok(Self { jetstream: async_nats::jetstream::new(client) })
+    }
+}
+
+#[cfg(feature = "nats")]
+impl Publisher for NatsPublisher {
+    async fn publish(&mut self, message: &BusMessage) -> Result<(), BusError> {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("Nats-Msg-Id", message.id.as_str());
+        headers.insert("Numena-Key", message.key.as_str());
+        let ack = self
+            .jetstream
+            .publish_with_headers(message.topic.clone(), headers, message.payload.clone().into())
+            .await
+            .map_err(|error| BusError(error.to_string()))?;
+        ack.await.map_err(|error| BusError(error.to_string()))?;
+        Ok(())
+    }
+}
+
+/// How many messages the publishing task has delivered, for flushes to wait on
+#[derive(Default)]
+struct Progress {
+    published: Mutex<u64>,
+    changed: Condvar,
+}
+
+/// Publishes trades and order events to a message bus, trades on `trades_topic` and the rest
+/// on `orders_topic`
+/// Like the audit log, publishing never blocks the matching thread: messages are queued for a
+/// task that publishes them one at a time, in order, trying each again until the bus takes it,
+/// so every message is delivered at least once. Once `buffer` of them wait, as they do while
+/// the broker is down, new ones are dropped and counted in the metrics.
+pub struct BusSink {
+    sender: mpsc::Sender<BusMessage>,
+    queued: u64,
+    progress: Arc<Progress>,
+    registry: Arc<BookRegistry>,
+    config: BusConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl BusSink {
+    /// Starts publishing to `publisher`, naming topics after the books in `registry`
+    /// Must be called within a Tokio runtime.
+    pub fn start(publisher: impl Publisher, config: BusConfig, registry: Arc<BookRegistry>, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+        let progress = Arc::new(Progress::default());
+        tokio::spawn(publish(publisher, receiver, progress.clone()));
+        Self { sender, queued: 0, progress, registry, config, metrics }
+    }
+
+    fn topic(&self, template: &str, book_id: BookId) -> String {
+        let book = self.registry.resolve_name(book_id).unwrap_or_else(|| book_id.value().to_string());
+        template.replace("{book}", &book)
+    }
+
+    /// Writes the message published for an event
+    fn message(&self, event: &OrderBookEvent) -> BusMessage {
+        let order = |kind: &str, order_id: OrderId, fields: Value| {
+            let mut payload = json!({ "type": kind, "order_id": order_id.0 });
+            payload.as_object_mut().unwrap().extend(fields.as_object().cloned().unwrap_or_default());
+            (&self.config.orders_topic, format!("{}-{}-{}", kind, order_id.0, event.book_seq()), payload)
+        };
+        let (template, id, mut payload) = match *event {
+            OrderBookEvent::Trade { trade, maker_trader, taker_trader, improvement, .. } => (
+                &self.config.trades_topic,
+                format!("trade-{}", trade.trade_id),
+                json!({
+                    "type": "trade",
+                    "trade_id": trade.trade_id,
+                    "timestamp": trade.timestamp,
+                    "price": trade.price,
+                    "quantity": trade.qty.value(),
+                    "side": side(trade.aggressor_is_bid),
+                    "maker_order_id": trade.maker_order_id.0,
+                    "taker_order_id": trade.taker_order_id.0,
+                    "maker_trader": maker_trader.map(address),
+                    "taker_trader": taker_trader.map(address),
+                    "improvement_ticks": improvement.map(|improvement| improvement.ticks),
+                }),
+            ),
+            OrderBookEvent::OrderAdded { order_id, price, is_bid, qty, trader, display, .. } => order(
+                "order_added",
+                order_id,
+                json!({
+                    "price": price,
+                    "side": side(is_bid),
+                    "quantity": qty.value(),
+                    "display_quantity": display.map(|display| display.value()),
+                    "trader": trader.map(address),
+                }),
+            ),
+            OrderBookEvent::OrderExecuted { order_id, exec_qty, remaining_qty, .. } => order(
+                "order_executed",
+                order_id,
+                json!({ "executed_quantity": exec_qty.value(), "remaining_quantity": remaining_qty.value() }),
+            ),
+            OrderBookEvent::OrderCancelled { order_id, cancelled_qty, remaining_qty, reason, .. } => order(
+                "order_cancelled",
+                order_id,
+                json!({
+                    "cancelled_quantity": cancelled_qty.value(),
+                    "remaining_quantity": remaining_qty.value(),
+                    "reason": match reason {
+                        CancelReason::Requested => "requested",
+                        CancelReason::BookClosed => "book_closed",
+                    },
+                }),
+            ),
+            OrderBookEvent::OrderReplaced { order_id, new_order_id, new_price, new_qty, .. } => order(
+                "order_replaced",
+                order_id,
+                json!({ "new_order_id": new_order_id.0, "new_price": new_price, "new_quantity": new_qty.value() }),
+            ),
+            OrderBookEvent::OrderExpired { order_id, qty, .. } => {
+                order("order_expired", order_id, json!({ "quantity": qty.value() }))
+            }
+            OrderBookEvent::OrderReplenished { order_id, qty, .. } => {
+                order("order_replenished", order_id, json!({ "quantity": qty.value() }))
+            }
+            // Not about any one order, so it is known by its event sequence number
+            OrderBookEvent::BookCrossed { seq, best_bid, best_ask, .. } => (
+                &self.config.orders_topic,
+                format!("book_crossed-{}", seq),
+                json!({ "type": "book_crossed", "best_bid": best_bid, "best_ask": best_ask }),
+            ),
+        };
+        let book_id = event.book_id();
+        let fields = payload.as_object_mut().unwrap();
+        fields.insert("seq".to_string(), json!(event.seq()));
+        fields.insert("book_seq".to_string(), json!(event.book_seq()));
+        fields.insert("book_id".to_string(), json!(book_id.value()));
+        fields.insert("book".to_string(), json!(self.registry.resolve_name(book_id)));
+        BusMessage {
+            topic: self.topic(template, book_id),
+            key: book_id.value().to_string(),
+            id,
+            payload: serde_json::to_vec(&payload).unwrap_or_default(),
+        }
+    }
+}
+
+impl EventSink for BusSink {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        match self.sender.try_send(self.message(event)) {
+            Ok(()) => self.queued += 1,
+            Err(_) => self.metrics.bus_dropped.inc(),
+        }
+    }
+
+    /// Waits until the bus has taken every message queued, or the flush timeout passes
+    fn flush(&mut self) {
+        let published = self.progress.published.lock().unwrap();
+        let (published, _) = self
+            .progress
+            .changed
+            .wait_timeout_while(published, self.config.flush_timeout, |published| *published < self.queued)
+            .unwrap();
+        if *published < self.queued {
+            tracing::warn!(unpublished = self.queued - *published, "Bus messages still unpublished after the flush timeout");
+        }
+    }
+}
+
+/// Publishes each message in turn, trying it again until the bus takes it
+async fn publish(mut publisher: impl Publisher, mut receiver: mpsc::Receiver<BusMessage>, progress: Arc<Progress>) {
+    while let Some(message) = receiver.recv().await {
+        let mut delay = FIRST_RETRY_DELAY;
+        while let Err(error) = publisher.publish(&message).await {
+            tracing::warn!(%error, topic = %message.topic, "Failed to publish to the bus; trying again");
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        *progress.published.lock().unwrap() += 1;
+        progress.changed.notify_all();
+    }
+}
+
+fn side(is_bid: bool) -> &'static str {
+    if is_bid {
+        "buy"
+    } else {
+        "sell"
+    }
+}
+
+fn address(trader: [u8; 20]) -> String {
+    format!("0x{}", hex::encode(trader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{matching::MatchingEngine, quantity::Qty};
+
+    fn engine_with_sink(publisher: &MemoryPublisher, config: BusConfig) -> (MatchingEngine, Arc<Metrics>) {
+        let registry = Arc::new(BookRegistry::new());
+        assert_eq!(registry.register_book("ETH-USD".to_string()).unwrap(), BookId(0));
+        let metrics = Arc::new(Metrics::new());
+        let mut engine = MatchingEngine::auto_creating();
+        let sink = BusSink::start(publisher.clone(), config, registry, metrics.clone());
+        engine.orderbook_manager.set_event_sink(Box::new(sink));
+        (engine, metrics)
+    }
+
+    fn submit(engine: &mut MatchingEngine, order_id: u64, qty: u64, price: u32, is_bid: bool, trader: [u8; 20]) {
+        engine
+            .match_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, Some(trader), Some(order_id), None, None)
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bus_messages() {
+        let publisher = MemoryPublisher::new();
+        let (mut engine, _) = engine_with_sink(&publisher, BusConfig::default());
+        let (maker, taker) = ([1; 20], [2; 20]);
+        submit(&mut engine, 1, 10, 100, false, maker);
+        submit(&mut engine, 2, 4, 100, true, taker);
+        engine.finalize().unwrap();
+
+        let published = publisher.published();
+        let topics: Vec<(&str, &str, &str)> =
+            published.iter().map(|message| (message.topic.as_str(), message.key.as_str(), message.id.as_str())).collect();
+        assert_eq!(
+            topics,
+            vec![
+                ("orders.ETH-USD", "0", "order_added-1-1"),
+                ("orders.ETH-USD", "0", "order_executed-1-2"),
+                ("trades.ETH-USD", "0", "trade-1"),
+            ]
+        );
+        assert_eq!(
+            published[0].json(),
+            json!({
+                "type": "order_added", "seq": 1, "book_seq": 1, "book": "ETH-USD", "book_id": 0, "order_id": 1,
+                "price": 100, "side": "sell", "quantity": 10, "display_quantity": null,
+                "trader": "0x0101010101010101010101010101010101010101",
+            })
+        );
+        assert_eq!(
+            published[1].json(),
+            json!({
+                "type": "order_executed", "seq": 2, "book_seq": 2, "book": "ETH-USD", "book_id": 0, "order_id": 1,
+                "executed_quantity": 4, "remaining_quantity": 6,
+            })
+        );
+        let trade = published[2].json();
+        assert_eq!(
+            trade,
+            json!({
+                "type": "trade", "seq": 3, "book_seq": 2, "book": "ETH-USD", "book_id": 0, "trade_id": 1,
+                "timestamp": trade["timestamp"], "price": 100, "quantity": 4, "side": "buy",
+                "maker_order_id": 1, "taker_order_id": 2,
+                "maker_trader": "0x0101010101010101010101010101010101010101",
+                "taker_trader": "0x0202020202020202020202020202020202020202",
+                "improvement_ticks": 0,
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bus_buffers_while_down() {
+        let publisher = MemoryPublisher::new();
+        publisher.set_down(true);
+        let config = BusConfig { buffer: 2, flush_timeout: Duration::from_millis(100), ..BusConfig::default() };
+        let (mut engine, metrics) = engine_with_sink(&publisher, config);
+        // The first message is taken off the queue and retried, two wait, and the rest are dropped
+        for order_id in 1..=5 {
+            submit(&mut engine, order_id, 1, 100 + order_id as u32, false, [1; 20]);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        engine.finalize().unwrap();
+        assert!(publisher.published().is_empty());
+        assert_eq!(metrics.bus_dropped.get(), 2);
+
+        // Once the broker is back, what was kept arrives in order
+        publisher.set_down(false);
+        for _ in 0..100 {
+            if publisher.published().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let ids: Vec<String> = publisher.published().into_iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec!["order_added-1-1", "order_added-2-2", "order_added-3-3"]);
+        assert!(metrics.render(&engine, &[]).contains("numena_bus_dropped_total 2"));
+    }
+
+    /// Needs a NATS server with JetStream at NUMENA_TEST_NATS_URL
+    #[cfg(feature = "nats")]
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_nats_publisher() {
+        use futures_util::StreamExt;
+
+        let url = std::env::var("NUMENA_TEST_NATS_URL").expect("NUMENA_TEST_NATS_URL is not set");
+        let client = async_nats::connect(&url).await.unwrap();
+        let jetstream = async_nats::jetstream::new(client);
+        let stream_name = format!("NUMENA_TEST_{}", std::process::id());
+        let prefix = stream_name.to_lowercase();
+        let mut stream = jetstream
+            .get_or_create_stream(async_nats::jetstream::stream::Config {
+                name: stream_name.clone(),
+                subjects: vec![format!("{}.>", prefix)],
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let registry = Arc::new(BookRegistry::new());
+        registry.register_book("ETH-USD".to_string()).unwrap();
+        let config = BusConfig {
+            trades_topic: format!("{}.trades.{{book}}", prefix),
+            orders_topic: format!("{}.orders.{{book}}", prefix),
+            ..BusConfig::default()
+        };
+        let publisher = NatsPublisher::connect(&url).await.unwrap();
+        let mut engine = MatchingEngine::auto_creating();
+        engine
+            .orderbook_manager
+            .set_event_sink(Box::new(BusSink::start(publisher, config, registry, Arc::new(Metrics::new()))));
+        submit(&mut engine, 1, 10, 100, false, [1; 20]);
+        submit(&mut engine, 2, 10, 100, true, [2; 20]);
+        engine.finalize().unwrap();
+
+        assert_eq!(stream.info().await.unwrap().state.messages, 4);
+        let consumer = stream
+            .create_consumer(async_nats::jetstream::consumer::pull::Config::default())
+            .await
+            .unwrap();
+        let mut messages = consumer.fetch().max_messages(4).messages().await.unwrap();
+        let mut subjects = Vec::new();
+        while let Some(message) = messages.next().await {
+            subjects.push(message.unwrap().subject.to_string());
+        }
+        assert_eq!(
+            subjects,
+            vec![
+                format!("{}.orders.ETH-USD", prefix),
+                format!("{}.orders.ETH-USD", prefix),
+                format!("{}.trades.ETH-USD", prefix),
+                format!("{}.orders.ETH-USD", prefix),
+            ]
+        );
+        jetstream.delete_stream(&stream_name).await.unwrap();
+    }
+}
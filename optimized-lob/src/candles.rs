@@ -0,0 +1,345 @@
+// candles.rs
+
+use crate::{trade_tape::Trade, utils::BookId};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Width of a candle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandleInterval {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+}
+
+impl CandleInterval {
+    /// Every interval candles are built at
+    pub const ALL: [CandleInterval; 3] = [Self::OneMinute, Self::FiveMinutes, Self::OneHour];
+
+    /// Parses an interval name: "1m", "5m" or "1h"
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "1m" => Some(Self::OneMinute),
+            "5m" => Some(Self::FiveMinutes),
+            "1h" => Some(Self::OneHour),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OneMinute => "1m",
+            Self::FiveMinutes => "5m",
+            Self::OneHour => "1h",
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            Self::OneMinute => Duration::from_secs(60),
+            Self::FiveMinutes => Duration::from_secs(5 * 60),
+            Self::OneHour => Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// Gets the start of the bucket holding `timestamp`, both in nanoseconds since the epoch
+    #[inline]
+    pub fn bucket_start(&self, timestamp: u64) -> u64 {
+        let width = self.duration().as_nanos() as u64;
+        timestamp - timestamp % width
+    }
+}
+
+/// Open, high, low, close and volume of the trades in one bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candle {
+    pub start: u64, // Start of the bucket, in nanoseconds since the epoch
+    pub open: u32,
+    pub high: u32,
+    pub low: u32,
+    pub close: u32,
+    pub volume: u64,
+    pub trade_count: u64,
+}
+
+impl Candle {
+    fn open(start: u64, trade: &Trade) -> Self {
+        Self {
+            start,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.qty.value(),
+            trade_count: 1,
+        }
+    }
+
+    /// An empty candle that carries the previous close forward
+    fn carried(start: u64, close: u32) -> Self {
+        Self {
+            start,
+            open: close,
+            high: close,
+            low: close,
+            close,
+            volume: 0,
+            trade_count: 0,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume = self.volume.saturating_add(trade.qty.value());
+        self.trade_count += 1;
+    }
+}
+
+/// Keeps the closed candles of one book and interval.
+/// Implementations must not block, they are called on the matching thread.
+pub trait CandleStore: Send {
+    /// Stores a candle whose bucket has ended. Candles arrive in bucket order.
+    fn push(&mut self, candle: Candle);
+
+    /// Returns up to `limit` of the most recent candles, oldest first.
+    fn recent(&self, limit: usize) -> Vec<Candle>;
+}
+
+/// Keeps at most `capacity` closed candles in memory, dropping the oldest.
+#[derive(Debug)]
+pub struct MemoryCandleStore {
+    candles: VecDeque<Candle>,
+    capacity: usize,
+}
+
+impl MemoryCandleStore {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "candle store capacity must be positive");
+        Self {
+            candles: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl CandleStore for MemoryCandleStore {
+    fn push(&mut self, candle: Candle) {
+        if self.candles.len() == self.capacity {
+            self.candles.pop_front();
+        }
+        self.candles.push_back(candle);
+    }
+
+    fn recent(&self, limit: usize) -> Vec<Candle> {
+        let skip = self.candles.len().saturating_sub(limit);
+        self.candles.iter().skip(skip).copied().collect()
+    }
+}
+
+/// Builds the candles of one book at one interval as trades arrive.
+/// The candle of the current bucket stays here until a trade opens a later bucket, then
+/// it is handed to the store.
+pub struct CandleBuilder {
+    interval: CandleInterval,
+    current: Option<Candle>,
+    store: Box<dyn CandleStore>,
+}
+
+impl CandleBuilder {
+    /// Creates a builder keeping at most `capacity` closed candles in memory
+    pub fn new(interval: CandleInterval, capacity: usize) -> Self {
+        Self::with_store(interval, Box::new(MemoryCandleStore::new(capacity)))
+    }
+
+    pub fn with_store(interval: CandleInterval, store: Box<dyn CandleStore>) -> Self {
+        Self {
+            interval,
+            current: None,
+            store,
+        }
+    }
+
+    #[inline]
+    pub fn interval(&self) -> CandleInterval {
+        self.interval
+    }
+
+    /// Adds a trade to the candle of its bucket.
+    /// A trade stamped before the current bucket, e.g. after a clock step back, counts toward it.
+    pub fn record(&mut self, trade: &Trade) {
+        let start = self.interval.bucket_start(trade.timestamp);
+        match &mut self.current {
+            Some(candle) if candle.start >= start => candle.add(trade),
+            current => {
+                if let Some(closed) = current.replace(Candle::open(start, trade)) {
+                    self.store.push(closed);
+                }
+            }
+        }
+    }
+
+    /// Returns up to `limit` of the most recent candles, oldest first, including the current one.
+    /// Buckets without trades are omitted, or with `fill_empty` carry the previous close forward
+    /// up to the bucket holding `now`.
+    pub fn candles(&self, limit: usize, fill_empty: bool, now: u64) -> Vec<Candle> {
+        let mut candles = self.store.recent(limit);
+        candles.extend(self.current);
+        if !fill_empty {
+            let skip = candles.len().saturating_sub(limit);
+            return candles.split_off(skip);
+        }
+
+        // Walk back from now so a long gap never fills more than `limit` candles
+        let width = self.interval.duration().as_nanos() as u64;
+        let mut filled = Vec::with_capacity(limit);
+        let mut next = self.interval.bucket_start(now) + width; // Start of the oldest bucket filled so far
+        for candle in candles.iter().rev() {
+            while filled.len() < limit && next > candle.start + width {
+                next -= width;
+                filled.push(Candle::carried(next, candle.close));
+            }
+            if filled.len() == limit {
+                break;
+            }
+            filled.push(*candle);
+            next = candle.start;
+        }
+        filled.reverse();
+        filled
+    }
+}
+
+/// Candle builders of every book that has traded, one per interval.
+pub struct CandleAggregator {
+    books: HashMap<BookId, Vec<CandleBuilder>>,
+    capacity: usize,
+}
+
+impl CandleAggregator {
+    /// Creates an aggregator keeping at most `capacity` closed candles per book and interval
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            books: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Adds a trade of `book_id` to its book's candles at every interval
+    pub fn record(&mut self, book_id: BookId, trade: &Trade) {
+        let capacity = self.capacity;
+        let builders = self.books.entry(book_id).or_insert_with(|| {
+            CandleInterval::ALL.iter().map(|&interval| CandleBuilder::new(interval, capacity)).collect()
+        });
+        for builder in builders {
+            builder.record(trade);
+        }
+    }
+
+    /// Gets the candle builder of a book at an interval, or None if the book has never traded
+    pub fn get(&self, book_id: BookId, interval: CandleInterval) -> Option<&CandleBuilder> {
+        self.books
+            .get(&book_id)?
+            .iter()
+            .find(|builder| builder.interval() == interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{order::OrderId, quantity::Qty};
+
+    const SECOND: u64 = 1_000_000_000;
+    const MINUTE: u64 = 60 * SECOND;
+
+    fn trade(timestamp: u64, price: u32, qty: u64) -> Trade {
+        Trade {
+            trade_id: 0,
+            timestamp,
+            price,
+            qty: Qty(qty),
+            aggressor_is_bid: true,
+            maker_order_id: OrderId(0),
+            taker_order_id: OrderId(1),
+        }
+    }
+
+    fn ohlcv(candle: &Candle) -> (u64, u32, u32, u32, u32, u64, u64) {
+        (candle.start, candle.open, candle.high, candle.low, candle.close, candle.volume, candle.trade_count)
+    }
+
+    #[test]
+    fn test_candles_across_buckets() {
+        let mut aggregator = CandleAggregator::new(16);
+        let start = 1_000 * 60 * MINUTE;
+        let trades = [
+            trade(start + 5 * SECOND, 100, 1),
+            trade(start + 20 * SECOND, 110, 2),
+            trade(start + 59 * SECOND, 95, 3),
+            trade(start + MINUTE, 97, 4),                // Opens the second minute on its boundary
+            trade(start + 3 * MINUTE + SECOND, 105, 5),  // Minute three has no trades
+            trade(start + 5 * MINUTE + 30 * SECOND, 90, 6),
+        ];
+        for trade in &trades {
+            aggregator.record(BookId(0), trade);
+        }
+        assert!(aggregator.get(BookId(1), CandleInterval::OneMinute).is_none());
+
+        let minutes = aggregator.get(BookId(0), CandleInterval::OneMinute).unwrap();
+        let candles = minutes.candles(100, false, start + 6 * MINUTE);
+        println!("1m candles: {:?}", candles);
+        assert_eq!(
+            candles.iter().map(ohlcv).collect::<Vec<_>>(),
+            vec![
+                (start, 100, 110, 95, 95, 6, 3),
+                (start + MINUTE, 97, 97, 97, 97, 4, 1),
+                (start + 3 * MINUTE, 105, 105, 105, 105, 5, 1),
+                (start + 5 * MINUTE, 90, 90, 90, 90, 6, 1),
+            ]
+        );
+        assert_eq!(minutes.candles(2, false, start).iter().map(|candle| candle.start).collect::<Vec<_>>(), vec![start + 3 * MINUTE, start + 5 * MINUTE]);
+
+        let five_minutes = aggregator.get(BookId(0), CandleInterval::FiveMinutes).unwrap();
+        assert_eq!(
+            five_minutes.candles(100, false, start).iter().map(ohlcv).collect::<Vec<_>>(),
+            vec![(start, 100, 110, 95, 105, 15, 5), (start + 5 * MINUTE, 90, 90, 90, 90, 6, 1)]
+        );
+        let hours = aggregator.get(BookId(0), CandleInterval::OneHour).unwrap();
+        assert_eq!(hours.candles(100, false, start).iter().map(ohlcv).collect::<Vec<_>>(), vec![(start, 100, 110, 90, 90, 21, 6)]);
+    }
+
+    #[test]
+    fn test_fill_empty_buckets() {
+        let mut builder = CandleBuilder::new(CandleInterval::OneMinute, 16);
+        builder.record(&trade(10 * SECOND, 100, 1));
+        builder.record(&trade(3 * MINUTE, 120, 2));
+
+        // Minutes one and two carry 100 forward, minutes four and five carry 120 up to now
+        let candles = builder.candles(100, true, 5 * MINUTE + 10 * SECOND);
+        assert_eq!(
+            candles.iter().map(ohlcv).collect::<Vec<_>>(),
+            vec![
+                (0, 100, 100, 100, 100, 1, 1),
+                (MINUTE, 100, 100, 100, 100, 0, 0),
+                (2 * MINUTE, 100, 100, 100, 100, 0, 0),
+                (3 * MINUTE, 120, 120, 120, 120, 2, 1),
+                (4 * MINUTE, 120, 120, 120, 120, 0, 0),
+                (5 * MINUTE, 120, 120, 120, 120, 0, 0),
+            ]
+        );
+        assert_eq!(builder.candles(2, true, 5 * MINUTE).iter().map(|candle| candle.start).collect::<Vec<_>>(), vec![4 * MINUTE, 5 * MINUTE]);
+    }
+
+    #[test]
+    fn test_store_is_bounded() {
+        let mut builder = CandleBuilder::new(CandleInterval::OneMinute, 2);
+        for minute in 0..5 {
+            builder.record(&trade(minute * MINUTE, 100 + minute as u32, 1));
+        }
+        // Two closed candles are kept, plus the current one
+        let starts: Vec<u64> = builder.candles(100, false, 0).iter().map(|candle| candle.start).collect();
+        assert_eq!(starts, vec![2 * MINUTE, 3 * MINUTE, 4 * MINUTE]);
+    }
+}
@@ -0,0 +1,184 @@
+// client_order_ids.rs
+
+use crate::{
+    order::OrderId,
+    utils::{hex_array, MAX_CLIENT_ORDER_ID_LEN},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+/// An identifier a trader gives its own order (FIX ClOrdID)
+/// Up to MAX_CLIENT_ORDER_ID_LEN printable ASCII characters, stored inline so order updates
+/// carrying it stay Copy.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientOrderId {
+    len: u8,
+    bytes: [u8; MAX_CLIENT_ORDER_ID_LEN],
+}
+
+impl ClientOrderId {
+    /// Checks a client order ID, returning None unless it is 1 to MAX_CLIENT_ORDER_ID_LEN
+    /// printable ASCII characters with no spaces
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::client_order_ids::ClientOrderId;
+    /// assert_eq!(ClientOrderId::parse("order-7").unwrap().as_str(), "order-7");
+    /// assert!(ClientOrderId::parse("").is_none());
+    /// assert!(ClientOrderId::parse("two words").is_none());
+    /// ```
+    pub fn parse(id: &str) -> Option<Self> {
+        if id.is_empty() || id.len() > MAX_CLIENT_ORDER_ID_LEN || !id.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return None;
+        }
+        let mut bytes = [0; MAX_CLIENT_ORDER_ID_LEN];
+        bytes[..id.len()].copy_from_slice(id.as_bytes());
+        Some(Self { len: id.len() as u8, bytes })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from ASCII by parse
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for ClientOrderId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for ClientOrderId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ClientOrderId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientOrderId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = String::deserialize(deserializer)?;
+        Self::parse(&id).ok_or_else(|| serde::de::Error::custom(format!("invalid client order ID {:?}", id)))
+    }
+}
+
+/// A client order ID bound to an order, as kept in snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientOrderEntry {
+    pub order_id: u64,
+    #[serde(with = "hex_array")]
+    pub trader: [u8; 20],
+    pub client_order_id: ClientOrderId,
+}
+
+/// Maps the client order IDs of working orders to their engine OrderIds and back
+/// IDs are scoped to their trader, so two traders may use the same one. A binding lasts until
+/// its order is done: filled, cancelled, or expired.
+#[derive(Debug, Default)]
+pub struct ClientOrderIds {
+    by_client: HashMap<([u8; 20], ClientOrderId), OrderId>,
+    by_order: HashMap<OrderId, ([u8; 20], ClientOrderId)>,
+}
+
+impl ClientOrderIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds `client_order_id` of `trader` to `order_id`, replacing any order it was bound to
+    pub fn insert(&mut self, trader: [u8; 20], client_order_id: ClientOrderId, order_id: OrderId) {
+        if let Some(previous) = self.by_client.insert((trader, client_order_id), order_id) {
+            self.by_order.remove(&previous);
+        }
+        if let Some(previous) = self.by_order.insert(order_id, (trader, client_order_id)) {
+            if previous != (trader, client_order_id) {
+                self.by_client.remove(&previous);
+            }
+        }
+    }
+
+    /// Gets the order a trader's client order ID is bound to
+    pub fn find(&self, trader: [u8; 20], client_order_id: ClientOrderId) -> Option<OrderId> {
+        self.by_client.get(&(trader, client_order_id)).copied()
+    }
+
+    /// Gets the client order ID bound to an order
+    #[inline]
+    pub fn get(&self, order_id: OrderId) -> Option<ClientOrderId> {
+        self.by_order.get(&order_id).map(|&(_, client_order_id)| client_order_id)
+    }
+
+    /// Unbinds an order's client order ID, returning it
+    #[inline]
+    pub fn remove(&mut self, order_id: OrderId) -> Option<ClientOrderId> {
+        let (trader, client_order_id) = self.by_order.remove(&order_id)?;
+        self.by_client.remove(&(trader, client_order_id));
+        Some(client_order_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_order.is_empty()
+    }
+
+    /// Gets every binding, sorted by order ID, for snapshots
+    pub fn entries(&self) -> Vec<ClientOrderEntry> {
+        let mut entries: Vec<ClientOrderEntry> = self
+            .by_order
+            .iter()
+            .map(|(order_id, &(trader, client_order_id))| ClientOrderEntry { order_id: order_id.0, trader, client_order_id })
+            .collect();
+        entries.sort_unstable_by_key(|entry| entry.order_id);
+        entries
+    }
+
+    /// Rebuilds the bindings from snapshot entries
+    pub fn from_entries(entries: Vec<ClientOrderEntry>) -> Self {
+        let mut ids = Self::new();
+        for entry in entries {
+            ids.insert(entry.trader, entry.client_order_id, OrderId(entry.order_id));
+        }
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_order_ids() {
+        let id = ClientOrderId::parse("abc-1").unwrap();
+        assert!(ClientOrderId::parse(&"x".repeat(MAX_CLIENT_ORDER_ID_LEN)).is_some());
+        assert!(ClientOrderId::parse(&"x".repeat(MAX_CLIENT_ORDER_ID_LEN + 1)).is_none());
+        assert!(ClientOrderId::parse("tab\there").is_none());
+        assert_eq!(serde_json::to_string(&id).unwrap(), "\"abc-1\"");
+        assert!(serde_json::from_str::<ClientOrderId>("\"\"").is_err());
+
+        // Traders share IDs without clashing
+        let mut ids = ClientOrderIds::new();
+        ids.insert([1; 20], id, OrderId(1));
+        ids.insert([2; 20], id, OrderId(2));
+        assert_eq!((ids.find([1; 20], id), ids.find([2; 20], id)), (Some(OrderId(1)), Some(OrderId(2))));
+        assert_eq!(ids.get(OrderId(2)), Some(id));
+
+        // Rebinding an ID drops its old order's binding
+        ids.insert([1; 20], id, OrderId(3));
+        assert_eq!((ids.get(OrderId(1)), ids.find([1; 20], id)), (None, Some(OrderId(3))));
+        println!("Entries: {:?}", ids.entries());
+        assert_eq!(ids.remove(OrderId(3)), Some(id));
+        assert_eq!(ids.find([1; 20], id), None);
+
+        let restored = ClientOrderIds::from_entries(ids.entries());
+        assert_eq!((restored.len(), restored.find([2; 20], id)), (1, Some(OrderId(2))));
+    }
+}
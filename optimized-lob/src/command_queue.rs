@@ -0,0 +1,223 @@
+// command_queue.rs
+
+use crate::{
+    matching::MatchingEngine,
+    utils::{MAX_CANCELS_IN_A_ROW, MAX_COMMANDS_PER_CYCLE},
+};
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{oneshot, Mutex, Notify};
+
+/// Which lane a command waits in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Cancel, // Cancels, mass cancels, and nonce bumps, which take risk off
+    New,    // New orders and replaces, which add it
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// Too many commands are waiting; new orders are refused until the queue drains.
+    Full,
+    /// The worker stopped before the command ran.
+    Closed,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "The engine is overloaded; try again later"),
+            QueueError::Closed => write!(f, "The matching worker has stopped"),
+        }
+    }
+}
+
+type Command = Box<dyn FnOnce(&mut MatchingEngine) + Send>;
+
+/// The result of a pushed command, once the worker has run it
+pub struct Pending<T>(oneshot::Receiver<T>);
+
+impl<T> Pending<T> {
+    /// Waits for the command to run, failing with Closed if it never will
+    pub async fn wait(self) -> Result<T, QueueError> {
+        self.0.await.map_err(|_| QueueError::Closed)
+    }
+}
+
+#[derive(Default)]
+struct Lanes {
+    cancels: VecDeque<Command>,
+    new: VecDeque<Command>,
+    cancels_in_a_row: usize,
+}
+
+impl Lanes {
+    fn len(&self) -> usize {
+        self.cancels.len() + self.new.len()
+    }
+
+    /// Takes the next command to run: a cancel whenever one waits, except that after
+    /// MAX_CANCELS_IN_A_ROW of them a waiting new command goes first
+    fn next(&mut self) -> Option<Command> {
+        if !self.cancels.is_empty() && (self.cancels_in_a_row < MAX_CANCELS_IN_A_ROW || self.new.is_empty()) {
+            self.cancels_in_a_row += 1;
+            return self.cancels.pop_front();
+        }
+        self.cancels_in_a_row = 0;
+        self.new.pop_front()
+    }
+}
+
+/// Commands waiting for the matching worker, the only writer of the engine
+/// Handlers queue what they would have done under the engine lock and await the result. Each
+/// cycle the worker locks the engine once and runs up to MAX_COMMANDS_PER_CYCLE commands, cancels
+/// ahead of new orders, so taking risk off never waits behind a flood of orders adding it. Once
+/// `max_depth` commands are waiting, new orders are refused with Full while cancels still go in.
+pub struct CommandQueue {
+    lanes: std::sync::Mutex<Lanes>,
+    ready: Notify,
+    max_depth: usize,
+}
+
+impl CommandQueue {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            lanes: std::sync::Mutex::new(Lanes::default()),
+            ready: Notify::new(),
+            max_depth,
+        }
+    }
+
+    /// Creates a queue and spawns its worker onto the current Tokio runtime
+    pub fn spawn(engine: Arc<Mutex<MatchingEngine>>, max_depth: usize) -> Arc<Self> {
+        let queue = Arc::new(Self::new(max_depth));
+        tokio::spawn(queue.clone().work(engine));
+        queue
+    }
+
+    /// Gets the number of commands waiting
+    pub fn depth(&self) -> usize {
+        self.lanes().len()
+    }
+
+    fn lanes(&self) -> std::sync::MutexGuard<'_, Lanes> {
+        self.lanes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues a command in `lane` and waits for the worker to run it, returning its result
+    /// The command runs in the caller's tracing span. One that panics fails with Closed, and
+    /// the worker carries on.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        lane: Lane,
+        command: impl FnOnce(&mut MatchingEngine) -> T + Send + 'static,
+    ) -> Result<T, QueueError> {
+        self.push(lane, command)?.wait().await
+    }
+
+    /// Queues a command in `lane` without waiting for it, returning where its result will arrive
+    /// Commands of one lane run in the order they were pushed, so a caller that must keep its
+    /// commands in order pushes each before letting the next one go.
+    pub fn push<T: Send + 'static>(
+        &self,
+        lane: Lane,
+        command: impl FnOnce(&mut MatchingEngine) -> T + Send + 'static,
+    ) -> Result<Pending<T>, QueueError> {
+        let (sender, receiver) = oneshot::channel();
+        let span = tracing::Span::current();
+        let command: Command = Box::new(move |engine| {
+            let _entered = span.enter();
+            let _ = sender.send(command(engine));
+        });
+        {
+            let mut lanes = self.lanes();
+            match lane {
+                Lane::New if lanes.len() >= self.max_depth => return Err(QueueError::Full),
+                Lane::New => lanes.new.push_back(command),
+                Lane::Cancel => lanes.cancels.push_back(command),
+            }
+        }
+        self.ready.notify_one();
+        Ok(Pending(receiver))
+    }
+
+    /// Runs queued commands against `engine` until the runtime shuts down
+    /// The lock is given up between cycles so reads get their turn. Each cycle starts with the
+    /// next pass of an order the fill limit cut short, if one waits, so it fills between the
+    /// commands queued after it instead of ahead of all of them.
+    pub async fn work(self: Arc<Self>, engine: Arc<Mutex<MatchingEngine>>) {
+        loop {
+            self.ready.notified().await;
+            let started = Instant::now();
+            let mut engine = engine.lock().await;
+            engine.metrics.lock_wait.observe(started.elapsed());
+            engine.resume_matching();
+            for _ in 0..MAX_COMMANDS_PER_CYCLE {
+                let Some(command) = self.lanes().next() else {
+                    break;
+                };
+                // A panicking command drops its sender, failing only its own caller
+                if panic::catch_unwind(AssertUnwindSafe(|| command(&mut engine))).is_err() {
+                    tracing::error!("A queued command panicked");
+                }
+            }
+            if self.depth() > 0 || engine.can_resume() {
+                self.ready.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    #[tokio::test]
+    async fn test_cancels_go_first() {
+        let engine = Arc::new(Mutex::new(MatchingEngine::auto_creating()));
+        let queue = CommandQueue::spawn(engine.clone(), 2 * MAX_CANCELS_IN_A_ROW);
+        let ran = Arc::new(StdMutex::new(Vec::new()));
+
+        // With the engine held, a burst of new orders queues up, then a burst of cancels
+        let held = engine.lock().await;
+        let mut commands = Vec::new();
+        for (lane, count) in [(Lane::New, MAX_CANCELS_IN_A_ROW), (Lane::Cancel, MAX_CANCELS_IN_A_ROW + 2)] {
+            for _ in 0..count {
+                let (queue, ran) = (queue.clone(), ran.clone());
+                commands.push(tokio::spawn(async move {
+                    queue.run(lane, move |_| ran.lock().unwrap().push(lane)).await
+                }));
+            }
+            while queue.depth() < commands.len() {
+                tokio::task::yield_now().await;
+            }
+        }
+        // The queue is full for new orders, but not for cancels
+        assert_eq!(queue.run(Lane::New, |_| ()).await, Err(QueueError::Full));
+        drop(held);
+        for command in commands {
+            command.await.unwrap().unwrap();
+        }
+
+        // Cancels ran first, letting one new order through after MAX_CANCELS_IN_A_ROW of them
+        let ran = ran.lock().unwrap().clone();
+        let first_new = ran.iter().position(|&lane| lane == Lane::New).unwrap();
+        println!("First new order ran after {} cancels", first_new);
+        assert_eq!(first_new, MAX_CANCELS_IN_A_ROW);
+        assert_eq!(&ran[first_new + 1..first_new + 3], &[Lane::Cancel, Lane::Cancel]);
+        assert!(ran[first_new + 3..].iter().all(|&lane| lane == Lane::New));
+        assert_eq!((ran.len(), queue.depth()), (2 * MAX_CANCELS_IN_A_ROW + 2, 0));
+        assert_eq!(queue.run(Lane::New, |engine| engine.next_order_id()).await.map(|order_id| order_id.0), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_command() {
+        let queue = CommandQueue::spawn(Arc::new(Mutex::new(MatchingEngine::auto_creating())), 8);
+        assert_eq!(queue.run(Lane::Cancel, |_| panic!("boom")).await, Err::<(), _>(QueueError::Closed));
+        assert_eq!(queue.run(Lane::Cancel, |_| 1).await, Ok(1));
+    }
+}
@@ -0,0 +1,763 @@
+// config.rs
+
+use crate::{
+    audit::AuditConfig,
+    bus::BusConfig,
+    matching::FillLimitPolicy,
+    order_intake::IntakeLimits,
+    settlement_submitter::SubmitterConfig,
+    utils::{SETTLEMENT_BATCH_WINDOW, SETTLEMENT_MAX_BATCH_SIZE, SETTLEMENT_MAX_RETRIES},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Environment variables that override the configuration file
+pub const BIND_ADDRESS_ENV: &str = "NUMENA_BIND_ADDRESS";
+pub const PORT_ENV: &str = "NUMENA_PORT";
+pub const WORKERS_ENV: &str = "NUMENA_WORKERS";
+pub const BODY_LIMIT_ENV: &str = "NUMENA_BODY_LIMIT";
+pub const CORS_ORIGINS_ENV: &str = "NUMENA_CORS_ORIGINS"; // Comma separated
+pub const LOG_LEVEL_ENV: &str = "NUMENA_LOG_LEVEL";
+/// Directory of the write-ahead log; commands are not logged when unset
+pub const WAL_DIR_ENV: &str = "NUMENA_WAL_DIR";
+/// Directory admin snapshots are written to and restored from
+pub const SNAPSHOT_DIR_ENV: &str = "NUMENA_SNAPSHOT_DIR";
+/// File the book registry is kept in; books are only known from the WAL and snapshots when unset
+pub const REGISTRY_PATH_ENV: &str = "NUMENA_REGISTRY_PATH";
+/// SQLite database trades and finished orders are kept in; needs the `sqlite` feature
+pub const TRADE_DB_ENV: &str = "NUMENA_TRADE_DB";
+/// Ethereum JSON-RPC endpoint used to verify contract-wallet signatures; without it they get 503
+pub const ETH_RPC_URL_ENV: &str = "NUMENA_ETH_RPC_URL";
+/// Hex private key of the account that submits settlements; with the RPC URL, it enables the submitter
+pub const OPERATOR_KEY_ENV: &str = "NUMENA_OPERATOR_KEY";
+/// "false" turns authentication off, for development only
+pub const AUTH_ENABLED_ENV: &str = "NUMENA_AUTH_ENABLED";
+pub const ADMIN_KEYS_ENV: &str = "NUMENA_ADMIN_KEYS"; // Comma separated
+/// Directory of the audit log; commands are not audited when unset
+pub const AUDIT_DIR_ENV: &str = "NUMENA_AUDIT_DIR";
+/// Port the FIX gateway listens on; needs the `fix` feature, and there is no gateway when unset
+pub const FIX_PORT_ENV: &str = "NUMENA_FIX_PORT";
+/// Port the gRPC API listens on; needs the `grpc` feature, and there is no gRPC API when unset
+pub const GRPC_PORT_ENV: &str = "NUMENA_GRPC_PORT";
+/// NATS server trades and order events are published to; needs the `nats` feature
+pub const BUS_URL_ENV: &str = "NUMENA_BUS_URL";
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_BODY_LIMIT: usize = 256 << 10;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 300;
+const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 100_000;
+const DEFAULT_QUEUE_DEPTH: usize = 10_000;
+const DEFAULT_MIN_EXPIRY_MARGIN_SECS: u64 = 5;
+const DEFAULT_SIGNATURE_WINDOW_SECS: u64 = 30;
+const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+const DEFAULT_FIX_COMP_ID: &str = "NUMENA";
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Everything the server is started with, read from a TOML file and then the environment
+/// Every value has a default, so a file only needs the ones it changes and no file is needed
+/// at all; environment variables win over the file.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::config::Config;
+/// let config = Config::parse("[server]\nport = 9000\n").unwrap();
+/// assert_eq!((config.server.bind_address.as_str(), config.server.port), ("127.0.0.1", 9000));
+/// assert_eq!(Config::parse("[server]\nworkers = 0\n").unwrap_err().to_string(), "Invalid server.workers: must be at least 1");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSettings,
+    pub storage: StorageSettings,
+    pub settlement: SettlementSettings,
+    pub auth: AuthSettings,
+    pub audit: AuditSettings,
+    pub fix: FixSettings,
+    pub grpc: GrpcSettings,
+    pub bus: BusSettings,
+    pub accounting: AccountingSettings,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_address: String,
+    pub port: u16,
+    pub workers: Option<usize>, // HTTP worker threads; one per CPU core when unset
+    pub body_limit: usize,      // Largest JSON request body accepted, in bytes
+    pub cors_origins: Vec<String>, // Origins browsers may call the API from; "*" allows any, none turns CORS off
+    pub log_level: String, // A level, or filter directives like "info,optimized_lob::matching=trace"
+    pub shutdown_timeout_secs: u64, // How long in-flight requests may take to finish on shutdown
+    pub idempotency_ttl_secs: u64, // How long the response to a submission is kept for its retries
+    pub idempotency_capacity: usize, // Most submission responses kept; 0 turns deduplication off
+    pub queue_depth: usize, // Commands waiting for the matching worker past which new orders get 503
+    pub max_order_quantity: Option<u64>, // Largest quantity an order may have; no limit when unset
+    pub min_expiry_margin_secs: u64, // How far in the future a signed expiry must be when the order comes in
+    pub max_fills_per_order: Option<usize>, // Most fills an order makes before other commands get a turn; no limit when unset
+    pub fill_limit_policy: FillLimitPolicy, // "cancel" or "continue": what becomes of an order at max_fills_per_order
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+            port: DEFAULT_PORT,
+            workers: None,
+            body_limit: DEFAULT_BODY_LIMIT,
+            cors_origins: Vec::new(),
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            idempotency_capacity: DEFAULT_IDEMPOTENCY_CAPACITY,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            max_order_quantity: None,
+            min_expiry_margin_secs: DEFAULT_MIN_EXPIRY_MARGIN_SECS,
+            max_fills_per_order: None,
+            fill_limit_policy: FillLimitPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageSettings {
+    pub wal_dir: Option<PathBuf>, // Commands are not logged when unset
+    pub snapshot_dir: PathBuf,
+    pub registry_path: Option<PathBuf>, // The book registry is kept only in memory when unset
+    pub trade_db: Option<PathBuf>, // Trade history only goes back as far as the in-memory tapes when unset
+}
+
+impl Default for StorageSettings {
+    fn default() -> Self {
+        Self {
+            wal_dir: None,
+            snapshot_dir: PathBuf::from(DEFAULT_SNAPSHOT_DIR),
+            registry_path: None,
+            trade_db: None,
+        }
+    }
+}
+
+/// Where the commands the API takes are audited, and how much of the log is kept
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditSettings {
+    pub dir: Option<PathBuf>, // Commands are not audited when unset
+    pub segment_bytes: u64, // Size a file grows to before the next one is started
+    pub retained_segments: usize, // Files kept; queries see no further back than these
+    pub buffer: usize, // Entries waiting to be written past which new ones are dropped
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        let config = AuditConfig::default();
+        Self {
+            dir: None,
+            segment_bytes: config.segment_bytes,
+            retained_segments: config.retained_segments,
+            buffer: config.buffer,
+        }
+    }
+}
+
+/// The FIX order entry gateway, which listens on the server's bind address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FixSettings {
+    pub port: Option<u16>, // There is no gateway when unset
+    pub comp_id: String, // SenderCompID of the gateway, which sessions must give as their TargetCompID
+    pub state_path: Option<PathBuf>, // Sequence numbers outlive restarts only when set
+}
+
+impl Default for FixSettings {
+    fn default() -> Self {
+        Self { port: None, comp_id: DEFAULT_FIX_COMP_ID.to_string(), state_path: None }
+    }
+}
+
+/// The gRPC API, which listens on the server's bind address
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcSettings {
+    pub port: Option<u16>, // There is no gRPC API when unset
+}
+
+/// The volume and fee ledger
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingSettings {
+    pub utc_offset_minutes: i32, // Where the ledger's days start, ahead of UTC; 0 starts them at UTC midnight
+}
+
+/// The message bus trades and order events are published to, and the topics they go on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BusSettings {
+    pub url: Option<String>, // NATS server; nothing is published when unset
+    pub trades_topic: String, // `{book}` is replaced by the name of the book
+    pub orders_topic: String,
+    pub buffer: usize, // Messages waiting for the bus past which new ones are dropped
+}
+
+impl Default for BusSettings {
+    fn default() -> Self {
+        let config = BusConfig::default();
+        Self {
+            url: None,
+            trades_topic: config.trades_topic,
+            orders_topic: config.orders_topic,
+            buffer: config.buffer,
+        }
+    }
+}
+
+/// The node and account settlements go through, and how they are batched
+/// Without an RPC URL, contract-wallet signatures and funds are not checked; without an
+/// operator key as well, settlements stay Pending.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettlementSettings {
+    pub rpc_url: Option<String>,
+    pub operator_key: Option<String>, // Hex private key
+    pub batch_window_ms: u64,
+    pub max_batch_size: usize,
+    pub max_retries: u32,
+    pub recredit_on_failure: bool,
+}
+
+impl Default for SettlementSettings {
+    fn default() -> Self {
+        Self {
+            rpc_url: None,
+            operator_key: None,
+            batch_window_ms: SETTLEMENT_BATCH_WINDOW.as_millis() as u64,
+            max_batch_size: SETTLEMENT_MAX_BATCH_SIZE,
+            max_retries: SETTLEMENT_MAX_RETRIES,
+            recredit_on_failure: false,
+        }
+    }
+}
+
+/// The operator key is never printed
+impl fmt::Debug for SettlementSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SettlementSettings")
+            .field("rpc_url", &self.rpc_url)
+            .field("operator_key", &self.operator_key.as_ref().map(|_| "<redacted>"))
+            .field("batch_window_ms", &self.batch_window_ms)
+            .field("max_batch_size", &self.max_batch_size)
+            .field("max_retries", &self.max_retries)
+            .field("recredit_on_failure", &self.recredit_on_failure)
+            .finish()
+    }
+}
+
+/// Who may call the API
+/// Admin keys open every endpoint; a trader key, or a request signed by the trader, opens the
+/// trading endpoints for that trader alone. Reads need no credentials.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    pub enabled: bool, // Off lets every request through, for development only
+    pub admin_keys: Vec<String>,
+    pub api_keys: Vec<ApiKeySetting>,
+    pub signature_window_secs: u64, // How far a signed request's timestamp may be from the server's clock
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            admin_keys: Vec::new(),
+            api_keys: Vec::new(),
+            signature_window_secs: DEFAULT_SIGNATURE_WINDOW_SECS,
+        }
+    }
+}
+
+/// The keys themselves are never printed
+impl fmt::Debug for AuthSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let traders: Vec<&str> = self.api_keys.iter().map(|api_key| api_key.trader.as_str()).collect();
+        f.debug_struct("AuthSettings")
+            .field("enabled", &self.enabled)
+            .field("admin_keys", &format!("<{} redacted>", self.admin_keys.len()))
+            .field("api_keys", &traders)
+            .field("signature_window_secs", &self.signature_window_secs)
+            .finish()
+    }
+}
+
+/// An API key bound to the trader it acts for
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeySetting {
+    pub key: String,
+    pub trader: String, // 0x-prefixed address
+}
+
+impl ApiKeySetting {
+    /// Gets the address the key acts for, or None if it isn't one
+    pub fn trader_address(&self) -> Option<[u8; 20]> {
+        let bytes = hex::decode(self.trader.trim_start_matches("0x")).ok()?;
+        bytes.try_into().ok()
+    }
+}
+
+impl ServerSettings {
+    /// Gets the bounds every submitted order is held to
+    pub fn intake_limits(&self) -> IntakeLimits {
+        IntakeLimits {
+            max_quantity: self.max_order_quantity.unwrap_or(u64::MAX),
+            min_expiry_margin_secs: self.min_expiry_margin_secs,
+        }
+    }
+}
+
+impl AuditSettings {
+    /// Gets the audit log tunables these settings give
+    pub fn audit_config(&self) -> AuditConfig {
+        AuditConfig {
+            segment_bytes: self.segment_bytes,
+            retained_segments: self.retained_segments,
+            buffer: self.buffer,
+        }
+    }
+}
+
+impl BusSettings {
+    /// Gets the bus publisher tunables these settings give, the rest at their defaults
+    pub fn bus_config(&self) -> BusConfig {
+        BusConfig {
+            trades_topic: self.trades_topic.clone(),
+            orders_topic: self.orders_topic.clone(),
+            buffer: self.buffer,
+            ..BusConfig::default()
+        }
+    }
+}
+
+impl SettlementSettings {
+    /// Gets the submitter tunables these settings give, the rest at their defaults
+    pub fn submitter_config(&self) -> SubmitterConfig {
+        SubmitterConfig {
+            batch_window: Duration::from_millis(self.batch_window_ms),
+            max_batch_size: self.max_batch_size,
+            max_retries: self.max_retries,
+            recredit_on_failure: self.recredit_on_failure,
+            ..SubmitterConfig::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse(String),
+    /// A value out of range, named by its key in the file, or by its variable when it came from the environment.
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "Cannot read config file {}: {}", path.display(), error),
+            ConfigError::Parse(message) => write!(f, "Invalid config file: {}", message),
+            ConfigError::Invalid { key, message } => write!(f, "Invalid {}: {}", key, message),
+        }
+    }
+}
+
+fn invalid(key: &str, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid { key: key.to_string(), message: message.into() }
+}
+
+impl Config {
+    /// Parses and validates a TOML configuration
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(|error| ConfigError::Parse(error.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a configuration from `path`, or starts from the defaults without one, then applies
+    /// the environment over it
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|error| ConfigError::Io { path: path.to_path_buf(), error })?;
+                Config::parse(&text)?
+            }
+            None => Config::default(),
+        };
+        config.with_env(|name| std::env::var(name).ok())
+    }
+
+    /// Overrides values with the environment variables `var` finds, and validates the result
+    /// Variables that are set but empty count as unset.
+    pub fn with_env(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+        let number = |name: &str, value: String| {
+            value.trim().parse::<u64>().map_err(|_| invalid(name, format!("expected a number, got {:?}", value)))
+        };
+        if let Some(value) = var(BIND_ADDRESS_ENV) {
+            self.server.bind_address = value;
+        }
+        if let Some(value) = var(PORT_ENV) {
+            let port = number(PORT_ENV, value)?;
+            self.server.port = u16::try_from(port).map_err(|_| invalid(PORT_ENV, "must be at most 65535"))?;
+        }
+        if let Some(value) = var(WORKERS_ENV) {
+            self.server.workers = Some(number(WORKERS_ENV, value)? as usize);
+        }
+        if let Some(value) = var(BODY_LIMIT_ENV) {
+            self.server.body_limit = number(BODY_LIMIT_ENV, value)? as usize;
+        }
+        if let Some(value) = var(CORS_ORIGINS_ENV) {
+            self.server.cors_origins = value.split(',').map(|origin| origin.trim().to_string()).collect();
+        }
+        if let Some(value) = var(LOG_LEVEL_ENV) {
+            self.server.log_level = value;
+        }
+        if let Some(value) = var(WAL_DIR_ENV) {
+            self.storage.wal_dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var(SNAPSHOT_DIR_ENV) {
+            self.storage.snapshot_dir = PathBuf::from(value);
+        }
+        if let Some(value) = var(REGISTRY_PATH_ENV) {
+            self.storage.registry_path = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var(TRADE_DB_ENV) {
+            self.storage.trade_db = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var(ETH_RPC_URL_ENV) {
+            self.settlement.rpc_url = Some(value);
+        }
+        if let Some(value) = var(OPERATOR_KEY_ENV) {
+            self.settlement.operator_key = Some(value);
+        }
+        if let Some(value) = var(AUTH_ENABLED_ENV) {
+            self.auth.enabled = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid(AUTH_ENABLED_ENV, format!("expected true or false, got {:?}", value)))?;
+        }
+        if let Some(value) = var(ADMIN_KEYS_ENV) {
+            self.auth.admin_keys = value.split(',').map(|key| key.trim().to_string()).collect();
+        }
+        if let Some(value) = var(AUDIT_DIR_ENV) {
+            self.audit.dir = Some(PathBuf::from(value));
+        }
+        if let Some(value) = var(FIX_PORT_ENV) {
+            let port = number(FIX_PORT_ENV, value)?;
+            self.fix.port = Some(u16::try_from(port).map_err(|_| invalid(FIX_PORT_ENV, "must be at most 65535"))?);
+        }
+        if let Some(value) = var(GRPC_PORT_ENV) {
+            let port = number(GRPC_PORT_ENV, value)?;
+            self.grpc.port = Some(u16::try_from(port).map_err(|_| invalid(GRPC_PORT_ENV, "must be at most 65535"))?);
+        }
+        if let Some(value) = var(BUS_URL_ENV) {
+            self.bus.url = Some(value);
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Checks every value is one the server can start with
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let server = &self.server;
+        if server.bind_address.parse::<IpAddr>().is_err() {
+            return Err(invalid("server.bind_address", format!("{:?} is not an IP address", server.bind_address)));
+        }
+        if server.workers == Some(0) {
+            return Err(invalid("server.workers", "must be at least 1"));
+        }
+        if server.body_limit == 0 {
+            return Err(invalid("server.body_limit", "must be at least 1 byte"));
+        }
+        if server.queue_depth == 0 {
+            return Err(invalid("server.queue_depth", "must be at least 1"));
+        }
+        if server.max_order_quantity == Some(0) {
+            return Err(invalid("server.max_order_quantity", "must be at least 1"));
+        }
+        if server.max_fills_per_order == Some(0) {
+            return Err(invalid("server.max_fills_per_order", "must be at least 1"));
+        }
+        for origin in &server.cors_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(invalid("server.cors_origins", format!("{:?} is not \"*\" or an http(s) origin", origin)));
+            }
+        }
+        // Each directive is a level, for every target, or `target=level`
+        for directive in server.log_level.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let (target, level) = directive.split_once('=').unwrap_or(("*", directive));
+            if target.is_empty() || !LOG_LEVELS.contains(&level.trim().to_ascii_lowercase().as_str()) {
+                let message = format!("{:?} is not one of {}, or target=level", directive, LOG_LEVELS.join(", "));
+                return Err(invalid("server.log_level", message));
+            }
+        }
+        if cfg!(not(feature = "sqlite")) && self.storage.trade_db.is_some() {
+            return Err(invalid("storage.trade_db", "needs a build with the sqlite feature"));
+        }
+        let settlement = &self.settlement;
+        if let Some(url) = &settlement.rpc_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(invalid("settlement.rpc_url", format!("{:?} is not an http(s) URL", url)));
+            }
+        }
+        if settlement.operator_key.is_some() && settlement.rpc_url.is_none() {
+            return Err(invalid("settlement.operator_key", "needs settlement.rpc_url to submit through"));
+        }
+        if settlement.max_batch_size == 0 {
+            return Err(invalid("settlement.max_batch_size", "must be at least 1"));
+        }
+        let auth = &self.auth;
+        if auth.admin_keys.iter().chain(auth.api_keys.iter().map(|api_key| &api_key.key)).any(|key| key.is_empty()) {
+            return Err(invalid("auth", "API keys must not be empty"));
+        }
+        if let Some(api_key) = auth.api_keys.iter().find(|api_key| api_key.trader_address().is_none()) {
+            return Err(invalid("auth.api_keys", format!("{:?} is not a trader address", api_key.trader)));
+        }
+        let mut keys: Vec<&String> = auth.admin_keys.iter().chain(auth.api_keys.iter().map(|api_key| &api_key.key)).collect();
+        keys.sort();
+        if keys.windows(2).any(|pair| pair[0] == pair[1]) {
+            return Err(invalid("auth", "the same API key is given twice"));
+        }
+        if auth.signature_window_secs == 0 {
+            return Err(invalid("auth.signature_window_secs", "must be at least 1"));
+        }
+        let audit = &self.audit;
+        if audit.segment_bytes == 0 {
+            return Err(invalid("audit.segment_bytes", "must be at least 1 byte"));
+        }
+        if audit.retained_segments == 0 {
+            return Err(invalid("audit.retained_segments", "must be at least 1"));
+        }
+        if audit.buffer == 0 {
+            return Err(invalid("audit.buffer", "must be at least 1"));
+        }
+        let fix = &self.fix;
+        if cfg!(not(feature = "fix")) && fix.port.is_some() {
+            return Err(invalid("fix.port", "needs a build with the fix feature"));
+        }
+        if fix.port.is_some() && fix.port == Some(server.port) {
+            return Err(invalid("fix.port", "must differ from server.port"));
+        }
+        if fix.comp_id.is_empty() || !fix.comp_id.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(invalid("fix.comp_id", format!("{:?} is not printable ASCII without spaces", fix.comp_id)));
+        }
+        let grpc = &self.grpc;
+        if cfg!(not(feature = "grpc")) && grpc.port.is_some() {
+            return Err(invalid("grpc.port", "needs a build with the grpc feature"));
+        }
+        if grpc.port.is_some() && (grpc.port == Some(server.port) || grpc.port == fix.port) {
+            return Err(invalid("grpc.port", "must differ from server.port and fix.port"));
+        }
+        let bus = &self.bus;
+        if cfg!(not(feature = "nats")) && bus.url.is_some() {
+            return Err(invalid("bus.url", "needs a build with the nats feature"));
+        }
+        if bus.trades_topic.is_empty() || bus.orders_topic.is_empty() {
+            return Err(invalid("bus", "topics must not be empty"));
+        }
+        if bus.buffer == 0 {
+            return Err(invalid("bus.buffer", "must be at least 1"));
+        }
+        if self.accounting.utc_offset_minutes.abs() >= 1_440 {
+            return Err(invalid("accounting.utc_offset_minutes", "must be less than a day either way"));
+        }
+        Ok(())
+    }
+
+    /// Gets the address the server listens on
+    pub fn bind_address(&self) -> (String, u16) {
+        (self.server.bind_address.clone(), self.server.port)
+    }
+}
+
+/// The effective configuration as printed at startup, one `key = value` per line
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "(unset)".to_string());
+        let (server, storage, settlement, auth) = (&self.server, &self.storage, &self.settlement, &self.auth);
+        writeln!(f, "server.bind_address = {}", server.bind_address)?;
+        writeln!(f, "server.port = {}", server.port)?;
+        writeln!(f, "server.workers = {}", optional(server.workers.map(|workers| workers.to_string())))?;
+        writeln!(f, "server.body_limit = {}", server.body_limit)?;
+        writeln!(f, "server.cors_origins = [{}]", server.cors_origins.join(", "))?;
+        writeln!(f, "server.log_level = {}", server.log_level)?;
+        writeln!(f, "server.shutdown_timeout_secs = {}", server.shutdown_timeout_secs)?;
+        writeln!(f, "server.idempotency_ttl_secs = {}", server.idempotency_ttl_secs)?;
+        writeln!(f, "server.idempotency_capacity = {}", server.idempotency_capacity)?;
+        writeln!(f, "server.queue_depth = {}", server.queue_depth)?;
+        writeln!(f, "server.max_order_quantity = {}", optional(server.max_order_quantity.map(|max| max.to_string())))?;
+        writeln!(f, "server.min_expiry_margin_secs = {}", server.min_expiry_margin_secs)?;
+        writeln!(f, "server.max_fills_per_order = {}", optional(server.max_fills_per_order.map(|max| max.to_string())))?;
+        let policy = match server.fill_limit_policy {
+            FillLimitPolicy::Cancel => "cancel",
+            FillLimitPolicy::Continue => "continue",
+        };
+        writeln!(f, "server.fill_limit_policy = {}", policy)?;
+        writeln!(f, "storage.wal_dir = {}", optional(storage.wal_dir.as_ref().map(|dir| dir.display().to_string())))?;
+        writeln!(f, "storage.snapshot_dir = {}", storage.snapshot_dir.display())?;
+        writeln!(f, "storage.registry_path = {}", optional(storage.registry_path.as_ref().map(|path| path.display().to_string())))?;
+        writeln!(f, "storage.trade_db = {}", optional(storage.trade_db.as_ref().map(|path| path.display().to_string())))?;
+        writeln!(f, "settlement.rpc_url = {}", optional(settlement.rpc_url.clone()))?;
+        writeln!(f, "settlement.operator_key = {}", optional(settlement.operator_key.as_ref().map(|_| "<redacted>".to_string())))?;
+        writeln!(f, "settlement.batch_window_ms = {}", settlement.batch_window_ms)?;
+        writeln!(f, "settlement.max_batch_size = {}", settlement.max_batch_size)?;
+        writeln!(f, "settlement.max_retries = {}", settlement.max_retries)?;
+        writeln!(f, "settlement.recredit_on_failure = {}", settlement.recredit_on_failure)?;
+        writeln!(f, "auth.enabled = {}", auth.enabled)?;
+        writeln!(f, "auth.admin_keys = <{} redacted>", auth.admin_keys.len())?;
+        let traders: Vec<&str> = auth.api_keys.iter().map(|api_key| api_key.trader.as_str()).collect();
+        writeln!(f, "auth.api_keys = [{}]", traders.join(", "))?;
+        writeln!(f, "auth.signature_window_secs = {}", auth.signature_window_secs)?;
+        let audit = &self.audit;
+        writeln!(f, "audit.dir = {}", optional(audit.dir.as_ref().map(|dir| dir.display().to_string())))?;
+        writeln!(f, "audit.segment_bytes = {}", audit.segment_bytes)?;
+        writeln!(f, "audit.retained_segments = {}", audit.retained_segments)?;
+        writeln!(f, "audit.buffer = {}", audit.buffer)?;
+        let fix = &self.fix;
+        writeln!(f, "fix.port = {}", optional(fix.port.map(|port| port.to_string())))?;
+        writeln!(f, "fix.comp_id = {}", fix.comp_id)?;
+        writeln!(f, "fix.state_path = {}", optional(fix.state_path.as_ref().map(|path| path.display().to_string())))?;
+        writeln!(f, "grpc.port = {}", optional(self.grpc.port.map(|port| port.to_string())))?;
+        let bus = &self.bus;
+        writeln!(f, "bus.url = {}", optional(bus.url.clone()))?;
+        writeln!(f, "bus.trades_topic = {}", bus.trades_topic)?;
+        writeln!(f, "bus.orders_topic = {}", bus.orders_topic)?;
+        writeln!(f, "bus.buffer = {}", bus.buffer)?;
+        write!(f, "accounting.utc_offset_minutes = {}", self.accounting.utc_offset_minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_load_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("numena.toml");
+        std::fs::write(
+            &path,
+            r#"
+[server]
+bind_address = "0.0.0.0"
+port = 9090
+workers = 4
+cors_origins = ["https://app.numena.io"]
+log_level = "info, optimized_lob::matching=debug"
+idempotency_ttl_secs = 60
+max_order_quantity = 1000000
+max_fills_per_order = 500
+fill_limit_policy = "continue"
+
+[storage]
+wal_dir = "/var/lib/numena/wal"
+snapshot_dir = "/var/lib/numena/snapshots"
+registry_path = "/var/lib/numena/books.json"
+
+[settlement]
+rpc_url = "http://localhost:8545"
+operator_key = "0x0101"
+max_batch_size = 8
+
+[auth]
+admin_keys = ["admin-secret"]
+api_keys = [{ key = "trader-secret", trader = "0x0202020202020202020202020202020202020202" }]
+
+[audit]
+dir = "/var/log/numena/audit"
+retained_segments = 4
+"#,
+        )
+        .unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let config = Config::parse(&text).unwrap();
+        println!("{}", config);
+        assert_eq!(config.bind_address(), ("0.0.0.0".to_string(), 9090));
+        assert_eq!((config.server.workers, config.server.body_limit), (Some(4), DEFAULT_BODY_LIMIT));
+        assert_eq!(config.server.log_level, "info, optimized_lob::matching=debug");
+        assert_eq!((config.server.idempotency_ttl_secs, config.server.idempotency_capacity), (60, DEFAULT_IDEMPOTENCY_CAPACITY));
+        let limits = config.server.intake_limits();
+        assert_eq!((limits.max_quantity, limits.min_expiry_margin_secs), (1_000_000, DEFAULT_MIN_EXPIRY_MARGIN_SECS));
+        assert_eq!(Config::default().server.intake_limits().max_quantity, u64::MAX);
+        assert_eq!((config.server.max_fills_per_order, config.server.fill_limit_policy), (Some(500), FillLimitPolicy::Continue));
+        assert_eq!(config.storage.wal_dir, Some(PathBuf::from("/var/lib/numena/wal")));
+        assert_eq!(config.storage.registry_path, Some(PathBuf::from("/var/lib/numena/books.json")));
+        assert_eq!(config.settlement.submitter_config().max_batch_size, 8);
+        assert_eq!(config.audit.dir, Some(PathBuf::from("/var/log/numena/audit")));
+        assert_eq!((config.audit.audit_config().retained_segments, config.audit.buffer), (4, AuditConfig::default().buffer));
+        assert!(!config.to_string().contains("0x0101"));
+        assert!(!config.to_string().contains("secret") && !format!("{:?}", config).contains("secret"));
+        assert_eq!(config.auth.api_keys[0].trader_address(), Some([2; 20]));
+
+        // The environment wins over the file
+        let env = HashMap::from([(PORT_ENV, "7000"), (WORKERS_ENV, ""), (CORS_ORIGINS_ENV, "https://a.io, *")]);
+        let config = config.with_env(|name| env.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!((config.server.port, config.server.workers), (7000, Some(4)));
+        assert_eq!(config.server.cors_origins, vec!["https://a.io".to_string(), "*".to_string()]);
+
+        let missing = Config::load(Some(&dir.path().join("missing.toml"))).unwrap_err();
+        assert!(missing.to_string().starts_with("Cannot read config file"));
+    }
+
+    #[test]
+    fn test_config_defaults_and_errors() {
+        // Missing sections and keys fall back to the defaults
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        let config = Config::parse("[storage]\nwal_dir = \"wal\"\n").unwrap();
+        assert_eq!((config.server, config.settlement), (ServerSettings::default(), SettlementSettings::default()));
+        assert_eq!(config.storage.snapshot_dir, PathBuf::from(DEFAULT_SNAPSHOT_DIR));
+
+        let errors = [
+            ("[server]\nport = \"high\"\n", "Invalid config file: "),
+            ("[server]\nprot = 80\n", "Invalid config file: unknown field `prot`"),
+            ("[server]\nbind_address = \"localhost:80\"\n", "Invalid server.bind_address: "),
+            ("[server]\nlog_level = \"loud\"\n", "Invalid server.log_level: "),
+            ("[server]\nlog_level = \"info,matching=loud\"\n", "Invalid server.log_level: "),
+            ("[server]\ncors_origins = [\"app.io\"]\n", "Invalid server.cors_origins: "),
+            ("[server]\nmax_order_quantity = 0\n", "Invalid server.max_order_quantity: "),
+            ("[server]\nmax_fills_per_order = 0\n", "Invalid server.max_fills_per_order: "),
+            ("[server]\nfill_limit_policy = \"rest\"\n", "Invalid config file: "),
+            ("[settlement]\noperator_key = \"0x01\"\n", "Invalid settlement.operator_key: "),
+            ("[auth]\napi_keys = [{ key = \"k\", trader = \"0x01\" }]\n", "Invalid auth.api_keys: "),
+            ("[auth]\nadmin_keys = [\"k\"]\napi_keys = [{ key = \"k\", trader = \"0x0101010101010101010101010101010101010101\" }]\n", "Invalid auth: "),
+        ];
+        for (text, expected) in errors {
+            let error = Config::parse(text).unwrap_err().to_string();
+            println!("{}", error);
+            assert!(error.starts_with(expected), "{}", error);
+        }
+        let env = |name: &str| (name == PORT_ENV).then(|| "70000".to_string());
+        assert_eq!(Config::default().with_env(env).unwrap_err().to_string(), "Invalid NUMENA_PORT: must be at most 65535");
+        // Trade history is only kept by builds that can
+        assert_eq!(Config::parse("[storage]\ntrade_db = \"trades.db\"\n").is_ok(), cfg!(feature = "sqlite"));
+        assert_eq!(Config::parse("[fix]\nport = 9878\n").is_ok(), cfg!(feature = "fix"));
+        assert!(Config::parse("[fix]\ncomp_id = \"NUM ENA\"\n").unwrap_err().to_string().starts_with("Invalid fix.comp_id: "));
+        assert_eq!(Config::default().fix.comp_id, DEFAULT_FIX_COMP_ID);
+        assert_eq!(Config::parse("[grpc]\nport = 9090\n").is_ok(), cfg!(feature = "grpc"));
+        assert!(Config::parse("[grpc]\nport = 8080\n").is_err());
+        assert_eq!(Config::parse("[bus]\nurl = \"nats://localhost:4222\"\n").is_ok(), cfg!(feature = "nats"));
+        assert!(Config::parse("[bus]\ntrades_topic = \"\"\n").unwrap_err().to_string().starts_with("Invalid bus: "));
+        assert!(Config::parse("[bus]\nbuffer = 0\n").is_err());
+        let config = Config::parse("[bus]\norders_topic = \"numena.orders.{book}\"\n").unwrap();
+        assert_eq!(config.bus.bus_config().orders_topic, "numena.orders.{book}");
+        assert_eq!(config.bus.bus_config().trades_topic, BusConfig::default().trades_topic);
+        assert_eq!(Config::parse("[accounting]\nutc_offset_minutes = -300\n").unwrap().accounting.utc_offset_minutes, -300);
+        assert!(Config::parse("[accounting]\nutc_offset_minutes = 1440\n").is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// eip1271.rs
+
+use crate::utils::CONTRACT_SIGNATURE_CACHE_CAPACITY;
+#[cfg(feature = "server")]
+use crate::utils::RPC_TIMEOUT;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+/// Selector of `isValidSignature(bytes32,bytes)`, which is also the value a wallet returns
+/// when it accepts the signature.
+pub const IS_VALID_SIGNATURE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// The node could not be reached or did not answer usefully; the verdict is unknown.
+    Unavailable(String),
+    /// The call reverted, which a wallet does for signatures it rejects.
+    Reverted(String),
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcError::Unavailable(message) => write!(f, "Ethereum RPC unavailable: {}", message),
+            RpcError::Reverted(message) => write!(f, "Call reverted: {}", message),
+        }
+    }
+}
+
+pub type RpcFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, RpcError>> + Send + 'a>>;
+
+/// Read-only access to an Ethereum node.
+pub trait EthRpc: Send + Sync {
+    /// Executes `eth_call` of `data` against `to` at the latest block and returns its output.
+    fn call(&self, to: [u8; 20], data: Vec<u8>) -> RpcFuture<'_>;
+}
+
+/// EthRpc over HTTP JSON-RPC.
+/// Needs the `server` feature, which brings in the HTTP client.
+#[cfg(feature = "server")]
+pub struct JsonRpcClient {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "server")]
+impl JsonRpcClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sends one JSON-RPC request and returns its result.
+    pub async fn request(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value, RpcError> {
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let unavailable = |error: reqwest::Error| RpcError::Unavailable(error.to_string());
+        let mut response: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .timeout(RPC_TIMEOUT)
+            .send()
+            .await
+            .map_err(unavailable)?
+            .error_for_status()
+            .map_err(unavailable)?
+            .json()
+            .await
+            .map_err(unavailable)?;
+
+        if let Some(error) = response.get("error") {
+            // Nodes report reverts with code 3 or an "execution reverted" message
+            let message = error["message"].as_str().unwrap_or_default().to_string();
+            return if error["code"] == 3 || message.contains("revert") {
+                Err(RpcError::Reverted(message))
+            } else {
+                Err(RpcError::Unavailable(error.to_string()))
+            };
+        }
+        match response.get_mut("result") {
+            Some(result) => Ok(result.take()),
+            None => Err(RpcError::Unavailable(format!("Malformed response: {}", response))),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl EthRpc for JsonRpcClient {
+    fn call(&self, to: [u8; 20], data: Vec<u8>) -> RpcFuture<'_> {
+        Box::pin(async move {
+            let params = serde_json::json!([
+                { "to": format!("0x{}", hex::encode(to)), "data": format!("0x{}", hex::encode(data)) },
+                "latest",
+            ]);
+            let result = self.request("eth_call", params).await?;
+            let result = result
+                .as_str()
+                .ok_or_else(|| RpcError::Unavailable(format!("Malformed result: {}", result)))?;
+            hex::decode(result.trim_start_matches("0x"))
+                .map_err(|_| RpcError::Unavailable(format!("Malformed result: {}", result)))
+        })
+    }
+}
+
+/// Encodes a call of `isValidSignature(hash, signature)`.
+pub fn encode_is_valid_signature(hash: &[u8; 32], signature: &[u8]) -> Vec<u8> {
+    let padded_len = signature.len().div_ceil(32) * 32;
+    let mut data = Vec::with_capacity(4 + 32 * 3 + padded_len);
+    data.extend_from_slice(&IS_VALID_SIGNATURE);
+    data.extend_from_slice(hash);
+    let mut word = [0u8; 32];
+    word[31] = 0x40; // Offset of the bytes argument
+    data.extend_from_slice(&word);
+    word[24..].copy_from_slice(&(signature.len() as u64).to_be_bytes());
+    data.extend_from_slice(&word);
+    data.extend_from_slice(signature);
+    data.resize(4 + 32 * 3 + padded_len, 0);
+    data
+}
+
+/// Recent verdicts, evicted oldest first.
+struct VerdictCache {
+    verdicts: HashMap<[u8; 32], (Vec<u8>, bool)>, // Keyed by order hash, with the signature checked.
+    order: VecDeque<[u8; 32]>,
+}
+
+/// Verifies signatures of contract wallets (Safe and the like) by asking the wallet itself.
+/// Verdicts are cached by order hash, so a resubmitted order does not cost another call.
+pub struct ContractSignatureVerifier {
+    rpc: Arc<dyn EthRpc>,
+    cache: Mutex<VerdictCache>,
+}
+
+impl ContractSignatureVerifier {
+    pub fn new(rpc: Arc<dyn EthRpc>) -> Self {
+        Self {
+            rpc,
+            cache: Mutex::new(VerdictCache {
+                verdicts: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Asks `wallet` whether `signature` is valid for `hash`.
+    /// Returns Err only when the node can't give a verdict; a revert counts as a rejection.
+    pub async fn is_valid_signature(
+        &self,
+        wallet: [u8; 20],
+        hash: [u8; 32],
+        signature: &[u8],
+    ) -> Result<bool, RpcError> {
+        if let Some((cached_signature, verdict)) = self.cache.lock().unwrap().verdicts.get(&hash) {
+            if cached_signature == signature {
+                return Ok(*verdict);
+            }
+        }
+
+        let verdict = match self.rpc.call(wallet, encode_is_valid_signature(&hash, signature)).await {
+            Ok(output) => output.len() >= 32 && output[..4] == IS_VALID_SIGNATURE,
+            Err(RpcError::Reverted(_)) => false,
+            Err(error) => return Err(error),
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.verdicts.insert(hash, (signature.to_vec(), verdict)).is_none() {
+            cache.order.push_back(hash);
+        }
+        while cache.order.len() > CONTRACT_SIGNATURE_CACHE_CAPACITY {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.verdicts.remove(&oldest);
+            }
+        }
+        Ok(verdict)
+    }
+}
+
+/// What a MockRpc wallet answers
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockVerdict {
+    Valid,
+    Invalid,
+    Reverted,
+    Unavailable,
+}
+
+/// EthRpc stand-in whose wallets all give the same verdict, counting the calls made
+#[cfg(test)]
+pub struct MockRpc {
+    pub verdict: MockVerdict,
+    pub calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl MockRpc {
+    pub fn new(verdict: MockVerdict) -> Self {
+        Self {
+            verdict,
+            calls: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl EthRpc for MockRpc {
+    fn call(&self, _to: [u8; 20], data: Vec<u8>) -> RpcFuture<'_> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(data[..4], IS_VALID_SIGNATURE);
+        let mut output = vec![0u8; 32];
+        let result = match self.verdict {
+            MockVerdict::Valid => {
+                output[..4].copy_from_slice(&IS_VALID_SIGNATURE);
+                Ok(output)
+            }
+            MockVerdict::Invalid => Ok(output),
+            MockVerdict::Reverted => Err(RpcError::Reverted("execution reverted".to_string())),
+            MockVerdict::Unavailable => Err(RpcError::Unavailable("connection refused".to_string())),
+        };
+        Box::pin(async move { result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_encode_is_valid_signature() {
+        let data = encode_is_valid_signature(&[0xaa; 32], &[0xbb; 65]);
+        assert_eq!(hex::encode(&data[..4]), "1626ba7e");
+        assert_eq!(data.len(), 4 + 32 * 3 + 96);
+        assert_eq!(data[4 + 63], 0x40);
+        assert_eq!(data[4 + 95], 65);
+        assert_eq!(data[4 + 96..4 + 96 + 65], [0xbb; 65]);
+        assert!(data[4 + 96 + 65..].iter().all(|&byte| byte == 0));
+    }
+
+    #[tokio::test]
+    async fn test_verdicts() {
+        for (verdict, expected) in [
+            (MockVerdict::Valid, Ok(true)),
+            (MockVerdict::Invalid, Ok(false)),
+            (MockVerdict::Reverted, Ok(false)),
+            (MockVerdict::Unavailable, Err(RpcError::Unavailable("connection refused".to_string()))),
+        ] {
+            let verifier = ContractSignatureVerifier::new(Arc::new(MockRpc::new(verdict)));
+            let result = verifier.is_valid_signature([1; 20], [2; 32], &[3; 65]).await;
+            println!("{:?}: {:?}", verdict, result);
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verdicts_are_cached() {
+        let rpc = Arc::new(MockRpc::new(MockVerdict::Valid));
+        let verifier = ContractSignatureVerifier::new(rpc.clone());
+        for _ in 0..3 {
+            assert_eq!(verifier.is_valid_signature([1; 20], [2; 32], &[3; 65]).await, Ok(true));
+        }
+        assert_eq!(rpc.calls.load(Ordering::SeqCst), 1);
+
+        // Another signature for the same order is asked about again
+        assert_eq!(verifier.is_valid_signature([1; 20], [2; 32], &[4; 65]).await, Ok(true));
+        assert_eq!(rpc.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "server")]
+    #[actix_web::test]
+    async fn test_json_rpc_client() {
+        use actix_web::{web, App, HttpResponse, HttpServer};
+
+        // A node whose wallet accepts everything
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/",
+                web::post().to(|request: web::Json<serde_json::Value>| async move {
+                    assert_eq!(request["method"], "eth_call");
+                    assert_eq!(request["params"][0]["to"], format!("0x{}", "01".repeat(20)));
+                    HttpResponse::Ok().json(serde_json::json!({
+                        "jsonrpc": "2.0",
+                        "id": 1,
+                        "result": format!("0x1626ba7e{}", "0".repeat(56)),
+                    }))
+                }),
+            )
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let verifier = ContractSignatureVerifier::new(Arc::new(JsonRpcClient::new(format!("http://{}/", addr))));
+        assert_eq!(verifier.is_valid_signature([1; 20], [2; 32], &[3; 65]).await, Ok(true));
+
+        // Nothing listens on port 9, so the verdict is unknown rather than negative
+        let verifier = ContractSignatureVerifier::new(Arc::new(JsonRpcClient::new("http://127.0.0.1:9/")));
+        let result = verifier.is_valid_signature([1; 20], [2; 32], &[3; 65]).await;
+        println!("Unreachable node: {:?}", result);
+        assert!(matches!(result, Err(RpcError::Unavailable(_))));
+    }
+}
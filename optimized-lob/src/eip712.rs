@@ -0,0 +1,170 @@
+// eip712.rs
+
+use crate::utils::hex_array;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+
+const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+
+/// The typed struct traders sign when submitting an order.
+/// The sign of `price` is the side, exactly as submitted to the API.
+const ORDER_TYPE: &str = "Order(string book,address trader,int256 price,uint256 quantity,uint256 nonce,uint256 expiry)";
+
+/// The EIP-712 domain orders are signed under.
+/// Each market can use its own, so a signature for one venue or chain is useless on another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eip712Domain {
+    pub name: String,
+    pub version: String,
+    pub chain_id: u64,
+    #[serde(with = "hex_array")]
+    pub verifying_contract: [u8; 20],
+}
+
+impl Default for Eip712Domain {
+    fn default() -> Self {
+        Self {
+            name: "Numena".to_string(),
+            version: "1".to_string(),
+            chain_id: 1,
+            verifying_contract: [0; 20],
+        }
+    }
+}
+
+/// The signed fields of an order submission.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eip712Order<'a> {
+    pub book: &'a str,
+    pub trader: [u8; 20],
+    pub price: i32,
+    pub quantity: u64,
+    pub nonce: u64,
+    pub expiry: u64, // 0 when the order does not expire.
+}
+
+impl Eip712Domain {
+    /// Computes the domain separator.
+    pub fn separator(&self) -> [u8; 32] {
+        let mut hasher = Keccak256::new();
+        hasher.update(Keccak256::digest(DOMAIN_TYPE));
+        hasher.update(Keccak256::digest(&self.name));
+        hasher.update(Keccak256::digest(&self.version));
+        hasher.update(uint(self.chain_id));
+        hasher.update(address(self.verifying_contract));
+        hasher.finalize().into()
+    }
+
+    /// Computes the digest a wallet signs for a typed struct under this domain.
+    pub fn typed_data_hash(&self, struct_hash: &[u8; 32]) -> [u8; 32] {
+        let mut hasher = Keccak256::new();
+        hasher.update([0x19, 0x01]);
+        hasher.update(self.separator());
+        hasher.update(struct_hash);
+        hasher.finalize().into()
+    }
+
+    /// Computes the digest a wallet signs for an order under this domain.
+    ///
+    /// ## Arguments:
+    /// - `order`: The signed fields of the submission.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::eip712::{Eip712Domain, Eip712Order};
+    /// let domain = Eip712Domain::default();
+    /// let order = Eip712Order { book: "ETH-USD", trader: [1; 20], price: -100, quantity: 5, nonce: 1, expiry: 0 };
+    /// assert_ne!(domain.hash_order(&order), domain.hash_order(&Eip712Order { price: 100, ..order }));
+    /// ```
+    pub fn hash_order(&self, order: &Eip712Order) -> [u8; 32] {
+        self.typed_data_hash(&order.struct_hash())
+    }
+}
+
+impl Eip712Order<'_> {
+    /// Computes the EIP-712 struct hash of the order.
+    pub fn struct_hash(&self) -> [u8; 32] {
+        let mut hasher = Keccak256::new();
+        hasher.update(Keccak256::digest(ORDER_TYPE));
+        hasher.update(Keccak256::digest(self.book));
+        hasher.update(address(self.trader));
+        hasher.update(int(self.price));
+        hasher.update(uint(self.quantity));
+        hasher.update(uint(self.nonce));
+        hasher.update(uint(self.expiry));
+        hasher.finalize().into()
+    }
+}
+
+/// Encodes an unsigned integer as a big-endian 32-byte word.
+fn uint(value: u64) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+/// Encodes a signed integer as a sign-extended two's complement 32-byte word.
+fn int(value: i32) -> [u8; 32] {
+    let mut word = if value < 0 { [0xff; 32] } else { [0; 32] };
+    word[28..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+/// Encodes an address as a left-padded 32-byte word.
+fn address(value: [u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(&value);
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::recover_prehash;
+
+    fn bytes32(hex_str: &str) -> [u8; 32] {
+        hex::decode(hex_str).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_spec_example() {
+        // The "Ether Mail" example from the EIP-712 specification
+        let domain = Eip712Domain {
+            name: "Ether Mail".to_string(),
+            version: "1".to_string(),
+            chain_id: 1,
+            verifying_contract: [0xcc; 20],
+        };
+        assert_eq!(
+            hex::encode(domain.separator()),
+            "f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f"
+        );
+
+        let mail_hash = bytes32("c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e");
+        let digest = domain.typed_data_hash(&mail_hash);
+        assert_eq!(
+            hex::encode(digest),
+            "be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2"
+        );
+
+        // Signed by keccak256("cow") in the specification
+        let mut signature = hex::decode(concat!(
+            "4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d",
+            "07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562",
+        ))
+        .unwrap();
+        signature.push(28);
+        assert_eq!(
+            hex::encode(recover_prehash(&digest, &signature).unwrap()),
+            "cd2a3d9f938e13cd947ec05abc7fe734df8dd826"
+        );
+    }
+
+    #[test]
+    fn test_int_encoding() {
+        assert_eq!(int(1)[31], 1);
+        assert_eq!(int(-1), [0xff; 32]);
+        assert_eq!(&int(-256)[30..], &[0xff, 0x00]);
+        assert_eq!(uint(u64::MAX)[..24], [0; 24]);
+    }
+}
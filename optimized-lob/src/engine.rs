@@ -0,0 +1,380 @@
+// engine.rs
+
+use crate::{
+    book_registry::{BookRegistry, BookRegistryError},
+    config::Config,
+    level::LevelId,
+    market::{MarketConfig, MarketError, MarketManager},
+    matching::{MatchDetails, MatchingEngine},
+    order::{Order, OrderId, Signature},
+    order_updates::OrderStatus,
+    orderbook_manager::{Depth, OrderBookError},
+    quantity::Qty,
+    snapshot::EngineSnapshot,
+    stats::BookStats,
+    utils::BookId,
+    wal::{Wal, WalCommand, WalConfig, WalError},
+};
+use std::fmt;
+use std::io;
+
+/// Why an Engine operation failed
+#[derive(Debug)]
+pub enum EngineError {
+    Registry(BookRegistryError),
+    Book(OrderBookError),
+    Market(MarketError),
+    Wal(WalError), // The command couldn't be logged, so it was not applied
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Registry(error) => write!(f, "{}", error),
+            EngineError::Book(error) => write!(f, "{}", error),
+            EngineError::Market(error) => write!(f, "{}", error),
+            EngineError::Wal(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<BookRegistryError> for EngineError {
+    fn from(error: BookRegistryError) -> Self {
+        EngineError::Registry(error)
+    }
+}
+
+impl From<OrderBookError> for EngineError {
+    fn from(error: OrderBookError) -> Self {
+        EngineError::Book(error)
+    }
+}
+
+impl From<MarketError> for EngineError {
+    fn from(error: MarketError) -> Self {
+        EngineError::Market(error)
+    }
+}
+
+impl From<WalError> for EngineError {
+    fn from(error: WalError) -> Self {
+        EngineError::Wal(error)
+    }
+}
+
+/// What became of a submitted order
+#[derive(Debug)]
+pub struct Submitted {
+    pub order_id: OrderId,
+    pub remaining: Qty,           // Left resting on the book
+    pub fills: Vec<MatchDetails>, // The order's own fills, in the order they happened
+}
+
+/// The matching engine together with the names and markets of its books, for embedding it
+/// without the server
+/// Books are addressed by name, as they are over the API, and take orders once created with their
+/// market, or explicitly without one by create_book_only. Every change is logged to the engine's
+/// WAL, when it has one, before it is applied, so an engine recovered from the same storage ends
+/// up the same.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::{engine::Engine, market::MarketConfig, quantity::Qty};
+/// let mut engine = Engine::new();
+/// engine.create_market("ETH-USD", MarketConfig::default()).unwrap();
+///
+/// engine.submit("ETH-USD", Qty(10), 1000, false).unwrap();
+/// let buy = engine.submit("ETH-USD", Qty(4), 1000, true).unwrap();
+/// assert_eq!(buy.fills.len(), 1);
+///
+/// let depth = engine.depth("ETH-USD", 5).unwrap();
+/// assert_eq!(depth.asks, vec![(1000, 6, 1)]);
+/// ```
+pub struct Engine {
+    matching: MatchingEngine, // Holds the MarketManager of the books' markets
+    registry: BookRegistry,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// Creates an engine without books, logging nothing
+    pub fn new() -> Self {
+        Self::from_parts(MatchingEngine::new(), BookRegistry::new())
+    }
+
+    /// Wraps an engine and the registry naming its books
+    pub fn from_parts(matching: MatchingEngine, registry: BookRegistry) -> Self {
+        Self { matching, registry }
+    }
+
+    /// Rebuilds an engine from the storage `config` names, see `recover`
+    pub fn recover(config: &Config, restore_snapshot: bool) -> io::Result<Self> {
+        let registry = match &config.storage.registry_path {
+            Some(path) => BookRegistry::open(path)?,
+            None => BookRegistry::new(),
+        };
+        let matching = recover(config, restore_snapshot, &registry)?;
+        Ok(Self::from_parts(matching, registry))
+    }
+
+    /// Splits the engine back into its matching engine and registry
+    pub fn into_parts(self) -> (MatchingEngine, BookRegistry) {
+        (self.matching, self.registry)
+    }
+
+    pub fn matching(&self) -> &MatchingEngine {
+        &self.matching
+    }
+
+    /// Gets the matching engine for what the facade doesn't cover
+    /// Changes made through it are not logged.
+    pub fn matching_mut(&mut self) -> &mut MatchingEngine {
+        &mut self.matching
+    }
+
+    pub fn registry(&self) -> &BookRegistry {
+        &self.registry
+    }
+
+    pub fn markets(&self) -> &MarketManager {
+        &self.matching.market_manager
+    }
+
+    /// Creates a book without a market that matches anyway; its fills are not settled
+    /// Books that should settle are created with create_market instead.
+    pub fn create_book_only(&mut self, name: &str) -> Result<BookId, EngineError> {
+        provision_book(&mut self.matching, &self.registry, name, None, true)
+    }
+
+    /// Creates a book along with the market its fills settle in
+    pub fn create_market(&mut self, name: &str, market: MarketConfig) -> Result<BookId, EngineError> {
+        provision_book(&mut self.matching, &self.registry, name, Some(&market), false)
+    }
+
+    /// Submits an unsigned limit order at `price`, matching what it can and resting the rest
+    /// Orders that need a trader's signature go through OrderIntake first, as the API does.
+    pub fn submit(&mut self, book: &str, qty: Qty, price: u32, is_bid: bool) -> Result<Submitted, EngineError> {
+        let book_id = self.registry.get_book_id(book)?;
+        self.matching.check_accepting()?;
+        self.matching.open_book(book_id)?;
+        let order_id = self.matching.next_order_id();
+        let received_at = self.matching.clock.now();
+        self.matching.log(&WalCommand::Submit {
+            order_id: order_id.0,
+            book_id: book_id.value(),
+            qty: qty.value(),
+            price,
+            is_bid,
+            trader: None,
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+            display: None,
+            reduce_only: false,
+            received_at,
+        })?;
+        let order = Order::new(qty, LevelId(0), book_id, None, None, None, Signature::None).with_received_at(received_at);
+        let (remaining, fills) = self.matching.match_limit_order(order_id, order, price, is_bid)?;
+        Ok(Submitted { order_id, remaining, fills })
+    }
+
+    /// Cancels a working order: resting, parked, or a stop waiting for its trigger
+    pub fn cancel(&mut self, order_id: OrderId) -> Result<(), EngineError> {
+        if self.matching.order_status(order_id).is_none() {
+            return Err(OrderBookError::UnknownOrder.into());
+        }
+        if self.matching.stops().get(order_id).is_some() {
+            self.matching.log(&WalCommand::CancelStop { order_id: order_id.0 })?;
+            self.matching.cancel_stop(order_id)?;
+        } else {
+            self.matching.log(&WalCommand::Remove { order_id: order_id.0 })?;
+            self.matching.cancel_resting(order_id, OrderStatus::Cancelled)?;
+        }
+        Ok(())
+    }
+
+    /// Gets up to `levels` aggregated levels of each side of a book
+    pub fn depth(&self, book: &str, levels: usize) -> Result<Depth, EngineError> {
+        let book_id = self.registry.get_book_id(book)?;
+        let depth = self.matching.orderbook_manager.get_depth(book_id, levels);
+        depth.ok_or(EngineError::Book(OrderBookError::UnknownBook(book_id)))
+    }
+
+    /// Gets the trading statistics of a book, or None before its first trade
+    pub fn stats(&self, book: &str) -> Result<Option<&BookStats>, EngineError> {
+        let book_id = self.registry.get_book_id(book)?;
+        Ok(self.matching.stats().get(book_id))
+    }
+}
+
+/// Registers the book `name` and sets up its orderbook and market, then logs it
+/// A book without a market only takes orders if it is `book_only`; its fills are never settled.
+/// A step that fails takes back the ones before it: the book is logged only once all of it is in
+/// place, and is never left registered without its orderbook or the market it was created with.
+/// Run under whatever lock serializes changes to the engine, so the log sees books in BookId order.
+pub fn provision_book(
+    engine: &mut MatchingEngine,
+    registry: &BookRegistry,
+    name: &str,
+    market: Option<&MarketConfig>,
+    book_only: bool,
+) -> Result<BookId, EngineError> {
+    let book_id = registry.register_book(name.to_string())?;
+    let had_book = engine.orderbook_manager.book(book_id).is_some();
+    let undo = |engine: &mut MatchingEngine, market_added: bool| {
+        if market_added {
+            engine.market_manager.remove_market(book_id);
+        }
+        if !had_book {
+            engine.orderbook_manager.remove_book(book_id);
+        }
+        let _ = registry.unregister_last(book_id);
+    };
+    if let Err(error) = engine.orderbook_manager.create_book(book_id) {
+        undo(engine, false);
+        return Err(error.into());
+    }
+    if let Some(market) = market {
+        if let Err(error) = engine.market_manager.add_market(book_id, market.clone(), false) {
+            undo(engine, false);
+            return Err(error.into());
+        }
+    }
+    let command = WalCommand::RegisterBook {
+        name: name.to_string(),
+        book_id: book_id.value(),
+        market: market.cloned(),
+        book_only: Some(book_only),
+    };
+    if let Err(error) = engine.log(&command) {
+        undo(engine, market.is_some());
+        return Err(error.into());
+    }
+    engine.set_book_only(book_id, book_only);
+    Ok(book_id)
+}
+
+/// Rebuilds the engine from the newest snapshot, when asked to, and the WAL, registering its books
+/// The WAL stays attached to the engine, so it goes on logging where it left off. The engine
+/// caps fills per order as the server settings say, which the log was written under.
+pub fn recover(config: &Config, restore_snapshot: bool, book_registry: &BookRegistry) -> io::Result<MatchingEngine> {
+    let snapshot_dir = &config.storage.snapshot_dir;
+    let mut engine = MatchingEngine::new();
+    let mut first_segment = 0;
+
+    // Start from the newest snapshot; only the WAL written after it needs replaying
+    if restore_snapshot {
+        match EngineSnapshot::load_latest(snapshot_dir)? {
+            Some(snapshot) => {
+                for (name, book_id) in &snapshot.registry {
+                    let _ = book_registry.restore_book(name.clone(), BookId(*book_id));
+                }
+                first_segment = snapshot.wal_segment.unwrap_or(0);
+                tracing::info!(orders = snapshot.order_count(), taken_at = snapshot.taken_at, "Restored snapshot");
+                engine = MatchingEngine::restore(snapshot).map_err(io::Error::other)?;
+            }
+            None => tracing::warn!(dir = %snapshot_dir.display(), "No snapshot found"),
+        }
+    }
+
+    engine.set_fill_limit(config.server.max_fills_per_order, config.server.fill_limit_policy);
+
+    // Rebuild the books from the write-ahead log before accepting traffic
+    if let Some(dir) = &config.storage.wal_dir {
+        let to_io = |error: WalError| io::Error::other(error.to_string());
+        let commands = Wal::read_from(dir, first_segment).map_err(to_io)?;
+        for command in &commands {
+            if let Some((name, book_id)) = command.registered_book() {
+                let _ = book_registry.restore_book(name.to_string(), book_id);
+            }
+            if let Some(book_id) = command.closed_book() {
+                let _ = book_registry.unregister(book_id);
+            }
+            engine.apply(command);
+        }
+        tracing::info!(commands = commands.len(), dir = %dir.display(), "Replayed the WAL");
+        engine.wal = Some(Wal::open(dir, WalConfig::default()).map_err(to_io)?);
+    }
+
+    // A server that went down halted comes back halted
+    if engine.is_halted() {
+        tracing::warn!("Kill switch engaged; only cancels are accepted until it is released");
+    }
+    Ok(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageSettings;
+
+    #[test]
+    fn test_engine_facade() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            storage: StorageSettings {
+                wal_dir: Some(dir.path().join("wal")),
+                snapshot_dir: dir.path().join("snapshots"),
+                registry_path: Some(dir.path().join("books.json")),
+                trade_db: None,
+            },
+            ..Config::default()
+        };
+
+        let mut engine = Engine::recover(&config, false).unwrap();
+        assert_eq!(engine.create_book_only("BTC-USD").unwrap(), BookId(0));
+        assert_eq!(engine.create_market("ETH-USD", MarketConfig::builder().chain_id(8453).build()).unwrap(), BookId(1));
+        assert!(matches!(engine.create_book_only("ETH-USD"), Err(EngineError::Registry(BookRegistryError::BookAlreadyExists))));
+        assert_eq!(engine.markets().get_config(BookId(1)).map(|market| market.chain_id), Some(8453));
+
+        let ask = engine.submit("BTC-USD", Qty(10), 1000, false).unwrap();
+        engine.submit("BTC-USD", Qty(5), 990, true).unwrap();
+        let buy = engine.submit("BTC-USD", Qty(4), 1000, true).unwrap();
+        assert_eq!((buy.remaining, buy.fills.len()), (Qty(0), 1));
+        assert_eq!(buy.fills[0].exec_qty, Qty(4));
+        engine.cancel(ask.order_id).unwrap();
+        assert!(matches!(engine.cancel(ask.order_id), Err(EngineError::Book(OrderBookError::UnknownOrder))));
+        assert!(matches!(engine.submit("DOGE-USD", Qty(1), 1, true), Err(EngineError::Registry(BookRegistryError::BookNotFound))));
+
+        let depth = engine.depth("BTC-USD", 5).unwrap();
+        assert_eq!((depth.bids, depth.asks), (vec![(990, 5, 1)], vec![]));
+        let stats = engine.stats("BTC-USD").unwrap().unwrap();
+        assert_eq!((stats.last_price(), stats.trade_count()), (Some(1000), 1));
+        assert!(engine.stats("ETH-USD").unwrap().is_none());
+
+        // The same storage recovers the same books and orders
+        let (matching, _) = engine.into_parts();
+        drop(matching);
+        let mut recovered = Engine::recover(&config, false).unwrap();
+        assert_eq!(recovered.registry().get_book_id("ETH-USD").unwrap(), BookId(1));
+        assert_eq!(recovered.markets().get_config(BookId(1)).map(|market| market.chain_id), Some(8453));
+        let depth = recovered.depth("BTC-USD", 5).unwrap();
+        assert_eq!((depth.bids, depth.asks), (vec![(990, 5, 1)], vec![]));
+        assert!(recovered.matching().is_book_only(BookId(0)));
+        let sell = recovered.submit("BTC-USD", Qty(5), 990, false).unwrap();
+        assert_eq!(sell.fills.len(), 1);
+    }
+
+    #[test]
+    fn test_book_without_market_takes_no_orders() {
+        let (mut matching, registry) = Engine::new().into_parts();
+        provision_book(&mut matching, &registry, "BTC-USD", None, false).unwrap();
+        let mut engine = Engine::from_parts(matching, registry);
+
+        let refused = engine.submit("BTC-USD", Qty(1), 1000, true);
+        assert!(matches!(refused, Err(EngineError::Book(OrderBookError::NoMarket(BookId(0))))));
+        assert_eq!(engine.depth("BTC-USD", 1).unwrap().bids, vec![]);
+
+        // Until it is let match book-only
+        engine.matching_mut().set_book_only(BookId(0), true);
+        engine.submit("BTC-USD", Qty(1), 1000, true).unwrap();
+        assert_eq!(engine.depth("BTC-USD", 1).unwrap().bids, vec![(1000, 1, 1)]);
+    }
+}
@@ -0,0 +1,244 @@
+// events.rs
+
+use crate::{order::{OrderId, Signature}, quantity::Qty, trade_tape::{PriceImprovement, Trade}, utils::BookId};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Why an order left the book without trading, carried by `OrderCancelled`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    Requested,  // By its owner, an admin, or the engine, e.g. for an OCO sibling
+    BookClosed, // Its book was closed; see MatchingEngine::close_book
+}
+
+/// Every state change made by the OrderBookManager and MatchingEngine, in the order it happened.
+/// `seq` increases by one per event across all books, so consumers can detect gaps.
+/// `book_seq` is the sequence number of the event's book once the event has been applied;
+/// events that don't change the book's orders, like `Trade`, repeat the number of the change
+/// before them.
+///
+/// While matching, each fill emits the maker's `OrderExecuted` first and then the `Trade`
+/// it produced; an incoming order that still has quantity left afterwards emits `OrderAdded`
+/// last. A replace emits `OrderReplaced` for the old order and then behaves like a new order.
+///
+/// Quantities count the whole of an iceberg order, shown or not. When its displayed slice is
+/// used up, `OrderReplenished` follows the `OrderExecuted` that did it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderBookEvent {
+    OrderAdded {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        book_id: BookId,
+        price: u32,
+        is_bid: bool,
+        qty: Qty,
+        trader: Option<[u8; 20]>,
+        nonce: Option<u64>,
+        expiry: Option<u64>,
+        signature: Signature,
+        display: Option<Qty>, // Display quantity of an iceberg order
+    },
+    OrderExecuted {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        book_id: BookId,
+        exec_qty: Qty,
+        remaining_qty: Qty,
+    },
+    OrderCancelled {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        book_id: BookId,
+        cancelled_qty: Qty,
+        remaining_qty: Qty,
+        reason: CancelReason,
+    },
+    OrderReplaced {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        new_order_id: OrderId,
+        book_id: BookId,
+        new_price: u32,
+        new_qty: Qty,
+    },
+    OrderExpired {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        book_id: BookId,
+        qty: Qty,
+    },
+    /// An iceberg showed its next slice of `qty` and went to the back of its level's queue.
+    /// It shares the book_seq of the OrderExecuted that used up the slice before.
+    OrderReplenished {
+        seq: u64,
+        book_seq: u64,
+        order_id: OrderId,
+        book_id: BookId,
+        qty: Qty,
+    },
+    Trade {
+        seq: u64,
+        book_seq: u64,
+        book_id: BookId,
+        trade: Trade,
+        maker_trader: Option<[u8; 20]>,
+        taker_trader: Option<[u8; 20]>,
+        improvement: Option<PriceImprovement>, // For a fill of a limit order
+    },
+    /// Critical: the book's best bid reached its best ask outside an auction without trading.
+    /// Matching never leaves a book this way, so something else broke; the engine halts the
+    /// book until MatchingEngine::repair_crossed uncrosses it. Shares the book_seq of the change
+    /// that crossed it.
+    BookCrossed {
+        seq: u64,
+        book_seq: u64,
+        book_id: BookId,
+        best_bid: u32,
+        best_ask: u32,
+    },
+}
+
+impl OrderBookEvent {
+    /// Gets the sequence number of the event.
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        match self {
+            OrderBookEvent::OrderAdded { seq, .. }
+            | OrderBookEvent::OrderExecuted { seq, .. }
+            | OrderBookEvent::OrderCancelled { seq, .. }
+            | OrderBookEvent::OrderReplaced { seq, .. }
+            | OrderBookEvent::OrderExpired { seq, .. }
+            | OrderBookEvent::OrderReplenished { seq, .. }
+            | OrderBookEvent::Trade { seq, .. }
+            | OrderBookEvent::BookCrossed { seq, .. } => *seq,
+        }
+    }
+
+    /// Gets the sequence number of the event's book after the event.
+    #[inline]
+    pub fn book_seq(&self) -> u64 {
+        match self {
+            OrderBookEvent::OrderAdded { book_seq, .. }
+            | OrderBookEvent::OrderExecuted { book_seq, .. }
+            | OrderBookEvent::OrderCancelled { book_seq, .. }
+            | OrderBookEvent::OrderReplaced { book_seq, .. }
+            | OrderBookEvent::OrderExpired { book_seq, .. }
+            | OrderBookEvent::OrderReplenished { book_seq, .. }
+            | OrderBookEvent::Trade { book_seq, .. }
+            | OrderBookEvent::BookCrossed { book_seq, .. } => *book_seq,
+        }
+    }
+
+    /// Gets the book the event belongs to.
+    #[inline]
+    pub fn book_id(&self) -> BookId {
+        match self {
+            OrderBookEvent::OrderAdded { book_id, .. }
+            | OrderBookEvent::OrderExecuted { book_id, .. }
+            | OrderBookEvent::OrderCancelled { book_id, .. }
+            | OrderBookEvent::OrderReplaced { book_id, .. }
+            | OrderBookEvent::OrderExpired { book_id, .. }
+            | OrderBookEvent::OrderReplenished { book_id, .. }
+            | OrderBookEvent::Trade { book_id, .. }
+            | OrderBookEvent::BookCrossed { book_id, .. } => *book_id,
+        }
+    }
+}
+
+/// Receives every OrderBookEvent synchronously, on the matching thread.
+/// Implementations must not block; hand work off to another thread or task instead.
+pub trait EventSink: Send {
+    fn on_event(&mut self, event: &OrderBookEvent);
+
+    /// Returns once every event received so far is delivered; sinks that deliver as they
+    /// receive have nothing to do.
+    fn flush(&mut self) {}
+}
+
+/// Discards every event. This is the default sink.
+#[derive(Debug, Default)]
+pub struct NoopSink;
+
+impl EventSink for NoopSink {
+    #[inline]
+    fn on_event(&mut self, _event: &OrderBookEvent) {}
+}
+
+/// Collects events in memory. Clones share the same buffer, so a handle can be kept
+/// after the sink has been given to the manager.
+#[derive(Debug, Clone, Default)]
+pub struct VecSink {
+    events: Arc<Mutex<Vec<OrderBookEvent>>>,
+}
+
+impl VecSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of every event collected so far.
+    pub fn events(&self) -> Vec<OrderBookEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Removes and returns every event collected so far.
+    pub fn take(&self) -> Vec<OrderBookEvent> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+impl EventSink for VecSink {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        self.events.lock().unwrap().push(*event);
+    }
+}
+
+/// Forwards events to an async consumer over an unbounded channel.
+/// Events are dropped once the receiver is gone.
+pub struct ChannelSink {
+    sender: mpsc::UnboundedSender<OrderBookEvent>,
+}
+
+impl ChannelSink {
+    /// Creates a sink and the receiver its events arrive on.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<OrderBookEvent>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        let _ = self.sender.send(*event);
+    }
+}
+
+/// Hands every event to each of several sinks, in turn.
+pub struct FanoutSink {
+    sinks: Vec<Box<dyn EventSink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Box<dyn EventSink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl EventSink for FanoutSink {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        for sink in &mut self.sinks {
+            sink.on_event(event);
+        }
+    }
+
+    fn flush(&mut self) {
+        for sink in &mut self.sinks {
+            sink.flush();
+        }
+    }
+}
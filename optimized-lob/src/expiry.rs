@@ -0,0 +1,79 @@
+// expiry.rs
+
+use crate::order::OrderId;
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// Good-til-time orders keyed by expiry, soonest first
+/// Expiries are in seconds since the Unix epoch, like the ones orders are signed with. Nothing is
+/// taken out when an order stops working early; whoever pops an entry checks that the order is
+/// still there with that expiry and skips it otherwise.
+#[derive(Debug, Default)]
+pub struct ExpirySchedule {
+    heap: BinaryHeap<Reverse<(u64, OrderId)>>,
+}
+
+impl ExpirySchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Schedules an order to expire at `expiry`
+    /// None, zero, and u64::MAX all mean the order never expires, and are not scheduled.
+    pub fn schedule(&mut self, order_id: OrderId, expiry: Option<u64>) {
+        match expiry {
+            Some(expiry) if expiry != 0 && expiry != u64::MAX => self.heap.push(Reverse((expiry, order_id))),
+            _ => {}
+        }
+    }
+
+    /// Gets the soonest expiry scheduled
+    #[inline]
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.heap.peek().map(|Reverse((expiry, _))| *expiry)
+    }
+
+    /// Takes the soonest entry if it is due at `now`, i.e. its expiry is at or before it
+    /// Entries due at the same time come out in order ID order.
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, OrderId)> {
+        if self.next_expiry()? > now {
+            return None;
+        }
+        self.heap.pop().map(|Reverse(entry)| entry)
+    }
+
+    /// Keeps only the entries matching `filter`, e.g. to drop orders that are gone
+    pub fn retain(&mut self, mut filter: impl FnMut(OrderId, u64) -> bool) {
+        self.heap.retain(|Reverse((expiry, order_id))| filter(*order_id, *expiry));
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_schedule() {
+        let mut schedule = ExpirySchedule::new();
+        for (order_id, expiry) in [(1, Some(30)), (2, Some(10)), (3, None), (4, Some(u64::MAX)), (5, Some(10)), (6, Some(0))] {
+            schedule.schedule(OrderId(order_id), expiry);
+        }
+        assert_eq!((schedule.len(), schedule.next_expiry()), (3, Some(10)));
+        assert_eq!(schedule.pop_due(9), None);
+        assert_eq!(schedule.pop_due(10), Some((10, OrderId(2))));
+        assert_eq!(schedule.pop_due(10), Some((10, OrderId(5))));
+        assert_eq!(schedule.pop_due(29), None);
+
+        schedule.retain(|order_id, _| order_id != OrderId(1));
+        assert!(schedule.is_empty() && schedule.pop_due(u64::MAX).is_none());
+    }
+}
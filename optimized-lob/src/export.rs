@@ -0,0 +1,343 @@
+// export.rs
+
+use crate::{
+    market::MarketConfig,
+    price::PriceScale,
+    settlement_manager::{SettlementStatus, TrackedSettlement},
+    trade_tape::Trade,
+    utils::UtcTime,
+};
+use std::io::{self, Write};
+
+/// Size a CsvWriter lets its buffer grow to before writing it out, so an export holds about
+/// one chunk in memory however many rows it has
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Columns of a trade export, in order
+/// - trade_id, book: the trade and the name of its book
+/// - timestamp: when it executed, RFC3339 in UTC with nanoseconds
+/// - price: in decimal, with the market's decimal places
+/// - quantity: whole security tokens, as the book counts them
+/// - side: buy or sell, the side of the taker
+/// - maker_order_id, taker_order_id: the orders that traded
+/// - maker, taker: 0x-hex addresses of their traders, empty where not known
+pub const TRADE_COLUMNS: [&str; 10] =
+    ["trade_id", "book", "timestamp", "price", "quantity", "side", "maker_order_id", "taker_order_id", "maker", "taker"];
+
+/// Columns of a settlement export, in order
+/// - settlement_id, trade_id, book: the settlement, the trade it settles, and its book's name
+/// - created_at, updated_at: RFC3339 in UTC with nanoseconds
+/// - status: pending, submitted, confirmed or failed
+/// - tx_hash: 0x-hex transaction once submitted, batch_id: the batch it went in, if any
+/// - failure: why it failed, if it did
+/// - maker, taker: 0x-hex addresses of the traders
+/// - price, quantity: as in the trade export
+/// - maker_token, maker_amount, taker_token, taker_amount: what each side gives, amounts in
+///   decimal with their token's decimals
+/// - fee_amount: base token the buyer pays on top; maker_fee: taker token withheld from the
+///   maker; taker_fee: maker token withheld from the taker
+pub const SETTLEMENT_COLUMNS: [&str; 20] = [
+    "settlement_id",
+    "trade_id",
+    "book",
+    "created_at",
+    "updated_at",
+    "status",
+    "tx_hash",
+    "batch_id",
+    "failure",
+    "maker",
+    "taker",
+    "price",
+    "quantity",
+    "maker_token",
+    "maker_amount",
+    "taker_token",
+    "taker_amount",
+    "fee_amount",
+    "maker_fee",
+    "taker_fee",
+];
+
+/// Writes CSV rows to `out` in chunks of about CHUNK_BYTES
+/// Rows end in CRLF, and fields holding a comma, quote or line break are quoted, as RFC 4180 has it.
+pub struct CsvWriter<W: Write> {
+    out: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Starts a CSV with a header row of `columns`
+    pub fn new(out: W, columns: &[&str]) -> io::Result<Self> {
+        let mut writer = Self { out, buffer: Vec::with_capacity(CHUNK_BYTES) };
+        writer.write_row(columns)?;
+        Ok(writer)
+    }
+
+    /// Adds a row, writing the buffer out once it reaches CHUNK_BYTES
+    pub fn write_row<I>(&mut self, fields: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        for (index, field) in fields.into_iter().enumerate() {
+            if index > 0 {
+                self.buffer.push(b',');
+            }
+            let field = field.as_ref();
+            if field.contains([',', '"', '\r', '\n']) {
+                self.buffer.push(b'"');
+                self.buffer.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+                self.buffer.push(b'"');
+            } else {
+                self.buffer.extend_from_slice(field.as_bytes());
+            }
+        }
+        self.buffer.extend_from_slice(b"\r\n");
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.out.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes out what is left in the buffer, and gives back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buffer.is_empty() {
+            self.out.write_all(&self.buffer)?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Gets the row of a trade in `book`, in TRADE_COLUMNS order
+pub fn trade_row(book: &str, trade: &Trade, traders: (Option<[u8; 20]>, Option<[u8; 20]>), scale: PriceScale) -> [String; 10] {
+    let (maker, taker) = traders;
+    [
+        trade.trade_id.to_string(),
+        book.to_string(),
+        rfc3339(trade.timestamp),
+        scale.format(trade.price),
+        trade.qty.value().to_string(),
+        if trade.aggressor_is_bid { "buy" } else { "sell" }.to_string(),
+        trade.maker_order_id.0.to_string(),
+        trade.taker_order_id.0.to_string(),
+        maker.map(|maker| hex_address(&maker)).unwrap_or_default(),
+        taker.map(|taker| hex_address(&taker)).unwrap_or_default(),
+    ]
+}
+
+/// Gets the row of a settlement in `book`, in SETTLEMENT_COLUMNS order, with amounts in the
+/// decimals of `market`'s tokens
+pub fn settlement_row(book: &str, settlement: &TrackedSettlement, market: &MarketConfig) -> [String; 20] {
+    let order = &settlement.order;
+    let token_decimals = |token: &[u8; 20]| match *token == market.base_token {
+        true => market.base_decimals,
+        false => market.security_decimals,
+    };
+    let (tx_hash, failure) = match &settlement.status {
+        SettlementStatus::Pending => (String::new(), String::new()),
+        SettlementStatus::Submitted { tx_hash } | SettlementStatus::Confirmed { tx_hash } => (hex_address(tx_hash), String::new()),
+        SettlementStatus::Failed { reason } => (String::new(), reason.clone()),
+    };
+    [
+        settlement.settlement_id.to_string(),
+        settlement.trade_id.to_string(),
+        book.to_string(),
+        rfc3339(settlement.created_at),
+        rfc3339(settlement.updated_at),
+        settlement.status.name().to_string(),
+        tx_hash,
+        settlement.batch_id.map(|batch_id| batch_id.to_string()).unwrap_or_default(),
+        failure,
+        hex_address(&order.maker),
+        hex_address(&order.taker),
+        market.price_scale().format(settlement.exec_price),
+        settlement.exec_qty.to_string(),
+        hex_address(&order.maker_token),
+        decimal(order.maker_amount, token_decimals(&order.maker_token)),
+        hex_address(&order.taker_token),
+        decimal(order.taker_amount, token_decimals(&order.taker_token)),
+        decimal(order.fee_amount, market.base_decimals),
+        decimal(order.maker_fee, token_decimals(&order.taker_token)),
+        decimal(order.taker_fee, token_decimals(&order.maker_token)),
+    ]
+}
+
+/// Writes bytes such as an address or hash as 0x-prefixed lowercase hex
+pub fn hex_address(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+/// Writes a token amount in its smallest units as a decimal with exactly `decimals` places
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::export::decimal;
+/// assert_eq!(decimal(1_500_000, 6), "1.500000");
+/// assert_eq!(decimal(5, 3), "0.005");
+/// assert_eq!(decimal(42, 0), "42");
+/// ```
+pub fn decimal(amount: u128, decimals: u8) -> String {
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let digits = format!("{:0>width$}", amount, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    format!("{}.{}", whole, fraction)
+}
+
+/// Writes nanoseconds since the Unix epoch as an RFC3339 time in UTC, to the nanosecond
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::export::rfc3339;
+/// assert_eq!(rfc3339(0), "1970-01-01T00:00:00.000000000Z");
+/// assert_eq!(rfc3339(1_700_000_000_250_000_000), "2023-11-14T22:13:20.250000000Z");
+/// ```
+pub fn rfc3339(timestamp: u64) -> String {
+    let time = UtcTime::from_nanos(timestamp);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        time.year, time.month, time.day, time.hour, time.minute, time.second, time.nanos
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{order::OrderId, quantity::Qty, translator::{SettlementOrder, SettlementSignature}};
+
+    const GOLDEN: &str = include_str!("../fixtures/export_trades.csv");
+
+    fn trade(trade_id: u64) -> Trade {
+        Trade {
+            trade_id,
+            timestamp: 1_700_000_000_000_000_000 + trade_id * 1_500_000_000,
+            price: 123_450 + trade_id as u32 * 5,
+            qty: Qty(trade_id * 10),
+            aggressor_is_bid: trade_id % 2 == 1,
+            maker_order_id: OrderId(trade_id * 100),
+            taker_order_id: OrderId(trade_id * 100 + 1),
+        }
+    }
+
+    /// Counts what is written to it, and the largest single write
+    #[derive(Default)]
+    struct CountingWriter {
+        total: usize,
+        largest: usize,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.total += buf.len();
+            self.largest = self.largest.max(buf.len());
+            self.writes += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trade_export_matches_golden_file() {
+        let scale = PriceScale::new(2, 5);
+        let mut csv = CsvWriter::new(Vec::new(), &TRADE_COLUMNS).unwrap();
+        csv.write_row(trade_row("ETH-USDC", &trade(1), (Some([0xab; 20]), Some([0x01; 20])), scale)).unwrap();
+        csv.write_row(trade_row("ETH-USDC", &trade(2), (Some([0xcd; 20]), None), scale)).unwrap();
+        csv.write_row(trade_row("ETH-USDC", &trade(3), (None, None), scale)).unwrap();
+        let csv = String::from_utf8(csv.finish().unwrap()).unwrap();
+
+        // Rows end in CRLF whatever line endings the fixture was checked out with
+        assert_eq!(csv.matches("\r\n").count(), 4);
+        assert!(csv.ends_with("\r\n"));
+        assert_eq!(csv.lines().collect::<Vec<_>>(), GOLDEN.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_large_export_is_written_in_bounded_chunks() {
+        let scale = PriceScale::new(2, 1);
+        let mut csv = CsvWriter::new(CountingWriter::default(), &TRADE_COLUMNS).unwrap();
+        for trade_id in 1..=100_000 {
+            csv.write_row(trade_row("ETH-USDC", &trade(trade_id), (Some([0xab; 20]), Some([0xcd; 20])), scale)).unwrap();
+            assert!(csv.buffer.len() < CHUNK_BYTES, "rows are held back past a chunk");
+        }
+        let out = csv.finish().unwrap();
+
+        // Tens of megabytes went through, none of it in a write much past one chunk
+        assert!(out.total > 15 * 1024 * 1024, "wrote {} bytes", out.total);
+        assert!(out.largest < CHUNK_BYTES + 1024, "largest write was {} bytes", out.largest);
+        assert!(out.writes > out.total / (CHUNK_BYTES + 1024));
+    }
+
+    #[test]
+    fn test_fields_are_quoted() {
+        let mut csv = CsvWriter::new(Vec::new(), &["a", "b"]).unwrap();
+        csv.write_row(["plain", "has,comma"]).unwrap();
+        csv.write_row(["say \"hi\"", "two\nlines"]).unwrap();
+        let csv = String::from_utf8(csv.finish().unwrap()).unwrap();
+        assert_eq!(csv, "a,b\r\nplain,\"has,comma\"\r\n\"say \"\"hi\"\"\",\"two\nlines\"\r\n");
+    }
+
+    #[test]
+    fn test_settlement_row_uses_token_decimals() {
+        let signature = SettlementSignature { signature_type: 2, v: 27, r: [1; 32], s: [2; 32] };
+        let market = MarketConfig { base_token: [1; 20], security_token: [2; 20], base_decimals: 6, security_decimals: 18, price_decimals: 2, ..MarketConfig::default() };
+        let settlement = TrackedSettlement {
+            settlement_id: 7,
+            trade_id: 3,
+            book_id: 1,
+            maker_order_id: 10,
+            taker_order_id: 11,
+            exec_qty: 2,
+            exec_price: 150_025,
+            order: SettlementOrder {
+                maker_token: [2; 20],
+                taker_token: [1; 20],
+                maker_amount: 2_000_000_000_000_000_000,
+                taker_amount: 3_000_500_000,
+                fee_amount: 1_500,
+                maker_fee: 300,
+                taker_fee: 0,
+                maker: [0xab; 20],
+                taker: [0xcd; 20],
+                fee_recipient: [0; 20],
+                pool: [0; 20],
+                maker_expiration: u64::MAX,
+                maker_salt: 1,
+                taker_expiration: u64::MAX,
+                taker_salt: 2,
+                maker_is_buyer: false,
+                maker_signature: signature.clone(),
+                taker_signature: signature,
+                chain_id: 1,
+                domain_separator: [5; 32],
+            },
+            status: SettlementStatus::Failed { reason: "reverted, out of gas".to_string() },
+            batch_id: Some(4),
+            created_at: 1_700_000_000_000_000_000,
+            updated_at: 1_700_000_001_000_000_000,
+        };
+
+        let row = settlement_row("ETH-USDC", &settlement, &market);
+        assert_eq!(row.len(), SETTLEMENT_COLUMNS.len());
+        assert_eq!(&row[3..9], ["2023-11-14T22:13:20.000000000Z", "2023-11-14T22:13:21.000000000Z", "failed", "", "4", "reverted, out of gas"]);
+        assert_eq!(row[11], "1500.25");
+        assert_eq!(row[14], "2.000000000000000000"); // Security token, 18 decimals
+        assert_eq!(row[16], "3000.500000"); // Base token, 6 decimals
+        assert_eq!(&row[17..], ["0.001500", "0.000300", "0.000000000000000000"]);
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(951_782_400_000_000_001), "2000-02-29T00:00:00.000000001Z");
+        assert_eq!(rfc3339(1_709_251_199_999_999_999), "2024-02-29T23:59:59.999999999Z");
+        assert_eq!(rfc3339(4_102_444_800_000_000_000), "2100-01-01T00:00:00.000000000Z");
+    }
+}
@@ -0,0 +1,327 @@
+// fix.rs
+
+use crate::utils::UtcTime;
+use std::fmt;
+
+/// Field delimiter of FIX tag=value messages
+pub const SOH: u8 = 0x01;
+pub const BEGIN_STRING: &str = "FIX.4.4";
+/// Longest message accepted; a peer sending more without completing one is cut off
+pub const MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// Tags the gateway reads and writes
+pub mod tag {
+    pub const ACCOUNT: u32 = 1;
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECKSUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TRANSACT_TIME: u32 = 60;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const ORD_REJ_REASON: u32 = 103;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const REF_TAG_ID: u32 = 371;
+    pub const REF_MSG_TYPE: u32 = 372;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+    pub const PASSWORD: u32 = 554;
+    /// User-defined fields carrying what the trader signed besides the order itself
+    pub const NONCE: u32 = 20001;
+    pub const EXPIRY: u32 = 20002; // Seconds since the Unix epoch
+    pub const SIGNATURE: u32 = 20003; // 0x-prefixed hex
+}
+
+/// Message types the gateway handles
+pub mod msg_type {
+    pub const HEARTBEAT: &str = "0";
+    pub const TEST_REQUEST: &str = "1";
+    pub const RESEND_REQUEST: &str = "2";
+    pub const REJECT: &str = "3";
+    pub const SEQUENCE_RESET: &str = "4";
+    pub const LOGOUT: &str = "5";
+    pub const EXECUTION_REPORT: &str = "8";
+    pub const ORDER_CANCEL_REJECT: &str = "9";
+    pub const LOGON: &str = "A";
+    pub const NEW_ORDER_SINGLE: &str = "D";
+    pub const ORDER_CANCEL_REQUEST: &str = "F";
+    pub const ORDER_CANCEL_REPLACE_REQUEST: &str = "G";
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixError {
+    Garbled(String), // Framing, body length, or checksum that doesn't add up
+    TooLong,
+}
+
+impl fmt::Display for FixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixError::Garbled(reason) => write!(f, "Garbled FIX message: {}", reason),
+            FixError::TooLong => write!(f, "FIX message longer than {} bytes", MAX_MESSAGE_LEN),
+        }
+    }
+}
+
+impl std::error::Error for FixError {}
+
+/// A FIX message as its fields in order, from MsgType on
+/// BeginString, BodyLength and CheckSum are left out; encode adds them and take_message checks them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FixMessage {
+    fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &str) -> Self {
+        Self { fields: vec![(tag::MSG_TYPE, msg_type.to_string())] }
+    }
+
+    /// Appends a field
+    pub fn with(mut self, tag: u32, value: impl ToString) -> Self {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    /// Sets the first value of `tag`, appending the field if it has none
+    pub fn set(&mut self, tag: u32, value: impl ToString) {
+        match self.fields.iter_mut().find(|(field, _)| *field == tag) {
+            Some((_, current)) => *current = value.to_string(),
+            None => self.fields.push((tag, value.to_string())),
+        }
+    }
+
+    /// Gets the first value of `tag`
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields.iter().find(|(field, _)| *field == tag).map(|(_, value)| value.as_str())
+    }
+
+    pub fn fields(&self) -> &[(u32, String)] {
+        &self.fields
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.get(tag::MSG_TYPE).unwrap_or_default()
+    }
+
+    pub fn seq_num(&self) -> Option<u64> {
+        self.get(tag::MSG_SEQ_NUM)?.parse().ok()
+    }
+
+    /// Returns true if the message is flagged as a possible resend
+    pub fn is_poss_dup(&self) -> bool {
+        self.get(tag::POSS_DUP_FLAG) == Some("Y")
+    }
+
+    /// Puts the standard header in front of the body fields, replacing any header fields already set
+    pub fn with_header(self, sender: &str, target: &str, seq_num: u64, sending_time: u64) -> Self {
+        let header = [tag::MSG_TYPE, tag::SENDER_COMP_ID, tag::TARGET_COMP_ID, tag::MSG_SEQ_NUM, tag::SENDING_TIME];
+        let mut message = Self::new(self.msg_type())
+            .with(tag::SENDER_COMP_ID, sender)
+            .with(tag::TARGET_COMP_ID, target)
+            .with(tag::MSG_SEQ_NUM, seq_num)
+            .with(tag::SENDING_TIME, utc_timestamp(sending_time));
+        message.fields.extend(self.fields.into_iter().filter(|(field, _)| !header.contains(field)));
+        message
+    }
+
+    /// Frames the message with its BeginString, BodyLength and CheckSum
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (field, value) in &self.fields {
+            body.extend_from_slice(format!("{}={}", field, value).as_bytes());
+            body.push(SOH);
+        }
+        let mut message = format!("8={}\x019={}\x01", BEGIN_STRING, body.len()).into_bytes();
+        message.extend_from_slice(&body);
+        let checksum = checksum(&message);
+        message.extend_from_slice(format!("10={:03}\x01", checksum).as_bytes());
+        message
+    }
+
+    /// Parses the fields of a message in tag=value form; `|` may stand in for SOH
+    /// Meant for fixtures and logs; messages off the wire go through take_message.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::fix::{msg_type, tag, FixMessage};
+    /// let message = FixMessage::parse("35=0|112=ping|").unwrap();
+    /// assert_eq!((message.msg_type(), message.get(tag::TEST_REQ_ID)), (msg_type::HEARTBEAT, Some("ping")));
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, FixError> {
+        let fields = text
+            .split(['|', SOH as char])
+            .filter(|field| !field.is_empty())
+            .map(parse_field)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { fields })
+    }
+}
+
+fn parse_field(field: &str) -> Result<(u32, String), FixError> {
+    let (tag, value) = field.split_once('=').ok_or_else(|| FixError::Garbled(format!("field {:?} has no tag", field)))?;
+    let tag = tag.parse().map_err(|_| FixError::Garbled(format!("tag {:?} is not a number", tag)))?;
+    Ok((tag, value.to_string()))
+}
+
+/// Sums the bytes modulo 256, as the CheckSum field does
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Takes the first whole message off the front of `buffer`, or None if it hasn't all arrived
+/// The header must be BeginString then BodyLength, and the body must be followed by a CheckSum
+/// that matches; anything else is Garbled, as the stream can't be read past it.
+pub fn take_message(buffer: &mut Vec<u8>) -> Result<Option<FixMessage>, FixError> {
+    let prefix = format!("8={}\x019=", BEGIN_STRING);
+    let compared = buffer.len().min(prefix.len());
+    if buffer[..compared] != prefix.as_bytes()[..compared] {
+        return Err(FixError::Garbled("expected BeginString then BodyLength".to_string()));
+    }
+    let Some(length_end) = buffer.iter().skip(prefix.len()).position(|byte| *byte == SOH) else {
+        return match buffer.len() > prefix.len() + 8 {
+            true => Err(FixError::Garbled("BodyLength is not a number".to_string())),
+            false => Ok(None),
+        };
+    };
+    let length_end = prefix.len() + length_end;
+    let body_length: usize = std::str::from_utf8(&buffer[prefix.len()..length_end])
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .ok_or_else(|| FixError::Garbled("BodyLength is not a number".to_string()))?;
+    // Checked before adding, so a huge BodyLength can't overflow the total
+    let body_start = length_end + 1;
+    if body_length > MAX_MESSAGE_LEN {
+        return Err(FixError::TooLong);
+    }
+    let total = body_start + body_length + "10=000\x01".len();
+    if total > MAX_MESSAGE_LEN {
+        return Err(FixError::TooLong);
+    }
+    if buffer.len() < total {
+        return Ok(None);
+    }
+    let body_end = body_start + body_length;
+    let trailer = &buffer[body_end..total];
+    if !trailer.starts_with(b"10=") || trailer[trailer.len() - 1] != SOH {
+        return Err(FixError::Garbled("BodyLength does not end at the CheckSum".to_string()));
+    }
+    let expected = format!("{:03}", checksum(&buffer[..body_end]));
+    if &trailer[3..6] != expected.as_bytes() {
+        return Err(FixError::Garbled(format!("CheckSum should be {}", expected)));
+    }
+    let body = std::str::from_utf8(&buffer[body_start..body_end])
+        .map_err(|_| FixError::Garbled("body is not UTF-8".to_string()))?;
+    let message = FixMessage::parse(body)?;
+    if message.fields.first().map(|(field, _)| *field) != Some(tag::MSG_TYPE) {
+        return Err(FixError::Garbled("MsgType is not the first body field".to_string()));
+    }
+    buffer.drain(..total);
+    Ok(Some(message))
+}
+
+/// Writes nanoseconds since the Unix epoch as a FIX UTCTimestamp, to the millisecond
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::fix::utc_timestamp;
+/// assert_eq!(utc_timestamp(1_700_000_000_250_000_000), "20231114-22:13:20.250");
+/// ```
+pub fn utc_timestamp(timestamp: u64) -> String {
+    let time = UtcTime::from_nanos(timestamp);
+    format!(
+        "{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}",
+        time.year,
+        time.month,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+        time.nanos / 1_000_000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_take_message() {
+        let message = FixMessage::new(msg_type::HEARTBEAT)
+            .with(tag::SENDER_COMP_ID, "NUMENA")
+            .with(tag::TARGET_COMP_ID, "CLIENT")
+            .with(tag::MSG_SEQ_NUM, 2);
+        let encoded = message.encode();
+        // The body runs from MsgType to the last field before the CheckSum
+        let text = String::from_utf8(encoded.clone()).unwrap().replace('\x01', "|");
+        assert_eq!(text, "8=FIX.4.4|9=30|35=0|49=NUMENA|56=CLIENT|34=2|10=127|");
+
+        // Two messages back to back, the second arriving in pieces
+        let mut buffer = encoded.clone();
+        buffer.extend_from_slice(&encoded[..10]);
+        assert_eq!(take_message(&mut buffer).unwrap(), Some(message.clone()));
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(take_message(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&encoded[10..]);
+        let taken = take_message(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!((taken.msg_type(), taken.seq_num()), (msg_type::HEARTBEAT, Some(2)));
+        assert_eq!(take_message(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_garbled_messages() {
+        let encoded = FixMessage::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, "ping").encode();
+        let mut corrupted = encoded.clone();
+        let last = corrupted.len() - 2;
+        corrupted[last] = b'0' + (corrupted[last] - b'0' + 1) % 10;
+        assert!(matches!(take_message(&mut corrupted), Err(FixError::Garbled(reason)) if reason.starts_with("CheckSum")));
+
+        let mut wrong_version = String::from_utf8(encoded).unwrap().replace("FIX.4.4", "FIX.4.2").into_bytes();
+        assert!(take_message(&mut wrong_version).is_err());
+        let mut too_long = b"8=FIX.4.4\x019=99999\x01".to_vec();
+        assert_eq!(take_message(&mut too_long), Err(FixError::TooLong));
+        let mut overflowing = b"8=FIX.4.4\x019=18446744073709551615\x01".to_vec();
+        assert_eq!(take_message(&mut overflowing), Err(FixError::TooLong));
+        assert!(FixMessage::parse("35=D|price=10|").is_err());
+    }
+
+    #[test]
+    fn test_with_header() {
+        let message = FixMessage::new(msg_type::HEARTBEAT)
+            .with(tag::MSG_SEQ_NUM, 9)
+            .with(tag::TEST_REQ_ID, "ping")
+            .with_header("NUMENA", "CLIENT", 3, 1_700_000_000_000_000_000);
+        let text: Vec<String> = message.fields().iter().map(|(tag, value)| format!("{}={}", tag, value)).collect();
+        assert_eq!(text, ["35=0", "49=NUMENA", "56=CLIENT", "34=3", "52=20231114-22:13:20.000", "112=ping"]);
+    }
+}
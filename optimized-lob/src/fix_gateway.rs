@@ -0,0 +1,915 @@
+// fix_gateway.rs
+
+use crate::{
+    api::{cancel_owned_order, place_order, replace_owned_order, AppState, OrderRequest, OrderType},
+    api_auth::{Authenticator, Identity},
+    api_error::ApiError,
+    fix::{msg_type, tag, take_message, FixMessage},
+    order::OrderId,
+    order_intake::Side,
+    order_updates::{OrderStatus, OrderUpdate},
+    price::{ApiPrice, PriceScale},
+    utils::Clock,
+};
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+
+/// How long a new connection has to log on before it is closed
+const LOGON_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a session checks whether a heartbeat is due and its peer is still there
+const HEARTBEAT_TICK: Duration = Duration::from_secs(1);
+
+/// Order entry over FIX 4.4, next to the HTTP API and sharing its state
+/// Orders go through the same intake as REST submissions: a NewOrderSingle must carry the
+/// trader's EIP-712 signature in the user-defined NONCE, EXPIRY and SIGNATURE tags, and its
+/// ClOrdID becomes the order's client order ID. Peers log on with an API key as the Password;
+/// without an authenticator every logon is let through, as the API lets every request through.
+pub struct FixGateway {
+    state: web::Data<AppState>,
+    authenticator: Option<web::Data<Authenticator>>,
+    comp_id: String, // SenderCompID of the gateway, the TargetCompID peers must log on to
+    sessions: FixSessions,
+}
+
+impl FixGateway {
+    pub fn new(state: web::Data<AppState>, comp_id: impl Into<String>, sessions: FixSessions) -> Self {
+        Self { state, authenticator: None, comp_id: comp_id.into(), sessions }
+    }
+
+    /// Checks the Password of each Logon as an API key, acting as the trader or admin it belongs to
+    pub fn with_authenticator(mut self, authenticator: web::Data<Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+}
+
+/// Accepts FIX connections on `listener` until the server shuts down
+/// Sessions still connected then are logged out, and each is saved before this returns.
+pub async fn serve(listener: TcpListener, gateway: Arc<FixGateway>) {
+    let mut shutdown = gateway.state.shutdown.subscribe();
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    tracing::debug!(%peer, "FIX connection accepted");
+                    connections.spawn(gateway.clone().run(stream));
+                }
+                Err(error) => tracing::warn!(%error, "Failed to accept a FIX connection"),
+            },
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = shutdown.wait_for(|down| *down) => break,
+        }
+    }
+    while connections.join_next().await.is_some() {}
+}
+
+/// What a session keeps between connections
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionState {
+    pub next_incoming: u64, // MsgSeqNum expected of the peer next
+    pub next_outgoing: u64, // MsgSeqNum of the next message sent to the peer
+    orders: BTreeMap<u64, FixOrder>, // Working orders entered over the session, by OrderId
+}
+
+impl Default for SessionState {
+    fn default() -> Self {
+        Self { next_incoming: 1, next_outgoing: 1, orders: BTreeMap::new() }
+    }
+}
+
+/// An order entered over a session, as its reports describe it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FixOrder {
+    cl_ord_id: String,
+    replaces: Option<String>, // ClOrdID of the order this one replaced, set until it is acknowledged
+    cancel: Option<String>,   // ClOrdID of the cancel request that was accepted for it
+    symbol: String,
+    side: String,
+    price: String,
+    order_qty: u64, // Including what filled before a replace
+    cum_qty: u64,
+    cum_notional: u64, // Book units
+    decimals: u8,      // Of its market's prices
+    acked: bool,
+    executions: u64, // Reports sent for the order, numbering their ExecIDs
+}
+
+impl FixOrder {
+    /// Starts an ExecutionReport for the order, numbering it with the next ExecID
+    fn report(&mut self, order_id: u64, exec_type: &str, ord_status: &str, leaves_qty: u64) -> FixMessage {
+        self.executions += 1;
+        FixMessage::new(msg_type::EXECUTION_REPORT)
+            .with(tag::ORDER_ID, order_id)
+            .with(tag::CL_ORD_ID, &self.cl_ord_id)
+            .with(tag::EXEC_ID, format!("{}-{}", order_id, self.executions))
+            .with(tag::EXEC_TYPE, exec_type)
+            .with(tag::ORD_STATUS, ord_status)
+            .with(tag::SYMBOL, &self.symbol)
+            .with(tag::SIDE, &self.side)
+            .with(tag::ORDER_QTY, self.order_qty)
+            .with(tag::PRICE, &self.price)
+            .with(tag::LEAVES_QTY, leaves_qty)
+            .with(tag::CUM_QTY, self.cum_qty)
+            .with(tag::AVG_PX, average_price(self.cum_notional, self.cum_qty, self.decimals))
+            .with(tag::TRANSACT_TIME, crate::fix::utc_timestamp(Clock::System.now()))
+    }
+
+    /// OrdStatus of the order while it works
+    fn working_status(&self) -> &'static str {
+        if self.cum_qty > 0 {
+            "1"
+        } else {
+            "0"
+        }
+    }
+}
+
+/// Writes the average of `notional` over `qty` as a price in `decimals` places
+fn average_price(notional: u64, qty: u64, decimals: u8) -> String {
+    let scale = PriceScale::new(decimals, 1);
+    match qty {
+        0 => "0".to_string(),
+        _ if notional.is_multiple_of(qty) => scale.format(u32::try_from(notional / qty).unwrap_or(u32::MAX)),
+        _ => scale.to_f64(notional as f64 / qty as f64).to_string(),
+    }
+}
+
+/// The sessions of every peer, by its SenderCompID
+/// A session is checked out while its peer is connected, so a second logon as the same peer is
+/// refused, and checked back in when the connection ends. With a file, sessions are written to
+/// it as they are checked in, so sequence numbers carry on across restarts too.
+pub struct FixSessions {
+    sessions: Mutex<Sessions>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    states: HashMap<String, SessionState>,
+    connected: HashSet<String>,
+}
+
+impl Default for FixSessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FixSessions {
+    /// Sessions kept in memory only
+    pub fn new() -> Self {
+        Self { sessions: Mutex::new(Sessions::default()), path: None }
+    }
+
+    /// Sessions kept in the file at `path`, read back from it if it exists
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut sessions = Sessions::default();
+        if path.exists() {
+            sessions.states = serde_json::from_slice(&fs::read(path)?)?;
+        }
+        Ok(Self { sessions: Mutex::new(sessions), path: Some(path.to_path_buf()) })
+    }
+
+    /// Gets a copy of the state `peer` last left its session in
+    pub fn get(&self, peer: &str) -> Option<SessionState> {
+        self.sessions.lock().unwrap().states.get(peer).cloned()
+    }
+
+    /// Takes the session of `peer` for a connection, or None if another connection has it
+    fn check_out(&self, peer: &str) -> Option<SessionState> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if !sessions.connected.insert(peer.to_string()) {
+            return None;
+        }
+        Some(sessions.states.get(peer).cloned().unwrap_or_default())
+    }
+
+    /// Hands the session of `peer` back once its connection ends, writing every session to the file
+    /// The file is written under a temporary name and renamed once synced, so a crash leaves
+    /// either the old sessions or the new ones.
+    fn check_in(&self, peer: &str, state: SessionState) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.connected.remove(peer);
+        sessions.states.insert(peer.to_string(), state);
+        let Some(path) = &self.path else {
+            return;
+        };
+        let write = || -> io::Result<()> {
+            let tmp = path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec(&sessions.states)?)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        if let Err(error) = write() {
+            tracing::error!(%error, path = %path.display(), "Failed to save FIX sessions");
+        }
+    }
+}
+
+impl FixGateway {
+    /// Runs one connection from its Logon to its end
+    async fn run(self: Arc<Self>, mut stream: TcpStream) {
+        let mut buffer = Vec::new();
+        let logon = match tokio::time::timeout(LOGON_TIMEOUT, read_message(&mut stream, &mut buffer)).await {
+            Ok(Ok(Some(logon))) => logon,
+            Ok(Err(error)) => {
+                tracing::warn!(%error, "FIX connection closed before logon");
+                return;
+            }
+            Ok(Ok(None)) | Err(_) => return,
+        };
+        let peer = logon.get(tag::SENDER_COMP_ID).unwrap_or_default().to_string();
+        let (identity, heartbeat) = match self.logon(&logon) {
+            Ok(logged_on) => logged_on,
+            Err(text) => return self.refuse(&mut stream, &peer, &text).await,
+        };
+        let Some(mut session) = self.sessions.check_out(&peer) else {
+            return self.refuse(&mut stream, &peer, "Session is already logged on").await;
+        };
+        let reset = logon.get(tag::RESET_SEQ_NUM_FLAG) == Some("Y");
+        if reset {
+            session.next_incoming = 1;
+            session.next_outgoing = 1;
+        }
+        let updates = self.state.lock_engine().await.orderbook_manager.order_updates.subscribe();
+        let mut connection = Connection {
+            gateway: &self,
+            stream,
+            buffer,
+            peer: peer.clone(),
+            identity,
+            session,
+            heartbeat,
+            updates,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            test_request_pending: false,
+            resend_requested: false,
+        };
+        tracing::info!(%peer, "FIX session logged on");
+        if let Err(error) = connection.serve(logon, reset).await {
+            tracing::warn!(%peer, %error, "FIX session ended");
+        }
+        let Connection { session, stream, .. } = connection;
+        self.sessions.check_in(&peer, session);
+        drop(stream);
+        tracing::info!(%peer, "FIX session logged out");
+    }
+
+    /// Answers a Logon that is refused with a Logout, outside any session
+    async fn refuse(&self, stream: &mut TcpStream, peer: &str, text: &str) {
+        tracing::warn!(%peer, reason = %text, "FIX logon refused");
+        let logout = FixMessage::new(msg_type::LOGOUT).with(tag::TEXT, text);
+        let _ = stream.write_all(&logout.with_header(&self.comp_id, peer, 1, Clock::System.now()).encode()).await;
+    }
+
+    /// Checks a Logon, returning who the peer acts as and how often it wants heartbeats
+    fn logon(&self, logon: &FixMessage) -> Result<(Identity, Option<Duration>), String> {
+        if logon.msg_type() != msg_type::LOGON {
+            return Err("The first message must be a Logon".to_string());
+        }
+        if logon.get(tag::SENDER_COMP_ID).is_none_or(str::is_empty) {
+            return Err("SenderCompID is required".to_string());
+        }
+        if logon.get(tag::TARGET_COMP_ID) != Some(self.comp_id.as_str()) {
+            return Err(format!("TargetCompID must be {}", self.comp_id));
+        }
+        let heartbeat: u64 = logon
+            .get(tag::HEART_BT_INT)
+            .and_then(|interval| interval.parse().ok())
+            .ok_or_else(|| "HeartBtInt is required".to_string())?;
+        let identity = match &self.authenticator {
+            Some(authenticator) => {
+                let key = logon.get(tag::PASSWORD).ok_or_else(|| "Password is required".to_string())?;
+                authenticator.authenticate_key(key).map_err(|error| error.to_string())?
+            }
+            None => Identity::Unchecked,
+        };
+        Ok((identity, (heartbeat > 0).then(|| Duration::from_secs(heartbeat))))
+    }
+}
+
+/// Reads until a whole message is buffered, or None if the peer closed the connection first
+async fn read_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<Option<FixMessage>> {
+    let mut chunk = [0; 4096];
+    loop {
+        if let Some(message) = take_message(buffer).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))? {
+            return Ok(Some(message));
+        }
+        match stream.read(&mut chunk).await? {
+            0 => return Ok(None),
+            read => buffer.extend_from_slice(&chunk[..read]),
+        }
+    }
+}
+
+/// A logged on session and the connection it runs over
+struct Connection<'a> {
+    gateway: &'a FixGateway,
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    peer: String, // SenderCompID of the peer
+    identity: Identity,
+    session: SessionState,
+    heartbeat: Option<Duration>, // None when the peer asked for no heartbeats
+    updates: broadcast::Receiver<OrderUpdate>,
+    last_received: Instant,
+    last_sent: Instant,
+    test_request_pending: bool,
+    resend_requested: bool, // Set once a gap is asked for, until the message it was waiting for arrives
+}
+
+impl Connection<'_> {
+    /// Answers the Logon and runs the session until either side logs out or the connection drops
+    /// Order updates are taken ahead of the peer's messages, so a request always sees its orders
+    /// as the engine last reported them.
+    async fn serve(&mut self, logon: FixMessage, reset: bool) -> io::Result<()> {
+        let mut reply = FixMessage::new(msg_type::LOGON)
+            .with(tag::ENCRYPT_METHOD, 0)
+            .with(tag::HEART_BT_INT, self.heartbeat.map_or(0, |interval| interval.as_secs()));
+        if reset {
+            reply = reply.with(tag::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        self.send(reply).await?;
+        if !self.check_sequence(&logon).await? {
+            return Ok(());
+        }
+
+        let mut shutdown = self.gateway.state.shutdown.subscribe();
+        let mut ticks = tokio::time::interval(HEARTBEAT_TICK);
+        let mut chunk = [0; 4096];
+        loop {
+            tokio::select! {
+                biased;
+                update = self.updates.recv() => match update {
+                    Ok(update) => self.report(update).await?,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(peer = %self.peer, missed, "FIX session lagged behind order updates");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                // The guard wait_for returns isn't Send, so it is dropped before any handler awaits
+                _ = async { shutdown.wait_for(|down| *down).await.is_ok() } => break,
+                read = self.stream.read(&mut chunk) => {
+                    match read? {
+                        0 => return Ok(()),
+                        read => self.buffer.extend_from_slice(&chunk[..read]),
+                    }
+                    while let Some(message) = take_message(&mut self.buffer)
+                        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+                    {
+                        if !self.handle(message).await? {
+                            return Ok(());
+                        }
+                    }
+                }
+                _ = ticks.tick() => {
+                    if !self.check_heartbeat().await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        self.logout("Server shutting down").await
+    }
+
+    /// Sends a message under the next outgoing MsgSeqNum
+    async fn send(&mut self, message: FixMessage) -> io::Result<()> {
+        let seq_num = self.session.next_outgoing;
+        self.session.next_outgoing += 1;
+        self.write(message, seq_num).await
+    }
+
+    async fn write(&mut self, message: FixMessage, seq_num: u64) -> io::Result<()> {
+        let message = message.with_header(&self.gateway.comp_id, &self.peer, seq_num, Clock::System.now());
+        self.stream.write_all(&message.encode()).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    async fn logout(&mut self, text: impl ToString) -> io::Result<()> {
+        self.send(FixMessage::new(msg_type::LOGOUT).with(tag::TEXT, text)).await
+    }
+
+    /// Rejects a message the session can't take, naming the field at fault if there is one
+    async fn reject(&mut self, message: &FixMessage, field: Option<u32>, reason: u32, text: &str) -> io::Result<()> {
+        let mut reject = FixMessage::new(msg_type::REJECT)
+            .with(tag::REF_SEQ_NUM, message.seq_num().unwrap_or_default())
+            .with(tag::REF_MSG_TYPE, message.msg_type());
+        if let Some(field) = field {
+            reject = reject.with(tag::REF_TAG_ID, field);
+        }
+        self.send(reject.with(tag::SESSION_REJECT_REASON, reason).with(tag::TEXT, text)).await
+    }
+
+    /// Checks the MsgSeqNum of a message, returning whether the session goes on
+    /// The message is to be processed only if it was the one expected, counted in next_incoming.
+    /// Past a gap, its resend is asked for once and the message is left for the resend; a
+    /// number already used ends the session unless the message is flagged as a possible resend.
+    async fn check_sequence(&mut self, message: &FixMessage) -> io::Result<bool> {
+        let expected = self.session.next_incoming;
+        let Some(seq_num) = message.seq_num() else {
+            self.logout("MsgSeqNum is required").await?;
+            return Ok(false);
+        };
+        if seq_num < expected && !message.is_poss_dup() {
+            self.logout(format!("MsgSeqNum too low, expecting {} but received {}", expected, seq_num)).await?;
+            return Ok(false);
+        }
+        if seq_num > expected && !self.resend_requested {
+            let request = FixMessage::new(msg_type::RESEND_REQUEST).with(tag::BEGIN_SEQ_NO, expected).with(tag::END_SEQ_NO, 0);
+            self.send(request).await?;
+            self.resend_requested = true;
+        }
+        if seq_num == expected {
+            self.session.next_incoming += 1;
+            self.resend_requested = false;
+        }
+        Ok(true)
+    }
+
+    /// Handles a message from the peer, returning whether the session goes on
+    async fn handle(&mut self, message: FixMessage) -> io::Result<bool> {
+        self.last_received = Instant::now();
+        self.test_request_pending = false;
+        // A SequenceReset moves the expected number on whatever its own number is
+        if message.msg_type() == msg_type::SEQUENCE_RESET {
+            let Some(new_seq_no) = message.get(tag::NEW_SEQ_NO).and_then(|seq_num| seq_num.parse::<u64>().ok()) else {
+                self.reject(&message, Some(tag::NEW_SEQ_NO), 1, "NewSeqNo is required").await?;
+                return Ok(true);
+            };
+            if new_seq_no > self.session.next_incoming {
+                self.session.next_incoming = new_seq_no;
+                self.resend_requested = false;
+            }
+            return Ok(true);
+        }
+        let expected = self.session.next_incoming;
+        if !self.check_sequence(&message).await? {
+            return Ok(false);
+        }
+        if message.seq_num() != Some(expected) {
+            return Ok(true);
+        }
+        match message.msg_type() {
+            msg_type::HEARTBEAT => {}
+            msg_type::TEST_REQUEST => {
+                let mut heartbeat = FixMessage::new(msg_type::HEARTBEAT);
+                if let Some(test_req_id) = message.get(tag::TEST_REQ_ID) {
+                    heartbeat = heartbeat.with(tag::TEST_REQ_ID, test_req_id);
+                }
+                self.send(heartbeat).await?;
+            }
+            // Messages sent are not kept, so a resend is a gap fill up to the next one; the
+            // orders they reported on can be read back over the API
+            msg_type::RESEND_REQUEST => {
+                let begin = message.get(tag::BEGIN_SEQ_NO).and_then(|seq_num| seq_num.parse::<u64>().ok());
+                match begin {
+                    Some(begin) if begin < self.session.next_outgoing => {
+                        let gap_fill = FixMessage::new(msg_type::SEQUENCE_RESET)
+                            .with(tag::POSS_DUP_FLAG, "Y")
+                            .with(tag::GAP_FILL_FLAG, "Y")
+                            .with(tag::NEW_SEQ_NO, self.session.next_outgoing);
+                        self.write(gap_fill, begin.max(1)).await?;
+                    }
+                    Some(_) => {}
+                    None => self.reject(&message, Some(tag::BEGIN_SEQ_NO), 1, "BeginSeqNo is required").await?,
+                }
+            }
+            msg_type::LOGOUT => {
+                self.send(FixMessage::new(msg_type::LOGOUT)).await?;
+                return Ok(false);
+            }
+            msg_type::LOGON => self.reject(&message, None, 11, "Session is already logged on").await?,
+            msg_type::NEW_ORDER_SINGLE => self.new_order(&message).await?,
+            msg_type::ORDER_CANCEL_REQUEST => self.cancel_order(&message).await?,
+            msg_type::ORDER_CANCEL_REPLACE_REQUEST => self.replace_order(&message).await?,
+            _ => self.reject(&message, None, 11, "Unsupported MsgType").await?,
+        }
+        Ok(true)
+    }
+
+    /// Sends a heartbeat when one is due, and tests or drops a peer gone quiet
+    /// Returns whether the session goes on.
+    async fn check_heartbeat(&mut self) -> io::Result<bool> {
+        let Some(interval) = self.heartbeat else {
+            return Ok(true);
+        };
+        if self.last_sent.elapsed() >= interval {
+            self.send(FixMessage::new(msg_type::HEARTBEAT)).await?;
+        }
+        let quiet = self.last_received.elapsed();
+        if self.test_request_pending && quiet >= interval * 2 {
+            self.logout("Heartbeat timeout").await?;
+            return Ok(false);
+        }
+        if !self.test_request_pending && quiet >= interval + interval / 5 {
+            let test_req_id = format!("TEST-{}", self.session.next_outgoing);
+            self.send(FixMessage::new(msg_type::TEST_REQUEST).with(tag::TEST_REQ_ID, test_req_id)).await?;
+            self.test_request_pending = true;
+        }
+        Ok(true)
+    }
+
+    /// Enters a NewOrderSingle, reporting it rejected if the engine doesn't take it
+    /// It is acknowledged by its first order update, like any other change to it.
+    async fn new_order(&mut self, message: &FixMessage) -> io::Result<()> {
+        let Some(cl_ord_id) = message.get(tag::CL_ORD_ID) else {
+            return self.reject(message, Some(tag::CL_ORD_ID), 1, "ClOrdID is required").await;
+        };
+        let state = &self.gateway.state;
+        let result = match self.order_request(message, cl_ord_id) {
+            Ok(request) => {
+                let decimals = state.order_intake.read().await.price_scale(&request.book_id).decimals;
+                place_order(&request, self.identity, state).await.map(|response| (request, response.order_id, decimals))
+            }
+            Err(error) => Err(error),
+        };
+        state.metrics.record_submission(result.as_ref().err().map(ApiError::code));
+        match result {
+            Ok((request, Some(order_id), decimals)) => {
+                let order = FixOrder {
+                    cl_ord_id: cl_ord_id.to_string(),
+                    replaces: None,
+                    cancel: None,
+                    symbol: request.book_id,
+                    side: message.get(tag::SIDE).unwrap_or_default().to_string(),
+                    price: message.get(tag::PRICE).unwrap_or_default().to_string(),
+                    order_qty: request.quantity,
+                    cum_qty: 0,
+                    cum_notional: 0,
+                    decimals,
+                    acked: false,
+                    executions: 0,
+                };
+                self.session.orders.insert(order_id, order);
+                Ok(())
+            }
+            Ok((_, None, _)) => Ok(()),
+            Err(error) => {
+                let reason = match error {
+                    ApiError::UnknownBook => 1, // Unknown symbol
+                    _ => 99,                    // Other
+                };
+                let rejected = FixMessage::new(msg_type::EXECUTION_REPORT)
+                    .with(tag::ORDER_ID, "NONE")
+                    .with(tag::CL_ORD_ID, cl_ord_id)
+                    .with(tag::EXEC_ID, format!("{}-{}", self.peer, self.session.next_outgoing))
+                    .with(tag::EXEC_TYPE, "8")
+                    .with(tag::ORD_STATUS, "8")
+                    .with(tag::SYMBOL, message.get(tag::SYMBOL).unwrap_or_default())
+                    .with(tag::SIDE, message.get(tag::SIDE).unwrap_or_default())
+                    .with(tag::LEAVES_QTY, 0)
+                    .with(tag::CUM_QTY, 0)
+                    .with(tag::AVG_PX, 0)
+                    .with(tag::ORD_REJ_REASON, reason)
+                    .with(tag::TEXT, format!("{}: {}", error.code(), error));
+                self.send(rejected).await
+            }
+        }
+    }
+
+    /// Reads a NewOrderSingle as the order request the API would take
+    /// Only limit orders are taken. The trader is the Account, or the trader the session logged
+    /// on as; the price is read in the decimal places of the market.
+    fn order_request(&self, message: &FixMessage, cl_ord_id: &str) -> Result<OrderRequest, ApiError> {
+        let required = |field: u32, name: &str| {
+            message.get(field).ok_or_else(|| ApiError::InvalidParameter(format!("{} ({}) is required", name, field)))
+        };
+        let side = match message.get(tag::SIDE) {
+            Some("1") => Side::Buy,
+            Some("2") => Side::Sell,
+            _ => return Err(ApiError::InvalidParameter("Side (54) must be 1 (buy) or 2 (sell)".to_string())),
+        };
+        if message.get(tag::ORD_TYPE) != Some("2") {
+            return Err(ApiError::InvalidParameter("OrdType (40) must be 2 (limit)".to_string()));
+        }
+        let trader = match (message.get(tag::ACCOUNT), self.identity) {
+            (Some(account), _) => account.to_string(),
+            (None, Identity::Trader(trader)) => format!("0x{}", hex::encode(trader)),
+            (None, _) => return Err(ApiError::InvalidParameter("Account (1) is required".to_string())),
+        };
+        let expiry = match message.get(tag::EXPIRY) {
+            Some(expiry) => Some(expiry.parse().map_err(|_| ApiError::InvalidParameter("Invalid expiry (20002)".to_string()))?),
+            None => None,
+        };
+        Ok(OrderRequest {
+            book_id: required(tag::SYMBOL, "Symbol")?.to_string(),
+            price: ApiPrice::Decimal(message.get(tag::PRICE).ok_or(ApiError::InvalidPrice)?.to_string()),
+            side: Some(side),
+            quantity: message.get(tag::ORDER_QTY).and_then(|qty| qty.parse().ok()).ok_or(ApiError::InvalidQuantity)?,
+            trader,
+            nonce: message.get(tag::NONCE).and_then(|nonce| nonce.parse().ok()).ok_or(ApiError::InvalidNonce)?,
+            expiry,
+            signature: message.get(tag::SIGNATURE).ok_or(ApiError::InvalidSignature)?.to_string(),
+            order_type: OrderType::Limit,
+            trigger_price: None,
+            display_quantity: None,
+            peg_offset: None,
+            allow_cross: false,
+            reduce_only: false,
+            client_order_id: Some(cl_ord_id.to_string()),
+        })
+    }
+
+    /// Finds a working order of the session by its ClOrdID
+    fn find_order(&self, cl_ord_id: &str) -> Option<u64> {
+        self.session.orders.iter().find(|(_, order)| order.cl_ord_id == cl_ord_id).map(|(order_id, _)| *order_id)
+    }
+
+    /// Cancels an order of the session, reported by the Cancelled update it leads to
+    async fn cancel_order(&mut self, message: &FixMessage) -> io::Result<()> {
+        let (Some(cl_ord_id), Some(orig_cl_ord_id)) = (message.get(tag::CL_ORD_ID), message.get(tag::ORIG_CL_ORD_ID)) else {
+            return self.reject(message, Some(tag::ORIG_CL_ORD_ID), 1, "ClOrdID and OrigClOrdID are required").await;
+        };
+        let Some(order_id) = self.find_order(orig_cl_ord_id) else {
+            return self.cancel_reject(message, None, "1", 1, "Unknown order").await;
+        };
+        match cancel_owned_order(&self.gateway.state, OrderId(order_id), None, self.identity).await {
+            Ok(_) => {
+                if let Some(order) = self.session.orders.get_mut(&order_id) {
+                    order.cancel = Some(cl_ord_id.to_string());
+                }
+                Ok(())
+            }
+            Err(error) => self.refused(message, order_id, "1", error).await,
+        }
+    }
+
+    /// Replaces an order of the session with its new price and OrderQty
+    /// What has filled counts toward the new OrderQty; the rest goes in as the replacement,
+    /// which is acknowledged as Replaced by its first order update.
+    async fn replace_order(&mut self, message: &FixMessage) -> io::Result<()> {
+        let (Some(cl_ord_id), Some(orig_cl_ord_id)) = (message.get(tag::CL_ORD_ID), message.get(tag::ORIG_CL_ORD_ID)) else {
+            return self.reject(message, Some(tag::ORIG_CL_ORD_ID), 1, "ClOrdID and OrigClOrdID are required").await;
+        };
+        let Some(order_id) = self.find_order(orig_cl_ord_id) else {
+            return self.cancel_reject(message, None, "2", 1, "Unknown order").await;
+        };
+        let (Some(order_qty), Some(price)) =
+            (message.get(tag::ORDER_QTY).and_then(|qty| qty.parse::<u64>().ok()), message.get(tag::PRICE))
+        else {
+            return self.reject(message, Some(tag::ORDER_QTY), 1, "OrderQty and Price are required").await;
+        };
+        let order = self.session.orders[&order_id].clone();
+        let Some(leaves_qty) = order_qty.checked_sub(order.cum_qty).filter(|leaves_qty| *leaves_qty > 0) else {
+            let text = "OrderQty must be more than CumQty";
+            return self.cancel_reject(message, Some((order_id, &order)), "2", 99, text).await;
+        };
+        let new_price = ApiPrice::Decimal(price.to_string());
+        match replace_owned_order(&self.gateway.state, OrderId(order_id), new_price, leaves_qty, self.identity).await {
+            Ok(response) => {
+                // The old order's Cancelled update is not reported; the replacement's ack stands for it
+                self.session.orders.remove(&order_id);
+                if let Some(new_order_id) = response.order_id {
+                    let replacement = FixOrder {
+                        cl_ord_id: cl_ord_id.to_string(),
+                        replaces: Some(order.cl_ord_id.clone()),
+                        price: price.to_string(),
+                        order_qty,
+                        acked: false,
+                        executions: 0,
+                        ..order
+                    };
+                    self.session.orders.insert(new_order_id, replacement);
+                }
+                Ok(())
+            }
+            Err(error) => self.refused(message, order_id, "2", error).await,
+        }
+    }
+
+    /// Answers a cancel or replace the engine refused; an order it no longer knows was too late
+    async fn refused(&mut self, message: &FixMessage, order_id: u64, response_to: &str, error: ApiError) -> io::Result<()> {
+        let order = self.session.orders.get(&order_id).cloned();
+        let order = order.as_ref().map(|order| (order_id, order));
+        let reason = match error {
+            ApiError::UnknownOrder => 0, // Too late to cancel
+            _ => 99,                     // Other
+        };
+        self.cancel_reject(message, order, response_to, reason, &format!("{}: {}", error.code(), error)).await
+    }
+
+    /// Sends an OrderCancelReject for a cancel (`response_to` 1) or replace (2)
+    async fn cancel_reject(
+        &mut self,
+        message: &FixMessage,
+        order: Option<(u64, &FixOrder)>,
+        response_to: &str,
+        reason: u32,
+        text: &str,
+    ) -> io::Result<()> {
+        let reject = FixMessage::new(msg_type::ORDER_CANCEL_REJECT)
+            .with(tag::ORDER_ID, order.map_or("NONE".to_string(), |(order_id, _)| order_id.to_string()))
+            .with(tag::CL_ORD_ID, message.get(tag::CL_ORD_ID).unwrap_or_default())
+            .with(tag::ORIG_CL_ORD_ID, message.get(tag::ORIG_CL_ORD_ID).unwrap_or_default())
+            .with(tag::ORD_STATUS, order.map_or("8", |(_, order)| order.working_status()))
+            .with(tag::CXL_REJ_RESPONSE_TO, response_to)
+            .with(tag::CXL_REJ_REASON, reason)
+            .with(tag::TEXT, text);
+        self.send(reject).await
+    }
+
+    /// Reports an order update on one of the session's orders
+    /// The first update of an order acknowledges it; fills are reported with the average price
+    /// of the update, and an order that is done leaves the session.
+    async fn report(&mut self, update: OrderUpdate) -> io::Result<()> {
+        let Some(order) = self.session.orders.get_mut(&update.order_id) else {
+            return Ok(());
+        };
+        let mut reports = Vec::new();
+        if !order.acked {
+            order.acked = true;
+            let leaves_qty = order.order_qty.saturating_sub(order.cum_qty);
+            let ord_status = order.working_status();
+            let report = match order.replaces.take() {
+                Some(orig_cl_ord_id) => {
+                    order.report(update.order_id, "5", ord_status, leaves_qty).with(tag::ORIG_CL_ORD_ID, orig_cl_ord_id)
+                }
+                None => order.report(update.order_id, "0", ord_status, leaves_qty),
+            };
+            reports.push(report);
+        }
+        if update.filled_qty > 0 {
+            order.cum_qty += update.filled_qty;
+            order.cum_notional = order.cum_notional.saturating_add(update.filled_notional);
+            let ord_status = if update.remaining_qty == 0 { "2" } else { "1" };
+            let last_px = average_price(update.filled_notional, update.filled_qty, order.decimals);
+            let report = order
+                .report(update.order_id, "F", ord_status, update.remaining_qty)
+                .with(tag::LAST_QTY, update.filled_qty)
+                .with(tag::LAST_PX, last_px);
+            reports.push(report);
+        }
+        let text = match update.status {
+            OrderStatus::SelfTradePrevented => Some("Self-trade prevented"),
+            OrderStatus::BookClosed => Some("Book closed"),
+            _ => None,
+        };
+        match update.status {
+            OrderStatus::Cancelled | OrderStatus::SelfTradePrevented | OrderStatus::BookClosed => {
+                // A requested cancel is reported under the ClOrdID of its request
+                let cancel = order.cancel.take();
+                let mut report = order.report(update.order_id, "4", "4", 0);
+                if let Some(cancel) = cancel {
+                    report.set(tag::CL_ORD_ID, cancel);
+                    report = report.with(tag::ORIG_CL_ORD_ID, &order.cl_ord_id);
+                }
+                if let Some(text) = text {
+                    report = report.with(tag::TEXT, text);
+                }
+                reports.push(report);
+            }
+            OrderStatus::Expired => reports.push(order.report(update.order_id, "C", "C", 0)),
+            _ => {}
+        }
+        if update.status.is_final() {
+            self.session.orders.remove(&update.order_id);
+        }
+        for report in reports {
+            self.send(report).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::tests::{test_state, test_trader},
+        auth::{address_of, sign_prehash},
+        config::{ApiKeySetting, AuthSettings},
+        eip712::{Eip712Domain, Eip712Order},
+        order_intake::NO_EXPIRY,
+    };
+    use k256::ecdsa::SigningKey;
+    use std::net::SocketAddr;
+
+    const SCRIPT: &str = include_str!("../fixtures/fix_lifecycle.txt");
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// A FIX client following the script
+    struct ScriptedClient {
+        name: String, // Its SenderCompID
+        key: SigningKey,
+        stream: Option<TcpStream>,
+        buffer: Vec<u8>,
+        next_seq_num: u64,
+        nonce: u64,
+    }
+
+    impl ScriptedClient {
+        fn new(name: &str, key: SigningKey) -> Self {
+            Self { name: name.to_string(), key, stream: None, buffer: Vec::new(), next_seq_num: 1, nonce: 0 }
+        }
+
+        /// Sends `message`, connecting first for a Logon
+        async fn send(&mut self, mut message: FixMessage, gateway: SocketAddr) {
+            if message.msg_type() == msg_type::LOGON && self.stream.is_none() {
+                self.stream = Some(TcpStream::connect(gateway).await.unwrap());
+                self.buffer.clear();
+            }
+            let seq_num = message.seq_num().unwrap_or(self.next_seq_num);
+            self.next_seq_num = self.next_seq_num.max(seq_num + 1);
+            if message.msg_type() == msg_type::NEW_ORDER_SINGLE {
+                self.nonce += 1;
+                let price: i32 = message.get(tag::PRICE).and_then(|price| price.parse().ok()).unwrap_or_default();
+                let digest = Eip712Domain::default().hash_order(&Eip712Order {
+                    book: message.get(tag::SYMBOL).unwrap(),
+                    trader: address_of(self.key.verifying_key()),
+                    price: if message.get(tag::SIDE) == Some("2") { -price } else { price },
+                    quantity: message.get(tag::ORDER_QTY).unwrap().parse().unwrap(),
+                    nonce: self.nonce,
+                    expiry: NO_EXPIRY,
+                });
+                let signature = format!("0x{}", hex::encode(sign_prehash(&self.key, &digest)));
+                message = message.with(tag::NONCE, self.nonce).with(tag::SIGNATURE, signature);
+            }
+            let target = message.get(tag::TARGET_COMP_ID).unwrap_or("NUMENA").to_string();
+            let message = message.with_header(&self.name, &target, seq_num, Clock::System.now());
+            self.stream.as_mut().unwrap().write_all(&message.encode()).await.unwrap();
+        }
+
+        /// Receives the next message; after a Logout, waits for the gateway to close the connection
+        async fn receive(&mut self) -> FixMessage {
+            let stream = self.stream.as_mut().expect("not connected");
+            let message = tokio::time::timeout(WAIT, read_message(stream, &mut self.buffer)).await.unwrap().unwrap();
+            let message = message.expect("connection closed");
+            if message.msg_type() == msg_type::LOGOUT {
+                let closed = tokio::time::timeout(WAIT, read_message(stream, &mut self.buffer)).await.unwrap();
+                assert!(matches!(closed, Ok(None)), "expected the connection to close after {:?}", message);
+                self.stream = None;
+            }
+            message
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fix_lifecycle() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let (maker_key, maker) = test_trader(0x31);
+        let (taker_key, taker) = test_trader(0x32);
+        let settings = AuthSettings {
+            api_keys: vec![
+                ApiKeySetting { key: "maker-key".to_string(), trader: maker },
+                ApiKeySetting { key: "taker-key".to_string(), trader: taker },
+            ],
+            ..AuthSettings::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fix_sessions.json");
+        let gateway = FixGateway::new(state.clone(), "NUMENA", FixSessions::open(&path).unwrap())
+            .with_authenticator(web::Data::new(Authenticator::new(&settings)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let serving = tokio::spawn(serve(listener, Arc::new(gateway)));
+
+        let mut clients =
+            HashMap::from([("maker", ScriptedClient::new("maker", maker_key)), ("taker", ScriptedClient::new("taker", taker_key))]);
+        clients.insert("intruder", ScriptedClient::new("intruder", test_trader(0x33).0));
+        for (number, line) in SCRIPT.lines().enumerate().map(|(index, line)| (index + 1, line.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let split = line.find(['>', '<']).unwrap();
+            let (name, fields) = (&line[..split], FixMessage::parse(line[split + 1..].trim()).unwrap());
+            let client = clients.get_mut(name).unwrap();
+            if line[split..].starts_with('>') {
+                client.send(fields, address).await;
+                continue;
+            }
+            let received = client.receive().await;
+            println!("{}: {:?}", number, received);
+            for (field, value) in fields.fields() {
+                assert_eq!(received.get(*field), Some(value.as_str()), "line {}: tag {} of {:?}", number, field, received);
+            }
+        }
+
+        // The maker's session was saved as it ended, with nothing left working
+        let saved = FixSessions::open(&path).unwrap().get("maker").unwrap();
+        assert_eq!((saved.next_incoming, saved.next_outgoing), (23, 12));
+        assert!(saved.orders.is_empty());
+
+        // Sessions still logged on are logged out as the server shuts down
+        state.shutdown.send_replace(true);
+        let logout = clients.get_mut("taker").unwrap().receive().await;
+        assert_eq!((logout.msg_type(), logout.get(tag::TEXT)), (msg_type::LOGOUT, Some("Server shutting down")));
+        tokio::time::timeout(WAIT, serving).await.unwrap().unwrap();
+        assert_eq!(FixSessions::open(&path).unwrap().get("taker").unwrap().next_outgoing, 10);
+    }
+}
@@ -0,0 +1,310 @@
+// funds.rs
+
+use crate::{
+    eip1271::{EthRpc, RpcError},
+    market::MarketConfig,
+    order_intake::OrderIntakeError,
+    quantity::Qty,
+    translator::scale_amounts,
+    utils::FUNDS_CACHE_TTL,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Selector of ERC-20 `balanceOf(address)`
+pub const BALANCE_OF: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+/// Selector of ERC-20 `allowance(address,address)`
+pub const ALLOWANCE: [u8; 4] = [0xdd, 0x62, 0xed, 0x3e];
+
+pub type AmountFuture<'a> = Pin<Box<dyn Future<Output = Result<u128, RpcError>> + Send + 'a>>;
+/// Whether an order passed the funds check, or why not
+pub type FundsVerdict = Result<(), OrderIntakeError>;
+
+/// Read-only access to the ERC-20 state orders settle against
+/// Amounts above u128::MAX, like the usual unlimited allowance, come back as u128::MAX.
+pub trait ChainClient: Send + Sync {
+    /// Gets the `token` balance of `owner`
+    fn balance_of(&self, token: [u8; 20], owner: [u8; 20]) -> AmountFuture<'_>;
+    /// Gets how much of its `token` `owner` lets `spender` move
+    fn allowance(&self, token: [u8; 20], owner: [u8; 20], spender: [u8; 20]) -> AmountFuture<'_>;
+}
+
+/// ChainClient calling the token contracts through an EthRpc
+pub struct RpcChainClient {
+    rpc: Arc<dyn EthRpc>,
+}
+
+impl RpcChainClient {
+    pub fn new(rpc: Arc<dyn EthRpc>) -> Self {
+        Self { rpc }
+    }
+}
+
+impl ChainClient for RpcChainClient {
+    fn balance_of(&self, token: [u8; 20], owner: [u8; 20]) -> AmountFuture<'_> {
+        let data = encode_call(BALANCE_OF, &[owner]);
+        Box::pin(async move { decode_amount(&self.rpc.call(token, data).await?) })
+    }
+
+    fn allowance(&self, token: [u8; 20], owner: [u8; 20], spender: [u8; 20]) -> AmountFuture<'_> {
+        let data = encode_call(ALLOWANCE, &[owner, spender]);
+        Box::pin(async move { decode_amount(&self.rpc.call(token, data).await?) })
+    }
+}
+
+/// Encodes a call of `selector` with address arguments
+pub fn encode_call(selector: [u8; 4], addresses: &[[u8; 20]]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + 32 * addresses.len());
+    data.extend_from_slice(&selector);
+    for address in addresses {
+        data.extend_from_slice(&[0; 12]);
+        data.extend_from_slice(address);
+    }
+    data
+}
+
+/// Decodes a uint256 return value, saturating at u128::MAX
+fn decode_amount(output: &[u8]) -> Result<u128, RpcError> {
+    let word: [u8; 32] = output
+        .get(..32)
+        .and_then(|word| word.try_into().ok())
+        .ok_or_else(|| RpcError::Unavailable(format!("Malformed amount: 0x{}", hex::encode(output))))?;
+    if word[..16].iter().any(|&byte| byte != 0) {
+        return Ok(u128::MAX);
+    }
+    Ok(u128::from_be_bytes(word[16..].try_into().unwrap_or_default()))
+}
+
+/// The token an order pays with and how much of it, in token base units, if all of it fills
+/// at its limit price: the base notional for a bid, rounded up as the buyer pays it, and the
+/// security for an ask. None if the amount does not fit a u128.
+pub fn required_funds(config: &MarketConfig, is_bid: bool, price: u32, qty: Qty) -> Option<([u8; 20], u128)> {
+    let amounts = scale_amounts(qty, price, config)?;
+    if is_bid {
+        Some((config.base_token, amounts.base_amount.checked_add(amounts.dust)?))
+    } else {
+        Some((config.security_token, amounts.security_amount))
+    }
+}
+
+/// What traders could spend of each token, and when the chain said so, keyed by trader and token
+type FundsCache = HashMap<([u8; 20], [u8; 20]), (u128, Instant)>;
+
+/// Checks at intake that a trader holds, and has approved, what an order would pay if it filled
+/// What a trader can spend of a token, the lesser of balance and allowance, is cached for a
+/// short TTL so a burst of orders costs one round of calls; `invalidate` drops a trader's
+/// entries once their balances are known to move. Orders already resting are not taken off
+/// what is available, so each order is checked on its own.
+pub struct FundsChecker {
+    client: Arc<dyn ChainClient>,
+    ttl: Duration,
+    cache: Mutex<FundsCache>,
+}
+
+impl FundsChecker {
+    pub fn new(client: Arc<dyn ChainClient>) -> Self {
+        Self::with_ttl(client, FUNDS_CACHE_TTL)
+    }
+
+    pub fn with_ttl(client: Arc<dyn ChainClient>, ttl: Duration) -> Self {
+        Self {
+            client,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets how much of `token` `trader` can spend through `spender`, from the cache while fresh
+    pub async fn available(&self, trader: [u8; 20], token: [u8; 20], spender: [u8; 20]) -> Result<u128, RpcError> {
+        if let Some(&(available, fetched)) = self.cache.lock().unwrap().get(&(trader, token)) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(available);
+            }
+        }
+        let balance = self.client.balance_of(token, trader).await?;
+        let allowance = self.client.allowance(token, trader, spender).await?;
+        let available = balance.min(allowance);
+        self.cache.lock().unwrap().insert((trader, token), (available, Instant::now()));
+        Ok(available)
+    }
+
+    /// Checks that `trader` can pay for an order in `config`'s market, rejecting it with
+    /// InsufficientFunds otherwise
+    /// Markets that skip the check, or have no address for the token, pass without a call.
+    /// Returns Err only when the node can't tell what the trader has.
+    pub async fn check(
+        &self,
+        trader: [u8; 20],
+        config: &MarketConfig,
+        is_bid: bool,
+        price: u32,
+        qty: Qty,
+    ) -> Result<FundsVerdict, RpcError> {
+        if config.skip_funds_check {
+            return Ok(Ok(()));
+        }
+        let Some((token, required)) = required_funds(config, is_bid, price, qty) else {
+            return Ok(Err(OrderIntakeError::InvalidQuantity));
+        };
+        if token == [0; 20] {
+            return Ok(Ok(()));
+        }
+        let available = self.available(trader, token, config.verifying_contract).await?;
+        if required > available {
+            return Ok(Err(OrderIntakeError::InsufficientFunds { token, required, available }));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Forgets what `trader` was last seen to have, so their next order asks the chain again
+    pub fn invalidate(&self, trader: [u8; 20]) {
+        self.cache.lock().unwrap().retain(|&(cached, _), _| cached != trader);
+    }
+}
+
+/// Amounts of a token an owner holds, keyed by token and owner
+#[cfg(test)]
+type Balances = Mutex<HashMap<([u8; 20], [u8; 20]), u128>>;
+
+/// ChainClient stand-in holding balances and allowances in memory, counting the calls made
+/// Allowances are per token and owner, whatever the spender.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockChain {
+    pub balances: Balances,
+    pub allowances: Balances,
+    pub calls: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+impl MockChain {
+    /// Gives `owner` `amount` of `token`, all of it approved
+    pub fn fund(&self, token: [u8; 20], owner: [u8; 20], amount: u128) {
+        self.balances.lock().unwrap().insert((token, owner), amount);
+        self.allowances.lock().unwrap().insert((token, owner), amount);
+    }
+}
+
+#[cfg(test)]
+impl ChainClient for MockChain {
+    fn balance_of(&self, token: [u8; 20], owner: [u8; 20]) -> AmountFuture<'_> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let amount = self.balances.lock().unwrap().get(&(token, owner)).copied().unwrap_or_default();
+        Box::pin(async move { Ok(amount) })
+    }
+
+    fn allowance(&self, token: [u8; 20], owner: [u8; 20], _spender: [u8; 20]) -> AmountFuture<'_> {
+        self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let amount = self.allowances.lock().unwrap().get(&(token, owner)).copied().unwrap_or_default();
+        Box::pin(async move { Ok(amount) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eip1271::RpcFuture;
+    use std::sync::atomic::Ordering;
+
+    fn market() -> MarketConfig {
+        MarketConfig::builder()
+            .base_token([1; 20])
+            .security_token([2; 20])
+            .verifying_contract([5; 20])
+            .build()
+    }
+
+    #[test]
+    fn test_required_funds() {
+        let config = market();
+        assert_eq!(required_funds(&config, true, 1000, Qty(3)), Some(([1; 20], 3000)));
+        assert_eq!(required_funds(&config, false, 1000, Qty(3)), Some(([2; 20], 3)));
+
+        // Buyers pay the fractional base unit rounded up
+        let cents = MarketConfig { price_decimals: 2, ..market() };
+        assert_eq!(required_funds(&cents, true, 150, Qty(3)), Some(([1; 20], 5)));
+    }
+
+    #[tokio::test]
+    async fn test_funds_pass_and_fail() {
+        let chain = Arc::new(MockChain::default());
+        chain.fund([1; 20], [9; 20], 5000);
+        chain.fund([2; 20], [9; 20], 2);
+        chain.allowances.lock().unwrap().insert(([1; 20], [9; 20]), 4000);
+        let checker = FundsChecker::new(chain.clone());
+
+        assert!(matches!(checker.check([9; 20], &market(), true, 1000, Qty(4)).await, Ok(Ok(()))));
+        // The allowance, not the balance, is what holds the bid back
+        let result = checker.check([9; 20], &market(), true, 1000, Qty(5)).await;
+        println!("Bid of 5: {:?}", result);
+        assert!(matches!(
+            result,
+            Ok(Err(OrderIntakeError::InsufficientFunds { token, required: 5000, available: 4000 })) if token == [1; 20]
+        ));
+        assert!(matches!(
+            checker.check([9; 20], &market(), false, 1000, Qty(3)).await,
+            Ok(Err(OrderIntakeError::InsufficientFunds { token, required: 3, available: 2 })) if token == [2; 20]
+        ));
+        assert_eq!(chain.calls.load(Ordering::SeqCst), 4);
+
+        // Markets that skip the check, or have no tokens, are not asked about
+        let skipped = MarketConfig { skip_funds_check: true, ..market() };
+        assert!(matches!(checker.check([8; 20], &skipped, true, 1000, Qty(5)).await, Ok(Ok(()))));
+        assert!(matches!(checker.check([8; 20], &MarketConfig::default(), true, 1000, Qty(5)).await, Ok(Ok(()))));
+        assert_eq!(chain.calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_stale_cache_then_refresh() {
+        let chain = Arc::new(MockChain::default());
+        chain.fund([1; 20], [9; 20], 1000);
+        let checker = FundsChecker::with_ttl(chain.clone(), Duration::from_millis(50));
+        assert!(matches!(checker.check([9; 20], &market(), true, 1000, Qty(1)).await, Ok(Ok(()))));
+
+        // A deposit goes unseen while the cached amount is fresh
+        chain.fund([1; 20], [9; 20], 2000);
+        assert!(matches!(checker.check([9; 20], &market(), true, 1000, Qty(2)).await, Ok(Err(_))));
+        assert_eq!(chain.calls.load(Ordering::SeqCst), 2);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(checker.check([9; 20], &market(), true, 1000, Qty(2)).await, Ok(Ok(()))));
+        assert_eq!(chain.calls.load(Ordering::SeqCst), 4);
+
+        // Invalidating a trader makes the next check ask again, however fresh the entry
+        chain.fund([1; 20], [9; 20], 0);
+        checker.invalidate([9; 20]);
+        assert!(matches!(checker.check([9; 20], &market(), true, 1000, Qty(1)).await, Ok(Err(_))));
+        assert_eq!(chain.calls.load(Ordering::SeqCst), 6);
+    }
+
+    /// A token contract over EthRpc whose every amount is `output`
+    struct FixedToken(Vec<u8>);
+
+    impl EthRpc for FixedToken {
+        fn call(&self, to: [u8; 20], data: Vec<u8>) -> RpcFuture<'_> {
+            assert_eq!(to, [1; 20]);
+            assert!(data[..4] == BALANCE_OF || data[..4] == ALLOWANCE);
+            assert_eq!(data[16..36], [9; 20]);
+            let output = self.0.clone();
+            Box::pin(async move { Ok(output) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rpc_chain_client() {
+        let mut amount = vec![0u8; 32];
+        amount[30..].copy_from_slice(&[0x12, 0x34]);
+        let client = RpcChainClient::new(Arc::new(FixedToken(amount)));
+        assert_eq!(client.balance_of([1; 20], [9; 20]).await, Ok(0x1234));
+
+        // An unlimited allowance saturates, and a short answer is no answer
+        let client = RpcChainClient::new(Arc::new(FixedToken(vec![0xff; 32])));
+        assert_eq!(client.allowance([1; 20], [9; 20], [5; 20]).await, Ok(u128::MAX));
+        let client = RpcChainClient::new(Arc::new(FixedToken(vec![0; 4])));
+        assert!(matches!(client.balance_of([1; 20], [9; 20]).await, Err(RpcError::Unavailable(_))));
+        assert_eq!(encode_call(ALLOWANCE, &[[9; 20], [5; 20]]).len(), 68);
+    }
+}
@@ -0,0 +1,554 @@
+// grpc.rs
+
+use crate::{
+    api::{
+        book_depth, cancel_owned_order, order_status, place_order, replace_owned_order, subscribe_book, AppState,
+        OrderRequest, OrderType, OrderbookResponse, PriceLevelResponse,
+    },
+    api_auth::{Authenticator, Identity, API_KEY_HEADER},
+    api_error::{ApiError, ErrorResponse},
+    auth::AuthError,
+    market_data::MarketDataEvent,
+    order::OrderId,
+    order_intake::Side,
+    order_updates::{OrderStatus, OrderUpdate},
+    price::{ApiPrice, PriceScale},
+    utils::BookId,
+};
+use actix_web::web;
+use proto::exchange_server::{Exchange, ExchangeServer};
+use proto::market_data_message::Event;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{codegen::Bytes, Code, Request, Response, Status};
+
+/// The messages and service of proto/numena.proto
+pub mod proto {
+    tonic::include_proto!("numena.v1");
+}
+
+/// Metadata of a failed call carrying its API error code, as ErrorResponse carries it over HTTP
+pub const ERROR_CODE_METADATA: &str = "x-numena-error-code";
+
+/// Messages a stream holds for a subscriber reading slower than they come; past these the
+/// subscriber falls behind the market data channel and is ended
+const STREAM_BUFFER: usize = 1_024;
+
+/// The gRPC API, next to the HTTP API and sharing its state
+/// Every call goes through the functions the HTTP handlers call, so the two can't disagree.
+/// Calls that change something need an API key in the API_KEY_HEADER metadata; without an
+/// authenticator every call is let through, as the API lets every request through.
+pub struct GrpcService {
+    state: web::Data<AppState>,
+    authenticator: Option<web::Data<Authenticator>>,
+}
+
+impl GrpcService {
+    pub fn new(state: web::Data<AppState>) -> Self {
+        Self { state, authenticator: None }
+    }
+
+    /// Checks the API key of each call that changes something, acting as the trader or admin it belongs to
+    pub fn with_authenticator(mut self, authenticator: web::Data<Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Gets who a call was made as, from its API key
+    fn identity<T>(&self, request: &Request<T>) -> Result<Identity, ApiError> {
+        let Some(authenticator) = &self.authenticator else {
+            return Ok(Identity::Unchecked);
+        };
+        let key = request.metadata().get(API_KEY_HEADER).and_then(|key| key.to_str().ok());
+        let key = key.ok_or(ApiError::Unauthorized(AuthError::MissingCredentials))?;
+        authenticator.authenticate_key(key).map_err(ApiError::Unauthorized)
+    }
+}
+
+/// Serves the gRPC API on `listener` until the server shuts down
+/// Streams still open then are ended with ShuttingDown before this returns.
+pub async fn serve(listener: TcpListener, service: GrpcService) -> Result<(), tonic::transport::Error> {
+    let mut shutdown = service.state.shutdown.subscribe();
+    tonic::transport::Server::builder()
+        .add_service(ExchangeServer::new(service))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+            let _ = shutdown.wait_for(|down| *down).await;
+        })
+        .await
+}
+
+#[tonic::async_trait]
+impl Exchange for GrpcService {
+    async fn submit_order(
+        &self,
+        request: Request<proto::SubmitOrderRequest>,
+    ) -> Result<Response<proto::SubmitOrderResponse>, Status> {
+        let identity = self.identity(&request)?;
+        let result = match order_request(request.into_inner()) {
+            Ok(order) => place_order(&order, identity, &self.state).await,
+            Err(error) => Err(error),
+        };
+        self.state.metrics.record_submission(result.as_ref().err().map(ApiError::code));
+        let response = result?;
+        Ok(Response::new(proto::SubmitOrderResponse {
+            order_id: response.order_id,
+            handle: response.handle,
+            state: response.status.as_ref().map(order_state),
+            client_order_id: response.client_order_id.map(|client_order_id| client_order_id.to_string()),
+        }))
+    }
+
+    async fn cancel_order(
+        &self,
+        request: Request<proto::CancelOrderRequest>,
+    ) -> Result<Response<proto::CancelOrderResponse>, Status> {
+        let identity = self.identity(&request)?;
+        let request = request.into_inner();
+        let response = cancel_owned_order(&self.state, OrderId(request.order_id), request.handle, identity).await?;
+        Ok(Response::new(proto::CancelOrderResponse {
+            order_id: request.order_id,
+            client_order_id: response.client_order_id.map(|client_order_id| client_order_id.to_string()),
+        }))
+    }
+
+    async fn replace_order(
+        &self,
+        request: Request<proto::ReplaceOrderRequest>,
+    ) -> Result<Response<proto::ReplaceOrderResponse>, Status> {
+        let identity = self.identity(&request)?;
+        let request = request.into_inner();
+        let quantity = quantity(&request.quantity)?;
+        let price = ApiPrice::Decimal(request.price);
+        let response = replace_owned_order(&self.state, OrderId(request.order_id), price, quantity, identity).await?;
+        Ok(Response::new(proto::ReplaceOrderResponse {
+            order_id: response.order_id.unwrap_or_default(),
+            remaining_quantity: response.remaining_quantity.to_string(),
+            fills: response
+                .fills
+                .into_iter()
+                .map(|fill| proto::Fill {
+                    price: price_text(fill.price),
+                    quantity: fill.quantity.to_string(),
+                    executed_at: fill.executed_at.unwrap_or_default(),
+                })
+                .collect(),
+            state: response.status.as_ref().map(order_state),
+        }))
+    }
+
+    async fn get_depth(&self, request: Request<proto::GetDepthRequest>) -> Result<Response<proto::Depth>, Status> {
+        let request = request.into_inner();
+        let depth = book_depth(&self.state, &request.book, request.depth.map(|depth| depth as usize), None).await?;
+        Ok(Response::new(depth_message(depth)))
+    }
+
+    async fn get_order(
+        &self,
+        request: Request<proto::GetOrderRequest>,
+    ) -> Result<Response<proto::OrderStatusResponse>, Status> {
+        let response = order_status(&*self.state.lock_engine().await, OrderId(request.into_inner().order_id))?;
+        Ok(Response::new(proto::OrderStatusResponse {
+            order_id: response.order_id,
+            status: response.status.map_or(proto::OrderStatus::Unspecified, status_message) as i32,
+            remaining_quantity: response.remaining_quantity.to_string(),
+            price: response.price.map(price_text),
+            client_order_id: response.client_order_id.map(|client_order_id| client_order_id.to_string()),
+            received_at: response.received_at,
+        }))
+    }
+
+    type SubscribeMarketDataStream = ReceiverStream<Result<proto::MarketDataMessage, Status>>;
+
+    async fn subscribe_market_data(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeMarketDataStream>, Status> {
+        let (book_id, events, depth, scale) = subscribe_book(&self.state, &request.into_inner().book).await?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let snapshot = proto::MarketDataMessage { event: Some(Event::Snapshot(depth_message(depth))) };
+        let shutdown = self.state.shutdown.subscribe();
+        tokio::spawn(async move {
+            if sender.send(Ok(snapshot)).await.is_ok() {
+                forward(book_id, events, shutdown, sender, |event| Some(market_data_message(event, scale))).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type SubscribeTradesStream = ReceiverStream<Result<proto::Trade, Status>>;
+
+    async fn subscribe_trades(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeTradesStream>, Status> {
+        let (book_id, events, _, scale) = subscribe_book(&self.state, &request.into_inner().book).await?;
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let shutdown = self.state.shutdown.subscribe();
+        tokio::spawn(forward(book_id, events, shutdown, sender, move |event| trade_message(event, scale)));
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Sends a subscriber the events of its book `convert` gives a message for, until the subscriber
+/// goes away or the server shuts down
+/// A subscriber that falls behind the market data channel is ended with DataLoss, and should resubscribe.
+async fn forward<T>(
+    book_id: BookId,
+    mut events: broadcast::Receiver<MarketDataEvent>,
+    mut shutdown: watch::Receiver<bool>,
+    sender: mpsc::Sender<Result<T, Status>>,
+    convert: impl Fn(&MarketDataEvent) -> Option<T>,
+) {
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.book_id() == book_id => match convert(&event) {
+                    Some(message) => Ok(message),
+                    None => continue,
+                },
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(_)) => Err(Status::data_loss("Subscriber lagged behind market data")),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            down = async { shutdown.wait_for(|down| *down).await.is_ok() } => match down {
+                true => Err(ApiError::ShuttingDown.into()),
+                false => return,
+            },
+            _ = sender.closed() => return,
+        };
+        let ended = message.is_err();
+        if sender.send(message).await.is_err() || ended {
+            return;
+        }
+    }
+}
+
+/// Each error keeps its API code in ERROR_CODE_METADATA, and its ErrorResponse as the details
+impl From<ApiError> for Status {
+    fn from(error: ApiError) -> Self {
+        let code = match error {
+            ApiError::Unauthorized(_) => Code::Unauthenticated,
+            ApiError::Forbidden => Code::PermissionDenied,
+            ApiError::UnknownBook
+            | ApiError::UnknownOrder
+            | ApiError::UnknownMarket
+            | ApiError::UnknownSettlement
+            | ApiError::UnknownBatch => Code::NotFound,
+            ApiError::BookExists
+            | ApiError::MarketExists
+            | ApiError::DuplicateClientOrderId(_)
+            | ApiError::IdempotencyConflict => Code::AlreadyExists,
+            ApiError::Halted
+            | ApiError::BookCrossed(_)
+            | ApiError::PriceOutsideBand { .. }
+            | ApiError::BookInAuction(_)
+            | ApiError::NotInAuction(_)
+            | ApiError::NoPegReference(_)
+            | ApiError::PositionsNotTracked(_)
+            | ApiError::NoPositionToReduce(_)
+            | ApiError::RiskLimitExceeded(_)
+            | ApiError::InsufficientFunds { .. }
+            | ApiError::BookFull => Code::FailedPrecondition,
+            ApiError::TooManyBooks | ApiError::Overloaded => Code::ResourceExhausted,
+            ApiError::Internal(_) => Code::Internal,
+            ApiError::Unavailable(_) | ApiError::ShuttingDown | ApiError::NotReady => Code::Unavailable,
+            _ => Code::InvalidArgument,
+        };
+        let body = ErrorResponse { success: false, code: error.code(), message: error.to_string(), details: error.details() };
+        let details = Bytes::from(serde_json::to_vec(&body).unwrap_or_default());
+        let mut status = Status::with_details(code, error.to_string(), details);
+        status.metadata_mut().insert(ERROR_CODE_METADATA, error.code().into());
+        status
+    }
+}
+
+/// Reads a submission as the order request the HTTP API would take
+fn order_request(request: proto::SubmitOrderRequest) -> Result<OrderRequest, ApiError> {
+    let invalid = |message: &str| ApiError::InvalidParameter(message.to_string());
+    let side = match proto::Side::try_from(request.side).map_err(|_| invalid("Unknown side"))? {
+        proto::Side::Unspecified => None,
+        proto::Side::Buy => Some(Side::Buy),
+        proto::Side::Sell => Some(Side::Sell),
+    };
+    let order_type = match proto::OrderType::try_from(request.order_type).map_err(|_| invalid("Unknown order type"))? {
+        proto::OrderType::Limit => OrderType::Limit,
+        proto::OrderType::Stop => OrderType::Stop,
+        proto::OrderType::StopLimit => OrderType::StopLimit,
+        proto::OrderType::MidpointPeg => OrderType::MidpointPeg,
+        proto::OrderType::PrimaryPeg => OrderType::PrimaryPeg,
+    };
+    Ok(OrderRequest {
+        book_id: request.book,
+        price: ApiPrice::Decimal(request.price),
+        side,
+        quantity: quantity(&request.quantity)?,
+        trader: request.trader,
+        nonce: request.nonce,
+        expiry: request.expiry,
+        signature: request.signature,
+        order_type,
+        trigger_price: request.trigger_price.map(ApiPrice::Decimal),
+        display_quantity: request.display_quantity.as_deref().map(quantity).transpose()?,
+        peg_offset: request.peg_offset,
+        allow_cross: request.allow_cross,
+        reduce_only: request.reduce_only,
+        client_order_id: request.client_order_id,
+    })
+}
+
+/// Reads a quantity, a whole number of lots
+fn quantity(text: &str) -> Result<u64, ApiError> {
+    text.parse().map_err(|_| ApiError::InvalidQuantity)
+}
+
+/// Writes a price as the HTTP API wrote it, as a decimal string
+fn price_text(price: ApiPrice) -> String {
+    match price {
+        ApiPrice::Units(units) => units.to_string(),
+        ApiPrice::Decimal(text) => text,
+    }
+}
+
+fn side_message(side: &str) -> proto::Side {
+    match side {
+        "buy" => proto::Side::Buy,
+        _ => proto::Side::Sell,
+    }
+}
+
+fn status_message(status: OrderStatus) -> proto::OrderStatus {
+    match status {
+        OrderStatus::New => proto::OrderStatus::New,
+        OrderStatus::PartiallyFilled => proto::OrderStatus::PartiallyFilled,
+        OrderStatus::Filled => proto::OrderStatus::Filled,
+        OrderStatus::Cancelled => proto::OrderStatus::Cancelled,
+        OrderStatus::Expired => proto::OrderStatus::Expired,
+        OrderStatus::SelfTradePrevented => proto::OrderStatus::SelfTradePrevented,
+        OrderStatus::Untriggered => proto::OrderStatus::Untriggered,
+        OrderStatus::Triggered => proto::OrderStatus::Triggered,
+        OrderStatus::Parked => proto::OrderStatus::Parked,
+        OrderStatus::BookClosed => proto::OrderStatus::BookClosed,
+    }
+}
+
+fn order_state(update: &OrderUpdate) -> proto::OrderState {
+    proto::OrderState {
+        status: status_message(update.status) as i32,
+        filled_quantity: update.filled_qty.to_string(),
+        remaining_quantity: update.remaining_qty.to_string(),
+    }
+}
+
+fn depth_message(depth: OrderbookResponse) -> proto::Depth {
+    let levels = |levels: Vec<PriceLevelResponse>| -> Vec<proto::Level> {
+        levels
+            .into_iter()
+            .map(|level| proto::Level {
+                price: price_text(level.price),
+                size: level.size.to_string(),
+                order_count: level.order_count,
+            })
+            .collect()
+    };
+    proto::Depth { bids: levels(depth.bids), asks: levels(depth.asks), checksum: depth.checksum, seq: depth.seq }
+}
+
+/// Gets the trade of a market data event, if it is one
+fn trade_message(event: &MarketDataEvent, scale: PriceScale) -> Option<proto::Trade> {
+    match *event {
+        MarketDataEvent::Trade { seq, trade_id, timestamp, price, quantity, side, maker_order_id, taker_order_id, .. } => {
+            Some(proto::Trade {
+                seq,
+                trade_id,
+                timestamp,
+                price: scale.format(price),
+                quantity: quantity.to_string(),
+                side: side_message(side) as i32,
+                maker_order_id,
+                taker_order_id,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn market_data_message(event: &MarketDataEvent, scale: PriceScale) -> proto::MarketDataMessage {
+    let event = match *event {
+        MarketDataEvent::LevelUpdate { prev_seq, seq, side, price, size, .. } => Event::LevelUpdate(proto::LevelUpdate {
+            prev_seq,
+            seq,
+            side: side_message(side) as i32,
+            price: scale.format(price),
+            size: size.to_string(),
+        }),
+        MarketDataEvent::Trade { .. } => Event::Trade(trade_message(event, scale).unwrap_or_default()),
+        MarketDataEvent::Checksum { seq, checksum, .. } => Event::Checksum(proto::Checksum { seq, checksum }),
+        MarketDataEvent::Indicative { seq, price, volume, imbalance, .. } => Event::Indicative(proto::Indicative {
+            seq,
+            price: price.map(|price| scale.format(price)),
+            volume: volume.to_string(),
+            imbalance,
+        }),
+        MarketDataEvent::Halt { seq, halted, .. } => Event::Halt(proto::Halt { seq, halted }),
+    };
+    proto::MarketDataMessage { event: Some(event) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api::tests::{signed_order, test_state, test_trader},
+        config::{ApiKeySetting, AuthSettings},
+    };
+    use k256::ecdsa::SigningKey;
+    use proto::exchange_client::ExchangeClient;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tonic::transport::Channel;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// Serves `service` on a free port of the loopback interface
+    async fn start(service: GrpcService) -> (SocketAddr, tokio::task::JoinHandle<Result<(), tonic::transport::Error>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        (address, tokio::spawn(serve(listener, service)))
+    }
+
+    async fn connect(address: SocketAddr) -> ExchangeClient<Channel> {
+        ExchangeClient::connect(format!("http://{}", address)).await.unwrap()
+    }
+
+    /// An ETH-USD limit order signed by `key`, as a gRPC client submits it
+    fn submission(key: &SigningKey, price: i32, quantity: u64) -> proto::SubmitOrderRequest {
+        let order = signed_order(key, price, quantity);
+        proto::SubmitOrderRequest {
+            book: order.book_id,
+            price: price_text(order.price),
+            quantity: order.quantity.to_string(),
+            trader: order.trader,
+            nonce: order.nonce,
+            signature: order.signature,
+            ..Default::default()
+        }
+    }
+
+    fn error_code(status: &Status) -> Option<&str> {
+        status.metadata().get(ERROR_CODE_METADATA).and_then(|code| code.to_str().ok())
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_submit_fill_and_stream() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let (address, serving) = start(GrpcService::new(state.clone())).await;
+        let mut client = connect(address).await;
+        let subscribe = || proto::SubscribeRequest { book: "ETH-USD".to_string() };
+        let mut market_data = client.subscribe_market_data(subscribe()).await.unwrap().into_inner();
+        let mut trades = client.subscribe_trades(subscribe()).await.unwrap().into_inner();
+        let snapshot = market_data.message().await.unwrap().unwrap();
+        assert!(matches!(snapshot.event, Some(Event::Snapshot(depth)) if depth.bids.is_empty() && depth.asks.is_empty()));
+
+        // A maker rests a sell of 10 at 1000, and a taker buys 4 of it
+        let (maker_key, _) = test_trader(0x41);
+        let (taker_key, _) = test_trader(0x42);
+        let maker = client.submit_order(submission(&maker_key, -1000, 10)).await.unwrap().into_inner();
+        let maker_order_id = maker.order_id.unwrap();
+        assert_eq!(maker.state.unwrap().status(), proto::OrderStatus::New);
+        let taker = client.submit_order(submission(&taker_key, 1005, 4)).await.unwrap().into_inner();
+        let state_after = taker.state.unwrap();
+        assert_eq!((state_after.status(), state_after.filled_quantity.as_str()), (proto::OrderStatus::Filled, "4"));
+
+        // Both streams see the trade, at the taker's limit
+        let trade = tokio::time::timeout(WAIT, trades.message()).await.unwrap().unwrap().unwrap();
+        assert_eq!((trade.price.as_str(), trade.quantity.as_str(), trade.side()), ("1005", "4", proto::Side::Buy));
+        assert_eq!((trade.maker_order_id, trade.taker_order_id), (maker_order_id, taker.order_id.unwrap()));
+        let mut levels = Vec::new();
+        loop {
+            match tokio::time::timeout(WAIT, market_data.message()).await.unwrap().unwrap().unwrap().event {
+                Some(Event::LevelUpdate(update)) => levels.push((update.side(), update.price, update.size)),
+                Some(Event::Trade(streamed)) => {
+                    assert_eq!(streamed, trade);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let level = |size: &str| (proto::Side::Sell, "1000".to_string(), size.to_string());
+        assert_eq!(levels, [level("10"), level("6")]);
+
+        // What is left of the maker's order rests on the book
+        let depth = client.get_depth(proto::GetDepthRequest { book: "ETH-USD".to_string(), depth: None }).await.unwrap();
+        let asks = &depth.get_ref().asks;
+        assert_eq!((asks.len(), asks[0].price.as_str(), asks[0].size.as_str()), (1, "1000", "6"));
+        let order = client.get_order(proto::GetOrderRequest { order_id: maker_order_id }).await.unwrap().into_inner();
+        assert_eq!((order.status(), order.remaining_quantity.as_str()), (proto::OrderStatus::New, "6"));
+        assert_eq!(order.price.as_deref(), Some("1000"));
+
+        // It is replaced, then cancelled; a second cancel finds nothing, as over HTTP
+        let replace = proto::ReplaceOrderRequest { order_id: maker_order_id, price: "1010".to_string(), quantity: "8".to_string() };
+        let replaced = client.replace_order(replace).await.unwrap().into_inner();
+        assert_eq!(replaced.remaining_quantity, "8");
+        let cancel = proto::CancelOrderRequest { order_id: replaced.order_id, handle: None };
+        assert_eq!(client.cancel_order(cancel).await.unwrap().into_inner().order_id, replaced.order_id);
+        let status = client.cancel_order(cancel).await.unwrap_err();
+        assert_eq!((status.code(), error_code(&status)), (Code::NotFound, Some("2002")));
+        let body: ErrorResponse = serde_json::from_slice(status.details()).unwrap();
+        assert_eq!((body.code, body.message.as_str()), (2002, "Unknown order"));
+
+        // Streams end as the server shuts down
+        state.shutdown.send_replace(true);
+        let status = loop {
+            match tokio::time::timeout(WAIT, trades.message()).await.unwrap() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("the stream ended without a status"),
+                Err(status) => break status,
+            }
+        };
+        assert_eq!((status.code(), error_code(&status)), (Code::Unavailable, Some("5003")));
+        tokio::time::timeout(WAIT, serving).await.unwrap().unwrap().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_grpc_errors() {
+        let state = test_state();
+        state.book_registry.register_book("ETH-USD".to_string()).unwrap();
+        let (trader_key, trader) = test_trader(0x43);
+        let settings = AuthSettings {
+            api_keys: vec![ApiKeySetting { key: "trader-key".to_string(), trader }],
+            ..AuthSettings::default()
+        };
+        let service = GrpcService::new(state.clone()).with_authenticator(web::Data::new(Authenticator::new(&settings)));
+        let (address, _serving) = start(service).await;
+        let mut client = connect(address).await;
+
+        // Orders need an API key; reads don't
+        let status = client.submit_order(submission(&trader_key, 1000, 1)).await.unwrap_err();
+        assert_eq!((status.code(), error_code(&status)), (Code::Unauthenticated, Some("1010")));
+
+        let keyed = client.clone();
+        let submit = |submission: proto::SubmitOrderRequest| {
+            let mut request = Request::new(submission);
+            request.metadata_mut().insert(API_KEY_HEADER, "trader-key".parse().unwrap());
+            let mut client = keyed.clone();
+            async move { client.submit_order(request).await }
+        };
+        assert!(submit(submission(&trader_key, 1000, 1)).await.is_ok());
+        let depth = proto::GetDepthRequest { book: "ETH-USD".to_string(), depth: Some(5) };
+        assert_eq!(client.get_depth(depth).await.unwrap().into_inner().bids.len(), 1);
+        let cases = [
+            (proto::SubmitOrderRequest { book: "BTC-USD".to_string(), ..submission(&trader_key, 1000, 1) }, Code::NotFound, "2001"),
+            (proto::SubmitOrderRequest { quantity: "1.5".to_string(), ..submission(&trader_key, 1000, 1) }, Code::InvalidArgument, "1001"),
+            (proto::SubmitOrderRequest { price: "10.5".to_string(), ..submission(&trader_key, 1000, 1) }, Code::InvalidArgument, "1013"),
+            (submission(&test_trader(0x44).0, 1000, 1), Code::PermissionDenied, "1011"),
+        ];
+        for (submission, code, api_code) in cases {
+            let status = submit(submission).await.unwrap_err();
+            assert_eq!((status.code(), error_code(&status)), (code, Some(api_code)), "{}", status.message());
+        }
+        let unknown = client.subscribe_trades(proto::SubscribeRequest { book: "BTC-USD".to_string() }).await.unwrap_err();
+        assert_eq!((unknown.code(), error_code(&unknown)), (Code::NotFound, Some("2001")));
+    }
+}
@@ -0,0 +1,357 @@
+// itch.rs
+
+use std::fmt;
+use std::io::{self, Read};
+
+/// Every message starts with its type, stock locate, tracking number and a 6-byte timestamp.
+const HEADER_LEN: usize = 11;
+
+/// One message of the NASDAQ TotalView-ITCH 5.0 subset the ITCH replay drives books with
+/// `timestamp` is in nanoseconds since midnight. Prices have 4 implied decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ItchMessage {
+    pub stock_locate: u16,
+    pub timestamp: u64,
+    pub body: ItchBody,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItchBody {
+    /// `A`, or `F` with the MPID attribution left out
+    AddOrder {
+        order_ref: u64,
+        is_bid: bool,
+        shares: u32,
+        stock: [u8; 8], // Space padded
+        price: u32,
+    },
+    /// `E`, at the order's own price
+    OrderExecuted {
+        order_ref: u64,
+        shares: u32,
+        match_number: u64,
+    },
+    /// `C`, at a price other than the order's own
+    OrderExecutedWithPrice {
+        order_ref: u64,
+        shares: u32,
+        match_number: u64,
+        printable: bool,
+        price: u32,
+    },
+    /// `X`, a partial cancel
+    OrderCancelled {
+        order_ref: u64,
+        shares: u32,
+    },
+    /// `D`
+    OrderDeleted {
+        order_ref: u64,
+    },
+    /// `U`: the order leaves the book and `new_order_ref` joins it, on the same side
+    OrderReplaced {
+        order_ref: u64,
+        new_order_ref: u64,
+        shares: u32,
+        price: u32,
+    },
+    /// Any other message type, which the replay skips
+    Other(u8),
+}
+
+impl ItchBody {
+    /// Gets the reference number of the order the message acts on, if it is one of the subset
+    pub fn order_ref(&self) -> Option<u64> {
+        match *self {
+            ItchBody::AddOrder { order_ref, .. }
+            | ItchBody::OrderExecuted { order_ref, .. }
+            | ItchBody::OrderExecutedWithPrice { order_ref, .. }
+            | ItchBody::OrderCancelled { order_ref, .. }
+            | ItchBody::OrderDeleted { order_ref }
+            | ItchBody::OrderReplaced { order_ref, .. } => Some(order_ref),
+            ItchBody::Other(_) => None,
+        }
+    }
+}
+
+/// Gets the stock symbol of an AddOrder, without its padding
+pub fn stock_symbol(stock: &[u8; 8]) -> String {
+    String::from_utf8_lossy(stock).trim_end().to_string()
+}
+
+#[derive(Debug)]
+pub enum ItchError {
+    Io(io::Error),
+    /// A message too short for its type, or a file that ends inside one. `offset` is the byte of
+    /// the file its length prefix starts at.
+    Malformed { offset: u64, message: String },
+}
+
+impl fmt::Display for ItchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ItchError::Io(error) => write!(f, "I/O error: {}", error),
+            ItchError::Malformed { offset, message } => write!(f, "Byte {}: {}", offset, message),
+        }
+    }
+}
+
+impl std::error::Error for ItchError {}
+
+impl From<io::Error> for ItchError {
+    fn from(error: io::Error) -> Self {
+        ItchError::Io(error)
+    }
+}
+
+/// Parses one message, without the length prefix it has in a file
+/// Bytes past those its type needs are ignored, as later versions of a message may add fields.
+pub fn parse_message(bytes: &[u8]) -> Result<ItchMessage, String> {
+    let kind = *bytes.first().ok_or("empty message")?;
+    let needed = match kind {
+        b'A' | b'C' => 36,
+        b'F' => 40,
+        b'E' => 31,
+        b'X' => 23,
+        b'D' => 19,
+        b'U' => 35,
+        _ => HEADER_LEN,
+    };
+    if bytes.len() < needed {
+        return Err(format!("'{}' message of {} bytes, expected {}", kind as char, bytes.len(), needed));
+    }
+    let mut fields = Fields { bytes, at: 1 };
+    let stock_locate = fields.u16();
+    fields.skip(2); // Tracking number
+    let timestamp = fields.u48();
+    let body = match kind {
+        b'A' | b'F' => {
+            let order_ref = fields.u64();
+            let is_bid = match fields.u8() {
+                b'B' => true,
+                b'S' => false,
+                side => return Err(format!("side '{}' is neither B nor S", side as char)),
+            };
+            let shares = fields.u32();
+            let stock = fields.array();
+            ItchBody::AddOrder { order_ref, is_bid, shares, stock, price: fields.u32() }
+        }
+        b'E' => ItchBody::OrderExecuted { order_ref: fields.u64(), shares: fields.u32(), match_number: fields.u64() },
+        b'C' => ItchBody::OrderExecutedWithPrice {
+            order_ref: fields.u64(),
+            shares: fields.u32(),
+            match_number: fields.u64(),
+            printable: fields.u8() == b'Y',
+            price: fields.u32(),
+        },
+        b'X' => ItchBody::OrderCancelled { order_ref: fields.u64(), shares: fields.u32() },
+        b'D' => ItchBody::OrderDeleted { order_ref: fields.u64() },
+        b'U' => ItchBody::OrderReplaced {
+            order_ref: fields.u64(),
+            new_order_ref: fields.u64(),
+            shares: fields.u32(),
+            price: fields.u32(),
+        },
+        other => ItchBody::Other(other),
+    };
+    Ok(ItchMessage { stock_locate, timestamp, body })
+}
+
+/// Big-endian fields read in turn; the caller has checked there are enough bytes
+struct Fields<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Fields<'_> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0; N];
+        out.copy_from_slice(&self.bytes[self.at..self.at + N]);
+        self.at += N;
+        out
+    }
+
+    fn skip(&mut self, len: usize) {
+        self.at += len;
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.array())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.array())
+    }
+
+    fn u48(&mut self) -> u64 {
+        let bytes: [u8; 6] = self.array();
+        bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.array())
+    }
+}
+
+/// Reads the messages of an ITCH file, each preceded by its length as a big-endian u16
+/// Iteration ends at the end of the file, or after the first error.
+pub struct ItchReader<R> {
+    reader: R,
+    offset: u64,
+    buf: Vec<u8>,
+    failed: bool,
+}
+
+impl<R: Read> ItchReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, offset: 0, buf: Vec::with_capacity(64), failed: false }
+    }
+
+    fn next_message(&mut self) -> Result<Option<ItchMessage>, ItchError> {
+        let offset = self.offset;
+        let malformed = |message: String| ItchError::Malformed { offset, message };
+        let mut prefix = [0; 2];
+        match read_full(&mut self.reader, &mut prefix)? {
+            0 => return Ok(None),
+            2 => {}
+            _ => return Err(malformed("file ends inside a length prefix".to_string())),
+        }
+        let len = u16::from_be_bytes(prefix) as usize;
+        self.buf.resize(len, 0);
+        if read_full(&mut self.reader, &mut self.buf)? < len {
+            return Err(malformed(format!("file ends inside a message of {} bytes", len)));
+        }
+        self.offset += 2 + len as u64;
+        parse_message(&self.buf).map(Some).map_err(malformed)
+    }
+}
+
+impl<R: Read> Iterator for ItchReader<R> {
+    type Item = Result<ItchMessage, ItchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_message().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
+
+/// Reads until `buf` is full or the reader ends, returning how much was read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Hand-assembled messages of the subset, each with its length prefix. Each is stock locate 1,
+    /// tracking number 0 and timestamp 0x000102030405.
+    pub(crate) const ADD_BUY: &[u8] = &[
+        0, 36, b'A', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, // Header
+        0, 0, 0, 0, 0, 0, 0, 7, b'B', 0, 0, 0, 100, // Order 7 buys 100
+        b'A', b'A', b'P', b'L', b' ', b' ', b' ', b' ', 0, 0x0f, 0x42, 0x40, // AAPL at 100.0000
+    ];
+    pub(crate) const ADD_SELL_MPID: &[u8] = &[
+        0, 40, b'F', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 8, b'S', 0, 0, 0, 50, //
+        b'A', b'A', b'P', b'L', b' ', b' ', b' ', b' ', 0, 0x0f, 0x69, 0x50, // At 101.0000
+        b'N', b'S', b'D', b'Q', // Attribution
+    ];
+    pub(crate) const EXECUTE: &[u8] = &[
+        0, 31, b'E', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 0, 9, // 30 of order 7, match 9
+    ];
+    pub(crate) const EXECUTE_AT_PRICE: &[u8] = &[
+        0, 36, b'C', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 10, b'Y', 0, 0x0f, 0x3e, 0x58, // 20 at 99.9000
+    ];
+    pub(crate) const CANCEL: &[u8] = &[
+        0, 23, b'X', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 10, // 10 of order 7
+    ];
+    pub(crate) const DELETE: &[u8] = &[
+        0, 19, b'D', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 8, // Order 8
+    ];
+    pub(crate) const REPLACE: &[u8] = &[
+        0, 35, b'U', 0, 1, 0, 0, 0, 1, 2, 3, 4, 5, //
+        0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 11, 0, 0, 0, 25, 0, 0x0f, 0x3e, 0x58, // 7 becomes 11, 25 at 99.9000
+    ];
+    pub(crate) const SYSTEM_EVENT: &[u8] = &[
+        0, 12, b'S', 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, b'O', // Start of messages
+    ];
+
+    pub(crate) fn feed(messages: &[&[u8]]) -> Vec<u8> {
+        messages.concat()
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let file = feed(&[SYSTEM_EVENT, ADD_BUY, ADD_SELL_MPID, EXECUTE, EXECUTE_AT_PRICE, CANCEL, DELETE, REPLACE]);
+        let messages: Vec<ItchMessage> = ItchReader::new(file.as_slice()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(messages.len(), 8);
+        assert_eq!(
+            messages[1],
+            ItchMessage {
+                stock_locate: 1,
+                timestamp: 0x0001_0203_0405,
+                body: ItchBody::AddOrder { order_ref: 7, is_bid: true, shares: 100, stock: *b"AAPL    ", price: 1_000_000 },
+            }
+        );
+        let bodies: Vec<ItchBody> = messages.iter().map(|message| message.body).collect();
+        assert_eq!(
+            bodies,
+            vec![
+                ItchBody::Other(b'S'),
+                bodies[1],
+                ItchBody::AddOrder { order_ref: 8, is_bid: false, shares: 50, stock: *b"AAPL    ", price: 1_010_000 },
+                ItchBody::OrderExecuted { order_ref: 7, shares: 30, match_number: 9 },
+                ItchBody::OrderExecutedWithPrice { order_ref: 7, shares: 20, match_number: 10, printable: true, price: 999_000 },
+                ItchBody::OrderCancelled { order_ref: 7, shares: 10 },
+                ItchBody::OrderDeleted { order_ref: 8 },
+                ItchBody::OrderReplaced { order_ref: 7, new_order_ref: 11, shares: 25, price: 999_000 },
+            ]
+        );
+        assert_eq!(stock_symbol(b"AAPL    "), "AAPL");
+        assert_eq!(messages[0].stock_locate, 0);
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        // A message shorter than its type, a bad side, and files cut off mid-prefix or mid-message
+        assert_eq!(parse_message(&EXECUTE[2..30]), Err("'E' message of 28 bytes, expected 31".to_string()));
+        let mut bad_side = ADD_BUY[2..].to_vec();
+        bad_side[19] = b'Q';
+        assert_eq!(parse_message(&bad_side), Err("side 'Q' is neither B nor S".to_string()));
+        assert_eq!(parse_message(&[]), Err("empty message".to_string()));
+
+        let errors = [
+            (feed(&[ADD_BUY, &[0]]), "Byte 38: file ends inside a length prefix"),
+            (feed(&[ADD_BUY, &EXECUTE[..20]]), "Byte 38: file ends inside a message of 31 bytes"),
+        ];
+        for (file, expected) in errors {
+            let mut reader = ItchReader::new(file.as_slice());
+            assert!(reader.next().unwrap().is_ok());
+            assert_eq!(reader.next().unwrap().unwrap_err().to_string(), expected);
+            assert!(reader.next().is_none());
+        }
+        assert!(ItchReader::new(&[][..]).next().is_none());
+    }
+}
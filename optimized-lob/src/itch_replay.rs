@@ -0,0 +1,322 @@
+// itch_replay.rs
+
+use crate::{
+    itch::{stock_symbol, ItchBody, ItchError, ItchMessage, ItchReader},
+    order::OrderId,
+    orderbook_manager::{OrderBookError, OrderBookManager},
+    quantity::Qty,
+    replay::Pace,
+    throughput_latency_test::LatencyPercentiles,
+    utils::BookId,
+};
+use hdrhistogram::Histogram;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Where the engine's view of the book parts from what the feed says happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// The feed acts on an order the engine has no resting order for
+    UnknownOrder,
+    /// The feed executes or cancels more of an order than the engine has left of it
+    QtyExceedsRemaining { feed: u64, engine: u64 },
+    /// The engine refused a message for another reason, like a duplicate order reference
+    Rejected(OrderBookError),
+}
+
+/// A message the engine couldn't apply as the feed recorded it; the message is skipped
+/// `message` counts the feed's messages from 1, those outside the subset included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub message: u64,
+    pub order_ref: u64,
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Message {}, order {}: ", self.message, self.order_ref)?;
+        match &self.kind {
+            DivergenceKind::UnknownOrder => write!(f, "not resting in the engine"),
+            DivergenceKind::QtyExceedsRemaining { feed, engine } => {
+                write!(f, "the feed takes {} shares, the engine has {} left", feed, engine)
+            }
+            DivergenceKind::Rejected(error) => write!(f, "rejected: {}", error),
+        }
+    }
+}
+
+/// The state a book ends a replay in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookSummary {
+    pub book_id: BookId, // The stock locate of its messages
+    pub stock: String,
+    pub bid_levels: usize,
+    pub ask_levels: usize,
+    pub best_bid: Option<u32>,
+    pub best_ask: Option<u32>,
+    pub checksum: u32, // Of every level, see orderbook::checksum_levels
+}
+
+#[derive(Debug, Clone)]
+pub struct ItchReplayReport {
+    pub messages: u64, // Every message read, those outside the subset included
+    pub applied: u64,  // Messages of the subset the engine applied
+    pub divergences: u64,
+    pub elapsed: Duration,
+    pub throughput: f64, // Messages read per second
+    pub latency: LatencyPercentiles, // Of each applied message
+    pub books: Vec<BookSummary>, // By book ID
+}
+
+impl fmt::Display for ItchReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let price = |price: Option<u32>| price.map_or("-".to_string(), |price| price.to_string());
+        writeln!(f, "ITCH REPLAY")?;
+        writeln!(f, "===========")?;
+        writeln!(f, "Messages: {} ({} applied)", self.messages, self.applied)?;
+        writeln!(f, "Divergences: {}", self.divergences)?;
+        writeln!(f, "Total Time: {:?}", self.elapsed)?;
+        writeln!(f, "Throughput: {:.2} messages/second", self.throughput)?;
+        let latency = &self.latency;
+        writeln!(
+            f,
+            "Latency: p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            latency.p50, latency.p90, latency.p99, latency.p999, latency.max
+        )?;
+        writeln!(f, "Books:")?;
+        for book in &self.books {
+            writeln!(
+                f,
+                "  {} {}: {} bids, {} asks, best {} / {}, checksum {:08x}",
+                book.book_id.value(),
+                book.stock,
+                book.bid_levels,
+                book.ask_levels,
+                price(book.best_bid),
+                price(book.best_ask),
+                book.checksum
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Drives an OrderBookManager with the order messages of an ITCH feed
+/// Each stock locate is a book, and each order reference the OrderId of its order. Executions
+/// and cancels are checked against the quantity the engine has left, so a feed known to be
+/// good doubles as a check of the book: any divergence is a bug on one side or the other.
+pub struct ItchReplayer {
+    pub manager: OrderBookManager,
+    stocks: BTreeMap<BookId, String>,
+    latency: Histogram<u64>,
+    messages: u64,
+    applied: u64,
+    divergences: u64,
+}
+
+impl Default for ItchReplayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ItchReplayer {
+    pub fn new() -> Self {
+        Self {
+            manager: OrderBookManager::auto_creating(),
+            stocks: BTreeMap::new(),
+            latency: Histogram::new_with_bounds(1, 3_600_000_000_000, 3).unwrap(),
+            messages: 0,
+            applied: 0,
+            divergences: 0,
+        }
+    }
+
+    /// Applies one message, timing it if it is of the subset
+    pub fn apply(&mut self, message: &ItchMessage) -> Result<(), Divergence> {
+        self.messages += 1;
+        let Some(order_ref) = message.body.order_ref() else { return Ok(()) };
+        let started = Instant::now();
+        let result = self.apply_order_message(message);
+        let _ = self.latency.record((started.elapsed().as_nanos() as u64).max(1));
+        match result {
+            Ok(()) => {
+                self.applied += 1;
+                Ok(())
+            }
+            Err(kind) => {
+                self.divergences += 1;
+                Err(Divergence { message: self.messages, order_ref, kind })
+            }
+        }
+    }
+
+    fn apply_order_message(&mut self, message: &ItchMessage) -> Result<(), DivergenceKind> {
+        let manager = &mut self.manager;
+        match message.body {
+            ItchBody::AddOrder { order_ref, is_bid, shares, stock, price } => {
+                let book_id = BookId(message.stock_locate as u32);
+                self.stocks.entry(book_id).or_insert_with(|| stock_symbol(&stock));
+                manager.add_order(OrderId(order_ref), book_id, Qty(shares as u64), price, is_bid, None, None, None, None)?;
+            }
+            ItchBody::OrderExecuted { order_ref, shares, .. } => {
+                remaining(manager, order_ref, shares)?;
+                manager.execute_order(OrderId(order_ref), Qty(shares as u64))?;
+            }
+            ItchBody::OrderExecutedWithPrice { order_ref, shares, price, .. } => {
+                remaining(manager, order_ref, shares)?;
+                manager.execute_order_at(OrderId(order_ref), Qty(shares as u64), price)?;
+            }
+            ItchBody::OrderCancelled { order_ref, shares } => {
+                remaining(manager, order_ref, shares)?;
+                manager.cancel_order(OrderId(order_ref), Qty(shares as u64))?;
+            }
+            ItchBody::OrderDeleted { order_ref } => manager.remove_order(OrderId(order_ref))?,
+            ItchBody::OrderReplaced { order_ref, new_order_ref, shares, price } => {
+                manager.replace_order(OrderId(order_ref), OrderId(new_order_ref), Qty(shares as u64), price)?;
+            }
+            ItchBody::Other(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Replays every message of an ITCH file, calling `on_divergence` for each message the
+    /// engine couldn't apply; replay goes on after one. A malformed file stops it.
+    pub fn run(
+        &mut self,
+        reader: impl Read,
+        pace: Pace,
+        mut on_divergence: impl FnMut(&Divergence),
+    ) -> Result<ItchReplayReport, ItchError> {
+        let started = Instant::now();
+        let mut first_timestamp = None;
+        for message in ItchReader::new(reader) {
+            let message = message?;
+            if pace == Pace::Recorded {
+                let first = *first_timestamp.get_or_insert(message.timestamp);
+                let due = Duration::from_nanos(message.timestamp.saturating_sub(first));
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    std::thread::sleep(wait);
+                }
+            }
+            if let Err(divergence) = self.apply(&message) {
+                on_divergence(&divergence);
+            }
+        }
+        Ok(self.report(started.elapsed()))
+    }
+
+    /// Sums up the replay so far, and the state of each book it touched
+    pub fn report(&self, elapsed: Duration) -> ItchReplayReport {
+        let books = self
+            .stocks
+            .iter()
+            .filter_map(|(&book_id, stock)| {
+                let book = self.manager.book(book_id)?;
+                let best = |is_bid| book.iter_levels(is_bid).next().map(|level| level.price().absolute() as u32);
+                Some(BookSummary {
+                    book_id,
+                    stock: stock.clone(),
+                    bid_levels: book.iter_levels(true).count(),
+                    ask_levels: book.iter_levels(false).count(),
+                    best_bid: best(true),
+                    best_ask: best(false),
+                    checksum: book.checksum(usize::MAX),
+                })
+            })
+            .collect();
+        ItchReplayReport {
+            messages: self.messages,
+            applied: self.applied,
+            divergences: self.divergences,
+            elapsed,
+            throughput: self.messages as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+            latency: LatencyPercentiles::of(&self.latency),
+            books,
+        }
+    }
+}
+
+/// Checks the engine has at least `shares` left of an order
+fn remaining(manager: &OrderBookManager, order_ref: u64, shares: u32) -> Result<(), DivergenceKind> {
+    let order = manager.oid_map.get(OrderId(order_ref)).ok_or(DivergenceKind::UnknownOrder)?;
+    let engine = order.qty().value();
+    if shares as u64 > engine {
+        return Err(DivergenceKind::QtyExceedsRemaining { feed: shares as u64, engine });
+    }
+    Ok(())
+}
+
+impl From<OrderBookError> for DivergenceKind {
+    fn from(error: OrderBookError) -> Self {
+        match error {
+            OrderBookError::UnknownOrder => DivergenceKind::UnknownOrder,
+            error => DivergenceKind::Rejected(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::itch::tests::*;
+    use crate::orderbook::checksum_levels;
+
+    fn replay(file: &[u8]) -> (ItchReplayer, ItchReplayReport, Vec<Divergence>) {
+        let mut replayer = ItchReplayer::new();
+        let mut divergences = Vec::new();
+        let report = replayer.run(file, Pace::Fast, |divergence| divergences.push(divergence.clone())).unwrap();
+        (replayer, report, divergences)
+    }
+
+    #[test]
+    fn test_itch_replay() {
+        let file = feed(&[SYSTEM_EVENT, ADD_BUY, ADD_SELL_MPID, EXECUTE, EXECUTE_AT_PRICE, CANCEL, DELETE, REPLACE]);
+        let (replayer, report, divergences) = replay(&file);
+        assert_eq!(divergences, vec![]);
+        assert_eq!((report.messages, report.applied, report.divergences), (8, 7, 0));
+
+        // Order 7 bought 100, of which 30 and 20 executed and 10 was cancelled; it became order 11
+        // for 25 at 99.9000, and order 8 was deleted
+        let manager = &replayer.manager;
+        assert!(manager.oid_map.get(OrderId(7)).is_none() && manager.oid_map.get(OrderId(8)).is_none());
+        assert_eq!(manager.oid_map.get(OrderId(11)).map(|order| order.qty()), Some(Qty(25)));
+        assert_eq!(
+            report.books,
+            vec![BookSummary {
+                book_id: BookId(1),
+                stock: "AAPL".to_string(),
+                bid_levels: 1,
+                ask_levels: 0,
+                best_bid: Some(999_000),
+                best_ask: None,
+                checksum: checksum_levels([(999_000, 25)].into_iter(), std::iter::empty()),
+            }]
+        );
+        assert!(report.to_string().contains("1 AAPL: 1 bids, 0 asks, best 999000 / -"));
+    }
+
+    #[test]
+    fn test_itch_replay_divergences() {
+        // Order 7 is down to 10 after executions of 30, 30 and 20 and a cancel of 10, so an
+        // execution of 30 more diverges; cancelling the last 10 leaves nothing to execute
+        let file = feed(&[ADD_BUY, EXECUTE, EXECUTE, ADD_BUY, DELETE, CANCEL, EXECUTE_AT_PRICE, EXECUTE, CANCEL, EXECUTE]);
+        let (_, report, divergences) = replay(&file);
+        let expected = vec![
+            Divergence {
+                message: 4,
+                order_ref: 7,
+                kind: DivergenceKind::Rejected(OrderBookError::DuplicateOrder(OrderId(7))),
+            },
+            Divergence { message: 5, order_ref: 8, kind: DivergenceKind::UnknownOrder },
+            Divergence { message: 8, order_ref: 7, kind: DivergenceKind::QtyExceedsRemaining { feed: 30, engine: 10 } },
+            Divergence { message: 10, order_ref: 7, kind: DivergenceKind::UnknownOrder },
+        ];
+        assert_eq!((report.applied, report.divergences), (6, 4));
+        assert_eq!(divergences, expected);
+        assert_eq!(divergences[2].to_string(), "Message 8, order 7: the feed takes 30 shares, the engine has 10 left");
+    }
+}
@@ -16,11 +16,15 @@ impl LevelId {
 }
 
 /// Represents the Level for a price.
-/// It stores the price and total capacity of the level.
+/// It stores the price and total capacity of the level, how many orders rest on it, and the
+/// ends of its queue of orders as slots of the OrderPool they rest in.
 #[derive(Debug, Clone)]
 pub struct Level {
     price: Price,
     size: Qty,
+    order_count: u32,
+    head: Option<u32>,
+    tail: Option<u32>,
 }
 
 impl Default for Level {
@@ -29,6 +33,9 @@ impl Default for Level {
         Self {
             price: Price(0),
             size: Qty(0),
+            order_count: 0,
+            head: None,
+            tail: None,
         }
     }
 }
@@ -36,7 +43,7 @@ impl Default for Level {
 impl Level {
     #[inline]
     pub fn new(price: Price, size: Qty) -> Self {
-        Self { price, size }
+        Self { price, size, order_count: 0, head: None, tail: None }
     }
 
     #[inline]
@@ -49,6 +56,12 @@ impl Level {
         self.size
     }
 
+    /// Gets the number of orders resting on the level.
+    #[inline]
+    pub fn order_count(&self) -> u32 {
+        self.order_count
+    }
+
     #[inline]
     pub fn set_price(&mut self, price: Price) {
         self.price = price
@@ -59,14 +72,54 @@ impl Level {
         self.size = size
     }
 
+    /// Gets the slot of the order at the front of the queue, the next to match.
+    #[inline]
+    pub fn head(&self) -> Option<u32> {
+        self.head
+    }
+
+    /// Gets the slot of the order at the back of the queue, the last to arrive.
+    #[inline]
+    pub fn tail(&self) -> Option<u32> {
+        self.tail
+    }
+
+    #[inline]
+    pub fn set_head(&mut self, head: Option<u32>) {
+        self.head = head
+    }
+
+    #[inline]
+    pub fn set_tail(&mut self, tail: Option<u32>) {
+        self.tail = tail
+    }
+
+    /// Adds `size` to the level, returning the new size.
+    /// Returns None, leaving the level unchanged, if the size would overflow.
+    #[inline]
+    pub fn incr(&mut self, size: Qty) -> Option<Qty> {
+        self.size = self.size.checked_add(size)?;
+        Some(self.size)
+    }
+
+    /// Counts an order joining the level.
+    #[inline]
+    pub fn incr_count(&mut self) {
+        self.order_count += 1;
+    }
+
+    /// Counts an order leaving the level.
     #[inline]
-    pub fn incr(&mut self, size: Qty) {
-        self.size += size
+    pub fn decr_count(&mut self) {
+        self.order_count = self.order_count.saturating_sub(1);
     }
 
+    /// Takes `size` off the level, returning the new size.
+    /// Returns None, leaving the level unchanged, if the level holds less than `size`.
     #[inline]
-    pub fn decr(&mut self, size: Qty) {
-        self.size -= size
+    pub fn decr(&mut self, size: Qty) -> Option<Qty> {
+        self.size = self.size.checked_sub(size)?;
+        Some(self.size)
     }
 }
 
@@ -165,15 +218,17 @@ impl SortedLevels {
     }
 
     /// Gets the best price in the level
+    /// Levels are sorted ascending by signed price, so the best bid (highest positive)
+    /// and the best ask (least negative) are both at the end.
     #[inline]
     pub fn get_best_price(&self) -> Option<Price> {
-        self.0.first().map(|level| level.price())
+        self.0.last().map(|level| level.price())
     }
 
     /// Gets the level ID of the best price
     #[inline]
     pub fn get_best_level(&self) -> Option<LevelId> {
-        self.0.first().map(|level| level.level_id())
+        self.0.last().map(|level| level.level_id())
     }
 
     pub fn iter(&self) -> std::slice::Iter<'_, PriceLevel> {
@@ -1,14 +1,73 @@
 pub mod level;
 pub mod order;
+pub mod order_intake;
+pub mod book_registry;
+pub mod nonce_registry;
 pub mod orderbook;
 pub mod orderbook_manager;
 pub mod pool;
 pub mod price;
 pub mod quantity;
 pub mod utils;
+pub mod config;
 pub mod matching;
+pub mod engine;
+pub mod metrics;
+pub mod auction;
+pub mod stops;
+pub mod pegs;
+pub mod oco;
+pub mod mass_quote;
+pub mod rfq;
+pub mod positions;
+pub mod expiry;
+pub mod funds;
+pub mod risk;
 pub mod translator;
+pub mod settlement_manager;
+pub mod settlement_batcher;
+pub mod settlement_submitter;
+pub mod trade_tape;
+pub mod accounting;
+#[cfg(feature = "sqlite")]
+pub mod trade_history;
+pub mod export;
+pub mod stats;
+pub mod candles;
+pub mod client_order_ids;
+pub mod command_queue;
+pub mod sequencer;
+pub mod sharded;
+pub mod wal;
+pub mod snapshot;
+pub mod replay;
+pub mod itch;
+pub mod itch_replay;
+pub mod stress;
 pub mod market;
+pub mod market_data;
+pub mod events;
+pub mod audit;
+pub mod bus;
+pub mod order_updates;
+pub mod abi;
+pub mod auth;
+pub mod eip712;
+pub mod eip1271;
 pub mod throughput_latency_test;
-
-
+#[cfg(feature = "server")]
+pub mod api;
+#[cfg(feature = "server")]
+pub mod api_audit;
+#[cfg(feature = "server")]
+pub mod api_auth;
+#[cfg(feature = "server")]
+pub mod api_error;
+#[cfg(feature = "server")]
+pub mod api_idempotency;
+#[cfg(feature = "fix")]
+pub mod fix;
+#[cfg(feature = "fix")]
+pub mod fix_gateway;
+#[cfg(feature = "grpc")]
+pub mod grpc;
@@ -1,21 +1,93 @@
-mod api;
-mod book_registry;
-mod level;
-mod order;
-mod order_intake;
-mod utils;
-mod orderbook_manager;
-mod market;
-mod price;
-mod quantity;
-mod matching;
-mod orderbook;
-mod pool;
+use optimized_lob::{
+    api,
+    config::Config,
+    eip1271::{ContractSignatureVerifier, JsonRpcClient},
+    engine::recover,
+    funds::{FundsChecker, RpcChainClient},
+    settlement_submitter::SettlementSubmitter,
+};
+use k256::ecdsa::SigningKey;
+use std::sync::Arc;
+
+/// Startup flag to restore the newest snapshot before replaying the WAL
+const RESTORE_SNAPSHOT_FLAG: &str = "--restore-snapshot";
+/// Startup flag naming the TOML configuration file, as `--config <path>` or `--config=<path>`
+const CONFIG_FLAG: &str = "--config";
+
+/// Gets the configuration file named on the command line, if any
+fn config_path(args: &[String]) -> Option<std::path::PathBuf> {
+    let flag = format!("{}=", CONFIG_FLAG);
+    args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix(&flag) {
+        Some(path) => Some(path.into()),
+        None => (arg == CONFIG_FLAG).then(|| args.get(i + 1).map(Into::into)).flatten(),
+    })
+}
+
+/// Resolves once the process is asked to stop, by Ctrl-C or, on Unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+    tracing::info!("Shutdown requested");
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    // Initialize your orderbook and other components here
-    
-    // Start the API server
-    api::start_server().await
+    let args: Vec<String> = std::env::args().collect();
+    let config = Config::load(config_path(&args).as_deref()).map_err(|error| std::io::Error::other(error.to_string()))?;
+    // tracing events reach env_logger through tracing's log feature, filtered by the same directives
+    env_logger::Builder::new().parse_filters(&config.server.log_level).init();
+    tracing::info!("Effective configuration:\n{}", config);
+    let restore_snapshot = args.iter().any(|arg| arg == RESTORE_SNAPSHOT_FLAG);
+
+    let rpc_url = config.settlement.rpc_url.clone();
+    let signature_verifier = rpc_url
+        .clone()
+        .map(|url| ContractSignatureVerifier::new(Arc::new(JsonRpcClient::new(url))));
+    let funds_checker = rpc_url
+        .clone()
+        .map(|url| FundsChecker::new(Arc::new(RpcChainClient::new(Arc::new(JsonRpcClient::new(url))))));
+
+    // Settlements stay Pending unless there is both a node and an account to submit them from
+    let settlement_submitter = match (rpc_url, &config.settlement.operator_key) {
+        (Some(url), Some(key)) => {
+            let key = hex::decode(key.trim().trim_start_matches("0x"))
+                .ok()
+                .and_then(|bytes| SigningKey::from_slice(&bytes).ok())
+                .ok_or_else(|| std::io::Error::other("settlement.operator_key is not a valid private key"))?;
+            let submitter = SettlementSubmitter::new(Arc::new(JsonRpcClient::new(url)), key, config.settlement.submitter_config());
+            tracing::info!(operator = %format!("0x{}", hex::encode(submitter.operator_address())), "Submitting settlements");
+            Some(submitter)
+        }
+        _ => None,
+    };
+
+    // Start the API server; it reports not ready until the engine is recovered
+    // Runs until a shutdown signal, then drains requests and flushes state before returning
+    let recover_config = config.clone();
+    api::start_server(
+        move |book_registry| recover(&recover_config, restore_snapshot, book_registry),
+        &config,
+        signature_verifier,
+        funds_checker,
+        settlement_submitter,
+        shutdown_signal(),
+    )
+    .await
 } 
\ No newline at end of file
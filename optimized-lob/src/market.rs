@@ -1,38 +1,502 @@
-use crate::utils::BookId;
+use crate::{
+    eip712::Eip712Domain,
+    price::PriceScale,
+    quantity::Qty,
+    risk::notional,
+    utils::{hex_array, BookId, DEFAULT_MAX_OPEN_ORDERS},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Signature types, numbered as in the 0x protocol's SignatureType enum
+pub const SIGNATURE_TYPE_EIP712: u8 = 2;
+/// Orders may also come from contract wallets, verified with EIP-1271 `isValidSignature`
+pub const SIGNATURE_TYPE_EIP1271: u8 = 7;
 
 /// Configuration for a specific trading pair/market
-#[derive(Debug, Clone)]
+/// Fields missing from JSON take their MarketConfigBuilder defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct MarketConfig {
+    #[serde(with = "hex_array")]
     pub base_token: [u8; 20],     // e.g. USDC
+    #[serde(with = "hex_array")]
     pub security_token: [u8; 20],  // e.g. ETH
+    #[serde(with = "hex_array")]
     pub fee_recipient: [u8; 20],
+    #[serde(with = "hex_array")]
     pub pool: [u8; 20],
     pub signature_type: u8,
+    // Fees in basis points of what each side receives, withheld for fee_recipient
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+    // Rebate makers earn, in basis points of the notional they trade; accrued in the volume ledger, not settled
+    pub maker_rebate_bps: u16,
+    // Decimals of the tokens, and decimal places of the book's u32 prices (base per whole security).
+    // Book quantities are whole security tokens. All zero settles raw book units.
+    pub base_decimals: u8,
+    pub security_decimals: u8,
+    pub price_decimals: u8,
+    // Book prices must be whole multiples of this, in book units; zero is taken as one
+    pub tick_size: u32,
+    // EIP-712 domain of the settlement contract, used for order signatures and settlement
+    pub name: String,
+    pub version: String,
+    pub chain_id: u64,
+    #[serde(with = "hex_array")]
+    pub verifying_contract: [u8; 20],
+    // Incoming orders priced outside this band around the reference price are refused
+    pub price_band: Option<PriceBand>,
+    // Keep each trader's position from fills, which reduce-only orders are held to
+    pub track_positions: bool,
+    // Caps on what each trader may have resting in the book
+    pub risk_limits: RiskLimits,
+    // Smallest orders the book takes, and the step their quantities move in
+    pub size_rules: SizeRules,
+    // Accept orders without checking on-chain that their trader can pay for them
+    pub skip_funds_check: bool,
+    // Refuse orders signed with a zero nonce
+    pub require_nonce: bool,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        let domain = Eip712Domain::default();
+        Self {
+            base_token: [0; 20],
+            security_token: [0; 20],
+            fee_recipient: [0; 20],
+            pool: [0; 20],
+            signature_type: SIGNATURE_TYPE_EIP712,
+            maker_fee_bps: 0,
+            taker_fee_bps: 0,
+            maker_rebate_bps: 0,
+            base_decimals: 0,
+            security_decimals: 0,
+            price_decimals: 0,
+            tick_size: 1,
+            name: domain.name,
+            version: domain.version,
+            chain_id: domain.chain_id,
+            verifying_contract: domain.verifying_contract,
+            price_band: None,
+            track_positions: false,
+            risk_limits: RiskLimits::default(),
+            size_rules: SizeRules::default(),
+            skip_funds_check: false,
+            require_nonce: false,
+        }
+    }
+}
+
+impl MarketConfig {
+    /// Starts a MarketConfig from the defaults
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::market::MarketConfig;
+    /// let config = MarketConfig::builder()
+    ///     .base_token([1; 20])
+    ///     .security_token([2; 20])
+    ///     .chain_id(137)
+    ///     .build();
+    /// assert_eq!(config.domain().chain_id, 137);
+    /// ```
+    pub fn builder() -> MarketConfigBuilder {
+        MarketConfigBuilder::new()
+    }
+
+    /// Gets the EIP-712 domain orders in this market are signed under
+    pub fn domain(&self) -> Eip712Domain {
+        Eip712Domain {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            chain_id: self.chain_id,
+            verifying_contract: self.verifying_contract,
+        }
+    }
+
+    /// Gets how prices in this market are written in decimal, and the ticks they fall on
+    pub fn price_scale(&self) -> PriceScale {
+        PriceScale::new(self.price_decimals, self.tick_size)
+    }
+}
+
+/// Caps on what one trader may have resting in a market at once
+/// Incoming orders that would take their trader past either cap are refused. The defaults allow
+/// DEFAULT_MAX_OPEN_ORDERS orders and any notional, since notional depends on the market's units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskLimits {
+    pub max_open_orders: u32,
+    pub max_open_notional: u64, // Sum of price × remaining quantity over the trader's resting orders
+}
+
+impl Default for RiskLimits {
+    fn default() -> Self {
+        Self { max_open_orders: DEFAULT_MAX_OPEN_ORDERS, max_open_notional: u64::MAX }
+    }
+}
+
+/// Smallest orders a market takes, and the step their quantities move in
+/// Orders under either minimum, or not a whole number of lots, are refused; quantities are never
+/// rounded onto a lot. The defaults take any order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SizeRules {
+    pub min_qty: u64,
+    pub lot_size: u64,     // Quantities must be whole multiples of it; zero is taken as one
+    pub min_notional: u64, // Smallest price × quantity, in book units
+}
+
+impl Default for SizeRules {
+    fn default() -> Self {
+        Self { min_qty: 1, lot_size: 1, min_notional: 0 }
+    }
+}
+
+/// A size rule an order broke, with the rule's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SizeRule {
+    MinQty(u64),
+    LotSize(u64),
+    MinNotional(u64),
+}
+
+impl fmt::Display for SizeRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SizeRule::MinQty(min) => write!(f, "quantity of at least {}", min),
+            SizeRule::LotSize(lot) => write!(f, "quantity in whole lots of {}", lot),
+            SizeRule::MinNotional(min) => write!(f, "notional of at least {}", min),
+        }
+    }
+}
+
+impl SizeRules {
+    /// Checks an order of `qty` at `price` against the rules, lot size first
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{market::{SizeRule, SizeRules}, quantity::Qty};
+    /// let rules = SizeRules { min_qty: 10, lot_size: 5, min_notional: 1000 };
+    /// assert_eq!(rules.check(100, Qty(10)), Ok(()));
+    /// assert_eq!(rules.check(100, Qty(12)), Err(SizeRule::LotSize(5)));
+    /// assert_eq!(rules.check(100, Qty(5)), Err(SizeRule::MinQty(10)));
+    /// assert_eq!(rules.check(99, Qty(10)), Err(SizeRule::MinNotional(1000)));
+    /// ```
+    pub fn check(&self, price: u32, qty: Qty) -> Result<(), SizeRule> {
+        if !qty.value().is_multiple_of(self.lot_size.max(1)) {
+            return Err(SizeRule::LotSize(self.lot_size));
+        }
+        if qty.value() < self.min_qty {
+            return Err(SizeRule::MinQty(self.min_qty));
+        }
+        if notional(price, qty) < self.min_notional {
+            return Err(SizeRule::MinNotional(self.min_notional));
+        }
+        Ok(())
+    }
+}
+
+/// Width of a market's price band on either side of its reference price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceBand {
+    Bps(u32),   // Basis points of the reference price, rounded down
+    Ticks(u32), // Absolute price units
+}
+
+impl PriceBand {
+    /// Gets the lowest and highest prices inside the band around `reference`, both inclusive
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::market::PriceBand;
+    /// assert_eq!(PriceBand::Bps(500).limits(1000), (950, 1050));
+    /// assert_eq!(PriceBand::Ticks(20).limits(10), (0, 30));
+    /// ```
+    pub fn limits(&self, reference: u32) -> (u32, u32) {
+        let width = match *self {
+            PriceBand::Bps(bps) => (reference as u64 * bps as u64 / 10_000).min(u32::MAX as u64) as u32,
+            PriceBand::Ticks(ticks) => ticks,
+        };
+        (reference.saturating_sub(width), reference.saturating_add(width))
+    }
+}
+
+/// Builds a MarketConfig, leaving unset fields at their defaults
+#[derive(Debug, Clone, Default)]
+pub struct MarketConfigBuilder {
+    config: MarketConfig,
+}
+
+impl MarketConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn base_token(mut self, base_token: [u8; 20]) -> Self {
+        self.config.base_token = base_token;
+        self
+    }
+
+    pub fn security_token(mut self, security_token: [u8; 20]) -> Self {
+        self.config.security_token = security_token;
+        self
+    }
+
+    pub fn fee_recipient(mut self, fee_recipient: [u8; 20]) -> Self {
+        self.config.fee_recipient = fee_recipient;
+        self
+    }
+
+    pub fn pool(mut self, pool: [u8; 20]) -> Self {
+        self.config.pool = pool;
+        self
+    }
+
+    pub fn signature_type(mut self, signature_type: u8) -> Self {
+        self.config.signature_type = signature_type;
+        self
+    }
+
+    pub fn maker_fee_bps(mut self, maker_fee_bps: u16) -> Self {
+        self.config.maker_fee_bps = maker_fee_bps;
+        self
+    }
+
+    pub fn taker_fee_bps(mut self, taker_fee_bps: u16) -> Self {
+        self.config.taker_fee_bps = taker_fee_bps;
+        self
+    }
+
+    pub fn maker_rebate_bps(mut self, maker_rebate_bps: u16) -> Self {
+        self.config.maker_rebate_bps = maker_rebate_bps;
+        self
+    }
+
+    pub fn base_decimals(mut self, base_decimals: u8) -> Self {
+        self.config.base_decimals = base_decimals;
+        self
+    }
+
+    pub fn security_decimals(mut self, security_decimals: u8) -> Self {
+        self.config.security_decimals = security_decimals;
+        self
+    }
+
+    pub fn price_decimals(mut self, price_decimals: u8) -> Self {
+        self.config.price_decimals = price_decimals;
+        self
+    }
+
+    pub fn tick_size(mut self, tick_size: u32) -> Self {
+        self.config.tick_size = tick_size;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = name.into();
+        self
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.config.version = version.into();
+        self
+    }
+
+    pub fn chain_id(mut self, chain_id: u64) -> Self {
+        self.config.chain_id = chain_id;
+        self
+    }
+
+    pub fn verifying_contract(mut self, verifying_contract: [u8; 20]) -> Self {
+        self.config.verifying_contract = verifying_contract;
+        self
+    }
+
+    pub fn price_band(mut self, price_band: PriceBand) -> Self {
+        self.config.price_band = Some(price_band);
+        self
+    }
+
+    pub fn track_positions(mut self, track_positions: bool) -> Self {
+        self.config.track_positions = track_positions;
+        self
+    }
+
+    pub fn risk_limits(mut self, risk_limits: RiskLimits) -> Self {
+        self.config.risk_limits = risk_limits;
+        self
+    }
+
+    pub fn size_rules(mut self, size_rules: SizeRules) -> Self {
+        self.config.size_rules = size_rules;
+        self
+    }
+
+    pub fn skip_funds_check(mut self, skip_funds_check: bool) -> Self {
+        self.config.skip_funds_check = skip_funds_check;
+        self
+    }
+
+    pub fn require_nonce(mut self, require_nonce: bool) -> Self {
+        self.config.require_nonce = require_nonce;
+        self
+    }
+
+    /// Sets all four domain fields at once
+    pub fn domain(self, domain: Eip712Domain) -> Self {
+        self.name(domain.name)
+            .version(domain.version)
+            .chain_id(domain.chain_id)
+            .verifying_contract(domain.verifying_contract)
+    }
+
+    pub fn build(self) -> MarketConfig {
+        self.config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarketError {
+    /// The book already has a market configuration and overwriting was not asked for.
+    MarketExists(BookId),
+    /// The book has no market configuration.
+    UnknownMarket(BookId),
+}
+
+impl fmt::Display for MarketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarketError::MarketExists(book_id) => {
+                write!(f, "Book {} already has a market configuration", book_id.value())
+            }
+            MarketError::UnknownMarket(book_id) => {
+                write!(f, "Book {} has no market configuration", book_id.value())
+            }
+        }
+    }
 }
 
 /// Manages market configurations for different book IDs
+/// Configurations are keyed by BookId, so memory grows with the number of markets, not their ids.
 pub struct MarketManager {
-    configs: Vec<Option<MarketConfig>>,
+    configs: BTreeMap<BookId, MarketConfig>,
+}
+
+impl Default for MarketManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MarketManager {
     pub fn new() -> Self {
         Self {
-            configs: Vec::new(),
+            configs: BTreeMap::new(),
         }
     }
 
-    pub fn add_market(&mut self, book_id: BookId, config: MarketConfig) {
-        let idx = book_id.value() as usize;
-        if idx >= self.configs.len() {
-            self.configs.resize(idx + 1, None);
+    /// Sets the market configuration of `book_id`.
+    /// Fails with MarketExists if the book already has one, unless `overwrite` is set.
+    pub fn add_market(&mut self, book_id: BookId, config: MarketConfig, overwrite: bool) -> Result<(), MarketError> {
+        if !overwrite && self.configs.contains_key(&book_id) {
+            return Err(MarketError::MarketExists(book_id));
         }
-        self.configs[idx] = Some(config);
+        self.configs.insert(book_id, config);
+        Ok(())
+    }
+
+    /// Removes the market configuration of `book_id`, returning it if there was one.
+    pub fn remove_market(&mut self, book_id: BookId) -> Option<MarketConfig> {
+        self.configs.remove(&book_id)
+    }
+
+    /// Replaces the price band of `book_id`'s market; None lifts it.
+    /// Fails with UnknownMarket if the book has no market configuration.
+    pub fn set_price_band(&mut self, book_id: BookId, price_band: Option<PriceBand>) -> Result<(), MarketError> {
+        let config = self.configs.get_mut(&book_id).ok_or(MarketError::UnknownMarket(book_id))?;
+        config.price_band = price_band;
+        Ok(())
+    }
+
+    /// Replaces the risk limits of `book_id`'s market.
+    /// Fails with UnknownMarket if the book has no market configuration.
+    pub fn set_risk_limits(&mut self, book_id: BookId, risk_limits: RiskLimits) -> Result<(), MarketError> {
+        let config = self.configs.get_mut(&book_id).ok_or(MarketError::UnknownMarket(book_id))?;
+        config.risk_limits = risk_limits;
+        Ok(())
+    }
+
+    /// Replaces the size rules of `book_id`'s market.
+    /// Fails with UnknownMarket if the book has no market configuration.
+    pub fn set_size_rules(&mut self, book_id: BookId, size_rules: SizeRules) -> Result<(), MarketError> {
+        let config = self.configs.get_mut(&book_id).ok_or(MarketError::UnknownMarket(book_id))?;
+        config.size_rules = size_rules;
+        Ok(())
     }
 
     pub fn get_config(&self, book_id: BookId) -> Option<&MarketConfig> {
-        self.configs
-            .get(book_id.value() as usize)
-            .and_then(|config| config.as_ref())
+        self.configs.get(&book_id)
     }
-} 
\ No newline at end of file
+
+    /// Lists every configured market in BookId order
+    pub fn list_markets(&self) -> Vec<(BookId, &MarketConfig)> {
+        self.configs.iter().map(|(&book_id, config)| (book_id, config)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markets_by_book_id() {
+        let mut manager = MarketManager::new();
+        let eth = MarketConfig::builder().base_token([1; 20]).build();
+        let far = MarketConfig::builder().base_token([2; 20]).build();
+
+        // A BookId near the top of the range costs one entry, not billions of empty slots
+        manager.add_market(BookId(u32::MAX), far.clone(), false).unwrap();
+        manager.add_market(BookId(0), eth.clone(), false).unwrap();
+        assert_eq!(manager.list_markets(), vec![(BookId(0), &eth), (BookId(u32::MAX), &far)]);
+        assert_eq!(manager.get_config(BookId(u32::MAX)), Some(&far));
+        assert_eq!(manager.get_config(BookId(1)), None);
+
+        // An existing configuration is only replaced when asked to
+        assert_eq!(manager.add_market(BookId(0), far.clone(), false), Err(MarketError::MarketExists(BookId(0))));
+        assert_eq!(manager.get_config(BookId(0)), Some(&eth));
+        manager.add_market(BookId(0), far.clone(), true).unwrap();
+        assert_eq!(manager.get_config(BookId(0)), Some(&far));
+
+        assert_eq!(manager.remove_market(BookId(u32::MAX)), Some(far));
+        assert_eq!(manager.remove_market(BookId(u32::MAX)), None);
+        assert_eq!(manager.list_markets().len(), 1);
+    }
+
+    #[test]
+    fn test_size_rules() {
+        let rules = SizeRules { min_qty: 10, lot_size: 5, min_notional: 2000 };
+        // Exactly at each minimum is enough
+        assert_eq!(rules.check(200, Qty(10)), Ok(()));
+        assert_eq!(rules.check(100, Qty(20)), Ok(()));
+        assert_eq!(rules.check(u32::MAX, Qty(u64::MAX / 5 * 5)), Ok(()));
+
+        // Just under each one is not
+        assert_eq!(rules.check(1000, Qty(5)), Err(SizeRule::MinQty(10)));
+        assert_eq!(rules.check(199, Qty(10)), Err(SizeRule::MinNotional(2000)));
+        // Off the lot step is refused, never rounded, even past the minimums
+        for qty in [11, 14, 21, 1_000_001] {
+            assert_eq!(rules.check(1000, Qty(qty)), Err(SizeRule::LotSize(5)));
+        }
+        assert_eq!(SizeRule::LotSize(5).to_string(), "quantity in whole lots of 5");
+
+        // The defaults take any order, and a lot size of zero is taken as one
+        assert_eq!(SizeRules::default().check(1, Qty(1)), Ok(()));
+        assert_eq!(SizeRules { lot_size: 0, ..SizeRules::default() }.check(1, Qty(7)), Ok(()));
+    }
+}
@@ -0,0 +1,344 @@
+// market_data.rs
+
+use crate::{
+    auction::Uncross,
+    price::Price,
+    quantity::Qty,
+    trade_tape::Trade,
+    utils::{BookId, CHECKSUM_INTERVAL, MARKET_DATA_CHANNEL_CAPACITY},
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use tokio::sync::broadcast;
+
+/// Incremental market data published whenever a book mutates.
+/// Every event carries the sequence number of its book, which increases by one per change to
+/// the book's orders. Each change publishes one level update carrying the number before and
+/// after it, so subscribers can line updates up against a depth snapshot and resubscribe on
+/// a gap. Trades and checksums repeat the number of the change before them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketDataEvent {
+    /// A level's aggregate size changed; a size of zero means the level was removed.
+    LevelUpdate {
+        book_id: u32,
+        prev_seq: u64,
+        seq: u64,
+        side: &'static str,
+        price: u32,
+        size: u64,
+    },
+    /// An execution between a resting and an incoming order.
+    Trade {
+        book_id: u32,
+        seq: u64,
+        trade_id: u64,
+        timestamp: u64,
+        price: u32,
+        quantity: u64,
+        side: &'static str, // Aggressor side
+        maker_order_id: u64,
+        taker_order_id: u64,
+    },
+    /// Checksum of the book as of the event numbered `seq`, see `orderbook::checksum_levels`.
+    /// It takes no sequence number of its own.
+    Checksum {
+        book_id: u32,
+        seq: u64,
+        checksum: u32,
+    },
+    /// Where a book in an auction would uncross as of the event numbered `seq`; no price while
+    /// nothing crosses. It takes no sequence number of its own.
+    Indicative {
+        book_id: u32,
+        seq: u64,
+        price: Option<u32>,
+        volume: u64,
+        imbalance: i64,
+    },
+    /// The kill switch was engaged or released as of the event numbered `seq`; while halted the
+    /// book accepts only cancels. It takes no sequence number of its own.
+    Halt {
+        book_id: u32,
+        seq: u64,
+        halted: bool,
+    },
+}
+
+impl MarketDataEvent {
+    /// Gets the book the event belongs to.
+    #[inline]
+    pub fn book_id(&self) -> BookId {
+        match self {
+            MarketDataEvent::LevelUpdate { book_id, .. } => BookId(*book_id),
+            MarketDataEvent::Trade { book_id, .. } => BookId(*book_id),
+            MarketDataEvent::Checksum { book_id, .. } => BookId(*book_id),
+            MarketDataEvent::Indicative { book_id, .. } => BookId(*book_id),
+            MarketDataEvent::Halt { book_id, .. } => BookId(*book_id),
+        }
+    }
+
+    /// Gets the per-book sequence number of the event.
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        match self {
+            MarketDataEvent::LevelUpdate { seq, .. } => *seq,
+            MarketDataEvent::Trade { seq, .. } => *seq,
+            MarketDataEvent::Checksum { seq, .. } => *seq,
+            MarketDataEvent::Indicative { seq, .. } => *seq,
+            MarketDataEvent::Halt { seq, .. } => *seq,
+        }
+    }
+}
+
+/// An event that was not published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MarketDataError {
+    /// The event's sequence number is behind the last one published for its book.
+    SequenceRegression { book_id: BookId, last: u64, seq: u64 },
+}
+
+impl fmt::Display for MarketDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarketDataError::SequenceRegression { book_id, last, seq } => write!(
+                f,
+                "Sequence {} of book {} is behind the last published sequence {}",
+                seq,
+                book_id.value(),
+                last
+            ),
+        }
+    }
+}
+
+/// Fans market data out to subscribers over a bounded broadcast channel.
+/// Publishing never blocks: subscribers that fall more than the channel capacity
+/// behind observe a lag error and are expected to disconnect.
+pub struct MarketDataPublisher {
+    sender: broadcast::Sender<MarketDataEvent>,
+    sequences: HashMap<BookId, u64>, // Last sequence number published per book
+    since_checksum: HashMap<BookId, u32>, // Level updates published per book since its last checksum
+}
+
+impl Default for MarketDataPublisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MarketDataPublisher {
+    /// Creates a publisher with no subscribers.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(MARKET_DATA_CHANNEL_CAPACITY);
+        Self {
+            sender,
+            sequences: HashMap::new(),
+            since_checksum: HashMap::new(),
+        }
+    }
+
+    /// Subscribes to every event published after this call.
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketDataEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Returns true if anyone is listening; events are skipped entirely otherwise.
+    #[inline]
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Gets the sequence number of the last event published for a book.
+    #[inline]
+    pub fn sequence(&self, book_id: BookId) -> u64 {
+        self.sequences.get(&book_id).copied().unwrap_or(0)
+    }
+
+    /// Publishes the new aggregate size of a level after change `seq` of its book.
+    /// Fails with SequenceRegression, publishing nothing, unless `seq` is past the last sequence
+    /// number published for the book.
+    #[inline]
+    pub fn publish_level(&mut self, book_id: BookId, seq: u64, price: Price, size: Qty) -> Result<(), MarketDataError> {
+        if !self.has_subscribers() {
+            return Ok(());
+        }
+        self.advance(book_id, seq, false)?;
+        let _ = self.sender.send(MarketDataEvent::LevelUpdate {
+            book_id: book_id.value(),
+            prev_seq: seq.saturating_sub(1),
+            seq,
+            side: if price.is_bid() { "buy" } else { "sell" },
+            price: price.absolute() as u32,
+            size: size.value(),
+        });
+        *self.since_checksum.entry(book_id).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Returns true once CHECKSUM_INTERVAL level updates have been published for a book since
+    /// its last checksum.
+    #[inline]
+    pub fn checksum_due(&self, book_id: BookId) -> bool {
+        self.since_checksum.get(&book_id).is_some_and(|&count| count >= CHECKSUM_INTERVAL)
+    }
+
+    /// Publishes the checksum of a book as of change `seq`.
+    /// Fails with SequenceRegression, publishing nothing, if `seq` is behind the last sequence
+    /// number published for the book.
+    #[inline]
+    pub fn publish_checksum(&mut self, book_id: BookId, seq: u64, checksum: u32) -> Result<(), MarketDataError> {
+        self.since_checksum.insert(book_id, 0);
+        if !self.has_subscribers() {
+            return Ok(());
+        }
+        self.advance(book_id, seq, true)?;
+        let _ = self.sender.send(MarketDataEvent::Checksum {
+            book_id: book_id.value(),
+            seq,
+            checksum,
+        });
+        Ok(())
+    }
+
+    /// Publishes an execution that followed change `seq` of its book.
+    /// Fails with SequenceRegression, publishing nothing, if `seq` is behind the last sequence
+    /// number published for the book.
+    #[inline]
+    pub fn publish_trade(&mut self, book_id: BookId, seq: u64, trade: &Trade) -> Result<(), MarketDataError> {
+        if !self.has_subscribers() {
+            return Ok(());
+        }
+        self.advance(book_id, seq, true)?;
+        let _ = self.sender.send(MarketDataEvent::Trade {
+            book_id: book_id.value(),
+            seq,
+            trade_id: trade.trade_id,
+            timestamp: trade.timestamp,
+            price: trade.price,
+            quantity: trade.qty.value(),
+            side: if trade.aggressor_is_bid { "buy" } else { "sell" },
+            maker_order_id: trade.maker_order_id.0,
+            taker_order_id: trade.taker_order_id.0,
+        });
+        Ok(())
+    }
+
+    /// Publishes the indicative uncross of a book in an auction as of change `seq`.
+    /// Fails with SequenceRegression, publishing nothing, if `seq` is behind the last sequence
+    /// number published for the book.
+    pub fn publish_indicative(&mut self, book_id: BookId, seq: u64, uncross: Option<Uncross>) -> Result<(), MarketDataError> {
+        if !self.has_subscribers() {
+            return Ok(());
+        }
+        self.advance(book_id, seq, true)?;
+        let _ = self.sender.send(MarketDataEvent::Indicative {
+            book_id: book_id.value(),
+            seq,
+            price: uncross.map(|uncross| uncross.price),
+            volume: uncross.map_or(0, |uncross| uncross.volume),
+            imbalance: uncross.map_or(0, |uncross| uncross.imbalance),
+        });
+        Ok(())
+    }
+
+    /// Publishes that trading in a book was halted or resumed as of change `seq`.
+    /// Fails with SequenceRegression, publishing nothing, if `seq` is behind the last sequence
+    /// number published for the book.
+    pub fn publish_halt(&mut self, book_id: BookId, seq: u64, halted: bool) -> Result<(), MarketDataError> {
+        if !self.has_subscribers() {
+            return Ok(());
+        }
+        self.advance(book_id, seq, true)?;
+        let _ = self.sender.send(MarketDataEvent::Halt {
+            book_id: book_id.value(),
+            seq,
+            halted,
+        });
+        Ok(())
+    }
+
+    /// Records `seq` as the last sequence number published for a book, unless it goes backwards.
+    /// `repeat` allows the last number again, for events that don't change the book.
+    #[inline]
+    fn advance(&mut self, book_id: BookId, seq: u64, repeat: bool) -> Result<(), MarketDataError> {
+        let last = self.sequences.entry(book_id).or_insert(0);
+        if seq < *last || (seq == *last && !repeat) {
+            return Err(MarketDataError::SequenceRegression { book_id, last: *last, seq });
+        }
+        *last = seq;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequences_per_book() {
+        let mut publisher = MarketDataPublisher::new();
+
+        // Nothing is tracked while nobody listens
+        publisher.publish_level(BookId(0), 1, Price(100), Qty(5)).unwrap();
+        assert_eq!(publisher.sequence(BookId(0)), 0);
+
+        let mut rx = publisher.subscribe();
+        publisher.publish_level(BookId(0), 2, Price(100), Qty(5)).unwrap();
+        publisher.publish_level(BookId(1), 1, Price(-200), Qty(7)).unwrap();
+        publisher.publish_level(BookId(0), 3, Price(100), Qty(0)).unwrap();
+
+        let events: Vec<MarketDataEvent> = (0..3).map(|_| rx.try_recv().unwrap()).collect();
+        for event in &events {
+            println!("{:?}", event);
+        }
+        assert_eq!((events[0].book_id(), events[0].seq()), (BookId(0), 2));
+        assert_eq!((events[1].book_id(), events[1].seq()), (BookId(1), 1));
+        assert_eq!((events[2].book_id(), events[2].seq()), (BookId(0), 3));
+        assert_eq!(
+            events[1],
+            MarketDataEvent::LevelUpdate { book_id: 1, prev_seq: 0, seq: 1, side: "sell", price: 200, size: 7 }
+        );
+    }
+
+    #[test]
+    fn test_sequence_regression() {
+        let mut publisher = MarketDataPublisher::new();
+        let mut rx = publisher.subscribe();
+        publisher.publish_level(BookId(0), 5, Price(100), Qty(5)).unwrap();
+
+        // A trade repeats the number of the change before it, a level update can't
+        publisher.publish_trade(BookId(0), 5, &Trade::default()).unwrap();
+        let regression = |seq| Err(MarketDataError::SequenceRegression { book_id: BookId(0), last: 5, seq });
+        assert_eq!(publisher.publish_level(BookId(0), 5, Price(100), Qty(4)), regression(5));
+        assert_eq!(publisher.publish_level(BookId(0), 4, Price(100), Qty(4)), regression(4));
+        assert_eq!(publisher.publish_trade(BookId(0), 3, &Trade::default()), regression(3));
+        assert_eq!(publisher.publish_checksum(BookId(0), 4, 0), regression(4));
+
+        let events: Vec<MarketDataEvent> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(events.iter().map(MarketDataEvent::seq).collect::<Vec<_>>(), vec![5, 5]);
+        assert_eq!(publisher.sequence(BookId(0)), 5);
+    }
+
+    #[test]
+    fn test_checksum_interval() {
+        let mut publisher = MarketDataPublisher::new();
+        let mut rx = publisher.subscribe();
+        for seq in 1..CHECKSUM_INTERVAL as u64 {
+            publisher.publish_level(BookId(0), seq, Price(100), Qty(seq)).unwrap();
+        }
+        assert!(!publisher.checksum_due(BookId(0)));
+        let seq = CHECKSUM_INTERVAL as u64;
+        publisher.publish_level(BookId(0), seq, Price(100), Qty(0)).unwrap();
+        assert!(publisher.checksum_due(BookId(0)));
+        assert!(!publisher.checksum_due(BookId(1)));
+
+        // The checksum carries the sequence number of the change it follows
+        publisher.publish_checksum(BookId(0), seq, 42).unwrap();
+        assert!(!publisher.checksum_due(BookId(0)));
+        let events: Vec<MarketDataEvent> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(events.last(), Some(&MarketDataEvent::Checksum { book_id: 0, seq, checksum: 42 }));
+        assert_eq!(publisher.sequence(BookId(0)), seq);
+    }
+}
@@ -0,0 +1,158 @@
+// mass_quote.rs
+
+use crate::{
+    order::{OrderId, Signature},
+    utils::{hex_array, BookId},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One side of a maker's two-sided quote, a limit order signed as any order is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteSide {
+    pub order_id: u64,
+    pub qty: u64,
+    pub price: u32,
+    pub nonce: u64,
+    pub expiry: Option<u64>,
+    pub signature: Signature,
+}
+
+/// A trader's quote in one book: a bid, an ask, or both
+/// An entry with neither side only takes the trader's previous quote in the book down.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteEntry {
+    pub book_id: u32,
+    pub bid: Option<QuoteSide>,
+    pub ask: Option<QuoteSide>,
+}
+
+impl QuoteEntry {
+    /// Returns true if the bid is at or above the ask, so the quote would trade with itself
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{mass_quote::{QuoteEntry, QuoteSide}, order::Signature};
+    /// let side = |order_id, price| Some(QuoteSide { order_id, qty: 10, price, nonce: order_id, expiry: None, signature: Signature::None });
+    /// assert!(!QuoteEntry { book_id: 0, bid: side(0, 99), ask: side(1, 101) }.crosses());
+    /// assert!(QuoteEntry { book_id: 0, bid: side(0, 101), ask: side(1, 101) }.crosses());
+    /// assert!(!QuoteEntry { book_id: 0, bid: side(0, 101), ask: None }.crosses());
+    /// ```
+    pub fn crosses(&self) -> bool {
+        matches!((&self.bid, &self.ask), (Some(bid), Some(ask)) if bid.price >= ask.price)
+    }
+
+    /// Gets the sides quoted, bid first, each with whether it is the bid
+    pub fn sides(&self) -> impl Iterator<Item = (&QuoteSide, bool)> {
+        self.bid.iter().map(|bid| (bid, true)).chain(self.ask.iter().map(|ask| (ask, false)))
+    }
+}
+
+/// The orders a trader's last quote in a book went in as
+/// Either may have filled or been cancelled since; the set is only a record of what to take
+/// down when the quote is replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteSet {
+    #[serde(with = "hex_array")]
+    pub trader: [u8; 20],
+    pub book_id: u32,
+    pub bid: Option<u64>,
+    pub ask: Option<u64>,
+}
+
+impl QuoteSet {
+    /// Gets the order IDs of the set, bid first
+    pub fn order_ids(&self) -> impl Iterator<Item = OrderId> {
+        self.bid.into_iter().chain(self.ask).map(OrderId)
+    }
+}
+
+/// Each trader's current quote set in each book
+/// Sets are kept by trader and book, so replacing one touches only its own orders, never the
+/// rest of the book.
+#[derive(Debug, Default)]
+pub struct QuoteBook {
+    sets: HashMap<([u8; 20], BookId), QuoteSet>,
+}
+
+impl QuoteBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a trader's new quote set in its book, returning the one it replaces
+    pub fn replace(&mut self, set: QuoteSet) -> Option<QuoteSet> {
+        self.sets.insert((set.trader, BookId(set.book_id)), set)
+    }
+
+    /// Forgets a trader's quote set in a book, returning it
+    pub fn take(&mut self, trader: [u8; 20], book_id: BookId) -> Option<QuoteSet> {
+        self.sets.remove(&(trader, book_id))
+    }
+
+    /// Forgets every quote set of a trader, or only the one in `book_id`, returning them
+    pub fn take_all(&mut self, trader: [u8; 20], book_id: Option<BookId>) -> Vec<QuoteSet> {
+        if let Some(book_id) = book_id {
+            return self.take(trader, book_id).into_iter().collect();
+        }
+        let mut sets: Vec<QuoteSet> = self.sets.extract_if(|(owner, _), _| *owner == trader).map(|(_, set)| set).collect();
+        sets.sort_unstable_by_key(|set| set.book_id);
+        sets
+    }
+
+    /// Forgets the quote sets of every trader in a book, as when the book closes
+    pub fn remove_book(&mut self, book_id: BookId) {
+        self.sets.retain(|(_, book), _| *book != book_id);
+    }
+
+    #[inline]
+    pub fn get(&self, trader: [u8; 20], book_id: BookId) -> Option<&QuoteSet> {
+        self.sets.get(&(trader, book_id))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Lists the sets by trader, then book
+    pub fn entries(&self) -> Vec<QuoteSet> {
+        let mut sets: Vec<QuoteSet> = self.sets.values().copied().collect();
+        sets.sort_unstable_by_key(|set| (set.trader, set.book_id));
+        sets
+    }
+
+    pub fn from_entries(entries: Vec<QuoteSet>) -> Self {
+        let mut book = Self::new();
+        for set in entries {
+            book.replace(set);
+        }
+        book
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_book() {
+        let mut quotes = QuoteBook::new();
+        let set = |trader, book_id, bid| QuoteSet { trader: [trader; 20], book_id, bid: Some(bid), ask: None };
+        assert_eq!(quotes.replace(set(1, 0, 10)), None);
+        assert_eq!(quotes.replace(set(1, 0, 11)), Some(set(1, 0, 10)));
+        quotes.replace(set(1, 2, 12));
+        quotes.replace(set(2, 0, 13));
+        assert_eq!(quotes.entries(), vec![set(1, 0, 11), set(1, 2, 12), set(2, 0, 13)]);
+
+        assert_eq!(quotes.take_all([1; 20], None), vec![set(1, 0, 11), set(1, 2, 12)]);
+        assert_eq!(quotes.get([2; 20], BookId(0)).map(|set| set.order_ids().collect()), Some(vec![OrderId(13)]));
+        quotes.remove_book(BookId(0));
+        assert!(quotes.is_empty());
+    }
+}
@@ -1,32 +1,924 @@
 use crate::{
-    order::{OrderId, Order},
-    orderbook_manager::OrderBookManager,
-    price::Price,
+    auction::{clearing_price, Uncross},
+    events::OrderBookEvent,
+    metrics::Metrics,
+    client_order_ids::{ClientOrderId, ClientOrderIds},
+    order::{Iceberg, OidMap, OrderId, Order, Signature},
+    orderbook_manager::{OrderBookError, OrderBookManager},
+    price::{Price, PriceScale},
     quantity::Qty,
-    utils::BookId,
-    market::MarketManager,
-    level::LevelId,
+    utils::{hex_bytes, BookId, Clock, CANDLE_HISTORY_CAPACITY, DEFAULT_TRADE_TAPE_CAPACITY, MAX_STOP_ROUNDS},
+    market::{MarketConfig, MarketError, MarketManager, PriceBand, RiskLimits, SizeRules},
+    order_intake::OrderIntakeError,
+    nonce_registry::NonceRegistry,
+    settlement_manager::{SettlementError, SettlementStatus, SettlementTracker, TrackedSettlement},
+    translator::{salt_nonce, translate_to_settlement, TranslationError},
+    level::{Level, LevelId},
+    order_updates::{OrderStatus, OrderUpdate},
+    trade_tape::{PriceImprovement, Trade, TradeTape},
+    stats::StatsTracker,
+    stops::{StopBook, StopOrder},
+    pegs::{PegBook, PeggedOrder},
+    positions::PositionTracker,
+    risk::notional,
+    oco::{OcoBook, OcoGroup, OcoLeg, OcoPolicy},
+    mass_quote::{QuoteBook, QuoteEntry, QuoteSet},
+    rfq::{BlockTrade, RfqManager},
+    expiry::ExpirySchedule,
+    candles::CandleAggregator,
+    snapshot::{BookSnapshot, ContinuationSnapshot, EngineSnapshot, LevelSnapshot, OrderSnapshot, RestoreError},
+    wal::{Wal, WalCommand, WalError},
 };
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
 
 pub struct MatchingEngine {
     pub orderbook_manager: OrderBookManager,
     pub market_manager: MarketManager,
+    pub nonces: NonceRegistry, // Nonces of accepted orders; the API checks them before submitting.
+    pub settlements: SettlementTracker, // Settlements of fills in books with a market configuration.
+    trade_tapes: HashMap<BookId, TradeTape>,
+    trade_tape_capacity: usize,
+    stats: StatsTracker,
+    candles: CandleAggregator,
+    auctions: BTreeSet<BookId>, // Books whose orders accumulate without matching until uncrossed.
+    book_only: BTreeSet<BookId>, // Books that match without a market; their fills are never settled.
+    stops: StopBook,
+    triggered_stops: HashSet<OrderId>, // Stop-limits that triggered, while they may still rest.
+    pegs: PegBook, // Pegged orders, repriced whenever their book changes.
+    oco: OcoBook,  // OCO pairs, linked until a fill or cancel settles them.
+    oco_fills: Vec<OrderId>, // OCO legs that traded since their siblings were last adjusted.
+    quotes: QuoteBook, // Each trader's last mass quote in each book, replaced as a whole.
+    pub rfqs: RfqManager, // RFQs for blocks traded off the book; the API checks quotes against them.
+    expiries: ExpirySchedule, // Good-til-time orders, soonest expiry first.
+    positions: PositionTracker, // Positions in books whose market tracks them.
+    reduce_only: HashSet<OrderId>, // Reduce-only orders, while they may still rest.
+    halted: bool, // Kill switch; while set, orders are refused and only cancels go through.
+    crossed: BTreeSet<BookId>, // Books found crossed outside an auction, halted until repaired.
+    max_fills_per_order: Option<usize>, // Most fills an order makes in one pass; no limit when None.
+    fill_limit_policy: FillLimitPolicy,
+    continuations: VecDeque<Continuation>, // Orders cut short by the fill limit, oldest first.
+    fill_limit_cancelled: Option<OrderId>, // Last order the fill limit cancelled the rest of.
+    resume_stalled: bool, // Set when a pass couldn't be logged; cleared once the log takes a command again.
+    #[cfg(test)]
+    cross_expected: bool, // Set by tests that cross a book on purpose, to see it halted instead of asserted.
+    shutting_down: bool, // Refuses orders like the kill switch, but is never logged or snapshotted.
+    next_order_id: u64,
+    next_trade_id: u64,
+    pub wal: Option<Wal>, // Commands are logged here before they are applied, when set.
+    pub clock: Clock,     // Timestamps trades; the only source of time on the matching path.
+    pub metrics: Arc<Metrics>, // Shared with the API; latencies are timed but never affect matching.
+}
+
+impl Default for MatchingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MatchingEngine {
     pub fn new() -> Self {
+        Self::with_trade_tape_capacity(DEFAULT_TRADE_TAPE_CAPACITY)
+    }
+
+    /// Creates an engine whose per-book trade tapes retain at most `capacity` trades
+    pub fn with_trade_tape_capacity(capacity: usize) -> Self {
         Self {
             orderbook_manager: OrderBookManager::new(),
             market_manager: MarketManager::new(),
+            nonces: NonceRegistry::new(),
+            settlements: SettlementTracker::new(),
+            trade_tapes: HashMap::new(),
+            trade_tape_capacity: capacity,
+            stats: StatsTracker::new(),
+            candles: CandleAggregator::new(CANDLE_HISTORY_CAPACITY),
+            auctions: BTreeSet::new(),
+            book_only: BTreeSet::new(),
+            stops: StopBook::new(),
+            triggered_stops: HashSet::new(),
+            pegs: PegBook::new(),
+            oco: OcoBook::new(),
+            oco_fills: Vec::new(),
+            quotes: QuoteBook::new(),
+            rfqs: RfqManager::new(),
+            expiries: ExpirySchedule::new(),
+            positions: PositionTracker::new(),
+            reduce_only: HashSet::new(),
+            halted: false,
+            crossed: BTreeSet::new(),
+            max_fills_per_order: None,
+            fill_limit_policy: FillLimitPolicy::default(),
+            continuations: VecDeque::new(),
+            fill_limit_cancelled: None,
+            resume_stalled: false,
+            #[cfg(test)]
+            cross_expected: false,
+            shutting_down: false,
+            next_order_id: 0,
+            next_trade_id: 1,
+            wal: None,
+            clock: Clock::System,
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    /// Creates an engine that takes orders for any book, creating it on first use, and matches them
+    /// whether or not the book has a market, as the engine did before books had to be set up first.
+    pub fn auto_creating() -> Self {
+        let mut engine = Self::new();
+        engine.orderbook_manager.set_auto_create_books(true);
+        engine
+    }
+
+    /// Lets a book without a market match, or stops it from matching again until it has one
+    /// Fills in a book-only book are traded but never settled.
+    pub fn set_book_only(&mut self, book_id: BookId, book_only: bool) {
+        match book_only {
+            true => self.book_only.insert(book_id),
+            false => self.book_only.remove(&book_id),
+        };
+    }
+
+    /// Returns true if a book was set to match without a market, see set_book_only
+    pub fn is_book_only(&self, book_id: BookId) -> bool {
+        self.book_only.contains(&book_id)
+    }
+
+    /// Checks that orders can go into a book, creating it first when the engine is `auto_creating`
+    /// Fails with UnknownBook if the book hasn't been created, and NoMarket if it has no market and
+    /// wasn't set book-only. An auto-creating engine only fails for a book out of range.
+    pub fn open_book(&mut self, book_id: BookId) -> Result<(), OrderBookError> {
+        if self.orderbook_manager.auto_creates_books() {
+            self.orderbook_manager.create_book(book_id)?;
+            return Ok(());
+        }
+        self.orderbook_manager.book(book_id).ok_or(OrderBookError::UnknownBook(book_id))?;
+        match self.market_manager.get_config(book_id).is_some() || self.book_only.contains(&book_id) {
+            true => Ok(()),
+            false => Err(OrderBookError::NoMarket(book_id)),
         }
     }
 
+    /// Caps the fills an order makes in one pass of the engine, or lifts the cap with None
+    /// An order that reaches the cap while it could still trade is cancelled or left waiting for
+    /// another pass, as `policy` says; the fills it made are traded, settled, and published like
+    /// any others first. A log replays the same only with the cap and policy it was written under.
+    pub fn set_fill_limit(&mut self, max_fills_per_order: Option<usize>, policy: FillLimitPolicy) {
+        self.max_fills_per_order = max_fills_per_order;
+        self.fill_limit_policy = policy;
+    }
+
+    /// Returns true if an order cut short by the fill limit waits for a pass resume_matching can run now
+    pub fn can_resume(&self) -> bool {
+        !self.resume_stalled && !self.continuations.is_empty() && self.check_accepting().is_ok()
+    }
+
+    /// Returns true if an order was cut short by the fill limit and waits to go on matching
+    pub fn is_continuing(&self, order_id: OrderId) -> bool {
+        self.continuations.iter().any(|waiting| waiting.order_id == order_id)
+    }
+
+    /// Returns true if the fill limit cancelled what was left of an order, see FillLimitPolicy::Cancel
+    /// Only the last order cancelled this way is remembered, so ask right after matching it.
+    pub fn cancelled_by_fill_limit(&self, order_id: OrderId) -> bool {
+        self.fill_limit_cancelled == Some(order_id)
+    }
+
     pub fn get_orderbook_manager(&self) -> &OrderBookManager {
         &self.orderbook_manager
     }
 
+    /// Gets the trade tape of a book, or None if the book has never traded
+    pub fn trade_tape(&self, book_id: BookId) -> Option<&TradeTape> {
+        self.trade_tapes.get(&book_id)
+    }
+
+    /// Gets the rolling trade statistics of every book
+    pub fn stats(&self) -> &StatsTracker {
+        &self.stats
+    }
+
+    /// Gets the candles of every book
+    pub fn candles(&self) -> &CandleAggregator {
+        &self.candles
+    }
+
+    /// Numbers trades on past `trade_id`, the last one a trade history kept outside the engine
+    /// already has; numbering never moves backwards.
+    pub fn continue_trade_ids_after(&mut self, trade_id: u64) {
+        self.next_trade_id = self.next_trade_id.max(trade_id + 1);
+    }
+
+    /// Assigns the next engine-wide order ID for an incoming order
+    pub fn next_order_id(&mut self) -> OrderId {
+        let order_id = OrderId(self.next_order_id);
+        self.next_order_id += 1;
+        order_id
+    }
+
+    /// Copies the full engine state into a snapshot
+    /// This is the only part that needs the engine; serializing and writing the result can
+    /// happen after the engine lock is released. `registry` and `wal_segment` are left empty
+    /// for the caller, which owns the book registry and the log.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let manager = &self.orderbook_manager;
+
+        let mut books = Vec::new();
+        for (book_id, book) in manager.books() {
+            // Each level's queue lists its orders in time priority.
+            let levels = |side: &mut dyn Iterator<Item = &Level>| -> Vec<LevelSnapshot> {
+                side.map(|level| LevelSnapshot {
+                    price: level.price().absolute() as u32,
+                    size: level.size().value(),
+                    orders: manager
+                        .oid_map
+                        .pool()
+                        .queue(level)
+                        .map(|(order_id, order)| {
+                            let meta = manager.oid_map.meta(order_id).copied().unwrap_or_default();
+                            let iceberg = manager.oid_map.iceberg(order_id);
+                            OrderSnapshot {
+                                order_id: order_id.0,
+                                qty: order.qty().value(),
+                                trader: order.trader(),
+                                nonce: meta.nonce,
+                                expiry: meta.expiry,
+                                signature: meta.signature,
+                                display: iceberg.map(|iceberg| iceberg.display.value()),
+                                reserve: iceberg.map_or(0, |iceberg| iceberg.reserve.value()),
+                                reduce_only: self.reduce_only.contains(&order_id),
+                                received_at: meta.received_at,
+                            }
+                        })
+                        .collect(),
+                })
+                .collect()
+            };
+            let bids = levels(&mut book.iter_bids());
+            let asks = levels(&mut book.iter_asks());
+            let last_price = self.stats.get(book_id).and_then(|stats| stats.last_price());
+            books.push(BookSnapshot { book_id: book_id.value(), seq: book.seq(), last_price, bids, asks });
+        }
+
+        EngineSnapshot {
+            taken_at: self.clock.now(),
+            next_order_id: self.next_order_id,
+            next_trade_id: self.next_trade_id,
+            event_seq: manager.event_seq(),
+            books,
+            markets: self
+                .market_manager
+                .list_markets()
+                .into_iter()
+                .map(|(book_id, config)| (book_id.value(), config.clone()))
+                .collect(),
+            nonces: self.nonces.entries(),
+            settlements: self.settlements.entries(),
+            next_settlement_id: self.settlements.next_settlement_id(),
+            settlement_batches: self.settlements.batch_entries(),
+            next_settlement_batch_id: self.settlements.next_batch_id(),
+            auctions: self.auctions.iter().map(|book_id| book_id.value()).collect(),
+            // An auto-creating engine matches every book without a market, as older snapshots say
+            book_only: match self.orderbook_manager.auto_creates_books() {
+                true => None,
+                false => Some(self.book_only.iter().map(|book_id| book_id.value()).collect()),
+            },
+            stops: self.stops.entries(),
+            triggered_stops: {
+                let mut triggered_stops: Vec<u64> = self.triggered_stops.iter().map(|order_id| order_id.0).collect();
+                triggered_stops.sort_unstable();
+                triggered_stops
+            },
+            pegs: self.pegs.entries(),
+            oco_groups: self.oco.entries(),
+            quote_sets: self.quotes.entries(),
+            positions: self.positions.entries(),
+            client_order_ids: manager
+                .client_order_ids
+                .entries()
+                .into_iter()
+                .filter(|entry| self.order_owner(OrderId(entry.order_id)).is_some())
+                .collect(),
+            continuations: self
+                .continuations
+                .iter()
+                .map(|Continuation { order_id, order, is_bid }| ContinuationSnapshot {
+                    book_id: order.book_id().value(),
+                    price: order.price().absolute() as u32,
+                    is_bid: *is_bid,
+                    order: OrderSnapshot {
+                        order_id: order_id.0,
+                        qty: order.qty().value(),
+                        trader: order.trader(),
+                        nonce: order.nonce(),
+                        expiry: order.expiry(),
+                        signature: order.signature(),
+                        display: order.display().map(|display| display.value()),
+                        reserve: 0,
+                        reduce_only: order.reduce_only(),
+                        received_at: order.received_at(),
+                    },
+                })
+                .collect(),
+            halted: self.halted,
+            registry: Vec::new(),
+            wal_segment: None,
+        }
+    }
+
+    /// Builds an engine from a snapshot
+    /// Each level's orders are re-added in the order its queue listed them, so depth, queue
+    /// positions, and quantities match the engine the snapshot was taken from. Trade tapes,
+    /// statistics other than the last trade price, and subscribers start empty.
+    /// Fails, rather than restore a different engine, if the snapshot couldn't have been taken
+    /// from one: an order ID used twice, a price or BookId out of range, or a market listed twice.
+    pub fn restore(snapshot: EngineSnapshot) -> Result<Self, RestoreError> {
+        let mut engine = Self::new();
+
+        for book in &snapshot.books {
+            engine.orderbook_manager.create_book(BookId(book.book_id))?;
+            for (levels, is_bid) in [(&book.bids, true), (&book.asks, false)] {
+                for level in levels {
+                    for order in &level.orders {
+                        let order_id = OrderId(order.order_id);
+                        let resting = Order::new(
+                            Qty(order.qty),
+                            LevelId(0),
+                            BookId(book.book_id),
+                            order.trader,
+                            order.nonce,
+                            order.expiry,
+                            order.signature,
+                        )
+                        .with_received_at(order.received_at);
+                        engine.orderbook_manager.rest_order(order_id, resting, level.price, is_bid)?;
+                        if let Some(display) = order.display {
+                            let iceberg = Iceberg { display: Qty(display), reserve: Qty(order.reserve) };
+                            engine.orderbook_manager.oid_map.set_iceberg(order_id, iceberg);
+                            let total = engine.orderbook_manager.oid_map.total_qty(order_id).unwrap_or_default();
+                            engine.orderbook_manager.open_orders.update(order_id, total);
+                        }
+                        engine.expiries.schedule(order_id, order.expiry);
+                        if order.reduce_only {
+                            engine.reduce_only.insert(order_id);
+                        }
+                    }
+                }
+            }
+            // Snapshots taken before books were sequenced count from the orders re-added instead
+            if book.seq > 0 {
+                if let Ok(restored) = engine.orderbook_manager.create_book(BookId(book.book_id)) {
+                    restored.set_seq(book.seq);
+                }
+            }
+            if let Some(last_price) = book.last_price {
+                engine.stats.restore_last_price(BookId(book.book_id), last_price);
+            }
+        }
+
+        for (book_id, config) in snapshot.markets {
+            engine.market_manager.add_market(BookId(book_id), config, false)?;
+        }
+        engine.nonces = NonceRegistry::from_entries(snapshot.nonces);
+        engine.settlements = SettlementTracker::from_entries(
+            snapshot.settlements,
+            snapshot.next_settlement_id,
+            snapshot.settlement_batches,
+            snapshot.next_settlement_batch_id,
+        );
+        engine.auctions = snapshot.auctions.into_iter().map(BookId).collect();
+        // Snapshots from before books could be book-only had every book without a market match
+        engine.book_only = match snapshot.book_only {
+            Some(book_only) => book_only.into_iter().map(BookId).collect(),
+            None => {
+                let book_ids: Vec<BookId> = engine.orderbook_manager.books().map(|(book_id, _)| book_id).collect();
+                book_ids.into_iter().filter(|&book_id| engine.market_manager.get_config(book_id).is_none()).collect()
+            }
+        };
+        for stop in snapshot.stops {
+            engine.expiries.schedule(OrderId(stop.order_id), stop.expiry);
+            engine.stops.insert(stop)?;
+        }
+        engine.triggered_stops = snapshot.triggered_stops.into_iter().map(OrderId).collect();
+        for peg in snapshot.pegs {
+            engine.expiries.schedule(OrderId(peg.order_id), peg.expiry);
+            engine.pegs.insert(peg)?;
+        }
+        for group in snapshot.oco_groups {
+            engine.oco.insert(group)?;
+        }
+        engine.quotes = QuoteBook::from_entries(snapshot.quote_sets);
+        engine.positions = PositionTracker::from_entries(snapshot.positions);
+        engine.orderbook_manager.client_order_ids = ClientOrderIds::from_entries(snapshot.client_order_ids);
+        for ContinuationSnapshot { book_id, price, is_bid, order } in snapshot.continuations {
+            let limit = Price::from_u32(price, is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+            let mut waiting = Order::new(Qty(order.qty), LevelId(0), BookId(book_id), order.trader, order.nonce, order.expiry, order.signature)
+                .with_price(limit)
+                .with_received_at(order.received_at);
+            if let Some(display) = order.display {
+                waiting = waiting.with_display(Qty(display));
+            }
+            if order.reduce_only {
+                waiting = waiting.with_reduce_only();
+            }
+            engine.expiries.schedule(OrderId(order.order_id), order.expiry);
+            engine.continuations.push_back(Continuation { order_id: OrderId(order.order_id), order: waiting, is_bid });
+        }
+        engine.halted = snapshot.halted;
+        // A book halted crossed is still crossed in the snapshot, and stays halted until repaired
+        let book_ids: Vec<BookId> = engine.orderbook_manager.books().map(|(book_id, _)| book_id).collect();
+        engine.crossed = book_ids.into_iter().filter(|&book_id| engine.crossing(book_id).is_some()).collect();
+        engine.next_order_id = snapshot.next_order_id;
+        engine.next_trade_id = snapshot.next_trade_id;
+        engine.orderbook_manager.set_event_seq(snapshot.event_seq);
+        Ok(engine)
+    }
+
+    /// Invalidates every nonce of `trader` below `min_nonce` and cancels the trader's resting
+    /// orders signed with one of them, in every book. Returns the IDs of the cancelled orders.
+    pub fn bump_nonce(&mut self, trader: [u8; 20], min_nonce: u64) -> Vec<OrderId> {
+        let min_nonce = self.nonces.bump(trader, min_nonce);
+        let mut cancelled = self.orderbook_manager.cancel_below_nonce(trader, min_nonce);
+        let stops = self.stops.remove_where(|stop| {
+            stop.trader == Some(trader) && stop.nonce.is_some_and(|nonce| nonce < min_nonce)
+        });
+        cancelled.extend(stops);
+        let parked = self.pegs.remove_where(|peg| {
+            peg.price.is_none() && peg.trader == Some(trader) && peg.nonce.is_some_and(|nonce| nonce < min_nonce)
+        });
+        cancelled.extend(parked);
+        let waiting = self.take_continuations(|waiting| {
+            waiting.order.trader() == Some(trader) && waiting.order.nonce().is_some_and(|nonce| nonce < min_nonce)
+        });
+        cancelled.extend(self.cancel_continuations(waiting));
+        self.reprice_all_pegs();
+        self.prune_oco();
+        cancelled
+    }
+
+    /// Records that a settlement was sent to the settlement contract in transaction `tx_hash`
+    pub fn mark_settlement_submitted(&mut self, settlement_id: u64, tx_hash: [u8; 32]) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        self.settlements.mark_submitted(settlement_id, tx_hash, now).map(|_| ())
+    }
+
+    /// Records that the transaction of a submitted settlement was confirmed
+    pub fn mark_settlement_confirmed(&mut self, settlement_id: u64) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        self.settlements.mark_confirmed(settlement_id, now).map(|_| ())
+    }
+
+    /// Records that a settlement failed, optionally putting the maker's quantity back on the book
+    /// The re-credited quantity is re-submitted under `recredit_order_id` at the execution price,
+    /// with the maker's original signature, so it goes to the back of the queue and may match again.
+    ///
+    /// ## Arguments:
+    /// - `settlement_id`: The settlement that failed.
+    /// - `reason`: Why it failed, e.g. the revert reason of the transaction.
+    /// - `recredit_order_id`: The order ID for the re-credited quantity, or None to drop it.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{matching::MatchingEngine, settlement_manager::SettlementError};
+    /// let mut engine = MatchingEngine::new();
+    /// let result = engine.mark_settlement_failed(1, "reverted".to_string(), None);
+    /// assert_eq!(result, Err(SettlementError::UnknownSettlement));
+    /// ```
+    pub fn mark_settlement_failed(
+        &mut self,
+        settlement_id: u64,
+        reason: String,
+        recredit_order_id: Option<OrderId>,
+    ) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        let settlement = self.settlements.mark_failed(settlement_id, reason, now)?.clone();
+        if let Some(order_id) = recredit_order_id {
+            self.recredit(&settlement, order_id);
+        }
+        Ok(())
+    }
+
+    /// Records that a batch of settlements was sent in transaction `tx_hash`
+    pub fn mark_settlement_batch_submitted(
+        &mut self,
+        batch_id: u64,
+        settlement_ids: &[u64],
+        tx_hash: [u8; 32],
+    ) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        self.settlements.mark_batch_submitted(batch_id, settlement_ids, tx_hash, now).map(|_| ())
+    }
+
+    /// Records that the transaction of a submitted batch was confirmed
+    pub fn mark_settlement_batch_confirmed(&mut self, batch_id: u64) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        self.settlements.mark_batch_confirmed(batch_id, now).map(|_| ())
+    }
+
+    /// Records that a batch failed, failing every settlement of it that had not finished yet
+    /// `recredit_order_ids` is either empty, to drop the makers' quantities, or holds one order ID
+    /// per settlement of the batch, in batch order; see mark_settlement_failed.
+    pub fn mark_settlement_batch_failed(
+        &mut self,
+        batch_id: u64,
+        reason: String,
+        recredit_order_ids: &[OrderId],
+    ) -> Result<(), SettlementError> {
+        let now = self.clock.now();
+        let in_flight: Vec<TrackedSettlement> = self
+            .settlements
+            .batch(batch_id)
+            .map(|batch| {
+                batch
+                    .settlement_ids
+                    .iter()
+                    .filter_map(|&settlement_id| self.settlements.get(settlement_id))
+                    .filter(|settlement| !settlement.status.is_finished())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let settlement_ids = self.settlements.mark_batch_failed(batch_id, reason, now)?.settlement_ids.clone();
+
+        for (settlement_id, &order_id) in settlement_ids.iter().zip(recredit_order_ids) {
+            if let Some(settlement) = in_flight.iter().find(|settlement| settlement.settlement_id == *settlement_id) {
+                self.recredit(settlement, order_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-submits the maker's quantity of a failed settlement under `order_id`
+    /// The settlement's book took the fill, so it exists. The maker's price was accepted once,
+    /// so it is not held to the price band again.
+    fn recredit(&mut self, settlement: &TrackedSettlement, order_id: OrderId) {
+        let order = &settlement.order;
+        let Some(limit) = Price::from_u32(settlement.exec_price, order.maker_is_buyer) else {
+            return;
+        };
+        let maker = Order::new(
+            Qty(settlement.exec_qty),
+            LevelId(0),
+            BookId(settlement.book_id),
+            Some(order.maker),
+            Some(salt_nonce(order.maker_salt)),
+            Some(order.maker_expiration),
+            order.maker_signature.to_bytes(),
+        );
+        let mut match_details = Vec::new();
+        if self.match_limit(order_id, maker, limit, order.maker_is_buyer, &mut match_details).is_ok() {
+            self.after_match(BookId(settlement.book_id), &match_details);
+        }
+    }
+
+    /// Appends a command to the write-ahead log, if one is attached
+    /// Call this before applying the command, so an acknowledged command survives a crash.
+    pub fn log(&mut self, command: &WalCommand) -> Result<(), WalError> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(command)?;
+        }
+        self.resume_stalled = false;
+        Ok(())
+    }
+
+    /// Applies a logged command to the engine without logging it again
+    /// Replaying a log in order onto an empty engine rebuilds its books, resting orders, and order ID counter.
+    /// A command that failed when first applied, e.g. for a book out of range, fails again.
+    pub fn apply(&mut self, command: &WalCommand) {
+        if let Some(order_id) = command.max_order_id() {
+            self.next_order_id = self.next_order_id.max(order_id.0 + 1);
+        }
+        match *command {
+            WalCommand::RegisterBook { book_id, ref market, book_only, .. } => {
+                let _ = self.orderbook_manager.create_book(BookId(book_id));
+                if let Some(market) = market {
+                    let _ = self.market_manager.add_market(BookId(book_id), market.clone(), true);
+                }
+                self.set_book_only(BookId(book_id), book_only.unwrap_or(market.is_none()));
+            }
+            WalCommand::CloseBook { book_id } => {
+                let _ = self.close_book(BookId(book_id));
+            }
+            WalCommand::SetPriceBand { book_id, price_band } => {
+                let _ = self.set_price_band(BookId(book_id), price_band);
+            }
+            WalCommand::SetRiskLimits { book_id, risk_limits } => {
+                let _ = self.set_risk_limits(BookId(book_id), risk_limits);
+            }
+            WalCommand::SetSizeRules { book_id, size_rules } => {
+                let _ = self.set_size_rules(BookId(book_id), size_rules);
+            }
+            WalCommand::SetKillSwitch { engaged } => self.set_halted(engaged),
+            WalCommand::EnterAuction { book_id } => {
+                let _ = self.enter_auction(BookId(book_id));
+            }
+            WalCommand::Uncross { book_id } => {
+                let _ = self.uncross(BookId(book_id));
+            }
+            WalCommand::RepairCrossed { book_id } => {
+                let _ = self.repair_crossed(BookId(book_id));
+            }
+            WalCommand::Submit {
+                order_id, book_id, qty, price, is_bid, trader, nonce, expiry, signature, display, reduce_only, received_at,
+            } => {
+                if let (Some(trader), Some(nonce)) = (trader, nonce) {
+                    let _ = self.nonces.consume(trader, nonce);
+                }
+                let mut order = Order::new(Qty(qty), LevelId(0), BookId(book_id), trader, nonce, expiry, signature)
+                    .with_received_at(received_at);
+                if let Some(display) = display {
+                    order = order.with_display(Qty(display));
+                }
+                if reduce_only {
+                    order = order.with_reduce_only();
+                }
+                let _ = self.match_limit_order(OrderId(order_id), order, price, is_bid);
+            }
+            WalCommand::SubmitStop(ref stop) => {
+                if let (Some(trader), Some(nonce)) = (stop.trader, stop.nonce) {
+                    let _ = self.nonces.consume(trader, nonce);
+                }
+                let _ = self.submit_stop(stop.clone());
+            }
+            WalCommand::SubmitPegged(ref peg) => {
+                if let (Some(trader), Some(nonce)) = (peg.trader, peg.nonce) {
+                    let _ = self.nonces.consume(trader, nonce);
+                }
+                let _ = self.submit_pegged(peg.clone());
+            }
+            WalCommand::SubmitOco { ref legs, policy, cancel_together } => {
+                for leg in legs {
+                    if let (Some(trader), Some(nonce)) = (leg.trader(), leg.nonce()) {
+                        let _ = self.nonces.consume(trader, nonce);
+                    }
+                }
+                let _ = self.submit_oco(legs.clone(), policy, cancel_together);
+            }
+            WalCommand::MassQuote { trader, ref entries, received_at } => {
+                for (side, _) in entries.iter().flat_map(|entry| entry.sides()) {
+                    let _ = self.nonces.consume(trader, side.nonce);
+                }
+                let _ = self.mass_quote(trader, entries, received_at);
+            }
+            WalCommand::CancelQuotes { trader, book_id } => {
+                self.cancel_quotes(trader, book_id.map(BookId));
+            }
+            WalCommand::BlockTrade(ref block) => {
+                for side in [&block.maker, &block.taker] {
+                    let _ = self.nonces.consume(side.trader, side.nonce);
+                }
+                let _ = self.execute_block(block);
+            }
+            WalCommand::CancelStop { order_id } => {
+                let _ = self.cancel_stop(OrderId(order_id));
+            }
+            WalCommand::Add { order_id, book_id, qty, price, is_bid, trader, nonce, expiry, signature } => {
+                let _ = self.orderbook_manager.add_order(
+                    OrderId(order_id),
+                    BookId(book_id),
+                    Qty(qty),
+                    price,
+                    is_bid,
+                    trader,
+                    nonce,
+                    expiry,
+                    signature,
+                );
+                self.schedule_expiry(OrderId(order_id), expiry);
+                self.reprice_pegs(BookId(book_id));
+                // Added without matching, so the order may have crossed the book
+                self.check_crossed(BookId(book_id));
+            }
+            // Commands on an unknown order were refused the same way when first applied
+            WalCommand::Cancel { order_id, qty } => {
+                if let Some(book_id) = self.book_of(OrderId(order_id)) {
+                    let _ = self.orderbook_manager.cancel_order(OrderId(order_id), Qty(qty));
+                    self.reprice_pegs(book_id);
+                }
+            }
+            WalCommand::Remove { order_id } => {
+                let _ = self.cancel_resting(OrderId(order_id), OrderStatus::Cancelled);
+            }
+            WalCommand::Execute { order_id, qty } => {
+                if let Some(book_id) = self.book_of(OrderId(order_id)) {
+                    let _ = self.orderbook_manager.execute_order(OrderId(order_id), Qty(qty));
+                    if self.oco.group_of(OrderId(order_id)).is_some() {
+                        self.oco_fills.push(OrderId(order_id));
+                    }
+                    self.after_match(book_id, &[]);
+                }
+            }
+            WalCommand::Replace { order_id, new_order_id, new_qty, new_price } => {
+                // An unknown order failed the same way when the command was first applied
+                let _ = self.replace_order(OrderId(order_id), OrderId(new_order_id), Qty(new_qty), new_price);
+            }
+            WalCommand::CancelAll { trader, book_id } => {
+                self.cancel_all_for_trader(trader, book_id.map(BookId));
+            }
+            WalCommand::Expire { order_id } => {
+                let _ = self.expire_order(OrderId(order_id));
+            }
+            WalCommand::Resume { order_id } => {
+                self.resume(OrderId(order_id));
+            }
+            WalCommand::BumpNonce { trader, min_nonce } => {
+                self.bump_nonce(trader, min_nonce);
+            }
+            WalCommand::BindClientOrderId { order_id, trader, client_order_id } => {
+                let _ = self.bind_client_order_id(OrderId(order_id), trader, client_order_id);
+            }
+            // A transition that was refused when first applied is refused again
+            WalCommand::SettlementSubmitted { settlement_id, tx_hash } => {
+                let _ = self.mark_settlement_submitted(settlement_id, tx_hash);
+            }
+            WalCommand::SettlementConfirmed { settlement_id } => {
+                let _ = self.mark_settlement_confirmed(settlement_id);
+            }
+            WalCommand::SettlementFailed { settlement_id, ref reason, recredit_order_id } => {
+                let _ = self.mark_settlement_failed(settlement_id, reason.clone(), recredit_order_id.map(OrderId));
+            }
+            WalCommand::SettlementBatchSubmitted { batch_id, ref settlement_ids, tx_hash } => {
+                let _ = self.mark_settlement_batch_submitted(batch_id, settlement_ids, tx_hash);
+            }
+            WalCommand::SettlementBatchConfirmed { batch_id } => {
+                let _ = self.mark_settlement_batch_confirmed(batch_id);
+            }
+            WalCommand::SettlementBatchFailed { batch_id, ref reason, ref recredit_order_ids } => {
+                let order_ids: Vec<OrderId> = recredit_order_ids.iter().copied().map(OrderId).collect();
+                let _ = self.mark_settlement_batch_failed(batch_id, reason.clone(), &order_ids);
+            }
+        }
+    }
+
+    /// Gets the lowest and highest prices the price band of a book allows right now
+    /// The band is centered on the last trade price, or the mid price when the book has not traded.
+    /// None when the book's market has no band, or there is no reference price to center it on.
+    pub fn price_band(&self, book_id: BookId) -> Option<(u32, u32)> {
+        let band = self.market_manager.get_config(book_id)?.price_band?;
+        let reference = match self.stats.get(book_id).and_then(|stats| stats.last_price()) {
+            Some(last_price) => last_price,
+            None => {
+                let best_bid = self.orderbook_manager.get_best_bid(book_id)?.absolute() as u64;
+                let best_ask = self.orderbook_manager.get_best_ask(book_id)?.absolute() as u64;
+                ((best_bid + best_ask) / 2) as u32
+            }
+        };
+        Some(band.limits(reference))
+    }
+
+    /// Gets how the prices of a book are written in decimal; books without a market configuration
+    /// write them as they are
+    pub fn price_scale(&self, book_id: BookId) -> PriceScale {
+        self.market_manager.get_config(book_id).map(MarketConfig::price_scale).unwrap_or_default()
+    }
+
+    /// Fails with PriceOutsideBand if `price` is outside the price band of `book_id`
+    fn check_price_band(&self, book_id: BookId, price: u32) -> Result<(), OrderBookError> {
+        match self.price_band(book_id) {
+            Some((low, high)) if price < low || price > high => {
+                Err(OrderBookError::PriceOutsideBand { price, low, high })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces the price band of a book's market; None lifts it
+    pub fn set_price_band(&mut self, book_id: BookId, price_band: Option<PriceBand>) -> Result<(), MarketError> {
+        self.market_manager.set_price_band(book_id, price_band)
+    }
+
+    /// Fails with LimitExceeded if `orders` more resting orders worth `notional` in all would take
+    /// `trader` past the risk limits of `book_id`'s market
+    /// Books without a market configuration have no limits. Callers check under the engine lock,
+    /// before the orders go in, so that no burst of orders can get past a limit between the two.
+    pub fn check_risk_limits(
+        &self,
+        book_id: BookId,
+        trader: [u8; 20],
+        orders: u32,
+        notional: u64,
+    ) -> Result<(), OrderIntakeError> {
+        let Some(config) = self.market_manager.get_config(book_id) else {
+            return Ok(());
+        };
+        self.orderbook_manager
+            .open_orders
+            .usage(trader, book_id)
+            .check(&config.risk_limits, orders, notional)
+            .map_err(OrderIntakeError::LimitExceeded)
+    }
+
+    /// Replaces the per-trader risk limits of a book's market
+    /// Orders already resting past the new limits stay; only new ones are refused.
+    pub fn set_risk_limits(&mut self, book_id: BookId, risk_limits: RiskLimits) -> Result<(), MarketError> {
+        self.market_manager.set_risk_limits(book_id, risk_limits)
+    }
+
+    /// Fails with SizeRuleBroken if an order of `qty` at `price` breaks the size rules of
+    /// `book_id`'s market; books without a market configuration take any size
+    pub fn check_size_rules(&self, book_id: BookId, price: u32, qty: Qty) -> Result<(), OrderIntakeError> {
+        match self.market_manager.get_config(book_id) {
+            Some(config) => config.size_rules.check(price, qty).map_err(OrderIntakeError::SizeRuleBroken),
+            None => Ok(()),
+        }
+    }
+
+    /// Replaces the size rules of a book's market
+    /// Orders already resting that break the new rules stay; only new ones are refused.
+    pub fn set_size_rules(&mut self, book_id: BookId, size_rules: SizeRules) -> Result<(), MarketError> {
+        self.market_manager.set_size_rules(book_id, size_rules)
+    }
+
+    /// Engages the kill switch, or releases it
+    /// While it is engaged every way in for new risk fails with Halted: orders of any type,
+    /// replaces, and uncrosses. Cancels, expiries, settlement updates, and queries go on, so
+    /// traders can still get out. Market data subscribers of every book are told of the change.
+    pub fn set_halted(&mut self, halted: bool) {
+        if self.halted == halted {
+            return;
+        }
+        self.halted = halted;
+        if !self.orderbook_manager.market_data.has_subscribers() {
+            return;
+        }
+        let book_ids: Vec<BookId> = self.orderbook_manager.books().map(|(book_id, _)| book_id).collect();
+        for book_id in book_ids {
+            self.publish_halt(book_id, halted);
+        }
+    }
+
+    /// Tells market data subscribers of a book that it halted or resumed
+    fn publish_halt(&mut self, book_id: BookId, halted: bool) {
+        let book_seq = self.orderbook_manager.book_seq(book_id);
+        let published = self.orderbook_manager.market_data.publish_halt(book_id, book_seq, halted);
+        debug_assert!(published.is_ok(), "{:?}", published);
+    }
+
+    /// Returns true while the kill switch is engaged
+    #[inline]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Fails with Halted while the kill switch is engaged, and with ShuttingDown once the
+    /// engine is shutting down
+    /// Callers that log before applying check this first, so refused orders are never logged.
+    #[inline]
+    pub fn check_accepting(&self) -> Result<(), OrderBookError> {
+        if self.halted {
+            return Err(OrderBookError::Halted);
+        }
+        if self.shutting_down {
+            return Err(OrderBookError::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Fails with BookCrossed while a book is halted for being found crossed
+    #[inline]
+    fn check_not_crossed(&self, book_id: BookId) -> Result<(), OrderBookError> {
+        match self.crossed.contains(&book_id) {
+            true => Err(OrderBookError::BookCrossed(book_id)),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns true while a book is halted for being found crossed, see repair_crossed
+    #[inline]
+    pub fn is_crossed(&self, book_id: BookId) -> bool {
+        self.crossed.contains(&book_id)
+    }
+
+    /// Refuses every way in for new risk from now on, as the kill switch does, for a shutdown
+    /// Unlike the kill switch this is neither logged nor snapshotted, so the engine restarts
+    /// as it was before the shutdown began.
+    pub fn begin_shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+
+    /// Shuts the engine down: refuses new orders, then returns once the write-ahead log is on
+    /// stable storage and the event sink has delivered every event
+    /// Cancels and expiries still go through afterwards; call it again once they are done.
+    pub fn finalize(&mut self) -> Result<(), WalError> {
+        self.begin_shutdown();
+        if let Some(wal) = self.wal.as_mut() {
+            wal.sync()?;
+        }
+        self.orderbook_manager.event_sink.flush();
+        Ok(())
+    }
+
     /// Attempts to match an incoming order against the order book
     /// Returns the remaining quantity after matching
+    /// Fills in books with a market configuration are translated and tracked as Pending settlements.
+    /// Stops triggered by the fills go in within the same call; only the incoming order's own
+    /// fills are returned.
+    /// Fails with UnknownBook, NoMarket, or InvalidPrice, leaving the engine untouched, if orders
+    /// can't go into `book_id` (see open_book) or `price` doesn't fit in an i32, and with
+    /// PriceOutsideBand if `price` is outside the book's price band.
+    #[allow(clippy::too_many_arguments)]
     pub fn match_order(
         &mut self,
         order_id: OrderId,
@@ -37,289 +929,3378 @@ impl MatchingEngine {
         trader: Option<[u8; 20]>,
         nonce: Option<u64>,
         expiry: Option<u64>,
-        signature: Option<[u8; 65]>,
-    ) -> (Qty, Vec<MatchDetails>) {
-        let mut remaining_qty = qty;
+        signature: impl Into<Signature>,
+    ) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        let taker = Order::new(qty, LevelId(0), book_id, trader, nonce, expiry, signature);
+        self.match_limit_order(order_id, taker, price, is_bid)
+    }
+
+    /// Matches an order built by the caller at limit `price`, like `match_order`
+    /// This is how an iceberg order goes in: it takes liquidity with all of its quantity, and
+    /// what is left rests showing only its display quantity at a time.
+    /// A reduce-only order is capped at its owner's opposite position in the book, and capped
+    /// again before each of its fills against the position those before it left. What it leaves
+    /// resting is capped the same way whenever it is about to trade, and cancelled once there is
+    /// nothing left to reduce. Fails with PositionsNotTracked if the book's market doesn't track
+    /// positions, and NoPositionToReduce if the owner has no position for the order to reduce.
+    pub fn match_limit_order(
+        &mut self,
+        order_id: OrderId,
+        order: Order,
+        price: u32,
+        is_bid: bool,
+    ) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        let mut fills = Vec::new();
+        let remaining_qty = self.match_limit_order_into(order_id, order, price, is_bid, &mut fills)?;
+        Ok((remaining_qty, fills))
+    }
 
+    /// Matches an order like `match_limit_order`, writing its fills into `fills` instead of a new Vec
+    /// `fills` is emptied first, so a caller matching order after order can keep reusing one
+    /// buffer and the engine allocates nothing for fills once it has grown large enough.
+    pub fn match_limit_order_into(
+        &mut self,
+        order_id: OrderId,
+        order: Order,
+        price: u32,
+        is_bid: bool,
+        fills: &mut Vec<MatchDetails>,
+    ) -> Result<Qty, OrderBookError> {
+        fills.clear();
+        self.check_accepting()?;
+        let started = Instant::now();
+        let _span = tracing::debug_span!(
+            "matching",
+            order_id = order_id.0,
+            book_id = order.book_id().value(),
+            qty = order.qty().value(),
+            price,
+            is_bid
+        )
+        .entered();
         // Convert price to internal format
-        let price = Price::from_u32(price, is_bid);
+        let limit = Price::from_u32(price, is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+        let book_id = order.book_id();
+        self.open_book(book_id)?;
+        self.check_not_crossed(book_id)?;
+        self.check_price_band(book_id, price)?;
 
-        // Get the opposite side's best price
-        let opposite_best_price = if is_bid {
-            self.orderbook_manager
-                .get_best_ask(book_id)
+        let remaining_qty = self.match_limit(order_id, order, limit, is_bid, fills)?;
+        tracing::debug!(fills = fills.len(), remaining = remaining_qty.value(), "Matched limit order");
+        self.after_match(book_id, fills);
+        self.metrics.match_latency.observe(started.elapsed());
+        Ok(remaining_qty)
+    }
+
+    /// Works out what a limit order would do if it went in now, changing nothing
+    /// The checks and the walk of the book are those of match_limit_order: the order would trade
+    /// at its limit price with the orders preview_takes gives, and rest what is left, all of it in
+    /// a book in an auction. Hidden iceberg reserve isn't counted, so the order can fill more than
+    /// previewed but never less, and stops its fills would trigger are left out.
+    pub fn match_preview(
+        &self,
+        book_id: BookId,
+        qty: Qty,
+        price: u32,
+        is_bid: bool,
+        trader: Option<[u8; 20]>,
+        reduce_only: bool,
+    ) -> Result<MatchPreview, OrderBookError> {
+        self.check_accepting()?;
+        Price::from_u32(price, is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+        self.check_price_band(book_id, price)?;
+        let mut taker = Order::new(qty, LevelId(0), book_id, trader, None, None, None);
+        if reduce_only {
+            taker = taker.with_reduce_only();
+        }
+        self.check_reduce_only(&mut taker, is_bid)?;
+        let takes = if self.in_auction(book_id) {
+            Vec::new()
         } else {
-            self.orderbook_manager
-                .get_best_bid(book_id)
+            self.orderbook_manager.preview_takes(book_id, is_bid, taker.qty(), price)
         };
+        let fills: Vec<PreviewFill> =
+            takes.into_iter().map(|(maker_order_id, _, qty)| PreviewFill { maker_order_id, price, qty }).collect();
+        let filled_qty = Qty(fills.iter().map(|fill| fill.qty.value()).sum());
+        let remaining_qty = taker.qty().saturating_sub(filled_qty);
+        Ok(MatchPreview {
+            fills,
+            filled_qty,
+            average_price: (!filled_qty.is_empty()).then_some(price),
+            remaining_qty,
+            rests: !remaining_qty.is_empty(),
+        })
+    }
 
-        // Check if we can match (price crosses spread)
-        let can_match = match opposite_best_price {
-            Some(best_price) => {
-                if is_bid {
-                    price.absolute() >= best_price.absolute()
-                } else {
-                    price.absolute() <= best_price.absolute()
+    /// Matches a limit order that passed the checks of match_order and rests what is left of it
+    /// In a book in an auction the whole order rests. Fills are appended to `fills`.
+    fn match_limit(
+        &mut self,
+        order_id: OrderId,
+        taker: Order,
+        limit: Price,
+        is_bid: bool,
+        fills: &mut Vec<MatchDetails>,
+    ) -> Result<Qty, OrderBookError> {
+        self.match_limit_pass(order_id, taker, limit, is_bid, fills, true)
+    }
+
+    /// Runs one pass of match_limit; only the first pass of an order acknowledges its arrival
+    /// An order that reaches the fill limit and could still trade is cancelled or queued to go
+    /// on, see FillLimitPolicy, instead of resting. A cancelled order has no quantity left.
+    fn match_limit_pass(
+        &mut self,
+        order_id: OrderId,
+        taker: Order,
+        limit: Price,
+        is_bid: bool,
+        fills: &mut Vec<MatchDetails>,
+        first_pass: bool,
+    ) -> Result<Qty, OrderBookError> {
+        let mut taker = self.stamp_arrival(taker);
+        taker.set_price(limit);
+        self.check_reduce_only(&mut taker, is_bid)?;
+        let (book_id, qty) = (taker.book_id(), taker.qty());
+        let in_auction = self.in_auction(book_id);
+        let first_fill = fills.len();
+        let remaining_qty = if in_auction {
+            qty
+        } else {
+            self.cross(order_id, &taker, limit, is_bid, false, fills)?
+        };
+        // Only the fill limit stops an order short of all it could trade
+        let cut_short = !in_auction && remaining_qty.value() > 0 && self.crosses(book_id, limit, is_bid);
+        let cancelled = cut_short && self.fill_limit_policy == FillLimitPolicy::Cancel;
+
+        if cut_short {
+            if cancelled {
+                self.fill_limit_cancelled = Some(order_id);
+            } else {
+                let mut order = taker.clone();
+                order.set_qty(remaining_qty);
+                self.continuations.push_back(Continuation { order_id, order, is_bid });
+                if first_pass {
+                    self.schedule_expiry(order_id, taker.expiry());
                 }
             }
-            None => false,
+        } else if remaining_qty.value() > 0 {
+            // Add any remaining quantity to the book
+            let mut resting = taker.clone();
+            resting.set_qty(remaining_qty);
+            self.orderbook_manager.rest_order(order_id, resting, limit.absolute() as u32, is_bid)?;
+            self.schedule_expiry(order_id, taker.expiry());
+            if taker.reduce_only() {
+                self.remember_reduce_only(order_id);
+            }
+        }
+        if in_auction {
+            self.publish_indicative(book_id);
+        }
+
+        if let Some(trader) = taker.trader() {
+            let mut update = OrderUpdate::taker(order_id, book_id, trader, qty, remaining_qty);
+            if cancelled {
+                update.status = OrderStatus::Cancelled;
+                update.remaining_qty = 0;
+            }
+            let received_at = first_pass.then(|| taker.received_at());
+            let filled_notional = fills[first_fill..].iter().map(|fill| notional(fill.exec_price, fill.exec_qty)).sum();
+            self.orderbook_manager.publish_update(OrderUpdate { received_at, filled_notional, ..update });
+        }
+
+        Ok(if cancelled { Qty(0) } else { remaining_qty })
+    }
+
+    /// Stamps an order that arrived without going through OrderIntake with the time it reached the engine
+    #[inline]
+    fn stamp_arrival(&self, order: Order) -> Order {
+        match order.received_at() {
+            0 => order.with_received_at(self.clock.now()),
+            _ => order,
+        }
+    }
+
+    /// Executes an order against the best prices of the opposite side and cancels what is left
+    /// Each fill trades at the maker's price. In a book with a price band the order stops at the
+    /// band edge; the quantity that could have traded beyond it is reported as band_cut_qty. One
+    /// that reaches the fill limit is cancelled there too, whatever the FillLimitPolicy.
+    /// Fails with UnknownBook or NoMarket if orders can't go into the order's book (see open_book),
+    /// and with BookInAuction if it is in an auction, where a market order has no price to rest at.
+    pub fn match_market_order(
+        &mut self,
+        order_id: OrderId,
+        order: Order,
+        is_bid: bool,
+    ) -> Result<MarketOrderFill, OrderBookError> {
+        self.check_accepting()?;
+        let started = Instant::now();
+        let book_id = order.book_id();
+        let _span = tracing::debug_span!(
+            "matching",
+            order_id = order_id.0,
+            book_id = book_id.value(),
+            qty = order.qty().value(),
+            is_bid
+        )
+        .entered();
+        let fill = self.execute_market(order_id, order, is_bid)?;
+        tracing::debug!(fills = fill.matches.len(), "Matched market order");
+        self.after_match(book_id, &fill.matches);
+        self.metrics.match_latency.observe(started.elapsed());
+        Ok(fill)
+    }
+
+    /// Runs a market order as match_market_order does, without triggering stops or repricing pegs
+    fn execute_market(
+        &mut self,
+        order_id: OrderId,
+        order: Order,
+        is_bid: bool,
+    ) -> Result<MarketOrderFill, OrderBookError> {
+        let book_id = order.book_id();
+        self.open_book(book_id)?;
+        self.check_not_crossed(book_id)?;
+        if self.in_auction(book_id) {
+            return Err(OrderBookError::BookInAuction(book_id));
+        }
+        let mut order = self.stamp_arrival(order);
+        self.check_reduce_only(&mut order, is_bid)?;
+        let band = self.price_band(book_id);
+        let edge = match band {
+            Some((low, high)) => if is_bid { high } else { low },
+            None => if is_bid { i32::MAX as u32 } else { 0 },
+        };
+        let edge = edge.min(i32::MAX as u32);
+        let limit = Price::from_u32(edge, is_bid).ok_or(OrderBookError::InvalidPrice(edge))?;
+        let mut matches = Vec::new();
+        let remaining_qty = self.cross(order_id, &order, limit, is_bid, true, &mut matches)?;
+
+        // Unless the fill limit stopped the order, whatever is left on the opposite side now lies beyond the band
+        let opposite_left = if is_bid {
+            self.orderbook_manager.get_best_ask(book_id)
+        } else {
+            self.orderbook_manager.get_best_bid(book_id)
         };
+        let band_cut_qty = match (band, opposite_left) {
+            (Some(_), Some(_)) if !self.crosses(book_id, limit, is_bid) => remaining_qty,
+            _ => Qty(0),
+        };
+
+        if let Some(trader) = order.trader() {
+            let mut update = OrderUpdate::taker(order_id, book_id, trader, order.qty(), remaining_qty);
+            update.received_at = Some(order.received_at());
+            if remaining_qty.value() > 0 {
+                update.status = OrderStatus::Cancelled;
+                update.remaining_qty = 0;
+            }
+            self.orderbook_manager.publish_update(update);
+        }
+
+        Ok(MarketOrderFill {
+            filled_qty: Qty(order.qty().value() - remaining_qty.value()),
+            cancelled_qty: remaining_qty,
+            band_cut_qty,
+            matches,
+        })
+    }
+
+    /// Fills `taker` against resting orders priced at `limit` or better
+    /// Fills trade at the taker's limit, or with `at_maker_price` at the maker's level.
+    /// Appends the fills to `fills` and returns the quantity left unfilled; nothing of the taker rests.
+    /// It stops after max_fills_per_order fills, leaving the book still crossing `limit` if the
+    /// taker could have gone on.
+    fn cross(
+        &mut self,
+        order_id: OrderId,
+        taker: &Order,
+        limit: Price,
+        is_bid: bool,
+        at_maker_price: bool,
+        fills: &mut Vec<MatchDetails>,
+    ) -> Result<Qty, OrderBookError> {
+        let book_id = taker.book_id();
+        let mut remaining_qty = taker.qty();
+        let settles = self.market_manager.get_config(book_id).is_some();
+        // The book as a limit order found it, before any of its fills, which are measured against it
+        let arrival = (!at_maker_price).then(|| {
+            let best = |price: Option<Price>| price.map(|price| price.absolute() as u32);
+            let manager = &self.orderbook_manager;
+            (best(manager.get_best_bid(book_id)), best(manager.get_best_ask(book_id)))
+        });
+        let tick_size = self.price_scale(book_id).tick_size;
+
+        let max_fills = self.max_fills_per_order.unwrap_or(usize::MAX);
+        let mut fill_count = 0;
+
+        if self.crosses(book_id, limit, is_bid) {
+            let timestamp = self.clock.now();
+
+            // Match against resting orders until either:
+            // 1. The incoming order is fully filled
+            // 2. There are no more orders at acceptable prices
+            // 3. It has made as many fills as one pass may
+            while remaining_qty.value() > 0 && fill_count < max_fills {
+                if let Some((resting_order_id, match_qty, maker_price)) = self.orderbook_manager
+                    .get_next_match(book_id, is_bid, limit) 
+                {
+                    // Reduce-only orders are held to positions as the fills so far left them
+                    if self.cap_reduce_only(book_id, resting_order_id, !is_bid) {
+                        continue;
+                    }
+                    let mut exec_qty = std::cmp::min(remaining_qty, match_qty);
+                    if taker.reduce_only() {
+                        let reducible = Qty(self.reducible(book_id, taker.trader(), is_bid));
+                        if reducible.is_empty() {
+                            break;
+                        }
+                        exec_qty = exec_qty.min(reducible);
+                    }
+                    let exec_price = if at_maker_price { maker_price } else { limit }.absolute() as u32;
+                    let improvement = arrival.map(|quoted| {
+                        PriceImprovement::new(exec_price, maker_price.absolute() as u32, is_bid, tick_size, quoted)
+                    });
+
+                    // Capture the maker before execution, a full fill removes it from the map. Only a
+                    // fill that settles needs the whole order, signature included.
+                    let oid_map = &self.orderbook_manager.oid_map;
+                    let maker = FillSide::resting(oid_map, resting_order_id);
+                    let maker_order = if settles { oid_map.get_order(resting_order_id) } else { None };
+
+                    // Execute the match
+                    let exec_qty = self.orderbook_manager.execute_order_at(resting_order_id, exec_qty, exec_price)?;
+                    fill_count += 1;
+                    remaining_qty = remaining_qty.checked_sub(exec_qty).ok_or(
+                        OrderBookError::QtyExceedsRemaining { requested: exec_qty, remaining: remaining_qty },
+                    )?;
+
+                    let trade = Trade {
+                        trade_id: self.next_trade_id,
+                        timestamp,
+                        price: exec_price,
+                        qty: exec_qty,
+                        aggressor_is_bid: is_bid,
+                        maker_order_id: resting_order_id,
+                        taker_order_id: order_id,
+                    };
+                    let traders = (maker.as_ref().and_then(|maker| maker.trader), taker.trader());
+                    self.record_trade(book_id, trade, traders, improvement);
+
+                    // Add match details
+                    if let Some(maker) = maker {
+                        let orders = maker_order.as_ref().map(|maker_order| (maker_order, taker));
+                        let taker_side = TakerInfo::new(FillSide::new(order_id, taker), taker.qty(), remaining_qty);
+                        let fill = self.settle(book_id, &trade, maker, taker_side, orders);
+                        fills.push(MatchDetails { improvement, ..fill });
+                    }
+                } else {
+                    break;
+                }
+            }
+        }
+        if taker.reduce_only() {
+            remaining_qty = remaining_qty.min(Qty(self.reducible(book_id, taker.trader(), is_bid)));
+        }
+
+        Ok(remaining_qty)
+    }
+
+    /// Returns true if an order at `limit` would trade with the best price of the opposite side
+    fn crosses(&self, book_id: BookId, limit: Price, is_bid: bool) -> bool {
+        // Get the opposite side's best price
+        let opposite_best_price = if is_bid {
+            self.orderbook_manager
+                .get_best_ask(book_id)
+        } else {
+            self.orderbook_manager
+                .get_best_bid(book_id)
+        };
+
+        // Check if we can match (price crosses spread)
+        match opposite_best_price {
+            Some(best_price) => {
+                if is_bid {
+                    limit.absolute() >= best_price.absolute()
+                } else {
+                    limit.absolute() <= best_price.absolute()
+                }
+            }
+            None => false,
+        }
+    }
+
+    /// Prints a trade: appends it to its book's tape, statistics and candles, and publishes it
+    /// `traders` are the maker's and the taker's, for the event stream, and `improvement` is that
+    /// of a limit order's fill.
+    fn record_trade(
+        &mut self,
+        book_id: BookId,
+        trade: Trade,
+        (maker_trader, taker_trader): (Option<[u8; 20]>, Option<[u8; 20]>),
+        improvement: Option<PriceImprovement>,
+    ) {
+        let capacity = self.trade_tape_capacity;
+        self.trade_tapes
+            .entry(book_id)
+            .or_insert_with(|| TradeTape::new(capacity))
+            .push(trade);
+        self.stats.record(book_id, &trade);
+        if let Some(improvement) = improvement {
+            self.stats.record_improvement(book_id, improvement.ticks);
+        }
+        self.candles.record(book_id, &trade);
+        self.metrics.record_trade(book_id, trade.qty.value());
+        tracing::trace!(
+            trade_id = trade.trade_id,
+            maker_order_id = trade.maker_order_id.0,
+            taker_order_id = trade.taker_order_id.0,
+            qty = trade.qty.value(),
+            price = trade.price,
+            "Trade"
+        );
+        for order_id in [trade.maker_order_id, trade.taker_order_id] {
+            if self.oco.group_of(order_id).is_some() {
+                self.oco_fills.push(order_id);
+            }
+        }
+        let book_seq = self.orderbook_manager.book_seq(book_id);
+        let published = self.orderbook_manager.market_data.publish_trade(book_id, book_seq, &trade);
+        debug_assert!(published.is_ok(), "{:?}", published);
+        self.orderbook_manager
+            .emit(book_id, |seq, book_seq| OrderBookEvent::Trade { seq, book_seq, book_id, trade, maker_trader, taker_trader, improvement });
+        self.next_trade_id += 1;
+    }
+
+    /// Describes the fill behind a trade
+    /// In books with a market configuration the fill is translated and tracked as a Pending settlement,
+    /// and in those whose market tracks positions it moves the positions of both traders.
+    /// Translating takes both whole orders, `orders`, which callers only fetch for books that settle.
+    fn settle(
+        &mut self,
+        book_id: BookId,
+        trade: &Trade,
+        maker: FillSide,
+        taker: TakerInfo,
+        orders: Option<(&Order, &Order)>,
+    ) -> MatchDetails {
+        let (exec_qty, exec_price, maker_is_buyer) = (trade.qty, trade.price, !trade.aggressor_is_bid);
+        if self.tracks_positions(book_id) {
+            for (trader, is_buy) in [(maker.trader, maker_is_buyer), (taker.trader, !maker_is_buyer)] {
+                if let Some(trader) = trader {
+                    self.positions.record(trader, book_id, is_buy, exec_qty.value());
+                }
+            }
+        }
+        let config = self.market_manager.get_config(book_id);
+        let translation = config.zip(orders).map(|(config, (maker_order, taker_order))| {
+            translate_to_settlement(maker_order, taker_order, exec_qty, exec_price, trade.trade_id, config)
+        });
+        let (settlement_id, settlement_error) = match translation {
+            Some(Ok(order)) => {
+                let settlement_id = self.settlements.register(TrackedSettlement {
+                    settlement_id: 0, // Assigned by the tracker
+                    trade_id: trade.trade_id,
+                    book_id: book_id.value(),
+                    maker_order_id: trade.maker_order_id.0,
+                    taker_order_id: trade.taker_order_id.0,
+                    exec_qty: exec_qty.value(),
+                    exec_price,
+                    order,
+                    status: SettlementStatus::Pending,
+                    batch_id: None,
+                    created_at: trade.timestamp,
+                    updated_at: trade.timestamp,
+                });
+                (Some(settlement_id), None)
+            }
+            Some(Err(error)) => (None, Some(error)),
+            None => (None, None),
+        };
+        MatchDetails {
+            maker,
+            taker,
+            exec_qty,
+            exec_price,
+            maker_is_buyer,
+            trade_id: trade.trade_id,
+            executed_at: trade.timestamp,
+            settlement_id,
+            settlement_error,
+            improvement: None,
+        }
+    }
+
+    /// Trades a block between the two signed orders of an accepted RFQ quote, without touching the book
+    /// The block prints and settles like any fill, the maker's order as the maker, and both
+    /// orders are reported Filled. The caller has checked the quote, both signatures and both
+    /// nonces, and logged the block; nothing here is checked against the book's price band or
+    /// risk limits. Fails with UnknownBook for a book that doesn't exist.
+    pub fn execute_block(&mut self, block: &BlockTrade) -> Result<MatchDetails, OrderBookError> {
+        self.check_accepting()?;
+        let book_id = BookId(block.book_id);
+        if self.orderbook_manager.book(book_id).is_none() {
+            return Err(OrderBookError::UnknownBook(book_id));
+        }
+        let (maker, taker) = (block.order(&block.maker), block.order(&block.taker));
+        let (maker_order_id, taker_order_id) = (OrderId(block.maker.order_id), OrderId(block.taker.order_id));
+        let trade = Trade {
+            trade_id: self.next_trade_id,
+            timestamp: self.clock.now(),
+            price: block.price,
+            qty: Qty(block.qty),
+            aggressor_is_bid: block.taker.is_bid,
+            maker_order_id,
+            taker_order_id,
+        };
+        self.record_trade(book_id, trade, (maker.trader(), taker.trader()), None);
+        let maker_side = FillSide::new(maker_order_id, &maker);
+        let taker_side = TakerInfo::new(FillSide::new(taker_order_id, &taker), Qty(block.qty), Qty(0));
+        let fill = self.settle(book_id, &trade, maker_side, taker_side, Some((&maker, &taker)));
+        let filled_notional = notional(block.price, Qty(block.qty));
+        for (order_id, side) in [(maker_order_id, &block.maker), (taker_order_id, &block.taker)] {
+            let update = OrderUpdate::taker(order_id, book_id, side.trader, Qty(block.qty), Qty(0));
+            self.orderbook_manager.publish_update(OrderUpdate { filled_notional, ..update });
+        }
+        Ok(fill)
+    }
+
+    /// Puts a book into an auction
+    /// Until uncross is called, incoming limit orders rest without matching, even when they cross.
+    /// Fails with UnknownBook or NoMarket if orders can't go into the book, see open_book.
+    pub fn enter_auction(&mut self, book_id: BookId) -> Result<(), OrderBookError> {
+        self.open_book(book_id)?;
+        self.auctions.insert(book_id);
+        self.publish_indicative(book_id);
+        Ok(())
+    }
+
+    /// Returns true while a book is in an auction
+    #[inline]
+    pub fn in_auction(&self, book_id: BookId) -> bool {
+        self.auctions.contains(&book_id)
+    }
+
+    /// Gets the price, volume and imbalance a book would uncross at right now, or None if
+    /// nothing crosses. Ties are broken toward the last trade price, see `auction::clearing_price`.
+    pub fn indicative(&self, book_id: BookId) -> Option<Uncross> {
+        let book = self.orderbook_manager.book(book_id)?;
+        let levels = |side: &mut dyn Iterator<Item = &Level>| -> Vec<(u32, u64)> {
+            side.map(|level| (level.price().absolute() as u32, level.size().value())).collect()
+        };
+        let reference = self.stats.get(book_id).and_then(|stats| stats.last_price());
+        clearing_price(&levels(&mut book.iter_bids()), &levels(&mut book.iter_asks()), reference)
+    }
+
+    /// Ends the auction of a book and switches it to continuous trading
+    /// Every crossing order executes at the single clearing price. Bids and asks are paired in
+    /// price-time priority, and of each pair the order that arrived later is the taker, as it
+    /// would have been in continuous trading. Without crossing volume nothing trades.
+    /// Fails with NotInAuction if the book is not in an auction.
+    pub fn uncross(&mut self, book_id: BookId) -> Result<Vec<MatchDetails>, OrderBookError> {
+        self.check_accepting()?;
+        if !self.auctions.remove(&book_id) {
+            return Err(OrderBookError::NotInAuction(book_id));
+        }
+        let Some(uncross) = self.indicative(book_id) else {
+            return Ok(Vec::new());
+        };
+        // Both prices are on the book, so they fit
+        let buy_limit = Price::from_u32(uncross.price, true).ok_or(OrderBookError::InvalidPrice(uncross.price))?;
+        let sell_limit = Price::from_u32(uncross.price, false).ok_or(OrderBookError::InvalidPrice(uncross.price))?;
+
+        let timestamp = self.clock.now();
+        let mut match_details = Vec::new();
+        while let (Some((bid_id, bid_qty, _)), Some((ask_id, ask_qty, _))) = (
+            self.orderbook_manager.get_next_match(book_id, false, sell_limit),
+            self.orderbook_manager.get_next_match(book_id, true, buy_limit),
+        ) {
+            if self.cap_reduce_only(book_id, bid_id, true) || self.cap_reduce_only(book_id, ask_id, false) {
+                continue;
+            }
+            let exec_qty = bid_qty.min(ask_qty);
+            if let Some(fill) = self.trade_resting(book_id, (bid_id, ask_id), exec_qty, uncross.price, timestamp)? {
+                match_details.push(fill);
+            }
+        }
+        self.after_match(book_id, &match_details);
+        Ok(match_details)
+    }
+
+    /// Trades a resting bid against a resting ask at `price`, the later of the two the taker,
+    /// as it would have been in continuous trading
+    /// Returns the fill, or None if either order had no settlement metadata to describe it.
+    fn trade_resting(
+        &mut self,
+        book_id: BookId,
+        (bid_id, ask_id): (OrderId, OrderId),
+        exec_qty: Qty,
+        price: u32,
+        timestamp: u64,
+    ) -> Result<Option<MatchDetails>, OrderBookError> {
+        let settles = self.market_manager.get_config(book_id).is_some();
+        let taker_is_bid = bid_id > ask_id;
+        let (maker_id, taker_id) = if taker_is_bid { (ask_id, bid_id) } else { (bid_id, ask_id) };
+        let oid_map = &self.orderbook_manager.oid_map;
+        let sides = FillSide::resting(oid_map, maker_id).zip(FillSide::resting(oid_map, taker_id));
+        let orders = if settles { oid_map.get_order(maker_id).zip(oid_map.get_order(taker_id)) } else { None };
+        let taker_qty = oid_map.total_qty(taker_id).unwrap_or_default();
+
+        self.orderbook_manager.execute_order_at(bid_id, exec_qty, price)?;
+        self.orderbook_manager.execute_order_at(ask_id, exec_qty, price)?;
+        let trade = Trade {
+            trade_id: self.next_trade_id,
+            timestamp,
+            price,
+            qty: exec_qty,
+            aggressor_is_bid: taker_is_bid,
+            maker_order_id: maker_id,
+            taker_order_id: taker_id,
+        };
+        let traders = sides.as_ref().map_or((None, None), |(maker, taker)| (maker.trader, taker.trader));
+        self.record_trade(book_id, trade, traders, None);
+        let Some((maker, taker)) = sides else {
+            return Ok(None);
+        };
+        let orders = orders.as_ref().map(|(maker_order, taker_order)| (maker_order, taker_order));
+        let taker = TakerInfo::new(taker, taker_qty, taker_qty.saturating_sub(exec_qty));
+        Ok(Some(self.settle(book_id, &trade, maker, taker, orders)))
+    }
+
+    /// Publishes where a book in an auction would uncross, if anyone listens
+    fn publish_indicative(&mut self, book_id: BookId) {
+        if !self.orderbook_manager.market_data.has_subscribers() {
+            return;
+        }
+        let uncross = self.indicative(book_id);
+        let book_seq = self.orderbook_manager.book_seq(book_id);
+        let published = self.orderbook_manager.market_data.publish_indicative(book_id, book_seq, uncross);
+        debug_assert!(published.is_ok(), "{:?}", published);
+    }
+
+    /// Atomically cancels a resting order and re-submits it at a new price and quantity
+    /// The replacement keeps the original side, book, and settlement metadata, and goes
+    /// through the matching path so a marketable price fills immediately.
+    /// Fails with UnknownOrder when the original order does not exist. Every other failure leaves
+    /// the original resting where it was too: a new price outside the book's price band
+    /// (PriceOutsideBand) or not fitting (InvalidPrice), a new ID already in use (DuplicateOrder),
+    /// a full book (BookFull), or a reduce-only original with no position left to reduce.
+    pub fn replace_order(
+        &mut self,
+        order_id: OrderId,
+        new_order_id: OrderId,
+        new_qty: Qty,
+        new_price: u32,
+    ) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        self.check_accepting()?;
+        let original = self.orderbook_manager.oid_map.get_order(order_id);
+        if let Some(original) = original {
+            let book_id = original.book_id();
+            self.check_not_crossed(book_id)?;
+            self.check_price_band(book_id, new_price)?;
+            if new_order_id != order_id && self.order_owner(new_order_id).is_some() {
+                return Err(OrderBookError::DuplicateOrder(new_order_id));
+            }
+            if self.reduce_only.contains(&order_id) {
+                let is_bid = original.price().is_bid();
+                let mut probe = Order::new(new_qty, LevelId(0), book_id, original.trader(), None, None, None).with_reduce_only();
+                self.check_reduce_only(&mut probe, is_bid)?;
+            }
+        }
+        // The client order ID moves to the new order; taking the old one off unbinds it
+        let client_order_id = self.orderbook_manager.client_order_ids.get(order_id);
+        let (order, is_bid) = self
+            .orderbook_manager
+            .take_for_replace(order_id, new_order_id, new_qty, new_price)?;
+        self.oco.remove_order(order_id);
+        if let (Some(client_order_id), Some(trader)) = (client_order_id, order.trader()) {
+            self.orderbook_manager.client_order_ids.insert(trader, client_order_id, new_order_id);
+        }
+        let limit = Price::from_u32(new_price, is_bid).ok_or(OrderBookError::InvalidPrice(new_price))?;
+
+        // The band was checked against the book with the original order still in it
+        let mut taker = Order::new(
+            new_qty,
+            LevelId(0),
+            order.book_id(),
+            order.trader(),
+            order.nonce(),
+            order.expiry(),
+            order.signature(),
+        )
+        .with_price(limit);
+        if let Some(display) = order.display() {
+            taker = taker.with_display(display);
+        }
+        if self.reduce_only.remove(&order_id) {
+            taker = taker.with_reduce_only();
+        }
+        let mut match_details = Vec::new();
+        let remaining_qty = self.match_limit(new_order_id, taker, limit, is_bid, &mut match_details)?;
+        self.after_match(order.book_id(), &match_details);
+        Ok((remaining_qty, match_details))
+    }
+
+    /// Accepts a stop or stop-limit order
+    /// The stop waits outside the book, invisible to depth, until a trade in its book prints at or
+    /// through its trigger; then a stop goes in as a market order and a stop-limit as a limit order.
+    /// A trigger the market is already through fires on the next trade that is too.
+    /// Fails with InvalidPrice if the trigger or limit doesn't fit in an i32, UnknownBook or NoMarket
+    /// if orders can't go into the book, and DuplicateOrder if the order ID is already waiting.
+    pub fn submit_stop(&mut self, stop: StopOrder) -> Result<(), OrderBookError> {
+        self.check_accepting()?;
+        for price in std::iter::once(stop.trigger).chain(stop.limit) {
+            Price::from_u32(price, stop.is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+        }
+        let book_id = BookId(stop.book_id);
+        self.open_book(book_id)?;
+        let (order_id, trader, qty, expiry) = (OrderId(stop.order_id), stop.trader, stop.qty, stop.expiry);
+        self.stops.insert(stop)?;
+        self.schedule_expiry(order_id, expiry);
+        if let Some(trader) = trader {
+            self.orderbook_manager.publish_update(OrderUpdate {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                trader,
+                status: OrderStatus::Untriggered,
+                filled_qty: 0,
+                remaining_qty: qty,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+        Ok(())
+    }
+
+    /// Cancels a stop that has not triggered yet
+    /// Fails with UnknownOrder if no stop with that ID is waiting.
+    pub fn cancel_stop(&mut self, order_id: OrderId) -> Result<StopOrder, OrderBookError> {
+        self.remove_stop(order_id, OrderStatus::Cancelled)
+    }
+
+    /// Takes a waiting stop out, telling its owner why, and unlinks it from its OCO pair
+    fn remove_stop(&mut self, order_id: OrderId, status: OrderStatus) -> Result<StopOrder, OrderBookError> {
+        let stop = self.stops.remove(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        self.publish_stop_update(&stop, status);
+        self.leave_oco(order_id);
+        Ok(stop)
+    }
+
+    /// Gets the stops waiting for their trigger
+    pub fn stops(&self) -> &StopBook {
+        &self.stops
+    }
+
+    /// Gets the status and remaining quantity of an order that is still working, or None
+    /// Stops waiting for their trigger are Untriggered and stop-limits resting after it Triggered.
+    /// Pegged orders off the book are Parked. Other resting orders are New: fills are reported on
+    /// the trader stream, not tracked here.
+    pub fn order_status(&self, order_id: OrderId) -> Option<(OrderStatus, Qty)> {
+        if let Some(stop) = self.stops.get(order_id) {
+            return Some((OrderStatus::Untriggered, Qty(stop.qty)));
+        }
+        if let Some(peg) = self.pegs.get(order_id).filter(|peg| peg.price.is_none()) {
+            return Some((OrderStatus::Parked, Qty(peg.qty)));
+        }
+        let qty = self.orderbook_manager.oid_map.total_qty(order_id)?;
+        let status = if self.triggered_stops.contains(&order_id) {
+            OrderStatus::Triggered
+        } else {
+            OrderStatus::New
+        };
+        Some((status, qty))
+    }
+
+    /// Removes every resting order, waiting stop, parked peg, and order waiting to go on matching
+    /// of a trader, optionally in one book
+    /// Returns the IDs of the cancelled orders: resting ones first, then stops, then parked pegs,
+    /// then the waiting ones.
+    pub fn cancel_all_for_trader(&mut self, trader: [u8; 20], book_id: Option<BookId>) -> Vec<OrderId> {
+        let mut cancelled = self.orderbook_manager.cancel_all_for_trader(trader, book_id);
+        let stops = self.stops.remove_where(|stop| {
+            stop.trader == Some(trader) && book_id.is_none_or(|book_id| stop.book_id == book_id.value())
+        });
+        cancelled.extend(stops);
+        let parked = self.pegs.remove_where(|peg| {
+            peg.price.is_none()
+                && peg.trader == Some(trader)
+                && book_id.is_none_or(|book_id| peg.book_id == book_id.value())
+        });
+        cancelled.extend(parked);
+        let waiting = self.take_continuations(|waiting| {
+            waiting.order.trader() == Some(trader) && book_id.is_none_or(|book_id| waiting.order.book_id() == book_id)
+        });
+        cancelled.extend(self.cancel_continuations(waiting));
+        self.reprice_all_pegs();
+        self.prune_oco();
+        cancelled
+    }
+
+    /// Closes a book: cancels its resting orders, waiting stops, parked pegs, and orders waiting to
+    /// go on matching, telling each owner the book closed, then drops the book with its market,
+    /// auction, and trade tape
+    /// Returns the IDs of the cancelled orders: resting ones first, then stops, then parked pegs,
+    /// then the waiting ones.
+    /// Fails with UnknownBook if the book hasn't been created.
+    pub fn close_book(&mut self, book_id: BookId) -> Result<Vec<OrderId>, OrderBookError> {
+        let mut cancelled = self.orderbook_manager.close_book(book_id).ok_or(OrderBookError::UnknownBook(book_id))?;
+        let stops: Vec<StopOrder> = self.stops.entries().into_iter().filter(|stop| stop.book_id == book_id.value()).collect();
+        for stop in stops {
+            self.stops.remove(OrderId(stop.order_id));
+            self.publish_stop_update(&stop, OrderStatus::BookClosed);
+            cancelled.push(OrderId(stop.order_id));
+        }
+        // Pegs resting in the book went with it; the parked ones are told here
+        let pegs: Vec<PeggedOrder> = self.pegs.entries().into_iter().filter(|peg| peg.book_id == book_id.value()).collect();
+        for peg in pegs {
+            self.pegs.remove(OrderId(peg.order_id));
+            if peg.price.is_none() {
+                self.publish_peg_update(&peg, OrderStatus::BookClosed);
+                cancelled.push(OrderId(peg.order_id));
+            }
+        }
+        for waiting in self.take_continuations(|waiting| waiting.order.book_id() == book_id) {
+            self.publish_continuation_update(&waiting, OrderStatus::BookClosed);
+            cancelled.push(waiting.order_id);
+        }
+        self.prune_oco();
+        self.quotes.remove_book(book_id);
+        self.market_manager.remove_market(book_id);
+        self.auctions.remove(&book_id);
+        self.book_only.remove(&book_id);
+        self.trade_tapes.remove(&book_id);
+        Ok(cancelled)
+    }
+
+    /// Cancels a resting order, a pegged order parked off the book, or what is left of an order
+    /// waiting to go on matching, and tells its owner why
+    /// A leg of an OCO pair leaves the pair, see submit_oco.
+    /// Fails with UnknownOrder if there is no such order.
+    pub fn cancel_resting(&mut self, order_id: OrderId, status: OrderStatus) -> Result<(), OrderBookError> {
+        if let Some(waiting) = self.take_continuations(|waiting| waiting.order_id == order_id).pop() {
+            self.publish_continuation_update(&waiting, status);
+        } else if let Some(peg) = self.pegs.get(order_id).filter(|peg| peg.price.is_none()).cloned() {
+            self.pegs.remove(order_id);
+            self.publish_peg_update(&peg, status);
+        } else {
+            let book_id = self.book_of(order_id).ok_or(OrderBookError::UnknownOrder)?;
+            self.orderbook_manager.cancel_resting(order_id, status)?;
+            self.pegs.remove(order_id);
+            self.reprice_pegs(book_id);
+        }
+        self.leave_oco(order_id);
+        Ok(())
+    }
+
+    /// Gets the price an order rests at, or None if it isn't on a book
+    /// For a pegged order this is the price it pegs to now; a parked one has none.
+    pub fn order_price(&self, order_id: OrderId) -> Option<u32> {
+        Some(self.orderbook_manager.oid_map.get(order_id)?.price().absolute() as u32)
+    }
+
+    /// Binds a trader's client order ID to the order about to be submitted under `order_id`
+    /// Fails with DuplicateClientOrderId while the ID is still bound to a working order of the
+    /// trader; a binding left behind by an order that never came to work is taken over.
+    pub fn bind_client_order_id(
+        &mut self,
+        order_id: OrderId,
+        trader: [u8; 20],
+        client_order_id: ClientOrderId,
+    ) -> Result<(), OrderBookError> {
+        if let Some(existing) = self.orderbook_manager.client_order_ids.find(trader, client_order_id) {
+            if existing != order_id && self.order_owner(existing).is_some() {
+                return Err(OrderBookError::DuplicateClientOrderId(client_order_id));
+            }
+        }
+        self.orderbook_manager.client_order_ids.insert(trader, client_order_id, order_id);
+        Ok(())
+    }
+
+    /// Gets the working order a trader's client order ID is bound to
+    pub fn find_client_order(&self, trader: [u8; 20], client_order_id: ClientOrderId) -> Option<OrderId> {
+        let order_id = self.orderbook_manager.client_order_ids.find(trader, client_order_id)?;
+        self.order_owner(order_id).map(|_| order_id)
+    }
+
+    /// Gets the trader of a working order: resting, a stop waiting for its trigger, a parked peg,
+    /// or one waiting to go on matching
+    /// Returns None if there is no such order, and Some(None) for an order entered without a trader.
+    pub fn order_owner(&self, order_id: OrderId) -> Option<Option<[u8; 20]>> {
+        if let Some(waiting) = self.continuations.iter().find(|waiting| waiting.order_id == order_id) {
+            return Some(waiting.order.trader());
+        }
+        if let Some(stop) = self.stops.get(order_id) {
+            return Some(stop.trader);
+        }
+        if let Some(peg) = self.pegs.get(order_id) {
+            return Some(peg.trader);
+        }
+        Some(self.orderbook_manager.oid_map.get(order_id)?.trader())
+    }
+
+    /// Expires the good-til-time orders due at `now`, in seconds since the Unix epoch, and returns
+    /// their IDs, soonest expiry first
+    /// The server's timer calls this; each expiry is logged as WalCommand::Expire before it is
+    /// applied, so a replay expires the same orders without a clock. Orders that were filled,
+    /// cancelled, or replaced before their expiry are skipped. If the log fails, polling stops
+    /// and the order that could not be logged is tried again on the next poll.
+    pub fn poll_expirations(&mut self, now: u64) -> Vec<OrderId> {
+        let mut expired = Vec::new();
+        while let Some((expiry, order_id)) = self.expiries.pop_due(now) {
+            if self.expiry_of(order_id) != Some(expiry) {
+                continue;
+            }
+            if self.log(&WalCommand::Expire { order_id: order_id.0 }).is_err() {
+                self.expiries.schedule(order_id, Some(expiry));
+                break;
+            }
+            if self.expire_order(order_id).is_ok() {
+                expired.push(order_id);
+            }
+        }
+        expired
+    }
+
+    /// Runs the next pass of the order that has waited longest to go on matching, see FillLimitPolicy::Continue
+    /// Returns the order's ID and the fills of the pass, or None if no order waits or the engine
+    /// isn't taking orders. Each pass is logged as WalCommand::Resume before it runs, so a replay
+    /// runs it between the same commands; if the log fails, the order keeps waiting and can_resume
+    /// is false until the log takes another command.
+    /// What the order can no longer trade rests, and if its book was halted crossed, it is cancelled.
+    pub fn resume_matching(&mut self) -> Option<(OrderId, Vec<MatchDetails>)> {
+        if !self.can_resume() {
+            return None;
+        }
+        let order_id = self.continuations.front()?.order_id;
+        if self.log(&WalCommand::Resume { order_id: order_id.0 }).is_err() {
+            self.resume_stalled = true;
+            return None;
+        }
+        Some((order_id, self.resume(order_id)))
+    }
+
+    /// Runs the next pass of a waiting order, wherever it is in the queue, and returns its fills
+    fn resume(&mut self, order_id: OrderId) -> Vec<MatchDetails> {
+        let Some(waiting) = self.take_continuations(|waiting| waiting.order_id == order_id).pop() else {
+            return Vec::new();
+        };
+        let book_id = waiting.order.book_id();
+        let mut fills = Vec::new();
+        let passed = self.check_not_crossed(book_id).and_then(|()| {
+            self.match_limit_pass(order_id, waiting.order.clone(), waiting.order.price(), waiting.is_bid, &mut fills, false)
+        });
+        if passed.is_err() {
+            self.publish_continuation_update(&waiting, OrderStatus::Cancelled);
+        }
+        self.after_match(book_id, &fills);
+        fills
+    }
+
+    /// Takes the waiting orders `filter` selects out of the queue; the caller tells their owners
+    fn take_continuations(&mut self, filter: impl Fn(&Continuation) -> bool) -> Vec<Continuation> {
+        let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.continuations).into_iter().partition(filter);
+        self.continuations = kept.into();
+        taken
+    }
+
+    /// Tells the owners of orders taken out of the queue that they are cancelled, returning their IDs
+    fn cancel_continuations(&mut self, waiting: Vec<Continuation>) -> Vec<OrderId> {
+        waiting
+            .into_iter()
+            .map(|waiting| {
+                self.publish_continuation_update(&waiting, OrderStatus::Cancelled);
+                waiting.order_id
+            })
+            .collect()
+    }
+
+    /// Tells the owner of an order that was waiting to go on matching why what was left of it is gone
+    fn publish_continuation_update(&mut self, waiting: &Continuation, status: OrderStatus) {
+        if let Some(trader) = waiting.order.trader() {
+            self.orderbook_manager.publish_update(OrderUpdate {
+                order_id: waiting.order_id.0,
+                book_id: waiting.order.book_id().value(),
+                trader,
+                status,
+                filled_qty: 0,
+                remaining_qty: 0,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+    }
+
+    /// Cancels a working order, resting, parked, waiting to go on matching, or a waiting stop, as Expired
+    /// Fails with UnknownOrder if there is no such order.
+    fn expire_order(&mut self, order_id: OrderId) -> Result<(), OrderBookError> {
+        match self.stops.get(order_id) {
+            Some(_) => self.remove_stop(order_id, OrderStatus::Expired).map(|_| ()),
+            None => self.cancel_resting(order_id, OrderStatus::Expired),
+        }
+    }
+
+    /// Gets the expiry of a working order, or None if it has none or isn't working
+    fn expiry_of(&self, order_id: OrderId) -> Option<u64> {
+        if let Some(stop) = self.stops.get(order_id) {
+            return stop.expiry;
+        }
+        if let Some(peg) = self.pegs.get(order_id).filter(|peg| peg.price.is_none()) {
+            return peg.expiry;
+        }
+        if let Some(waiting) = self.continuations.iter().find(|waiting| waiting.order_id == order_id) {
+            return waiting.order.expiry();
+        }
+        self.orderbook_manager.oid_map.get(order_id)?;
+        self.orderbook_manager.oid_map.meta(order_id)?.expiry
+    }
+
+    /// Schedules a working order to expire, see ExpirySchedule::schedule
+    /// Entries of orders that stopped working are dropped whenever the schedule reaches a power
+    /// of two, which keeps it bounded by the working orders at amortized constant cost.
+    fn schedule_expiry(&mut self, order_id: OrderId, expiry: Option<u64>) {
+        self.expiries.schedule(order_id, expiry);
+        let len = self.expiries.len();
+        if len >= 1024 && len.is_power_of_two() {
+            let mut expiries = std::mem::take(&mut self.expiries);
+            expiries.retain(|order_id, expiry| self.expiry_of(order_id) == Some(expiry));
+            self.expiries = expiries;
+        }
+    }
+
+    /// Gets the book a resting order is in
+    fn book_of(&self, order_id: OrderId) -> Option<BookId> {
+        self.orderbook_manager.oid_map.get(order_id).map(|order| order.book_id())
+    }
+
+    /// Accepts a pegged order
+    /// The order rests at the price its peg gives, see `PeggedOrder::target`, and follows the
+    /// book: whenever the book changes its pegs are repriced, each going to the back of the queue
+    /// at its new price. Pegs follow the best prices of the other orders, never of pegs. Unless it
+    /// may cross, a peg stays a tick off the other side and never executes by moving; incoming
+    /// orders can still hit it like any resting order. Pegs hold still while their book is in
+    /// an auction.
+    /// A resting peg that loses its reference, e.g. a midpoint peg whose book empties on one side,
+    /// or whose price leaves the price band, is parked off the book and goes back once it has a
+    /// price again. A peg submitted without a reference is rejected instead.
+    /// Fails with InvalidPrice if the limit doesn't fit in an i32, UnknownBook or NoMarket if orders
+    /// can't go into the book, DuplicateOrder if the order ID is taken, NoPegReference if there is nothing to peg
+    /// to, and PriceOutsideBand if the price it pegs to is outside the price band.
+    pub fn submit_pegged(&mut self, mut peg: PeggedOrder) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        self.check_accepting()?;
+        let (order_id, book_id, is_bid) = (OrderId(peg.order_id), BookId(peg.book_id), peg.is_bid);
+        Price::from_u32(peg.limit, is_bid).ok_or(OrderBookError::InvalidPrice(peg.limit))?;
+        self.open_book(book_id)?;
+        if self.pegs.get(order_id).is_some() || self.orderbook_manager.oid_map.get(order_id).is_some() {
+            return Err(OrderBookError::DuplicateOrder(order_id));
+        }
+        self.check_not_crossed(book_id)?;
+        let price = self.peg_target(&peg).ok_or(OrderBookError::NoPegReference(book_id))?;
+        self.check_price_band(book_id, price)?;
+        let limit = Price::from_u32(price, is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+
+        let order = peg.order();
+        peg.price = Some(price);
+        self.pegs.insert(peg)?;
+        let mut match_details = Vec::new();
+        let remaining_qty = match self.match_limit(order_id, order, limit, is_bid, &mut match_details) {
+            Ok(remaining_qty) => remaining_qty,
+            Err(error) => {
+                self.pegs.remove(order_id);
+                return Err(error);
+            }
+        };
+        if remaining_qty.value() == 0 {
+            self.pegs.remove(order_id);
+        }
+        self.after_match(book_id, &match_details);
+        Ok((remaining_qty, match_details))
+    }
+
+    /// Gets the positions of the traders in books whose market tracks them
+    pub fn positions(&self) -> &PositionTracker {
+        &self.positions
+    }
+
+    /// Gets the pegged orders, resting or parked
+    pub fn pegs(&self) -> &PegBook {
+        &self.pegs
+    }
+
+    /// Follows up on a change to a book: settles the OCO legs that traded, fires the stops its
+    /// fills trigger, reprices its pegs, and settles the legs those traded in turn
+    fn after_match(&mut self, book_id: BookId, match_details: &[MatchDetails]) {
+        self.settle_oco();
+        self.trigger_stops(book_id, match_details);
+        self.reprice_pegs(book_id);
+        self.settle_oco();
+        self.check_crossing(book_id);
+        self.check_crossed(book_id);
+    }
+
+    /// Gets the best bid and ask of a book if they cross outside an auction, which matching
+    /// never leaves them doing
+    fn crossing(&self, book_id: BookId) -> Option<(u32, u32)> {
+        if self.in_auction(book_id) {
+            return None;
+        }
+        let manager = &self.orderbook_manager;
+        let best_bid = manager.get_best_bid(book_id)?.absolute() as u32;
+        let best_ask = manager.get_best_ask(book_id)?.absolute() as u32;
+        (best_bid >= best_ask).then_some((best_bid, best_ask))
+    }
+
+    /// Halts a book left crossed outside an auction, after any change that may have done it
+    /// A crossed book is a bug elsewhere, so debug builds panic on one. Otherwise the book
+    /// refuses orders with BookCrossed until repair_crossed uncrosses it, the critical
+    /// BookCrossed event goes out, and market data subscribers see the book halt.
+    fn check_crossed(&mut self, book_id: BookId) {
+        if self.crossed.contains(&book_id) {
+            return;
+        }
+        let Some((best_bid, best_ask)) = self.crossing(book_id) else {
+            return;
+        };
+        debug_assert!(self.cross_expected(), "Book {} crossed: bid {} at or above ask {}", book_id.value(), best_bid, best_ask);
+        tracing::error!(book_id = book_id.value(), best_bid, best_ask, "Book found crossed; halting it");
+        self.crossed.insert(book_id);
+        self.orderbook_manager
+            .emit(book_id, |seq, book_seq| OrderBookEvent::BookCrossed { seq, book_seq, book_id, best_bid, best_ask });
+        if self.orderbook_manager.market_data.has_subscribers() {
+            self.publish_halt(book_id, true);
+        }
+    }
+
+    #[cfg(test)]
+    fn cross_expected(&self) -> bool {
+        self.cross_expected
+    }
+
+    #[cfg(not(test))]
+    #[inline(always)]
+    fn cross_expected(&self) -> bool {
+        false
+    }
+
+    /// Rests an order without matching it, crossing the book if its price does, and checks the
+    /// book as any change would; stands in for the bug check_crossed is there to catch
+    #[cfg(test)]
+    pub(crate) fn rest_unmatched(&mut self, order_id: OrderId, book_id: BookId, qty: Qty, price: u32, is_bid: bool) {
+        self.cross_expected = true;
+        let order = Order::new(qty, LevelId(0), book_id, None, None, None, None);
+        self.orderbook_manager.rest_order(order_id, order, price, is_bid).unwrap();
+        self.check_crossed(book_id);
+    }
+
+    /// Uncrosses a book halted for being found crossed, and lets it trade again
+    /// While the best bid is at or above the best ask, the orders at the front of the two levels
+    /// trade at the price of whichever arrived first, as the later one would have on arrival
+    /// had it matched. Does nothing to a book that isn't halted crossed.
+    pub fn repair_crossed(&mut self, book_id: BookId) -> Result<Vec<MatchDetails>, OrderBookError> {
+        self.check_accepting()?;
+        if !self.crossed.remove(&book_id) {
+            return Ok(Vec::new());
+        }
+        let timestamp = self.clock.now();
+        let mut match_details = Vec::new();
+        while let Some((best_bid, best_ask)) = self.crossing(book_id) {
+            // Both prices are on the book, so they fit
+            let buy_limit = Price::from_u32(best_bid, true).ok_or(OrderBookError::InvalidPrice(best_bid))?;
+            let sell_limit = Price::from_u32(best_ask, false).ok_or(OrderBookError::InvalidPrice(best_ask))?;
+            let (Some((bid_id, bid_qty, bid_price)), Some((ask_id, ask_qty, ask_price))) = (
+                self.orderbook_manager.get_next_match(book_id, false, sell_limit),
+                self.orderbook_manager.get_next_match(book_id, true, buy_limit),
+            ) else {
+                break;
+            };
+            if self.cap_reduce_only(book_id, bid_id, true) || self.cap_reduce_only(book_id, ask_id, false) {
+                continue;
+            }
+            let maker_price = if bid_id < ask_id { bid_price } else { ask_price };
+            let exec_qty = bid_qty.min(ask_qty);
+            if let Some(fill) = self.trade_resting(book_id, (bid_id, ask_id), exec_qty, maker_price.absolute() as u32, timestamp)? {
+                match_details.push(fill);
+            }
+        }
+        if self.orderbook_manager.market_data.has_subscribers() {
+            self.publish_halt(book_id, false);
+        }
+        self.after_match(book_id, &match_details);
+        Ok(match_details)
+    }
+
+    /// Panics with a report of the book if it is left crossed outside an auction.
+    /// Only built with the paranoid-checks feature in debug builds; OrderBookManager checks the
+    /// levels themselves after every change.
+    #[cfg(all(feature = "paranoid-checks", debug_assertions))]
+    fn check_crossing(&self, book_id: BookId) {
+        let manager = &self.orderbook_manager;
+        let Some(book) = manager.book(book_id).filter(|_| !self.in_auction(book_id)) else {
+            return;
+        };
+        if let Err(error) = book.check_invariants(book_id, &manager.oid_map) {
+            panic!("Book {} broke an invariant after matching: {}\n{}", book_id.value(), error, book.describe(&manager.oid_map));
+        }
+    }
+
+    #[cfg(not(all(feature = "paranoid-checks", debug_assertions)))]
+    #[inline(always)]
+    fn check_crossing(&self, _book_id: BookId) {}
+
+    /// Accepts two orders linked as a one-cancels-other pair
+    /// A fill of either leg cancels the other or, under OcoPolicy::Reduce, shrinks it in proportion
+    /// to what the filled leg has left; a stop leg that triggers cancels the other outright.
+    /// Cancelling or expiring a leg unlinks the pair, and with `cancel_together` cancels the other
+    /// leg too. Stop legs go in first, so a limit leg that fills on arrival finds its sibling in
+    /// place; a second limit leg whose sibling filled on arrival goes in reduced, or not at all.
+    /// Returns the fills of the legs on arrival. The pair is known by the first leg's order ID.
+    /// Fails before submitting anything: with InvalidOco if the legs are one order or in two books,
+    /// DuplicateOrder if either order ID is taken, and otherwise as submit_stop and
+    /// match_limit_order would.
+    pub fn submit_oco(
+        &mut self,
+        legs: [OcoLeg; 2],
+        policy: OcoPolicy,
+        cancel_together: bool,
+    ) -> Result<Vec<MatchDetails>, OrderBookError> {
+        self.check_accepting()?;
+        let [first, second] = &legs;
+        if first.order_id() == second.order_id() || first.book_id() != second.book_id() {
+            return Err(OrderBookError::InvalidOco);
+        }
+        let book_id = BookId(first.book_id());
+        self.open_book(book_id)?;
+        for leg in &legs {
+            let order_id = leg.order_id();
+            if self.order_status(order_id).is_some() || self.oco.group_of(order_id).is_some() {
+                return Err(OrderBookError::DuplicateOrder(order_id));
+            }
+            match leg {
+                OcoLeg::Limit { price, is_bid, .. } => {
+                    Price::from_u32(*price, *is_bid).ok_or(OrderBookError::InvalidPrice(*price))?;
+                    self.check_price_band(book_id, *price)?;
+                }
+                OcoLeg::Stop(stop) => {
+                    for price in std::iter::once(stop.trigger).chain(stop.limit) {
+                        Price::from_u32(price, stop.is_bid).ok_or(OrderBookError::InvalidPrice(price))?;
+                    }
+                }
+            }
+        }
+        self.oco.insert(OcoGroup {
+            group_id: first.order_id().0,
+            legs: [(first.order_id().0, first.qty()), (second.order_id().0, second.qty())],
+            policy,
+            cancel_together,
+        })?;
+
+        let mut match_details = Vec::new();
+        let mut submitted = Vec::new();
+        let (stops, limits): (Vec<OcoLeg>, Vec<OcoLeg>) = legs.into_iter().partition(|leg| matches!(leg, OcoLeg::Stop(_)));
+        for leg in stops.into_iter().chain(limits) {
+            let order_id = leg.order_id();
+            match leg {
+                OcoLeg::Stop(stop) => self.submit_stop(stop)?,
+                OcoLeg::Limit { book_id, qty, price, is_bid, trader, nonce, expiry, signature, .. } => {
+                    // A sibling that already traded leaves this leg less to do, or nothing
+                    let qty = match self.oco.group_of(order_id).copied() {
+                        Some(group) if group.policy == OcoPolicy::Reduce && submitted.contains(&group.sibling(order_id)) => {
+                            let sibling = group.sibling(order_id);
+                            let left = self.order_status(sibling).map_or(0, |(_, qty)| qty.value());
+                            group.sibling_target(sibling, left).min(qty)
+                        }
+                        Some(_) => qty,
+                        None => 0,
+                    };
+                    if qty == 0 {
+                        self.oco.remove_order(order_id);
+                        if let Some(trader) = trader {
+                            self.orderbook_manager.publish_update(OrderUpdate {
+                                order_id: order_id.0,
+                                book_id,
+                                trader,
+                                status: OrderStatus::Cancelled,
+                                filled_qty: 0,
+                                remaining_qty: 0,
+                                client_order_id: None,
+                                received_at: None,
+                                filled_notional: 0,
+                            });
+                        }
+                        continue;
+                    }
+                    let order = Order::new(Qty(qty), LevelId(0), BookId(book_id), trader, nonce, expiry, signature);
+                    let (_, fills) = self.match_limit_order(order_id, order, price, is_bid)?;
+                    match_details.extend(fills);
+                }
+            }
+            submitted.push(order_id);
+        }
+        Ok(match_details)
+    }
+
+    /// Gets the OCO pairs still linked
+    pub fn oco(&self) -> &OcoBook {
+        &self.oco
+    }
+
+    /// Replaces a trader's quotes book by book: each entry takes down the trader's previous
+    /// quote set in its book and puts its bid and ask in as limit orders
+    /// All entries go in within this one call, so nothing trades between a set being cancelled
+    /// and its replacement going in, and only the orders of the sets replaced are touched. Each
+    /// entry succeeds or fails on its own, and one that fails leaves the trader's previous set in
+    /// its book working: CrossedQuote if its bid is at or above its ask, InvalidPrice or
+    /// PriceOutsideBand if a side can't go in at its price. A later entry for the same book
+    /// replaces an earlier one. The caller has checked the signatures, nonces and risk limits.
+    /// Fails as a whole only while orders aren't accepted, see check_accepting.
+    pub fn mass_quote(
+        &mut self,
+        trader: [u8; 20],
+        entries: &[QuoteEntry],
+        received_at: u64,
+    ) -> Result<Vec<Result<QuoteOutcome, OrderBookError>>, OrderBookError> {
+        self.check_accepting()?;
+        Ok(entries.iter().map(|entry| self.replace_quote(trader, entry, received_at)).collect())
+    }
+
+    /// Replaces a trader's quote set in one book, see mass_quote
+    fn replace_quote(&mut self, trader: [u8; 20], entry: &QuoteEntry, received_at: u64) -> Result<QuoteOutcome, OrderBookError> {
+        let book_id = BookId(entry.book_id);
+        if entry.crosses() {
+            return Err(OrderBookError::CrossedQuote(book_id));
+        }
+        self.open_book(book_id)?;
+        for (side, is_bid) in entry.sides() {
+            Price::from_u32(side.price, is_bid).ok_or(OrderBookError::InvalidPrice(side.price))?;
+            self.check_price_band(book_id, side.price)?;
+        }
+
+        let mut outcome = QuoteOutcome::default();
+        for order_id in self.quotes.take(trader, book_id).iter().flat_map(QuoteSet::order_ids) {
+            // Orders of the old set that have filled or been cancelled since are gone already
+            if self.order_owner(order_id) == Some(Some(trader)) && self.cancel_resting(order_id, OrderStatus::Cancelled).is_ok() {
+                outcome.cancelled.push(order_id);
+            }
+        }
+        for (side, is_bid) in entry.sides() {
+            let order = Order::new(Qty(side.qty), LevelId(0), book_id, Some(trader), Some(side.nonce), side.expiry, side.signature)
+                .with_received_at(received_at);
+            let (remaining, fills) = self.match_limit_order(OrderId(side.order_id), order, side.price, is_bid)?;
+            *if is_bid { &mut outcome.bid_remaining } else { &mut outcome.ask_remaining } = Some(remaining);
+            outcome.fills.extend(fills);
+        }
+        self.quotes.replace(QuoteSet {
+            trader,
+            book_id: entry.book_id,
+            bid: entry.bid.map(|bid| bid.order_id),
+            ask: entry.ask.map(|ask| ask.order_id),
+        });
+        Ok(outcome)
+    }
+
+    /// Takes down every quote set of a trader, or only the one in `book_id`
+    /// Returns the IDs of the quote orders cancelled; those already filled or cancelled are skipped.
+    pub fn cancel_quotes(&mut self, trader: [u8; 20], book_id: Option<BookId>) -> Vec<OrderId> {
+        let mut cancelled = Vec::new();
+        for order_id in self.quotes.take_all(trader, book_id).iter().flat_map(QuoteSet::order_ids) {
+            if self.order_owner(order_id) == Some(Some(trader)) && self.cancel_resting(order_id, OrderStatus::Cancelled).is_ok() {
+                cancelled.push(order_id);
+            }
+        }
+        cancelled
+    }
+
+    /// Gets a trader's current quote set in a book
+    pub fn quote_set(&self, trader: [u8; 20], book_id: BookId) -> Option<&QuoteSet> {
+        self.quotes.get(trader, book_id)
+    }
+
+    /// Adjusts the siblings of the OCO legs that traded since the last call
+    /// Under Cancel the sibling goes and the pair is unlinked; under Reduce the sibling shrinks
+    /// with what the traded leg has left, and the pair stays linked until either is done.
+    fn settle_oco(&mut self) {
+        for order_id in std::mem::take(&mut self.oco_fills) {
+            let Some(group) = self.oco.group_of(order_id).copied() else {
+                continue;
+            };
+            let remaining = self.order_status(order_id).map_or(0, |(_, qty)| qty.value());
+            let target = group.sibling_target(order_id, remaining);
+            if target == 0 {
+                self.oco.remove_order(order_id);
+            }
+            self.shrink_order(group.sibling(order_id), target);
+        }
+    }
+
+    /// Unlinks a cancelled OCO leg, cancelling its sibling as well if the pair asked for it
+    fn leave_oco(&mut self, order_id: OrderId) {
+        if let Some(group) = self.oco.remove_order(order_id) {
+            if group.cancel_together {
+                self.shrink_order(group.sibling(order_id), 0);
+            }
+        }
+    }
+
+    /// Unlinks the OCO pairs with a leg that is no longer working
+    fn prune_oco(&mut self) {
+        let stale: Vec<OrderId> = self
+            .oco
+            .entries()
+            .iter()
+            .filter(|group| group.legs.iter().any(|&(order_id, _)| self.order_status(OrderId(order_id)).is_none()))
+            .map(|group| OrderId(group.group_id))
+            .collect();
+        for order_id in stale {
+            self.oco.remove_order(order_id);
+        }
+    }
+
+    /// Cuts a working order, resting, parked, or a waiting stop, down to `qty` left, cancelling it
+    /// at zero. A resting order keeps its place in the queue, see OrderBookManager::reduce_to.
+    fn shrink_order(&mut self, order_id: OrderId, qty: u64) {
+        let Some((_, left)) = self.order_status(order_id) else {
+            return;
+        };
+        if qty >= left.value() {
+            return;
+        }
+        if qty == 0 {
+            let _ = match self.stops.get(order_id) {
+                Some(_) => self.cancel_stop(order_id).map(|_| ()),
+                None => self.cancel_resting(order_id, OrderStatus::Cancelled),
+            };
+        } else if self.stops.get(order_id).is_some() {
+            self.stops.set_qty(order_id, qty);
+        } else if let Some(peg) = self.pegs.get_mut(order_id).filter(|peg| peg.price.is_none()) {
+            peg.qty = qty;
+        } else if let Some(book_id) = self.book_of(order_id) {
+            let _ = self.orderbook_manager.reduce_to(order_id, Qty(qty));
+            self.reprice_pegs(book_id);
+        }
+    }
+
+    /// Gets the best price on one side of a book among the orders pegs follow, i.e. all but pegs
+    fn peg_reference(&self, book_id: BookId, is_bid: bool) -> Option<u32> {
+        let book = self.orderbook_manager.book(book_id)?;
+        let oid_map = &self.orderbook_manager.oid_map;
+        let mut pegged: HashMap<u32, u64> = HashMap::new();
+        for order_id in self.pegs.in_book(book_id) {
+            let (Some(peg), Some(order)) = (self.pegs.get(order_id), oid_map.get(order_id)) else {
+                continue;
+            };
+            if let (Some(price), true) = (peg.price, peg.is_bid == is_bid) {
+                *pegged.entry(price).or_default() += order.qty().value();
+            }
+        }
+        book.iter_levels(is_bid)
+            .map(|level| (level.price().absolute() as u32, level.size().value()))
+            .find(|(price, size)| *size > pegged.get(price).copied().unwrap_or_default())
+            .map(|(price, _)| price)
+    }
+
+    /// Gets the price a pegged order pegs to right now, or None if it has nothing to peg to
+    fn peg_target(&self, peg: &PeggedOrder) -> Option<u32> {
+        let book_id = BookId(peg.book_id);
+        let opposite = if peg.is_bid {
+            self.orderbook_manager.get_best_ask(book_id)
+        } else {
+            self.orderbook_manager.get_best_bid(book_id)
+        };
+        let (bid, ask) = (self.peg_reference(book_id, true), self.peg_reference(book_id, false));
+        peg.target(bid, ask, opposite.map(|price| price.absolute() as u32))
+    }
+
+    /// Moves the pegs of a book to the prices they peg to now, parking those left without one
+    /// Each peg moves once per pass, in order ID order; a book without pegs costs one lookup.
+    /// Fills of pegs allowed to cross trigger stops, but don't start another pass.
+    fn reprice_pegs(&mut self, book_id: BookId) {
+        if self.in_auction(book_id) {
+            return;
+        }
+        let mut match_details = Vec::new();
+        for order_id in self.pegs.in_book(book_id) {
+            let Some(peg) = self.pegs.get(order_id).cloned() else {
+                continue;
+            };
+            // A resting peg missing from the book was filled or cancelled since it last moved
+            if peg.price.is_some() && self.orderbook_manager.oid_map.get(order_id).is_none() {
+                self.pegs.remove(order_id);
+                continue;
+            }
+            let target = self.peg_target(&peg).filter(|&price| self.check_price_band(book_id, price).is_ok());
+            if target == peg.price {
+                continue;
+            }
+            let order = match peg.price {
+                Some(_) => match self.orderbook_manager.lift_order(order_id, target) {
+                    Ok(order) => order,
+                    Err(_) => continue,
+                },
+                None => peg.order(),
+            };
+            if let Some(entry) = self.pegs.get_mut(order_id) {
+                entry.qty = order.qty().value();
+                entry.price = target;
+            }
+
+            // The target fits in an i32, so only a missing one parks the order
+            let Some(limit) = target.and_then(|price| Price::from_u32(price, peg.is_bid)) else {
+                if let Some(parked) = self.pegs.get(order_id).cloned() {
+                    self.publish_peg_update(&parked, OrderStatus::Parked);
+                }
+                continue;
+            };
+            match self.match_limit(order_id, order, limit, peg.is_bid, &mut match_details) {
+                Ok(remaining_qty) => {
+                    if remaining_qty.value() == 0 {
+                        self.pegs.remove(order_id);
+                    }
+                }
+                Err(_) => {
+                    self.pegs.remove(order_id);
+                }
+            }
+        }
+        if !match_details.is_empty() {
+            self.trigger_stops(book_id, &match_details);
+        }
+    }
+
+    /// Reprices the pegs of every book that has any
+    fn reprice_all_pegs(&mut self) {
+        for book_id in self.pegs.books() {
+            self.reprice_pegs(book_id);
+        }
+    }
+
+    fn publish_peg_update(&mut self, peg: &PeggedOrder, status: OrderStatus) {
+        if let Some(trader) = peg.trader {
+            let remaining_qty = if status == OrderStatus::Parked { peg.qty } else { 0 };
+            self.orderbook_manager.publish_update(OrderUpdate {
+                order_id: peg.order_id,
+                book_id: peg.book_id,
+                trader,
+                status,
+                filled_qty: 0,
+                remaining_qty,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+    }
+
+    /// Fires the stops of a book that `match_details` trigger, then those their own fills trigger
+    /// A cascade runs at most MAX_STOP_ROUNDS rounds; stops it did not reach stay armed and fire on
+    /// a later trade.
+    fn trigger_stops(&mut self, book_id: BookId, match_details: &[MatchDetails]) {
+        let mut prints = Self::price_range(match_details);
+        for _ in 0..MAX_STOP_ROUNDS {
+            let Some((low, high)) = prints else {
+                return;
+            };
+            let triggered = self.stops.take_triggered(book_id, low, high);
+            if triggered.is_empty() {
+                return;
+            }
+            prints = None;
+            let mut cancelled = Vec::new();
+            for stop in triggered {
+                // A stop leg of an OCO pair cancels its sibling, which may have triggered with it
+                let order_id = OrderId(stop.order_id);
+                if cancelled.contains(&order_id) {
+                    self.publish_stop_update(&stop, OrderStatus::Cancelled);
+                    continue;
+                }
+                if let Some(group) = self.oco.remove_order(order_id) {
+                    self.shrink_order(group.sibling(order_id), 0);
+                    cancelled.push(group.sibling(order_id));
+                }
+                let fills = self.fire_stop(stop);
+                prints = [prints, Self::price_range(&fills)]
+                    .into_iter()
+                    .flatten()
+                    .reduce(|(low, high), (other_low, other_high)| (low.min(other_low), high.max(other_high)));
+            }
+        }
+    }
+
+    /// Sends a triggered stop in as a market order, or a stop-limit as a limit order, and returns its fills
+    /// A stop-limit is held to the price band like any limit order; if it falls outside, it is cancelled.
+    fn fire_stop(&mut self, stop: StopOrder) -> Vec<MatchDetails> {
+        let (order_id, book_id) = (OrderId(stop.order_id), BookId(stop.book_id));
+        if let Some(trader) = stop.trader {
+            self.orderbook_manager.publish_update(OrderUpdate {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                trader,
+                status: OrderStatus::Triggered,
+                filled_qty: 0,
+                remaining_qty: stop.qty,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+        let Some(limit) = stop.limit else {
+            return self
+                .execute_market(order_id, stop.order(), stop.is_bid)
+                .map(|fill| fill.matches)
+                .unwrap_or_default();
+        };
+
+        // The limit was checked when the stop was accepted
+        let price = Price::from_u32(limit, stop.is_bid);
+        let (Some(price), Ok(())) = (price, self.check_price_band(book_id, limit)) else {
+            self.publish_stop_update(&stop, OrderStatus::Cancelled);
+            return Vec::new();
+        };
+        let mut match_details = Vec::new();
+        match self.match_limit(order_id, stop.order(), price, stop.is_bid, &mut match_details) {
+            Ok(remaining_qty) => {
+                if remaining_qty.value() > 0 {
+                    self.remember_triggered(order_id);
+                }
+                match_details
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Notes a stop-limit resting after its trigger, for order_status
+    /// Entries whose order stopped resting are dropped whenever the set reaches a power of two,
+    /// which keeps it bounded at amortized constant cost.
+    fn remember_triggered(&mut self, order_id: OrderId) {
+        self.triggered_stops.insert(order_id);
+        let len = self.triggered_stops.len();
+        if len >= 64 && len.is_power_of_two() {
+            let oid_map = &self.orderbook_manager.oid_map;
+            self.triggered_stops.retain(|&order_id| oid_map.get(order_id).is_some());
+        }
+    }
+
+    /// Notes a reduce-only order resting, so it is capped whenever it is about to trade
+    /// Entries whose order stopped resting are dropped as in remember_triggered.
+    fn remember_reduce_only(&mut self, order_id: OrderId) {
+        self.reduce_only.insert(order_id);
+        let len = self.reduce_only.len();
+        if len >= 64 && len.is_power_of_two() {
+            let oid_map = &self.orderbook_manager.oid_map;
+            self.reduce_only.retain(|&order_id| oid_map.get(order_id).is_some());
+        }
+    }
+
+    /// Returns true if the market of a book tracks positions
+    fn tracks_positions(&self, book_id: BookId) -> bool {
+        self.market_manager.get_config(book_id).is_some_and(|config| config.track_positions)
+    }
+
+    /// Gets how much a trader's order on the given side can reduce their position in a book
+    fn reducible(&self, book_id: BookId, trader: Option<[u8; 20]>, is_bid: bool) -> u64 {
+        trader.map_or(0, |trader| self.positions.reducible(trader, book_id, is_bid))
+    }
+
+    /// Caps an incoming reduce-only order at what its owner's position lets it reduce
+    /// Fails with PositionsNotTracked if the book's market doesn't track positions, and with
+    /// NoPositionToReduce if there is nothing for the order to reduce.
+    fn check_reduce_only(&self, order: &mut Order, is_bid: bool) -> Result<(), OrderBookError> {
+        if !order.reduce_only() {
+            return Ok(());
+        }
+        let book_id = order.book_id();
+        if !self.tracks_positions(book_id) {
+            return Err(OrderBookError::PositionsNotTracked(book_id));
+        }
+        let reducible = self.reducible(book_id, order.trader(), is_bid);
+        if reducible == 0 {
+            return Err(OrderBookError::NoPositionToReduce(book_id));
+        }
+        if order.qty().value() > reducible {
+            order.set_qty(Qty(reducible));
+        }
+        Ok(())
+    }
+
+    /// Cuts a resting reduce-only order down to what its owner's position lets it reduce now,
+    /// cancelling it when there is nothing left to reduce. Returns true if the order changed.
+    fn cap_reduce_only(&mut self, book_id: BookId, order_id: OrderId, is_bid: bool) -> bool {
+        if !self.reduce_only.contains(&order_id) {
+            return false;
+        }
+        let oid_map = &self.orderbook_manager.oid_map;
+        let (Some(trader), Some(qty)) = (oid_map.get(order_id).map(|order| order.trader()), oid_map.total_qty(order_id)) else {
+            return false;
+        };
+        let reducible = self.reducible(book_id, trader, is_bid);
+        if reducible >= qty.value() {
+            return false;
+        }
+        if reducible == 0 {
+            self.reduce_only.remove(&order_id);
+            self.orderbook_manager.cancel_resting(order_id, OrderStatus::Cancelled).is_ok()
+        } else {
+            self.orderbook_manager.reduce_to(order_id, Qty(reducible)).is_ok()
+        }
+    }
+
+    fn publish_stop_update(&mut self, stop: &StopOrder, status: OrderStatus) {
+        if let Some(trader) = stop.trader {
+            self.orderbook_manager.publish_update(OrderUpdate {
+                order_id: stop.order_id,
+                book_id: stop.book_id,
+                trader,
+                status,
+                filled_qty: 0,
+                remaining_qty: 0,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+    }
+
+    /// Gets the lowest and highest price among fills, or None without fills
+    fn price_range(match_details: &[MatchDetails]) -> Option<(u32, u32)> {
+        let low = match_details.iter().map(|details| details.exec_price).min()?;
+        let high = match_details.iter().map(|details| details.exec_price).max()?;
+        Some((low, high))
+    }
+}
+
+/// What happens to an order that made max_fills_per_order fills and could still trade
+/// Resting what is left is not among them: priced through the book, it would cross it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FillLimitPolicy {
+    #[default]
+    Cancel,   // What is left is cancelled, as if the order were immediate-or-cancel
+    Continue, // What is left waits to go on matching in a later pass, see resume_matching
+}
+
+/// What is left of an order cut short by the fill limit, priced at its limit
+#[derive(Debug, Clone)]
+struct Continuation {
+    order_id: OrderId,
+    order: Order,
+    is_bid: bool,
+}
+
+/// One side of a fill: the order and what the fill needs to know of it, as it was when it traded
+/// The rest of the order, such as its signature, stays in the OidMap while it rests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FillSide {
+    pub order_id: OrderId,
+    #[serde(with = "hex_bytes")]
+    pub trader: Option<[u8; 20]>,
+    pub nonce: Option<u64>,
+    pub expiry: Option<u64>,
+}
+
+impl FillSide {
+    #[inline]
+    pub fn new(order_id: OrderId, order: &Order) -> Self {
+        Self { order_id, trader: order.trader(), nonce: order.nonce(), expiry: order.expiry() }
+    }
+
+    /// Reads a resting order's side of a fill from the OidMap, or None if it doesn't rest
+    #[inline]
+    pub fn resting(oid_map: &OidMap, order_id: OrderId) -> Option<Self> {
+        let trader = oid_map.get(order_id)?.trader();
+        let meta = oid_map.meta(order_id).copied().unwrap_or_default();
+        Some(Self { order_id, trader, nonce: meta.nonce, expiry: meta.expiry })
+    }
+}
+
+/// The incoming side of a fill, with how much of the order the fill left
+/// `qty` is what the order had when it began matching, so `qty - remaining_qty` is all it has
+/// filled so far, this fill included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TakerInfo {
+    pub order_id: OrderId,
+    #[serde(with = "hex_bytes")]
+    pub trader: Option<[u8; 20]>,
+    pub nonce: Option<u64>,
+    pub expiry: Option<u64>,
+    #[serde(default)]
+    pub qty: Qty,
+    #[serde(default)]
+    pub remaining_qty: Qty, // Left to fill once this fill was done; zero when it completed the order.
+}
+
+impl TakerInfo {
+    #[inline]
+    pub fn new(side: FillSide, qty: Qty, remaining_qty: Qty) -> Self {
+        let FillSide { order_id, trader, nonce, expiry } = side;
+        Self { order_id, trader, nonce, expiry, qty, remaining_qty }
+    }
+}
+
+/// A fill of an incoming order against a resting one
+/// The orders are named by their IDs in `maker` and `taker`, and the taker's side says how much of
+/// it was left to fill after this one. The trade ID is the one the trade
+/// tape, events, and the settlement tracked for the fill carry.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MatchDetails {
+    pub maker: FillSide,
+    pub taker: TakerInfo,
+    pub exec_qty: Qty,
+    pub exec_price: u32,
+    pub maker_is_buyer: bool,
+    pub trade_id: u64, // Numbers the engine's trades in the order they executed, so it only grows within a book.
+    #[serde(default)]
+    pub executed_at: u64, // Nanoseconds since the Unix epoch, the timestamp of its trade.
+    pub settlement_id: Option<u64>, // Set when the fill was registered for settlement.
+    pub settlement_error: Option<TranslationError>, // Set when the book settles but the fill could not be translated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub improvement: Option<PriceImprovement>, // Set for the fills of limit orders.
+}
+
+/// Outcome of a market order; whatever it could not fill is cancelled, never rested
+#[derive(Debug)]
+pub struct MarketOrderFill {
+    pub matches: Vec<MatchDetails>,
+    pub filled_qty: Qty,
+    pub cancelled_qty: Qty,
+    pub band_cut_qty: Qty, // Part of cancelled_qty that stopped at the price band with liquidity beyond it.
+}
+
+/// What one entry of a mass quote did, see MatchingEngine::mass_quote
+#[derive(Debug, Default)]
+pub struct QuoteOutcome {
+    pub cancelled: Vec<OrderId>, // Orders of the replaced set that were still working
+    pub bid_remaining: Option<Qty>, // What is left of the new bid, resting; None without a bid
+    pub ask_remaining: Option<Qty>,
+    pub fills: Vec<MatchDetails>, // Fills of the new bid and ask as they went in
+}
+
+/// What a limit order would do if it went in now, see MatchingEngine::match_preview
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchPreview {
+    pub fills: Vec<PreviewFill>,
+    pub filled_qty: Qty,
+    pub average_price: Option<u32>, // None when nothing would fill
+    pub remaining_qty: Qty, // After a reduce-only order is capped at its owner's position
+    pub rests: bool, // What is left would rest in the book
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreviewFill {
+    pub maker_order_id: OrderId,
+    pub price: u32, // The price it would execute at
+    pub qty: Qty,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pegs::Peg;
+    use crate::market::MarketConfig;
+    use crate::translator::{SettlementOrder, SettlementSignature};
+    use std::time::{Duration, Instant};
+    use rand::Rng;
+
+    // Helper function to print match details
+    fn print_match_details(
+        maker_order: &Order,
+        taker_id: OrderId,
+        taker_trader: Option<[u8; 20]>,
+        taker_nonce: Option<u64>,
+        exec_qty: Qty,
+        price: u32,
+        is_bid: bool,
+    ) {
+        println!("\nMATCH DETAILS:");
+        println!("---------------");
+        println!("Execution Quantity: {}", exec_qty.value());
+        println!("Price: {}", price);
+        println!("Direction: {}", if is_bid { "BUY" } else { "SELL" });
+        println!("Taker Order ID: {}", taker_id.0);
+
+        // Maker (resting order) details
+        println!("\nMAKER DETAILS:");
+        if let Some(trader) = maker_order.trader() {
+            println!("Address: 0x{}", hex::encode(trader));
+        }
+        if let Some(nonce) = maker_order.nonce() {
+            println!("Nonce: {}", nonce);
+        }
+        if let Some(expiry) = maker_order.expiry() {
+            println!("Expiry: {}", expiry);
+        }
+
+        // Taker (incoming order) details
+        println!("\nTAKER DETAILS:");
+        if let Some(trader) = taker_trader {
+            println!("Address: 0x{}", hex::encode(trader));
+        }
+        if let Some(nonce) = taker_nonce {
+            println!("Nonce: {}", nonce);
+        }
+        println!("---------------\n");
+    }
+
+    #[test]
+    fn test_basic_matching() {
+        let mut engine = MatchingEngine::auto_creating();
+
+        println!("\nStarting basic matching test...");
+
+        // Add a resting sell order
+        engine.orderbook_manager.add_order(
+            OrderId(1),
+            BookId(0),
+            Qty(100),
+            100,
+            false, // is_bid
+            Some([1; 20]),  // Example trader address
+            Some(1),        // Example nonce
+            Some(u64::MAX), // Example expiry
+            Some([0; 65]),  // Example signature
+        ).unwrap();
+
+        println!("Added resting sell order: ID(1), Qty(100), Price(100)");
+
+        // Send in a matching buy order
+        let (remaining, _details) = engine.match_order(
+            OrderId(2),
+            BookId(0),
+            Qty(60),
+            100,
+            true, // is_bid
+            Some([2; 20]),  // Different trader
+            Some(2),        // Different nonce
+            Some(u64::MAX),
+            Some([0; 65]),
+        ).unwrap();
+
+        // Get resting order details for printing
+        if let Some(maker_order) = engine.orderbook_manager.oid_map.get_order(OrderId(1)) {
+            print_match_details(
+                &maker_order,
+                OrderId(2),
+                Some([2; 20]),  // taker trader
+                Some(2),        // taker nonce
+                Qty(60),
+                100,
+                true,
+            );
+        }
+
+        assert_eq!(remaining.value(), 0);
+        println!("Remaining quantity: {}", remaining.value());
+
+        // Check remaining sell order quantity
+        if let Some(order) = engine.orderbook_manager.oid_map.get(OrderId(1)) {
+            assert_eq!(order.qty().value(), 40);
+            println!("Remaining resting order quantity: {}", order.qty().value());
+        }
+    }
+
+    #[test]
+    fn test_no_match_price() {
+        let mut engine = MatchingEngine::auto_creating();
+
+        println!("\nStarting no-match price test...");
+
+        // Add a resting sell order at 100
+        engine.orderbook_manager.add_order(
+            OrderId(1),
+            BookId(0),
+            Qty(100),
+            100,
+            false,
+            Some([1; 20]),
+            Some(1),
+            Some(u64::MAX),
+            Some([0; 65]),
+        ).unwrap();
+
+        println!("Added resting sell order: ID(1), Qty(100), Price(100)");
+
+        // Send in a buy order at 99 (shouldn't match)
+        let (remaining, _) = engine.match_order(
+            OrderId(2),
+            BookId(0),
+            Qty(60),
+            99,
+            true,
+            Some([2; 20]),
+            Some(2),
+            Some(u64::MAX),
+            Some([0; 65]),
+        ).unwrap();
+
+        println!("Attempted match with buy order: ID(2), Qty(60), Price(99)");
+        println!("No match occurred due to price mismatch");
+        println!("Remaining quantity: {}", remaining.value());
+
+        assert_eq!(remaining.value(), 60);
+    }
+
+    #[test]
+    fn test_match_order_out_of_range_book() {
+        let mut engine = MatchingEngine::auto_creating();
+        let far = BookId(u32::MAX);
+        let result = engine.match_order(OrderId(0), far, Qty(10), 100, true, None, None, None, None);
+        assert!(matches!(result, Err(OrderBookError::BookOutOfRange(book_id)) if book_id == far));
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(0)).is_none());
+
+        // Nor is a price that can't be negated for the book's asks
+        let result = engine.match_order(OrderId(0), BookId(0), Qty(10), u32::MAX, false, None, None, None, None);
+        assert!(matches!(result, Err(OrderBookError::InvalidPrice(u32::MAX))));
+        assert!(engine.orderbook_manager.book(BookId(0)).is_none());
+    }
+
+    /// An engine whose book 0 has a 5% price band and last traded at 1000
+    fn banded_engine() -> MatchingEngine {
+        use crate::market::MarketConfig;
+
+        let mut engine = MatchingEngine::auto_creating();
+        engine.market_manager.add_market(BookId(0), MarketConfig::builder().price_band(PriceBand::Bps(500)).build(), false).unwrap();
+        engine.orderbook_manager.add_order(OrderId(0), BookId(0), Qty(10), 1000, false, None, None, None, None).unwrap();
+        engine.match_order(OrderId(1), BookId(0), Qty(10), 1000, true, None, None, None, None).unwrap();
+        engine.next_order_id = 2;
+        engine
+    }
+
+    #[test]
+    fn test_price_band() {
+        let mut engine = banded_engine();
+        assert_eq!(engine.price_band(BookId(0)), Some((950, 1050)));
+
+        // Orders exactly on the band are accepted on both sides
+        let (remaining, _) = engine.match_order(OrderId(2), BookId(0), Qty(10), 1050, false, None, None, None, None).unwrap();
+        assert_eq!(remaining, Qty(10));
+        let (remaining, _) = engine.match_order(OrderId(3), BookId(0), Qty(10), 950, true, None, None, None, None).unwrap();
+        assert_eq!(remaining, Qty(10));
+
+        // One tick beyond is rejected without touching the book
+        let result = engine.match_order(OrderId(4), BookId(0), Qty(10), 1051, true, None, None, None, None);
+        println!("Bid beyond the band: {:?}", result.as_ref().err());
+        assert!(matches!(result, Err(OrderBookError::PriceOutsideBand { price: 1051, low: 950, high: 1050 })));
+        let result = engine.match_order(OrderId(5), BookId(0), Qty(10), 949, false, None, None, None, None);
+        assert!(matches!(result, Err(OrderBookError::PriceOutsideBand { price: 949, .. })));
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(4)).is_none());
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), Some(Price(-1050)));
+
+        // A replace outside the band leaves the original order resting
+        let result = engine.replace_order(OrderId(3), OrderId(6), Qty(10), 900);
+        assert!(matches!(result, Err(OrderBookError::PriceOutsideBand { price: 900, .. })));
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(3)).is_some());
+
+        // The band moves with the last trade, and can be lifted
+        engine.match_order(OrderId(7), BookId(0), Qty(10), 1050, true, None, None, None, None).unwrap();
+        assert_eq!(engine.price_band(BookId(0)), Some((998, 1102)));
+        engine.set_price_band(BookId(0), None).unwrap();
+        assert!(engine.match_order(OrderId(8), BookId(0), Qty(10), 5000, false, None, None, None, None).is_ok());
+        assert_eq!(engine.set_price_band(BookId(1), None), Err(MarketError::UnknownMarket(BookId(1))));
+    }
+
+    #[test]
+    fn test_auction_uncross() {
+        use crate::market_data::MarketDataEvent;
+
+        let mut engine = MatchingEngine::auto_creating();
+        engine.enter_auction(BookId(0)).unwrap();
+        let mut market_data = engine.orderbook_manager.market_data.subscribe();
+
+        // The textbook book of auction::tests::test_max_volume_price clears 60 at 100
+        let orders = [
+            (98, 15, false), (102, 10, true), (99, 25, false), (101, 20, true),
+            (100, 20, false), (100, 30, true), (101, 30, false), (99, 10, true),
+        ];
+        for (order_id, &(price, qty, is_bid)) in orders.iter().enumerate() {
+            let (remaining, matches) = engine.match_order(OrderId(order_id as u64), BookId(0), Qty(qty), price, is_bid, None, None, None, None).unwrap();
+            assert_eq!((remaining, matches.len()), (Qty(qty), 0));
+        }
+        assert_eq!(engine.indicative(BookId(0)), Some(Uncross { price: 100, volume: 60, imbalance: 0 }));
+        let indicative: Vec<Option<u32>> = std::iter::from_fn(|| market_data.try_recv().ok())
+            .filter_map(|event| match event {
+                MarketDataEvent::Indicative { price, .. } => Some(price),
+                _ => None,
+            })
+            .collect();
+        println!("Indicative prices: {:?}", indicative);
+        assert_eq!(indicative.len(), orders.len());
+        assert_eq!(indicative[0], None);
+        assert_eq!(indicative.last(), Some(&Some(100)));
+
+        let matches = engine.uncross(BookId(0)).unwrap();
+        assert!(!engine.in_auction(BookId(0)));
+        assert!(matches.iter().all(|fill| fill.exec_price == 100));
+        assert_eq!(matches.iter().map(|fill| fill.exec_qty.value()).sum::<u64>(), 60);
+        // The bid at 102 (order 1) arrived after the ask at 98 (order 0), so it took
+        assert_eq!((matches[0].exec_qty, matches[0].maker_is_buyer), (Qty(10), false));
+        let trade = engine.trade_tape(BookId(0)).unwrap().iter_newest().next().copied().unwrap();
+        assert_eq!((trade.maker_order_id, trade.taker_order_id, trade.qty), (OrderId(4), OrderId(5), Qty(20)));
+        assert_eq!(matches.len(), 5);
+
+        // Whatever did not clear is left uncrossed, and the book trades continuously again
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), Some(Price(99)));
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), Some(Price(-101)));
+        let (remaining, _) = engine.match_order(OrderId(8), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!(remaining, Qty(0));
+    }
+
+    #[test]
+    fn test_uncross_without_crossing_volume() {
+        let mut engine = MatchingEngine::auto_creating();
+        assert!(matches!(engine.uncross(BookId(0)), Err(OrderBookError::NotInAuction(_))));
+        engine.enter_auction(BookId(0)).unwrap();
+        engine.match_order(OrderId(0), BookId(0), Qty(10), 99, true, None, None, None, None).unwrap();
+        engine.match_order(OrderId(1), BookId(0), Qty(10), 101, false, None, None, None, None).unwrap();
+        assert_eq!(engine.indicative(BookId(0)), None);
+
+        // Market orders have nothing to rest at during an auction
+        let order = Order::new(Qty(5), LevelId(0), BookId(0), None, None, None, None);
+        assert!(matches!(engine.match_market_order(OrderId(2), order, true), Err(OrderBookError::BookInAuction(_))));
+
+        // Nothing trades, the book just goes back to continuous trading
+        assert!(engine.uncross(BookId(0)).unwrap().is_empty());
+        assert!(engine.trade_tape(BookId(0)).is_none());
+        assert!(!engine.in_auction(BookId(0)));
+        let (remaining, _) = engine.match_order(OrderId(3), BookId(0), Qty(4), 101, true, None, None, None, None).unwrap();
+        assert_eq!(remaining, Qty(0));
+    }
+
+    #[test]
+    fn test_market_order_truncated_at_band() {
+        let mut engine = banded_engine();
+        for (order_id, price) in [(2, 1020), (3, 1050), (4, 1060)] {
+            engine.orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(5), price, false, None, None, None, None).unwrap();
+        }
+
+        // The buy walks up to the band edge at 1050, each fill at the maker's price, and the rest is cut
+        let order = Order::new(Qty(20), LevelId(0), BookId(0), Some([9; 20]), None, None, None);
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        let fill = engine.match_market_order(OrderId(5), order, true).unwrap();
+        println!("Market order: filled {:?}, cancelled {:?}, cut {:?}", fill.filled_qty, fill.cancelled_qty, fill.band_cut_qty);
+        assert_eq!((fill.filled_qty, fill.cancelled_qty, fill.band_cut_qty), (Qty(10), Qty(10), Qty(10)));
+        let fills: Vec<(u32, u64)> = fill.matches.iter().map(|m| (m.exec_price, m.exec_qty.value())).collect();
+        assert_eq!(fills, vec![(1020, 5), (1050, 5)]);
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(5)).is_none());
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), Some(Price(-1060)));
+        let update = updates.try_recv().unwrap();
+        assert_eq!((update.status, update.filled_qty, update.remaining_qty), (OrderStatus::Cancelled, 10, 0));
+
+        // Without a band a market order sweeps the book, and a remainder on an empty side isn't a cut
+        engine.set_price_band(BookId(0), None).unwrap();
+        let order = Order::new(Qty(20), LevelId(0), BookId(0), None, None, None, None);
+        let fill = engine.match_market_order(OrderId(6), order, true).unwrap();
+        assert_eq!((fill.filled_qty, fill.cancelled_qty, fill.band_cut_qty), (Qty(5), Qty(15), Qty(0)));
+        assert_eq!(fill.matches[0].exec_price, 1060);
+    }
+
+    #[test]
+    fn test_fill_notional_in_updates() {
+        let mut engine = MatchingEngine::auto_creating();
+        let maker = Order::new(Qty(10), LevelId(0), BookId(0), Some([1; 20]), None, None, None);
+        engine.match_limit_order(OrderId(0), maker, 1000, false).unwrap();
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        // A limit order trades at its own limit, and both sides are told the same notional
+        let taker = Order::new(Qty(4), LevelId(0), BookId(0), Some([2; 20]), None, None, None);
+        engine.match_limit_order(OrderId(1), taker, 1005, true).unwrap();
+        let fills: Vec<(u64, OrderStatus, u64, u64)> = std::iter::from_fn(|| updates.try_recv().ok())
+            .map(|update| (update.order_id, update.status, update.filled_qty, update.filled_notional))
+            .collect();
+        assert_eq!(fills, vec![(0, OrderStatus::PartiallyFilled, 4, 4_020), (1, OrderStatus::Filled, 4, 4_020)]);
+    }
+
+    #[test]
+    fn test_iceberg_replenish() {
+        let mut engine = MatchingEngine::auto_creating();
+        let iceberg = Order::new(Qty(1000), LevelId(0), BookId(0), Some([1; 20]), None, None, None).with_display(Qty(100));
+        engine.match_limit_order(OrderId(0), iceberg, 100, false).unwrap();
+        engine.orderbook_manager.add_order(OrderId(1), BookId(0), Qty(100), 100, false, None, None, None, None).unwrap();
+        let queue = |engine: &MatchingEngine| -> Vec<u64> {
+            let book = engine.orderbook_manager.book(BookId(0)).unwrap();
+            book.iter_asks()
+                .next()
+                .map(|level| engine.orderbook_manager.oid_map.pool().queue(level).map(|(order_id, _)| order_id.0).collect())
+                .unwrap_or_default()
+        };
+        assert_eq!(engine.orderbook_manager.get_depth(BookId(0), 1).unwrap().asks, vec![(100, 200, 2)]);
+        assert_eq!(queue(&engine), vec![0, 1]);
+
+        for taker_id in 2..13 {
+            let (remaining, matches) = engine.match_order(OrderId(taker_id), BookId(0), Qty(100), 100, true, None, None, None, None).unwrap();
+            assert_eq!((remaining, matches.len(), matches[0].exec_qty), (Qty(0), 1, Qty(100)));
+
+            // The iceberg never shows more than its display quantity
+            let shown = engine.orderbook_manager.oid_map.get(OrderId(0)).map_or(0, |order| order.qty().value());
+            assert!(shown <= 100);
+            if taker_id == 2 {
+                // Replenishing sent the iceberg behind the order that was waiting after it
+                assert_eq!(queue(&engine), vec![1, 0]);
+                assert_eq!(engine.order_status(OrderId(0)), Some((OrderStatus::New, Qty(900))));
+            }
+        }
+        let mut makers: Vec<u64> = engine
+            .trade_tape(BookId(0))
+            .unwrap()
+            .iter_newest()
+            .map(|trade| trade.maker_order_id.0)
+            .collect();
+        makers.reverse();
+        println!("Makers: {:?}", makers);
+        assert_eq!(makers, vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), None);
+    }
+
+    #[test]
+    fn test_iceberg_fills_across_slices() {
+        let mut engine = MatchingEngine::auto_creating();
+        let iceberg = Order::new(Qty(300), LevelId(0), BookId(0), None, None, None, None).with_display(Qty(100));
+        engine.match_limit_order(OrderId(0), iceberg, 100, false).unwrap();
+
+        // One taker larger than a slice fills slice after slice, each fill its own
+        let (_, matches) = engine.match_order(OrderId(1), BookId(0), Qty(250), 100, true, None, None, None, None).unwrap();
+        let fills: Vec<u64> = matches.iter().map(|m| m.exec_qty.value()).collect();
+        assert_eq!(fills, vec![100, 100, 50]);
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(50)));
+
+        // An iceberg taker crosses with all of its quantity and rests showing one slice
+        let taker = Order::new(Qty(500), LevelId(0), BookId(0), None, None, None, None).with_display(Qty(30));
+        let (remaining, _) = engine.match_limit_order(OrderId(2), taker, 100, true).unwrap();
+        assert_eq!(remaining, Qty(450));
+        assert_eq!(engine.orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(30)));
+
+        // Snapshots keep the reserve, and cancelling removes all of it
+        let mut restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert_eq!(restored.orderbook_manager.oid_map.total_qty(OrderId(2)), Some(Qty(450)));
+        assert_eq!(restored.orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(30)));
+        assert_eq!(restored.orderbook_manager.cancel_remaining(OrderId(2)), Ok(Qty(450)));
+        assert_eq!(restored.orderbook_manager.get_best_bid(BookId(0)), None);
+    }
+
+    #[test]
+    fn test_match_preview_agrees_with_matching() {
+        // Previews an order, submits it right after, and checks the two agree
+        fn preview_then_match(engine: &mut MatchingEngine, order_id: u64, qty: u64, price: u32, is_bid: bool) -> MatchPreview {
+            let preview = engine.match_preview(BookId(0), Qty(qty), price, is_bid, None, false).unwrap();
+            let (remaining, fills) =
+                engine.match_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, None, None, None, None).unwrap();
+            let fills: Vec<PreviewFill> = fills
+                .iter()
+                .map(|fill| PreviewFill { maker_order_id: fill.maker.order_id, price: fill.exec_price, qty: fill.exec_qty })
+                .collect();
+            assert_eq!((&preview.fills, preview.remaining_qty), (&fills, remaining));
+            assert_eq!(preview.rests, engine.orderbook_manager.oid_map.get(OrderId(order_id)).is_some());
+            preview
+        }
+
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(1, 101, 10, false), (2, 101, 5, false), (3, 103, 20, false), (4, 99, 10, true)]);
+
+        // Across levels and through a queue, at the taker's limit
+        let preview = preview_then_match(&mut engine, 5, 30, 103, true);
+        assert_eq!(preview.fills.iter().map(|fill| fill.maker_order_id.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!((preview.filled_qty, preview.average_price, preview.rests), (Qty(30), Some(103), false));
+        // Nothing crosses, and it all rests
+        let preview = preview_then_match(&mut engine, 6, 10, 100, true);
+        assert_eq!((preview.filled_qty, preview.average_price, preview.rests), (Qty(0), None, true));
+        // A sell through two bid levels with some left to rest
+        let preview = preview_then_match(&mut engine, 7, 25, 99, false);
+        assert_eq!((preview.filled_qty, preview.remaining_qty), (Qty(20), Qty(5)));
+        // The book is left as it was
+        let before = engine.orderbook_manager.get_depth(BookId(0), 10);
+        engine.match_preview(BookId(0), Qty(100), 200, true, None, false).unwrap();
+        assert_eq!(engine.orderbook_manager.get_depth(BookId(0), 10), before);
+
+        // In an auction everything rests
+        engine.enter_auction(BookId(0)).unwrap();
+        let preview = preview_then_match(&mut engine, 8, 5, 90, false);
+        assert!(preview.fills.is_empty() && preview.rests);
+
+        // Errors are those of a submission
+        let errors = [(Qty(5), u32::MAX, false), (Qty(5), 100, true)];
+        for (qty, price, reduce_only) in errors {
+            let preview = engine.match_preview(BookId(0), qty, price, true, Some([1; 20]), reduce_only).unwrap_err();
+            let mut taker = Order::new(qty, LevelId(0), BookId(0), Some([1; 20]), None, None, None);
+            if reduce_only {
+                taker = taker.with_reduce_only();
+            }
+            assert_eq!(Err(preview), engine.match_limit_order(OrderId(9), taker, price, true).map(|_| ()));
+        }
+
+        // Hidden iceberg reserve isn't shown, so a preview can fill less than the order does
+        let mut engine = MatchingEngine::auto_creating();
+        let iceberg = Order::new(Qty(300), LevelId(0), BookId(0), None, None, None, None).with_display(Qty(100));
+        engine.match_limit_order(OrderId(0), iceberg, 100, false).unwrap();
+        rest(&mut engine, &[(1, 100, 50, false)]);
+        let preview = engine.match_preview(BookId(0), Qty(250), 100, true, None, false).unwrap();
+        let (_, fills) = engine.match_order(OrderId(2), BookId(0), Qty(250), 100, true, None, None, None, None).unwrap();
+        let makers: Vec<(u64, u64)> = fills.iter().map(|fill| (fill.maker.order_id.0, fill.exec_qty.value())).collect();
+        assert_eq!(makers, vec![(0, 100), (1, 50), (0, 100)]);
+        let previewed: Vec<(u64, u64)> = preview.fills.iter().map(|fill| (fill.maker_order_id.0, fill.qty.value())).collect();
+        assert_eq!((previewed.as_slice(), preview.remaining_qty), (&makers[..2], Qty(100)));
+    }
+
+    fn stop(order_id: u64, qty: u64, is_bid: bool, trigger: u32, limit: Option<u32>) -> StopOrder {
+        StopOrder {
+            order_id,
+            book_id: 0,
+            qty,
+            is_bid,
+            trigger,
+            limit,
+            trader: Some([7; 20]),
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+        }
+    }
+
+    fn rest(engine: &mut MatchingEngine, orders: &[(u64, u32, u64, bool)]) {
+        for &(order_id, price, qty, is_bid) in orders {
+            engine.orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, None, None, None, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stop_buy_triggers_on_uptick() {
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 101, 10, false), (1, 103, 10, false), (2, 99, 10, true)]);
+        engine.submit_stop(stop(3, 5, true, 102, None)).unwrap();
+        engine.submit_stop(stop(4, 5, false, 98, None)).unwrap();
+
+        // Untriggered stops stay out of the book
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), Some(Price(99)));
+        assert_eq!(engine.order_status(OrderId(3)), Some((OrderStatus::Untriggered, Qty(5))));
+        assert!(matches!(engine.submit_stop(stop(3, 5, true, 102, None)), Err(OrderBookError::DuplicateOrder(_))));
+
+        // A print at 101 is below the trigger
+        engine.match_order(OrderId(5), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!(engine.stops().len(), 2);
+
+        // A print at 103 fires the stop, which buys at the best ask as a market order
+        let (_, matches) = engine.match_order(OrderId(6), BookId(0), Qty(5), 103, true, None, None, None, None).unwrap();
+        assert_eq!(matches.len(), 1);
+        let trade = engine.trade_tape(BookId(0)).unwrap().iter_newest().next().copied().unwrap();
+        println!("Stop fill: {:?}", trade);
+        assert_eq!((trade.maker_order_id, trade.taker_order_id, trade.qty), (OrderId(1), OrderId(3), Qty(5)));
+        assert_eq!(engine.orderbook_manager.oid_map.get(OrderId(1)).map(|order| order.qty()), Some(Qty(5)));
+        assert_eq!(engine.order_status(OrderId(3)), None);
+
+        // The sell stop below the market is left waiting
+        assert_eq!(engine.stops().entries(), vec![stop(4, 5, false, 98, None)]);
+    }
+
+    #[test]
+    fn test_stop_cascade() {
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 101, 5, false), (1, 102, 5, false), (2, 104, 10, false)]);
+        engine.submit_stop(stop(3, 10, true, 101, None)).unwrap(); // Fired by the print at 101
+        engine.submit_stop(stop(4, 5, true, 104, Some(104))).unwrap(); // Fired by stop 3's print at 104
+        engine.submit_stop(stop(5, 5, true, 110, None)).unwrap(); // Never fired
+        engine.submit_stop(stop(7, 5, true, 104, Some(103))).unwrap(); // Fired with stop 4, rests below the asks
+
+        let (remaining, matches) = engine.match_order(OrderId(6), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!((remaining, matches.len()), (Qty(0), 1));
+
+        let mut takers: Vec<(u64, u32)> = engine
+            .trade_tape(BookId(0))
+            .unwrap()
+            .iter_newest()
+            .map(|trade| (trade.taker_order_id.0, trade.price))
+            .collect();
+        takers.reverse();
+        println!("Takers: {:?}", takers);
+        assert_eq!(takers, vec![(6, 101), (3, 102), (3, 104), (4, 104)]);
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(2)).is_none());
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), Some(Price(103)));
+        assert_eq!(engine.order_status(OrderId(7)), Some((OrderStatus::Triggered, Qty(5))));
+        assert_eq!(engine.order_status(OrderId(5)), Some((OrderStatus::Untriggered, Qty(5))));
+
+        // A snapshot keeps the waiting stop and the triggered one's status
+        let restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert_eq!(restored.stops().entries(), engine.stops().entries());
+        assert_eq!(restored.order_status(OrderId(7)), Some((OrderStatus::Triggered, Qty(5))));
+    }
+
+    #[test]
+    fn test_cancel_untriggered_stop() {
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 101, 10, false)]);
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        engine.submit_stop(stop(1, 5, true, 101, Some(101))).unwrap();
+        let cancelled = engine.cancel_stop(OrderId(1)).unwrap();
+        assert_eq!(cancelled.trigger, 101);
+        let statuses: Vec<OrderStatus> = std::iter::from_fn(|| updates.try_recv().ok()).map(|update| update.status).collect();
+        assert_eq!(statuses, vec![OrderStatus::Untriggered, OrderStatus::Cancelled]);
+        assert!(matches!(engine.cancel_stop(OrderId(1)), Err(OrderBookError::UnknownOrder)));
+
+        // A print through the trigger finds nothing to fire
+        engine.match_order(OrderId(2), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!(engine.trade_tape(BookId(0)).unwrap().iter_newest().count(), 1);
+        assert_eq!(engine.order_status(OrderId(1)), None);
+
+        // Cancelling all of a trader's orders takes their stops too
+        engine.submit_stop(stop(3, 5, false, 90, None)).unwrap();
+        assert_eq!(engine.cancel_all_for_trader([7; 20], None), vec![OrderId(3)]);
+        assert!(engine.stops().is_empty());
+    }
+
+    fn pegged(order_id: u64, qty: u64, is_bid: bool, peg: Peg) -> PeggedOrder {
+        PeggedOrder {
+            order_id,
+            book_id: 0,
+            qty,
+            is_bid,
+            limit: if is_bid { 1000 } else { 1 },
+            peg,
+            may_cross: false,
+            display: None,
+            trader: Some([7; 20]),
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+            price: None,
+        }
+    }
+
+    #[test]
+    fn test_order_price_follows_its_level() {
+        // Every resting order's own price is the price of the level it rests on
+        let check = |engine: &MatchingEngine| {
+            let manager = &engine.orderbook_manager;
+            for (order_id, order) in manager.oid_map.iter() {
+                let level = manager.book(order.book_id()).unwrap().level_pool.get(order.level_id()).unwrap();
+                assert_eq!(order.price(), level.price(), "order {}", order_id.0);
+            }
+            assert_eq!(manager.book(BookId(0)).unwrap().check_invariants(BookId(0), &manager.oid_map), Ok(()));
+        };
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 99, 10, true), (1, 105, 10, false)]);
+        let iceberg = Order::new(Qty(300), LevelId(0), BookId(0), None, None, None, None).with_display(Qty(100));
+        engine.match_limit_order(OrderId(2), iceberg, 106, false).unwrap();
+        check(&engine);
+        assert_eq!(engine.orderbook_manager.oid_map.get(OrderId(1)).unwrap().price(), Price(-105));
+
+        // A taker that rests what it doesn't fill keeps its limit, not the price it traded at
+        engine.match_order(OrderId(3), BookId(0), Qty(15), 105, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_price(OrderId(3)), Some(105));
+        assert_eq!(engine.orderbook_manager.is_bid(OrderId(3)), Some(true));
+        check(&engine);
+
+        // Partial fills, partial cancels, a replace, and an iceberg slice refilling
+        engine.orderbook_manager.cancel_order(OrderId(0), Qty(4)).unwrap();
+        engine.replace_order(OrderId(3), OrderId(4), Qty(5), 98).unwrap();
+        assert_eq!(engine.order_price(OrderId(4)), Some(98));
+        engine.match_order(OrderId(5), BookId(0), Qty(150), 106, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_price(OrderId(2)), Some(106));
+        check(&engine);
+
+        // Pegs moving between levels, and a book rebuilt from a snapshot
+        engine.submit_pegged(pegged(6, 5, true, Peg::Midpoint)).unwrap();
+        engine.match_order(OrderId(7), BookId(0), Qty(1), 100, true, None, None, None, None).unwrap();
+        check(&engine);
+        let restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        check(&restored);
+        assert_eq!(restored.order_price(OrderId(6)), engine.order_price(OrderId(6)));
+    }
+
+    #[test]
+    fn test_arrival_and_execution_times() {
+        let mut engine = MatchingEngine::auto_creating();
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+
+        // An order stamped at intake keeps its stamp; one without is stamped on reaching the engine
+        engine.clock = Clock::Fixed(2_000);
+        let stamped = Order::new(Qty(10), LevelId(0), BookId(0), Some([1; 20]), None, None, None).with_received_at(1_500);
+        engine.match_limit_order(OrderId(0), stamped, 100, false).unwrap();
+        engine.match_order(OrderId(1), BookId(0), Qty(10), 101, false, Some([1; 20]), None, None, None).unwrap();
+        let received = |engine: &MatchingEngine, order_id| engine.orderbook_manager.oid_map.meta(OrderId(order_id)).map(|meta| meta.received_at);
+        assert_eq!((received(&engine, 0), received(&engine, 1)), (Some(1_500), Some(2_000)));
+        assert_eq!(updates.try_recv().unwrap().received_at, Some(1_500));
+        assert_eq!(updates.try_recv().unwrap().received_at, Some(2_000));
+
+        // A fill is stamped with its trade's time, which the tape and the order updates agree on
+        engine.clock = Clock::Fixed(3_000);
+        let (_, fills) = engine.match_order(OrderId(2), BookId(0), Qty(15), 101, true, Some([2; 20]), None, None, None).unwrap();
+        assert_eq!(fills.iter().map(|fill| fill.executed_at).collect::<Vec<_>>(), vec![3_000, 3_000]);
+        let tape: Vec<u64> = engine.trade_tape(BookId(0)).unwrap().iter_newest().map(|trade| trade.timestamp).collect();
+        assert_eq!(tape, vec![3_000, 3_000]);
+        let update = std::iter::from_fn(|| updates.try_recv().ok()).find(|update| update.order_id == 2).unwrap();
+        assert_eq!(update.received_at, Some(3_000));
+
+        // Snapshots keep arrival times, and so does the WAL
+        assert_eq!(received(&MatchingEngine::restore(engine.snapshot()).unwrap(), 1), Some(2_000));
+        let mut replayed = MatchingEngine::auto_creating();
+        replayed.clock = Clock::Fixed(9_000);
+        replayed.apply(&WalCommand::Submit {
+            order_id: 7,
+            book_id: 0,
+            qty: 10,
+            price: 100,
+            is_bid: true,
+            trader: None,
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+            display: None,
+            reduce_only: false,
+            received_at: 1_234,
+        });
+        assert_eq!(received(&replayed, 7), Some(1_234));
+    }
+
+    #[test]
+    fn test_pegs_follow_bbo() {
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 99, 10, true), (1, 105, 10, false)]);
+        engine.submit_pegged(pegged(2, 5, true, Peg::Midpoint)).unwrap();
+        engine.submit_pegged(pegged(3, 5, true, Peg::Primary { offset: -1 })).unwrap();
+        let prices = |engine: &MatchingEngine| [2, 3].map(|order_id| engine.order_price(OrderId(order_id)));
+        assert_eq!(prices(&engine), [Some(102), Some(98)]);
+
+        // A better bid lifts both pegs, which follow the other orders rather than each other
+        engine.match_order(OrderId(4), BookId(0), Qty(10), 101, true, None, None, None, None).unwrap();
+        assert_eq!(prices(&engine), [Some(103), Some(100)]);
+
+        // A better ask pulls the midpoint down
+        engine.match_order(OrderId(5), BookId(0), Qty(10), 104, false, None, None, None, None).unwrap();
+        assert_eq!(prices(&engine), [Some(102), Some(100)]);
+
+        // The bid leaving takes both back
+        engine.cancel_resting(OrderId(4), OrderStatus::Cancelled).unwrap();
+        println!("Pegs: {:?}", engine.pegs().entries());
+        assert_eq!(prices(&engine), [Some(101), Some(98)]);
+        assert_eq!(engine.order_status(OrderId(2)), Some((OrderStatus::New, Qty(5))));
+
+        // A peg that would cross stays a tick behind the ask and doesn't trade
+        engine.submit_pegged(pegged(6, 5, true, Peg::Primary { offset: 10 })).unwrap();
+        assert_eq!(engine.order_price(OrderId(6)), Some(103));
+        assert!(engine.trade_tape(BookId(0)).is_none());
+
+        // Filled pegs are dropped
+        engine.match_order(OrderId(7), BookId(0), Qty(5), 103, false, None, None, None, None).unwrap();
+        assert_eq!(engine.order_price(OrderId(6)), None);
+        assert_eq!(engine.pegs().len(), 2);
+    }
+
+    #[test]
+    fn test_midpoint_peg_parks_without_a_side() {
+        let mut engine = MatchingEngine::auto_creating();
+
+        // Nothing to peg to: the submission is rejected
+        let result = engine.submit_pegged(pegged(0, 5, false, Peg::Midpoint));
+        assert!(matches!(result, Err(OrderBookError::NoPegReference(BookId(0)))));
+        assert!(engine.pegs().is_empty());
+
+        rest(&mut engine, &[(1, 99, 10, true), (2, 105, 10, false)]);
+        engine.submit_pegged(pegged(3, 5, false, Peg::Midpoint)).unwrap();
+        assert_eq!(engine.order_price(OrderId(3)), Some(102));
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+
+        // The bids empty: the resting peg is parked off the book
+        engine.cancel_resting(OrderId(1), OrderStatus::Cancelled).unwrap();
+        assert_eq!(engine.order_status(OrderId(3)), Some((OrderStatus::Parked, Qty(5))));
+        assert_eq!(engine.order_price(OrderId(3)), None);
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)).map(|price| price.absolute()), Some(105));
+        let statuses: Vec<OrderStatus> = std::iter::from_fn(|| updates.try_recv().ok()).map(|update| update.status).collect();
+        assert_eq!(statuses, vec![OrderStatus::Parked]);
+
+        // A snapshot keeps it parked, and a new bid brings it back
+        let mut engine = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert_eq!(engine.order_status(OrderId(3)), Some((OrderStatus::Parked, Qty(5))));
+        engine.match_order(OrderId(4), BookId(0), Qty(10), 101, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_price(OrderId(3)), Some(103));
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)).map(|price| price.absolute()), Some(103));
+
+        // A parked peg can be cancelled
+        engine.cancel_resting(OrderId(4), OrderStatus::Cancelled).unwrap();
+        engine.cancel_resting(OrderId(3), OrderStatus::Cancelled).unwrap();
+        assert!(engine.pegs().is_empty());
+        assert!(matches!(engine.cancel_resting(OrderId(3), OrderStatus::Cancelled), Err(OrderBookError::UnknownOrder)));
+    }
+
+    #[test]
+    fn test_crossed_book_halts_until_repaired() {
+        use crate::events::VecSink;
+        let mut engine = MatchingEngine::auto_creating();
+        let sink = VecSink::new();
+        engine.orderbook_manager.set_event_sink(Box::new(sink.clone()));
+        engine.orderbook_manager.add_order(OrderId(1), BookId(0), Qty(10), 100, false, None, None, None, None).unwrap();
+        engine.orderbook_manager.add_order(OrderId(2), BookId(0), Qty(5), 98, true, None, None, None, None).unwrap();
+        engine.rest_unmatched(OrderId(3), BookId(0), Qty(8), 101, true);
+
+        assert!(engine.is_crossed(BookId(0)));
+        let crossed = sink.take().into_iter().find(|event| matches!(event, OrderBookEvent::BookCrossed { .. }));
+        assert!(matches!(crossed, Some(OrderBookEvent::BookCrossed { best_bid: 101, best_ask: 100, .. })));
+        let refused = Some(OrderBookError::BookCrossed(BookId(0)));
+        assert_eq!(engine.match_order(OrderId(4), BookId(0), Qty(1), 90, false, None, None, None, None).err(), refused);
+        assert_eq!(engine.replace_order(OrderId(2), OrderId(5), Qty(5), 99).err(), refused);
+        engine.cancel_resting(OrderId(2), OrderStatus::Cancelled).unwrap();
+
+        // The halt survives a restart, as the book stays crossed
+        assert!(MatchingEngine::restore(engine.snapshot()).unwrap().is_crossed(BookId(0)));
+
+        // The ask rested first, so the bid that crossed it trades at the ask's price
+        let fills = engine.repair_crossed(BookId(0)).unwrap();
+        let fills: Vec<_> = fills.iter().map(|fill| (fill.maker.order_id, fill.taker.order_id, fill.exec_price, fill.exec_qty)).collect();
+        assert_eq!(fills, [(OrderId(1), OrderId(3), 100, Qty(8))]);
+        assert!(!engine.is_crossed(BookId(0)));
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(2)));
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
+        let (remaining, fills) = engine.match_order(OrderId(4), BookId(0), Qty(1), 100, true, None, None, None, None).unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(0), 1));
+    }
+
+    #[test]
+    fn test_orders_need_an_open_book() {
+        let mut engine = MatchingEngine::new();
+        let order = |book_id: u32| Order::new(Qty(10), LevelId(0), BookId(book_id), Some([1; 20]), None, None, Signature::None);
+
+        // A book nobody created refuses every kind of order, and isn't created by them
+        let unknown = Some(OrderBookError::UnknownBook(BookId(0)));
+        assert_eq!(engine.match_limit_order(OrderId(1), order(0), 100, true).err(), unknown);
+        assert_eq!(engine.match_market_order(OrderId(2), order(0), true).err(), unknown);
+        assert_eq!(engine.submit_stop(stop(3, 10, true, 100, None)).err(), unknown);
+        assert_eq!(engine.submit_pegged(pegged(4, 10, true, Peg::Midpoint)).err(), unknown);
+        assert_eq!(engine.enter_auction(BookId(0)).err(), unknown);
+        assert!(engine.orderbook_manager.book(BookId(0)).is_none());
+        assert_eq!(engine.orderbook_manager.books().count(), 0);
+        assert!(engine.order_status(OrderId(1)).is_none());
+
+        // A book without a market doesn't match until it has one or is made book-only
+        engine.orderbook_manager.create_book(BookId(0)).unwrap();
+        engine.orderbook_manager.create_book(BookId(1)).unwrap();
+        let no_market = Some(OrderBookError::NoMarket(BookId(0)));
+        assert_eq!(engine.match_limit_order(OrderId(1), order(0), 100, true).err(), no_market);
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
+        engine.market_manager.add_market(BookId(0), MarketConfig::default(), true).unwrap();
+        engine.match_limit_order(OrderId(1), order(0), 100, true).unwrap();
+        engine.set_book_only(BookId(1), true);
+        engine.match_limit_order(OrderId(2), order(1), 100, true).unwrap();
+        engine.set_book_only(BookId(1), false);
+        assert_eq!(engine.match_limit_order(OrderId(3), order(1), 100, true).err(), Some(OrderBookError::NoMarket(BookId(1))));
+
+        // Snapshots keep which books are book-only
+        engine.set_book_only(BookId(1), true);
+        let restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert!(restored.is_book_only(BookId(1)) && !restored.is_book_only(BookId(0)));
+
+        // An auto-creating engine takes orders for any book in range, as engines used to
+        let mut engine = MatchingEngine::auto_creating();
+        engine.match_limit_order(OrderId(1), order(5), 100, true).unwrap();
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(5)), Some(Price(100)));
+        let far = BookId(crate::utils::MAX_BOOKS as u32);
+        let refused = engine.match_limit_order(OrderId(2), order(far.value()), 100, true);
+        assert_eq!(refused.err(), Some(OrderBookError::BookOutOfRange(far)));
+    }
+
+    #[test]
+    fn test_close_book() {
+        use crate::events::{CancelReason, VecSink};
+
+        let mut engine = MatchingEngine::auto_creating();
+        engine.market_manager.add_market(BookId(0), MarketConfig::default(), false).unwrap();
+        rest(&mut engine, &[(1, 99, 10, true), (2, 105, 10, false)]);
+        engine.submit_pegged(pegged(3, 5, false, Peg::Midpoint)).unwrap();
+        engine.cancel_resting(OrderId(1), OrderStatus::Cancelled).unwrap(); // Parks the peg
+        engine.submit_stop(stop(4, 5, true, 110, None)).unwrap();
+        engine.enter_auction(BookId(0)).unwrap();
+        let replayed = MatchingEngine::restore(engine.snapshot()).unwrap();
+
+        let sink = VecSink::new();
+        engine.orderbook_manager.set_event_sink(Box::new(sink.clone()));
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        assert_eq!(engine.close_book(BookId(0)), Ok(vec![OrderId(2), OrderId(4), OrderId(3)]));
+
+        let reasons: Vec<(OrderId, CancelReason)> = sink
+            .events()
+            .iter()
+            .filter_map(|event| match *event {
+                OrderBookEvent::OrderCancelled { order_id, reason, .. } => Some((order_id, reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reasons, vec![(OrderId(2), CancelReason::BookClosed)]);
+        let statuses: Vec<(u64, OrderStatus)> =
+            std::iter::from_fn(|| updates.try_recv().ok()).map(|update| (update.order_id, update.status)).collect();
+        assert_eq!(statuses, vec![(4, OrderStatus::BookClosed), (3, OrderStatus::BookClosed)]);
+
+        assert!(engine.orderbook_manager.book(BookId(0)).is_none());
+        assert!(engine.market_manager.get_config(BookId(0)).is_none());
+        assert!(!engine.in_auction(BookId(0)));
+        assert!(engine.stops().is_empty() && engine.pegs().is_empty());
+        assert_eq!(engine.close_book(BookId(0)), Err(OrderBookError::UnknownBook(BookId(0))));
+
+        // Replaying the close leaves the same
+        let mut replayed = replayed;
+        replayed.apply(&WalCommand::CloseBook { book_id: 0 });
+        assert!(replayed.orderbook_manager.book(BookId(0)).is_none());
+        assert!(replayed.stops().is_empty() && replayed.pegs().is_empty());
+        assert_eq!(replayed.order_status(OrderId(2)), None);
+    }
+
+    fn limit_leg(order_id: u64, qty: u64, price: u32, is_bid: bool) -> OcoLeg {
+        OcoLeg::Limit {
+            order_id,
+            book_id: 0,
+            qty,
+            price,
+            is_bid,
+            trader: Some([7; 20]),
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+        }
+    }
+
+    #[test]
+    fn test_oco_fill_cancels_sibling() {
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 90, 50, true)]);
+
+        // A take-profit at 105 and a stop at 95, both selling 10
+        let legs = [limit_leg(1, 10, 105, false), OcoLeg::Stop(stop(2, 10, false, 95, None))];
+        engine.submit_oco(legs.clone(), OcoPolicy::Cancel, false).unwrap();
+        assert_eq!(engine.oco().group_of(OrderId(2)).map(|group| group.sibling(OrderId(2))), Some(OrderId(1)));
+        assert!(matches!(engine.submit_oco(legs, OcoPolicy::Cancel, false), Err(OrderBookError::DuplicateOrder(_))));
+        let legs = [limit_leg(3, 10, 105, false), limit_leg(3, 10, 106, false)];
+        assert!(matches!(engine.submit_oco(legs, OcoPolicy::Cancel, false), Err(OrderBookError::InvalidOco)));
+
+        // The take-profit fills in full and the stop goes
+        engine.match_order(OrderId(4), BookId(0), Qty(10), 105, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_status(OrderId(1)), None);
+        assert_eq!(engine.order_status(OrderId(2)), None);
+        assert!(engine.stops().is_empty() && engine.oco().is_empty());
+
+        // A stop that triggers takes the take-profit with it
+        let legs = [limit_leg(5, 10, 105, false), OcoLeg::Stop(stop(6, 10, false, 95, None))];
+        engine.submit_oco(legs, OcoPolicy::Reduce, false).unwrap();
+        engine.match_order(OrderId(7), BookId(0), Qty(5), 90, false, None, None, None, None).unwrap();
+        let trade = engine.trade_tape(BookId(0)).unwrap().iter_newest().next().copied().unwrap();
+        println!("Stop fill: {:?}", trade);
+        assert_eq!((trade.taker_order_id, trade.qty), (OrderId(6), Qty(10)));
+        assert_eq!(engine.order_status(OrderId(5)), None);
+        assert!(engine.oco().is_empty());
+    }
+
+    #[test]
+    fn test_oco_partial_fills() {
+        let mut engine = MatchingEngine::auto_creating();
+
+        // Cancel: the first partial fill cancels the sibling, and what is left of the leg rests alone
+        let legs = [limit_leg(0, 10, 105, false), OcoLeg::Stop(stop(1, 10, false, 95, None))];
+        engine.submit_oco(legs, OcoPolicy::Cancel, false).unwrap();
+        engine.match_order(OrderId(2), BookId(0), Qty(4), 105, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_status(OrderId(0)), Some((OrderStatus::New, Qty(6))));
+        assert!(engine.stops().is_empty() && engine.oco().is_empty());
+        engine.cancel_resting(OrderId(0), OrderStatus::Cancelled).unwrap();
+
+        // Reduce: the sibling shrinks with what the filled leg has left
+        let legs = [limit_leg(3, 10, 105, false), OcoLeg::Stop(stop(4, 20, false, 95, None))];
+        engine.submit_oco(legs, OcoPolicy::Reduce, false).unwrap();
+        engine.match_order(OrderId(5), BookId(0), Qty(4), 105, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_status(OrderId(4)), Some((OrderStatus::Untriggered, Qty(12))));
+        assert_eq!(engine.oco().len(), 1);
+
+        // The pair survives a snapshot, and the rest of the fill cancels the stop
+        let mut engine = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert_eq!(engine.oco().entries().len(), 1);
+        engine.match_order(OrderId(6), BookId(0), Qty(6), 105, true, None, None, None, None).unwrap();
+        assert_eq!(engine.order_status(OrderId(4)), None);
+        assert!(engine.oco().is_empty());
+
+        // Two limit legs: the second goes in reduced by what the first filled on arrival
+        rest(&mut engine, &[(7, 100, 4, true)]);
+        let legs = [limit_leg(8, 10, 100, false), limit_leg(9, 10, 110, false)];
+        engine.submit_oco(legs, OcoPolicy::Reduce, false).unwrap();
+        assert_eq!(engine.order_status(OrderId(8)), Some((OrderStatus::New, Qty(6))));
+        assert_eq!(engine.order_status(OrderId(9)), Some((OrderStatus::New, Qty(6))));
+    }
+
+    #[test]
+    fn test_oco_manual_cancel() {
+        let mut engine = MatchingEngine::auto_creating();
+
+        // By default the sibling survives, unlinked
+        let legs = [limit_leg(0, 10, 105, false), OcoLeg::Stop(stop(1, 10, false, 95, None))];
+        engine.submit_oco(legs, OcoPolicy::Cancel, false).unwrap();
+        engine.cancel_resting(OrderId(0), OrderStatus::Cancelled).unwrap();
+        assert_eq!(engine.order_status(OrderId(1)), Some((OrderStatus::Untriggered, Qty(10))));
+        assert!(engine.oco().is_empty());
+        engine.cancel_stop(OrderId(1)).unwrap();
+
+        // With cancel_together it goes too
+        let legs = [limit_leg(2, 10, 105, false), OcoLeg::Stop(stop(3, 10, false, 95, None))];
+        engine.submit_oco(legs, OcoPolicy::Cancel, true).unwrap();
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        engine.cancel_stop(OrderId(3)).unwrap();
+        assert_eq!(engine.order_status(OrderId(2)), None);
+        let cancelled: Vec<u64> = std::iter::from_fn(|| updates.try_recv().ok()).map(|update| update.order_id).collect();
+        assert_eq!(cancelled, vec![3, 2]);
+        assert!(engine.orderbook_manager.get_best_ask(BookId(0)).is_none());
+    }
+
+    #[test]
+    fn test_mass_quote_replaces_sets() {
+        use crate::mass_quote::{QuoteEntry, QuoteSide};
+
+        let maker = [5; 20];
+        let side = |order_id, price, qty| Some(QuoteSide { order_id, qty, price, nonce: order_id, expiry: None, signature: Signature::None });
+        let quote = |book_id, bid, ask| QuoteEntry { book_id, bid, ask };
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 90, 10, true)]);
+
+        // Two books quoted at once; the other trader's bid stays
+        let first = [quote(0, side(1, 99, 10), side(2, 101, 10)), quote(1, side(3, 49, 5), None)];
+        let outcomes = engine.mass_quote(maker, &first, 0).unwrap();
+        assert!(outcomes.iter().all(|outcome| outcome.as_ref().is_ok_and(|outcome| outcome.cancelled.is_empty())));
+        engine.match_order(OrderId(4), BookId(0), Qty(4), 101, true, None, None, None, None).unwrap();
 
-        let mut match_details = Vec::new();
+        // Replacing book 0 takes down what is left of its set and nothing else
+        let second = [quote(0, side(5, 98, 10), side(6, 102, 10)), quote(1, side(7, 50, 5), side(8, 50, 5))];
+        let outcomes = engine.mass_quote(maker, &second, 0).unwrap();
+        let replaced = outcomes[0].as_ref().unwrap();
+        assert_eq!(replaced.cancelled, vec![OrderId(1), OrderId(2)]);
+        assert_eq!((replaced.bid_remaining, replaced.ask_remaining), (Some(Qty(10)), Some(Qty(10))));
+        assert_eq!(outcomes[1].as_ref().err(), Some(&OrderBookError::CrossedQuote(BookId(1))));
+        assert_eq!(engine.order_status(OrderId(3)), Some((OrderStatus::New, Qty(5))));
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), Some(Price(98)));
+        assert_eq!(engine.order_status(OrderId(0)), Some((OrderStatus::New, Qty(10))));
+        let usage = engine.orderbook_manager.open_orders.usage(maker, BookId(0));
+        assert_eq!(usage.open_orders, 2);
 
-        if can_match {
-            // Match against resting orders until either:
-            // 1. The incoming order is fully filled
-            // 2. There are no more orders at acceptable prices
-            while remaining_qty.value() > 0 {
-                if let Some((resting_order_id, match_qty)) = self.orderbook_manager
-                    .get_next_match(book_id, is_bid, price) 
-                {
-                    let exec_qty = std::cmp::min(remaining_qty, match_qty);
-                    
-                    // Execute the match
-                    self.orderbook_manager.execute_order(resting_order_id, exec_qty);
-                    remaining_qty -= exec_qty;
+        // The log replays to the same quote sets, and cancelling them leaves the rest of the book
+        let mut replayed = MatchingEngine::auto_creating();
+        rest(&mut replayed, &[(0, 90, 10, true)]);
+        replayed.apply(&WalCommand::MassQuote { trader: maker, entries: first.to_vec(), received_at: 0 });
+        replayed.apply(&WalCommand::Submit {
+            order_id: 4, book_id: 0, qty: 4, price: 101, is_bid: true, trader: None, nonce: None, expiry: None,
+            signature: Signature::None, display: None, reduce_only: false, received_at: 0,
+        });
+        replayed.apply(&WalCommand::MassQuote { trader: maker, entries: second.to_vec(), received_at: 0 });
+        assert_eq!(replayed.quote_set(maker, BookId(0)), engine.quote_set(maker, BookId(0)));
+        assert!(replayed.nonces.check(maker, 8).is_err());
+        replayed.apply(&WalCommand::CancelQuotes { trader: maker, book_id: None });
+        let mut cancelled = engine.cancel_quotes(maker, None);
+        cancelled.sort_unstable();
+        assert_eq!(cancelled, vec![OrderId(3), OrderId(5), OrderId(6)]);
+        for engine in [&engine, &replayed] {
+            assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), Some(Price(90)));
+            assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), None);
+            assert!(engine.quote_set(maker, BookId(1)).is_none());
+        }
+    }
 
-                    // Add match details
-                    if let Some(maker_order) = self.orderbook_manager.oid_map.get(resting_order_id) {
-                        match_details.push(MatchDetails {
-                            maker_order: maker_order.clone(),
-                            taker_order: Order::new(
-                                qty,
-                                LevelId(0),
-                                book_id,
-                                trader,
-                                nonce,
-                                expiry,
-                                signature,
-                            ),
-                            exec_qty,
-                            exec_price: price.absolute() as u32,
-                            maker_is_buyer: !is_bid,
-                        });
-                    }
-                } else {
-                    break;
-                }
-            }
+    #[test]
+    fn test_good_til_time_expirations() {
+        let mut engine = MatchingEngine::auto_creating();
+        let expiry_of = |order_id: u64| (order_id % 10 != 9).then_some(1000 + order_id * 37 % 600);
+        for order_id in 0..3000u64 {
+            let (is_bid, level) = (order_id % 2 == 0, (order_id / 2 % 50) as u32);
+            let price = if is_bid { 100 + level } else { 200 + level };
+            let expiry = expiry_of(order_id);
+            engine.match_order(OrderId(order_id), BookId(0), Qty(10), price, is_bid, None, None, expiry, None).unwrap();
         }
+        engine.submit_stop(StopOrder { expiry: Some(1050), ..stop(3000, 10, true, 300, None) }).unwrap();
 
-        // Add any remaining quantity to the book
-        if remaining_qty.value() > 0 {
-            self.orderbook_manager.add_order(
-                order_id,
-                book_id,
-                remaining_qty,
-                price.absolute() as u32,
-                is_bid,
-                trader,
-                nonce,
-                expiry,
-                signature,
-            );
+        // Cancelled and filled orders leave stale entries behind, which polling skips
+        for order_id in (0..3000).step_by(13) {
+            engine.cancel_resting(OrderId(order_id), OrderStatus::Cancelled).unwrap();
         }
+        let (remaining, fills) = engine.match_order(OrderId(3001), BookId(0), Qty(100), 200, true, None, None, None, None).unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(0), 10));
+
+        let book_total = |engine: &MatchingEngine| -> u64 {
+            let depth = engine.orderbook_manager.get_depth(BookId(0), usize::MAX).unwrap();
+            depth.bids.iter().chain(&depth.asks).map(|&(_, qty, _)| qty).sum()
+        };
+        assert_eq!(engine.poll_expirations(999), Vec::new());
+        for now in [1200, 1599, 10_000] {
+            // Expected: every working order due by now, soonest expiry first, ties in order ID order
+            let mut due: Vec<(u64, u64)> = (0..=3000)
+                .filter(|&order_id| engine.order_status(OrderId(order_id)).is_some())
+                .filter_map(|order_id| {
+                    let expiry = if order_id == 3000 { Some(1050) } else { expiry_of(order_id) };
+                    expiry.filter(|&expiry| expiry <= now).map(|expiry| (expiry, order_id))
+                })
+                .collect();
+            due.sort_unstable();
+            let due_qty = due.iter().filter(|&&(_, order_id)| order_id != 3000).count() as u64 * 10;
+            let total = book_total(&engine);
 
-        (remaining_qty, match_details)
+            let start = Instant::now();
+            let expired = engine.poll_expirations(now);
+            println!("Expired {} orders due by {} in {:?}", expired.len(), now, start.elapsed());
+            assert_eq!(expired, due.iter().map(|&(_, order_id)| OrderId(order_id)).collect::<Vec<_>>());
+            assert_eq!(book_total(&engine), total - due_qty);
+        }
+        // Only the orders without an expiry are left, less those cancelled or filled
+        let working = (0..3000u64).filter(|&order_id| engine.order_status(OrderId(order_id)).is_some()).count();
+        assert_eq!(book_total(&engine), working as u64 * 10);
+        assert!((0..3000u64).all(|order_id| engine.order_status(OrderId(order_id)).is_none() || expiry_of(order_id).is_none()));
+        assert_eq!((working, engine.stops().len()), (277, 0));
     }
-}
 
-#[derive(Debug)]
-pub struct MatchDetails {
-    pub maker_order: Order,
-    pub taker_order: Order,
-    pub exec_qty: Qty,
-    pub exec_price: u32,
-    pub maker_is_buyer: bool,
-}
+    #[test]
+    fn test_kill_switch() {
+        use crate::market_data::MarketDataEvent;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::time::{Duration, Instant};
-    use rand::Rng;
+        let mut engine = MatchingEngine::auto_creating();
+        rest(&mut engine, &[(0, 101, 10, false), (1, 99, 10, true)]);
+        engine.match_order(OrderId(2), BookId(0), Qty(10), 98, true, None, None, Some(500), None).unwrap();
+        engine.enter_auction(BookId(1)).unwrap();
+        let mut market_data = engine.orderbook_manager.market_data.subscribe();
+        engine.set_halted(true);
+        let halts: Vec<(u32, bool)> = std::iter::from_fn(|| market_data.try_recv().ok())
+            .filter_map(|event| match event {
+                MarketDataEvent::Halt { book_id, halted, .. } => Some((book_id, halted)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(halts, vec![(0, true), (1, true)]);
 
-    // Helper function to print match details
-    fn print_match_details(
-        maker_order: &Order,
-        taker_id: OrderId,
-        taker_trader: Option<[u8; 20]>,
-        taker_nonce: Option<u64>,
-        exec_qty: Qty,
-        price: u32,
-        is_bid: bool,
-    ) {
-        println!("\nMATCH DETAILS:");
-        println!("---------------");
-        println!("Execution Quantity: {}", exec_qty.value());
-        println!("Price: {}", price);
-        println!("Direction: {}", if is_bid { "BUY" } else { "SELL" });
-        println!("Taker Order ID: {}", taker_id.0);
+        // Every way in for new risk is refused
+        let halted = Some(OrderBookError::Halted);
+        let taker = Order::new(Qty(5), LevelId(0), BookId(0), None, None, None, None);
+        assert_eq!(engine.match_order(OrderId(3), BookId(0), Qty(5), 101, true, None, None, None, None).err(), halted);
+        assert_eq!(engine.match_market_order(OrderId(3), taker, true).err(), halted);
+        assert_eq!(engine.submit_stop(stop(3, 5, true, 105, None)).err(), halted);
+        assert_eq!(engine.submit_pegged(pegged(3, 5, true, Peg::Midpoint)).err(), halted);
+        let legs = [limit_leg(3, 5, 90, true), limit_leg(4, 5, 110, false)];
+        assert_eq!(engine.submit_oco(legs, OcoPolicy::Cancel, false).err(), halted);
+        assert_eq!(engine.replace_order(OrderId(1), OrderId(3), Qty(5), 101).err(), halted);
+        assert_eq!(engine.uncross(BookId(1)).err(), halted);
+        assert!(engine.in_auction(BookId(1)));
+        assert_eq!(engine.orderbook_manager.get_best_ask(BookId(0)), Some(Price(-101)));
 
-        // Maker (resting order) details
-        println!("\nMAKER DETAILS:");
-        if let Some(trader) = maker_order.trader() {
-            println!("Address: 0x{}", hex::encode(trader));
-        }
-        if let Some(nonce) = maker_order.nonce() {
-            println!("Nonce: {}", nonce);
-        }
-        if let Some(expiry) = maker_order.expiry() {
-            println!("Expiry: {}", expiry);
-        }
+        // Cancels, expiries, and queries still go through
+        engine.cancel_resting(OrderId(1), OrderStatus::Cancelled).unwrap();
+        assert_eq!(engine.poll_expirations(500), vec![OrderId(2)]);
+        assert_eq!(engine.order_status(OrderId(0)), Some((OrderStatus::New, Qty(10))));
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
 
-        // Taker (incoming order) details
-        println!("\nTAKER DETAILS:");
-        if let Some(trader) = taker_trader {
-            println!("Address: 0x{}", hex::encode(trader));
-        }
-        if let Some(nonce) = taker_nonce {
-            println!("Nonce: {}", nonce);
-        }
-        println!("---------------\n");
+        // The switch survives a snapshot, and replaying its release lets orders in again
+        let mut restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert!(restored.is_halted());
+        restored.apply(&WalCommand::SetKillSwitch { engaged: false });
+        let (remaining, fills) = restored.match_order(OrderId(3), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(0), 1));
     }
 
     #[test]
-    fn test_basic_matching() {
-        let mut engine = MatchingEngine::new();
+    fn test_finalize() {
+        use crate::wal::{Wal, WalConfig};
 
-        println!("\nStarting basic matching test...");
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MatchingEngine::auto_creating();
+        engine.wal = Some(Wal::open(dir.path(), WalConfig::default()).unwrap());
+        rest(&mut engine, &[(0, 101, 10, false), (1, 99, 10, true)]);
+        engine.finalize().unwrap();
 
-        // Add a resting sell order
-        engine.orderbook_manager.add_order(
-            OrderId(1),
-            BookId(0),
-            Qty(100),
-            100,
-            false, // is_bid
-            Some([1; 20]),  // Example trader address
-            Some(1),        // Example nonce
-            Some(u64::MAX), // Example expiry
-            Some([0; 65]),  // Example signature
-        );
+        // New orders are refused but cancels go through
+        let refused = engine.match_order(OrderId(2), BookId(0), Qty(5), 101, true, None, None, None, None);
+        assert_eq!(refused.err(), Some(OrderBookError::ShuttingDown));
+        assert_eq!(engine.replace_order(OrderId(1), OrderId(2), Qty(5), 100).err(), Some(OrderBookError::ShuttingDown));
+        engine.cancel_resting(OrderId(1), OrderStatus::Cancelled).unwrap();
 
-        println!("Added resting sell order: ID(1), Qty(100), Price(100)");
+        // The engine restarts accepting orders, unlike after the kill switch
+        let mut restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert!(!restored.is_halted());
+        let (remaining, fills) = restored.match_order(OrderId(2), BookId(0), Qty(5), 101, true, None, None, None, None).unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(0), 1));
+    }
 
-        // Send in a matching buy order
-        let (remaining, details) = engine.match_order(
-            OrderId(2),
-            BookId(0),
-            Qty(60),
-            100,
-            true, // is_bid
-            Some([2; 20]),  // Different trader
-            Some(2),        // Different nonce
-            Some(u64::MAX),
-            Some([0; 65]),
-        );
+    /// An engine whose book 0 tracks positions, where trader 1 bought 5 from trader 2 at 100
+    fn position_engine() -> MatchingEngine {
+        use crate::market::MarketConfig;
 
-        // Get resting order details for printing
-        if let Some(maker_order) = engine.orderbook_manager.oid_map.get(OrderId(1)) {
-            print_match_details(
-                maker_order,
-                OrderId(2),
-                Some([2; 20]),  // taker trader
-                Some(2),        // taker nonce
-                Qty(60),
-                100,
-                true,
-            );
-        }
+        let mut engine = MatchingEngine::auto_creating();
+        engine.market_manager.add_market(BookId(0), MarketConfig::builder().track_positions(true).build(), false).unwrap();
+        engine.match_order(OrderId(0), BookId(0), Qty(5), 100, false, Some([2; 20]), None, None, None).unwrap();
+        engine.match_order(OrderId(1), BookId(0), Qty(5), 100, true, Some([1; 20]), None, None, None).unwrap();
+        assert_eq!((engine.positions().get([1; 20], BookId(0)), engine.positions().get([2; 20], BookId(0))), (5, -5));
+        engine
+    }
 
-        assert_eq!(remaining.value(), 0);
-        println!("Remaining quantity: {}", remaining.value());
+    fn reduce_only(qty: u64, trader: u8) -> Order {
+        Order::new(Qty(qty), LevelId(0), BookId(0), Some([trader; 20]), None, None, None).with_reduce_only()
+    }
 
-        // Check remaining sell order quantity
-        if let Some(order) = engine.orderbook_manager.oid_map.get(OrderId(1)) {
-            assert_eq!(order.qty().value(), 40);
-            println!("Remaining resting order quantity: {}", order.qty().value());
-        }
+    #[test]
+    fn test_reduce_only_truncated_to_position() {
+        let mut engine = position_engine();
+        engine.match_order(OrderId(2), BookId(0), Qty(8), 99, true, Some([3; 20]), None, None, None).unwrap();
+
+        // Long 5, the sell of 10 only trades 5 and nothing of it rests
+        let (remaining, fills) = engine.match_limit_order(OrderId(3), reduce_only(10, 1), 99, false).unwrap();
+        assert_eq!((remaining, fills.len(), fills[0].exec_qty), (Qty(0), 1, Qty(5)));
+        assert_eq!(engine.order_status(OrderId(2)), Some((OrderStatus::New, Qty(3))));
+        assert!(engine.positions().trader_positions([1; 20]).is_empty());
+
+        // Short 5, a buy of 8 rests capped at 5
+        let (remaining, _) = engine.match_limit_order(OrderId(4), reduce_only(8, 2), 90, true).unwrap();
+        assert_eq!(remaining, Qty(5));
+        assert_eq!(engine.order_status(OrderId(4)), Some((OrderStatus::New, Qty(5))));
     }
 
     #[test]
-    fn test_no_match_price() {
-        let mut engine = MatchingEngine::new();
+    fn test_reduce_only_without_position_rejected() {
+        let mut engine = position_engine();
+        assert!(matches!(
+            engine.match_limit_order(OrderId(2), reduce_only(5, 3), 100, false),
+            Err(OrderBookError::NoPositionToReduce(BookId(0)))
+        ));
+        // Long, so a buy would only add to the position
+        assert!(matches!(
+            engine.match_limit_order(OrderId(3), reduce_only(5, 1), 100, true),
+            Err(OrderBookError::NoPositionToReduce(BookId(0)))
+        ));
+        let untracked = Order::new(Qty(5), LevelId(0), BookId(1), Some([1; 20]), None, None, None).with_reduce_only();
+        assert!(matches!(
+            engine.match_limit_order(OrderId(4), untracked, 100, false),
+            Err(OrderBookError::PositionsNotTracked(BookId(1)))
+        ));
+        assert!(engine.orderbook_manager.get_best_ask(BookId(0)).is_none());
+    }
 
-        println!("\nStarting no-match price test...");
+    #[test]
+    fn test_failed_replace_keeps_original() {
+        let mut engine = position_engine();
+        engine.match_limit_order(OrderId(2), reduce_only(4, 1), 101, false).unwrap();
+        engine.match_order(OrderId(3), BookId(0), Qty(4), 101, false, Some([4; 20]), None, None, None).unwrap();
+        let queue = |engine: &MatchingEngine| -> Vec<u64> {
+            let book = engine.orderbook_manager.book(BookId(0)).unwrap();
+            let level = book.iter_asks().next().unwrap();
+            engine.orderbook_manager.oid_map.pool().queue(level).map(|(order_id, _)| order_id.0).collect()
+        };
+        assert_eq!(queue(&engine), vec![2, 3]);
 
-        // Add a resting sell order at 100
-        engine.orderbook_manager.add_order(
-            OrderId(1),
-            BookId(0),
-            Qty(100),
-            100,
-            false,
-            Some([1; 20]),
-            Some(1),
-            Some(u64::MAX),
-            Some([0; 65]),
-        );
+        // A price that doesn't fit, and a new ID already resting
+        for (new_order_id, new_price, error) in [
+            (OrderId(9), u32::MAX, OrderBookError::InvalidPrice(u32::MAX)),
+            (OrderId(3), 102, OrderBookError::DuplicateOrder(OrderId(3))),
+        ] {
+            assert_eq!(engine.replace_order(OrderId(2), new_order_id, Qty(4), new_price).unwrap_err(), error);
+            assert_eq!(queue(&engine), vec![2, 3]);
+        }
 
-        println!("Added resting sell order: ID(1), Qty(100), Price(100)");
+        // Once trader 1 has sold their long, the reduce-only order has nothing left to reduce
+        engine.match_order(OrderId(5), BookId(0), Qty(5), 99, true, Some([3; 20]), None, None, None).unwrap();
+        engine.match_order(OrderId(6), BookId(0), Qty(5), 99, false, Some([1; 20]), None, None, None).unwrap();
+        let error = engine.replace_order(OrderId(2), OrderId(9), Qty(4), 102).unwrap_err();
+        assert_eq!(error, OrderBookError::NoPositionToReduce(BookId(0)));
+        assert_eq!(queue(&engine), vec![2, 3]);
+        assert!(engine.orderbook_manager.oid_map.get(OrderId(9)).is_none());
+    }
 
-        // Send in a buy order at 99 (shouldn't match)
-        let (remaining, _) = engine.match_order(
-            OrderId(2),
-            BookId(0),
-            Qty(60),
-            99,
-            true,
-            Some([2; 20]),
-            Some(2),
-            Some(u64::MAX),
-            Some([0; 65]),
-        );
+    #[test]
+    fn test_resting_reduce_only_capped_as_position_changes() {
+        let mut engine = position_engine();
+        // Two reduce-only asks for 4 each rest against a long position of 5
+        engine.match_limit_order(OrderId(2), reduce_only(4, 1), 101, false).unwrap();
+        engine.match_limit_order(OrderId(3), reduce_only(4, 1), 102, false).unwrap();
 
-        println!("Attempted match with buy order: ID(2), Qty(60), Price(99)");
-        println!("No match occurred due to price mismatch");
-        println!("Remaining quantity: {}", remaining.value());
+        // The first fill leaves a long of 1, so the second ask is cut to 1 within the same sweep
+        let (remaining, _) = engine.match_order(OrderId(4), BookId(0), Qty(10), 102, true, Some([3; 20]), None, None, None).unwrap();
+        let trades = engine.trade_tape(BookId(0)).unwrap().recent(2, None);
+        println!("{:?}", trades);
+        let fills: Vec<(OrderId, Qty)> = trades.iter().rev().map(|trade| (trade.maker_order_id, trade.qty)).collect();
+        assert_eq!(fills, vec![(OrderId(2), Qty(4)), (OrderId(3), Qty(1))]);
+        assert_eq!(remaining, Qty(5));
+        assert_eq!((engine.positions().get([1; 20], BookId(0)), engine.positions().get([3; 20], BookId(0))), (0, 5));
 
-        assert_eq!(remaining.value(), 60);
+        // Trader 2 covers their short after resting a reduce-only bid, which then has nothing to
+        // reduce and is cancelled when a sell reaches it
+        engine.match_limit_order(OrderId(5), reduce_only(2, 2), 101, true).unwrap();
+        engine.match_order(OrderId(6), BookId(0), Qty(5), 103, false, Some([4; 20]), None, None, None).unwrap();
+        engine.match_order(OrderId(7), BookId(0), Qty(5), 103, true, Some([2; 20]), None, None, None).unwrap();
+        let (remaining, fills) = engine.match_order(OrderId(8), BookId(0), Qty(8), 95, false, Some([4; 20]), None, None, None).unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(3), 1));
+        assert_eq!(engine.order_status(OrderId(5)), None);
+        let depth = engine.orderbook_manager.get_depth(BookId(0), 10).unwrap();
+        assert_eq!((depth.bids, depth.asks), (vec![], vec![(95, 3, 1)]));
+    }
+
+    #[test]
+    fn test_client_order_id_binding() {
+        let id = ClientOrderId::parse("c-1").unwrap();
+        let mut engine = MatchingEngine::auto_creating();
+        let mut updates = engine.orderbook_manager.order_updates.subscribe();
+        engine.apply(&WalCommand::BindClientOrderId { order_id: 0, trader: [1; 20], client_order_id: id });
+        engine.match_order(OrderId(0), BookId(0), Qty(10), 100, true, Some([1; 20]), None, None, None).unwrap();
+        assert_eq!(updates.try_recv().unwrap().client_order_id, Some(id));
+        assert_eq!(engine.bind_client_order_id(OrderId(1), [1; 20], id), Err(OrderBookError::DuplicateClientOrderId(id)));
+
+        // A replace moves the ID to the new order, and a snapshot keeps it
+        engine.replace_order(OrderId(0), OrderId(2), Qty(10), 101).unwrap();
+        assert_eq!(engine.find_client_order([1; 20], id), Some(OrderId(2)));
+        let mut restored = MatchingEngine::restore(engine.snapshot()).unwrap();
+        assert_eq!(restored.find_client_order([1; 20], id), Some(OrderId(2)));
+
+        // Filling the order frees the ID
+        restored.match_order(OrderId(3), BookId(0), Qty(10), 101, false, Some([2; 20]), None, None, None).unwrap();
+        println!("Bindings left: {}", restored.orderbook_manager.client_order_ids.len());
+        assert_eq!(restored.find_client_order([1; 20], id), None);
+        assert!(restored.bind_client_order_id(OrderId(4), [1; 20], id).is_ok());
     }
 
     #[test]
     fn test_multiple_matches() {
-        let mut engine = MatchingEngine::new();
+        let mut engine = MatchingEngine::auto_creating();
 
         // Add resting sell orders at increasing prices
         engine.orderbook_manager.add_order(
             OrderId(1), BookId(0), Qty(50), 100, false,
             Some([1; 20]), Some(1), Some(u64::MAX), Some([0; 65])
-        );
+        ).unwrap();
         engine.orderbook_manager.add_order(
             OrderId(2), BookId(0), Qty(40), 101, false,
             Some([1; 20]), Some(1), Some(u64::MAX), Some([0; 65])
-        );
+        ).unwrap();
 
         // Match with buy order that should fully execute against first two orders
         let (remaining, _) = engine.match_order(
             OrderId(4), BookId(0), Qty(90), 102, true,
             Some([2; 20]), Some(2), Some(u64::MAX), Some([0; 65])
-        );
+        ).unwrap();
 
         assert_eq!(remaining.value(), 0); // Should fully match 90 against 50+40
     }
 
     #[test]
+    fn test_price_improvement() {
+        let mut engine = MatchingEngine::auto_creating();
+        engine.orderbook_manager.add_order(OrderId(1), BookId(0), Qty(50), 98, true, None, None, None, None).unwrap();
+        engine.orderbook_manager.add_order(OrderId(2), BookId(0), Qty(10), 100, false, None, None, None, None).unwrap();
+        engine.orderbook_manager.add_order(OrderId(3), BookId(0), Qty(10), 101, false, None, None, None, None).unwrap();
+
+        let (_, fills) = engine.match_order(OrderId(4), BookId(0), Qty(20), 105, true, None, None, None, None).unwrap();
+        // Both fills are measured against the book the buy found, an ask of 100 over a bid of 98
+        let improvements: Vec<_> = fills.iter().map(|fill| fill.improvement.unwrap()).collect();
+        assert_eq!(improvements.iter().map(|improvement| improvement.ticks).collect::<Vec<_>>(), [5, 4]);
+        assert!(improvements.iter().all(|improvement| improvement.quoted_spread() == Some(2)));
+        assert_eq!(engine.stats.get(BookId(0)).unwrap().average_improvement(), Some(4.5));
+
+        // A market order takes the maker's price, so it has none to report
+        engine.orderbook_manager.add_order(OrderId(5), BookId(0), Qty(10), 102, false, None, None, None, None).unwrap();
+        let order = Order::new(Qty(5), LevelId(0), BookId(0), None, None, None, None);
+        let fill = engine.match_market_order(OrderId(6), order, true).unwrap();
+        assert_eq!(fill.matches[0].improvement, None);
+    }
+
+    #[test]
+    fn test_fill_limit_continues_in_passes() {
+        let mut engine = MatchingEngine::auto_creating();
+        engine.set_fill_limit(Some(100), FillLimitPolicy::Continue);
+        let rest_asks = |engine: &mut MatchingEngine, order_ids: std::ops::Range<u64>, price: u32| {
+            for order_id in order_ids {
+                engine.orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(1), price, false, None, None, None, None).unwrap();
+            }
+        };
+        rest_asks(&mut engine, 0..10_000, 100);
+
+        // The first pass makes 100 fills and leaves the rest waiting, off the book
+        let taker = OrderId(10_000);
+        let (remaining, fills) = engine.match_order(taker, BookId(0), Qty(10_000), 100, true, Some([2; 20]), None, None, None).unwrap();
+        assert_eq!((fills.len(), remaining), (100, Qty(9_900)));
+        assert!(engine.is_continuing(taker) && engine.can_resume());
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
+
+        // Each later pass makes 100 more, until the order has filled
+        let mut passes = 1;
+        while let Some((order_id, fills)) = engine.resume_matching() {
+            assert_eq!((order_id, fills.len()), (taker, 100));
+            assert_eq!(fills[99].taker.remaining_qty, Qty(9_900 - 100 * passes));
+            passes += 1;
+        }
+        println!("Filled in {} passes", passes);
+        assert_eq!(passes, 100);
+        assert!(!engine.is_continuing(taker) && !engine.can_resume());
+        assert_eq!((engine.orderbook_manager.get_best_bid(BookId(0)), engine.orderbook_manager.get_best_ask(BookId(0))), (None, None));
+
+        // A waiting order is carried by snapshots and can be cancelled
+        rest_asks(&mut engine, 20_000..20_050, 101);
+        engine.set_fill_limit(Some(10), FillLimitPolicy::Continue);
+        engine.match_order(OrderId(20_050), BookId(0), Qty(30), 101, true, Some([2; 20]), None, None, None).unwrap();
+        assert!(MatchingEngine::restore(engine.snapshot()).unwrap().is_continuing(OrderId(20_050)));
+        engine.cancel_resting(OrderId(20_050), OrderStatus::Cancelled).unwrap();
+        assert!(engine.resume_matching().is_none());
+
+        // A waiting order expires like a resting one, in a restored engine too
+        engine.match_order(OrderId(20_060), BookId(0), Qty(30), 101, true, Some([2; 20]), None, Some(50), None).unwrap();
+        assert!(engine.is_continuing(OrderId(20_060)));
+        assert_eq!(MatchingEngine::restore(engine.snapshot()).unwrap().poll_expirations(50), vec![OrderId(20_060)]);
+        assert_eq!(engine.poll_expirations(50), vec![OrderId(20_060)]);
+        assert!(!engine.can_resume());
+
+        // Under Cancel, what the fill limit leaves is cancelled, and none of it rests
+        engine.set_fill_limit(Some(10), FillLimitPolicy::Cancel);
+        let (remaining, fills) = engine.match_order(OrderId(20_051), BookId(0), Qty(30), 101, true, None, None, None, None).unwrap();
+        assert_eq!((fills.len(), remaining), (10, Qty(0)));
+        assert!(engine.cancelled_by_fill_limit(OrderId(20_051)));
+        assert!(!engine.can_resume());
+        assert_eq!(engine.orderbook_manager.get_best_bid(BookId(0)), None);
+    }
+
+    #[test]
+    fn test_match_into_reused_buffer() {
+        let mut engine = MatchingEngine::auto_creating();
+        for (order_id, price) in [(1, 100), (2, 101)] {
+            engine.orderbook_manager.add_order(
+                OrderId(order_id), BookId(0), Qty(10), price, false, Some([1; 20]), Some(order_id), Some(7), Some([0; 65]),
+            ).unwrap();
+        }
+        let taker = |qty| Order::new(Qty(qty), LevelId(0), BookId(0), Some([2; 20]), Some(9), Some(8), [0; 65]);
+
+        // Each fill names both orders, with the maker's fields read before the fill removed it
+        let mut fills = Vec::new();
+        let remaining = engine.match_limit_order_into(OrderId(3), taker(15), 101, true, &mut fills).unwrap();
+        println!("Fills: {:?}", fills);
+        assert_eq!(remaining, Qty(0));
+        let makers: Vec<(OrderId, Option<u64>, Option<u64>)> =
+            fills.iter().map(|fill| (fill.maker.order_id, fill.maker.nonce, fill.maker.expiry)).collect();
+        assert_eq!(makers, vec![(OrderId(1), Some(1), Some(7)), (OrderId(2), Some(2), Some(7))]);
+        let taker_side = FillSide { order_id: OrderId(3), trader: Some([2; 20]), nonce: Some(9), expiry: Some(8) };
+        assert_eq!(fills[1].taker, TakerInfo::new(taker_side, Qty(15), Qty(0)));
+
+        // The buffer is emptied before the next order's fills go in
+        let capacity = fills.capacity();
+        engine.match_limit_order_into(OrderId(4), taker(5), 101, true, &mut fills).unwrap();
+        assert_eq!((fills.len(), fills[0].exec_qty, fills.capacity()), (1, Qty(5), capacity));
+        engine.match_limit_order_into(OrderId(5), taker(5), 99, true, &mut fills).unwrap();
+        assert!(fills.is_empty());
+    }
+
+    const WIRE_FORMAT: &str = include_str!("../fixtures/wire_format.json");
+
+    /// One of each core type as written to snapshots, the WAL, and clients, pinned by wire_format.json
+    fn wire_samples() -> serde_json::Value {
+        let price = Price::from_u32(1500, false).unwrap();
+        let order = Order::new_submission(Qty(25), price, BookId(2), [0xab; 20], 7, 1_700_000_000, Signature::Full65([0x11; 65]))
+            .with_display(Qty(5))
+            .with_received_at(1_700_000_000_000_000_000);
+        let maker = FillSide { order_id: OrderId(41), trader: Some([0xcd; 20]), nonce: Some(3), expiry: Some(1_700_000_000) };
+        let taker = TakerInfo {
+            order_id: OrderId(42),
+            trader: None,
+            nonce: None,
+            expiry: None,
+            qty: Qty(25),
+            remaining_qty: Qty(15),
+        };
+        let match_details = MatchDetails {
+            maker,
+            taker,
+            exec_qty: Qty(10),
+            exec_price: 1500,
+            maker_is_buyer: true,
+            trade_id: 9,
+            executed_at: 1_700_000_000_250_000_000,
+            settlement_id: None,
+            settlement_error: Some(TranslationError::MissingTakerAddress),
+            improvement: Some(PriceImprovement::new(1500, 1510, false, 5, (Some(1510), Some(1520)))),
+        };
+        let signature = |v, byte| SettlementSignature { signature_type: 2, v, r: [byte; 32], s: [byte + 1; 32] };
+        let settlement_order = SettlementOrder {
+            maker_token: [0x11; 20],
+            taker_token: [0x22; 20],
+            maker_amount: 1_000_000_000_000_000_000,
+            taker_amount: 15_000,
+            fee_amount: 0,
+            maker_fee: 0,
+            taker_fee: 3,
+            maker: [0x33; 20],
+            taker: [0x44; 20],
+            fee_recipient: [0x55; 20],
+            pool: [0x66; 20],
+            maker_expiration: 1_700_000_000,
+            maker_salt: 42,
+            taker_expiration: 1_700_000_001,
+            taker_salt: 43,
+            maker_is_buyer: true,
+            maker_signature: signature(27, 0xaa),
+            taker_signature: signature(28, 0xcc),
+            chain_id: 1,
+            domain_separator: [0x77; 32],
+        };
+        let market_config = MarketConfig::builder()
+            .base_token([0x11; 20])
+            .security_token([0x22; 20])
+            .fee_recipient([0x55; 20])
+            .pool([0x66; 20])
+            .signature_type(2)
+            .maker_fee_bps(5)
+            .taker_fee_bps(10)
+            .maker_rebate_bps(2)
+            .base_decimals(6)
+            .security_decimals(18)
+            .price_decimals(2)
+            .tick_size(5)
+            .name("Numena")
+            .version("1")
+            .chain_id(1)
+            .verifying_contract([0x88; 20])
+            .price_band(PriceBand::Bps(500))
+            .track_positions(true)
+            .risk_limits(RiskLimits { max_open_orders: 100, max_open_notional: 1_000_000 })
+            .size_rules(SizeRules { min_qty: 5, lot_size: 5, min_notional: 1000 })
+            .skip_funds_check(false)
+            .require_nonce(true)
+            .build();
+        serde_json::json!({
+            "order_id": OrderId(42),
+            "qty": Qty(25),
+            "book_id": BookId(2),
+            "price": price,
+            "order": order,
+            "match_details": match_details,
+            "settlement_order": settlement_order,
+            "market_config": market_config,
+        })
+    }
+
+    #[test]
+    fn test_wire_format_golden() {
+        let samples = wire_samples();
+        println!("{}", serde_json::to_string_pretty(&samples).unwrap());
+        let golden: serde_json::Value = serde_json::from_str(WIRE_FORMAT).unwrap();
+        for (name, sample) in samples.as_object().unwrap() {
+            assert_eq!(sample, &golden[name], "{} no longer serializes as wire_format.json has it", name);
+        }
+
+        // Everything reads back from the golden file to what it was written from
+        let read = |name: &str| golden[name].clone();
+        let round_trip = serde_json::json!({
+            "order_id": serde_json::from_value::<OrderId>(read("order_id")).unwrap(),
+            "qty": serde_json::from_value::<Qty>(read("qty")).unwrap(),
+            "book_id": serde_json::from_value::<BookId>(read("book_id")).unwrap(),
+            "price": serde_json::from_value::<Price>(read("price")).unwrap(),
+            "order": serde_json::from_value::<Order>(read("order")).unwrap(),
+            "match_details": serde_json::from_value::<MatchDetails>(read("match_details")).unwrap(),
+            "settlement_order": serde_json::from_value::<SettlementOrder>(read("settlement_order")).unwrap(),
+            "market_config": serde_json::from_value::<MarketConfig>(read("market_config")).unwrap(),
+        });
+        assert_eq!(round_trip, samples);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "paranoid-checks", ignore = "rests a crossed book straight through the manager")]
     fn test_matching_performance() {
-        let mut engine = MatchingEngine::new();
+        let mut engine = MatchingEngine::auto_creating();
         let num_orders = 100;
         let mut rng = rand::thread_rng();
         let mut latencies = Vec::with_capacity(num_orders);
 
-        // Setup initial orderbook with some resting orders
+        // Setup initial orderbook with some resting orders, bids below asks as matching would leave them
         for i in 0..1000 {
+            let is_bid = rng.gen_bool(0.5); // Random buy/sell
             engine.orderbook_manager.add_order(
-                OrderId(i as u32),
+                OrderId(i as u64),
                 BookId(0),
                 Qty(rng.gen_range(1..=100)),
-                rng.gen_range(90..110),
-                rng.gen_bool(0.5),  // Random buy/sell
+                if is_bid { rng.gen_range(90..100) } else { rng.gen_range(100..110) },
+                is_bid,
                 Some([1; 20]),
                 Some(i as u64),
                 Some(u64::MAX),
                 Some([0; 65]),
-            );
+            ).unwrap();
         }
 
         println!("\nMATCHING ENGINE PERFORMANCE TEST");
@@ -333,7 +4314,7 @@ mod tests {
         for i in 1000..(1000 + num_orders) {
             let order_start = Instant::now();
             let (remaining, _) = engine.match_order(
-                OrderId(i as u32),
+                OrderId(i as u64),
                 BookId(0),
                 Qty(rng.gen_range(1..=100)),
                 rng.gen_range(90..110),
@@ -342,7 +4323,7 @@ mod tests {
                 Some(i as u64),
                 Some(u64::MAX),
                 Some([0; 65]),
-            );
+            ).unwrap();
             latencies.push(order_start.elapsed());
 
             if remaining.value() == 0 {
@@ -371,4 +4352,257 @@ mod tests {
         assert!(throughput > 0.0);
         assert!(total_matches > 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_settlement_lifecycle() {
+        use crate::{market::MarketConfig, settlement_manager::SettlementStatus};
+
+        let mut engine = MatchingEngine::auto_creating();
+        engine.clock = Clock::Fixed(1_000);
+        engine.market_manager.add_market(
+            BookId(0),
+            MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build(),
+            false,
+        ).unwrap();
+        let maker = [5; 20];
+        for book in 0..2 {
+            engine.orderbook_manager.add_order(
+                OrderId(book as u64), BookId(book), Qty(50), 100, false, Some(maker), Some(1), Some(u64::MAX), Some([1; 65]),
+            ).unwrap();
+        }
+        let buy = |engine: &mut MatchingEngine, order_id: u64, book: u32, qty: u64| {
+            let (_, matches) = engine.match_order(
+                OrderId(order_id), BookId(book), Qty(qty), 100, true, Some([7; 20]), Some(order_id), Some(u64::MAX), Some([2; 65]),
+            ).unwrap();
+            matches
+        };
+
+        // Only books with a market configuration settle
+        assert_eq!(buy(&mut engine, 2, 1, 10)[0].settlement_id, None);
+        let matches = buy(&mut engine, 3, 0, 30);
+        assert_eq!(matches[0].settlement_id, Some(1));
+        let settlement = engine.settlements.get(1).unwrap();
+        println!("Settlement: {:?}", settlement);
+        assert_eq!(settlement.status, SettlementStatus::Pending);
+        assert_eq!((settlement.maker_order_id, settlement.taker_order_id, settlement.exec_qty), (0, 3, 30));
+        assert_eq!(settlement.order.maker, maker);
+
+        engine.clock = Clock::Fixed(2_000);
+        assert_eq!(engine.mark_settlement_confirmed(1), Err(SettlementError::InvalidTransition { from: "pending", to: "confirmed" }));
+        engine.mark_settlement_submitted(1, [0xaa; 32]).unwrap();
+        engine.mark_settlement_confirmed(1).unwrap();
+        assert_eq!(engine.settlements.get(1).unwrap().status, SettlementStatus::Confirmed { tx_hash: [0xaa; 32] });
+        assert_eq!(engine.settlements.get(1).unwrap().updated_at, 2_000);
+        assert!(engine.mark_settlement_failed(1, "late".to_string(), None).is_err());
+        assert_eq!(engine.mark_settlement_submitted(9, [0; 32]), Err(SettlementError::UnknownSettlement));
+
+        // A failed fill can put the maker's quantity back on the book, behind the maker's remainder
+        buy(&mut engine, 4, 0, 10);
+        engine.mark_settlement_failed(2, "transfer failed".to_string(), Some(OrderId(5))).unwrap();
+        assert_eq!(engine.settlements.get(2).unwrap().status, SettlementStatus::Failed { reason: "transfer failed".to_string() });
+        let recredited = engine.orderbook_manager.oid_map.get_order(OrderId(5)).unwrap();
+        assert_eq!((recredited.qty(), recredited.trader(), recredited.signature()), (Qty(10), Some(maker), Signature::Full65([1; 65])));
+        assert_eq!(recredited.nonce(), Some(1));
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(20)));
+    }
+
+    #[test]
+    fn test_block_trade_settles_and_replays() {
+        use crate::rfq::{BlockOrder, BlockTrade};
+
+        let setup = || {
+            let mut engine = MatchingEngine::auto_creating();
+            engine.clock = Clock::Fixed(1_000);
+            engine.market_manager.add_market(
+                BookId(0),
+                MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build(),
+                false,
+            ).unwrap();
+            engine.orderbook_manager.create_book(BookId(0)).unwrap();
+            engine
+        };
+        let side = |order_id, trader, is_bid, byte| BlockOrder {
+            order_id,
+            trader,
+            is_bid,
+            nonce: order_id,
+            expiry: u64::MAX,
+            signature: Signature::Full65([byte; 65]),
+        };
+        let block = BlockTrade { book_id: 0, qty: 500, price: 100, maker: side(0, [5; 20], false, 1), taker: side(1, [7; 20], true, 2) };
+
+        // The block settles like a fill but leaves the book empty
+        let mut engine = setup();
+        let fill = engine.execute_block(&block).unwrap();
+        assert_eq!((fill.exec_qty, fill.exec_price, fill.maker_is_buyer, fill.settlement_id), (Qty(500), 100, false, Some(1)));
+        let settlement = engine.settlements.get(1).unwrap().clone();
+        assert_eq!((settlement.maker_order_id, settlement.taker_order_id, settlement.exec_qty), (0, 1, 500));
+        assert_eq!((settlement.order.maker, settlement.order.taker), ([5; 20], [7; 20]));
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), None);
+        assert_eq!(engine.execute_block(&BlockTrade { book_id: 9, ..block.clone() }).err(), Some(OrderBookError::UnknownBook(BookId(9))));
+
+        // Replay gives the same settlement and burns both nonces
+        let mut replayed = setup();
+        replayed.apply(&WalCommand::BlockTrade(block));
+        assert_eq!(replayed.settlements.get(1).unwrap().order, settlement.order);
+        assert!(replayed.nonces.check([5; 20], 0).is_err());
+        assert!(replayed.nonces.check([7; 20], 1).is_err());
+    }
+
+    #[test]
+    fn test_event_sequence() {
+        use crate::events::VecSink;
+        use crate::order_updates::OrderStatus;
+
+        let mut engine = MatchingEngine::auto_creating();
+        let sink = VecSink::new();
+        engine.orderbook_manager.set_event_sink(Box::new(sink.clone()));
+
+        engine.orderbook_manager.add_order(
+            OrderId(1), BookId(0), Qty(50), 100, false,
+            Some([1; 20]), Some(1), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+        engine.orderbook_manager.add_order(
+            OrderId(2), BookId(0), Qty(40), 101, false,
+            Some([1; 20]), Some(2), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+        engine.match_order(
+            OrderId(3), BookId(0), Qty(60), 101, true,
+            Some([2; 20]), Some(1), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+        engine.replace_order(OrderId(2), OrderId(4), Qty(20), 105).unwrap();
+        engine.orderbook_manager.cancel_resting(OrderId(4), OrderStatus::Expired).unwrap();
+
+        // Trade timestamps come from the wall clock
+        let events: Vec<OrderBookEvent> = sink
+            .take()
+            .into_iter()
+            .map(|event| match event {
+                OrderBookEvent::Trade { seq, book_seq, book_id, trade, maker_trader, taker_trader, improvement } => {
+                    OrderBookEvent::Trade {
+                        seq,
+                        book_seq,
+                        book_id,
+                        trade: Trade { timestamp: 0, ..trade },
+                        maker_trader,
+                        taker_trader,
+                        improvement,
+                    }
+                }
+                event => event,
+            })
+            .collect();
+        for event in &events {
+            println!("{:?}", event);
+        }
+
+        let added = |seq, book_seq, order_id, price, qty, nonce| OrderBookEvent::OrderAdded {
+            seq,
+            book_seq,
+            order_id: OrderId(order_id),
+            book_id: BookId(0),
+            price,
+            is_bid: false,
+            qty: Qty(qty),
+            trader: Some([1; 20]),
+            nonce: Some(nonce),
+            expiry: Some(u64::MAX),
+            signature: Signature::Full65([0; 65]),
+            display: None,
+        };
+        // Both fills are against the ask of 100 the taker found
+        let trade = |seq, book_seq, trade_id, maker, qty, ticks: u32| OrderBookEvent::Trade {
+            seq,
+            book_seq,
+            book_id: BookId(0),
+            trade: Trade {
+                trade_id,
+                timestamp: 0,
+                price: 101,
+                qty: Qty(qty),
+                aggressor_is_bid: true,
+                maker_order_id: OrderId(maker),
+                taker_order_id: OrderId(3),
+            },
+            maker_trader: Some([1; 20]),
+            taker_trader: Some([2; 20]),
+            improvement: Some(PriceImprovement::new(101, 101 - ticks, true, 1, (None, Some(100)))),
+        };
+        let expected = vec![
+            added(1, 1, 1, 100, 50, 1),
+            added(2, 2, 2, 101, 40, 2),
+            OrderBookEvent::OrderExecuted {
+                seq: 3,
+                book_seq: 3,
+                order_id: OrderId(1),
+                book_id: BookId(0),
+                exec_qty: Qty(50),
+                remaining_qty: Qty(0),
+            },
+            trade(4, 3, 1, 1, 50, 1),
+            OrderBookEvent::OrderExecuted {
+                seq: 5,
+                book_seq: 4,
+                order_id: OrderId(2),
+                book_id: BookId(0),
+                exec_qty: Qty(10),
+                remaining_qty: Qty(30),
+            },
+            trade(6, 4, 2, 2, 10, 0),
+            OrderBookEvent::OrderReplaced {
+                seq: 7,
+                book_seq: 5,
+                order_id: OrderId(2),
+                new_order_id: OrderId(4),
+                book_id: BookId(0),
+                new_price: 105,
+                new_qty: Qty(20),
+            },
+            added(8, 6, 4, 105, 20, 2),
+            OrderBookEvent::OrderExpired {
+                seq: 9,
+                book_seq: 7,
+                order_id: OrderId(4),
+                book_id: BookId(0),
+                qty: Qty(20),
+            },
+        ];
+        assert_eq!(events, expected);
+        assert_eq!(engine.orderbook_manager.event_seq(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_channel_sink() {
+        use crate::events::{CancelReason, ChannelSink};
+
+        let mut engine = MatchingEngine::auto_creating();
+        let (sink, mut events) = ChannelSink::new();
+        engine.orderbook_manager.set_event_sink(Box::new(sink));
+
+        engine.orderbook_manager.add_order(
+            OrderId(1), BookId(0), Qty(50), 100, true,
+            None, None, None, None
+        ).unwrap();
+        engine.orderbook_manager.remove_order(OrderId(1)).unwrap();
+        // Already gone, emits nothing
+        assert_eq!(engine.orderbook_manager.remove_order(OrderId(1)), Err(OrderBookError::UnknownOrder));
+
+        let added = events.recv().await.unwrap();
+        let cancelled = events.recv().await.unwrap();
+        println!("{:?}\n{:?}", added, cancelled);
+        assert!(matches!(added, OrderBookEvent::OrderAdded { seq: 1, .. }));
+        assert_eq!(
+            cancelled,
+            OrderBookEvent::OrderCancelled {
+                seq: 2,
+                book_seq: 2,
+                order_id: OrderId(1),
+                book_id: BookId(0),
+                cancelled_qty: Qty(50),
+                remaining_qty: Qty(0),
+                reason: CancelReason::Requested,
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,281 @@
+// metrics.rs
+
+use crate::{
+    matching::MatchingEngine,
+    settlement_manager::SettlementStatus,
+    utils::{BookId, MAX_BOOKS},
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in nanoseconds; the last bucket is +Inf.
+const LATENCY_BUCKETS: [u64; 12] = [
+    1_000, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 5_000_000, 25_000_000, 100_000_000,
+];
+
+/// A monotonic counter
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A latency histogram over LATENCY_BUCKETS
+/// Observing is a few relaxed atomic adds; a scrape may see a count and sum that are one
+/// observation apart, which Prometheus tolerates.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1], // Not cumulative; the last one is +Inf
+    sum: AtomicU64,                                  // Nanoseconds
+    count: AtomicU64,
+}
+
+impl Histogram {
+    #[inline]
+    pub fn observe(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS.partition_point(|&bound| bound < nanos);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the number of observations
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Writes the histogram in the text exposition format, its bounds in seconds
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match LATENCY_BUCKETS.get(i) {
+                Some(&bound) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound as f64 / 1e9, cumulative);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed) as f64 / 1e9);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+    }
+}
+
+/// Trades of one book since the engine started
+#[derive(Debug, Default)]
+struct BookCounters {
+    matches: Counter,
+    volume: Counter,
+}
+
+/// Counters and latencies of the engine and its API, published at /metrics
+/// Everything recorded on the matching path is a relaxed atomic add, so recording never locks,
+/// allocates, or formats. Rejections are counted under a lock, as they are off that path.
+///
+/// Metric names and labels are stable; dashboards and alerts rely on them:
+/// - `numena_orders_received_total`: order submissions, an OCO pair counting as one
+/// - `numena_orders_accepted_total`: submissions that went into the book
+/// - `numena_orders_rejected_total{code}`: refused submissions, by the API error code
+/// - `numena_matches_total{book}`, `numena_traded_volume_total{book}`: fills, and their quantity
+/// - `numena_best_bid{book}`, `numena_best_ask{book}`: best prices; absent while a side is empty
+/// - `numena_book_depth{book,side}`: quantity resting on a side of a book
+/// - `numena_engine_lock_wait_seconds`: how long API requests wait for the engine lock
+/// - `numena_match_latency_seconds`: time to match and rest an incoming limit or market order
+/// - `numena_wal_sync_seconds`: time of each WAL fsync
+/// - `numena_settlement_queue_depth{status}`: settlements pending submission, or submitted and
+///   awaiting confirmation
+///
+/// Counters start at zero when the process starts; a replay of the WAL counts the trades it
+/// repeats.
+#[derive(Debug)]
+pub struct Metrics {
+    pub orders_received: Counter,
+    pub orders_accepted: Counter,
+    pub audit_dropped: Counter, // Audit entries dropped because the audit writer fell behind
+    pub bus_dropped: Counter, // Bus messages dropped because the bus fell behind
+    orders_rejected: Mutex<BTreeMap<u16, u64>>,
+    books: Box<[BookCounters]>, // Indexed by book ID
+    pub lock_wait: Histogram,
+    pub match_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            orders_received: Counter::default(),
+            orders_accepted: Counter::default(),
+            audit_dropped: Counter::default(),
+            bus_dropped: Counter::default(),
+            orders_rejected: Mutex::new(BTreeMap::new()),
+            books: (0..MAX_BOOKS).map(|_| BookCounters::default()).collect(),
+            lock_wait: Histogram::default(),
+            match_latency: Histogram::default(),
+        }
+    }
+
+    /// Counts an order submission, accepted if `rejected` is None
+    pub fn record_submission(&self, rejected: Option<u16>) {
+        self.orders_received.inc();
+        match rejected {
+            None => self.orders_accepted.inc(),
+            Some(code) => {
+                let mut rejections = self.orders_rejected.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                *rejections.entry(code).or_default() += 1;
+            }
+        }
+    }
+
+    /// Counts a trade of `qty` in a book
+    #[inline]
+    pub fn record_trade(&self, book_id: BookId, qty: u64) {
+        if let Some(book) = self.books.get(book_id.value() as usize) {
+            book.matches.inc();
+            book.volume.add(qty);
+        }
+    }
+
+    /// Gets the number of trades and the volume traded in a book
+    pub fn book_trades(&self, book_id: BookId) -> (u64, u64) {
+        self.books
+            .get(book_id.value() as usize)
+            .map_or((0, 0), |book| (book.matches.get(), book.volume.get()))
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    /// `books` names the books to publish, as the registry lists them; gauges are read from
+    /// `engine`, so the caller holds its lock for the call.
+    pub fn render(&self, engine: &MatchingEngine, books: &[(String, BookId)]) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        };
+        counter(&mut out, "numena_orders_received_total", "Order submissions received.", self.orders_received.get());
+        counter(&mut out, "numena_orders_accepted_total", "Order submissions accepted.", self.orders_accepted.get());
+        out.push_str("# HELP numena_orders_rejected_total Order submissions rejected, by API error code.\n");
+        out.push_str("# TYPE numena_orders_rejected_total counter\n");
+        for (code, count) in self.orders_rejected.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+            let _ = writeln!(out, "numena_orders_rejected_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        counter(&mut out, "numena_audit_dropped_total", "Audit entries dropped because the writer fell behind.", self.audit_dropped.get());
+        counter(&mut out, "numena_bus_dropped_total", "Bus messages dropped because the bus fell behind.", self.bus_dropped.get());
+
+        let books: Vec<(String, BookId)> = books.iter().map(|(name, book_id)| (escape(name), *book_id)).collect();
+        let per_book = |out: &mut String, name: &str, kind: &str, help: &str, value: &dyn Fn(BookId) -> Option<u64>| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (book, book_id) in &books {
+                if let Some(value) = value(*book_id) {
+                    let _ = writeln!(out, "{}{{book=\"{}\"}} {}", name, book, value);
+                }
+            }
+        };
+        per_book(&mut out, "numena_matches_total", "counter", "Fills per book.", &|book_id| Some(self.book_trades(book_id).0));
+        per_book(&mut out, "numena_traded_volume_total", "counter", "Quantity traded per book.", &|book_id| {
+            Some(self.book_trades(book_id).1)
+        });
+        let manager = &engine.orderbook_manager;
+        per_book(&mut out, "numena_best_bid", "gauge", "Best bid price per book.", &|book_id| {
+            manager.get_best_bid(book_id).map(|price| price.absolute() as u64)
+        });
+        per_book(&mut out, "numena_best_ask", "gauge", "Best ask price per book.", &|book_id| {
+            manager.get_best_ask(book_id).map(|price| price.absolute() as u64)
+        });
+        out.push_str("# HELP numena_book_depth Quantity resting per book and side.\n# TYPE numena_book_depth gauge\n");
+        for (book, book_id) in &books {
+            let depth = manager.get_depth(*book_id, usize::MAX).unwrap_or_default();
+            for (side, levels) in [("bid", &depth.bids), ("ask", &depth.asks)] {
+                let quantity: u64 = levels.iter().map(|&(_, size, _)| size).sum();
+                let _ = writeln!(out, "numena_book_depth{{book=\"{}\",side=\"{}\"}} {}", book, side, quantity);
+            }
+        }
+
+        self.lock_wait.render(&mut out, "numena_engine_lock_wait_seconds", "Time API requests wait for the engine lock.");
+        self.match_latency.render(&mut out, "numena_match_latency_seconds", "Time to match and rest an incoming order.");
+        let no_wal = Histogram::default();
+        let wal_sync = engine.wal.as_ref().map_or(&no_wal, |wal| wal.sync_latency());
+        wal_sync.render(&mut out, "numena_wal_sync_seconds", "Time of each WAL fsync.");
+
+        let (mut pending, mut submitted) = (0, 0);
+        for settlement in engine.settlements.settlements() {
+            match settlement.status {
+                SettlementStatus::Pending => pending += 1,
+                SettlementStatus::Submitted { .. } => submitted += 1,
+                _ => {}
+            }
+        }
+        out.push_str("# HELP numena_settlement_queue_depth Settlements not yet confirmed or failed, by status.\n");
+        out.push_str("# TYPE numena_settlement_queue_depth gauge\n");
+        let _ = writeln!(out, "numena_settlement_queue_depth{{status=\"pending\"}} {}", pending);
+        let _ = writeln!(out, "numena_settlement_queue_depth{{status=\"submitted\"}} {}", submitted);
+        out
+    }
+}
+
+/// Escapes a label value for the text exposition format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{order::OrderId, quantity::Qty};
+
+    #[test]
+    fn test_render_metrics() {
+        let mut engine = MatchingEngine::auto_creating();
+        engine.match_order(OrderId(0), BookId(0), Qty(10), 101, false, None, None, None, None).unwrap();
+        engine.match_order(OrderId(1), BookId(0), Qty(4), 101, true, None, None, None, None).unwrap();
+        engine.match_order(OrderId(2), BookId(0), Qty(3), 99, true, None, None, None, None).unwrap();
+        engine.metrics.record_submission(None);
+        engine.metrics.record_submission(Some(1002));
+        engine.metrics.lock_wait.observe(Duration::from_micros(30));
+
+        let text = engine.metrics.render(&engine, &[("ETH-\"USD\"".to_string(), BookId(0))]);
+        println!("{}", text);
+        for line in [
+            "numena_orders_received_total 2",
+            "numena_orders_accepted_total 1",
+            "numena_orders_rejected_total{code=\"1002\"} 1",
+            "numena_matches_total{book=\"ETH-\\\"USD\\\"\"} 1",
+            "numena_traded_volume_total{book=\"ETH-\\\"USD\\\"\"} 4",
+            "numena_best_bid{book=\"ETH-\\\"USD\\\"\"} 99",
+            "numena_book_depth{book=\"ETH-\\\"USD\\\"\",side=\"ask\"} 6",
+            "numena_engine_lock_wait_seconds_bucket{le=\"0.00001\"} 0",
+            "numena_engine_lock_wait_seconds_bucket{le=\"0.00005\"} 1",
+            "numena_engine_lock_wait_seconds_count 1",
+            "numena_match_latency_seconds_count 3",
+            "numena_settlement_queue_depth{status=\"pending\"} 0",
+        ] {
+            assert!(text.lines().any(|rendered| rendered == line), "missing {}", line);
+        }
+    }
+}
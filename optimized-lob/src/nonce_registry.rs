@@ -0,0 +1,140 @@
+// nonce_registry.rs
+
+use crate::{order_intake::OrderIntakeError, utils::hex_array};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Nonces are tracked in bitmap words of 256, as in 0x's order invalidation.
+const NONCES_PER_WORD: u64 = 256;
+
+/// The nonces a trader has used, and the minimum below which every nonce is invalid.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraderNonces {
+    #[serde(with = "hex_array")]
+    pub trader: [u8; 20],
+    pub min_nonce: u64,
+    pub words: BTreeMap<u64, [u64; 4]>, // Used-nonce bitmaps, keyed by nonce / 256.
+}
+
+impl TraderNonces {
+    fn new(trader: [u8; 20]) -> Self {
+        Self {
+            trader,
+            min_nonce: 0,
+            words: BTreeMap::new(),
+        }
+    }
+
+    fn is_used(&self, nonce: u64) -> bool {
+        let (word, limb, bit) = position(nonce);
+        nonce < self.min_nonce || self.words.get(&word).is_some_and(|bits| bits[limb] & bit != 0)
+    }
+}
+
+/// Splits a nonce into its bitmap word, the u64 limb within it, and the bit within that.
+fn position(nonce: u64) -> (u64, usize, u64) {
+    let offset = nonce % NONCES_PER_WORD;
+    (nonce / NONCES_PER_WORD, (offset / 64) as usize, 1 << (offset % 64))
+}
+
+/// Records which order nonces each trader has used, so a signed order is accepted at most once.
+#[derive(Debug, Default)]
+pub struct NonceRegistry {
+    traders: HashMap<[u8; 20], TraderNonces>,
+}
+
+impl NonceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks that `nonce` is still usable by `trader`, without using it.
+    pub fn check(&self, trader: [u8; 20], nonce: u64) -> Result<(), OrderIntakeError> {
+        match self.traders.get(&trader) {
+            Some(nonces) if nonces.is_used(nonce) => Err(OrderIntakeError::InvalidNonce),
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks `nonce` as used by `trader`.
+    /// Fails with InvalidNonce if it was already used or is below the trader's minimum.
+    ///
+    /// ## Arguments:
+    /// - `trader`: The address that signed the order.
+    /// - `nonce`: The nonce of the order.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::nonce_registry::NonceRegistry;
+    /// let mut nonces = NonceRegistry::new();
+    /// assert!(nonces.consume([1; 20], 7).is_ok());
+    /// assert!(nonces.consume([1; 20], 7).is_err());
+    /// assert!(nonces.consume([2; 20], 7).is_ok());
+    /// ```
+    pub fn consume(&mut self, trader: [u8; 20], nonce: u64) -> Result<(), OrderIntakeError> {
+        self.check(trader, nonce)?;
+        let (word, limb, bit) = position(nonce);
+        let nonces = self.traders.entry(trader).or_insert_with(|| TraderNonces::new(trader));
+        nonces.words.entry(word).or_default()[limb] |= bit;
+        Ok(())
+    }
+
+    /// Gets the lowest nonce `trader` can still use.
+    pub fn min_nonce(&self, trader: [u8; 20]) -> u64 {
+        self.traders.get(&trader).map_or(0, |nonces| nonces.min_nonce)
+    }
+
+    /// Invalidates every nonce of `trader` below `min_nonce` and returns the trader's new minimum.
+    /// The minimum only ever rises; bumping to a lower value changes nothing.
+    pub fn bump(&mut self, trader: [u8; 20], min_nonce: u64) -> u64 {
+        let nonces = self.traders.entry(trader).or_insert_with(|| TraderNonces::new(trader));
+        nonces.min_nonce = nonces.min_nonce.max(min_nonce);
+        // Words wholly below the minimum carry no information any more
+        let first_word = nonces.min_nonce / NONCES_PER_WORD;
+        nonces.words = nonces.words.split_off(&first_word);
+        nonces.min_nonce
+    }
+
+    /// Gets the state of every trader, sorted by address, for snapshots.
+    pub fn entries(&self) -> Vec<TraderNonces> {
+        let mut entries: Vec<TraderNonces> = self.traders.values().cloned().collect();
+        entries.sort_unstable_by_key(|nonces| nonces.trader);
+        entries
+    }
+
+    /// Rebuilds a registry from snapshot entries.
+    pub fn from_entries(entries: Vec<TraderNonces>) -> Self {
+        Self {
+            traders: entries.into_iter().map(|nonces| (nonces.trader, nonces)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consume_and_bump() {
+        let trader = [1; 20];
+        let mut nonces = NonceRegistry::new();
+        for nonce in [0, 5, 255, 256, 1_000] {
+            assert!(nonces.consume(trader, nonce).is_ok());
+        }
+        assert!(matches!(nonces.consume(trader, 256), Err(OrderIntakeError::InvalidNonce)));
+        assert!(nonces.check(trader, 257).is_ok());
+
+        assert_eq!(nonces.bump(trader, 300), 300);
+        assert_eq!(nonces.bump(trader, 10), 300);
+        assert!(nonces.check(trader, 299).is_err());
+        assert!(nonces.check(trader, 300).is_ok());
+        assert!(nonces.check(trader, 1_000).is_err());
+        println!("Entries: {:?}", nonces.entries());
+        // Only the word of 0..256 is dropped; 256 and 1000 sit in words kept by the bump
+        assert_eq!(nonces.entries()[0].words.keys().copied().collect::<Vec<_>>(), vec![1, 3]);
+
+        let restored = NonceRegistry::from_entries(nonces.entries());
+        assert_eq!(restored.entries(), nonces.entries());
+        assert_eq!(restored.min_nonce(trader), 300);
+    }
+}
@@ -0,0 +1,203 @@
+// oco.rs
+
+use crate::{
+    order::{OrderId, Signature},
+    orderbook_manager::OrderBookError,
+    stops::StopOrder,
+    utils::hex_bytes,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What a fill of one leg of an OCO pair does to the other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OcoPolicy {
+    #[default]
+    Cancel, // The first fill of either leg cancels the other
+    Reduce, // The other leg shrinks in proportion to what the filled leg has left
+}
+
+/// One leg of an OCO pair as submitted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OcoLeg {
+    Limit {
+        order_id: u64,
+        book_id: u32,
+        qty: u64,
+        price: u32,
+        is_bid: bool,
+        #[serde(with = "hex_bytes")]
+        trader: Option<[u8; 20]>,
+        nonce: Option<u64>,
+        expiry: Option<u64>,
+        signature: Signature,
+    },
+    Stop(StopOrder),
+}
+
+impl OcoLeg {
+    pub fn order_id(&self) -> OrderId {
+        match self {
+            OcoLeg::Limit { order_id, .. } => OrderId(*order_id),
+            OcoLeg::Stop(stop) => OrderId(stop.order_id),
+        }
+    }
+
+    pub fn book_id(&self) -> u32 {
+        match self {
+            OcoLeg::Limit { book_id, .. } => *book_id,
+            OcoLeg::Stop(stop) => stop.book_id,
+        }
+    }
+
+    pub fn qty(&self) -> u64 {
+        match self {
+            OcoLeg::Limit { qty, .. } => *qty,
+            OcoLeg::Stop(stop) => stop.qty,
+        }
+    }
+
+    pub fn trader(&self) -> Option<[u8; 20]> {
+        match self {
+            OcoLeg::Limit { trader, .. } => *trader,
+            OcoLeg::Stop(stop) => stop.trader,
+        }
+    }
+
+    pub fn nonce(&self) -> Option<u64> {
+        match self {
+            OcoLeg::Limit { nonce, .. } => *nonce,
+            OcoLeg::Stop(stop) => stop.nonce,
+        }
+    }
+}
+
+/// Two orders linked so that one filling cancels, or shrinks, the other
+/// A group is known by the order ID of its first leg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OcoGroup {
+    pub group_id: u64,
+    pub legs: [(u64, u64); 2], // Order ID and submitted quantity of each leg
+    pub policy: OcoPolicy,
+    pub cancel_together: bool, // Cancelling either leg by hand cancels the other too
+}
+
+impl OcoGroup {
+    /// Gets the other leg of the pair
+    #[inline]
+    pub fn sibling(&self, order_id: OrderId) -> OrderId {
+        if self.legs[0].0 == order_id.0 {
+            OrderId(self.legs[1].0)
+        } else {
+            OrderId(self.legs[0].0)
+        }
+    }
+
+    /// Gets what the sibling of `order_id` may have left once `order_id` has `remaining` left,
+    /// in proportion to their submitted quantities and rounded down; always zero under Cancel.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{oco::{OcoGroup, OcoPolicy}, order::OrderId};
+    /// let group = OcoGroup { group_id: 0, legs: [(0, 10), (1, 4)], policy: OcoPolicy::Reduce, cancel_together: false };
+    /// assert_eq!(group.sibling_target(OrderId(0), 6), 2);
+    /// assert_eq!(group.sibling_target(OrderId(1), 1), 2);
+    /// ```
+    pub fn sibling_target(&self, order_id: OrderId, remaining: u64) -> u64 {
+        if self.policy == OcoPolicy::Cancel {
+            return 0;
+        }
+        let (leg, sibling) = if self.legs[0].0 == order_id.0 {
+            (self.legs[0], self.legs[1])
+        } else {
+            (self.legs[1], self.legs[0])
+        };
+        if leg.1 == 0 {
+            return 0;
+        }
+        (sibling.1 as u128 * remaining.min(leg.1) as u128 / leg.1 as u128) as u64
+    }
+}
+
+/// OCO groups, indexed by group and by leg
+#[derive(Debug, Default)]
+pub struct OcoBook {
+    groups: HashMap<u64, OcoGroup>,
+    by_order: HashMap<OrderId, u64>,
+}
+
+impl OcoBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Links the legs of a group; fails with DuplicateOrder if either leg is already linked.
+    pub fn insert(&mut self, group: OcoGroup) -> Result<(), OrderBookError> {
+        for (order_id, _) in group.legs {
+            if self.by_order.contains_key(&OrderId(order_id)) {
+                return Err(OrderBookError::DuplicateOrder(OrderId(order_id)));
+            }
+        }
+        for (order_id, _) in group.legs {
+            self.by_order.insert(OrderId(order_id), group.group_id);
+        }
+        self.groups.insert(group.group_id, group);
+        Ok(())
+    }
+
+    /// Gets the group an order is a leg of
+    #[inline]
+    pub fn group_of(&self, order_id: OrderId) -> Option<&OcoGroup> {
+        self.groups.get(self.by_order.get(&order_id)?)
+    }
+
+    /// Unlinks the group an order is a leg of, returning it
+    pub fn remove_order(&mut self, order_id: OrderId) -> Option<OcoGroup> {
+        let group_id = *self.by_order.get(&order_id)?;
+        let group = self.groups.remove(&group_id)?;
+        for (order_id, _) in group.legs {
+            self.by_order.remove(&OrderId(order_id));
+        }
+        Some(group)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Lists the groups in group ID order
+    pub fn entries(&self) -> Vec<OcoGroup> {
+        let mut groups: Vec<OcoGroup> = self.groups.values().copied().collect();
+        groups.sort_unstable_by_key(|group| group.group_id);
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oco_book() {
+        let mut groups = OcoBook::new();
+        let group = OcoGroup { group_id: 3, legs: [(3, 10), (4, 10)], policy: OcoPolicy::Cancel, cancel_together: true };
+        groups.insert(group).unwrap();
+        let overlapping = OcoGroup { group_id: 4, legs: [(4, 10), (5, 10)], ..group };
+        assert!(matches!(groups.insert(overlapping), Err(OrderBookError::DuplicateOrder(OrderId(4)))));
+        assert_eq!(groups.group_of(OrderId(4)).map(|group| group.sibling(OrderId(4))), Some(OrderId(3)));
+        assert_eq!(group.sibling_target(OrderId(3), 5), 0);
+
+        assert_eq!(groups.remove_order(OrderId(4)), Some(group));
+        assert!(groups.group_of(OrderId(3)).is_none() && groups.is_empty());
+        groups.insert(group).unwrap();
+        assert_eq!(groups.entries(), vec![group]);
+    }
+}
@@ -2,48 +2,239 @@
 
 use crate::{
     level::LevelId,
+    pool::OrderPool,
     quantity::Qty,
-    utils::{BookId, INITIAL_ORDER_COUNT},
+    utils::{hex_bytes, BookId},
     price::Price,
 };
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::hash::{BuildHasherDefault, Hasher};
 
 /// Unique identifier for an order.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
-pub struct OrderId(pub u32);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct OrderId(pub u64);
 
-/// Represents an order in the trading system.
-#[derive(Default, Clone)]
-pub struct Order {
+/// An order's signature, as the trader sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Signature {
+    /// r || s || v, with v as 0/1 or 27/28.
+    Full65([u8; 65]),
+    /// EIP-2098 r || yParityAndS, with the y parity in the top bit of s.
+    Compact64([u8; 64]),
+    #[default]
+    None,
+}
+
+impl Signature {
+    /// Parses raw signature bytes; only 64 and 65 bytes are signatures.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            64 => bytes.try_into().ok().map(Signature::Compact64),
+            65 => bytes.try_into().ok().map(Signature::Full65),
+            _ => None,
+        }
+    }
+
+    /// Gets the raw bytes as sent, empty if there is no signature.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Signature::Full65(bytes) => bytes,
+            Signature::Compact64(bytes) => bytes,
+            Signature::None => &[],
+        }
+    }
+
+    /// Splits the signature into (v, r, s).
+    /// A compact signature's s loses its top bit, which becomes v as 27 + yParity.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::order::Signature;
+    /// let mut compact = [0u8; 64];
+    /// compact[32] = 0x80;
+    /// let (v, _, s) = Signature::Compact64(compact).to_vrs().unwrap();
+    /// assert_eq!((v, s), (28, [0; 32]));
+    /// ```
+    pub fn to_vrs(self) -> Option<(u8, [u8; 32], [u8; 32])> {
+        let (v, r, mut s): (u8, [u8; 32], [u8; 32]) = match self {
+            Signature::Full65(bytes) => (bytes[64], bytes[..32].try_into().unwrap(), bytes[32..64].try_into().unwrap()),
+            Signature::Compact64(bytes) => (27 + (bytes[32] >> 7), bytes[..32].try_into().unwrap(), bytes[32..].try_into().unwrap()),
+            Signature::None => return None,
+        };
+        if let Signature::Compact64(_) = &self {
+            s[0] &= 0x7f;
+        }
+        Some((v, r, s))
+    }
+
+    /// Gets the signature as r || s || v, expanding a compact one.
+    pub fn to_full(self) -> Option<[u8; 65]> {
+        let (v, r, s) = self.to_vrs()?;
+        let mut bytes = [0u8; 65];
+        bytes[..32].copy_from_slice(&r);
+        bytes[32..64].copy_from_slice(&s);
+        bytes[64] = v;
+        Some(bytes)
+    }
+}
+
+impl From<[u8; 65]> for Signature {
+    fn from(bytes: [u8; 65]) -> Self {
+        Signature::Full65(bytes)
+    }
+}
+
+impl From<[u8; 64]> for Signature {
+    fn from(bytes: [u8; 64]) -> Self {
+        Signature::Compact64(bytes)
+    }
+}
+
+impl From<Option<[u8; 65]>> for Signature {
+    fn from(bytes: Option<[u8; 65]>) -> Self {
+        bytes.map_or(Signature::None, Signature::Full65)
+    }
+}
+
+/// Signatures are stored as 0x-prefixed hex strings of 64 or 65 bytes, or null.
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Signature::None => serializer.serialize_none(),
+            signature => serializer.serialize_some(&format!("0x{}", hex::encode(signature.as_bytes()))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Some(text) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(Signature::None);
+        };
+        let bytes = hex::decode(text.trim_start_matches("0x")).map_err(serde::de::Error::custom)?;
+        Signature::from_bytes(&bytes).ok_or_else(|| serde::de::Error::custom("expected 64 or 65 bytes"))
+    }
+}
+
+/// The part of an order matching touches: what is left of it, where it rests, and who owns it.
+/// This is what the OrderPool holds, so a level's queue stays dense; the signature and the rest
+/// of the settlement data live apart in a SignedMeta.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RestingOrder {
     level_id: LevelId,
     price: Price,
     book_id: BookId,
     qty: Qty,
+    seq: u64,                      // Arrival sequence within the OidMap, for price-time priority
     trader: Option<[u8; 20]>,      // Ethereum address as fixed bytes
-    nonce: Option<u64>,            // Order nonce for signature
-    expiry: Option<u64>,           // Timestamp
-    signature: Option<[u8; 65]>,   // Raw signature bytes (r,s,v)
+}
+
+impl RestingOrder {
+    /// Gets the quantity of the order.
+    #[inline]
+    pub fn qty(&self) -> Qty {
+        self.qty
+    }
+
+    /// Gets the book ID associated with the order.
+    #[inline]
+    pub fn book_id(&self) -> BookId {
+        self.book_id
+    }
+
+    /// Gets the level ID associated with the order.
+    #[inline]
+    pub fn level_id(&self) -> LevelId {
+        self.level_id
+    }
+
+    /// Gets the price of the order
+    #[inline]
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    /// Gets the arrival sequence of the order; earlier orders have lower ones.
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the trader associated with the order.
+    #[inline]
+    pub fn trader(&self) -> Option<[u8; 20]> {
+        self.trader
+    }
+
+    /// Sets the quantity of the order.
+    #[inline]
+    pub fn set_qty(&mut self, qty: Qty) {
+        self.qty = qty;
+    }
+
+    /// Sets the level ID of the order.
+    #[inline]
+    pub fn set_level_id(&mut self, level_id: LevelId) {
+        self.level_id = level_id;
+    }
+}
+
+/// The part of an order matching never reads, kept out of the OrderPool: what settlement needs,
+/// and when the order arrived.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SignedMeta {
+    pub nonce: Option<u64>,        // Order nonce for signature
+    pub expiry: Option<u64>,       // Timestamp
+    pub signature: Signature,      // Raw signature bytes (r,s,v or compact)
+    pub received_at: u64,          // Nanoseconds since the Unix epoch; 0 until stamped
+}
+
+/// The hidden part of a resting iceberg order, kept in a side table like its SignedMeta.
+/// Only the displayed slice rests on the level; when it fully executes, the next slice of up
+/// to `display` comes out of `reserve` and joins the back of the level's queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Iceberg {
+    pub display: Qty, // Largest slice shown at once
+    pub reserve: Qty, // Quantity not shown yet
+}
+
+/// Represents an order in the trading system.
+/// Made of the RestingOrder the book keeps and the SignedMeta settlement needs;
+/// the accessors read whichever part holds the field.
+/// Serialized as an OrderRecord, without the level and queue position the book gave it.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(into = "OrderRecord", from = "OrderRecord")]
+pub struct Order {
+    resting: RestingOrder,
+    meta: SignedMeta,
+    display: Option<Qty>, // Display quantity of an iceberg order; None shows all of it
+    reduce_only: bool,    // May only trade down its owner's position, never open or flip one
 }
 
 impl Debug for Order {
     /// Formats the Order for debugging purposes.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Order")
-            .field("level_id", &self.level_id)
-            .field("price", &self.price)
-            .field("book_id", &self.book_id)
-            .field("qty", &self.qty)
-            .field("trader", &self.trader)
-            .field("nonce", &self.nonce)
-            .field("expiry", &self.expiry)
-            .field("signature", &self.signature)
+            .field("level_id", &self.resting.level_id)
+            .field("price", &self.resting.price)
+            .field("book_id", &self.resting.book_id)
+            .field("qty", &self.resting.qty)
+            .field("trader", &self.resting.trader)
+            .field("nonce", &self.meta.nonce)
+            .field("expiry", &self.meta.expiry)
+            .field("signature", &self.meta.signature)
+            .field("display", &self.display)
+            .field("reduce_only", &self.reduce_only)
             .finish()
     }
 }
 
 impl PartialEq for Order {
     fn eq(&self, other: &Self) -> bool {
-        self.level_id == other.level_id && self.book_id == other.book_id && self.qty == other.qty
+        self.level_id() == other.level_id() && self.book_id() == other.book_id() && self.qty() == other.qty()
     }
 }
 
@@ -63,82 +254,172 @@ impl Order {
         trader: Option<[u8; 20]>,
         nonce: Option<u64>,
         expiry: Option<u64>,
-        signature: Option<[u8; 65]>,
+        signature: impl Into<Signature>,
     ) -> Self {
         Self {
-            qty,
-            level_id,
-            book_id,
-            price: Price(0),
-            trader,
-            nonce,
-            expiry,
-            signature,
+            resting: RestingOrder {
+                qty,
+                level_id,
+                book_id,
+                price: Price(0),
+                seq: 0,
+                trader,
+            },
+            meta: SignedMeta {
+                nonce,
+                expiry,
+                signature: signature.into(),
+                received_at: 0,
+            },
+            display: None,
+            reduce_only: false,
         }
     }
 
+    /// Makes the order an iceberg that shows at most `display` of its quantity while it rests.
+    /// A display quantity of zero, or of at least the order's quantity, shows all of it.
+    #[inline]
+    pub fn with_display(mut self, display: Qty) -> Self {
+        self.display = Some(display);
+        self
+    }
+
+    /// Sets the limit price of the order; the book sets it again to the price it rests at.
+    #[inline]
+    pub fn with_price(mut self, price: Price) -> Self {
+        self.resting.price = price;
+        self
+    }
+
+    /// Stamps the time the order arrived, in nanoseconds since the Unix epoch.
+    #[inline]
+    pub fn with_received_at(mut self, received_at: u64) -> Self {
+        self.meta.received_at = received_at;
+        self
+    }
+
+    /// Gets the time the order arrived, or 0 if it was never stamped.
+    #[inline]
+    pub fn received_at(&self) -> u64 {
+        self.meta.received_at
+    }
+
+    /// Gets the display quantity of an iceberg order.
+    #[inline]
+    pub fn display(&self) -> Option<Qty> {
+        self.display
+    }
+
+    /// Makes the order reduce-only: it is capped at what its owner's position in the book lets
+    /// it reduce, see MatchingEngine::match_limit_order.
+    #[inline]
+    pub fn with_reduce_only(mut self) -> Self {
+        self.reduce_only = true;
+        self
+    }
+
+    /// Returns true if the order may only reduce its owner's position.
+    #[inline]
+    pub fn reduce_only(&self) -> bool {
+        self.reduce_only
+    }
+
+    /// Cuts the order down to the slice it shows when it rests, returning the reserve held back.
+    /// Returns None for an order shown in full.
+    #[inline]
+    pub(crate) fn take_slice(&mut self) -> Option<Iceberg> {
+        let display = self.display.filter(|display| !display.is_empty() && *display < self.qty())?;
+        let reserve = self.qty().checked_sub(display)?;
+        self.resting.qty = display;
+        Some(Iceberg { display, reserve })
+    }
+
+    /// Puts an order back together from the parts the OidMap keeps apart.
+    #[inline]
+    pub fn from_parts(resting: RestingOrder, meta: SignedMeta) -> Self {
+        Self { resting, meta, display: None, reduce_only: false }
+    }
+
+    /// Splits the order into the part the book keeps and the part settlement needs.
+    #[inline]
+    pub fn into_parts(self) -> (RestingOrder, SignedMeta) {
+        (self.resting, self.meta)
+    }
+
     /// Replaces the contents of the order with another order.
     #[inline]
     pub fn replace(&mut self, order: Order) {
-        self.level_id = order.level_id;
-        self.book_id = order.book_id;
-        self.qty = order.qty;
+        self.resting.level_id = order.resting.level_id;
+        self.resting.price = order.resting.price;
+        self.resting.book_id = order.resting.book_id;
+        self.resting.qty = order.resting.qty;
     }
 
     /// Gets the quantity of the order.
     #[inline]
     pub fn qty(&self) -> Qty {
-        self.qty
+        self.resting.qty
     }
 
     /// Gets the book ID associated with the order.
     #[inline]
     pub fn book_id(&self) -> BookId {
-        self.book_id
+        self.resting.book_id
     }
 
     /// Gets the level ID associated with the order.
     #[inline]
     pub fn level_id(&self) -> LevelId {
-        self.level_id
+        self.resting.level_id
     }
 
     /// Sets the quantity of the order.
     #[inline]
     pub fn set_qty(&mut self, qty: Qty) {
-        self.qty = qty;
+        self.resting.qty = qty;
     }
 
     /// Sets the book ID of the order.
     #[inline]
     pub fn set_book_id(&mut self, book_id: BookId) {
-        self.book_id = book_id;
+        self.resting.book_id = book_id;
     }
 
     /// Sets the level ID of the order.
     #[inline]
     pub fn set_level_id(&mut self, level_id: LevelId) {
-        self.level_id = level_id;
+        self.resting.level_id = level_id;
+    }
+
+    /// Sets the price of the order.
+    #[inline]
+    pub fn set_price(&mut self, price: Price) {
+        self.resting.price = price;
     }
 
     /// Gets the trader associated with the order.
     pub fn trader(&self) -> Option<[u8; 20]> {
-        self.trader
+        self.resting.trader
     }
 
     /// Gets the nonce associated with the order.
     pub fn nonce(&self) -> Option<u64> {
-        self.nonce
+        self.meta.nonce
     }
 
     /// Gets the expiry associated with the order.
     pub fn expiry(&self) -> Option<u64> {
-        self.expiry
+        self.meta.expiry
     }
 
     /// Gets the signature associated with the order.
-    pub fn signature(&self) -> Option<[u8; 65]> {
-        self.signature
+    pub fn signature(&self) -> Signature {
+        self.meta.signature
+    }
+
+    /// Gets the settlement data of the order.
+    pub fn meta(&self) -> &SignedMeta {
+        &self.meta
     }
 
     /// Creates a new order with price - this will be used for order submission
@@ -150,30 +431,147 @@ impl Order {
         trader: [u8; 20],
         nonce: u64,
         expiry: u64,
-        signature: [u8; 65],
+        signature: Signature,
     ) -> Self {
         Self {
-            qty,
-            level_id: LevelId(0), // This will be assigned by the matching engine
-            price,
-            book_id,
-            trader: Some(trader),
-            nonce: Some(nonce),
-            expiry: Some(expiry),
-            signature: Some(signature),
+            resting: RestingOrder {
+                qty,
+                level_id: LevelId(0), // This will be assigned by the matching engine
+                price,
+                book_id,
+                seq: 0,
+                trader: Some(trader),
+            },
+            meta: SignedMeta {
+                nonce: Some(nonce),
+                expiry: Some(expiry),
+                signature,
+                received_at: 0,
+            },
+            display: None,
+            reduce_only: false,
         }
     }
 
     /// Gets the price of the order
     #[inline]
     pub fn price(&self) -> Price {
-        self.price
+        self.resting.price
+    }
+}
+
+/// An order as written out: its price unsigned beside its side, and the trader and signature
+/// as 0x-prefixed hex. An order read back has no level until it is added to a book.
+#[derive(Serialize, Deserialize)]
+struct OrderRecord {
+    book_id: BookId,
+    qty: Qty,
+    #[serde(flatten)]
+    price: Price,
+    #[serde(with = "hex_bytes")]
+    trader: Option<[u8; 20]>,
+    nonce: Option<u64>,
+    expiry: Option<u64>,
+    signature: Signature,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display: Option<Qty>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    reduce_only: bool,
+    #[serde(default)]
+    received_at: u64,
+}
+
+impl From<Order> for OrderRecord {
+    fn from(order: Order) -> Self {
+        Self {
+            book_id: order.book_id(),
+            qty: order.qty(),
+            price: order.price(),
+            trader: order.trader(),
+            nonce: order.nonce(),
+            expiry: order.expiry(),
+            signature: order.signature(),
+            display: order.display,
+            reduce_only: order.reduce_only,
+            received_at: order.received_at(),
+        }
+    }
+}
+
+impl From<OrderRecord> for Order {
+    fn from(record: OrderRecord) -> Self {
+        let mut order = Order::new(record.qty, LevelId(0), record.book_id, record.trader, record.nonce, record.expiry, record.signature)
+            .with_price(record.price)
+            .with_received_at(record.received_at);
+        order.display = record.display;
+        order.reduce_only = record.reduce_only;
+        order
+    }
+}
+
+/// Hasher for OrderIds: one multiply and a fold instead of SipHash.
+/// Sequential and strided IDs both spread over the low bits a hash table indexes by.
+#[derive(Default)]
+pub struct OrderIdHasher(u64);
+
+impl Hasher for OrderIdHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    #[inline]
+    fn write_u64(&mut self, n: u64) {
+        let x = (self.0 ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        self.0 = x ^ (x >> 32);
+    }
+}
+
+/// A reference to a resting order by the OrderPool slot it occupies and the generation of that
+/// slot when the order was inserted. The generation moves on when the order leaves, so a handle
+/// kept past that stops resolving, even once the slot holds another order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OrderHandle {
+    pub slot: u32,
+    pub generation: u32,
+}
+
+impl OrderHandle {
+    /// Packs the handle into one u64 for clients, generation in the high half.
+    #[inline]
+    pub fn to_u64(self) -> u64 {
+        (self.generation as u64) << 32 | self.slot as u64
+    }
+
+    /// Unpacks a handle packed by `to_u64`.
+    #[inline]
+    pub fn from_u64(value: u64) -> Self {
+        Self {
+            slot: value as u32,
+            generation: (value >> 32) as u32,
+        }
     }
 }
 
 /// Data structure for mapping OrderIds to Order objects.
+/// Orders live once, in an OrderPool, found by OrderId through a hash map of their handles,
+/// so sparse 64-bit IDs cost no more memory than dense ones. Lookups stay O(1).
+/// The pool only holds each order's RestingOrder; its SignedMeta sits in a side table,
+/// read when a fill is settled rather than on every step through a queue. So does the
+/// Iceberg of each iceberg order, for the few that have one.
 pub struct OidMap {
-    data: Vec<Option<Order>>,
+    pool: OrderPool,
+    index: HashMap<OrderId, OrderHandle, BuildHasherDefault<OrderIdHasher>>, // Handle of each resting OrderId.
+    meta: HashMap<OrderId, SignedMeta, BuildHasherDefault<OrderIdHasher>>,   // Settlement data of each resting OrderId.
+    icebergs: HashMap<OrderId, Iceberg, BuildHasherDefault<OrderIdHasher>>,  // Hidden reserve of each resting iceberg.
+    next_seq: u64,                                                           // Arrival sequence of the next order.
 }
 
 impl Default for OidMap {
@@ -185,72 +583,355 @@ impl Default for OidMap {
 }
 
 impl OidMap {
-    /// Creates a new OidMap with an initial capacity.
+    /// Creates a new, empty OidMap that grows as orders arrive.
     #[inline]
     pub fn new() -> Self {
         OidMap {
-            data: vec![None; INITIAL_ORDER_COUNT], // Use a fixed-size array
+            pool: OrderPool::new(),
+            index: HashMap::default(),
+            meta: HashMap::default(),
+            icebergs: HashMap::default(),
+            next_seq: 0,
         }
     }
 
-    /// Reserves space for an OrderId in the map.
+    /// Moves an Order into the map with a specific OrderId, replacing any order it had.
+    /// Returns the handle of the inserted order. The order isn't queued on any level; the
+    /// OrderBook it rests on does that.
     #[inline]
-    pub fn reserve(&mut self, oid: OrderId) {
-        let idx = oid.0 as usize;
-        if idx >= self.data.len() {
-            self.data.resize(idx + 1, None);
-        }
+    pub fn insert(&mut self, oid: OrderId, value: Order) -> OrderHandle {
+        self.remove(oid);
+        let (mut resting, meta) = value.into_parts();
+        resting.seq = self.next_seq;
+        self.next_seq += 1;
+        let handle = self.pool.alloc(oid, resting);
+        self.index.insert(oid, handle);
+        self.meta.insert(oid, meta);
+        handle
     }
 
-    /// Inserts an Order into the map with a specific OrderId.
+    /// Removes an Order from the map by its OrderId, retiring its handle.
+    /// Returns the removed order; an iceberg comes back whole, its reserve included.
     #[inline]
-    pub fn insert(&mut self, oid: OrderId, value: &Order) {
-        let idx = oid.0 as usize;
-        if idx >= self.data.len() {
-            self.data.resize(idx + 1, None);
-        }
-        self.data[idx] = Some(value.clone()); // Clone only when necessary
+    pub fn remove(&mut self, oid: OrderId) -> Option<Order> {
+        let handle = self.index.get(&oid).copied()?;
+        self.remove_by_handle(handle).map(|(_, order)| order)
     }
 
-    /// Removes an Order from the map by its OrderId.
+    /// Removes the Order a handle refers to, retiring the handle.
+    /// Returns the removed order, or None if it had left the map already.
     #[inline]
-    pub fn remove(&mut self, oid: OrderId) {
-        let idx = oid.0 as usize;
-        if idx < self.data.len() {
-            self.data[idx] = None;
+    pub fn remove_by_handle(&mut self, handle: OrderHandle) -> Option<(OrderId, Order)> {
+        let (oid, _) = self.pool.get(handle)?;
+        self.index.remove(&oid);
+        let meta = self.meta.remove(&oid).unwrap_or_default();
+        let iceberg = self.icebergs.remove(&oid);
+        let (oid, resting) = self.pool.free(handle)?;
+        let mut order = Order::from_parts(resting, meta);
+        if let Some(iceberg) = iceberg {
+            order.set_qty(order.qty().saturating_add(iceberg.reserve));
+            order = order.with_display(iceberg.display);
         }
+        Some((oid, order))
     }
 
-    /// Updates the quantity of an Order in the map by its OrderId.
+    /// Gets the hidden reserve of a resting iceberg order.
     #[inline]
-    pub fn update_qty(&mut self, oid: OrderId, qty: Qty) {
-        let idx = oid.0 as usize;
-        if idx < self.data.len() {
-            if let Some(order) = &mut self.data[idx] {
-                order.qty -= qty;
-            }
+    pub fn iceberg(&self, oid: OrderId) -> Option<&Iceberg> {
+        self.icebergs.get(&oid)
+    }
+
+    /// Holds back `iceberg.reserve` of a resting order; an empty reserve makes it a plain order.
+    #[inline]
+    pub(crate) fn set_iceberg(&mut self, oid: OrderId, iceberg: Iceberg) {
+        if iceberg.reserve.is_empty() {
+            self.icebergs.remove(&oid);
+        } else {
+            self.icebergs.insert(oid, iceberg);
         }
     }
 
+    /// Gets all a resting order has left, shown or not.
+    #[inline]
+    pub fn total_qty(&self, oid: OrderId) -> Option<Qty> {
+        let reserve = self.iceberg(oid).map_or(Qty(0), |iceberg| iceberg.reserve);
+        Some(self.get(oid)?.qty().saturating_add(reserve))
+    }
+
+    /// Shows the next slice of an iceberg whose displayed slice is used up, as a new arrival.
+    /// Returns the slice, or None if the order has no reserve. The caller queues it.
+    #[inline]
+    pub(crate) fn replenish(&mut self, oid: OrderId) -> Option<Qty> {
+        let iceberg = *self.icebergs.get(&oid)?;
+        let slice = iceberg.display.min(iceberg.reserve);
+        let seq = self.next_seq;
+        let order = self.get_mut(oid)?;
+        order.qty = slice;
+        order.seq = seq;
+        self.next_seq += 1;
+        self.set_iceberg(oid, Iceberg { reserve: iceberg.reserve.saturating_sub(slice), ..iceberg });
+        Some(slice)
+    }
+
+    /// Gets the handle of a resting order.
+    #[inline]
+    pub fn handle(&self, oid: OrderId) -> Option<OrderHandle> {
+        self.index.get(&oid).copied()
+    }
+
+    /// Gets the order a handle refers to, or None if it has left the map since.
+    #[inline]
+    pub fn get_by_handle(&self, handle: OrderHandle) -> Option<(OrderId, &RestingOrder)> {
+        self.pool.get(handle)
+    }
+
+    /// Gets a mutable reference to the order a handle refers to, or None if it has left the map since.
+    #[inline]
+    pub fn get_mut_by_handle(&mut self, handle: OrderHandle) -> Option<&mut RestingOrder> {
+        self.pool.get_mut(handle)
+    }
+
+    /// Reduces the quantity of an Order in the map by its OrderId.
+    /// Returns the quantity left, or None if the order doesn't exist or has less than `qty` left.
+    #[inline]
+    pub fn update_qty(&mut self, oid: OrderId, qty: Qty) -> Option<Qty> {
+        let order = self.get_mut(oid)?;
+        order.qty = order.qty.checked_sub(qty)?;
+        Some(order.qty)
+    }
+
     /// Gets a reference to an Order by its OrderId.
     #[inline]
-    pub fn get(&self, oid: OrderId) -> Option<&Order> {
-        let idx = oid.0 as usize;
-        self.data.get(idx)?.as_ref()
+    pub fn get(&self, oid: OrderId) -> Option<&RestingOrder> {
+        self.pool.get(*self.index.get(&oid)?).map(|(_, order)| order)
     }
 
     /// Gets a mutable reference to an Order by its OrderId.
     #[inline]
-    pub fn get_mut(&mut self, oid: OrderId) -> Option<&mut Order> {
-        let idx = oid.0 as usize;
-        self.data.get_mut(idx)?.as_mut()
+    pub fn get_mut(&mut self, oid: OrderId) -> Option<&mut RestingOrder> {
+        self.pool.get_mut(*self.index.get(&oid)?)
+    }
+
+    /// Gets the settlement data of a resting order by its OrderId.
+    #[inline]
+    pub fn meta(&self, oid: OrderId) -> Option<&SignedMeta> {
+        self.meta.get(&oid)
+    }
+
+    /// Gets a copy of a resting order with its settlement data, e.g. for the maker of a fill.
+    #[inline]
+    pub fn get_order(&self, oid: OrderId) -> Option<Order> {
+        let resting = self.get(oid)?.clone();
+        Some(Order::from_parts(resting, self.meta(oid).copied().unwrap_or_default()))
+    }
+
+    /// Gets the number of resting orders.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Gets the pool the orders live in, e.g. to walk a level's queue.
+    #[inline]
+    pub fn pool(&self) -> &OrderPool {
+        &self.pool
+    }
+
+    /// Gets the pool mutably, to queue orders on levels and take them off.
+    #[inline]
+    pub(crate) fn pool_mut(&mut self) -> &mut OrderPool {
+        &mut self.pool
+    }
+
+    /// Approximates the heap memory the map has allocated.
+    pub fn allocated_bytes(&self) -> usize {
+        self.pool.allocated_bytes()
+            + self.index.capacity() * (std::mem::size_of::<(OrderId, OrderHandle)>() + 1)
+            + self.meta.capacity() * (std::mem::size_of::<(OrderId, SignedMeta)>() + 1)
+            + self.icebergs.capacity() * (std::mem::size_of::<(OrderId, Iceberg)>() + 1)
     }
 
+    /// Iterates over the orders in no particular order; walk a level's queue for time priority.
     #[inline]
-    pub fn iter(&self) -> impl Iterator<Item = (OrderId, &Order)> {
-        self.data
-            .iter()
-            .enumerate()
-            .filter_map(|(i, order)| order.as_ref().map(|o| (OrderId(i as u32), o)))
+    pub fn iter(&self) -> impl Iterator<Item = (OrderId, &RestingOrder)> {
+        self.pool.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{address_of, personal_message_hash, recover_prehash};
+    use k256::ecdsa::SigningKey;
+
+    #[test]
+    fn test_oid_map_sparse_ids() {
+        let mut oid_map = OidMap::new();
+        let order_ids = [OrderId(0), OrderId(1 << 40), OrderId(u64::MAX)];
+        for (i, &order_id) in order_ids.iter().enumerate() {
+            let order = Order::new(Qty(10 + i as u64), LevelId(0), BookId(0), None, None, None, None);
+            oid_map.insert(order_id, order);
+        }
+
+        assert_eq!(oid_map.get(OrderId(u64::MAX)).map(RestingOrder::qty), Some(Qty(12)));
+        assert_eq!(oid_map.update_qty(OrderId(1 << 40), Qty(4)), Some(Qty(7)));
+        assert!(oid_map.get(OrderId(1)).is_none());
+        oid_map.remove(OrderId(0));
+        let mut remaining: Vec<OrderId> = oid_map.iter().map(|(order_id, _)| order_id).collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec![OrderId(1 << 40), OrderId(u64::MAX)]);
+    }
+
+    #[test]
+    fn test_oid_map_grows_lazily() {
+        let mut oid_map = OidMap::new();
+        assert_eq!(oid_map.allocated_bytes(), 0);
+        oid_map.insert(OrderId(7), Order::new(Qty(10), LevelId(0), BookId(0), None, None, None, None));
+        assert!(oid_map.allocated_bytes() < 1 << 12);
+    }
+
+    #[test]
+    fn test_resting_order_size() {
+        // A queue walk reads one RestingOrder per order; the signature stays out of it
+        let (resting, order) = (std::mem::size_of::<RestingOrder>(), std::mem::size_of::<Order>());
+        println!("RestingOrder: {} bytes, Order: {} bytes", resting, order);
+        assert!(resting <= 56);
+        assert!(resting * 2 < order);
+    }
+
+    #[test]
+    fn test_signed_meta_side_table() {
+        let mut oid_map = OidMap::new();
+        let order = Order::new(Qty(10), LevelId(0), BookId(0), Some([1; 20]), Some(7), Some(99), [3; 65]);
+        oid_map.insert(OrderId(1), order.clone());
+        oid_map.insert(OrderId(2), order);
+
+        assert_eq!(oid_map.get(OrderId(1)).unwrap().trader(), Some([1; 20]));
+        assert!(oid_map.get(OrderId(1)).unwrap().seq() < oid_map.get(OrderId(2)).unwrap().seq());
+        let meta = oid_map.meta(OrderId(1)).unwrap();
+        assert_eq!((meta.nonce, meta.expiry, meta.signature), (Some(7), Some(99), Signature::Full65([3; 65])));
+        let full = oid_map.get_order(OrderId(1)).unwrap();
+        assert_eq!((full.qty(), full.nonce(), full.signature()), (Qty(10), Some(7), Signature::Full65([3; 65])));
+
+        // The metadata leaves with its order
+        let removed = oid_map.remove(OrderId(1)).unwrap();
+        assert_eq!(removed.expiry(), Some(99));
+        assert!(oid_map.meta(OrderId(1)).is_none());
+        assert!(oid_map.get_order(OrderId(1)).is_none());
+        let handle = oid_map.handle(OrderId(2)).unwrap();
+        assert_eq!(oid_map.remove_by_handle(handle).map(|(_, order)| order.nonce()), Some(Some(7)));
+        assert!(oid_map.meta(OrderId(2)).is_none());
+    }
+
+    #[test]
+    fn test_order_handles() {
+        let mut oid_map = OidMap::new();
+        let order = Order::new(Qty(10), LevelId(0), BookId(0), None, None, None, None);
+        let first = oid_map.insert(OrderId(5), order.clone());
+        assert_eq!(oid_map.handle(OrderId(5)), Some(first));
+        assert_eq!(oid_map.get_by_handle(first).map(|(oid, _)| oid), Some(OrderId(5)));
+        assert_eq!(OrderHandle::from_u64(first.to_u64()), first);
+
+        // The next order takes over the slot under a new generation
+        oid_map.remove(OrderId(5));
+        let second = oid_map.insert(OrderId(6), order.clone());
+        println!("{:?} then {:?}", first, second);
+        assert_eq!(second.slot, first.slot);
+        assert_ne!(second.generation, first.generation);
+        assert!(oid_map.get_by_handle(first).is_none());
+        assert_eq!(oid_map.get_by_handle(second).map(|(oid, _)| oid), Some(OrderId(6)));
+
+        // Even an order reusing the old ID doesn't revive the old handle
+        oid_map.remove(OrderId(6));
+        let third = oid_map.insert(OrderId(5), order.clone());
+        assert!(oid_map.get_by_handle(first).is_none());
+        assert!(oid_map.get_by_handle(second).is_none());
+        assert_eq!(oid_map.get_by_handle(third).map(|(oid, _)| oid), Some(OrderId(5)));
+        assert!(oid_map.get_by_handle(OrderHandle { slot: 99, generation: 0 }).is_none());
+    }
+
+    #[test]
+    fn test_compact_signatures() {
+        // The EIP-2098 test vectors, with v of 27 and 28, signed by 0x2e98...abfb
+        let key = SigningKey::from_slice(
+            &hex::decode("1234567890123456789012345678901234567890123456789012345678901234").unwrap(),
+        )
+        .unwrap();
+        let address = address_of(key.verifying_key());
+        assert_eq!(hex::encode(address), "2e988a386a799f506693793c6a5af6b54dfaabfb");
+        let vectors = [
+            (
+                "Hello World",
+                "68a020a209d3d56c46f38cc50a33f704f4a9a10a59377f8dd762ac66910e9b907e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea520641b",
+                "68a020a209d3d56c46f38cc50a33f704f4a9a10a59377f8dd762ac66910e9b907e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea52064",
+            ),
+            (
+                "It's a small(er) world",
+                "9328da16089fcba9bececa81663203989f2df5fe1faa6291a45381c81bd17f76139c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f5507931c",
+                "9328da16089fcba9bececa81663203989f2df5fe1faa6291a45381c81bd17f76939c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f550793",
+            ),
+        ];
+        for (message, full, compact) in vectors {
+            let full = Signature::from_bytes(&hex::decode(full).unwrap()).unwrap();
+            let compact = Signature::from_bytes(&hex::decode(compact).unwrap()).unwrap();
+            assert!(matches!(full, Signature::Full65(_)));
+            assert!(matches!(compact, Signature::Compact64(_)));
+            println!("{:?}: v = {:?}", message, compact.to_vrs().map(|(v, _, _)| v));
+
+            assert_eq!(compact.to_vrs(), full.to_vrs());
+            assert_eq!(compact.to_full(), full.to_full());
+            let hash = personal_message_hash(message.as_bytes());
+            let signer = recover_prehash(&hash, &compact.to_full().unwrap()).unwrap();
+            assert_eq!(signer, address);
+            assert_eq!(recover_prehash(&hash, full.as_bytes()), Ok(signer));
+        }
+
+        assert_eq!(Signature::from_bytes(&[]), None);
+        assert_eq!(Signature::from_bytes(&[1; 63]), None);
+        assert_eq!(Signature::from_bytes(&[1; 66]), None);
+        assert_eq!(Signature::None.to_full(), None);
+    }
+
+    #[test]
+    fn test_signature_serde() {
+        for signature in [Signature::Full65([1; 65]), Signature::Compact64([2; 64]), Signature::None] {
+            let json = serde_json::to_string(&signature).unwrap();
+            println!("{:?}: {}", signature, json);
+            assert_eq!(serde_json::from_str::<Signature>(&json).unwrap(), signature);
+        }
+        assert_eq!(serde_json::to_string(&Signature::None).unwrap(), "null");
+        assert!(serde_json::from_str::<Signature>(&format!("\"0x{}\"", "11".repeat(66))).is_err());
+    }
+
+    #[test]
+    fn test_order_serde() {
+        let price = Price::from_u32(1500, false).unwrap();
+        let iceberg = Order::new_submission(Qty(25), price, BookId(2), [0xab; 20], 7, 1_700_000_000, Signature::Compact64([0x11; 64]))
+            .with_display(Qty(5))
+            .with_reduce_only()
+            .with_received_at(1_700_000_000_123_456_789);
+        let plain = Order::new(Qty(10), LevelId(4), BookId(0), None, None, None, None);
+        for order in [iceberg, plain] {
+            let json = serde_json::to_string(&order).unwrap();
+            println!("{}", json);
+            let read: Order = serde_json::from_str(&json).unwrap();
+            assert_eq!(read.level_id(), LevelId(0), "the level belongs to the book the order rested in");
+            assert_eq!(serde_json::to_string(&read).unwrap(), json);
+            assert_eq!((read.price(), read.trader(), read.display(), read.reduce_only()), (order.price(), order.trader(), order.display(), order.reduce_only()));
+            assert_eq!(read.received_at(), order.received_at());
+        }
+
+        // Plain orders leave out the iceberg and reduce-only fields, and IDs and quantities are numbers
+        let json = serde_json::to_value(Order::new(Qty(10), LevelId(4), BookId(3), None, Some(1), None, None)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "book_id": 3, "qty": 10, "price": 0, "is_bid": false, "trader": null, "nonce": 1, "expiry": null, "signature": null, "received_at": 0 })
+        );
+        assert_eq!(serde_json::to_string(&OrderId(42)).unwrap(), "42");
     }
 }
@@ -1,12 +1,20 @@
 use crate::{
-    order::Order,
-    price::Price,
+    auth::recover_prehash,
+    book_registry::BookRegistry,
+    eip712::{Eip712Domain, Eip712Order},
+    market::{MarketConfig, SizeRule, SizeRules, SIGNATURE_TYPE_EIP1271},
+    order::{Order, Signature},
+    price::{Price, PriceScale},
     quantity::Qty,
-    utils::BookId,
+    risk::RiskLimit,
+    utils::{BookId, Clock},
 };
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum OrderIntakeError {
     InvalidQuantity,
     InvalidPrice,
@@ -14,6 +22,14 @@ pub enum OrderIntakeError {
     InvalidTrader,
     InvalidSignature,
     InvalidNonce,
+    NegativePrice, // Negative although the side was given apart from it
+    QuantityTooLarge { max: u64 },
+    Expired, // The signed expiry has already passed
+    ExpiryTooSoon { min_expiry: u64 }, // Earliest expiry taken right now, in seconds since the Unix epoch
+    NonceRequired, // Zero in a market whose orders must carry a nonce
+    LimitExceeded(RiskLimit), // The order would take its trader past this risk limit
+    SizeRuleBroken(SizeRule), // The order is too small for its market, or not in whole lots
+    InsufficientFunds { token: [u8; 20], required: u128, available: u128 }, // In token base units
 }
 
 impl fmt::Display for OrderIntakeError {
@@ -25,98 +41,590 @@ impl fmt::Display for OrderIntakeError {
             OrderIntakeError::InvalidTrader => write!(f, "Invalid trader address"),
             OrderIntakeError::InvalidSignature => write!(f, "Invalid signature"),
             OrderIntakeError::InvalidNonce => write!(f, "Invalid nonce"),
+            OrderIntakeError::NegativePrice => write!(f, "Price must be positive when the side is given"),
+            OrderIntakeError::QuantityTooLarge { max } => write!(f, "Quantity must be at most {}", max),
+            OrderIntakeError::Expired => write!(f, "The order has already expired"),
+            OrderIntakeError::ExpiryTooSoon { min_expiry } => write!(f, "Expiry must be at least {}", min_expiry),
+            OrderIntakeError::NonceRequired => write!(f, "The market requires a non-zero nonce"),
+            OrderIntakeError::LimitExceeded(limit) => write!(f, "Risk limit exceeded: {}", limit),
+            OrderIntakeError::SizeRuleBroken(rule) => write!(f, "Order must have a {}", rule),
+            OrderIntakeError::InsufficientFunds { token, required, available } => write!(
+                f,
+                "Insufficient funds: order needs {} of token 0x{}, {} available",
+                required,
+                hex::encode(token),
+                available
+            ),
         }
     }
 }
 
+/// Side of an order given apart from its price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Expiry of an order submitted without one, as it is signed and settled: it never expires
+pub const NO_EXPIRY: u64 = u64::MAX;
+
 /// Represents an order submission from the frontend
+/// Without a `side`, the sign of the price is the side, as it is signed: positive bids, negative
+/// asks. With one, the price must be positive and a sell is signed with it negated.
 #[derive(Debug)]
 pub struct OrderSubmission {
     pub book_id: String,
     pub price: i32,        // Changed from u64 to i32 to match Price
-    pub quantity: u32,     // Changed from u64 to u32 to match Qty
+    pub side: Option<Side>,
+    pub quantity: u64,     // Matches Qty
     pub trader: String,
     pub nonce: u64,
-    pub expiry: Option<u64>,  // Make expiry optional
+    pub expiry: Option<u64>,  // NO_EXPIRY if left out
     pub signature: String,
 }
 
 impl OrderSubmission {
     /// Validates and converts the submission into an internal Order
-    pub fn into_order(self) -> Result<Order, OrderIntakeError> {
+    /// The book must be registered in `registry`.
+    pub fn into_order(self, registry: &BookRegistry) -> Result<Order, OrderIntakeError> {
         // Validate quantity
         if self.quantity == 0 {
             return Err(OrderIntakeError::InvalidQuantity);
         }
 
         // Validate price
-        if self.price == 0 {
-            return Err(OrderIntakeError::InvalidPrice);
-        }
+        let price = self.signed_price()?;
 
         // Convert hex trader address to bytes
-        let trader_bytes = hex::decode(&self.trader.trim_start_matches("0x"))
-            .map_err(|_| OrderIntakeError::InvalidTrader)?;
-        if trader_bytes.len() != 20 {
-            return Err(OrderIntakeError::InvalidTrader);
-        }
-        let mut trader = [0u8; 20];
-        trader.copy_from_slice(&trader_bytes);
+        let trader = parse_trader(&self.trader)?;
 
-        // Just convert signature to bytes without validation
-        let sig_bytes = hex::decode(&self.signature.trim_start_matches("0x"))
+        // Signatures are r || s || v or EIP-2098 r || yParityAndS; whether they match is checked by OrderIntake
+        let bytes = hex::decode(self.signature.trim_start_matches("0x"))
             .map_err(|_| OrderIntakeError::InvalidSignature)?;
-        let mut signature = [0u8; 65];
-        let sig_len = std::cmp::min(sig_bytes.len(), 65);
-        signature[..sig_len].copy_from_slice(&sig_bytes[..sig_len]);
+        let signature = Signature::from_bytes(&bytes).ok_or(OrderIntakeError::InvalidSignature)?;
 
         Ok(Order::new_submission(
             Qty(self.quantity),
-            Price(self.price),
-            BookId::from_str(&self.book_id)?,
+            Price(price),
+            BookId::from_str(&self.book_id, registry)?,
             trader,
             self.nonce,
-            self.expiry.unwrap_or(u64::MAX), // Use max value if no expiry provided
+            self.expiry.unwrap_or(NO_EXPIRY),
             signature,
         ))
     }
+
+    /// Gets the price as it is signed, its sign the side
+    pub fn signed_price(&self) -> Result<i32, OrderIntakeError> {
+        // i32::MIN has no absolute value. A negative price with its side given would otherwise
+        // flip the order to the other side
+        let price = match self.side {
+            Some(_) if self.price < 0 => return Err(OrderIntakeError::NegativePrice),
+            Some(Side::Buy) | None => self.price,
+            Some(Side::Sell) => -self.price,
+        };
+        if price == 0 || price == i32::MIN {
+            return Err(OrderIntakeError::InvalidPrice);
+        }
+        Ok(price)
+    }
+}
+
+/// Parses a 0x-prefixed (or bare) hex Ethereum address into its 20 raw bytes
+pub fn parse_trader(address: &str) -> Result<[u8; 20], OrderIntakeError> {
+    let trader_bytes = hex::decode(address.trim_start_matches("0x"))
+        .map_err(|_| OrderIntakeError::InvalidTrader)?;
+    if trader_bytes.len() != 20 {
+        return Err(OrderIntakeError::InvalidTrader);
+    }
+    let mut trader = [0u8; 20];
+    trader.copy_from_slice(&trader_bytes);
+    Ok(trader)
+}
+
+/// Outcome of checking a submission's signature
+#[derive(Debug)]
+pub enum Verification {
+    /// The trader's key signed the order.
+    Verified(VerifiedOrder),
+    /// The signature is not the trader's key, but the book accepts contract wallets:
+    /// the trader contract must confirm it with EIP-1271 `isValidSignature(order_hash, signature)`.
+    NeedsContractCheck(ContractCheck),
+}
+
+/// An order whose signature OrderIntake checked
+/// Only OrderIntake and a confirmed ContractCheck make one, so code that takes it, like the
+/// server's matching commands, can't be handed an order nobody verified. It reads as its Order.
+#[derive(Debug, Clone)]
+pub struct VerifiedOrder(Order);
+
+impl VerifiedOrder {
+    pub fn into_order(self) -> Order {
+        self.0
+    }
+}
+
+impl std::ops::Deref for VerifiedOrder {
+    type Target = Order;
+
+    fn deref(&self) -> &Order {
+        &self.0
+    }
+}
+
+/// An order waiting on its trader contract to confirm the signature
+#[derive(Debug)]
+pub struct ContractCheck {
+    order: Order,
+    order_hash: [u8; 32],
 }
 
-pub struct OrderIntake;
+impl ContractCheck {
+    pub fn order(&self) -> &Order {
+        &self.order
+    }
+
+    /// Gets the EIP-712 hash the contract is asked about
+    pub fn order_hash(&self) -> [u8; 32] {
+        self.order_hash
+    }
+
+    /// Takes the order as verified, once `isValidSignature` has confirmed the signature
+    pub fn confirmed(self) -> VerifiedOrder {
+        VerifiedOrder(self.order)
+    }
+}
+
+/// Bounds every submission is held to, whatever its market
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntakeLimits {
+    pub max_quantity: u64,
+    pub min_expiry_margin_secs: u64, // How far in the future an expiry must be when the order comes in
+}
+
+impl Default for IntakeLimits {
+    fn default() -> Self {
+        Self { max_quantity: u64::MAX, min_expiry_margin_secs: 0 }
+    }
+}
+
+/// Validates order submissions and checks each one was signed by its trader
+pub struct OrderIntake {
+    default_domain: Eip712Domain,
+    domains: HashMap<String, Eip712Domain>, // Per-book overrides, keyed by book name.
+    contract_wallet_books: HashSet<String>, // Books whose market uses SIGNATURE_TYPE_EIP1271.
+    price_scales: HashMap<String, PriceScale>, // How prices are written in books whose market sets it.
+    size_rules: HashMap<String, SizeRules>,    // Smallest orders taken by books with a market.
+    nonce_books: HashSet<String>,           // Books whose market requires a non-zero nonce.
+    registry: Arc<BookRegistry>,            // Books orders may be submitted to.
+    limits: IntakeLimits,
+    clock: Clock, // What expiries are checked against.
+}
+
+impl Default for OrderIntake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl OrderIntake {
-    /// Creates a new OrderIntake instance
+    /// Creates a new OrderIntake instance that verifies against the default domain
     pub fn new() -> Self {
-        Self
+        Self::with_domain(Eip712Domain::default())
+    }
+
+    /// Creates an OrderIntake that verifies every book against `domain` unless overridden
+    pub fn with_domain(domain: Eip712Domain) -> Self {
+        Self {
+            default_domain: domain,
+            domains: HashMap::new(),
+            contract_wallet_books: HashSet::new(),
+            price_scales: HashMap::new(),
+            size_rules: HashMap::new(),
+            nonce_books: HashSet::new(),
+            registry: Arc::new(BookRegistry::new()),
+            limits: IntakeLimits::default(),
+            clock: Clock::System,
+        }
+    }
+
+    /// Resolves books through `registry`, e.g. the one the API registers books in
+    pub fn with_registry(mut self, registry: Arc<BookRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Holds every submission to `limits`
+    pub fn with_limits(mut self, limits: IntakeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Checks expiries against `clock` rather than the system time
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
     }
 
-    /// Processes an order submission and returns a validated Order
-    pub fn process_submission(&self, submission: OrderSubmission) -> Result<Order, OrderIntakeError> {
-        submission.into_order()
+    /// Sets the EIP-712 domain orders for `book_id` must be signed under
+    pub fn set_domain(&mut self, book_id: &str, domain: Eip712Domain) {
+        self.domains.insert(book_id.to_string(), domain);
+    }
+
+    /// Verifies orders for `book_id` the way its market says: under its domain, and
+    /// accepting contract-wallet signatures if its signature type is SIGNATURE_TYPE_EIP1271, and
+    /// reading submitted prices in its decimal places and ticks, and holding orders to its size rules
+    /// and nonce requirement
+    pub fn set_market(&mut self, book_id: &str, config: &MarketConfig) {
+        self.set_domain(book_id, config.domain());
+        self.price_scales.insert(book_id.to_string(), config.price_scale());
+        self.size_rules.insert(book_id.to_string(), config.size_rules);
+        if config.require_nonce {
+            self.nonce_books.insert(book_id.to_string());
+        } else {
+            self.nonce_books.remove(book_id);
+        }
+        if config.signature_type == SIGNATURE_TYPE_EIP1271 {
+            self.contract_wallet_books.insert(book_id.to_string());
+        } else {
+            self.contract_wallet_books.remove(book_id);
+        }
+    }
+
+    /// Forgets the market of a closed book, so a book later created under its name starts afresh
+    pub fn remove_market(&mut self, book_id: &str) {
+        self.domains.remove(book_id);
+        self.price_scales.remove(book_id);
+        self.size_rules.remove(book_id);
+        self.nonce_books.remove(book_id);
+        self.contract_wallet_books.remove(book_id);
+    }
+
+    /// Gets the EIP-712 domain orders for `book_id` are verified against
+    pub fn domain(&self, book_id: &str) -> &Eip712Domain {
+        self.domains.get(book_id).unwrap_or(&self.default_domain)
+    }
+
+    /// Gets how prices submitted to `book_id` are read; books without a market take them as they are
+    pub fn price_scale(&self, book_id: &str) -> PriceScale {
+        self.price_scales.get(book_id).copied().unwrap_or_default()
+    }
+
+    /// Processes an order submission and returns a validated Order.
+    /// The signature must be the trader's EIP-712 signature of the order under the book's domain;
+    /// contract-wallet signatures are rejected, as checking them takes a call to the chain.
+    pub fn process_submission(&self, submission: OrderSubmission) -> Result<VerifiedOrder, OrderIntakeError> {
+        match self.verify_submission(submission)? {
+            Verification::Verified(order) => Ok(order),
+            Verification::NeedsContractCheck(_) => Err(OrderIntakeError::InvalidSignature),
+        }
+    }
+
+    /// Checks an order arriving at `now` against the intake's limits and its market's nonce requirement
+    fn check_limits(&self, book_id: &str, order: &Order, now: u64) -> Result<(), OrderIntakeError> {
+        if order.qty().value() > self.limits.max_quantity {
+            return Err(OrderIntakeError::QuantityTooLarge { max: self.limits.max_quantity });
+        }
+        if let Some(expiry) = order.expiry() {
+            let now = now / 1_000_000_000;
+            let min_expiry = now.saturating_add(self.limits.min_expiry_margin_secs);
+            if expiry <= now {
+                return Err(OrderIntakeError::Expired);
+            }
+            if expiry < min_expiry {
+                return Err(OrderIntakeError::ExpiryTooSoon { min_expiry });
+            }
+        }
+        if order.nonce() == Some(0) && self.nonce_books.contains(book_id) {
+            return Err(OrderIntakeError::NonceRequired);
+        }
+        Ok(())
+    }
+
+    /// Validates a submission and checks its signature as far as possible without the chain.
+    /// Contract-wallet signatures are 64 or 65 bytes like any other, since the book stores them per order.
+    /// The order comes out stamped with the time it was received.
+    pub fn verify_submission(&self, submission: OrderSubmission) -> Result<Verification, OrderIntakeError> {
+        let now = self.clock.now();
+        let book_id = submission.book_id.clone();
+        let expiry = submission.expiry.unwrap_or(NO_EXPIRY);
+        let order = submission.into_order(&self.registry)?.with_received_at(now);
+        self.check_limits(&book_id, &order, now)?;
+        if let Some(rules) = self.size_rules.get(&book_id) {
+            let price = order.price().absolute() as u32;
+            rules.check(price, order.qty()).map_err(OrderIntakeError::SizeRuleBroken)?;
+        }
+
+        let (Some(trader), Some(nonce), Some(signature)) = (order.trader(), order.nonce(), order.signature().to_full()) else {
+            return Err(OrderIntakeError::InvalidSignature);
+        };
+        let digest = self.domain(&book_id).hash_order(&Eip712Order {
+            book: &book_id,
+            trader,
+            price: order.price().0,
+            quantity: order.qty().value(),
+            nonce,
+            expiry,
+        });
+        match recover_prehash(&digest, &signature) {
+            Ok(signer) if signer == trader => Ok(Verification::Verified(VerifiedOrder(order))),
+            _ if self.contract_wallet_books.contains(&book_id) => Ok(Verification::NeedsContractCheck(ContractCheck {
+                order,
+                order_hash: digest,
+            })),
+            _ => Err(OrderIntakeError::InvalidSignature),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::sign_prehash;
+    use k256::ecdsa::SigningKey;
+
+    /// Well-known development key whose address is 0xf39F...2266
+    const TRADER_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const TRADER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    /// Signs `submission` as the development key under `domain`
+    fn sign(mut submission: OrderSubmission, domain: &Eip712Domain) -> OrderSubmission {
+        let key = SigningKey::from_slice(&hex::decode(TRADER_KEY).unwrap()).unwrap();
+        let digest = domain.hash_order(&Eip712Order {
+            book: &submission.book_id,
+            trader: parse_trader(&submission.trader).unwrap_or_default(),
+            price: if submission.side == Some(Side::Sell) { -submission.price } else { submission.price },
+            quantity: submission.quantity,
+            nonce: submission.nonce,
+            expiry: submission.expiry.unwrap_or(NO_EXPIRY),
+        });
+        submission.signature = format!("0x{}", hex::encode(sign_prehash(&key, &digest)));
+        submission
+    }
+
+    /// An OrderIntake with the books the submissions go to registered
+    fn intake() -> OrderIntake {
+        let registry = BookRegistry::new();
+        for book in ["ETH-USD", "BTC-USD"] {
+            registry.register_book(book.to_string()).unwrap();
+        }
+        OrderIntake::new().with_registry(Arc::new(registry))
+    }
+
+    fn signed_submission() -> OrderSubmission {
+        sign(
+            OrderSubmission {
+                book_id: "ETH-USD".to_string(),
+                price: 1000,
+                side: None,
+                quantity: 100,
+                trader: TRADER.to_string(),
+                nonce: 1,
+                expiry: Some(std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() + 3600),  // 1 hour from now
+                signature: String::new(),
+            },
+            &Eip712Domain::default(),
+        )
+    }
 
     #[test]
     fn test_valid_order_submission() {
-        let submission = OrderSubmission {
-            book_id: "ETH-USD".to_string(),
-            price: 1000,
-            quantity: 100,
-            trader: "0x1234567890123456789012345678901234567890".to_string(),
-            nonce: 1,
-            expiry: Some(std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() + 3600),  // 1 hour from now
-            signature: "0x123456789012345678901234567890123456789012345678901234567890123456789012345678901234567890123456789012345".to_string(),
+        let submission = signed_submission();
+        println!("Signature: {}", submission.signature);
+
+        let order = intake().process_submission(submission).unwrap();
+        assert_eq!(order.trader(), Some(parse_trader(TRADER).unwrap()));
+        assert_eq!(order.qty(), Qty(100));
+        assert_eq!(order.book_id(), BookId(0));
+
+        // Only registered books take orders
+        let result = OrderIntake::new().process_submission(signed_submission());
+        assert!(matches!(result, Err(OrderIntakeError::InvalidBookId)));
+    }
+
+    #[test]
+    fn test_no_expiry_settles_as_signed() {
+        use crate::translator::translate_to_settlement;
+
+        let intake = intake();
+        let submit = |price: i32, nonce: u64| {
+            let mut submission = signed_submission();
+            (submission.price, submission.nonce, submission.expiry) = (price, nonce, None);
+            intake.process_submission(sign(submission, &Eip712Domain::default())).unwrap().into_order()
+        };
+        let (maker, taker) = (submit(-1000, 1), submit(1000, 2));
+        assert_eq!(maker.expiry(), Some(NO_EXPIRY));
+
+        // The settlement carries the expiry each side signed, so their signatures still recover
+        let settlement = translate_to_settlement(&maker, &taker, Qty(100), 1000, 1, &MarketConfig::default()).unwrap();
+        for (order, expiry, signature) in [
+            (&maker, settlement.maker_expiration, &settlement.maker_signature),
+            (&taker, settlement.taker_expiration, &settlement.taker_signature),
+        ] {
+            let digest = Eip712Domain::default().hash_order(&Eip712Order {
+                book: "ETH-USD",
+                trader: order.trader().unwrap(),
+                price: order.price().0,
+                quantity: 100,
+                nonce: order.nonce().unwrap(),
+                expiry,
+            });
+            let bytes = [&signature.r[..], &signature.s[..], &[signature.v]].concat();
+            assert_eq!(recover_prehash(&digest, &bytes), Ok(parse_trader(TRADER).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_compact_signature() {
+        let intake = intake();
+        let mut submission = signed_submission();
+        let full = hex::decode(submission.signature.trim_start_matches("0x")).unwrap();
+
+        // EIP-2098 folds v into the top bit of s
+        let mut compact = full[..64].to_vec();
+        compact[32] |= (full[64] - 27) << 7;
+        submission.signature = format!("0x{}", hex::encode(&compact));
+        let order = intake.process_submission(submission).unwrap();
+        assert!(matches!(order.signature(), Signature::Compact64(_)));
+        assert_eq!(order.signature().to_full().unwrap().to_vec(), full);
+
+        for length in [0, 32, 63, 66, 130] {
+            let mut submission = signed_submission();
+            submission.signature = format!("0x{}", "11".repeat(length));
+            let result = intake.process_submission(submission);
+            println!("{} bytes: {:?}", length, result.as_ref().err());
+            assert!(matches!(result, Err(OrderIntakeError::InvalidSignature)), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn test_tampered_submission_rejected() {
+        let intake = intake();
+        type Tamper = fn(&mut OrderSubmission);
+        let tampered: Vec<(&str, Tamper)> = vec![
+            ("price", |s| s.price = 1001),
+            ("side", |s| s.price = -1000),
+            ("quantity", |s| s.quantity = 101),
+            ("nonce", |s| s.nonce = 2),
+            ("expiry", |s| s.expiry = None),
+            ("book", |s| s.book_id = "BTC-USD".to_string()),
+            ("trader", |s| s.trader = "0x1234567890123456789012345678901234567890".to_string()),
+            // Cut to 64 bytes it could pass as a compact signature, so cut one more
+            ("truncated signature", |s| s.signature.truncate(s.signature.len() - 4)),
+        ];
+        for (field, tamper) in tampered {
+            let mut submission = signed_submission();
+            tamper(&mut submission);
+            let result = intake.process_submission(submission);
+            println!("Tampered {}: {:?}", field, result.as_ref().err());
+            assert!(matches!(result, Err(OrderIntakeError::InvalidSignature)), "{}", field);
+        }
+    }
+
+    #[test]
+    fn test_submission_bounds() {
+        let now = 1_700_000_000;
+        let limits = IntakeLimits { max_quantity: 1000, min_expiry_margin_secs: 30 };
+        let mut intake = intake().with_limits(limits).with_clock(Clock::Fixed(now * 1_000_000_000));
+        intake.set_market("BTC-USD", &MarketConfig::builder().require_nonce(true).build());
+        let submission = |change: fn(&mut OrderSubmission)| {
+            let mut submission = signed_submission();
+            change(&mut submission);
+            sign(submission, &Eip712Domain::default())
         };
 
-        let result = OrderIntake::new().process_submission(submission);
-        assert!(result.is_ok());
+        // A side given apart from the price takes it as it is, or negated for a sell
+        let order = intake.process_submission(submission(|s| s.side = Some(Side::Buy))).unwrap();
+        assert_eq!(order.price(), Price(1000));
+        assert_eq!(order.received_at(), now * 1_000_000_000);
+        let order = intake.process_submission(submission(|s| s.side = Some(Side::Sell))).unwrap();
+        assert_eq!(order.price(), Price(-1000));
+        let order = intake.process_submission(submission(|s| s.price = -1000)).unwrap();
+        assert_eq!(order.price(), Price(-1000));
+
+        type Change = fn(&mut OrderSubmission);
+        let cases: Vec<(&str, Change, OrderIntakeError)> = vec![
+            // Would have gone in as an ask when the sign was always the side
+            ("negative buy", |s| (s.side, s.price) = (Some(Side::Buy), -1000), OrderIntakeError::NegativePrice),
+            ("negative sell", |s| (s.side, s.price) = (Some(Side::Sell), -1000), OrderIntakeError::NegativePrice),
+            ("zero price", |s| s.price = 0, OrderIntakeError::InvalidPrice),
+            ("zero sell price", |s| (s.side, s.price) = (Some(Side::Sell), 0), OrderIntakeError::InvalidPrice),
+            ("no absolute value", |s| s.price = i32::MIN, OrderIntakeError::InvalidPrice),
+            ("zero quantity", |s| s.quantity = 0, OrderIntakeError::InvalidQuantity),
+            ("quantity", |s| s.quantity = 1001, OrderIntakeError::QuantityTooLarge { max: 1000 }),
+            ("expired", |s| s.expiry = Some(1_700_000_000 - 1), OrderIntakeError::Expired),
+            ("expiring now", |s| s.expiry = Some(1_700_000_000), OrderIntakeError::Expired),
+            ("expiring soon", |s| s.expiry = Some(1_700_000_029), OrderIntakeError::ExpiryTooSoon { min_expiry: 1_700_000_030 }),
+            ("short trader", |s| s.trader.truncate(40), OrderIntakeError::InvalidTrader),
+            ("long trader", |s| s.trader.push_str("00"), OrderIntakeError::InvalidTrader),
+            ("zero nonce", |s| (s.book_id, s.nonce) = ("BTC-USD".to_string(), 0), OrderIntakeError::NonceRequired),
+        ];
+        for (shape, change, expected) in cases {
+            let result = intake.process_submission(submission(change));
+            println!("{}: {:?}", shape, result.as_ref().err());
+            assert_eq!(result.err(), Some(expected), "{}", shape);
+        }
+
+        // At the bounds is enough, and only markets that ask for a nonce need one
+        let accepted: [Change; 3] = [
+            |s| s.quantity = 1000,
+            |s| s.expiry = Some(1_700_000_030),
+            |s| s.nonce = 0,
+        ];
+        for change in accepted {
+            assert!(intake.process_submission(submission(change)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_contract_wallet_books() {
+        let wallet = "0x5afe5afe5afe5afe5afe5afe5afe5afe5afe5afe";
+        let submission = || {
+            let mut submission = signed_submission();
+            submission.trader = wallet.to_string();
+            sign(submission, &Eip712Domain::default())
+        };
+
+        // The signer is not the wallet, so only a contract-wallet market defers to the chain
+        let mut intake = intake();
+        let result = intake.verify_submission(submission());
+        assert!(matches!(result, Err(OrderIntakeError::InvalidSignature)));
+
+        let market = MarketConfig::builder().signature_type(SIGNATURE_TYPE_EIP1271).build();
+        intake.set_market("ETH-USD", &market);
+        match intake.verify_submission(submission()).unwrap() {
+            Verification::NeedsContractCheck(check) => {
+                assert_eq!(check.order().trader(), Some(parse_trader(wallet).unwrap()));
+                println!("Order hash: 0x{}", hex::encode(check.order_hash()));
+                assert_eq!(check.confirmed().trader(), Some(parse_trader(wallet).unwrap()));
+            }
+            other => panic!("Expected a contract check, got {:?}", other),
+        }
+        assert!(matches!(intake.process_submission(submission()), Err(OrderIntakeError::InvalidSignature)));
+
+        // Keys still sign for themselves in such a market
+        assert!(matches!(intake.verify_submission(signed_submission()), Ok(Verification::Verified(_))));
+    }
+
+    #[test]
+    fn test_domain_per_book() {
+        let other_chain = Eip712Domain {
+            chain_id: 137,
+            ..Eip712Domain::default()
+        };
+        let mut intake = intake();
+        intake.set_domain("ETH-USD", other_chain.clone());
+
+        // A signature for the default domain does not carry over to the book's own
+        let result = intake.process_submission(signed_submission());
+        assert!(matches!(result, Err(OrderIntakeError::InvalidSignature)));
+        let submission = sign(signed_submission(), &other_chain);
+        assert!(intake.process_submission(submission).is_ok());
     }
 
     #[test]
@@ -124,14 +632,56 @@ mod tests {
         let submission = OrderSubmission {
             book_id: "ETH-USD".to_string(),
             price: 1000,
+            side: None,
             quantity: 0,  // Invalid quantity
             trader: "0x1234567890123456789012345678901234567890".to_string(),
             nonce: 1,
             expiry: None,
-            signature: "0x123456789012345678901234567890123456789012345678901234567890123456789012345678901234567890123456789012345".to_string(),
+            signature: "0x1234567890123456789012345678901234567890123456789012345678901234567890123456789012345678901234567890123456789012345678901234567890".to_string(),
         };
 
-        let result = OrderIntake::new().process_submission(submission);
+        let result = intake().process_submission(submission);
         assert!(matches!(result, Err(OrderIntakeError::InvalidQuantity)));
     }
+
+    #[test]
+    fn test_invalid_price() {
+        for price in [0, i32::MIN] {
+            let submission = OrderSubmission {
+                book_id: "ETH-USD".to_string(),
+                price,
+                side: None,
+                quantity: 100,
+                trader: "0x1234567890123456789012345678901234567890".to_string(),
+                nonce: 1,
+                expiry: None,
+                signature: format!("0x{}", "11".repeat(65)),
+            };
+
+            let result = intake().process_submission(submission);
+            println!("Price {}: {:?}", price, result.as_ref().err());
+            assert!(matches!(result, Err(OrderIntakeError::InvalidPrice)));
+        }
+    }
+
+    #[test]
+    fn test_size_rules() {
+        // The signed submission is 100 at 1000, a notional of 100,000
+        let mut intake = intake();
+        let rules = |min_qty, lot_size, min_notional| MarketConfig::builder().size_rules(SizeRules { min_qty, lot_size, min_notional }).build();
+        for config in [rules(100, 1, 0), rules(1, 50, 0), rules(1, 100, 100_000)] {
+            intake.set_market("ETH-USD", &config);
+            assert!(intake.process_submission(signed_submission()).is_ok(), "{:?}", config.size_rules);
+        }
+        for (config, rule) in [
+            (rules(101, 1, 0), SizeRule::MinQty(101)),
+            (rules(1, 30, 0), SizeRule::LotSize(30)),
+            (rules(1, 1, 100_001), SizeRule::MinNotional(100_001)),
+        ] {
+            intake.set_market("ETH-USD", &config);
+            let error = intake.process_submission(signed_submission()).unwrap_err();
+            println!("{}", error);
+            assert!(matches!(error, OrderIntakeError::SizeRuleBroken(broken) if broken == rule));
+        }
+    }
 }
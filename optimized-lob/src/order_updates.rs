@@ -0,0 +1,167 @@
+// order_updates.rs
+
+use crate::{
+    client_order_ids::ClientOrderId,
+    order::OrderId,
+    quantity::Qty,
+    utils::{BookId, ORDER_UPDATE_CHANNEL_CAPACITY},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::broadcast;
+
+/// Lifecycle state of an order as reported to its owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    New,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Expired,
+    SelfTradePrevented,
+    Untriggered, // A stop waiting, outside the book, for a trade at or through its trigger
+    Triggered,   // A stop whose trigger printed; it went in as a market or limit order
+    Parked,      // A pegged order off the book while there is no price for it to peg to
+    BookClosed,  // Cancelled because its book was closed
+}
+
+impl OrderStatus {
+    /// Returns true once the order is done and nothing more will happen to it
+    #[inline]
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled
+                | OrderStatus::Cancelled
+                | OrderStatus::Expired
+                | OrderStatus::SelfTradePrevented
+                | OrderStatus::BookClosed
+        )
+    }
+}
+
+/// A change to one order, shared by the REST responses and the private trader stream.
+/// `filled_qty` is the quantity executed by this update, `remaining_qty` what is left resting.
+/// `filled_notional` sums price × quantity over the fills of the update, in book units, so
+/// `filled_notional / filled_qty` is the average price they executed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub order_id: u64,
+    pub book_id: u32,
+    #[serde(serialize_with = "serialize_trader", deserialize_with = "deserialize_trader")]
+    pub trader: [u8; 20],
+    pub status: OrderStatus,
+    pub filled_qty: u64,
+    pub remaining_qty: u64,
+    #[serde(default)]
+    pub client_order_id: Option<ClientOrderId>, // Set for orders submitted with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<u64>, // When an incoming order arrived, on the update that acknowledges it
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub filled_notional: u64,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+impl OrderUpdate {
+    /// Builds the update for an incoming order once matching is done.
+    /// ## Arguments:
+    /// - `qty`: The submitted quantity of the order.
+    /// - `remaining`: The quantity left after matching, which rests on the book.
+    pub fn taker(
+        order_id: OrderId,
+        book_id: BookId,
+        trader: [u8; 20],
+        qty: Qty,
+        remaining: Qty,
+    ) -> Self {
+        let filled = qty.value() - remaining.value();
+        let status = if remaining.value() == 0 {
+            OrderStatus::Filled
+        } else if filled > 0 {
+            OrderStatus::PartiallyFilled
+        } else {
+            OrderStatus::New
+        };
+        Self {
+            order_id: order_id.0,
+            book_id: book_id.value(),
+            trader,
+            status,
+            filled_qty: filled,
+            remaining_qty: remaining.value(),
+            client_order_id: None,
+            received_at: None,
+            filled_notional: 0,
+        }
+    }
+}
+
+fn serialize_trader<S: Serializer>(trader: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("0x{}", hex::encode(trader)))
+}
+
+fn deserialize_trader<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 20], D::Error> {
+    let address = String::deserialize(deserializer)?;
+    let bytes = hex::decode(address.trim_start_matches("0x")).map_err(serde::de::Error::custom)?;
+    bytes
+        .try_into()
+        .map_err(|_| serde::de::Error::custom("trader address must be 20 bytes"))
+}
+
+/// Fans order updates out to private trader streams.
+/// Like market data, publishing never blocks and is skipped when nobody listens.
+pub struct OrderUpdatePublisher {
+    sender: broadcast::Sender<OrderUpdate>,
+}
+
+impl Default for OrderUpdatePublisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrderUpdatePublisher {
+    /// Creates a publisher with no subscribers.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(ORDER_UPDATE_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Subscribes to every update published after this call, for all traders.
+    pub fn subscribe(&self) -> broadcast::Receiver<OrderUpdate> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes an update.
+    #[inline]
+    pub fn publish(&self, update: OrderUpdate) {
+        if self.sender.receiver_count() > 0 {
+            let _ = self.sender.send(update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_taker_status() {
+        let trader = [0xab; 20];
+        let new = OrderUpdate::taker(OrderId(1), BookId(0), trader, Qty(10), Qty(10));
+        let partial = OrderUpdate::taker(OrderId(1), BookId(0), trader, Qty(10), Qty(4));
+        let filled = OrderUpdate::taker(OrderId(1), BookId(0), trader, Qty(10), Qty(0));
+        assert_eq!(new.status, OrderStatus::New);
+        assert_eq!((partial.status, partial.filled_qty, partial.remaining_qty), (OrderStatus::PartiallyFilled, 6, 4));
+        assert_eq!(filled.status, OrderStatus::Filled);
+
+        let json = serde_json::to_string(&partial).unwrap();
+        println!("{}", json);
+        assert!(json.contains(&format!("\"trader\":\"0x{}\"", "ab".repeat(20))));
+        assert!(json.contains("\"status\":\"partially_filled\""));
+        assert_eq!(serde_json::from_str::<OrderUpdate>(&json).unwrap(), partial);
+    }
+}
@@ -2,19 +2,72 @@
 
 use crate::{
     level::{Level, LevelId, PriceLevel, SortedLevels},
-    order::Order,
+    order::{OidMap, Order, OrderHandle, OrderId},
+    orderbook_manager::OrderBookError,
     pool::LevelPool,
     price::Price,
     quantity::Qty,
-    utils::MAX_LEVELS,
+    utils::{BookId, MAX_LEVELS},
 };
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+
+/// A way a book's levels and the orders resting on them disagree, found by `check_invariants`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantError {
+    /// A level's aggregate size isn't the sum of the orders queued on it, or it is empty
+    LevelSize { level_id: LevelId, price: Price, size: Qty, queued: Qty },
+    /// A level listed on a side has been freed back to the pool
+    FreedLevel { level_id: LevelId, price: Price },
+    /// An order of the book names a level that has been freed
+    OrderOnFreedLevel { order_id: OrderId, level_id: LevelId },
+    /// An order's own price isn't the price of the level it rests on
+    OrderPrice { order_id: OrderId, price: Price, level_price: Price },
+    /// A price or level is listed twice, or a side isn't in price order
+    DuplicateLevel { level_id: LevelId, price: Price },
+    /// The best bid isn't strictly below the best ask
+    Crossed { bid: Price, ask: Price },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvariantError::LevelSize { level_id, price, size, queued } => write!(
+                f,
+                "Level {} at {} has size {} but its orders add up to {}",
+                level_id.value(),
+                price.value(),
+                size.value(),
+                queued.value()
+            ),
+            InvariantError::FreedLevel { level_id, price } => {
+                write!(f, "Level {} at {} is listed but has been freed", level_id.value(), price.value())
+            }
+            InvariantError::OrderOnFreedLevel { order_id, level_id } => {
+                write!(f, "Order {} rests on level {}, which has been freed", order_id.0, level_id.value())
+            }
+            InvariantError::OrderPrice { order_id, price, level_price } => {
+                write!(f, "Order {} has price {} but rests at {}", order_id.0, price.value(), level_price.value())
+            }
+            InvariantError::DuplicateLevel { level_id, price } => {
+                write!(f, "Level {} at {} is listed twice or out of order", level_id.value(), price.value())
+            }
+            InvariantError::Crossed { bid, ask } => {
+                write!(f, "Best bid {} isn't below best ask {}", bid.absolute(), ask.absolute())
+            }
+        }
+    }
+}
 
 /// Represents an order book that holds bids and asks sorted by price levels.
+/// Every change to the book's orders increments its sequence number by one, so market data
+/// can be lined up against a snapshot of the book.
 #[derive(Clone)]
 pub struct OrderBook {
     pub bids: SortedLevels,    // Sorted levels for bid orders.
     pub asks: SortedLevels,    // Sorted levels for ask orders.
     pub level_pool: LevelPool, // Pool for managing price levels.
+    seq: u64,                  // Sequence number of the last change.
 }
 
 impl Default for OrderBook {
@@ -31,13 +84,38 @@ impl OrderBook {
             bids: SortedLevels::new(),
             asks: SortedLevels::new(),
             level_pool: LevelPool::new_with_capacity(MAX_LEVELS),
+            seq: 0,
         }
     }
 
-    /// Adds an order to the order book with the given price and quantity.
+    /// Gets the sequence number of the last change to the book
+    #[inline]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Continues numbering changes after `seq`, e.g. when restoring from a snapshot.
+    #[inline]
+    pub(crate) fn set_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
+    /// Adds an order to the order book with the given price, moving it into `oid_map` and
+    /// queueing it behind the orders already on its level. Returns the handle of the order.
     /// Determines whether the order is a bid or ask and inserts it accordingly.
+    /// Only the display slice of an iceberg order rests on the level; `oid_map` holds the rest.
+    /// Fails with BookFull if the order needs a new level and MAX_LEVELS are in use.
     #[inline]
-    pub fn add_order(&mut self, order: &mut Order, price: Price, qty: Qty) {
+    pub fn add_order(
+        &mut self,
+        oid_map: &mut OidMap,
+        order_id: OrderId,
+        mut order: Order,
+        price: Price,
+    ) -> Result<OrderHandle, OrderBookError> {
+        let iceberg = order.take_slice();
+        let qty = order.qty();
+        order.set_price(price);
         let levels = if price.is_bid() {
             &mut self.bids
         } else {
@@ -69,6 +147,9 @@ impl OrderBook {
         // If the insertion point is not found, insert it at the appropriate position.
         // Do the necessary allocations as well to the level pool.
         if !found_insertion_point {
+            if !self.level_pool.can_alloc(MAX_LEVELS) {
+                return Err(OrderBookError::BookFull);
+            }
             let level_ptr = self.level_pool.alloc();
             order.set_level_id(level_ptr);
             let level = Level::new(price, Qty(0));
@@ -76,25 +157,90 @@ impl OrderBook {
             let px = PriceLevel::new(price, level_ptr);
             levels.insert(insertion_point, px);
         }
-        self.level_pool.get_mut(order.level_id()).unwrap().incr(qty);
+        let level = self.level_mut(order.level_id())?;
+        let size = level.size();
+        level
+            .incr(qty)
+            .ok_or(OrderBookError::QtyOverflow { qty, size })?;
+        level.incr_count();
+
+        let handle = oid_map.insert(order_id, order);
+        if let Some(iceberg) = iceberg {
+            oid_map.set_iceberg(order_id, iceberg);
+        }
+        oid_map.pool_mut().push_back(level, handle);
+        self.seq += 1;
+        Ok(handle)
     }
 
-    /// Reduces the quantity of an existing order in the order book.
+    /// Replaces the used-up display slice of an iceberg with the next one from its reserve.
+    /// The order loses its place and joins the back of its level's queue, like a new arrival.
+    /// Returns the new slice. Fails with QtyExceedsRemaining, leaving the order and level
+    /// untouched, unless `executed` is all the displayed slice had left, and with UnknownOrder
+    /// if the order has no reserve.
     #[inline]
-    pub fn reduce_order(&mut self, order: &mut Order, qty: Qty) {
-        self.level_pool
-            .get_mut(LevelId(order.level_id().value()))
-            .unwrap()
-            .decr(qty);
+    pub fn replenish(&mut self, oid_map: &mut OidMap, handle: OrderHandle, executed: Qty) -> Result<Qty, OrderBookError> {
+        let (order_id, order) = oid_map.get_by_handle(handle).ok_or(OrderBookError::UnknownOrder)?;
+        let (level_id, shown) = (order.level_id(), order.qty());
+        let iceberg = oid_map.iceberg(order_id).copied().ok_or(OrderBookError::UnknownOrder)?;
+        if executed != shown {
+            return Err(OrderBookError::QtyExceedsRemaining { requested: executed, remaining: shown });
+        }
+        let slice = iceberg.display.min(iceberg.reserve);
+        let level = self.level_mut(level_id)?;
+        let size = level.size();
+        let replenished = size
+            .checked_sub(executed)
+            .ok_or(OrderBookError::QtyExceedsRemaining { requested: executed, remaining: size })?
+            .checked_add(slice)
+            .ok_or(OrderBookError::QtyOverflow { qty: slice, size })?;
+        level.set_size(replenished);
+        oid_map.replenish(order_id);
+        let pool = oid_map.pool_mut();
+        pool.unlink(level, handle);
+        pool.push_back(level, handle);
+        self.seq += 1;
+        Ok(slice)
+    }
+
+    /// Reduces the quantity of a resting order, keeping its place in the queue.
+    /// Reducing it by all it has left removes it. Fails with QtyExceedsRemaining, leaving the
+    /// order and level untouched, if `qty` is more than the order or its level has left.
+    #[inline]
+    pub fn reduce_order(&mut self, oid_map: &mut OidMap, handle: OrderHandle, qty: Qty) -> Result<(), OrderBookError> {
+        let order = oid_map.get_mut_by_handle(handle).ok_or(OrderBookError::UnknownOrder)?;
+        let left = order.qty();
+        if qty > left {
+            return Err(OrderBookError::QtyExceedsRemaining { requested: qty, remaining: left });
+        }
+        if qty == left {
+            return self.remove_order(oid_map, handle).map(|_| ());
+        }
+        let level = self.level_mut(order.level_id())?;
+        let remaining = level.size();
+        level
+            .decr(qty)
+            .ok_or(OrderBookError::QtyExceedsRemaining { requested: qty, remaining })?;
+        order.set_qty(left.saturating_sub(qty));
+        self.seq += 1;
+        Ok(())
     }
 
-    /// Removes an order from the order book and deallocates the associated level if it becomes empty.
+    /// Removes a resting order from its level's queue and from `oid_map`, and deallocates the
+    /// level if that was its last order. Returns the removed order.
     #[inline]
-    pub fn remove_order(&mut self, order: &mut Order) {
-        let lvl = self.level_pool.get_mut(order.level_id()).unwrap();
-        lvl.decr(order.qty());
+    pub fn remove_order(&mut self, oid_map: &mut OidMap, handle: OrderHandle) -> Result<(OrderId, Order), OrderBookError> {
+        let (_, order) = oid_map.get_by_handle(handle).ok_or(OrderBookError::UnknownOrder)?;
+        let (level_id, qty) = (order.level_id(), order.qty());
+        let lvl = self.level_mut(level_id)?;
+        let remaining = lvl.size();
+        lvl.decr(qty)
+            .ok_or(OrderBookError::QtyExceedsRemaining { requested: qty, remaining })?;
+        oid_map.pool_mut().unlink(lvl, handle);
+        lvl.decr_count();
+        debug_assert!(lvl.order_count() > 0 || lvl.size().is_empty(), "an empty level has no size left");
 
-        if lvl.size().is_empty() {
+        if lvl.order_count() == 0 {
             let level_price = lvl.price();
             let levels = if level_price.is_bid() {
                 &mut self.bids
@@ -102,8 +248,18 @@ impl OrderBook {
                 &mut self.asks
             };
             levels.remove(level_price);
-            self.level_pool.free(LevelId(order.level_id().value()));
+            self.level_pool.free(level_id)?;
         }
+        self.seq += 1;
+        oid_map.remove_by_handle(handle).ok_or(OrderBookError::UnknownOrder)
+    }
+
+    /// Gets a level an order rests on, which must have been allocated from this book's pool
+    #[inline]
+    fn level_mut(&mut self, level_id: LevelId) -> Result<&mut Level, OrderBookError> {
+        self.level_pool
+            .get_mut(level_id)
+            .ok_or(OrderBookError::UnknownLevel(level_id))
     }
 
     /// Gets the best bid price
@@ -129,4 +285,143 @@ impl OrderBook {
     pub fn get_best_ask_level(&self) -> Option<LevelId> {
         self.asks.get_best_level()
     }
+
+    /// Iterates over the bid levels in price order, best (highest) first
+    #[inline]
+    pub fn iter_bids(&self) -> impl Iterator<Item = &Level> {
+        self.iter_levels(true)
+    }
+
+    /// Iterates over the ask levels in price order, best (lowest) first
+    #[inline]
+    pub fn iter_asks(&self) -> impl Iterator<Item = &Level> {
+        self.iter_levels(false)
+    }
+
+    /// Iterates over the levels of one side in price order, best first
+    /// Levels sort ascending by signed price, so either side's best level is last.
+    #[inline]
+    pub fn iter_levels(&self, is_bid: bool) -> impl Iterator<Item = &Level> {
+        let levels = if is_bid { &self.bids } else { &self.asks };
+        levels
+            .iter()
+            .rev()
+            .filter_map(|level| self.level_pool.get(level.level_id()))
+    }
+
+    /// Checks that the book's levels agree with the orders of `book_id` in `oid_map`, and that
+    /// the best bid is strictly below the best ask. Walks every level and every order, so it is
+    /// meant for tests and the paranoid-checks feature rather than the matching path.
+    pub fn check_invariants(&self, book_id: BookId, oid_map: &OidMap) -> Result<(), InvariantError> {
+        self.check_levels(book_id, oid_map)?;
+        if let (Some(bid), Some(ask)) = (self.get_best_bid(), self.get_best_ask()) {
+            if bid.absolute() >= ask.absolute() {
+                return Err(InvariantError::Crossed { bid, ask });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks everything `check_invariants` does but crossing, which a book in an auction may do
+    pub fn check_levels(&self, book_id: BookId, oid_map: &OidMap) -> Result<(), InvariantError> {
+        let mut listed = HashSet::new();
+        for levels in [&self.bids, &self.asks] {
+            let mut previous: Option<Price> = None;
+            for price_level in levels.iter() {
+                let (level_id, price) = (price_level.level_id(), price_level.price());
+                if !listed.insert(level_id) || previous.is_some_and(|previous| previous >= price) {
+                    return Err(InvariantError::DuplicateLevel { level_id, price });
+                }
+                previous = Some(price);
+                let level = self.level_pool.get(level_id).ok_or(InvariantError::FreedLevel { level_id, price })?;
+                let queued = Qty(oid_map.pool().queue(level).map(|(_, order)| order.qty().value()).sum());
+                if level.size() != queued || level.size().is_empty() {
+                    return Err(InvariantError::LevelSize { level_id, price, size: level.size(), queued });
+                }
+            }
+        }
+        for (order_id, order) in oid_map.iter().filter(|(_, order)| order.book_id() == book_id) {
+            let level = self
+                .level_pool
+                .get(order.level_id())
+                .ok_or(InvariantError::OrderOnFreedLevel { order_id, level_id: order.level_id() })?;
+            if order.price() != level.price() {
+                return Err(InvariantError::OrderPrice { order_id, price: order.price(), level_price: level.price() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists every level of the book with the orders queued on it, best prices first, for
+    /// reports of a broken invariant
+    pub fn describe(&self, oid_map: &OidMap) -> String {
+        let mut report = String::new();
+        for (side, levels) in [("Bids", &self.bids), ("Asks", &self.asks)] {
+            let _ = writeln!(report, "{}:", side);
+            for price_level in levels.iter().rev() {
+                let level_id = price_level.level_id();
+                let _ = write!(report, "  level {} at {}", level_id.value(), price_level.price().value());
+                match self.level_pool.get(level_id) {
+                    Some(level) => {
+                        let _ = write!(report, ", size {}:", level.size().value());
+                        for (order_id, order) in oid_map.pool().queue(level) {
+                            let _ = write!(report, " {}x{}", order_id.0, order.qty().value());
+                        }
+                        let _ = writeln!(report);
+                    }
+                    None => {
+                        let _ = writeln!(report, ", freed");
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Computes the CRC32 checksum of the top `depth` levels of each side, see `checksum_levels`
+    #[inline]
+    pub fn checksum(&self, depth: usize) -> u32 {
+        let side = |is_bid| {
+            self.iter_levels(is_bid)
+                .take(depth)
+                .map(|level| (level.price().absolute() as u32, level.size().value()))
+        };
+        checksum_levels(side(true), side(false))
+    }
+}
+
+/// Computes the CRC32 (IEEE) checksum clients use to verify their copy of a book.
+/// The checksummed string lists the bids then the asks, best price first, each level written
+/// as `price:size` in decimal and separated by commas, with a `|` between the sides. Bids of
+/// 5 at 100 and 7 at 99 against an ask of 3 at 101 give `100:5,99:7|101:3`, and an empty book `|`.
+/// The string is fed to the hasher as it is written, nothing is allocated.
+pub fn checksum_levels(
+    bids: impl Iterator<Item = (u32, u64)>,
+    asks: impl Iterator<Item = (u32, u64)>,
+) -> u32 {
+    let mut writer = ChecksumWriter(crc32fast::Hasher::new());
+    writer.side(bids);
+    writer.0.update(b"|");
+    writer.side(asks);
+    writer.0.finalize()
+}
+
+/// Feeds formatted text straight into a CRC32 hasher
+struct ChecksumWriter(crc32fast::Hasher);
+
+impl ChecksumWriter {
+    fn side(&mut self, levels: impl Iterator<Item = (u32, u64)>) {
+        for (i, (price, size)) in levels.enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(self, "{}{}:{}", separator, price, size);
+        }
+    }
+}
+
+impl Write for ChecksumWriter {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0.update(s.as_bytes());
+        Ok(())
+    }
 }
@@ -1,18 +1,148 @@
 // orderbook_manager.rs
 
 use crate::{
-    level::LevelId,
-    order::{OidMap, Order, OrderId},
-    orderbook::OrderBook,
+    client_order_ids::{ClientOrderId, ClientOrderIds},
+    events::{CancelReason, EventSink, NoopSink, OrderBookEvent},
+    level::{Level, LevelId},
+    market_data::MarketDataPublisher,
+    order::{Iceberg, OidMap, Order, OrderHandle, OrderId, RestingOrder, Signature, SignedMeta},
+    order_updates::{OrderStatus, OrderUpdate, OrderUpdatePublisher},
+    orderbook::{checksum_levels, OrderBook},
     price::Price,
     quantity::Qty,
-    utils::{BookId, MAX_BOOKS},
+    risk::{notional, OpenOrderTracker},
+    utils::{BookId, CHECKSUM_DEPTH, MAX_BOOKS, MAX_LEVELS},
 };
+use std::fmt;
+
+/// Errors returned by order book operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrderBookError {
+    UnknownOrder,
+    DuplicateOrder(OrderId),
+    UnknownBook(BookId),
+    BookOutOfRange(BookId),
+    NoMarket(BookId), // The book has no market configuration and wasn't created book-only
+    BookFull,
+    UnknownLevel(LevelId),
+    QtyExceedsRemaining { requested: Qty, remaining: Qty },
+    QtyOverflow { qty: Qty, size: Qty },
+    InvalidPrice(u32),
+    PriceOutsideBand { price: u32, low: u32, high: u32 },
+    BookInAuction(BookId),
+    NotInAuction(BookId),
+    NoPegReference(BookId),
+    InvalidOco,
+    CrossedQuote(BookId), // A two-sided quote whose bid is at or above its ask
+    PositionsNotTracked(BookId),
+    NoPositionToReduce(BookId),
+    Halted,
+    BookCrossed(BookId), // The book was found crossed and stays halted until repaired
+    ShuttingDown,
+    DuplicateClientOrderId(ClientOrderId),
+}
+
+impl fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderBookError::UnknownOrder => write!(f, "Unknown order"),
+            OrderBookError::DuplicateOrder(order_id) => write!(f, "Order {} already exists", order_id.0),
+            OrderBookError::UnknownBook(book_id) => write!(f, "Book {} doesn't exist", book_id.value()),
+            OrderBookError::BookOutOfRange(book_id) => {
+                write!(f, "Book {} is out of range (at most {} books)", book_id.value(), MAX_BOOKS)
+            }
+            OrderBookError::NoMarket(book_id) => {
+                write!(f, "Book {} has no market configuration and doesn't trade book-only", book_id.value())
+            }
+            OrderBookError::BookFull => write!(f, "Book has no room for another price level"),
+            OrderBookError::UnknownLevel(level_id) => write!(f, "Level {} doesn't exist", level_id.value()),
+            OrderBookError::QtyExceedsRemaining { requested, remaining } => write!(
+                f,
+                "Quantity {} exceeds the remaining quantity {}",
+                requested.value(),
+                remaining.value()
+            ),
+            OrderBookError::QtyOverflow { qty, size } => write!(
+                f,
+                "Quantity {} doesn't fit on a level of size {}",
+                qty.value(),
+                size.value()
+            ),
+            OrderBookError::InvalidPrice(price) => write!(f, "Price {} is out of range", price),
+            OrderBookError::PriceOutsideBand { price, low, high } => {
+                write!(f, "Price {} is outside the price band [{}, {}]", price, low, high)
+            }
+            OrderBookError::BookInAuction(book_id) => write!(f, "Book {} is in an auction", book_id.value()),
+            OrderBookError::NotInAuction(book_id) => write!(f, "Book {} is not in an auction", book_id.value()),
+            OrderBookError::NoPegReference(book_id) => {
+                write!(f, "Book {} has no price for the order to peg to", book_id.value())
+            }
+            OrderBookError::InvalidOco => write!(f, "The legs of an OCO pair must be two orders in one book"),
+            OrderBookError::CrossedQuote(book_id) => {
+                write!(f, "The bid of a quote in book {} must be below its ask", book_id.value())
+            }
+            OrderBookError::PositionsNotTracked(book_id) => {
+                write!(f, "Book {} doesn't track positions, so orders can't be reduce-only", book_id.value())
+            }
+            OrderBookError::NoPositionToReduce(book_id) => {
+                write!(f, "The trader has no position in book {} for the order to reduce", book_id.value())
+            }
+            OrderBookError::Halted => write!(f, "Trading is halted; only cancels are accepted"),
+            OrderBookError::BookCrossed(book_id) => {
+                write!(f, "Book {} is halted after it was found crossed; only cancels are accepted", book_id.value())
+            }
+            OrderBookError::ShuttingDown => write!(f, "The server is shutting down; only cancels are accepted"),
+            OrderBookError::DuplicateClientOrderId(client_order_id) => {
+                write!(f, "Client order ID {} is already in use by a working order", client_order_id)
+            }
+        }
+    }
+}
+
+/// Aggregated levels of a book, best prices first on both sides.
+/// Each level is (price, aggregate size, number of resting orders); prices are always positive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Depth {
+    pub bids: Vec<(u32, u64, u32)>,
+    pub asks: Vec<(u32, u64, u32)>,
+    pub checksum: u32, // Checksum of the top CHECKSUM_DEPTH levels, however many are listed
+    pub seq: u64,      // Sequence number of the book's last change
+}
+
+impl Default for Depth {
+    /// The depth of an empty book
+    fn default() -> Self {
+        Self {
+            bids: Vec::new(),
+            asks: Vec::new(),
+            checksum: checksum_levels(std::iter::empty(), std::iter::empty()),
+            seq: 0,
+        }
+    }
+}
+
+/// The expected result of a taker order walking a book from its best price, as of now.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FillEstimate {
+    pub filled_qty: Qty,
+    pub notional: u128,            // Sum of price times quantity over the levels taken from.
+    pub vwap: Option<f64>,         // Volume-weighted average price; None when nothing fills.
+    pub worst_price: Option<u32>,  // Price of the last level touched.
+    pub levels: Vec<(u32, u64)>,   // Price and quantity taken from each level touched, best first.
+    pub exhausted: bool,           // The book ran out before the full quantity filled.
+}
 
 /// Manages multiple order books and orders.
 pub struct OrderBookManager {
-    pub books: Vec<Option<OrderBook>>, // A mapping of book IDs to order books.
+    books: Vec<Option<OrderBook>>,     // Indexed by BookId; grows as books are created, up to MAX_BOOKS.
     pub oid_map: OidMap,               // A mapping of order IDs to order objects.
+    pub market_data: MarketDataPublisher, // Publishes level changes to market data subscribers.
+    pub order_updates: OrderUpdatePublisher, // Publishes order lifecycle changes to their owners.
+    pub event_sink: Box<dyn EventSink>, // Receives every state change as an OrderBookEvent.
+    pub open_orders: OpenOrderTracker, // Counts what each trader has resting, for risk limits.
+    pub client_order_ids: ClientOrderIds, // Client order IDs of working orders, stamped on their updates.
+    event_seq: u64,                     // Sequence number of the last emitted event.
+    auto_create_books: bool,            // Orders for a book that doesn't exist create it, instead of failing.
 }
 
 impl Default for OrderBookManager {
@@ -26,12 +156,162 @@ impl OrderBookManager {
     #[inline]
     pub fn new() -> Self {
         Self {
-            books: vec![None; MAX_BOOKS],
+            books: Vec::new(),
             oid_map: OidMap::new(),
+            market_data: MarketDataPublisher::new(),
+            order_updates: OrderUpdatePublisher::new(),
+            event_sink: Box::new(NoopSink),
+            open_orders: OpenOrderTracker::new(),
+            client_order_ids: ClientOrderIds::new(),
+            event_seq: 0,
+            auto_create_books: false,
+        }
+    }
+
+    /// Creates an OrderBookManager that creates the book of any order added to a book that
+    /// doesn't exist yet, as it did before books had to be created first.
+    pub fn auto_creating() -> Self {
+        Self { auto_create_books: true, ..Self::new() }
+    }
+
+    /// Whether orders for a book that doesn't exist create it, see `auto_creating`
+    pub fn auto_creates_books(&self) -> bool {
+        self.auto_create_books
+    }
+
+    pub fn set_auto_create_books(&mut self, auto_create_books: bool) {
+        self.auto_create_books = auto_create_books;
+    }
+
+    /// Gets the book of `book_id`, if it has been created.
+    #[inline]
+    pub fn book(&self, book_id: BookId) -> Option<&OrderBook> {
+        self.books.get(book_id.value() as usize)?.as_ref()
+    }
+
+    /// Creates the book of `book_id` unless it exists, and returns it.
+    /// BookIds at or above MAX_BOOKS are out of range.
+    pub fn create_book(&mut self, book_id: BookId) -> Result<&mut OrderBook, OrderBookError> {
+        let idx = book_id.value() as usize;
+        if idx >= MAX_BOOKS {
+            return Err(OrderBookError::BookOutOfRange(book_id));
         }
+        if idx >= self.books.len() {
+            self.books.resize(idx + 1, None);
+        }
+        Ok(self.books[idx].get_or_insert_with(OrderBook::new))
+    }
+
+    /// Drops the book of `book_id`, e.g. one created for a registration taken back.
+    /// Only an empty book is removed; one with resting orders is left as it is.
+    pub fn remove_book(&mut self, book_id: BookId) -> Option<OrderBook> {
+        let slot = self.books.get_mut(book_id.value() as usize)?;
+        let book = slot.as_ref()?;
+        if book.get_best_bid().is_some() || book.get_best_ask().is_some() {
+            return None;
+        }
+        slot.take()
+    }
+
+    /// Cancels every resting order of a book, telling each owner its book closed, then drops the
+    /// book and with it its levels. Returns the IDs of the cancelled orders, or None if the book
+    /// hasn't been created.
+    pub fn close_book(&mut self, book_id: BookId) -> Option<Vec<OrderId>> {
+        self.book(book_id)?;
+        let cancelled = self.cancel_where(OrderStatus::BookClosed, |order, _| order.book_id() == book_id);
+        self.remove_book(book_id);
+        Some(cancelled)
+    }
+
+    /// Gets the book of `book_id` out of `books`, or UnknownBook if it hasn't been created.
+    /// Takes the field rather than self so an order borrowed from the OidMap can be passed along.
+    #[inline]
+    fn book_mut(books: &mut [Option<OrderBook>], book_id: BookId) -> Result<&mut OrderBook, OrderBookError> {
+        books
+            .get_mut(book_id.value() as usize)
+            .and_then(Option::as_mut)
+            .ok_or(OrderBookError::UnknownBook(book_id))
+    }
+
+    /// Gets the book of `book_id` for a test to corrupt, bypassing every check.
+    #[cfg(test)]
+    pub(crate) fn book_mut_unchecked(&mut self, book_id: BookId) -> &mut OrderBook {
+        Self::book_mut(&mut self.books, book_id).expect("book exists")
+    }
+
+    /// Panics with a report of the book if a change left its levels inconsistent with its orders.
+    /// Only built with the paranoid-checks feature in debug builds. Books cross during auctions,
+    /// so crossing is left to MatchingEngine, which knows when a book may.
+    #[cfg(all(feature = "paranoid-checks", debug_assertions))]
+    fn check_book(&self, book_id: BookId, operation: &str) {
+        let Some(book) = self.book(book_id) else {
+            return;
+        };
+        if let Err(error) = book.check_levels(book_id, &self.oid_map) {
+            panic!("Book {} broke an invariant after {}: {}\n{}", book_id.value(), operation, error, book.describe(&self.oid_map));
+        }
+    }
+
+    #[cfg(not(all(feature = "paranoid-checks", debug_assertions)))]
+    #[inline(always)]
+    fn check_book(&self, _book_id: BookId, _operation: &str) {}
+
+    /// Iterates over the created books in BookId order.
+    pub fn books(&self) -> impl Iterator<Item = (BookId, &OrderBook)> {
+        self.books
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, book)| Some((BookId(idx as u32), book.as_ref()?)))
+    }
+
+    /// Replaces the sink that receives OrderBookEvents, returning the previous one.
+    pub fn set_event_sink(&mut self, sink: Box<dyn EventSink>) -> Box<dyn EventSink> {
+        std::mem::replace(&mut self.event_sink, sink)
+    }
+
+    /// Publishes an order update to the order's owner, stamped with the order's client order ID
+    /// An order that is done gives its client order ID up, so its trader can use it again.
+    #[inline]
+    pub(crate) fn publish_update(&mut self, mut update: OrderUpdate) {
+        let order_id = OrderId(update.order_id);
+        update.client_order_id = if update.status.is_final() {
+            self.client_order_ids.remove(order_id)
+        } else {
+            self.client_order_ids.get(order_id)
+        };
+        self.order_updates.publish(update);
+    }
+
+    /// Gets the sequence number of the last emitted event.
+    #[inline]
+    pub fn event_seq(&self) -> u64 {
+        self.event_seq
+    }
+
+    /// Continues event numbering after `seq`, e.g. when restoring from a snapshot.
+    #[inline]
+    pub(crate) fn set_event_seq(&mut self, seq: u64) {
+        self.event_seq = seq;
+    }
+
+    /// Gets the sequence number of the last change to a book, 0 if it hasn't been created.
+    #[inline]
+    pub fn book_seq(&self, book_id: BookId) -> u64 {
+        self.book(book_id).map_or(0, OrderBook::seq)
+    }
+
+    /// Stamps an event with the next sequence number and its book's current one, and hands it to the sink.
+    #[inline]
+    pub(crate) fn emit(&mut self, book_id: BookId, event: impl FnOnce(u64, u64) -> OrderBookEvent) {
+        self.event_seq += 1;
+        let book_seq = self.book_seq(book_id);
+        self.event_sink.on_event(&event(self.event_seq, book_seq));
     }
 
     /// Adds a new order to the order book based on the provided parameters.
+    /// Returns the handle of the new order.
+    /// Nothing is added if `order_id` is already resting, the book hasn't been created (unless the
+    /// manager is `auto_creating`), the price doesn't fit in an i32, or the book has no room for the order.
     /// ## Arguments:
     /// - `order_id`: The order ID for the order. Represented as unique reference number.
     /// - `book_id`: The identifier for the book where the order will be placed. Represents as stock locate.
@@ -45,7 +325,9 @@ impl OrderBookManager {
     ///
     /// ## Example:
     /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
     /// let mut orderbook_manager = OrderBookManager::new();
+    /// orderbook_manager.create_book(BookId(0)).unwrap();
     ///
     /// orderbook_manager.add_order(
     ///     OrderId(0), // Order ID
@@ -57,9 +339,10 @@ impl OrderBookManager {
     ///     Some(123456), // Nonce
     ///     Some(1682534400), // Expiry
     ///     Some([0; 65]), // Signature
-    /// );
+    /// ).unwrap();
     /// ```
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     pub fn add_order(
         &mut self,
         order_id: OrderId,
@@ -70,114 +353,406 @@ impl OrderBookManager {
         trader: Option<[u8; 20]>,
         nonce: Option<u64>,
         expiry: Option<u64>,
-        signature: Option<[u8; 65]>,
-    ) {
-        let price_i32 = if is_bid {
-            price32 as i32
-        } else {
-            -(price32 as i32)
-        };
-
-        // Create a Price(i32) from the adjusted price_i32.
-        let price = Price(price_i32);
-
-        self.oid_map.reserve(order_id);
+        signature: impl Into<Signature>,
+    ) -> Result<OrderHandle, OrderBookError> {
+        let order = Order::new(qty, LevelId(0), book_id, trader, nonce, expiry, signature);
+        self.rest_order(order_id, order, price32, is_bid)
+    }
 
-        let mut order = Order::new(qty, LevelId(0), book_id, trader, nonce, expiry, signature);
+    /// Adds an order built by the caller to the book it names, like `add_order`.
+    /// This is how an iceberg order, with a display quantity, goes on the book: only its display
+    /// slice shows in depth, and each time a slice fully executes the next joins the back of the queue.
+    #[inline]
+    pub fn rest_order(
+        &mut self,
+        order_id: OrderId,
+        order: Order,
+        price32: u32,
+        is_bid: bool,
+    ) -> Result<OrderHandle, OrderBookError> {
+        let price = Price::from_u32(price32, is_bid).ok_or(OrderBookError::InvalidPrice(price32))?;
+        if self.oid_map.get(order_id).is_some() {
+            return Err(OrderBookError::DuplicateOrder(order_id));
+        }
+        // Create the book if it doesn't exist yet and that's allowed; this fails before anything is touched.
+        let book_id = order.book_id();
+        if self.auto_create_books {
+            self.create_book(book_id)?;
+        }
+        let (qty, trader, display, meta) = (order.qty(), order.trader(), order.display(), *order.meta());
+        let handle = Self::book_mut(&mut self.books, book_id)?.add_order(&mut self.oid_map, order_id, order, price)?;
+        self.check_book(book_id, "adding an order");
 
-        // Check if the book for the given book_id exists; if not, create it.
-        if self.books[book_id.value() as usize].is_none() {
-            self.books[book_id.value() as usize] = Some(OrderBook::new());
+        if let Some(level_id) = self.oid_map.get_by_handle(handle).map(|(_, order)| order.level_id()) {
+            self.publish_level(book_id, price, level_id);
         }
-        if let Some(orderbook) = self.books.get_mut(book_id.value() as usize).unwrap() {
-            orderbook.add_order(&mut order, price, qty);
+        if let Some(trader) = trader {
+            let total = self.oid_map.total_qty(order_id).unwrap_or(qty);
+            self.open_orders.insert(order_id, trader, book_id, price32, total);
         }
-        self.oid_map.insert(order_id, &order);
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderAdded {
+            seq,
+            book_seq,
+            order_id,
+            book_id,
+            price: price32,
+            is_bid,
+            qty,
+            trader,
+            nonce: meta.nonce,
+            expiry: meta.expiry,
+            signature: meta.signature,
+            display,
+        });
+        Ok(handle)
     }
 
     /// Removes an order from the order book based on its order ID.
+    /// Fails with UnknownOrder if the order isn't resting.
     /// ## Arguments:
     /// - `order_id`: The order ID for the order. Represented as unique reference number.
     /// ## Example:
     /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::{OrderBookError, OrderBookManager}, quantity::Qty, utils::BookId};
     /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
     ///
-    /// orderbook.remove_order(OrderId(0));
+    /// assert_eq!(orderbook_manager.remove_order(OrderId(0)), Ok(()));
+    /// assert_eq!(orderbook_manager.remove_order(OrderId(0)), Err(OrderBookError::UnknownOrder));
     /// ```
     #[inline]
-    pub fn remove_order(&mut self, order_id: OrderId) {
-        if let Some(order) = self.oid_map.get_mut(order_id) {
-            if let Some(orderbook) = self
-                .books
-                .get_mut(order.book_id().value() as usize)
-                .unwrap()
-            {
-                orderbook.remove_order(order);
-            }
-        }
-        self.oid_map.remove(order_id);
+    pub fn remove_order(&mut self, order_id: OrderId) -> Result<(), OrderBookError> {
+        let order = self.oid_map.get(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let book_id = order.book_id();
+        let cancelled_qty = self.oid_map.total_qty(order_id).unwrap_or_default();
+        self.detach_order(order_id)?;
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderCancelled {
+            seq,
+            book_seq,
+            order_id,
+            book_id,
+            cancelled_qty,
+            remaining_qty: Qty(0),
+            reason: CancelReason::Requested,
+        });
+        Ok(())
+    }
+
+    /// Gets the OrderId a handle refers to.
+    /// Fails with UnknownOrder if that order has left the book, even if its slot was reused.
+    #[inline]
+    pub fn resolve_handle(&self, handle: OrderHandle) -> Result<OrderId, OrderBookError> {
+        self.oid_map
+            .get_by_handle(handle)
+            .map(|(order_id, _)| order_id)
+            .ok_or(OrderBookError::UnknownOrder)
+    }
+
+    /// Removes the order a handle refers to, like `remove_order`.
+    /// A stale handle fails with UnknownOrder rather than removing the slot's new occupant.
+    #[inline]
+    pub fn remove_order_by_handle(&mut self, handle: OrderHandle) -> Result<(), OrderBookError> {
+        self.remove_order(self.resolve_handle(handle)?)
+    }
+
+    /// Takes an order off its book without emitting an event, returning it
+    #[inline]
+    fn detach_order(&mut self, order_id: OrderId) -> Result<Order, OrderBookError> {
+        let handle = self.oid_map.handle(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let (book_id, level_id, price, _) = self.resting(handle)?;
+        let (_, order) = Self::book_mut(&mut self.books, book_id)?.remove_order(&mut self.oid_map, handle)?;
+        self.check_book(book_id, "removing an order");
+        self.open_orders.update(order_id, Qty(0));
+        self.publish_level(book_id, price, level_id);
+        Ok(order)
+    }
+
+    /// Gets the book, level, price, and quantity left of the order a handle refers to
+    #[inline]
+    fn resting(&self, handle: OrderHandle) -> Result<(BookId, LevelId, Price, Qty), OrderBookError> {
+        let (_, order) = self.oid_map.get_by_handle(handle).ok_or(OrderBookError::UnknownOrder)?;
+        let (book_id, level_id) = (order.book_id(), order.level_id());
+        let level = self
+            .book(book_id)
+            .ok_or(OrderBookError::UnknownBook(book_id))?
+            .level_pool
+            .get(level_id)
+            .ok_or(OrderBookError::UnknownLevel(level_id))?;
+        Ok((book_id, level_id, level.price(), order.qty()))
     }
 
     /// Cancels an order by reducing its quantity in the order book.
+    /// Cancelling all it has left removes the order. Fails with UnknownOrder if the order isn't
+    /// resting, or QtyExceedsRemaining if `qty` is more than it has left.
+    /// An iceberg is reduced in its displayed slice; cancelling all of that cancels its reserve too.
     /// ## Arguments:
     /// - `order_id`: The order ID for the order. Represented as unique reference number.
     /// - `qty`: The quantity of the order to be cancelled. Represented as shares in the orderbook.
     /// ## Example:
     /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
     /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(300), 600, true, None, None, None, None).unwrap();
     ///
-    /// orderbook.cancel_order(OrderId(0), Qty(100));
+    /// orderbook_manager.cancel_order(OrderId(0), Qty(100)).unwrap();
     /// ```
     #[inline]
-    pub fn cancel_order(&mut self, order_id: OrderId, qty: Qty) {
-        if let Some(order) = self.oid_map.get_mut(order_id) {
-            if let Some(orderbook) = self
-                .books
-                .get_mut(order.book_id().value() as usize)
-                .unwrap()
-            {
-                orderbook.reduce_order(order, qty);
-            }
+    pub fn cancel_order(&mut self, order_id: OrderId, qty: Qty) -> Result<(), OrderBookError> {
+        let handle = self.oid_map.handle(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let reserve = self.reserve(order_id);
+        let (book_id, level_id, price, before) = self.resting(handle)?;
+        Self::book_mut(&mut self.books, book_id)?.reduce_order(&mut self.oid_map, handle, qty)?;
+        self.check_book(book_id, "reducing an order");
+        let (cancelled_qty, remaining_qty) = if qty == before {
+            (qty.saturating_add(reserve), Qty(0))
+        } else {
+            (qty, before.saturating_sub(qty).saturating_add(reserve))
+        };
+        self.open_orders.update(order_id, remaining_qty);
+        self.publish_level(book_id, price, level_id);
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderCancelled {
+            seq,
+            book_seq,
+            order_id,
+            book_id,
+            cancelled_qty,
+            remaining_qty,
+            reason: CancelReason::Requested,
+        });
+        Ok(())
+    }
+
+    /// Gets the hidden reserve of an order, zero unless it is an iceberg
+    #[inline]
+    fn reserve(&self, order_id: OrderId) -> Qty {
+        self.oid_map.iceberg(order_id).map_or(Qty(0), |iceberg| iceberg.reserve)
+    }
+
+    /// Cancels whatever quantity an order has left, removing it from the book.
+    /// Returns the cancelled quantity. Fails with UnknownOrder if the order isn't resting.
+    /// ## Arguments:
+    /// - `order_id`: The order ID for the order. Represented as unique reference number.
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
+    /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(300), 600, true, None, None, None, None).unwrap();
+    ///
+    /// assert_eq!(orderbook_manager.cancel_remaining(OrderId(0)), Ok(Qty(300)));
+    /// ```
+    #[inline]
+    pub fn cancel_remaining(&mut self, order_id: OrderId) -> Result<Qty, OrderBookError> {
+        let shown = self.oid_map.get(order_id).ok_or(OrderBookError::UnknownOrder)?.qty();
+        let qty = shown.saturating_add(self.reserve(order_id));
+        self.cancel_order(order_id, shown)?;
+        Ok(qty)
+    }
+
+    /// Cuts a resting order down to `qty` left in all, keeping its place in the queue.
+    /// An iceberg gives up its hidden reserve before its displayed slice; cutting to zero removes
+    /// the order. Does nothing if the order has no more than `qty` left, and fails with
+    /// UnknownOrder if it isn't resting.
+    pub fn reduce_to(&mut self, order_id: OrderId, qty: Qty) -> Result<(), OrderBookError> {
+        let order = self.oid_map.get(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let (book_id, shown, reserve) = (order.book_id(), order.qty(), self.reserve(order_id));
+        let total = shown.saturating_add(reserve);
+        if qty.value() == 0 {
+            return self.cancel_order(order_id, shown);
+        }
+        let Some(cut) = total.checked_sub(qty).filter(|cut| !cut.is_empty()) else {
+            return Ok(());
+        };
+        let from_reserve = cut.min(reserve);
+        if let Some(&iceberg) = self.oid_map.iceberg(order_id) {
+            let reserve = reserve.saturating_sub(from_reserve);
+            self.oid_map.set_iceberg(order_id, Iceberg { reserve, ..iceberg });
+        }
+        let from_shown = cut.saturating_sub(from_reserve);
+        if !from_shown.is_empty() {
+            return self.cancel_order(order_id, from_shown);
         }
-        self.oid_map.update_qty(order_id, qty);
+        self.open_orders.update(order_id, qty);
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderCancelled {
+            seq,
+            book_seq,
+            order_id,
+            book_id,
+            cancelled_qty: cut,
+            remaining_qty: qty,
+            reason: CancelReason::Requested,
+        });
+        Ok(())
     }
 
     /// Executes an order by either removing it completely or reducing its quantity.
+    /// Returns the executed quantity. Fails with UnknownOrder if the order isn't resting, or
+    /// QtyExceedsRemaining if `qty` is more than it has left.
+    /// Executing all of an iceberg's displayed slice shows the next one from its reserve at the
+    /// back of the level's queue; only the displayed slice can execute at once.
     /// ## Arguments:
     /// - `order_id`: The order ID for the order. Represented as unique reference number.
     /// - `qty`: The quantity of the order to be executed. Represented as shares in the orderbook.
     /// ## Example:
     /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
     /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
     ///
-    /// orderbook.execute_order(OrderId(0), Qty(100));
+    /// assert_eq!(orderbook_manager.execute_order(OrderId(0), Qty(100)), Ok(Qty(100)));
     /// ```
     #[inline]
-    pub fn execute_order(&mut self, order_id: OrderId, qty: Qty) {
-        if let Some(order) = self.oid_map.get_mut(order_id) {
-            if order.qty() == qty {
-                if let Some(orderbook) = self
-                    .books
-                    .get_mut(order.book_id().value() as usize)
-                    .unwrap()
-                {
-                    orderbook.remove_order(order);
-                }
-                self.oid_map.remove(order_id);
+    pub fn execute_order(&mut self, order_id: OrderId, qty: Qty) -> Result<Qty, OrderBookError> {
+        let handle = self.oid_map.handle(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        self.execute_order_by_handle(handle, qty)
+    }
+
+    /// Executes an order like `execute_order`, for a trade at `exec_price` rather than the order's own
+    /// price; its owner's update reports the fill at that price.
+    #[inline]
+    pub fn execute_order_at(&mut self, order_id: OrderId, qty: Qty, exec_price: u32) -> Result<Qty, OrderBookError> {
+        let handle = self.oid_map.handle(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        self.execute(handle, qty, Some(exec_price))
+    }
+
+    /// Executes the order a handle refers to, like `execute_order`.
+    /// A stale handle fails with UnknownOrder rather than filling the slot's new occupant.
+    #[inline]
+    pub fn execute_order_by_handle(&mut self, handle: OrderHandle, qty: Qty) -> Result<Qty, OrderBookError> {
+        self.execute(handle, qty, None)
+    }
+
+    fn execute(&mut self, handle: OrderHandle, qty: Qty, exec_price: Option<u32>) -> Result<Qty, OrderBookError> {
+        let (order_id, order) = self.oid_map.get_by_handle(handle).ok_or(OrderBookError::UnknownOrder)?;
+        let trader = order.trader();
+        let reserve = self.reserve(order_id);
+        let (book_id, level_id, price, before) = self.resting(handle)?;
+        let book = Self::book_mut(&mut self.books, book_id)?;
+        let replenished = if qty == before && !reserve.is_empty() {
+            Some(book.replenish(&mut self.oid_map, handle, qty)?)
+        } else {
+            book.reduce_order(&mut self.oid_map, handle, qty)?;
+            None
+        };
+        self.check_book(book_id, "executing an order");
+        let remaining_qty = before.saturating_sub(qty).saturating_add(reserve);
+        self.open_orders.update(order_id, remaining_qty);
+
+        if let Some(trader) = trader {
+            let status = if remaining_qty.is_empty() {
+                OrderStatus::Filled
             } else {
-                if let Some(orderbook) = self
-                    .books
-                    .get_mut(order.book_id().value() as usize)
-                    .unwrap()
-                {
-                    orderbook.reduce_order(order, qty);
-                }
-                self.oid_map.update_qty(order_id, qty);
-            }
+                OrderStatus::PartiallyFilled
+            };
+            self.publish_update(OrderUpdate {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                trader,
+                status,
+                filled_qty: qty.value(),
+                remaining_qty: remaining_qty.value(),
+                client_order_id: None,
+                received_at: None,
+                filled_notional: notional(exec_price.unwrap_or(price.absolute() as u32), qty),
+            });
+        }
+        self.publish_level(book_id, price, level_id);
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderExecuted {
+            seq,
+            book_seq,
+            order_id,
+            book_id,
+            exec_qty: qty,
+            remaining_qty,
+        });
+        if let Some(qty) = replenished {
+            self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderReplenished { seq, book_seq, order_id, book_id, qty });
+        }
+        Ok(qty)
+    }
+
+    /// Publishes the current aggregate size of a level to market data subscribers
+    /// A level that was freed because its last order left is published with a size of zero.
+    #[inline]
+    fn publish_level(&mut self, book_id: BookId, price: Price, level_id: LevelId) {
+        if !self.market_data.has_subscribers() {
+            return;
+        }
+        let Some(book) = self.books.get(book_id.value() as usize).and_then(Option::as_ref) else {
+            return;
+        };
+        let seq = book.seq();
+        let size = book.level_pool.get(level_id).map_or(Qty(0), |level| level.size());
+        let published = self.market_data.publish_level(book_id, seq, price, size);
+        debug_assert!(published.is_ok(), "{:?}", published);
+
+        if self.market_data.checksum_due(book_id) {
+            let published = self.market_data.publish_checksum(book_id, seq, book.checksum(CHECKSUM_DEPTH));
+            debug_assert!(published.is_ok(), "{:?}", published);
         }
     }
 
+    /// Removes every resting order owned by a trader, optionally scoped to a single book.
+    /// Returns the IDs of the cancelled orders.
+    /// ## Arguments:
+    /// - `trader`: Ethereum address of the trader whose orders are cancelled.
+    /// - `book_id`: Restricts the cancellation to one book when provided.
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
+    /// let mut orderbook_manager = OrderBookManager::new();
+    ///
+    /// let cancelled = orderbook_manager.cancel_all_for_trader([0; 20], Some(BookId(0)));
+    /// ```
+    pub fn cancel_all_for_trader(
+        &mut self,
+        trader: [u8; 20],
+        book_id: Option<BookId>,
+    ) -> Vec<OrderId> {
+        self.cancel_where(OrderStatus::Cancelled, |order, _| {
+            order.trader() == Some(trader) && book_id.is_none_or(|book_id| order.book_id() == book_id)
+        })
+    }
+
+    /// Removes every resting order of a trader signed with a nonce below `min_nonce`, in all books.
+    /// Returns the IDs of the cancelled orders.
+    /// ## Arguments:
+    /// - `trader`: Ethereum address of the trader whose orders are cancelled.
+    /// - `min_nonce`: The trader's new minimum nonce; orders at or above it keep resting.
+    pub fn cancel_below_nonce(&mut self, trader: [u8; 20], min_nonce: u64) -> Vec<OrderId> {
+        self.cancel_where(OrderStatus::Cancelled, |order, meta| {
+            order.trader() == Some(trader) && meta.nonce.is_some_and(|nonce| nonce < min_nonce)
+        })
+    }
+
+    /// Cancels every resting order matching `predicate`, in order ID order.
+    fn cancel_where(
+        &mut self,
+        status: OrderStatus,
+        predicate: impl Fn(&RestingOrder, &SignedMeta) -> bool,
+    ) -> Vec<OrderId> {
+        let mut cancelled: Vec<OrderId> = self
+            .oid_map
+            .iter()
+            .filter(|&(order_id, order)| {
+                self.oid_map.meta(order_id).is_some_and(|meta| predicate(order, meta))
+            })
+            .map(|(order_id, _)| order_id)
+            .collect();
+        cancelled.sort_unstable();
+
+        cancelled.retain(|&order_id| self.cancel_resting(order_id, status).is_ok());
+        cancelled
+    }
+
     /// Replaces an existing order with a new order based on order IDs and new parameters.
+    /// The new order keeps the side, book, and settlement metadata (trader, nonce, expiry,
+    /// signature) of the original. Nothing is added if the original order doesn't exist, and the
+    /// original keeps its place if the new order couldn't rest: a new ID already resting, a price
+    /// that doesn't fit, or a full book.
     /// ## Arguments:
     /// - `order_id`: The order ID for the order to be replaced. Represented as Original unique reference number.
     /// - `new_order_id`: The new order ID for the order that has to be replaced. Represented as the new unique reference number.
@@ -186,14 +761,20 @@ impl OrderBookManager {
     ///
     /// ## Example:
     /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::{OrderBookError, OrderBookManager}, quantity::Qty, utils::BookId};
+    /// # fn main() -> Result<(), OrderBookError> {
     /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None);
     ///
     /// orderbook_manager.replace_order(
     ///     OrderId(0), // Old Order ID
-    ///     OrderId(0), // New Order ID
+    ///     OrderId(1), // New Order ID
     ///     Qty(200), // Quantity
     ///     500, // Price
-    /// );
+    /// )?;
+    /// # Ok(())
+    /// # }
     /// ```
     #[inline]
     pub fn replace_order(
@@ -202,58 +783,290 @@ impl OrderBookManager {
         new_order_id: OrderId,
         new_qty: Qty,
         new_price: u32,
-    ) {
-        let order = self.oid_map.get_mut(order_id);
-        let mut is_bid = true;
-        let mut book_id = BookId(0);
-        if let Some(order) = order {
-            if let Some(book) = self
-                .books
-                .get_mut(order.book_id().value() as usize)
-                .unwrap()
-            {
-                is_bid = book
-                    .level_pool
-                    .get(order.level_id())
-                    .unwrap()
-                    .price()
-                    .is_bid();
-                book_id = order.book_id();
-                book.remove_order(order);
-            }
-            self.oid_map.remove(order_id);
+    ) -> Result<OrderHandle, OrderBookError> {
+        let (order, is_bid) = self.take_for_replace(order_id, new_order_id, new_qty, new_price)?;
+        self.add_order(
+            new_order_id,
+            order.book_id(),
+            new_qty,
+            new_price,
+            is_bid,
+            order.trader(),
+            order.nonce(),
+            order.expiry(),
+            order.signature(),
+        )
+    }
+
+    /// Removes a resting order and tells its owner why it left the book.
+    /// Fails with UnknownOrder if the order doesn't exist.
+    /// ## Arguments:
+    /// - `order_id`: The order ID for the order to be removed.
+    /// - `status`: The terminal status reported to the owner, e.g. Cancelled or Expired.
+    pub fn cancel_resting(&mut self, order_id: OrderId, status: OrderStatus) -> Result<(), OrderBookError> {
+        let order = self.oid_map.get(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let (book_id, qty) = (order.book_id(), order.qty().saturating_add(self.reserve(order_id)));
+        let update = order.trader().map(|trader| OrderUpdate {
+            order_id: order_id.0,
+            book_id: book_id.value(),
+            trader,
+            status,
+            filled_qty: 0,
+            remaining_qty: 0,
+            client_order_id: None,
+            received_at: None,
+            filled_notional: 0,
+        });
+        self.detach_order(order_id)?;
+        self.emit(book_id, |seq, book_seq| match status {
+            OrderStatus::Expired => OrderBookEvent::OrderExpired { seq, book_seq, order_id, book_id, qty },
+            _ => OrderBookEvent::OrderCancelled {
+                seq,
+                book_seq,
+                order_id,
+                book_id,
+                cancelled_qty: qty,
+                remaining_qty: Qty(0),
+                reason: match status {
+                    OrderStatus::BookClosed => CancelReason::BookClosed,
+                    _ => CancelReason::Requested,
+                },
+            },
+        });
+        if let Some(update) = update {
+            self.publish_update(update);
+        }
+        Ok(())
+    }
+
+    /// Takes a resting order off the book as the first half of a replace.
+    /// Emits OrderReplaced and tells the owner the old order is gone; the caller then
+    /// submits `new_order_id` with the returned order's metadata. Fails without touching the
+    /// order if the replacement couldn't rest, see check_replacement.
+    pub(crate) fn take_for_replace(
+        &mut self,
+        order_id: OrderId,
+        new_order_id: OrderId,
+        new_qty: Qty,
+        new_price: u32,
+    ) -> Result<(Order, bool), OrderBookError> {
+        let is_bid = self.is_bid(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        self.check_replacement(order_id, new_order_id, new_price, is_bid)?;
+        let order = self.detach_order(order_id)?;
+        let book_id = order.book_id();
+
+        self.emit(book_id, |seq, book_seq| OrderBookEvent::OrderReplaced {
+            seq,
+            book_seq,
+            order_id,
+            new_order_id,
+            book_id,
+            new_price,
+            new_qty,
+        });
+        if let Some(trader) = order.trader() {
+            self.publish_update(OrderUpdate {
+                order_id: order_id.0,
+                book_id: book_id.value(),
+                trader,
+                status: OrderStatus::Cancelled,
+                filled_qty: 0,
+                remaining_qty: 0,
+                client_order_id: None,
+                received_at: None,
+                filled_notional: 0,
+            });
+        }
+        Ok((order, is_bid))
+    }
+
+    /// Checks that `new_order_id` could rest at `new_price` once `order_id` has left the book
+    /// Fails with InvalidPrice, DuplicateOrder, or BookFull as add_order would, counting the level
+    /// the original order frees if it is alone on it.
+    fn check_replacement(&self, order_id: OrderId, new_order_id: OrderId, new_price: u32, is_bid: bool) -> Result<(), OrderBookError> {
+        let price = Price::from_u32(new_price, is_bid).ok_or(OrderBookError::InvalidPrice(new_price))?;
+        if new_order_id != order_id && self.oid_map.get(new_order_id).is_some() {
+            return Err(OrderBookError::DuplicateOrder(new_order_id));
+        }
+        let handle = self.oid_map.handle(order_id).ok_or(OrderBookError::UnknownOrder)?;
+        let (book_id, level_id, _, _) = self.resting(handle)?;
+        let book = self.book(book_id).ok_or(OrderBookError::UnknownBook(book_id))?;
+        // Walking the levels is left for when the pool is out of room
+        let frees_level = book.level_pool.get(level_id).is_some_and(|level| level.order_count() == 1);
+        if !book.level_pool.can_alloc(MAX_LEVELS)
+            && !frees_level
+            && !book.iter_levels(is_bid).any(|level| level.price() == price)
+        {
+            return Err(OrderBookError::BookFull);
         }
-        self.add_order(new_order_id, book_id, new_qty, new_price, is_bid, None, None, None, None);
+        Ok(())
+    }
+
+    /// Takes a pegged order off the book to move it, returning the whole order with any reserve.
+    /// With `new_price` it emits OrderReplaced onto the same order ID and the caller re-adds it
+    /// there; without, the order is parked and leaves the book as cancelled. The owner is not
+    /// told, since the order is still working.
+    pub(crate) fn lift_order(&mut self, order_id: OrderId, new_price: Option<u32>) -> Result<Order, OrderBookError> {
+        let order = self.detach_order(order_id)?;
+        let (book_id, qty) = (order.book_id(), order.qty());
+        self.emit(book_id, |seq, book_seq| match new_price {
+            Some(new_price) => OrderBookEvent::OrderReplaced {
+                seq,
+                book_seq,
+                order_id,
+                new_order_id: order_id,
+                book_id,
+                new_price,
+                new_qty: qty,
+            },
+            None => OrderBookEvent::OrderCancelled {
+                seq,
+                book_seq,
+                order_id,
+                book_id,
+                cancelled_qty: qty,
+                remaining_qty: Qty(0),
+                reason: CancelReason::Requested,
+            },
+        });
+        Ok(order)
+    }
+
+    /// Returns whether a resting order is a bid, or None if the order doesn't exist
+    #[inline]
+    pub fn is_bid(&self, order_id: OrderId) -> Option<bool> {
+        Some(self.oid_map.get(order_id)?.price().is_bid())
     }
 
     /// Gets the best bid price for a given book
     #[inline]
     pub fn get_best_bid(&self, book_id: BookId) -> Option<Price> {
-        self.books
-            .get(book_id.value() as usize)?
-            .as_ref()?
-            .get_best_bid()
+        self.book(book_id)?.get_best_bid()
     }
 
     /// Gets the best ask price for a given book
     #[inline]
     pub fn get_best_ask(&self, book_id: BookId) -> Option<Price> {
-        self.books
-            .get(book_id.value() as usize)?
-            .as_ref()?
-            .get_best_ask()
+        self.book(book_id)?.get_best_ask()
+    }
+
+    /// Gets the aggregate size resting at the best bid for a given book
+    #[inline]
+    pub fn get_best_bid_size(&self, book_id: BookId) -> Option<Qty> {
+        let book = self.book(book_id)?;
+        Some(book.level_pool.get(book.get_best_bid_level()?)?.size())
+    }
+
+    /// Gets the aggregate size resting at the best ask for a given book
+    #[inline]
+    pub fn get_best_ask_size(&self, book_id: BookId) -> Option<Qty> {
+        let book = self.book(book_id)?;
+        Some(book.level_pool.get(book.get_best_ask_level()?)?.size())
+    }
+
+    /// Gets up to `n` levels of each side of a book, best prices first
+    /// Returns None if the book hasn't been created.
+    pub fn get_depth(&self, book_id: BookId, n: usize) -> Option<Depth> {
+        let book = self.book(book_id)?;
+        Some(Depth {
+            bids: Self::levels(book, true).take(n).collect(),
+            asks: Self::levels(book, false).take(n).collect(),
+            checksum: book.checksum(CHECKSUM_DEPTH),
+            seq: book.seq(),
+        })
+    }
+
+    /// Iterates over the levels of one side of a book as get_depth lists them, best first
+    /// Yields nothing if the book hasn't been created.
+    pub fn iter_depth(&self, book_id: BookId, is_bid: bool) -> impl Iterator<Item = (u32, u64, u32)> + '_ {
+        self.book(book_id).into_iter().flat_map(move |book| Self::levels(book, is_bid))
+    }
+
+    /// Estimates how a taker order for `qty` would fill right now, without touching the book.
+    /// A bid walks the asks and an ask walks the bids, best price first, over the same levels
+    /// get_depth reports. A book that doesn't exist or can't absorb `qty` gives a partial
+    /// estimate with `exhausted` set.
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{order::OrderId, orderbook_manager::OrderBookManager, quantity::Qty, utils::BookId};
+    /// let mut orderbook_manager = OrderBookManager::new();
+    /// # orderbook_manager.create_book(BookId(0)).unwrap();
+    /// # orderbook_manager.add_order(OrderId(0), BookId(0), Qty(10), 100, false, None, None, None, None).unwrap();
+    /// # orderbook_manager.add_order(OrderId(1), BookId(0), Qty(10), 102, false, None, None, None, None).unwrap();
+    ///
+    /// let estimate = orderbook_manager.estimate_fill(BookId(0), true, Qty(15));
+    /// assert_eq!((estimate.filled_qty, estimate.notional), (Qty(15), 1510));
+    /// assert_eq!((estimate.worst_price, estimate.exhausted), (Some(102), false));
+    /// ```
+    pub fn estimate_fill(&self, book_id: BookId, is_bid: bool, qty: Qty) -> FillEstimate {
+        let mut estimate = FillEstimate::default();
+        let mut left = qty.value();
+        for level in self.levels_to(book_id, is_bid, None) {
+            if left == 0 {
+                break;
+            }
+            let (price, take) = (level.price().absolute() as u32, left.min(level.size().value()));
+            left -= take;
+            estimate.notional += price as u128 * take as u128;
+            estimate.levels.push((price, take));
+            estimate.worst_price = Some(price);
+        }
+        estimate.filled_qty = Qty(qty.value() - left);
+        estimate.exhausted = left > 0;
+        if !estimate.filled_qty.is_empty() {
+            estimate.vwap = Some(estimate.notional as f64 / estimate.filled_qty.value() as f64);
+        }
+        estimate
+    }
+
+    /// Gets the resting orders a taker for `qty` at `limit` would trade with, as (order, price,
+    /// quantity taken), best price first and oldest first within a price
+    /// Like estimate_fill it only reads the book. Only displayed quantity is counted, so an
+    /// iceberg's hidden reserve is never revealed: a taker can fill more than this, never less.
+    pub fn preview_takes(&self, book_id: BookId, is_bid: bool, qty: Qty, limit: u32) -> Vec<(OrderId, u32, Qty)> {
+        let mut takes = Vec::new();
+        let mut left = qty;
+        for level in self.levels_to(book_id, is_bid, Some(limit)) {
+            let price = level.price().absolute() as u32;
+            for (order_id, order) in self.oid_map.pool().queue(level) {
+                if left.is_empty() {
+                    return takes;
+                }
+                let take = left.min(order.qty());
+                left = left.saturating_sub(take);
+                takes.push((order_id, price, take));
+            }
+        }
+        takes
+    }
+
+    /// Gets the levels a taker on `is_bid` would meet, best first, up to `limit` if given
+    #[inline]
+    fn levels_to(&self, book_id: BookId, is_bid: bool, limit: Option<u32>) -> impl Iterator<Item = &Level> + '_ {
+        let within = move |level: &&Level| {
+            let price = level.price().absolute() as u32;
+            limit.is_none_or(|limit| if is_bid { price <= limit } else { price >= limit })
+        };
+        self.book(book_id).into_iter().flat_map(move |book| book.iter_levels(!is_bid)).take_while(within)
+    }
+
+    /// Summarizes the levels of one side of a book, best first, as (price, size, order count)
+    #[inline]
+    fn levels(book: &OrderBook, is_bid: bool) -> impl Iterator<Item = (u32, u64, u32)> + '_ {
+        book.iter_levels(is_bid)
+            .map(|level| (level.price().absolute() as u32, level.size().value(), level.order_count()))
     }
 
     /// Gets the next matching order at or better than the given price
-    /// Returns (OrderId, Qty) if a match is found
+    /// Returns the order's (OrderId, Qty, Price) if a match is found
     #[inline]
     pub fn get_next_match(
         &self,
         book_id: BookId,
         is_bid: bool,
         price: Price,
-    ) -> Option<(OrderId, Qty)> {
-        let book = self.books.get(book_id.value() as usize)?.as_ref()?;
+    ) -> Option<(OrderId, Qty, Price)> {
+        let book = self.book(book_id)?;
         
         // Get the best matching level from the opposite side
         let level = if is_bid {
@@ -262,24 +1075,714 @@ impl OrderBookManager {
             book.get_best_bid_level()
         }?;
 
+        // The order at the front of the level's queue arrived first
+        let (oid, order) = self.oid_map.pool().queue(book.level_pool.get(level)?).next()?;
+
         // Check if price is still acceptable
-        let level_price = book.level_pool.get(level)?.price();
         let can_match = if is_bid {
-            price.absolute() >= level_price.absolute()
+            price.absolute() >= order.price().absolute()
         } else {
-            price.absolute() <= level_price.absolute()
+            price.absolute() <= order.price().absolute()
         };
+        can_match.then_some((oid, order.qty(), order.price()))
+    }
+}
 
-        if !can_match {
-            return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{level::PriceLevel, orderbook::InvariantError, utils::MAX_LEVELS};
+
+    #[test]
+    fn test_cancel_all_for_trader() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        let market_maker = [1; 20];
+        let other_trader = [2; 20];
+
+        // Spread the market maker's quotes over both sides and several levels.
+        for i in 0..1000 {
+            orderbook_manager.add_order(
+                OrderId(i),
+                BookId(0),
+                Qty(10),
+                if i % 2 == 0 { 90 + i as u32 % 5 } else { 110 + i as u32 % 5 },
+                i % 2 == 0,
+                Some(market_maker),
+                Some(i),
+                Some(u64::MAX),
+                Some([0; 65]),
+            ).unwrap();
+        }
+        // The other trader shares a level with the market maker on each side.
+        orderbook_manager.add_order(
+            OrderId(1000), BookId(0), Qty(25), 90, true,
+            Some(other_trader), Some(1), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+        orderbook_manager.add_order(
+            OrderId(1001), BookId(0), Qty(35), 111, false,
+            Some(other_trader), Some(2), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+
+        let cancelled = orderbook_manager.cancel_all_for_trader(market_maker, None);
+
+        assert_eq!(cancelled.len(), 1000);
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert!(orderbook_manager.oid_map.get(OrderId(999)).is_none());
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(1000)).unwrap().qty(), Qty(25));
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(1001)).unwrap().qty(), Qty(35));
+
+        // Only the other trader's levels remain, sized to their orders alone.
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        assert_eq!(book.bids.len(), 1);
+        assert_eq!(book.asks.len(), 1);
+        let bid_level = book.level_pool.get(book.get_best_bid_level().unwrap()).unwrap();
+        let ask_level = book.level_pool.get(book.get_best_ask_level().unwrap()).unwrap();
+        assert_eq!(bid_level.size(), Qty(25));
+        assert_eq!(ask_level.size(), Qty(35));
+    }
+
+    #[test]
+    fn test_cancel_all_for_trader_scoped_to_book() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        let trader = [1; 20];
+
+        orderbook_manager.add_order(
+            OrderId(0), BookId(0), Qty(10), 100, true,
+            Some(trader), Some(0), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+        orderbook_manager.add_order(
+            OrderId(1), BookId(1), Qty(10), 100, true,
+            Some(trader), Some(1), Some(u64::MAX), Some([0; 65])
+        ).unwrap();
+
+        let cancelled = orderbook_manager.cancel_all_for_trader(trader, Some(BookId(1)));
+
+        assert_eq!(cancelled, vec![OrderId(1)]);
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_some());
+        assert_eq!(orderbook_manager.get_best_bid(BookId(1)), None);
+    }
+
+    #[test]
+    fn test_replace_order_keeps_metadata() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+
+        orderbook_manager.add_order(
+            OrderId(0), BookId(0), Qty(100), 99, false,
+            Some([1; 20]), Some(7), Some(1682534400), Some([9; 65])
+        ).unwrap();
+
+        orderbook_manager
+            .replace_order(OrderId(0), OrderId(1), Qty(50), 101)
+            .unwrap();
+
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        let replaced = orderbook_manager.oid_map.get_order(OrderId(1)).unwrap();
+        assert_eq!(replaced.qty(), Qty(50));
+        assert_eq!(replaced.trader(), Some([1; 20]));
+        assert_eq!(replaced.nonce(), Some(7));
+        assert_eq!(replaced.expiry(), Some(1682534400));
+        assert_eq!(replaced.signature(), Signature::Full65([9; 65]));
+        assert_eq!(orderbook_manager.is_bid(OrderId(1)), Some(false));
+        assert_eq!(orderbook_manager.get_best_ask(BookId(0)), Some(Price(-101)));
+    }
+
+    #[test]
+    fn test_unknown_book_is_not_created() {
+        let mut orderbook_manager = OrderBookManager::new();
+        let result = orderbook_manager.add_order(OrderId(0), BookId(2), Qty(10), 100, true, Some([1; 20]), None, None, None);
+        assert_eq!(result, Err(OrderBookError::UnknownBook(BookId(2))));
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(orderbook_manager.books().count(), 0);
+        assert_eq!(orderbook_manager.open_orders.len(), 0);
+
+        orderbook_manager.create_book(BookId(2)).unwrap();
+        orderbook_manager.add_order(OrderId(0), BookId(2), Qty(10), 100, true, Some([1; 20]), None, None, None).unwrap();
+        assert_eq!(orderbook_manager.get_best_bid(BookId(2)), Some(Price(100)));
+        assert_eq!(orderbook_manager.books().count(), 1);
+    }
+
+    #[test]
+    fn test_book_out_of_range() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        assert_eq!(orderbook_manager.books().count(), 0);
+
+        // A BookId a hashing registry could hand out
+        let far = BookId(3_201_554_112);
+        let result = orderbook_manager.add_order(OrderId(0), far, Qty(10), 100, true, None, None, None, None);
+        println!("Out of range: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::BookOutOfRange(far)));
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(orderbook_manager.get_best_bid(far), None);
+        assert!(orderbook_manager.book(far).is_none());
+        assert_eq!(
+            orderbook_manager.create_book(BookId(MAX_BOOKS as u32)).err(),
+            Some(OrderBookError::BookOutOfRange(BookId(MAX_BOOKS as u32)))
+        );
+
+        // Books are only allocated up to the highest one used
+        orderbook_manager.add_order(OrderId(1), BookId(3), Qty(10), 100, true, None, None, None, None).unwrap();
+        assert_eq!(orderbook_manager.books.len(), 4);
+        assert_eq!(orderbook_manager.books().map(|(book_id, _)| book_id).collect::<Vec<_>>(), vec![BookId(3)]);
+        assert!(orderbook_manager.create_book(BookId(MAX_BOOKS as u32 - 1)).is_ok());
+    }
+
+    #[test]
+    fn test_failed_replace_keeps_original() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for order_id in 0..3 {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(10), 100, true, None, None, None, None).unwrap();
+        }
+        orderbook_manager.add_order(OrderId(5), BookId(0), Qty(10), 90, true, None, None, None, None).unwrap();
+        let seq = orderbook_manager.event_seq();
+
+        // A replacement ID already resting, and a price that doesn't fit, leave the order where it was
+        let result = orderbook_manager.replace_order(OrderId(1), OrderId(5), Qty(50), 101);
+        assert_eq!(result, Err(OrderBookError::DuplicateOrder(OrderId(5))));
+        let result = orderbook_manager.replace_order(OrderId(1), OrderId(6), Qty(50), u32::MAX);
+        assert_eq!(result, Err(OrderBookError::InvalidPrice(u32::MAX)));
+
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        let level = book.level_pool.get(book.get_best_bid_level().unwrap()).unwrap();
+        let queue: Vec<u64> = orderbook_manager.oid_map.pool().queue(level).map(|(order_id, _)| order_id.0).collect();
+        assert_eq!(queue, vec![0, 1, 2]);
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(30)));
+        assert_eq!(orderbook_manager.event_seq(), seq);
+
+        // Replacing an order onto its own ID is not a duplicate
+        orderbook_manager.replace_order(OrderId(1), OrderId(1), Qty(50), 101).unwrap();
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(50)));
+    }
+
+    #[test]
+    fn test_replace_unknown_order() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+
+        let result = orderbook_manager.replace_order(OrderId(5), OrderId(6), Qty(50), 101);
+
+        assert_eq!(result, Err(OrderBookError::UnknownOrder));
+        assert!(orderbook_manager.oid_map.get(OrderId(6)).is_none());
+        assert_eq!(orderbook_manager.books().count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_and_duplicate_orders() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        assert_eq!(orderbook_manager.remove_order(OrderId(0)), Err(OrderBookError::UnknownOrder));
+        assert_eq!(orderbook_manager.cancel_order(OrderId(0), Qty(10)), Err(OrderBookError::UnknownOrder));
+        assert_eq!(orderbook_manager.execute_order(OrderId(0), Qty(10)), Err(OrderBookError::UnknownOrder));
+        assert_eq!(
+            orderbook_manager.cancel_resting(OrderId(0), OrderStatus::Cancelled),
+            Err(OrderBookError::UnknownOrder)
+        );
+        assert_eq!(orderbook_manager.event_seq(), 0);
+
+        // A second order under the same ID would orphan the first one's size on its level
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
+        let result = orderbook_manager.add_order(OrderId(0), BookId(0), Qty(50), 700, true, None, None, None, None);
+        println!("Duplicate: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::DuplicateOrder(OrderId(0))));
+        assert_eq!(orderbook_manager.get_best_bid(BookId(0)), Some(Price(600)));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(100)));
+    }
+
+    #[test]
+    fn test_execute_order() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
+
+        let result = orderbook_manager.execute_order(OrderId(0), Qty(101));
+        println!("Over-execution: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::QtyExceedsRemaining { requested: Qty(101), remaining: Qty(100) }));
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(0)).unwrap().qty(), Qty(100));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(100)));
+
+        assert_eq!(orderbook_manager.execute_order(OrderId(0), Qty(40)), Ok(Qty(40)));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(60)));
+        assert_eq!(orderbook_manager.execute_order(OrderId(0), Qty(60)), Ok(Qty(60)));
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(orderbook_manager.get_best_bid(BookId(0)), None);
+        assert_eq!(orderbook_manager.execute_order(OrderId(0), Qty(1)), Err(OrderBookError::UnknownOrder));
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
+        orderbook_manager.add_order(OrderId(1), BookId(0), Qty(50), 600, true, None, None, None, None).unwrap();
+
+        // Over-cancelling is refused and leaves the order and its level alone
+        let result = orderbook_manager.cancel_order(OrderId(0), Qty(101));
+        println!("Over-cancel: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::QtyExceedsRemaining { requested: Qty(101), remaining: Qty(100) }));
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(0)).unwrap().qty(), Qty(100));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(150)));
+
+        // Repeated partial cancels
+        for remaining in [70, 40, 10] {
+            orderbook_manager.cancel_order(OrderId(0), Qty(30)).unwrap();
+            assert_eq!(orderbook_manager.oid_map.get(OrderId(0)).unwrap().qty(), Qty(remaining));
+            assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(remaining + 50)));
+        }
+        assert!(orderbook_manager.cancel_order(OrderId(0), Qty(30)).is_err());
+
+        // Cancelling exactly what is left removes the order rather than resting it at 0
+        orderbook_manager.cancel_order(OrderId(0), Qty(10)).unwrap();
+        assert!(orderbook_manager.oid_map.get(OrderId(0)).is_none());
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(50)));
+
+        // And the level goes with its last order
+        assert_eq!(orderbook_manager.cancel_remaining(OrderId(1)), Ok(Qty(50)));
+        assert_eq!(orderbook_manager.get_best_bid(BookId(0)), None);
+        assert_eq!(orderbook_manager.book(BookId(0)).unwrap().bids.len(), 0);
+        assert_eq!(orderbook_manager.cancel_remaining(OrderId(1)), Err(OrderBookError::UnknownOrder));
+        assert_eq!(orderbook_manager.oid_map.update_qty(OrderId(1), Qty(1)), None);
+    }
+
+    #[test]
+    fn test_stale_handles() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        let stale = orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
+        orderbook_manager.remove_order_by_handle(stale).unwrap();
+
+        // A new order reuses the slot; a late cancel or fill for the old one must not reach it
+        let handle = orderbook_manager.add_order(OrderId(1), BookId(0), Qty(50), 600, true, None, None, None, None).unwrap();
+        assert_eq!(handle.slot, stale.slot);
+        assert_eq!(orderbook_manager.remove_order_by_handle(stale), Err(OrderBookError::UnknownOrder));
+        assert_eq!(orderbook_manager.execute_order_by_handle(stale, Qty(10)), Err(OrderBookError::UnknownOrder));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(50)));
+
+        assert_eq!(orderbook_manager.resolve_handle(handle), Ok(OrderId(1)));
+        assert_eq!(orderbook_manager.execute_order_by_handle(handle, Qty(10)), Ok(Qty(10)));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(40)));
+    }
+
+    #[test]
+    fn test_level_queues() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        // IDs out of arrival order; the queue, not the ID, decides who matches first
+        for order_id in [5, 2, 9, 7] {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(10), 600, true, None, None, None, None).unwrap();
         }
+        let queue = |orderbook_manager: &OrderBookManager| -> Vec<u64> {
+            let book = orderbook_manager.book(BookId(0)).unwrap();
+            let level = book.level_pool.get(book.get_best_bid_level().unwrap()).unwrap();
+            orderbook_manager.oid_map.pool().queue(level).map(|(order_id, _)| order_id.0).collect()
+        };
+        assert_eq!(queue(&orderbook_manager), vec![5, 2, 9, 7]);
+        assert_eq!(orderbook_manager.get_next_match(BookId(0), false, Price(-600)), Some((OrderId(5), Qty(10), Price(600))));
+
+        // A partial fill or cancel keeps the order's place, and the pool holds the new quantity
+        orderbook_manager.execute_order(OrderId(5), Qty(4)).unwrap();
+        orderbook_manager.cancel_order(OrderId(9), Qty(3)).unwrap();
+        assert_eq!(queue(&orderbook_manager), vec![5, 2, 9, 7]);
+        assert_eq!(orderbook_manager.get_next_match(BookId(0), false, Price(-600)), Some((OrderId(5), Qty(6), Price(600))));
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(9)).unwrap().qty(), Qty(7));
+
+        // Leaving from the middle, the front, and the back
+        orderbook_manager.remove_order(OrderId(2)).unwrap();
+        orderbook_manager.execute_order(OrderId(5), Qty(6)).unwrap();
+        orderbook_manager.cancel_remaining(OrderId(7)).unwrap();
+        println!("Queue: {:?}", queue(&orderbook_manager));
+        assert_eq!(queue(&orderbook_manager), vec![9]);
+
+        // Freed slots are reused at the back of the queue
+        orderbook_manager.add_order(OrderId(1), BookId(0), Qty(10), 600, true, None, None, None, None).unwrap();
+        assert_eq!(queue(&orderbook_manager), vec![9, 1]);
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(17)));
+        assert_eq!(orderbook_manager.oid_map.iter().count(), 2);
+    }
 
-        // Find the first order at this level
-        for (oid, order) in self.oid_map.iter() {
-            if order.book_id() == book_id && order.level_id() == level {
-                return Some((oid, order.qty()));
+    #[test]
+    fn test_depth_ordering() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        assert_eq!(orderbook_manager.get_depth(BookId(0), 10), None);
+
+        let mut next_id = 0;
+        let mut add = |orderbook_manager: &mut OrderBookManager, price: u32, is_bid: bool| {
+            next_id += 1;
+            orderbook_manager.add_order(OrderId(next_id), BookId(0), Qty(10), price, is_bid, None, None, None, None).unwrap();
+            OrderId(next_id)
+        };
+        let prices = |levels: &[(u32, u64, u32)]| levels.iter().map(|&(price, _, _)| price).collect::<Vec<_>>();
+
+        // Out of price order on both sides, with a second order on one level
+        let mut bid_ids = Vec::new();
+        for price in [98, 100, 96, 99] {
+            bid_ids.push(add(&mut orderbook_manager, price, true));
+        }
+        let mut ask_ids = Vec::new();
+        for price in [103, 101, 104, 102] {
+            ask_ids.push(add(&mut orderbook_manager, price, false));
+        }
+        add(&mut orderbook_manager, 100, true);
+        let depth = orderbook_manager.get_depth(BookId(0), 10).unwrap();
+        assert_eq!(prices(&depth.bids), vec![100, 99, 98, 96]);
+        assert_eq!(prices(&depth.asks), vec![101, 102, 103, 104]);
+        assert_eq!(depth.bids[0], (100, 20, 2));
+
+        // Emptying levels frees them; new prices reuse their LevelIds and still sort by price
+        orderbook_manager.remove_order(bid_ids[2]).unwrap();
+        orderbook_manager.remove_order(ask_ids[1]).unwrap();
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        assert_eq!(book.level_pool.free_count(), 2);
+        add(&mut orderbook_manager, 97, true);
+        add(&mut orderbook_manager, 105, false);
+        assert_eq!(orderbook_manager.book(BookId(0)).unwrap().level_pool.free_count(), 0);
+        orderbook_manager.remove_order(bid_ids[0]).unwrap();
+        add(&mut orderbook_manager, 101, false);
+
+        let depth = orderbook_manager.get_depth(BookId(0), 10).unwrap();
+        println!("Depth: {:?}", depth);
+        assert_eq!(prices(&depth.bids), vec![100, 99, 97]);
+        assert_eq!(prices(&depth.asks), vec![101, 102, 103, 104, 105]);
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        let bids: Vec<Price> = book.iter_bids().map(|level| level.price()).collect();
+        assert_eq!(bids, vec![Price(100), Price(99), Price(97)]);
+
+        let top = orderbook_manager.get_depth(BookId(0), 2).unwrap();
+        assert_eq!(top.bids, vec![(100, 20, 2), (99, 10, 1)]);
+        assert_eq!(top.asks, vec![(101, 10, 1), (102, 10, 1)]);
+    }
+
+    #[test]
+    fn test_level_order_count() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for order_id in 0..3 {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(10), 600, true, None, None, None, None).unwrap();
+        }
+        let level = |orderbook_manager: &OrderBookManager| orderbook_manager.get_depth(BookId(0), 1).unwrap().bids.first().copied();
+        assert_eq!(level(&orderbook_manager), Some((600, 30, 3)));
+
+        // A partial cancel leaves the count alone, cancelling the rest of the order takes it off
+        orderbook_manager.cancel_order(OrderId(0), Qty(4)).unwrap();
+        assert_eq!(level(&orderbook_manager), Some((600, 26, 3)));
+        orderbook_manager.cancel_order(OrderId(0), Qty(6)).unwrap();
+        assert_eq!(level(&orderbook_manager), Some((600, 20, 2)));
+
+        orderbook_manager.execute_order(OrderId(1), Qty(10)).unwrap();
+        assert_eq!(level(&orderbook_manager), Some((600, 10, 1)));
+
+        // The last order takes the level with it
+        orderbook_manager.cancel_resting(OrderId(2), OrderStatus::Expired).unwrap();
+        assert_eq!(level(&orderbook_manager), None);
+        assert_eq!(orderbook_manager.book(BookId(0)).unwrap().level_pool.allocated_count(), 0);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        orderbook_manager.create_book(BookId(0)).unwrap();
+        assert_eq!(orderbook_manager.get_depth(BookId(0), 10).unwrap().checksum, 2343686810);
+        assert_eq!(Depth::default().checksum, 2343686810);
+
+        for (order_id, price, qty, is_bid) in [(0, 100, 2, true), (1, 99, 7, true), (2, 101, 3, false), (3, 100, 3, true)] {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, None, None, None, None).unwrap();
+        }
+        // Pinned so the format can't drift: CRC32 of "100:5,99:7|101:3"
+        let checksum = orderbook_manager.get_depth(BookId(0), 1).unwrap().checksum;
+        assert_eq!(checksum, 695677624);
+        assert_eq!(checksum, crc32fast::hash(b"100:5,99:7|101:3"));
+
+        // Only the top CHECKSUM_DEPTH levels of each side count
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for i in 0..30 {
+            orderbook_manager.add_order(OrderId(2 * i), BookId(0), Qty(1), 200 - i as u32, true, None, None, None, None).unwrap();
+            orderbook_manager.add_order(OrderId(2 * i + 1), BookId(0), Qty(2), 201 + i as u32, false, None, None, None, None).unwrap();
+        }
+        assert_eq!(orderbook_manager.book(BookId(0)).unwrap().checksum(CHECKSUM_DEPTH), 210902025);
+    }
+
+    #[test]
+    fn test_book_sequence_has_no_gaps() {
+        use crate::{events::VecSink, market_data::MarketDataEvent};
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        let sink = VecSink::new();
+        orderbook_manager.set_event_sink(Box::new(sink.clone()));
+        let mut updates = orderbook_manager.market_data.subscribe();
+        let mut rng = StdRng::seed_from_u64(11);
+
+        let mut resting: Vec<OrderId> = Vec::new();
+        let mut mutations = 0u64;
+        for order_id in 0..1_000u64 {
+            let result = match (rng.gen_range(0..4), resting.is_empty()) {
+                (0, _) | (_, true) => {
+                    let (price, is_bid) = (rng.gen_range(90..110), rng.gen_bool(0.5));
+                    resting.push(OrderId(order_id));
+                    orderbook_manager
+                        .add_order(OrderId(order_id), BookId(0), Qty(rng.gen_range(1..20)), price, is_bid, None, None, None, None)
+                        .map(|_| ())
+                }
+                (kind, false) => {
+                    let idx = rng.gen_range(0..resting.len());
+                    let target = resting[idx];
+                    let left = orderbook_manager.oid_map.get(target).unwrap().qty();
+                    let qty = Qty(rng.gen_range(1..=left.value()));
+                    if kind == 3 || qty == left {
+                        resting.swap_remove(idx);
+                    }
+                    match kind {
+                        1 => orderbook_manager.cancel_order(target, qty),
+                        2 => orderbook_manager.execute_order(target, qty).map(|_| ()),
+                        _ => orderbook_manager.remove_order(target),
+                    }
+                }
+            };
+            result.unwrap();
+            mutations += 1;
+        }
+
+        // One level update per change, each picking up where the last one left off
+        let mut last = 0;
+        for update in std::iter::from_fn(|| updates.try_recv().ok()) {
+            match update {
+                MarketDataEvent::LevelUpdate { prev_seq, seq, .. } => {
+                    assert_eq!((prev_seq, seq), (last, last + 1));
+                    last = seq;
+                }
+                MarketDataEvent::Checksum { seq, .. } => assert_eq!(seq, last),
+                other => panic!("Unexpected event {:?}", other),
             }
         }
-        None
+        let book_seqs: Vec<u64> = sink.take().iter().map(OrderBookEvent::book_seq).collect();
+        assert_eq!(book_seqs, (1..=mutations).collect::<Vec<_>>());
+        assert_eq!((last, orderbook_manager.get_depth(BookId(0), 1).unwrap().seq), (mutations, mutations));
+    }
+
+    #[test]
+    fn test_freed_level_is_published_empty() {
+        use crate::market_data::MarketDataEvent;
+
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        let mut events = orderbook_manager.market_data.subscribe();
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(10), 600, false, None, None, None, None).unwrap();
+        orderbook_manager.add_order(OrderId(1), BookId(0), Qty(5), 601, false, None, None, None, None).unwrap();
+        orderbook_manager.remove_order(OrderId(0)).unwrap();
+        orderbook_manager.execute_order(OrderId(1), Qty(5)).unwrap();
+
+        let sizes: Vec<(u32, u64)> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| match event {
+                MarketDataEvent::LevelUpdate { price, size, .. } => (price, size),
+                other => panic!("Unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(sizes, vec![(600, 10), (601, 5), (600, 0), (601, 0)]);
+    }
+
+    #[test]
+    fn test_estimate_fill() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for (order_id, price, qty) in [(0, 101, 20), (1, 100, 10), (2, 103, 30), (3, 101, 5)] {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(qty), price, false, None, None, None, None).unwrap();
+        }
+        orderbook_manager.add_order(OrderId(4), BookId(0), Qty(8), 99, true, None, None, None, None).unwrap();
+
+        // 10 @ 100, 25 @ 101, 15 @ 103
+        let estimate = orderbook_manager.estimate_fill(BookId(0), true, Qty(50));
+        println!("Estimate: {:?}", estimate);
+        assert_eq!(estimate.filled_qty, Qty(50));
+        assert_eq!(estimate.notional, 1_000 + 2_525 + 1_545);
+        assert_eq!(estimate.vwap, Some(5_070.0 / 50.0));
+        assert_eq!(estimate.worst_price, Some(103));
+        assert_eq!(estimate.levels, vec![(100, 10), (101, 25), (103, 15)]);
+        assert!(!estimate.exhausted);
+
+        // The levels agree with the depth snapshot
+        let depth = orderbook_manager.get_depth(BookId(0), 10).unwrap();
+        let full = orderbook_manager.estimate_fill(BookId(0), true, Qty(1_000));
+        let depth_levels: Vec<(u32, u64)> = depth.asks.iter().map(|&(price, size, _)| (price, size)).collect();
+        assert_eq!(full.levels, depth_levels);
+        assert_eq!((full.filled_qty, full.exhausted), (Qty(65), true));
+
+        // Asks walk the bids
+        let sell = orderbook_manager.estimate_fill(BookId(0), false, Qty(5));
+        assert_eq!((sell.vwap, sell.worst_price, sell.exhausted), (Some(99.0), Some(99), false));
+
+        // A book without orders fills nothing
+        let empty = orderbook_manager.estimate_fill(BookId(1), true, Qty(5));
+        assert_eq!((empty.filled_qty, empty.vwap, empty.worst_price, empty.exhausted), (Qty(0), None, None, true));
+        assert_eq!(orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(10)));
+    }
+
+    #[test]
+    fn test_inconsistent_orders() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(100), 600, true, None, None, None, None).unwrap();
+
+        // An order whose book was never created
+        let order = Order::new(Qty(10), LevelId(0), BookId(7), None, None, None, None);
+        orderbook_manager.oid_map.insert(OrderId(1), order);
+        assert_eq!(orderbook_manager.remove_order(OrderId(1)), Err(OrderBookError::UnknownBook(BookId(7))));
+        assert_eq!(orderbook_manager.cancel_order(OrderId(1), Qty(5)), Err(OrderBookError::UnknownBook(BookId(7))));
+        assert_eq!(orderbook_manager.execute_order(OrderId(1), Qty(5)), Err(OrderBookError::UnknownBook(BookId(7))));
+        assert_eq!(orderbook_manager.oid_map.get(OrderId(1)).unwrap().qty(), Qty(10));
+
+        // And one on a level its book never allocated
+        let order = Order::new(Qty(10), LevelId(99), BookId(0), None, None, None, None);
+        orderbook_manager.oid_map.insert(OrderId(2), order);
+        let result = orderbook_manager.remove_order(OrderId(2));
+        println!("Unknown level: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::UnknownLevel(LevelId(99))));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(100)));
+
+        // And one larger than its level, which can't take the level below zero
+        let level_id = orderbook_manager.oid_map.get(OrderId(0)).unwrap().level_id();
+        let order = Order::new(Qty(500), level_id, BookId(0), None, None, None, None);
+        orderbook_manager.oid_map.insert(OrderId(3), order);
+        assert_eq!(
+            orderbook_manager.cancel_order(OrderId(3), Qty(200)),
+            Err(OrderBookError::QtyExceedsRemaining { requested: Qty(200), remaining: Qty(100) })
+        );
+        assert!(orderbook_manager.remove_order(OrderId(3)).is_err());
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(100)));
+        assert_eq!(orderbook_manager.event_seq(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(feature = "paranoid-checks", ignore = "fills a million levels, checking the whole book after each")]
+    fn test_book_full() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for i in 0..MAX_LEVELS as u32 {
+            orderbook_manager.add_order(OrderId(i as u64), BookId(0), Qty(1), i + 1, true, None, None, None, None).unwrap();
+        }
+
+        // Existing levels still take orders, new ones don't fit
+        let next = OrderId(MAX_LEVELS as u64);
+        let result = orderbook_manager.add_order(next, BookId(0), Qty(1), 0, false, None, None, None, None);
+        println!("Full book: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::BookFull));
+        assert!(orderbook_manager.oid_map.get(next).is_none());
+        orderbook_manager.add_order(next, BookId(0), Qty(1), 1, true, None, None, None, None).unwrap();
+
+        // A replace needing a new level fails with the original still first in its queue,
+        // unless the original frees its own level
+        let new_price = MAX_LEVELS as u32 + 1;
+        let result = orderbook_manager.replace_order(OrderId(0), OrderId(next.0 + 1), Qty(1), new_price);
+        assert_eq!(result, Err(OrderBookError::BookFull));
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        let level = book.iter_bids().last().unwrap();
+        let queue: Vec<u64> = orderbook_manager.oid_map.pool().queue(level).map(|(order_id, _)| order_id.0).collect();
+        assert_eq!(queue, vec![0, next.0]);
+        orderbook_manager.replace_order(OrderId(1), OrderId(next.0 + 1), Qty(1), new_price).unwrap();
+        orderbook_manager.replace_order(OrderId(next.0 + 1), OrderId(1), Qty(1), 2).unwrap();
+
+        // Freeing a level makes room again
+        orderbook_manager.remove_order(OrderId(MAX_LEVELS as u64 - 1)).unwrap();
+        orderbook_manager.add_order(OrderId(next.0 + 1), BookId(0), Qty(1), 1, false, None, None, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_boundary_values() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+
+        // Prices that would wrap when negated for an ask are refused on either side
+        for (price, is_bid) in [(i32::MAX as u32 + 1, true), (i32::MIN.unsigned_abs(), false), (u32::MAX, false)] {
+            let result = orderbook_manager.add_order(OrderId(0), BookId(0), Qty(1), price, is_bid, None, None, None, None);
+            assert_eq!(result, Err(OrderBookError::InvalidPrice(price)));
+        }
+        orderbook_manager.add_order(OrderId(0), BookId(0), Qty(1), i32::MAX as u32, false, None, None, None, None).unwrap();
+        assert_eq!(orderbook_manager.get_best_ask(BookId(0)), Some(Price(-i32::MAX)));
+
+        // A level holds at most u64::MAX
+        orderbook_manager.add_order(OrderId(1), BookId(0), Qty(u64::MAX), 100, true, None, None, None, None).unwrap();
+        let result = orderbook_manager.add_order(OrderId(2), BookId(0), Qty(1), 100, true, None, None, None, None);
+        println!("Level overflow: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::QtyOverflow { qty: Qty(1), size: Qty(u64::MAX) }));
+        assert!(orderbook_manager.oid_map.get(OrderId(2)).is_none());
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(u64::MAX)));
+
+        // Reducing the full level to zero takes the order and the level with it
+        orderbook_manager.cancel_order(OrderId(1), Qty(u64::MAX - 1)).unwrap();
+        assert_eq!(orderbook_manager.execute_order(OrderId(1), Qty(1)), Ok(Qty(1)));
+        assert_eq!(orderbook_manager.get_best_bid(BookId(0)), None);
+    }
+
+    #[test]
+    fn test_best_bid_and_ask() {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+
+        for (order_id, price, is_bid) in [(0, 98, true), (1, 99, true), (2, 97, true), (3, 102, false), (4, 101, false), (5, 103, false)] {
+            orderbook_manager.add_order(
+                OrderId(order_id), BookId(0), Qty(10 + order_id), price, is_bid,
+                Some([1; 20]), Some(0), Some(u64::MAX), Some([0; 65])
+            ).unwrap();
+        }
+
+        assert_eq!(orderbook_manager.get_best_bid(BookId(0)), Some(Price(99)));
+        assert_eq!(orderbook_manager.get_best_ask(BookId(0)), Some(Price(-101)));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(0)), Some(Qty(11)));
+        assert_eq!(orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(14)));
+        assert_eq!(orderbook_manager.get_best_bid_size(BookId(1)), None);
+    }
+
+    /// Two bids at 99 and one at 98 against an ask at 101, plus the level a bid at 97 left freed
+    fn invariant_book() -> (OrderBookManager, LevelId) {
+        let mut orderbook_manager = OrderBookManager::auto_creating();
+        for (order_id, price, is_bid) in [(0, 99, true), (1, 99, true), (2, 98, true), (3, 101, false), (4, 97, true)] {
+            orderbook_manager.add_order(OrderId(order_id), BookId(0), Qty(10 + order_id), price, is_bid, None, None, None, None).unwrap();
+        }
+        let freed = orderbook_manager.oid_map.get(OrderId(4)).unwrap().level_id();
+        orderbook_manager.remove_order(OrderId(4)).unwrap();
+        (orderbook_manager, freed)
+    }
+
+    fn check(orderbook_manager: &OrderBookManager) -> Result<(), InvariantError> {
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        println!("{}", book.describe(&orderbook_manager.oid_map));
+        book.check_invariants(BookId(0), &orderbook_manager.oid_map)
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let (mut orderbook_manager, freed) = invariant_book();
+        assert_eq!(check(&orderbook_manager), Ok(()));
+        let level_of = |orderbook_manager: &OrderBookManager, order_id| orderbook_manager.oid_map.get(OrderId(order_id)).unwrap().level_id();
+        let (level_99, level_98) = (level_of(&orderbook_manager, 0), level_of(&orderbook_manager, 2));
+
+        // A level whose size drifted from its orders
+        let book = orderbook_manager.book_mut_unchecked(BookId(0));
+        book.level_pool.get_mut(level_99).unwrap().set_size(Qty(5));
+        let error = check(&orderbook_manager).unwrap_err();
+        println!("{}", error);
+        assert_eq!(error, InvariantError::LevelSize { level_id: level_99, price: Price(99), size: Qty(5), queued: Qty(21) });
+
+        // A listed level that was freed
+        let (mut orderbook_manager, _) = invariant_book();
+        orderbook_manager.book_mut_unchecked(BookId(0)).level_pool.free(level_98).unwrap();
+        assert_eq!(check(&orderbook_manager), Err(InvariantError::FreedLevel { level_id: level_98, price: Price(98) }));
+
+        // An order pointing at a freed level
+        let (mut orderbook_manager, _) = invariant_book();
+        orderbook_manager.oid_map.get_mut(OrderId(2)).unwrap().set_level_id(freed);
+        assert_eq!(check(&orderbook_manager), Err(InvariantError::OrderOnFreedLevel { order_id: OrderId(2), level_id: freed }));
+
+        // An order queued on one level but naming another
+        let (mut orderbook_manager, _) = invariant_book();
+        orderbook_manager.oid_map.get_mut(OrderId(2)).unwrap().set_level_id(level_99);
+        let error = InvariantError::OrderPrice { order_id: OrderId(2), price: Price(98), level_price: Price(99) };
+        assert_eq!(check(&orderbook_manager), Err(error));
+
+        // A level listed twice
+        let (mut orderbook_manager, _) = invariant_book();
+        orderbook_manager.book_mut_unchecked(BookId(0)).bids.insert(0, PriceLevel::new(Price(98), level_98));
+        assert_eq!(check(&orderbook_manager), Err(InvariantError::DuplicateLevel { level_id: level_98, price: Price(98) }));
+
+        // A crossed book, which only check_levels allows
+        let (mut orderbook_manager, _) = invariant_book();
+        orderbook_manager.add_order(OrderId(5), BookId(0), Qty(1), 102, true, None, None, None, None).unwrap();
+        assert_eq!(check(&orderbook_manager), Err(InvariantError::Crossed { bid: Price(102), ask: Price(-101) }));
+        let book = orderbook_manager.book(BookId(0)).unwrap();
+        assert_eq!(book.check_levels(BookId(0), &orderbook_manager.oid_map), Ok(()));
+    }
+
+    #[cfg(all(feature = "paranoid-checks", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "Book 0 broke an invariant after adding an order")]
+    fn test_paranoid_checks_panic_on_corruption() {
+        let (mut orderbook_manager, _) = invariant_book();
+        let level_99 = orderbook_manager.oid_map.get(OrderId(0)).unwrap().level_id();
+        orderbook_manager.book_mut_unchecked(BookId(0)).level_pool.get_mut(level_99).unwrap().set_size(Qty(5));
+        orderbook_manager.add_order(OrderId(5), BookId(0), Qty(1), 99, true, None, None, None, None).unwrap();
     }
 }
@@ -0,0 +1,252 @@
+// pegs.rs
+
+use crate::{
+    level::LevelId,
+    order::{Order, OrderId, Signature},
+    orderbook_manager::OrderBookError,
+    quantity::Qty,
+    utils::{hex_bytes, BookId},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// What the price of a pegged order follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Peg {
+    Midpoint,                // Halfway between the best bid and ask, rounded away from the other side
+    Primary { offset: i32 }, // The best price on the order's own side, moved by `offset` ticks
+}
+
+/// A pegged order, resting at the price its peg gives or parked off the book without one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeggedOrder {
+    pub order_id: u64,
+    pub book_id: u32,
+    pub qty: u64, // Quantity as submitted, or left when parked
+    pub is_bid: bool,
+    pub limit: u32, // Worst price the peg may go to: the highest for a bid, the lowest for an ask
+    pub peg: Peg,
+    pub may_cross: bool, // Allowed to execute against the other side when it reprices
+    pub display: Option<u64>,
+    #[serde(with = "hex_bytes")]
+    pub trader: Option<[u8; 20]>,
+    pub nonce: Option<u64>,
+    pub expiry: Option<u64>,
+    pub signature: Signature,
+    #[serde(default)]
+    pub price: Option<u32>, // Price it rests at; None while parked
+}
+
+impl PeggedOrder {
+    /// Gets the order that goes on the book
+    pub fn order(&self) -> Order {
+        let order = Order::new(
+            Qty(self.qty),
+            LevelId(0),
+            BookId(self.book_id),
+            self.trader,
+            self.nonce,
+            self.expiry,
+            self.signature,
+        );
+        match self.display {
+            Some(display) => order.with_display(Qty(display)),
+            None => order,
+        }
+    }
+
+    /// Gets the price the order pegs to, or None if there is nothing to peg to.
+    /// `bid` and `ask` are the best prices of the orders pegs follow, `opposite` the best price
+    /// resting on the other side of the book. Unless the order may cross, it stays a tick off
+    /// `opposite`; it never goes past its limit.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::pegs::{Peg, PeggedOrder};
+    /// # use optimized_lob::order::Signature;
+    /// let mut order = PeggedOrder {
+    ///     order_id: 0, book_id: 0, qty: 10, is_bid: true, limit: 1000, peg: Peg::Midpoint,
+    ///     may_cross: false, display: None, trader: None, nonce: None, expiry: None,
+    ///     signature: Signature::None, price: None,
+    /// };
+    /// assert_eq!(order.target(Some(99), Some(104), Some(104)), Some(101));
+    /// order.peg = Peg::Primary { offset: 10 };
+    /// assert_eq!(order.target(Some(99), Some(104), Some(104)), Some(103));
+    /// assert_eq!(order.target(None, Some(104), Some(104)), None);
+    /// ```
+    pub fn target(&self, bid: Option<u32>, ask: Option<u32>, opposite: Option<u32>) -> Option<u32> {
+        let target = match self.peg {
+            Peg::Midpoint => {
+                let sum = bid? as u64 + ask? as u64;
+                (if self.is_bid { sum / 2 } else { sum.div_ceil(2) }) as u32
+            }
+            Peg::Primary { offset } => (if self.is_bid { bid? } else { ask? }).checked_add_signed(offset)?,
+        };
+        let target = if self.is_bid { target.min(self.limit) } else { target.max(self.limit) };
+        let target = match opposite {
+            Some(opposite) if !self.may_cross && self.is_bid && target >= opposite => opposite.checked_sub(1)?,
+            Some(opposite) if !self.may_cross && !self.is_bid && target <= opposite => opposite.checked_add(1)?,
+            _ => target,
+        };
+        (target > 0 && target <= i32::MAX as u32).then_some(target)
+    }
+}
+
+/// Pegged orders of every book, resting or parked.
+/// The book holds a resting peg like any other order; this keeps what it pegs to, so the
+/// engine can move it when the market does.
+#[derive(Debug, Default)]
+pub struct PegBook {
+    orders: HashMap<OrderId, PeggedOrder>,
+    by_book: BTreeSet<(BookId, OrderId)>,
+}
+
+impl PegBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a pegged order; fails with DuplicateOrder if its order ID is already pegged.
+    pub fn insert(&mut self, order: PeggedOrder) -> Result<(), OrderBookError> {
+        let order_id = OrderId(order.order_id);
+        if self.orders.contains_key(&order_id) {
+            return Err(OrderBookError::DuplicateOrder(order_id));
+        }
+        self.by_book.insert((BookId(order.book_id), order_id));
+        self.orders.insert(order_id, order);
+        Ok(())
+    }
+
+    /// Removes a pegged order, returning it if it was pegged.
+    pub fn remove(&mut self, order_id: OrderId) -> Option<PeggedOrder> {
+        let order = self.orders.remove(&order_id)?;
+        self.by_book.remove(&(BookId(order.book_id), order_id));
+        Some(order)
+    }
+
+    #[inline]
+    pub fn get(&self, order_id: OrderId) -> Option<&PeggedOrder> {
+        self.orders.get(&order_id)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, order_id: OrderId) -> Option<&mut PeggedOrder> {
+        self.orders.get_mut(&order_id)
+    }
+
+    /// Returns true if the order is pegged and parked off the book
+    #[inline]
+    pub fn is_parked(&self, order_id: OrderId) -> bool {
+        self.get(order_id).is_some_and(|order| order.price.is_none())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// Lists the pegged orders of a book in order ID order
+    pub fn in_book(&self, book_id: BookId) -> Vec<OrderId> {
+        self.by_book
+            .range((book_id, OrderId(0))..=(book_id, OrderId(u64::MAX)))
+            .map(|&(_, order_id)| order_id)
+            .collect()
+    }
+
+    /// Lists the books with pegged orders
+    pub fn books(&self) -> Vec<BookId> {
+        let mut books: Vec<BookId> = self.by_book.iter().map(|&(book_id, _)| book_id).collect();
+        books.dedup();
+        books
+    }
+
+    /// Lists the pegged orders in order ID order
+    pub fn entries(&self) -> Vec<PeggedOrder> {
+        let mut orders: Vec<PeggedOrder> = self.orders.values().cloned().collect();
+        orders.sort_unstable_by_key(|order| order.order_id);
+        orders
+    }
+
+    /// Removes the pegged orders matching `filter`, returning their order IDs in order ID order
+    pub fn remove_where(&mut self, mut filter: impl FnMut(&PeggedOrder) -> bool) -> Vec<OrderId> {
+        let mut order_ids: Vec<OrderId> = self
+            .orders
+            .iter()
+            .filter(|(_, order)| filter(order))
+            .map(|(&order_id, _)| order_id)
+            .collect();
+        order_ids.sort_unstable();
+        for &order_id in &order_ids {
+            self.remove(order_id);
+        }
+        order_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pegged(is_bid: bool, peg: Peg) -> PeggedOrder {
+        PeggedOrder {
+            order_id: 0,
+            book_id: 0,
+            qty: 10,
+            is_bid,
+            limit: if is_bid { 1000 } else { 1 },
+            peg,
+            may_cross: false,
+            display: None,
+            trader: None,
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+            price: None,
+        }
+    }
+
+    #[test]
+    fn test_peg_targets() {
+        // The midpoint rounds down for a bid and up for an ask, keeping both off the other side
+        assert_eq!(pegged(true, Peg::Midpoint).target(Some(100), Some(103), Some(103)), Some(101));
+        assert_eq!(pegged(false, Peg::Midpoint).target(Some(100), Some(103), Some(100)), Some(102));
+        assert_eq!(pegged(true, Peg::Midpoint).target(Some(100), None, None), None);
+
+        // A primary peg follows its own side, and a negative offset steps back from it
+        let bid = pegged(true, Peg::Primary { offset: -2 });
+        assert_eq!(bid.target(Some(100), None, None), Some(98));
+        assert_eq!(bid.target(Some(1), None, None), None);
+        let ask = pegged(false, Peg::Primary { offset: -5 });
+        assert_eq!(ask.target(Some(100), Some(103), Some(100)), Some(101));
+
+        // Crossing is only allowed when asked for, and the limit always holds
+        let crossing = PeggedOrder { may_cross: true, ..ask.clone() };
+        assert_eq!(crossing.target(Some(100), Some(103), Some(100)), Some(98));
+        let limited = PeggedOrder { limit: 102, ..ask };
+        assert_eq!(limited.target(Some(100), Some(103), Some(100)), Some(102));
+    }
+
+    #[test]
+    fn test_peg_book() {
+        let mut pegs = PegBook::new();
+        for (order_id, book_id) in [(3, 1), (1, 0), (2, 0)] {
+            pegs.insert(PeggedOrder { order_id, book_id, ..pegged(true, Peg::Midpoint) }).unwrap();
+        }
+        assert!(matches!(pegs.insert(pegged(true, Peg::Midpoint)), Ok(())));
+        assert!(pegs.insert(pegged(true, Peg::Midpoint)).is_err());
+        assert_eq!(pegs.in_book(BookId(0)), vec![OrderId(0), OrderId(1), OrderId(2)]);
+        assert_eq!(pegs.books(), vec![BookId(0), BookId(1)]);
+        assert!(pegs.is_parked(OrderId(1)));
+
+        assert_eq!(pegs.remove_where(|order| order.book_id == 1), vec![OrderId(3)]);
+        assert_eq!(pegs.remove(OrderId(0)).map(|order| order.order_id), Some(0));
+        assert_eq!(pegs.len(), 2);
+        assert_eq!(pegs.books(), vec![BookId(0)]);
+    }
+}
@@ -2,20 +2,30 @@
 
 // Import the Level and LevelId structs from the level module.
 use crate::level::{Level, LevelId};
+use crate::order::{OrderHandle, OrderId, RestingOrder};
+use crate::orderbook_manager::OrderBookError;
 
 // Define a struct named LevelPool, which is a pool for managing Level objects.
 #[derive(Clone)]
 pub struct LevelPool {
     levels: Vec<Level>, // A vector to store allocated Level objects.
+    allocated: Vec<bool>,       // Whether each LevelId is in use, to catch double frees.
     free_list: Vec<LevelId>,    // A vector to store free LevelId values.
 }
 
+impl Default for LevelPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LevelPool {
     // Constructor for creating a new LevelPool instance with default values.
     #[inline]
     pub fn new() -> Self {
         Self {
             levels: Vec::new(), // Initialize allocated vector as empty.
+            allocated: Vec::new(),
             free_list: Vec::new(),      // Initialize free vector as empty.
         }
     }
@@ -24,41 +34,279 @@ impl LevelPool {
     pub fn new_with_capacity(capacity: usize) -> Self {
         Self {
             levels: Vec::with_capacity(capacity), // Initialize allocated vector with the specified capacity.
+            allocated: Vec::with_capacity(capacity),
             free_list: Vec::with_capacity(capacity), // Initialize free vector with the specified capacity.
         }
     }
 
     // Allocate a LevelId from the pool. Reuses a free LevelId if available or creates a new one.
+    // Either way the level starts out as Level::default().
     pub fn alloc(&mut self) -> LevelId {
         if let Some(id) = self.free_list.pop() {
+            self.allocated[id.0 as usize] = true;
             id
         } else {
             let id = LevelId(self.levels.len() as u32);
             self.levels.push(Level::default());
+            self.allocated.push(true);
             id
         }
     }
 
-    // Free a LevelId by adding it back to the pool of available LevelIds.
-    pub fn free(&mut self, id: LevelId) {
+    // Check whether a LevelId can be allocated without growing the pool past `capacity` levels.
+    pub fn can_alloc(&self, capacity: usize) -> bool {
+        !self.free_list.is_empty() || self.levels.len() < capacity
+    }
+
+    // Free a LevelId by resetting its level and adding it back to the pool of available LevelIds.
+    // Fails with UnknownLevel, leaving the pool untouched, if the LevelId isn't allocated, so a
+    // double free can't hand the same LevelId to two price levels.
+    pub fn free(&mut self, id: LevelId) -> Result<(), OrderBookError> {
+        match self.allocated.get_mut(id.0 as usize) {
+            Some(allocated) if *allocated => *allocated = false,
+            _ => return Err(OrderBookError::UnknownLevel(id)),
+        }
+        self.levels[id.0 as usize] = Level::default();
         self.free_list.push(id);
+        Ok(())
     }
 
-    // Get a reference to a Level by LevelId if it exists in the pool.
+    // Get the number of LevelIds in use.
+    #[inline]
+    pub fn allocated_count(&self) -> usize {
+        self.levels.len() - self.free_list.len()
+    }
+
+    // Get the number of freed LevelIds waiting to be reused.
+    #[inline]
+    pub fn free_count(&self) -> usize {
+        self.free_list.len()
+    }
+
+    // Get a reference to a Level by LevelId if it is allocated.
     #[inline]
     pub fn get(&self, id: LevelId) -> Option<&Level> {
+        if !self.is_allocated(id) {
+            return None;
+        }
         self.levels.get(id.0 as usize)
     }
 
-    // Get a mutable reference to a Level by LevelId if it exists in the pool.
+    // Get a mutable reference to a Level by LevelId if it is allocated.
     pub fn get_mut(&mut self, id: LevelId) -> Option<&mut Level> {
+        if !self.is_allocated(id) {
+            return None;
+        }
         self.levels.get_mut(id.0 as usize)
     }
 
-    // Set the Level object associated with a LevelId in the pool.
+    // Set the Level object associated with an allocated LevelId in the pool.
     pub fn set_level(&mut self, id: LevelId, level: Level) {
-        if let Some(existing_level) = self.levels.get_mut(id.0 as usize) {
+        if let Some(existing_level) = self.get_mut(id) {
             *existing_level = level;
         }
     }
+
+    // Check whether a LevelId is in use.
+    #[inline]
+    fn is_allocated(&self, id: LevelId) -> bool {
+        self.allocated.get(id.0 as usize).copied().unwrap_or(false)
+    }
+}
+
+// One OrderPool slot: the order in it, if any, how many orders have left it, and its
+// neighbours in its level's queue.
+#[derive(Default)]
+struct OrderSlot {
+    generation: u32,
+    entry: Option<(OrderId, RestingOrder)>,
+    prev: Option<u32>,
+    next: Option<u32>,
+}
+
+// Define a struct named OrderPool, which is an arena where every resting Order lives exactly once.
+// Slots are reused as orders leave; each level queues its orders by linking their slots.
+pub struct OrderPool {
+    slots: Vec<OrderSlot>, // A vector to store the orders and their queue links.
+    free_list: Vec<u32>,   // A vector to store free slots.
+}
+
+impl OrderPool {
+    // Constructor for creating a new OrderPool instance with default values.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free_list: Vec::new(),
+        }
+    }
+
+    // Constructor for creating a new OrderPool instance with a specified capacity.
+    pub fn new_with_capacity(capacity: usize) -> Self {
+        Self {
+            slots: Vec::with_capacity(capacity),
+            free_list: Vec::new(),
+        }
+    }
+
+    // Move an order into the pool. Reuses a free slot if available or creates a new one.
+    pub fn alloc(&mut self, oid: OrderId, order: RestingOrder) -> OrderHandle {
+        let slot = self.free_list.pop().unwrap_or_else(|| {
+            self.slots.push(OrderSlot::default());
+            (self.slots.len() - 1) as u32
+        });
+        let entry = &mut self.slots[slot as usize];
+        entry.entry = Some((oid, order));
+        OrderHandle { slot, generation: entry.generation }
+    }
+
+    // Free the slot a handle refers to, retiring the handle, and move its order out.
+    // The order must have been taken off its level's queue first.
+    pub fn free(&mut self, handle: OrderHandle) -> Option<(OrderId, RestingOrder)> {
+        let slot = self.slots.get_mut(handle.slot as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let entry = slot.entry.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.prev = None;
+        slot.next = None;
+        self.free_list.push(handle.slot);
+        Some(entry)
+    }
+
+    // Get the current handle of an occupied slot.
+    #[inline]
+    pub fn handle(&self, slot: u32) -> Option<OrderHandle> {
+        let entry = self.slots.get(slot as usize)?;
+        entry.entry.as_ref()?;
+        Some(OrderHandle { slot, generation: entry.generation })
+    }
+
+    // Get a reference to the order a handle refers to, or None if it has left the pool since.
+    #[inline]
+    pub fn get(&self, handle: OrderHandle) -> Option<(OrderId, &RestingOrder)> {
+        let slot = self.slots.get(handle.slot as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.entry.as_ref().map(|(oid, order)| (*oid, order))
+    }
+
+    // Get a mutable reference to the order a handle refers to, or None if it has left the pool since.
+    #[inline]
+    pub fn get_mut(&mut self, handle: OrderHandle) -> Option<&mut RestingOrder> {
+        let slot = self.slots.get_mut(handle.slot as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.entry.as_mut().map(|(_, order)| order)
+    }
+
+    // Append an order to the back of a level's queue.
+    pub fn push_back(&mut self, level: &mut Level, handle: OrderHandle) {
+        let tail = level.tail();
+        if let Some(entry) = self.slots.get_mut(handle.slot as usize) {
+            entry.prev = tail;
+            entry.next = None;
+        }
+        match tail.and_then(|tail| self.slots.get_mut(tail as usize)) {
+            Some(entry) => entry.next = Some(handle.slot),
+            None => level.set_head(Some(handle.slot)),
+        }
+        level.set_tail(Some(handle.slot));
+    }
+
+    // Take an order out of a level's queue, wherever it is in it.
+    // An order that isn't queued on the level leaves the queue untouched.
+    pub fn unlink(&mut self, level: &mut Level, handle: OrderHandle) {
+        let Some(entry) = self.slots.get_mut(handle.slot as usize) else {
+            return;
+        };
+        let (prev, next) = (entry.prev.take(), entry.next.take());
+        match prev {
+            Some(prev) => self.slots[prev as usize].next = next,
+            None if level.head() == Some(handle.slot) => level.set_head(next),
+            None => {}
+        }
+        match next {
+            Some(next) => self.slots[next as usize].prev = prev,
+            None if level.tail() == Some(handle.slot) => level.set_tail(prev),
+            None => {}
+        }
+    }
+
+    // Iterate over the orders queued on a level, front first.
+    pub fn queue<'a>(&'a self, level: &Level) -> impl Iterator<Item = (OrderId, &'a RestingOrder)> + 'a {
+        let mut cursor = level.head();
+        std::iter::from_fn(move || {
+            let slot = &self.slots[cursor? as usize];
+            cursor = slot.next;
+            slot.entry.as_ref().map(|(oid, order)| (*oid, order))
+        })
+    }
+
+    // Iterate over every order in the pool, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (OrderId, &RestingOrder)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.entry.as_ref().map(|(oid, order)| (*oid, order)))
+    }
+
+    // Approximate the heap memory the pool has allocated.
+    pub fn allocated_bytes(&self) -> usize {
+        self.slots.capacity() * std::mem::size_of::<OrderSlot>()
+            + self.free_list.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+impl Default for OrderPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price::Price, quantity::Qty};
+
+    #[test]
+    fn test_level_reuse() {
+        let mut pool = LevelPool::new();
+        let first = pool.alloc();
+        pool.set_level(first, Level::new(Price(100), Qty(50)));
+        let second = pool.alloc();
+        assert_eq!((pool.allocated_count(), pool.free_count()), (2, 0));
+
+        // A freed level comes back empty rather than with its old price and size
+        pool.free(first).unwrap();
+        assert!(pool.get(first).is_none());
+        assert_eq!((pool.allocated_count(), pool.free_count()), (1, 1));
+        let reused = pool.alloc();
+        assert_eq!(reused, first);
+        let level = pool.get(reused).unwrap();
+        assert_eq!((level.price(), level.size(), level.head()), (Price(0), Qty(0), None));
+        assert_eq!((pool.allocated_count(), pool.free_count()), (2, 0));
+        assert_ne!(pool.alloc(), second);
+    }
+
+    #[test]
+    fn test_level_double_free() {
+        let mut pool = LevelPool::new();
+        let first = pool.alloc();
+        let second = pool.alloc();
+        pool.free(first).unwrap();
+
+        // Freeing twice would hand `first` to two price levels
+        let result = pool.free(first);
+        println!("Double free: {:?}", result);
+        assert_eq!(result, Err(OrderBookError::UnknownLevel(first)));
+        assert_eq!(pool.free(LevelId(7)), Err(OrderBookError::UnknownLevel(LevelId(7))));
+        assert_eq!((pool.allocated_count(), pool.free_count()), (1, 1));
+        assert_eq!(pool.alloc(), first);
+        assert_ne!(pool.alloc(), second);
+        assert_eq!(pool.free_count(), 0);
+    }
 }
@@ -0,0 +1,130 @@
+// positions.rs
+
+use crate::utils::{hex_array, BookId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Net position of one trader in one book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    #[serde(with = "hex_array")]
+    pub trader: [u8; 20],
+    pub book_id: u32,
+    pub qty: i64, // Long when positive, short when negative
+}
+
+/// Positions of every trader in the books whose market tracks them, built up from fills
+/// A buy adds its quantity and a sell takes it away; flat positions are not kept.
+#[derive(Debug, Default)]
+pub struct PositionTracker {
+    positions: HashMap<[u8; 20], BTreeMap<BookId, i64>>,
+}
+
+impl PositionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuilds a tracker from the positions listed by `entries`
+    pub fn from_entries(entries: Vec<Position>) -> Self {
+        let mut tracker = Self::new();
+        for position in entries {
+            if position.qty != 0 {
+                tracker.positions.entry(position.trader).or_default().insert(BookId(position.book_id), position.qty);
+            }
+        }
+        tracker
+    }
+
+    /// Adds one side of a fill to the trader's position
+    pub fn record(&mut self, trader: [u8; 20], book_id: BookId, is_buy: bool, qty: u64) {
+        let qty = qty.min(i64::MAX as u64) as i64;
+        let books = self.positions.entry(trader).or_default();
+        let position = books.entry(book_id).or_default();
+        *position = if is_buy { position.saturating_add(qty) } else { position.saturating_sub(qty) };
+        if *position == 0 {
+            books.remove(&book_id);
+            if books.is_empty() {
+                self.positions.remove(&trader);
+            }
+        }
+    }
+
+    /// Gets the position of a trader in a book; zero when flat
+    #[inline]
+    pub fn get(&self, trader: [u8; 20], book_id: BookId) -> i64 {
+        self.positions
+            .get(&trader)
+            .and_then(|books| books.get(&book_id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Gets how much an order on the given side can trade without taking the trader's position
+    /// past flat: a short for a bid and a long for an ask
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{positions::PositionTracker, utils::BookId};
+    /// let mut positions = PositionTracker::new();
+    /// positions.record([1; 20], BookId(0), true, 5);
+    /// assert_eq!(positions.reducible([1; 20], BookId(0), false), 5);
+    /// assert_eq!(positions.reducible([1; 20], BookId(0), true), 0);
+    /// ```
+    #[inline]
+    pub fn reducible(&self, trader: [u8; 20], book_id: BookId, is_bid: bool) -> u64 {
+        let position = self.get(trader, book_id);
+        if is_bid {
+            position.min(0).unsigned_abs()
+        } else {
+            position.max(0) as u64
+        }
+    }
+
+    /// Lists the open positions of a trader in book ID order
+    pub fn trader_positions(&self, trader: [u8; 20]) -> Vec<Position> {
+        self.positions
+            .get(&trader)
+            .into_iter()
+            .flatten()
+            .map(|(book_id, &qty)| Position { trader, book_id: book_id.value(), qty })
+            .collect()
+    }
+
+    /// Lists every open position, by trader and then by book
+    pub fn entries(&self) -> Vec<Position> {
+        let mut traders: Vec<[u8; 20]> = self.positions.keys().copied().collect();
+        traders.sort_unstable();
+        traders.into_iter().flat_map(|trader| self.trader_positions(trader)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_tracker() {
+        let mut positions = PositionTracker::new();
+        positions.record([2; 20], BookId(1), true, 10);
+        positions.record([2; 20], BookId(1), false, 4);
+        positions.record([2; 20], BookId(0), false, 3);
+        positions.record([1; 20], BookId(0), true, 7);
+        assert_eq!(positions.get([2; 20], BookId(1)), 6);
+        assert_eq!(positions.reducible([2; 20], BookId(0), true), 3);
+        assert_eq!(
+            positions.trader_positions([2; 20]),
+            vec![
+                Position { trader: [2; 20], book_id: 0, qty: -3 },
+                Position { trader: [2; 20], book_id: 1, qty: 6 },
+            ]
+        );
+
+        // Flat positions are dropped, and the rest survive a round trip through entries
+        positions.record([1; 20], BookId(0), false, 7);
+        assert!(positions.trader_positions([1; 20]).is_empty());
+        let restored = PositionTracker::from_entries(positions.entries());
+        assert_eq!(restored.entries(), positions.entries());
+        assert_eq!(restored.entries().len(), 2);
+    }
+}
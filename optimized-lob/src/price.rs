@@ -1,8 +1,34 @@
 // price.rs
 
-#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Default)]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A price as the book keeps it: positive for bids, negated for asks, so both sides sort the same way.
+/// Serialized as the unsigned price and its side, leaving the sign convention inside the book.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(into = "PriceRecord", try_from = "PriceRecord")]
 pub struct Price(pub i32);
 
+#[derive(Serialize, Deserialize)]
+struct PriceRecord {
+    price: u32,
+    is_bid: bool,
+}
+
+impl From<Price> for PriceRecord {
+    fn from(price: Price) -> Self {
+        Self { price: price.absolute() as u32, is_bid: price.is_bid() }
+    }
+}
+
+impl TryFrom<PriceRecord> for Price {
+    type Error = String;
+
+    fn try_from(record: PriceRecord) -> Result<Self, Self::Error> {
+        Price::from_u32(record.price, record.is_bid).ok_or_else(|| format!("price {} is out of range", record.price))
+    }
+}
+
 impl Price {
     /// Returns the value of the price.
     #[inline]
@@ -22,9 +48,302 @@ impl Price {
         self.0.abs()
     }
 
-    /// Convert a u32 to a Price.
+    /// Convert a u32 to a Price, negated for asks.
+    /// Returns None if the price is above i32::MAX, so that neither side wraps.
     #[inline]
-    pub fn from_u32(price: u32, is_bid: bool) -> Self {
-        Self(price as i32 * if is_bid { 1 } else { -1 })
+    pub fn from_u32(price: u32, is_bid: bool) -> Option<Self> {
+        let price = i32::try_from(price).ok()?;
+        Some(Self(if is_bid { price } else { -price }))
+    }
+}
+
+/// How a market writes its prices in decimal: the book's integer prices carry `decimals`
+/// decimal places, and only whole multiples of `tick_size` of them are valid prices.
+/// The default writes book prices as they are and allows every one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriceScale {
+    pub decimals: u8,
+    pub tick_size: u32, // In book units; never zero
+}
+
+impl Default for PriceScale {
+    fn default() -> Self {
+        Self { decimals: 0, tick_size: 1 }
+    }
+}
+
+/// Why a decimal price was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriceError {
+    Malformed(String),  // Not a plain decimal number such as "1234.56"
+    OutOfRange(String), // Above the highest price a book holds
+    InvalidTick { price: String, tick_size: String }, // Between two ticks of the market
+}
+
+impl fmt::Display for PriceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PriceError::Malformed(price) => write!(f, "Price {:?} is not a decimal number", price),
+            PriceError::OutOfRange(price) => write!(f, "Price {} is out of range", price),
+            PriceError::InvalidTick { price, tick_size } => {
+                write!(f, "Price {} is not a multiple of the tick size {}", price, tick_size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PriceError {}
+
+/// A price as the API carries it: an integer in book units, or a decimal string such as
+/// "1234.56" in the market's decimal places, which is how responses write prices of markets
+/// that have them. Neither form passes through a float.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ApiPrice {
+    Units(i64),
+    Decimal(String),
+}
+
+impl From<u32> for ApiPrice {
+    fn from(price: u32) -> Self {
+        ApiPrice::Units(price as i64)
+    }
+}
+
+impl From<i32> for ApiPrice {
+    fn from(price: i32) -> Self {
+        ApiPrice::Units(price as i64)
+    }
+}
+
+impl From<&str> for ApiPrice {
+    fn from(price: &str) -> Self {
+        ApiPrice::Decimal(price.to_string())
+    }
+}
+
+impl PriceScale {
+    /// Creates a scale with `decimals` decimal places; a tick size of zero is taken as one
+    pub fn new(decimals: u8, tick_size: u32) -> Self {
+        Self { decimals, tick_size: tick_size.max(1) }
+    }
+
+    /// Reads a non-negative decimal price such as "1234.56" into book units, exactly
+    /// Digits past the market's decimal places must be zeros, and the price must be a whole
+    /// number of ticks; either way a price between two ticks fails with InvalidTick.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::price::PriceScale;
+    /// let cents = PriceScale::new(2, 5);
+    /// assert_eq!(cents.parse("1234.55"), Ok(123455));
+    /// assert_eq!(cents.parse("0.3"), Ok(30));
+    /// assert!(cents.parse("1234.56").is_err());
+    /// ```
+    pub fn parse(&self, text: &str) -> Result<u32, PriceError> {
+        let malformed = || PriceError::Malformed(text.to_string());
+        let (whole, fraction) = match text.split_once('.') {
+            Some((_, "")) => return Err(malformed()),
+            Some((whole, fraction)) => (whole, fraction),
+            None => (text, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) {
+            return Err(malformed());
+        }
+        let decimals = self.decimals as usize;
+        let (kept, rest) = fraction.split_at(fraction.len().min(decimals));
+        if rest.bytes().any(|byte| byte != b'0') {
+            return Err(PriceError::InvalidTick { price: text.to_string(), tick_size: self.format(self.tick_size) });
+        }
+        let padding = std::iter::repeat_n(b'0', decimals - kept.len());
+        let mut scaled: u32 = 0;
+        for digit in whole.bytes().chain(kept.bytes()).chain(padding) {
+            scaled = scaled
+                .checked_mul(10)
+                .and_then(|scaled| scaled.checked_add((digit - b'0') as u32))
+                .filter(|&scaled| scaled <= i32::MAX as u32)
+                .ok_or_else(|| PriceError::OutOfRange(text.to_string()))?;
+        }
+        self.check_tick(scaled)?;
+        Ok(scaled)
+    }
+
+    /// Reads a decimal price that may carry a minus sign, as submitted prices do for asks
+    pub fn parse_signed(&self, text: &str) -> Result<i32, PriceError> {
+        match text.strip_prefix('-') {
+            Some(price) => Ok(-(self.parse(price)? as i32)),
+            None => Ok(self.parse(text)? as i32),
+        }
+    }
+
+    /// Fails with InvalidTick unless `price`, in book units, is a whole number of ticks
+    pub fn check_tick(&self, price: u32) -> Result<(), PriceError> {
+        match price % self.tick_size {
+            0 => Ok(()),
+            _ => Err(PriceError::InvalidTick { price: self.format(price), tick_size: self.format(self.tick_size) }),
+        }
+    }
+
+    /// Reads a non-negative price sent to the API into book units, checking it is on a tick
+    pub fn read(&self, price: &ApiPrice) -> Result<u32, PriceError> {
+        match price {
+            ApiPrice::Units(units) => {
+                let units = u32::try_from(*units)
+                    .ok()
+                    .filter(|&units| units <= i32::MAX as u32)
+                    .ok_or_else(|| PriceError::OutOfRange(units.to_string()))?;
+                self.check_tick(units)?;
+                Ok(units)
+            }
+            ApiPrice::Decimal(text) => self.parse(text),
+        }
+    }
+
+    /// Reads a price sent to the API whose sign carries the side, checking it is on a tick
+    pub fn read_signed(&self, price: &ApiPrice) -> Result<i32, PriceError> {
+        match price {
+            ApiPrice::Units(units) => {
+                let magnitude = self.read(&ApiPrice::Units(units.abs()))? as i32;
+                Ok(if *units < 0 { -magnitude } else { magnitude })
+            }
+            ApiPrice::Decimal(text) => self.parse_signed(text),
+        }
+    }
+
+    /// Writes a price in book units the way API responses carry it: as it is in markets without
+    /// decimal places, as a decimal string in the others
+    pub fn write(&self, price: u32) -> ApiPrice {
+        match self.decimals {
+            0 => ApiPrice::from(price),
+            _ => ApiPrice::Decimal(self.format(price)),
+        }
+    }
+
+    /// Converts a price in book units to a float in the market's decimal places, for values
+    /// such as a VWAP that are only ever shown
+    pub fn to_f64(self, price: f64) -> f64 {
+        price / 10f64.powi(self.decimals as i32)
+    }
+
+    /// Writes a price in book units as a decimal with exactly the market's decimal places
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::price::PriceScale;
+    /// assert_eq!(PriceScale::new(2, 1).format(123450), "1234.50");
+    /// assert_eq!(PriceScale::new(3, 1).format(5), "0.005");
+    /// assert_eq!(PriceScale::default().format(1000), "1000");
+    /// ```
+    pub fn format(&self, price: u32) -> String {
+        let decimals = self.decimals as usize;
+        if decimals == 0 {
+            return price.to_string();
+        }
+        let digits = format!("{:0>width$}", price, width = decimals + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - decimals);
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_u32() {
+        assert_eq!(Price::from_u32(100, true), Some(Price(100)));
+        assert_eq!(Price::from_u32(100, false), Some(Price(-100)));
+        assert_eq!(Price::from_u32(i32::MAX as u32, false), Some(Price(-i32::MAX)));
+        assert_eq!(Price::from_u32(i32::MAX as u32, false).unwrap().absolute(), i32::MAX);
+
+        // i32::MIN has no positive counterpart, so neither it nor anything above i32::MAX is a price
+        for price in [i32::MAX as u32 + 1, i32::MIN.unsigned_abs(), u32::MAX] {
+            assert_eq!(Price::from_u32(price, true), None);
+            assert_eq!(Price::from_u32(price, false), None);
+        }
+    }
+
+    #[test]
+    fn test_price_serde() {
+        for price in [Price(100), Price(-100), Price(-i32::MAX)] {
+            let json = serde_json::to_string(&price).unwrap();
+            println!("{:?}: {}", price, json);
+            assert_eq!(serde_json::from_str::<Price>(&json).unwrap(), price);
+        }
+        assert_eq!(serde_json::to_string(&Price(-100)).unwrap(), r#"{"price":100,"is_bid":false}"#);
+        assert!(serde_json::from_str::<Price>(r#"{"price":2147483648,"is_bid":true}"#).is_err());
+    }
+
+    #[test]
+    fn test_price_scale() {
+        let cents = PriceScale::new(2, 1);
+        for (text, price) in [("1234.56", 123456), ("1234.5", 123450), ("1234", 123400), ("0.01", 1), ("007.10", 710)] {
+            assert_eq!(cents.parse(text), Ok(price));
+        }
+        // Prices are read digit by digit, never through floats: 0.1 + 0.2 is exactly 0.3
+        let tenths = PriceScale::new(1, 1);
+        assert_eq!(tenths.parse("0.1").unwrap() + tenths.parse("0.2").unwrap(), tenths.parse("0.3").unwrap());
+        assert_eq!(PriceScale::new(17, 1).parse("0.00000000000000003"), Ok(3));
+        assert_eq!(cents.parse("1234.560000"), Ok(123456));
+
+        for text in ["", ".5", "5.", "1,5", "1e3", "+1", " 1", "0x10", "1.2.3", "-1"] {
+            assert_eq!(cents.parse(text), Err(PriceError::Malformed(text.to_string())), "{:?}", text);
+        }
+        assert_eq!(cents.parse("21474836.47"), Ok(i32::MAX as u32));
+        for text in ["21474836.48", "99999999999999999999"] {
+            assert_eq!(cents.parse(text), Err(PriceError::OutOfRange(text.to_string())));
+        }
+
+        // Signed prices carry the side
+        assert_eq!(cents.parse_signed("-0.3"), Ok(-30));
+        assert_eq!(cents.parse_signed("0.3"), Ok(30));
+        assert!(cents.parse_signed("--0.3").is_err());
+
+        for (price, text) in [(0, "0.00"), (7, "0.07"), (123456, "1234.56"), (i32::MAX as u32, "21474836.47")] {
+            assert_eq!(cents.format(price), text);
+            assert_eq!(cents.parse(&cents.format(price)), Ok(price));
+        }
+    }
+
+    #[test]
+    fn test_tick_size() {
+        // Nickels: prices in cents, every fifth of them valid
+        let nickels = PriceScale::new(2, 5);
+        assert_eq!(nickels.parse("100.05"), Ok(10005));
+        assert_eq!(nickels.parse("100.1"), Ok(10010));
+        let off_tick = PriceError::InvalidTick { price: "100.01".to_string(), tick_size: "0.05".to_string() };
+        assert_eq!(nickels.parse("100.01"), Err(off_tick.clone()));
+        assert_eq!(nickels.check_tick(10001), Err(off_tick));
+        assert_eq!(nickels.parse_signed("-100.03").unwrap_err().to_string(), "Price 100.03 is not a multiple of the tick size 0.05");
+
+        // A digit past the market's decimals is between ticks, not a rounding to make
+        let finer = nickels.parse("100.051").unwrap_err();
+        assert_eq!(finer, PriceError::InvalidTick { price: "100.051".to_string(), tick_size: "0.05".to_string() });
+
+        // Ticks of whole units, and a zero tick size taken as one
+        assert_eq!(PriceScale::new(0, 10).parse("25"), Err(PriceError::InvalidTick { price: "25".to_string(), tick_size: "10".to_string() }));
+        assert_eq!(PriceScale::new(0, 0), PriceScale::default());
+        assert_eq!(PriceScale::default().check_tick(12345), Ok(()));
+    }
+
+    #[test]
+    fn test_api_price() {
+        let nickels = PriceScale::new(2, 5);
+        assert_eq!(serde_json::from_str::<ApiPrice>(r#""100.05""#).unwrap(), ApiPrice::from("100.05"));
+        assert_eq!(serde_json::from_str::<ApiPrice>("-10005").unwrap(), ApiPrice::from(-10005));
+        for price in [ApiPrice::from("100.05"), ApiPrice::from(10005u32)] {
+            assert_eq!(nickels.read(&price), Ok(10005));
+        }
+        assert_eq!(nickels.read_signed(&ApiPrice::from("-100.05")), Ok(-10005));
+        assert_eq!(nickels.read_signed(&ApiPrice::from(-10005)), Ok(-10005));
+        // Integers are book units, held to the same ticks and range as decimals
+        assert!(matches!(nickels.read_signed(&ApiPrice::from(-10003)), Err(PriceError::InvalidTick { .. })));
+        assert_eq!(nickels.read(&ApiPrice::Units(-5)), Err(PriceError::OutOfRange("-5".to_string())));
+        assert!(nickels.read_signed(&ApiPrice::Units(i32::MIN as i64)).is_err());
+
+        // Responses keep integers in markets without decimals
+        assert_eq!(serde_json::to_string(&PriceScale::default().write(1000)).unwrap(), "1000");
+        assert_eq!(serde_json::to_string(&nickels.write(10005)).unwrap(), r#""100.05""#);
     }
 }
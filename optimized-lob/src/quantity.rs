@@ -1,9 +1,11 @@
 //quantity.rs
 
+use serde::{Deserialize, Serialize};
 use std::ops::{AddAssign, SubAssign, Sub};
 
-#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
-pub struct Qty(pub u32);
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Qty(pub u64);
 
 impl AddAssign for Qty {
     fn add_assign(&mut self, other: Qty) {
@@ -27,7 +29,7 @@ impl Sub for Qty {
 
 impl Qty {
     #[inline]
-    pub fn value(&self) -> u32 {
+    pub fn value(&self) -> u64 {
         self.0
     }
 
@@ -35,4 +37,45 @@ impl Qty {
     pub fn is_empty(&self) -> bool {
         self.0 == 0
     }
+
+    /// Adds two quantities, or None if the sum doesn't fit in a u64.
+    #[inline]
+    pub fn checked_add(self, other: Qty) -> Option<Qty> {
+        self.0.checked_add(other.0).map(Qty)
+    }
+
+    /// Subtracts `other`, or None if it is larger than `self`.
+    #[inline]
+    pub fn checked_sub(self, other: Qty) -> Option<Qty> {
+        self.0.checked_sub(other.0).map(Qty)
+    }
+
+    /// Subtracts `other`, stopping at zero.
+    #[inline]
+    pub fn saturating_sub(self, other: Qty) -> Qty {
+        Qty(self.0.saturating_sub(other.0))
+    }
+
+    /// Adds `other`, stopping at u64::MAX.
+    #[inline]
+    pub fn saturating_add(self, other: Qty) -> Qty {
+        Qty(self.0.saturating_add(other.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Qty(u64::MAX - 1).checked_add(Qty(1)), Some(Qty(u64::MAX)));
+        assert_eq!(Qty(u64::MAX).checked_add(Qty(1)), None);
+        assert_eq!(Qty(10).checked_sub(Qty(10)), Some(Qty(0)));
+        assert_eq!(Qty(10).checked_sub(Qty(11)), None);
+        assert_eq!(Qty(0).checked_sub(Qty(u64::MAX)), None);
+        assert_eq!(Qty(10).saturating_sub(Qty(11)), Qty(0));
+        assert_eq!(Qty(u64::MAX).saturating_sub(Qty(1)), Qty(u64::MAX - 1));
+        assert_eq!(Qty(u64::MAX).saturating_add(Qty(1)), Qty(u64::MAX));
+    }
 }
@@ -0,0 +1,475 @@
+// rfq.rs
+
+use crate::{
+    order::{Order, OrderId, Signature},
+    price::Price,
+    quantity::Qty,
+    utils::{hex_array, BookId, RFQ_NOTICE_CHANNEL_CAPACITY, RFQ_RETENTION_SECS},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use tokio::sync::broadcast;
+
+/// Where an RFQ is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RfqState {
+    Open,     // Waiting for its first quote
+    Quoted,   // At least one maker has quoted it
+    Accepted, // The requester took a quote and the block traded
+    Expired,  // Its expiry passed before a quote was taken
+}
+
+impl RfqState {
+    /// Returns true once nothing more can be quoted or accepted
+    #[inline]
+    pub fn is_final(&self) -> bool {
+        matches!(self, RfqState::Accepted | RfqState::Expired)
+    }
+}
+
+/// Why an RFQ, a quote, or an acceptance was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RfqError {
+    UnknownRfq(u64),
+    UnknownQuote(u64),
+    Closed(RfqState), // The RFQ was already accepted or has expired
+    ExpiryPassed,     // An RFQ was created with an expiry that has already passed
+    QuoteExpired(u64),
+    NotMaker,     // The quoting trader isn't a registered maker
+    NotRequester, // Only the trader who asked for quotes can accept one
+    Mismatch,     // The signed order isn't for the RFQ's book, side and quantity, or the quote's price
+}
+
+impl fmt::Display for RfqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RfqError::UnknownRfq(rfq_id) => write!(f, "RFQ {} doesn't exist", rfq_id),
+            RfqError::UnknownQuote(quote_id) => write!(f, "Quote {} doesn't exist", quote_id),
+            RfqError::Closed(state) => write!(f, "The RFQ is {:?} and takes no more quotes", state),
+            RfqError::ExpiryPassed => write!(f, "The RFQ's expiry has already passed"),
+            RfqError::QuoteExpired(quote_id) => write!(f, "Quote {} has expired", quote_id),
+            RfqError::NotMaker => write!(f, "Only registered makers can quote RFQs"),
+            RfqError::NotRequester => write!(f, "Only the trader who asked for quotes can accept one"),
+            RfqError::Mismatch => {
+                write!(f, "The signed order doesn't match the RFQ's book, side and quantity, or the quote's price")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RfqError {}
+
+/// A maker's firm price for the whole of an RFQ, backed by the maker's signed order
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub quote_id: u64,
+    pub maker: [u8; 20],
+    pub price: u32,
+    pub expires_at: u64, // The sooner of the signed order's expiry and the RFQ's
+    pub order: Order,
+}
+
+/// A taker asking makers to price a block too big for the book
+/// Times are in seconds since the Unix epoch, like the expiries orders are signed with.
+#[derive(Debug, Clone)]
+pub struct Rfq {
+    pub rfq_id: u64,
+    pub book_id: BookId,
+    pub is_bid: bool, // The requester's side
+    pub qty: Qty,
+    pub requester: [u8; 20],
+    pub expires_at: u64,
+    pub state: RfqState,
+    pub quotes: Vec<Quote>,
+    pub accepted: Option<(u64, u64)>, // Quote ID and trade ID of the block, once accepted
+}
+
+impl Rfq {
+    pub fn quote(&self, quote_id: u64) -> Option<&Quote> {
+        self.quotes.iter().find(|quote| quote.quote_id == quote_id)
+    }
+
+    /// Checks that `order` is signed for the side of the RFQ `is_bid` takes, in its book and for its quantity
+    fn matches(&self, order: &Order, is_bid: bool) -> bool {
+        order.book_id() == self.book_id && order.price().is_bid() == is_bid && order.qty() == self.qty
+    }
+}
+
+/// What happened to an RFQ, as told to one trader on their private stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RfqNotice {
+    #[serde(skip)]
+    pub trader: [u8; 20], // Who the notice is for
+    pub rfq_id: u64,
+    #[serde(flatten)]
+    pub event: RfqEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RfqEvent {
+    /// To every registered maker, for them to quote
+    Requested { book_id: u32, is_bid: bool, qty: u64, expires_at: u64 },
+    /// To the requester; the price is in book units
+    Quoted { quote_id: u64, price: u32, expires_at: u64 },
+    /// To the requester and the maker whose quote was taken
+    Accepted { quote_id: u64, trade_id: u64 },
+    /// To the requester and every maker that quoted
+    Expired,
+}
+
+/// One side of a block trade: a signed order as the settlement needs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockOrder {
+    pub order_id: u64,
+    #[serde(with = "hex_array")]
+    pub trader: [u8; 20],
+    pub is_bid: bool,
+    pub nonce: u64,
+    pub expiry: u64,
+    pub signature: Signature,
+}
+
+impl BlockOrder {
+    /// Takes the side of a block trade from a signed order, or None if it has no trader or nonce
+    pub fn new(order_id: OrderId, order: &Order) -> Option<Self> {
+        Some(Self {
+            order_id: order_id.0,
+            trader: order.trader()?,
+            is_bid: order.price().is_bid(),
+            nonce: order.nonce()?,
+            expiry: order.expiry().unwrap_or(u64::MAX),
+            signature: order.signature(),
+        })
+    }
+}
+
+/// A block traded between a maker's quote and the requester's order, off the book
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockTrade {
+    pub book_id: u32,
+    pub qty: u64,
+    pub price: u32,
+    pub maker: BlockOrder,
+    pub taker: BlockOrder,
+}
+
+impl BlockTrade {
+    /// Gets the signed order of one side back, at the block's price and quantity
+    /// A price too large to sign comes back as zero, which no settlement takes.
+    pub fn order(&self, side: &BlockOrder) -> Order {
+        Order::new_submission(
+            Qty(self.qty),
+            Price::from_u32(self.price, side.is_bid).unwrap_or(Price(0)),
+            BookId(self.book_id),
+            side.trader,
+            side.nonce,
+            side.expiry,
+            side.signature,
+        )
+    }
+}
+
+/// Open and recent RFQs, and the makers asked to quote them
+/// RFQs and quotes live in memory only: they are neither logged nor snapshotted, and a restart
+/// drops the ones still open. Only the block trade an acceptance makes is logged. Closed RFQs
+/// are kept for RFQ_RETENTION_SECS past their expiry, then forgotten.
+#[derive(Debug)]
+pub struct RfqManager {
+    rfqs: BTreeMap<u64, Rfq>,
+    makers: BTreeSet<[u8; 20]>,
+    next_rfq_id: u64,
+    next_quote_id: u64,
+    notices: broadcast::Sender<RfqNotice>,
+}
+
+impl Default for RfqManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RfqManager {
+    pub fn new() -> Self {
+        let (notices, _) = broadcast::channel(RFQ_NOTICE_CHANNEL_CAPACITY);
+        Self { rfqs: BTreeMap::new(), makers: BTreeSet::new(), next_rfq_id: 1, next_quote_id: 1, notices }
+    }
+
+    /// Subscribes to every notice published after this call, for all traders
+    pub fn subscribe(&self) -> broadcast::Receiver<RfqNotice> {
+        self.notices.subscribe()
+    }
+
+    /// Registers a maker to be told of new RFQs and allowed to quote them; false if it already was
+    pub fn register_maker(&mut self, maker: [u8; 20]) -> bool {
+        self.makers.insert(maker)
+    }
+
+    /// Stops asking a maker for quotes; its quotes already given stand. False if it wasn't registered.
+    pub fn unregister_maker(&mut self, maker: [u8; 20]) -> bool {
+        self.makers.remove(&maker)
+    }
+
+    #[inline]
+    pub fn is_maker(&self, trader: [u8; 20]) -> bool {
+        self.makers.contains(&trader)
+    }
+
+    pub fn get(&self, rfq_id: u64) -> Option<&Rfq> {
+        self.rfqs.get(&rfq_id)
+    }
+
+    /// Gets every RFQ still kept, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &Rfq> {
+        self.rfqs.values()
+    }
+
+    /// Opens an RFQ for `qty` on side `is_bid` of a book and tells every registered maker
+    /// Fails with ExpiryPassed unless `expires_at` is after `now`.
+    pub fn create(
+        &mut self,
+        book_id: BookId,
+        is_bid: bool,
+        qty: Qty,
+        requester: [u8; 20],
+        expires_at: u64,
+        now: u64,
+    ) -> Result<&Rfq, RfqError> {
+        if expires_at <= now {
+            return Err(RfqError::ExpiryPassed);
+        }
+        let rfq_id = self.next_rfq_id;
+        self.next_rfq_id += 1;
+        let event = RfqEvent::Requested { book_id: book_id.value(), is_bid, qty: qty.value(), expires_at };
+        for &maker in &self.makers {
+            publish(&self.notices, RfqNotice { trader: maker, rfq_id, event: event.clone() });
+        }
+        let rfq = Rfq {
+            rfq_id,
+            book_id,
+            is_bid,
+            qty,
+            requester,
+            expires_at,
+            state: RfqState::Open,
+            quotes: Vec::new(),
+            accepted: None,
+        };
+        Ok(self.rfqs.entry(rfq_id).or_insert(rfq))
+    }
+
+    /// Adds a registered maker's quote, backed by its signed order on the other side of the RFQ
+    /// for the whole quantity, and tells the requester
+    /// The caller checks the order's signature and nonce first, as for any order. The quote is
+    /// good until the order's expiry, or the RFQ's if that comes sooner.
+    pub fn quote(&mut self, rfq_id: u64, order: Order, now: u64) -> Result<&Quote, RfqError> {
+        let maker = order.trader().filter(|&maker| self.is_maker(maker)).ok_or(RfqError::NotMaker)?;
+        let rfq = Self::open(&mut self.rfqs, rfq_id, now)?;
+        if !rfq.matches(&order, !rfq.is_bid) {
+            return Err(RfqError::Mismatch);
+        }
+        let expires_at = match order.expiry() {
+            Some(expiry) if expiry != 0 => expiry.min(rfq.expires_at),
+            _ => rfq.expires_at,
+        };
+        let quote_id = self.next_quote_id;
+        self.next_quote_id += 1;
+        let price = order.price().absolute() as u32;
+        rfq.quotes.push(Quote { quote_id, maker, price, expires_at, order });
+        rfq.state = RfqState::Quoted;
+        let notice = RfqNotice { trader: rfq.requester, rfq_id, event: RfqEvent::Quoted { quote_id, price, expires_at } };
+        publish(&self.notices, notice);
+        Ok(&rfq.quotes[rfq.quotes.len() - 1])
+    }
+
+    /// Checks that the requester's signed `order` can take a quote, and gets the quote
+    /// The order must be the requester's, on the RFQ's side for its quantity at the quote's
+    /// price, and neither the RFQ nor the quote may have expired at `now`. Nothing changes until
+    /// the block has traded and `accepted` is called.
+    pub fn check_accept(&self, rfq_id: u64, quote_id: u64, order: &Order, now: u64) -> Result<&Quote, RfqError> {
+        let rfq = self.rfqs.get(&rfq_id).ok_or(RfqError::UnknownRfq(rfq_id))?;
+        if rfq.state.is_final() || rfq.expires_at <= now {
+            return Err(RfqError::Closed(if rfq.state.is_final() { rfq.state } else { RfqState::Expired }));
+        }
+        let quote = rfq.quote(quote_id).ok_or(RfqError::UnknownQuote(quote_id))?;
+        if quote.expires_at <= now {
+            return Err(RfqError::QuoteExpired(quote_id));
+        }
+        if order.trader() != Some(rfq.requester) {
+            return Err(RfqError::NotRequester);
+        }
+        if !rfq.matches(order, rfq.is_bid) || order.price().absolute() as u32 != quote.price {
+            return Err(RfqError::Mismatch);
+        }
+        Ok(quote)
+    }
+
+    /// Closes an RFQ whose quote was taken and traded as `trade_id`, and tells both sides
+    pub fn accepted(&mut self, rfq_id: u64, quote_id: u64, trade_id: u64) {
+        let Some(rfq) = self.rfqs.get_mut(&rfq_id) else {
+            return;
+        };
+        rfq.state = RfqState::Accepted;
+        rfq.accepted = Some((quote_id, trade_id));
+        let maker = rfq.quote(quote_id).map(|quote| quote.maker);
+        let event = RfqEvent::Accepted { quote_id, trade_id };
+        for trader in std::iter::once(rfq.requester).chain(maker) {
+            publish(&self.notices, RfqNotice { trader, rfq_id, event: event.clone() });
+        }
+    }
+
+    /// Expires the RFQs whose expiry has passed at `now` without a quote taken, and returns their IDs
+    /// The server's timer calls this with the expiry of orders. Closed RFQs past their retention
+    /// are forgotten.
+    pub fn expire(&mut self, now: u64) -> Vec<u64> {
+        let mut expired = Vec::new();
+        for rfq in self.rfqs.values_mut() {
+            if rfq.state.is_final() || rfq.expires_at > now {
+                continue;
+            }
+            rfq.state = RfqState::Expired;
+            expired.push(rfq.rfq_id);
+            let makers: BTreeSet<[u8; 20]> = rfq.quotes.iter().map(|quote| quote.maker).collect();
+            for trader in std::iter::once(rfq.requester).chain(makers) {
+                publish(&self.notices, RfqNotice { trader, rfq_id: rfq.rfq_id, event: RfqEvent::Expired });
+            }
+        }
+        self.rfqs.retain(|_, rfq| !rfq.state.is_final() || rfq.expires_at.saturating_add(RFQ_RETENTION_SECS) > now);
+        expired
+    }
+
+    /// Gets an RFQ that still takes quotes at `now`
+    fn open(rfqs: &mut BTreeMap<u64, Rfq>, rfq_id: u64, now: u64) -> Result<&mut Rfq, RfqError> {
+        let rfq = rfqs.get_mut(&rfq_id).ok_or(RfqError::UnknownRfq(rfq_id))?;
+        if rfq.state.is_final() {
+            return Err(RfqError::Closed(rfq.state));
+        }
+        if rfq.expires_at <= now {
+            return Err(RfqError::Closed(RfqState::Expired));
+        }
+        Ok(rfq)
+    }
+}
+
+/// Publishes a notice; like order updates, skipped when nobody listens
+#[inline]
+fn publish(notices: &broadcast::Sender<RfqNotice>, notice: RfqNotice) {
+    if notices.receiver_count() > 0 {
+        let _ = notices.send(notice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTER: [u8; 20] = [1; 20];
+    const MAKER: [u8; 20] = [2; 20];
+
+    /// A signed order of `trader` in book 0, its nonce the order's place in the test
+    fn order(trader: [u8; 20], is_bid: bool, qty: u64, price: u32, expiry: u64) -> Order {
+        let price = Price::from_u32(price, is_bid).unwrap();
+        Order::new_submission(Qty(qty), price, BookId(0), trader, 1, expiry, Signature::default())
+    }
+
+    /// A manager with MAKER registered and an RFQ to buy 1,000 open until 100
+    fn manager() -> (RfqManager, u64) {
+        let mut rfqs = RfqManager::new();
+        rfqs.register_maker(MAKER);
+        let rfq_id = rfqs.create(BookId(0), true, Qty(1_000), REQUESTER, 100, 0).unwrap().rfq_id;
+        (rfqs, rfq_id)
+    }
+
+    #[test]
+    fn test_quote_and_accept() {
+        let (mut rfqs, rfq_id) = manager();
+        let mut notices = rfqs.subscribe();
+        let quote = rfqs.quote(rfq_id, order(MAKER, false, 1_000, 250, 0), 10).unwrap();
+        assert_eq!((quote.quote_id, quote.price, quote.expires_at), (1, 250, 100));
+        assert_eq!(rfqs.get(rfq_id).unwrap().state, RfqState::Quoted);
+        let notice = notices.try_recv().unwrap();
+        assert_eq!((notice.trader, notice.event), (REQUESTER, RfqEvent::Quoted { quote_id: 1, price: 250, expires_at: 100 }));
+
+        // The acceptance must be the requester's, at the quote's price, for the whole block
+        let taker = order(REQUESTER, true, 1_000, 250, 0);
+        assert_eq!(rfqs.check_accept(rfq_id, 1, &taker, 20).unwrap().maker, MAKER);
+        let cases = [
+            (order(MAKER, true, 1_000, 250, 0), RfqError::NotRequester),
+            (order(REQUESTER, true, 1_000, 251, 0), RfqError::Mismatch),
+            (order(REQUESTER, true, 999, 250, 0), RfqError::Mismatch),
+            (order(REQUESTER, false, 1_000, 250, 0), RfqError::Mismatch),
+        ];
+        for (taker, error) in cases {
+            assert_eq!(rfqs.check_accept(rfq_id, 1, &taker, 20).unwrap_err(), error);
+        }
+        assert_eq!(rfqs.check_accept(rfq_id, 2, &taker, 20).unwrap_err(), RfqError::UnknownQuote(2));
+
+        rfqs.accepted(rfq_id, 1, 7);
+        let rfq = rfqs.get(rfq_id).unwrap();
+        assert_eq!((rfq.state, rfq.accepted), (RfqState::Accepted, Some((1, 7))));
+        let told: Vec<[u8; 20]> = std::iter::from_fn(|| notices.try_recv().ok()).map(|notice| notice.trader).collect();
+        assert_eq!(told, vec![REQUESTER, MAKER]);
+        // Nothing more is quoted or accepted
+        assert_eq!(rfqs.check_accept(rfq_id, 1, &taker, 20).unwrap_err(), RfqError::Closed(RfqState::Accepted));
+        let error = rfqs.quote(rfq_id, order(MAKER, false, 1_000, 240, 0), 20).unwrap_err();
+        assert_eq!(error, RfqError::Closed(RfqState::Accepted));
+    }
+
+    #[test]
+    fn test_quotes_refused() {
+        let (mut rfqs, rfq_id) = manager();
+        let cases = [
+            (order([3; 20], false, 1_000, 250, 0), RfqError::NotMaker),
+            (order(MAKER, true, 1_000, 250, 0), RfqError::Mismatch), // Same side as the requester
+            (order(MAKER, false, 500, 250, 0), RfqError::Mismatch),
+        ];
+        for (quote, error) in cases {
+            assert_eq!(rfqs.quote(rfq_id, quote, 10).unwrap_err(), error);
+        }
+        assert_eq!(rfqs.quote(9, order(MAKER, false, 1_000, 250, 0), 10).unwrap_err(), RfqError::UnknownRfq(9));
+        assert_eq!(rfqs.get(rfq_id).unwrap().state, RfqState::Open);
+        assert_eq!(rfqs.create(BookId(0), true, Qty(1), REQUESTER, 10, 10).unwrap_err(), RfqError::ExpiryPassed);
+
+        // A maker that is no longer registered can't quote
+        rfqs.unregister_maker(MAKER);
+        assert_eq!(rfqs.quote(rfq_id, order(MAKER, false, 1_000, 250, 0), 10).unwrap_err(), RfqError::NotMaker);
+    }
+
+    #[test]
+    fn test_stale_quote() {
+        let (mut rfqs, rfq_id) = manager();
+        // Signed to expire at 50, before the RFQ does
+        rfqs.quote(rfq_id, order(MAKER, false, 1_000, 250, 50), 10).unwrap();
+        rfqs.quote(rfq_id, order(MAKER, false, 1_000, 260, 0), 10).unwrap();
+        let taker = order(REQUESTER, true, 1_000, 250, 0);
+        assert!(rfqs.check_accept(rfq_id, 1, &taker, 49).is_ok());
+        assert_eq!(rfqs.check_accept(rfq_id, 1, &taker, 50).unwrap_err(), RfqError::QuoteExpired(1));
+        // The other quote still stands
+        let taker = order(REQUESTER, true, 1_000, 260, 0);
+        assert_eq!(rfqs.check_accept(rfq_id, 2, &taker, 50).unwrap().expires_at, 100);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let (mut rfqs, rfq_id) = manager();
+        rfqs.quote(rfq_id, order(MAKER, false, 1_000, 250, 0), 10).unwrap();
+        let mut notices = rfqs.subscribe();
+        assert!(rfqs.expire(99).is_empty());
+        assert_eq!(rfqs.expire(100), vec![rfq_id]);
+        assert_eq!(rfqs.get(rfq_id).unwrap().state, RfqState::Expired);
+        let told: Vec<[u8; 20]> = std::iter::from_fn(|| notices.try_recv().ok()).map(|notice| notice.trader).collect();
+        assert_eq!(told, vec![REQUESTER, MAKER]);
+        assert!(rfqs.expire(101).is_empty());
+
+        // An RFQ past its expiry takes nothing, even before the timer gets to it
+        let rfq_id = rfqs.create(BookId(0), false, Qty(5), REQUESTER, 200, 100).unwrap().rfq_id;
+        let quote = order(MAKER, true, 5, 250, 0);
+        assert_eq!(rfqs.quote(rfq_id, quote, 200).unwrap_err(), RfqError::Closed(RfqState::Expired));
+
+        // Closed RFQs are forgotten once their retention is up
+        rfqs.expire(100 + RFQ_RETENTION_SECS);
+        assert_eq!(rfqs.iter().map(|rfq| rfq.rfq_id).collect::<Vec<_>>(), vec![rfq_id]);
+    }
+}
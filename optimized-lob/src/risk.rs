@@ -0,0 +1,194 @@
+// risk.rs
+
+use crate::{
+    market::RiskLimits,
+    order::OrderId,
+    quantity::Qty,
+    utils::BookId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// A risk limit an order would have taken its trader past, with the limit's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskLimit {
+    OpenOrders(u32),
+    OpenNotional(u64),
+}
+
+impl fmt::Display for RiskLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RiskLimit::OpenOrders(limit) => write!(f, "at most {} open orders per trader", limit),
+            RiskLimit::OpenNotional(limit) => write!(f, "at most {} open notional per trader", limit),
+        }
+    }
+}
+
+/// What a trader has resting in one book
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenUsage {
+    pub open_orders: u32,
+    pub open_notional: u64, // Sum of price × remaining quantity, saturating
+}
+
+impl OpenUsage {
+    /// Checks that `orders` more orders worth `notional` in all keep the trader within `limits`
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::{market::RiskLimits, risk::{OpenUsage, RiskLimit}};
+    /// let limits = RiskLimits { max_open_orders: 2, max_open_notional: 1000 };
+    /// let usage = OpenUsage { open_orders: 1, open_notional: 600 };
+    /// assert_eq!(usage.check(&limits, 1, 400), Ok(()));
+    /// assert_eq!(usage.check(&limits, 2, 0), Err(RiskLimit::OpenOrders(2)));
+    /// assert_eq!(usage.check(&limits, 1, 401), Err(RiskLimit::OpenNotional(1000)));
+    /// ```
+    pub fn check(&self, limits: &RiskLimits, orders: u32, notional: u64) -> Result<(), RiskLimit> {
+        if self.open_orders.saturating_add(orders) > limits.max_open_orders {
+            return Err(RiskLimit::OpenOrders(limits.max_open_orders));
+        }
+        if self.open_notional.saturating_add(notional) > limits.max_open_notional {
+            return Err(RiskLimit::OpenNotional(limits.max_open_notional));
+        }
+        Ok(())
+    }
+}
+
+/// Notional of `qty` at `price`, saturating
+#[inline]
+pub fn notional(price: u32, qty: Qty) -> u64 {
+    (price as u64).saturating_mul(qty.value())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OpenOrder {
+    trader: [u8; 20],
+    book_id: BookId,
+    price: u32,
+    notional: u64,
+}
+
+/// Resting orders of every trader, counted per book as they rest, fill, and leave
+/// The book manager keeps it up to date with each change to a resting order, so the counts
+/// are exact at any point the engine lock is held. Orders without a trader are not counted.
+#[derive(Debug, Default)]
+pub struct OpenOrderTracker {
+    orders: HashMap<OrderId, OpenOrder>,
+    usage: HashMap<[u8; 20], BTreeMap<BookId, OpenUsage>>,
+}
+
+impl OpenOrderTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an order that went on the book with `qty` left in all at `price`
+    pub fn insert(&mut self, order_id: OrderId, trader: [u8; 20], book_id: BookId, price: u32, qty: Qty) {
+        self.update(order_id, Qty(0));
+        if qty.is_empty() {
+            return;
+        }
+        let order = OpenOrder { trader, book_id, price, notional: notional(price, qty) };
+        let usage = self.usage.entry(trader).or_default().entry(book_id).or_default();
+        usage.open_orders += 1;
+        usage.open_notional = usage.open_notional.saturating_add(order.notional);
+        self.orders.insert(order_id, order);
+    }
+
+    /// Sets what a counted order has left in all; at zero it no longer counts
+    /// Orders that are not counted are ignored.
+    pub fn update(&mut self, order_id: OrderId, qty: Qty) {
+        let Some(order) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        let (trader, book_id, before) = (order.trader, order.book_id, order.notional);
+        let after = if qty.is_empty() { 0 } else { notional(order.price, qty) };
+        order.notional = after;
+        let Some(books) = self.usage.get_mut(&trader) else {
+            return;
+        };
+        if let Some(usage) = books.get_mut(&book_id) {
+            usage.open_notional = usage.open_notional.saturating_sub(before).saturating_add(after);
+            if qty.is_empty() {
+                usage.open_orders = usage.open_orders.saturating_sub(1);
+                if usage.open_orders == 0 {
+                    books.remove(&book_id);
+                }
+            }
+        }
+        if books.is_empty() {
+            self.usage.remove(&trader);
+        }
+        if qty.is_empty() {
+            self.orders.remove(&order_id);
+        }
+    }
+
+    /// Gets the notional a counted order has left; zero for orders that are not counted
+    #[inline]
+    pub fn notional(&self, order_id: OrderId) -> u64 {
+        self.orders.get(&order_id).map_or(0, |order| order.notional)
+    }
+
+    /// Gets what a trader has resting in a book
+    #[inline]
+    pub fn usage(&self, trader: [u8; 20], book_id: BookId) -> OpenUsage {
+        self.usage
+            .get(&trader)
+            .and_then(|books| books.get(&book_id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Lists what a trader has resting, by book in book ID order
+    pub fn trader_usage(&self, trader: [u8; 20]) -> Vec<(BookId, OpenUsage)> {
+        self.usage
+            .get(&trader)
+            .into_iter()
+            .flatten()
+            .map(|(&book_id, &usage)| (book_id, usage))
+            .collect()
+    }
+
+    /// Gets the number of orders counted
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_order_tracker() {
+        let mut open_orders = OpenOrderTracker::new();
+        open_orders.insert(OrderId(1), [1; 20], BookId(0), 100, Qty(10));
+        open_orders.insert(OrderId(2), [1; 20], BookId(0), 50, Qty(4));
+        open_orders.insert(OrderId(3), [1; 20], BookId(2), 10, Qty(1));
+        assert_eq!(open_orders.usage([1; 20], BookId(0)), OpenUsage { open_orders: 2, open_notional: 1200 });
+
+        // A partial fill lowers the notional, a full one the count as well
+        open_orders.update(OrderId(1), Qty(6));
+        assert_eq!(open_orders.usage([1; 20], BookId(0)), OpenUsage { open_orders: 2, open_notional: 800 });
+        open_orders.update(OrderId(2), Qty(0));
+        open_orders.update(OrderId(2), Qty(0));
+        assert_eq!(open_orders.usage([1; 20], BookId(0)), OpenUsage { open_orders: 1, open_notional: 600 });
+
+        open_orders.update(OrderId(1), Qty(0));
+        assert_eq!(open_orders.trader_usage([1; 20]), vec![(BookId(2), OpenUsage { open_orders: 1, open_notional: 10 })]);
+        assert_eq!(open_orders.len(), 1);
+        assert_eq!(open_orders.usage([2; 20], BookId(0)), OpenUsage::default());
+    }
+}
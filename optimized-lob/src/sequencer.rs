@@ -0,0 +1,149 @@
+// sequencer.rs
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use tokio::sync::watch;
+
+/// Keeps each trader's submissions in arrival order while they are verified concurrently
+/// A submission takes a ticket as it arrives, is verified, waits for its ticket's turn, and
+/// hands its command to the matching worker before giving the turn up, so a trader's orders
+/// reach the book in the order they came in however long each one's verification took.
+#[derive(Default)]
+pub struct TraderSequencer {
+    lines: Mutex<HashMap<[u8; 20], Line>>,
+}
+
+/// One trader's tickets
+struct Line {
+    issued: u64,                 // Tickets handed out
+    serving: watch::Sender<u64>, // The ticket whose turn it is
+    abandoned: BTreeSet<u64>,    // Tickets given up before their turn, skipped when it comes
+}
+
+/// A place in line behind the earlier submissions of one or more traders
+/// Dropping it gives the turn to the next ticket, or, before its turn came, gives the place up.
+pub struct Ticket<'a> {
+    sequencer: &'a TraderSequencer,
+    places: Vec<([u8; 20], u64, watch::Receiver<u64>)>,
+}
+
+impl TraderSequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a ticket behind everything already in line for each of `traders`
+    /// A submission signed by several traders, like an OCO pair, takes one ticket for all of them,
+    /// so two such submissions can never each wait on the other.
+    pub fn ticket(&self, traders: &[[u8; 20]]) -> Ticket<'_> {
+        let mut lines = self.lines();
+        let mut places: Vec<([u8; 20], u64, watch::Receiver<u64>)> = Vec::with_capacity(traders.len());
+        for &trader in traders {
+            if places.iter().any(|&(taken, _, _)| taken == trader) {
+                continue;
+            }
+            let line = lines.entry(trader).or_insert_with(|| Line {
+                issued: 0,
+                serving: watch::channel(0).0,
+                abandoned: BTreeSet::new(),
+            });
+            places.push((trader, line.issued, line.serving.subscribe()));
+            line.issued += 1;
+        }
+        Ticket { sequencer: self, places }
+    }
+
+    /// Gets the number of traders with submissions in line
+    pub fn len(&self) -> usize {
+        self.lines().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines().is_empty()
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], Line>> {
+        self.lines.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Ends a ticket's place in a trader's line, passing the turn on if it held it
+    fn finish(&self, trader: [u8; 20], number: u64) {
+        let mut lines = self.lines();
+        let Some(line) = lines.get_mut(&trader) else {
+            return;
+        };
+        let mut serving = *line.serving.borrow();
+        if number != serving {
+            line.abandoned.insert(number);
+            return;
+        }
+        serving += 1;
+        while line.abandoned.remove(&serving) {
+            serving += 1;
+        }
+        if serving == line.issued {
+            lines.remove(&trader);
+        } else {
+            line.serving.send_replace(serving);
+        }
+    }
+}
+
+impl Ticket<'_> {
+    /// Waits until every earlier submission of the ticket's traders has gone through or given up
+    pub async fn wait(&mut self) {
+        for (_, number, serving) in &mut self.places {
+            // The sender lives as long as a ticket of the line is out, so this can't fail
+            let _ = serving.wait_for(|serving| serving == number).await;
+        }
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        for &(trader, number, _) in &self.places {
+            self.sequencer.finish(trader, number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trader_sequencer() {
+        let sequencer = TraderSequencer::new();
+        let (trader, other) = ([1; 20], [2; 20]);
+        let entered = Mutex::new(Vec::new());
+
+        // Tickets are served in the order they were taken, whatever order they are waited on in
+        let mut tickets: Vec<_> = (0..4).map(|_| sequencer.ticket(&[trader])).collect();
+        drop(tickets.remove(2)); // Gives its place up before its turn
+        let waiting = tickets.into_iter().enumerate().rev().map(|(i, mut ticket)| {
+            let entered = &entered;
+            async move {
+                ticket.wait().await;
+                entered.lock().unwrap().push(i);
+            }
+        });
+        futures_util::future::join_all(waiting).await;
+        let entered = entered.into_inner().unwrap();
+        println!("Entered: {:?}", entered);
+        assert_eq!(entered, vec![0, 1, 2]);
+        assert!(sequencer.is_empty());
+
+        // A ticket for two traders waits behind both lines, and other traders don't wait at all
+        let first = sequencer.ticket(&[trader]);
+        let mut pair = sequencer.ticket(&[trader, other, other]);
+        let mut unrelated = sequencer.ticket(&[[3; 20]]);
+        unrelated.wait().await;
+        let waited = tokio::time::timeout(std::time::Duration::from_millis(20), pair.wait()).await;
+        assert!(waited.is_err());
+        drop(first);
+        pair.wait().await;
+        assert_eq!(sequencer.len(), 3);
+        drop((pair, unrelated));
+        assert!(sequencer.is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+// settlement_batcher.rs
+
+use crate::translator::SettlementOrder;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// A token and two accounts, in address order.
+type AccountPair = ([u8; 20], [u8; 20], [u8; 20]);
+
+/// One token movement between two accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetTransfer {
+    pub token: [u8; 20],
+    pub from: [u8; 20],
+    pub to: [u8; 20],
+    pub amount: u128,
+}
+
+/// The token movements that settle one order: maker tokens to the taker and taker tokens to the
+/// maker, less their fees, and the fees and the buyer's fee amount to the fee recipient.
+pub fn order_transfers(order: &SettlementOrder) -> [NetTransfer; 5] {
+    let (base_token, buyer) = if order.maker_is_buyer {
+        (order.maker_token, order.maker)
+    } else {
+        (order.taker_token, order.taker)
+    };
+    let fee = |token, from, amount| NetTransfer { token, from, to: order.fee_recipient, amount };
+    [
+        NetTransfer {
+            token: order.maker_token,
+            from: order.maker,
+            to: order.taker,
+            amount: order.maker_amount - order.taker_fee,
+        },
+        NetTransfer {
+            token: order.taker_token,
+            from: order.taker,
+            to: order.maker,
+            amount: order.taker_amount - order.maker_fee,
+        },
+        fee(order.maker_token, order.maker, order.taker_fee),
+        fee(order.taker_token, order.taker, order.maker_fee),
+        fee(base_token, buyer, order.fee_amount),
+    ]
+}
+
+/// Nets the token movements of `orders`.
+/// Movements of a token between the same two accounts cancel out, so each pair of accounts
+/// moves each token at most once, in whichever direction is left over. Every account ends up
+/// with exactly the same balance change as if each order were settled on its own.
+/// Each amount fits a u128 and is bounded by its token's supply, so their sums can't overflow one.
+///
+/// ## Arguments:
+/// - `orders`: The settlements of a batch.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::settlement_batcher::net_transfers;
+/// assert!(net_transfers(&[]).is_empty());
+/// ```
+pub fn net_transfers(orders: &[SettlementOrder]) -> Vec<NetTransfer> {
+    // With the amounts moved each way, lower address to higher first
+    let mut flows: BTreeMap<AccountPair, (u128, u128)> = BTreeMap::new();
+    for transfer in orders.iter().flat_map(order_transfers) {
+        if transfer.from == transfer.to {
+            continue;
+        }
+        if transfer.from < transfer.to {
+            flows.entry((transfer.token, transfer.from, transfer.to)).or_default().0 += transfer.amount;
+        } else {
+            flows.entry((transfer.token, transfer.to, transfer.from)).or_default().1 += transfer.amount;
+        }
+    }
+
+    flows
+        .into_iter()
+        .filter_map(|((token, low, high), (low_to_high, high_to_low))| {
+            if low_to_high > high_to_low {
+                Some(NetTransfer { token, from: low, to: high, amount: low_to_high - high_to_low })
+            } else if high_to_low > low_to_high {
+                Some(NetTransfer { token, from: high, to: low, amount: high_to_low - low_to_high })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Sums the balance change of every account, per token, that `transfers` cause.
+pub fn balance_deltas(transfers: impl IntoIterator<Item = NetTransfer>) -> BTreeMap<([u8; 20], [u8; 20]), i128> {
+    let mut deltas: BTreeMap<([u8; 20], [u8; 20]), i128> = BTreeMap::new();
+    for transfer in transfers {
+        *deltas.entry((transfer.token, transfer.from)).or_default() -= transfer.amount as i128;
+        *deltas.entry((transfer.token, transfer.to)).or_default() += transfer.amount as i128;
+    }
+    deltas.retain(|_, delta| *delta != 0);
+    deltas
+}
+
+/// Settlements of one book waiting to be sent together.
+#[derive(Debug)]
+struct OpenBatch {
+    settlement_ids: Vec<u64>,
+    opened_at: Instant,
+}
+
+/// Groups settlements per book into batches.
+/// A batch is closed when it reaches `max_batch_size` settlements or when `window` has passed
+/// since its first settlement, whichever comes first.
+#[derive(Debug)]
+pub struct SettlementBatcher {
+    window: Duration,
+    max_batch_size: usize,
+    open: BTreeMap<u32, OpenBatch>, // Keyed by BookId.
+}
+
+impl SettlementBatcher {
+    pub fn new(window: Duration, max_batch_size: usize) -> Self {
+        Self {
+            window,
+            max_batch_size: max_batch_size.max(1),
+            open: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a settlement of `book_id` to the book's open batch.
+    /// Returns the batch if the settlement filled it up.
+    pub fn push(&mut self, book_id: u32, settlement_id: u64, now: Instant) -> Option<Vec<u64>> {
+        let batch = self.open.entry(book_id).or_insert_with(|| OpenBatch {
+            settlement_ids: Vec::new(),
+            opened_at: now,
+        });
+        batch.settlement_ids.push(settlement_id);
+        if batch.settlement_ids.len() >= self.max_batch_size {
+            return self.open.remove(&book_id).map(|batch| batch.settlement_ids);
+        }
+        None
+    }
+
+    /// Closes every batch whose window has passed.
+    pub fn due(&mut self, now: Instant) -> Vec<Vec<u64>> {
+        let due: Vec<u32> = self
+            .open
+            .iter()
+            .filter(|(_, batch)| now.duration_since(batch.opened_at) >= self.window)
+            .map(|(&book_id, _)| book_id)
+            .collect();
+        due.into_iter()
+            .filter_map(|book_id| self.open.remove(&book_id))
+            .map(|batch| batch.settlement_ids)
+            .collect()
+    }
+
+    /// Gets the time the next batch is due, if any is open.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.open.values().map(|batch| batch.opened_at + self.window).min()
+    }
+
+    /// Closes every open batch, e.g. on shutdown.
+    pub fn drain(&mut self) -> Vec<Vec<u64>> {
+        std::mem::take(&mut self.open)
+            .into_values()
+            .map(|batch| batch.settlement_ids)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::{fee_for, SettlementSignature};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const BASE: [u8; 20] = [1; 20];
+    const SECURITY: [u8; 20] = [2; 20];
+
+    /// A fill of `qty` at `price` between `maker` and `taker`
+    fn order(maker: u8, taker: u8, qty: u128, price: u128, maker_is_buyer: bool) -> SettlementOrder {
+        let signature = SettlementSignature { signature_type: 2, v: 27, r: [0; 32], s: [0; 32] };
+        let (maker_token, taker_token, maker_amount, taker_amount) = if maker_is_buyer {
+            (BASE, SECURITY, qty * price, qty)
+        } else {
+            (SECURITY, BASE, qty, qty * price)
+        };
+        SettlementOrder {
+            maker_token,
+            taker_token,
+            maker_amount,
+            taker_amount,
+            fee_amount: 0,
+            maker_fee: 0,
+            taker_fee: 0,
+            maker: [maker; 20],
+            taker: [taker; 20],
+            fee_recipient: [0; 20],
+            pool: [0; 20],
+            maker_expiration: u64::MAX,
+            maker_salt: 0,
+            taker_expiration: u64::MAX,
+            taker_salt: 0,
+            maker_is_buyer,
+            maker_signature: signature.clone(),
+            taker_signature: signature,
+            chain_id: 1,
+            domain_separator: [0; 32],
+        }
+    }
+
+    #[test]
+    fn test_opposing_flows_net_out() {
+        // 3 sells 10 to 4 at 100, then buys 4 back at 101, so 6 and 596 are left to move
+        let orders = [order(3, 4, 10, 100, false), order(4, 3, 4, 101, false)];
+        let transfers = net_transfers(&orders);
+        println!("Transfers: {:?}", transfers);
+        assert_eq!(
+            transfers,
+            vec![
+                NetTransfer { token: BASE, from: [4; 20], to: [3; 20], amount: 1_000 - 404 },
+                NetTransfer { token: SECURITY, from: [3; 20], to: [4; 20], amount: 6 },
+            ]
+        );
+
+        // A round trip at one price moves nothing
+        assert!(net_transfers(&[order(3, 4, 5, 100, false), order(3, 4, 5, 100, true)]).is_empty());
+    }
+
+    #[test]
+    fn test_netting_preserves_balances() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let orders: Vec<SettlementOrder> = (0..rng.gen_range(1..40))
+                .map(|_| {
+                    let maker = rng.gen_range(1..6);
+                    let taker = rng.gen_range(1..6);
+                    let mut order =
+                        order(maker, taker, rng.gen_range(1..1_000), rng.gen_range(90..110), rng.gen_bool(0.5));
+                    // Rounding dust, to a fee recipient that may also trade
+                    order.fee_amount = rng.gen_range(0..2);
+                    order.maker_fee = fee_for(order.taker_amount, rng.gen_range(0..50));
+                    order.taker_fee = fee_for(order.maker_amount, rng.gen_range(0..50));
+                    order.fee_recipient = [rng.gen_range(5..7); 20];
+                    order
+                })
+                .collect();
+            let gross = balance_deltas(orders.iter().flat_map(order_transfers));
+            let transfers = net_transfers(&orders);
+            assert_eq!(balance_deltas(transfers.iter().copied()), gross);
+
+            // Every pair of accounts moves each token at most once
+            let mut pairs: Vec<_> = transfers
+                .iter()
+                .map(|transfer| (transfer.token, transfer.from.min(transfer.to), transfer.from.max(transfer.to)))
+                .collect();
+            let count = pairs.len();
+            pairs.sort_unstable();
+            pairs.dedup();
+            assert_eq!(pairs.len(), count);
+            assert!(count <= orders.len() * 5);
+        }
+    }
+
+    #[test]
+    fn test_batch_windows() {
+        let start = Instant::now();
+        let mut batcher = SettlementBatcher::new(Duration::from_millis(100), 3);
+        assert_eq!(batcher.next_deadline(), None);
+
+        assert_eq!(batcher.push(0, 1, start), None);
+        assert_eq!(batcher.push(1, 2, start + Duration::from_millis(50)), None);
+        assert_eq!(batcher.push(0, 3, start + Duration::from_millis(60)), None);
+        assert_eq!(batcher.next_deadline(), Some(start + Duration::from_millis(100)));
+
+        // Book 0 is due first; book 1 opened later
+        assert!(batcher.due(start + Duration::from_millis(99)).is_empty());
+        assert_eq!(batcher.due(start + Duration::from_millis(100)), vec![vec![1, 3]]);
+
+        // A full batch closes at once
+        assert_eq!(batcher.push(1, 4, start + Duration::from_millis(110)), None);
+        assert_eq!(batcher.push(1, 5, start + Duration::from_millis(120)), Some(vec![2, 4, 5]));
+
+        assert_eq!(batcher.push(2, 6, start + Duration::from_millis(130)), None);
+        assert_eq!(batcher.drain(), vec![vec![6]]);
+        assert_eq!(batcher.next_deadline(), None);
+    }
+}
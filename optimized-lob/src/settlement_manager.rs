@@ -2,3 +2,529 @@
 // submits (partial) fills to settlement protocol
 // adds back unfillable orders to the orderbook
 // adds back reverted orders to the orderbook
+
+use crate::{
+    translator::SettlementOrder,
+    utils::{hex_array, SETTLEMENT_HISTORY_CAPACITY},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::fmt;
+use tokio::sync::mpsc;
+
+/// Where a settlement is in its on-chain lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SettlementStatus {
+    /// Matched and translated, not yet sent to the settlement contract.
+    Pending,
+    /// Sent in a transaction that has not been confirmed yet.
+    Submitted {
+        #[serde(with = "hex_array")]
+        tx_hash: [u8; 32],
+    },
+    Confirmed {
+        #[serde(with = "hex_array")]
+        tx_hash: [u8; 32],
+    },
+    Failed { reason: String },
+}
+
+impl SettlementStatus {
+    /// Whether the settlement has reached a final state.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, SettlementStatus::Confirmed { .. } | SettlementStatus::Failed { .. })
+    }
+
+    /// Gets the snake_case name of the status, as used in the REST API.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SettlementStatus::Pending => "pending",
+            SettlementStatus::Submitted { .. } => "submitted",
+            SettlementStatus::Confirmed { .. } => "confirmed",
+            SettlementStatus::Failed { .. } => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettlementError {
+    UnknownSettlement,
+    UnknownBatch,
+    /// The settlement is not in a status the requested transition starts from.
+    InvalidTransition { from: &'static str, to: &'static str },
+}
+
+impl fmt::Display for SettlementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettlementError::UnknownSettlement => write!(f, "Unknown settlement"),
+            SettlementError::UnknownBatch => write!(f, "Unknown settlement batch"),
+            SettlementError::InvalidTransition { from, to } => {
+                write!(f, "Cannot move a {} settlement to {}", from, to)
+            }
+        }
+    }
+}
+
+/// A settlement produced by one fill, with the fill it came from and its status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackedSettlement {
+    pub settlement_id: u64,
+    pub trade_id: u64,
+    pub book_id: u32,
+    pub maker_order_id: u64,
+    pub taker_order_id: u64,
+    pub exec_qty: u64,
+    pub exec_price: u32,
+    pub order: SettlementOrder,
+    #[serde(flatten)]
+    pub status: SettlementStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<u64>, // Set once the settlement is submitted as part of a batch.
+    pub created_at: u64, // Nanoseconds since the Unix epoch, by the engine clock.
+    pub updated_at: u64,
+}
+
+/// Settlements sent together in one transaction. The batch and its settlements share a status.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SettlementBatch {
+    pub batch_id: u64,
+    pub settlement_ids: Vec<u64>,
+    #[serde(flatten)]
+    pub status: SettlementStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// Keeps every settlement from translation until it is confirmed or has failed.
+/// In-flight settlements are kept indefinitely; finished ones are kept for the most recent
+/// `history_capacity` only.
+pub struct SettlementTracker {
+    settlements: BTreeMap<u64, TrackedSettlement>,
+    finished: VecDeque<u64>, // Finished settlement IDs, oldest first.
+    next_settlement_id: u64,
+    batches: BTreeMap<u64, SettlementBatch>,
+    finished_batches: VecDeque<u64>,
+    next_batch_id: u64,
+    history_capacity: usize,
+    queue: Option<mpsc::UnboundedSender<u64>>, // Gets the ID of every registered settlement, when set.
+}
+
+impl Default for SettlementTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SettlementTracker {
+    pub fn new() -> Self {
+        Self::with_history_capacity(SETTLEMENT_HISTORY_CAPACITY)
+    }
+
+    /// Creates a tracker that keeps at most `capacity` finished settlements
+    pub fn with_history_capacity(capacity: usize) -> Self {
+        Self {
+            settlements: BTreeMap::new(),
+            finished: VecDeque::new(),
+            next_settlement_id: 1,
+            batches: BTreeMap::new(),
+            finished_batches: VecDeque::new(),
+            next_batch_id: 1,
+            history_capacity: capacity,
+            queue: None,
+        }
+    }
+
+    /// Registers a freshly translated settlement as Pending and returns its ID.
+    pub fn register(&mut self, mut settlement: TrackedSettlement) -> u64 {
+        let settlement_id = self.next_settlement_id;
+        self.next_settlement_id += 1;
+        settlement.settlement_id = settlement_id;
+        settlement.status = SettlementStatus::Pending;
+        settlement.batch_id = None;
+        settlement.updated_at = settlement.created_at;
+        self.settlements.insert(settlement_id, settlement);
+        if let Some(queue) = &self.queue {
+            // A closed queue only means no submitter is running; the settlement stays Pending
+            let _ = queue.send(settlement_id);
+        }
+        settlement_id
+    }
+
+    /// Sends the ID of every settlement registered from now on to `queue`, e.g. for a submitter.
+    pub fn set_queue(&mut self, queue: mpsc::UnboundedSender<u64>) {
+        self.queue = Some(queue);
+    }
+
+    pub fn get(&self, settlement_id: u64) -> Option<&TrackedSettlement> {
+        self.settlements.get(&settlement_id)
+    }
+
+    /// Iterates over the tracked settlements in ID order.
+    pub fn settlements(&self) -> impl DoubleEndedIterator<Item = &TrackedSettlement> {
+        self.settlements.values()
+    }
+
+    /// Iterates over the tracked settlements with IDs after `after`, or all of them, in ID order.
+    pub fn settlements_after(&self, after: Option<u64>) -> impl Iterator<Item = &TrackedSettlement> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        self.settlements.range((start, Bound::Unbounded)).map(|(_, settlement)| settlement)
+    }
+
+    /// Gets the ID the next settlement will get.
+    pub fn next_settlement_id(&self) -> u64 {
+        self.next_settlement_id
+    }
+
+    /// Records that a Pending or Submitted settlement was sent in transaction `tx_hash`.
+    /// A Submitted settlement may be resubmitted, e.g. with a higher gas price.
+    pub fn mark_submitted(
+        &mut self,
+        settlement_id: u64,
+        tx_hash: [u8; 32],
+        now: u64,
+    ) -> Result<&TrackedSettlement, SettlementError> {
+        self.transition(settlement_id, SettlementStatus::Submitted { tx_hash }, now, |status| {
+            matches!(status, SettlementStatus::Pending | SettlementStatus::Submitted { .. })
+        })
+    }
+
+    /// Records that the transaction of a Submitted settlement was confirmed.
+    pub fn mark_confirmed(&mut self, settlement_id: u64, now: u64) -> Result<&TrackedSettlement, SettlementError> {
+        let tx_hash = match self.settlements.get(&settlement_id).map(|settlement| &settlement.status) {
+            Some(SettlementStatus::Submitted { tx_hash }) => *tx_hash,
+            Some(status) => {
+                return Err(SettlementError::InvalidTransition { from: status.name(), to: "confirmed" });
+            }
+            None => return Err(SettlementError::UnknownSettlement),
+        };
+        self.transition(settlement_id, SettlementStatus::Confirmed { tx_hash }, now, |_| true)
+    }
+
+    /// Records that a settlement that is not finished yet has failed.
+    pub fn mark_failed(
+        &mut self,
+        settlement_id: u64,
+        reason: String,
+        now: u64,
+    ) -> Result<&TrackedSettlement, SettlementError> {
+        self.transition(settlement_id, SettlementStatus::Failed { reason }, now, |status| !status.is_finished())
+    }
+
+    fn transition(
+        &mut self,
+        settlement_id: u64,
+        status: SettlementStatus,
+        now: u64,
+        allowed_from: impl Fn(&SettlementStatus) -> bool,
+    ) -> Result<&TrackedSettlement, SettlementError> {
+        let settlement = self
+            .settlements
+            .get_mut(&settlement_id)
+            .ok_or(SettlementError::UnknownSettlement)?;
+        if !allowed_from(&settlement.status) {
+            return Err(SettlementError::InvalidTransition {
+                from: settlement.status.name(),
+                to: status.name(),
+            });
+        }
+        settlement.status = status;
+        settlement.updated_at = now;
+
+        if settlement.status.is_finished() {
+            self.finished.push_back(settlement_id);
+            while self.finished.len() > self.history_capacity {
+                if let Some(oldest) = self.finished.pop_front() {
+                    self.settlements.remove(&oldest);
+                }
+            }
+        }
+        self.settlements.get(&settlement_id).ok_or(SettlementError::UnknownSettlement)
+    }
+
+    pub fn batch(&self, batch_id: u64) -> Option<&SettlementBatch> {
+        self.batches.get(&batch_id)
+    }
+
+    /// Iterates over the tracked batches in ID order.
+    pub fn batches(&self) -> impl DoubleEndedIterator<Item = &SettlementBatch> {
+        self.batches.values()
+    }
+
+    /// Gets the ID the next batch should get.
+    pub fn next_batch_id(&self) -> u64 {
+        self.next_batch_id
+    }
+
+    /// Records that the Pending or Submitted settlements `settlement_ids` were sent together
+    /// as batch `batch_id` in transaction `tx_hash`. Nothing changes unless all of them can move.
+    pub fn mark_batch_submitted(
+        &mut self,
+        batch_id: u64,
+        settlement_ids: &[u64],
+        tx_hash: [u8; 32],
+        now: u64,
+    ) -> Result<&SettlementBatch, SettlementError> {
+        for settlement_id in settlement_ids {
+            match self.settlements.get(settlement_id).map(|settlement| &settlement.status) {
+                Some(SettlementStatus::Pending | SettlementStatus::Submitted { .. }) => {}
+                Some(status) => {
+                    return Err(SettlementError::InvalidTransition { from: status.name(), to: "submitted" });
+                }
+                None => return Err(SettlementError::UnknownSettlement),
+            }
+        }
+        if let Some(batch) = self.batches.get(&batch_id) {
+            if batch.status.is_finished() {
+                return Err(SettlementError::InvalidTransition { from: batch.status.name(), to: "submitted" });
+            }
+        }
+
+        for &settlement_id in settlement_ids {
+            self.mark_submitted(settlement_id, tx_hash, now)?;
+            if let Some(settlement) = self.settlements.get_mut(&settlement_id) {
+                settlement.batch_id = Some(batch_id);
+            }
+        }
+        let created_at = self.batches.get(&batch_id).map_or(now, |batch| batch.created_at);
+        self.batches.insert(
+            batch_id,
+            SettlementBatch {
+                batch_id,
+                settlement_ids: settlement_ids.to_vec(),
+                status: SettlementStatus::Submitted { tx_hash },
+                created_at,
+                updated_at: now,
+            },
+        );
+        self.next_batch_id = self.next_batch_id.max(batch_id + 1);
+        self.batches.get(&batch_id).ok_or(SettlementError::UnknownBatch)
+    }
+
+    /// Records that the transaction of a Submitted batch was confirmed, confirming its settlements.
+    pub fn mark_batch_confirmed(&mut self, batch_id: u64, now: u64) -> Result<&SettlementBatch, SettlementError> {
+        let tx_hash = match self.batches.get(&batch_id).map(|batch| &batch.status) {
+            Some(SettlementStatus::Submitted { tx_hash }) => *tx_hash,
+            Some(status) => {
+                return Err(SettlementError::InvalidTransition { from: status.name(), to: "confirmed" });
+            }
+            None => return Err(SettlementError::UnknownBatch),
+        };
+        self.finish_batch(batch_id, SettlementStatus::Confirmed { tx_hash }, now)
+    }
+
+    /// Records that a batch that is not finished yet has failed, failing its settlements.
+    pub fn mark_batch_failed(
+        &mut self,
+        batch_id: u64,
+        reason: String,
+        now: u64,
+    ) -> Result<&SettlementBatch, SettlementError> {
+        match self.batches.get(&batch_id).map(|batch| &batch.status) {
+            Some(status) if status.is_finished() => {
+                return Err(SettlementError::InvalidTransition { from: status.name(), to: "failed" });
+            }
+            Some(_) => {}
+            None => return Err(SettlementError::UnknownBatch),
+        }
+        self.finish_batch(batch_id, SettlementStatus::Failed { reason }, now)
+    }
+
+    /// Moves a batch and its settlements to a final status.
+    /// Settlements that are already finished, or were aged out, are left alone.
+    fn finish_batch(
+        &mut self,
+        batch_id: u64,
+        status: SettlementStatus,
+        now: u64,
+    ) -> Result<&SettlementBatch, SettlementError> {
+        let settlement_ids = self.batches.get(&batch_id).map(|batch| batch.settlement_ids.clone()).unwrap_or_default();
+        for settlement_id in settlement_ids {
+            let _ = self.transition(settlement_id, status.clone(), now, |status| !status.is_finished());
+        }
+
+        let batch = self.batches.get_mut(&batch_id).ok_or(SettlementError::UnknownBatch)?;
+        batch.status = status;
+        batch.updated_at = now;
+        self.finished_batches.push_back(batch_id);
+        while self.finished_batches.len() > self.history_capacity {
+            if let Some(oldest) = self.finished_batches.pop_front() {
+                self.batches.remove(&oldest);
+            }
+        }
+        self.batches.get(&batch_id).ok_or(SettlementError::UnknownBatch)
+    }
+
+    /// Gets every tracked settlement, for snapshots.
+    pub fn entries(&self) -> Vec<TrackedSettlement> {
+        self.settlements.values().cloned().collect()
+    }
+
+    /// Gets every tracked batch, for snapshots.
+    pub fn batch_entries(&self) -> Vec<SettlementBatch> {
+        self.batches.values().cloned().collect()
+    }
+
+    /// Rebuilds a tracker from snapshot entries.
+    /// Finished settlements and batches are aged out in the order they were last updated.
+    pub fn from_entries(
+        entries: Vec<TrackedSettlement>,
+        next_settlement_id: u64,
+        batches: Vec<SettlementBatch>,
+        next_batch_id: u64,
+    ) -> Self {
+        let mut tracker = Self::new();
+        let mut finished_batches: Vec<(u64, u64)> = batches
+            .iter()
+            .filter(|batch| batch.status.is_finished())
+            .map(|batch| (batch.updated_at, batch.batch_id))
+            .collect();
+        finished_batches.sort_unstable();
+        tracker.finished_batches = finished_batches.into_iter().map(|(_, batch_id)| batch_id).collect();
+        tracker.batches = batches.into_iter().map(|batch| (batch.batch_id, batch)).collect();
+        tracker.next_batch_id = next_batch_id;
+        let mut finished: Vec<(u64, u64)> = entries
+            .iter()
+            .filter(|settlement| settlement.status.is_finished())
+            .map(|settlement| (settlement.updated_at, settlement.settlement_id))
+            .collect();
+        finished.sort_unstable();
+        tracker.finished = finished.into_iter().map(|(_, settlement_id)| settlement_id).collect();
+        tracker.settlements = entries
+            .into_iter()
+            .map(|settlement| (settlement.settlement_id, settlement))
+            .collect();
+        tracker.next_settlement_id = next_settlement_id;
+        tracker
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translator::SettlementSignature;
+
+    fn settlement(trade_id: u64, created_at: u64) -> TrackedSettlement {
+        let signature = SettlementSignature { signature_type: 2, v: 27, r: [1; 32], s: [2; 32] };
+        TrackedSettlement {
+            settlement_id: 0,
+            trade_id,
+            book_id: 0,
+            maker_order_id: 1,
+            taker_order_id: 2,
+            exec_qty: 10,
+            exec_price: 100,
+            order: SettlementOrder {
+                maker_token: [1; 20],
+                taker_token: [2; 20],
+                maker_amount: 10,
+                taker_amount: 1_000,
+                fee_amount: 0,
+                maker_fee: 0,
+                taker_fee: 0,
+                maker: [3; 20],
+                taker: [4; 20],
+                fee_recipient: [0; 20],
+                pool: [0; 20],
+                maker_expiration: u64::MAX,
+                maker_salt: 1,
+                taker_expiration: u64::MAX,
+                taker_salt: 2,
+                maker_is_buyer: false,
+                maker_signature: signature.clone(),
+                taker_signature: signature,
+                chain_id: 1,
+                domain_separator: [5; 32],
+            },
+            status: SettlementStatus::Pending,
+            batch_id: None,
+            created_at,
+            updated_at: created_at,
+        }
+    }
+
+    #[test]
+    fn test_transitions_and_history() {
+        let mut tracker = SettlementTracker::with_history_capacity(2);
+        for trade_id in 1..=4 {
+            assert_eq!(tracker.register(settlement(trade_id, trade_id)), trade_id);
+        }
+
+        // Resubmission replaces the transaction hash, confirmation keeps it
+        tracker.mark_submitted(1, [0xaa; 32], 10).unwrap();
+        tracker.mark_submitted(1, [0xbb; 32], 11).unwrap();
+        let confirmed = tracker.mark_confirmed(1, 12).unwrap();
+        assert_eq!(confirmed.status, SettlementStatus::Confirmed { tx_hash: [0xbb; 32] });
+        assert_eq!(
+            tracker.mark_submitted(1, [0xcc; 32], 13).unwrap_err(),
+            SettlementError::InvalidTransition { from: "confirmed", to: "submitted" }
+        );
+        assert_eq!(tracker.mark_confirmed(99, 13).unwrap_err(), SettlementError::UnknownSettlement);
+
+        let json = serde_json::to_string(tracker.get(1).unwrap()).unwrap();
+        println!("Confirmed settlement: {}", json);
+        assert!(json.contains("\"status\":\"confirmed\""));
+        assert!(json.contains(&format!("\"tx_hash\":\"0x{}\"", "bb".repeat(32))));
+
+        // Finished settlements age out oldest first; in-flight ones stay
+        tracker.mark_failed(3, "reverted".to_string(), 14).unwrap();
+        tracker.mark_failed(2, "reverted".to_string(), 15).unwrap();
+        assert!(tracker.get(1).is_none());
+        assert_eq!(tracker.settlements().map(|s| s.settlement_id).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let mut restored =
+            SettlementTracker::from_entries(tracker.entries(), tracker.next_settlement_id(), Vec::new(), 1);
+        restored.history_capacity = 2;
+        assert_eq!(restored.entries(), tracker.entries());
+        assert_eq!(restored.register(settlement(5, 16)), 5);
+        // Settlement 3 failed before 2, so it is the first to go
+        restored.mark_failed(4, "reverted".to_string(), 17).unwrap();
+        assert_eq!(restored.settlements().map(|s| s.settlement_id).collect::<Vec<_>>(), vec![2, 4, 5]);
+    }
+
+    #[test]
+    fn test_batches() {
+        let mut tracker = SettlementTracker::with_history_capacity(2);
+        for trade_id in 1..=4 {
+            tracker.register(settlement(trade_id, trade_id));
+        }
+        tracker.mark_failed(4, "reverted".to_string(), 5).unwrap();
+
+        // A batch moves only if all of its settlements can
+        assert_eq!(
+            tracker.mark_batch_submitted(1, &[1, 4], [0xaa; 32], 6).unwrap_err(),
+            SettlementError::InvalidTransition { from: "failed", to: "submitted" }
+        );
+        assert_eq!(tracker.get(1).unwrap().status, SettlementStatus::Pending);
+        assert_eq!(tracker.mark_batch_confirmed(1, 6).unwrap_err(), SettlementError::UnknownBatch);
+
+        let batch = tracker.mark_batch_submitted(1, &[1, 2], [0xaa; 32], 6).unwrap();
+        assert_eq!(batch.status, SettlementStatus::Submitted { tx_hash: [0xaa; 32] });
+        assert_eq!(tracker.get(2).unwrap().batch_id, Some(1));
+        assert_eq!(tracker.next_batch_id(), 2);
+        let json = serde_json::to_string(tracker.batch(1).unwrap()).unwrap();
+        println!("Submitted batch: {}", json);
+        assert!(json.contains("\"settlement_ids\":[1,2],\"status\":\"submitted\""));
+
+        // The batch and its settlements finish together
+        let batch = tracker.mark_batch_confirmed(1, 7).unwrap();
+        assert_eq!(batch.status, SettlementStatus::Confirmed { tx_hash: [0xaa; 32] });
+        assert_eq!(tracker.get(2).unwrap().status, SettlementStatus::Confirmed { tx_hash: [0xaa; 32] });
+        assert!(tracker.mark_batch_failed(1, "late".to_string(), 8).is_err());
+
+        tracker.mark_batch_submitted(2, &[3], [0xbb; 32], 8).unwrap();
+        tracker.mark_batch_failed(2, "reverted".to_string(), 9).unwrap();
+        assert_eq!(tracker.get(3).unwrap().status, SettlementStatus::Failed { reason: "reverted".to_string() });
+
+        let restored = SettlementTracker::from_entries(
+            tracker.entries(),
+            tracker.next_settlement_id(),
+            tracker.batch_entries(),
+            tracker.next_batch_id(),
+        );
+        assert_eq!(restored.batch_entries(), tracker.batch_entries());
+        assert_eq!(restored.next_batch_id(), 3);
+    }
+}
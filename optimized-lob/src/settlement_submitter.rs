@@ -0,0 +1,888 @@
+// settlement_submitter.rs
+
+use crate::{
+    abi::{
+        encode_netted_batch, encode_settlement, encode_settlement_with_fees, SETTLE_BATCH_SELECTOR,
+        SETTLE_SELECTOR, SETTLE_WITH_FEES_SELECTOR,
+    },
+    auth::address_of,
+    eip1271::RpcError,
+    matching::MatchingEngine,
+    settlement_batcher::{net_transfers, SettlementBatcher},
+    settlement_manager::SettlementStatus,
+    translator::SettlementOrder,
+    utils::{
+        BookId, SETTLEMENT_BATCH_WINDOW, SETTLEMENT_CONFIRMATION_INTERVAL, SETTLEMENT_INITIAL_BACKOFF,
+        SETTLEMENT_MAX_BACKOFF, SETTLEMENT_MAX_BATCH_SIZE, SETTLEMENT_MAX_RETRIES,
+    },
+    wal::WalCommand,
+};
+use k256::ecdsa::SigningKey;
+#[cfg(feature = "server")]
+use serde_json::{json, Value};
+use sha3::{Digest, Keccak256};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::JoinHandle;
+
+/// Percentage added on top of the gas estimate, in case state changes before the transaction lands.
+const GAS_LIMIT_MARGIN_PERCENT: u64 = 20;
+
+pub type ChainFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, RpcError>> + Send + 'a>>;
+
+/// The outcome of a mined transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionReceipt {
+    pub success: bool,
+    pub block_number: u64,
+}
+
+/// Everything the submitter needs from a chain.
+/// RpcError::Unavailable is treated as transient and retried; RpcError::Reverted is final.
+pub trait ChainClient: Send + Sync {
+    fn chain_id(&self) -> ChainFuture<'_, u64>;
+    /// Gets the next nonce of `address`, counting transactions still in the mempool.
+    fn pending_nonce(&self, address: [u8; 20]) -> ChainFuture<'_, u64>;
+    fn gas_price(&self) -> ChainFuture<'_, u128>;
+    fn estimate_gas(&self, from: [u8; 20], to: [u8; 20], data: Vec<u8>) -> ChainFuture<'_, u64>;
+    /// Broadcasts a signed transaction and returns its hash.
+    fn send_raw_transaction(&self, raw: Vec<u8>) -> ChainFuture<'_, [u8; 32]>;
+    /// Gets the receipt of a transaction, or None while it is not mined.
+    fn transaction_receipt(&self, tx_hash: [u8; 32]) -> ChainFuture<'_, Option<TransactionReceipt>>;
+}
+
+/// Parses a hex quantity such as "0x1a".
+#[cfg(feature = "server")]
+fn quantity(value: &Value) -> Result<u128, RpcError> {
+    value
+        .as_str()
+        .and_then(|hex_str| u128::from_str_radix(hex_str.trim_start_matches("0x"), 16).ok())
+        .ok_or_else(|| RpcError::Unavailable(format!("Malformed quantity: {}", value)))
+}
+
+#[cfg(feature = "server")]
+fn hex_data(data: &[u8]) -> String {
+    format!("0x{}", hex::encode(data))
+}
+
+#[cfg(feature = "server")]
+impl ChainClient for crate::eip1271::JsonRpcClient {
+    fn chain_id(&self) -> ChainFuture<'_, u64> {
+        Box::pin(async move { Ok(quantity(&self.request("eth_chainId", json!([])).await?)? as u64) })
+    }
+
+    fn pending_nonce(&self, address: [u8; 20]) -> ChainFuture<'_, u64> {
+        Box::pin(async move {
+            let params = json!([hex_data(&address), "pending"]);
+            Ok(quantity(&self.request("eth_getTransactionCount", params).await?)? as u64)
+        })
+    }
+
+    fn gas_price(&self) -> ChainFuture<'_, u128> {
+        Box::pin(async move { quantity(&self.request("eth_gasPrice", json!([])).await?) })
+    }
+
+    fn estimate_gas(&self, from: [u8; 20], to: [u8; 20], data: Vec<u8>) -> ChainFuture<'_, u64> {
+        Box::pin(async move {
+            let params = json!([{ "from": hex_data(&from), "to": hex_data(&to), "data": hex_data(&data) }]);
+            Ok(quantity(&self.request("eth_estimateGas", params).await?)? as u64)
+        })
+    }
+
+    fn send_raw_transaction(&self, raw: Vec<u8>) -> ChainFuture<'_, [u8; 32]> {
+        Box::pin(async move {
+            let result = self.request("eth_sendRawTransaction", json!([hex_data(&raw)])).await?;
+            result
+                .as_str()
+                .and_then(|hash| hex::decode(hash.trim_start_matches("0x")).ok())
+                .and_then(|hash| hash.try_into().ok())
+                .ok_or_else(|| RpcError::Unavailable(format!("Malformed transaction hash: {}", result)))
+        })
+    }
+
+    fn transaction_receipt(&self, tx_hash: [u8; 32]) -> ChainFuture<'_, Option<TransactionReceipt>> {
+        Box::pin(async move {
+            let receipt = self.request("eth_getTransactionReceipt", json!([hex_data(&tx_hash)])).await?;
+            if receipt.is_null() {
+                return Ok(None);
+            }
+            Ok(Some(TransactionReceipt {
+                success: quantity(&receipt["status"])? == 1,
+                block_number: quantity(&receipt["blockNumber"])? as u64,
+            }))
+        })
+    }
+}
+
+/// RLP-encodes a byte string.
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    match bytes {
+        [byte] if *byte < 0x80 => vec![*byte],
+        _ => {
+            let mut encoded = rlp_length(bytes.len(), 0x80);
+            encoded.extend_from_slice(bytes);
+            encoded
+        }
+    }
+}
+
+/// RLP-encodes an unsigned integer given as big-endian bytes, without leading zeros.
+fn rlp_uint(bytes: &[u8]) -> Vec<u8> {
+    let first = bytes.iter().position(|&byte| byte != 0).unwrap_or(bytes.len());
+    rlp_bytes(&bytes[first..])
+}
+
+/// RLP-encodes a list of already encoded items.
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+    let mut encoded = rlp_length(payload.len(), 0xc0);
+    encoded.extend_from_slice(&payload);
+    encoded
+}
+
+/// Encodes the header of a string (`offset` 0x80) or list (`offset` 0xc0) of `len` bytes.
+fn rlp_length(len: usize, offset: u8) -> Vec<u8> {
+    if len <= 55 {
+        return vec![offset + len as u8];
+    }
+    let len_bytes = len.to_be_bytes();
+    let first = len_bytes.iter().position(|&byte| byte != 0).unwrap_or(len_bytes.len());
+    let mut header = vec![offset + 55 + (len_bytes.len() - first) as u8];
+    header.extend_from_slice(&len_bytes[first..]);
+    header
+}
+
+/// A legacy transaction with EIP-155 replay protection, which every EVM chain accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyTransaction {
+    pub nonce: u64,
+    pub gas_price: u128,
+    pub gas_limit: u64,
+    pub to: [u8; 20],
+    pub value: u128,
+    pub data: Vec<u8>,
+    pub chain_id: u64,
+}
+
+impl LegacyTransaction {
+    fn fields(&self) -> Vec<Vec<u8>> {
+        vec![
+            rlp_uint(&self.nonce.to_be_bytes()),
+            rlp_uint(&self.gas_price.to_be_bytes()),
+            rlp_uint(&self.gas_limit.to_be_bytes()),
+            rlp_bytes(&self.to),
+            rlp_uint(&self.value.to_be_bytes()),
+            rlp_bytes(&self.data),
+        ]
+    }
+
+    /// Computes the hash the sender signs, which commits to the chain ID.
+    pub fn signing_hash(&self) -> [u8; 32] {
+        let mut fields = self.fields();
+        fields.extend([rlp_uint(&self.chain_id.to_be_bytes()), rlp_uint(&[]), rlp_uint(&[])]);
+        Keccak256::digest(rlp_list(&fields)).into()
+    }
+
+    /// Signs the transaction and returns the raw transaction and its hash.
+    ///
+    /// ## Arguments:
+    /// - `key`: The sender's private key.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::settlement_submitter::LegacyTransaction;
+    /// # use k256::ecdsa::SigningKey;
+    /// let tx = LegacyTransaction { nonce: 0, gas_price: 1, gas_limit: 21_000, to: [1; 20], value: 0, data: vec![], chain_id: 1 };
+    /// let (raw, _tx_hash) = tx.sign(&SigningKey::from_slice(&[7; 32]).unwrap());
+    /// assert_eq!(raw[0], 0xf8); // An RLP list of more than 55 bytes
+    /// ```
+    pub fn sign(&self, key: &SigningKey) -> (Vec<u8>, [u8; 32]) {
+        let (signature, recovery_id) = key
+            .sign_prehash_recoverable(&self.signing_hash())
+            .expect("a 32-byte prehash is always signable");
+        let v = self.chain_id * 2 + 35 + u64::from(recovery_id.to_byte());
+        let mut fields = self.fields();
+        fields.extend([
+            rlp_uint(&v.to_be_bytes()),
+            rlp_uint(&signature.r().to_bytes()),
+            rlp_uint(&signature.s().to_bytes()),
+        ]);
+        let raw = rlp_list(&fields);
+        let tx_hash = Keccak256::digest(&raw).into();
+        (raw, tx_hash)
+    }
+}
+
+/// Tunables of the submitter.
+#[derive(Debug, Clone)]
+pub struct SubmitterConfig {
+    pub selector: [u8; 4], // Function of the settlement contract that takes one settlement.
+    pub fee_selector: [u8; 4], // Function that takes one settlement with fees.
+    pub batch_selector: [u8; 4], // Function that takes a netted batch, see abi::encode_netted_batch.
+    pub batch_window: Duration,  // How long a book's first settlement waits for others to batch with.
+    pub max_batch_size: usize,   // A batch this large is sent at once; 1 sends every settlement alone.
+    pub max_retries: u32,  // Retries of a submission that failed with a transient error.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub confirmation_interval: Duration, // How often receipts of submitted settlements are polled.
+    pub recredit_on_failure: bool,       // Put the maker's quantity back on the book when a settlement fails.
+}
+
+impl Default for SubmitterConfig {
+    fn default() -> Self {
+        Self {
+            selector: SETTLE_SELECTOR,
+            fee_selector: SETTLE_WITH_FEES_SELECTOR,
+            batch_selector: SETTLE_BATCH_SELECTOR,
+            batch_window: SETTLEMENT_BATCH_WINDOW,
+            max_batch_size: SETTLEMENT_MAX_BATCH_SIZE,
+            max_retries: SETTLEMENT_MAX_RETRIES,
+            initial_backoff: SETTLEMENT_INITIAL_BACKOFF,
+            max_backoff: SETTLEMENT_MAX_BACKOFF,
+            confirmation_interval: SETTLEMENT_CONFIRMATION_INTERVAL,
+            recredit_on_failure: false,
+        }
+    }
+}
+
+/// What a watcher confirms or fails once its transaction is mined.
+#[derive(Debug, Clone, Copy)]
+enum Watched {
+    Settlement(u64),
+    Batch(u64),
+}
+
+/// A running submitter.
+pub struct SubmitterHandle {
+    task: JoinHandle<()>,
+    shutdown: Arc<Notify>,
+}
+
+impl SubmitterHandle {
+    /// Stops taking new settlements and waits until every open batch has been sent.
+    /// Transactions still unconfirmed are picked up again by the next start.
+    pub async fn shutdown(self) {
+        self.shutdown.notify_one();
+        let _ = self.task.await;
+    }
+}
+
+/// Sends Pending settlements to their market's settlement contract, signed by the operator
+/// account, and follows them until they are confirmed or have failed.
+/// Settlements of a book are collected for `batch_window`, up to `max_batch_size`, and sent in
+/// one transaction with their transfers netted; a settlement left alone is sent as is.
+/// Every status change goes through the WAL like any other engine command.
+pub struct SettlementSubmitter {
+    chain: Arc<dyn ChainClient>,
+    operator: SigningKey,
+    operator_address: [u8; 20],
+    config: SubmitterConfig,
+    chain_id: Option<u64>,   // Fetched from the node on first use.
+    next_nonce: Option<u64>, // The operator's next nonce; refetched after a failed send.
+    batcher: SettlementBatcher,
+    connected: Arc<AtomicBool>, // Whether the node answered the last call that needed it.
+}
+
+impl SettlementSubmitter {
+    pub fn new(chain: Arc<dyn ChainClient>, operator: SigningKey, config: SubmitterConfig) -> Self {
+        let operator_address = address_of(operator.verifying_key());
+        let batcher = SettlementBatcher::new(config.batch_window, config.max_batch_size);
+        Self {
+            chain,
+            operator,
+            operator_address,
+            config,
+            chain_id: None,
+            next_nonce: None,
+            batcher,
+            connected: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn operator_address(&self) -> [u8; 20] {
+        self.operator_address
+    }
+
+    /// Gets a flag that is set while the submitter reaches the node.
+    /// It is set once the node first answers and cleared whenever a call fails for want of a
+    /// connection; a call the node answers with a revert still counts as reaching it.
+    pub fn connection(&self) -> Arc<AtomicBool> {
+        self.connected.clone()
+    }
+
+    /// Hooks the submitter up to the engine's settlements and spawns it.
+    /// Settlements recovered as Pending are submitted first, and recovered Submitted ones and
+    /// batches are watched again, so a restart does not strand anything in flight.
+    pub async fn start(self, engine: Arc<Mutex<MatchingEngine>>) -> SubmitterHandle {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut submitted = Vec::new();
+        {
+            let mut engine = engine.lock().await;
+            for settlement in engine.settlements.settlements() {
+                match settlement.status {
+                    SettlementStatus::Pending => {
+                        let _ = sender.send(settlement.settlement_id);
+                    }
+                    SettlementStatus::Submitted { tx_hash } if settlement.batch_id.is_none() => {
+                        submitted.push((Watched::Settlement(settlement.settlement_id), tx_hash));
+                    }
+                    _ => {}
+                }
+            }
+            for batch in engine.settlements.batches() {
+                if let SettlementStatus::Submitted { tx_hash } = batch.status {
+                    submitted.push((Watched::Batch(batch.batch_id), tx_hash));
+                }
+            }
+            engine.settlements.set_queue(sender);
+        }
+        for (watched, tx_hash) in submitted {
+            self.spawn_watcher(&engine, watched, tx_hash);
+        }
+        let shutdown = Arc::new(Notify::new());
+        SubmitterHandle {
+            task: tokio::spawn(self.run(engine, receiver, shutdown.clone())),
+            shutdown,
+        }
+    }
+
+    async fn run(
+        mut self,
+        engine: Arc<Mutex<MatchingEngine>>,
+        mut queue: mpsc::UnboundedReceiver<u64>,
+        shutdown: Arc<Notify>,
+    ) {
+        // Reach the node up front, so the connection is known before the first settlement
+        let chain_id = self.chain.chain_id().await;
+        note_connection(&self.connected, &chain_id);
+        self.chain_id = chain_id.ok();
+        loop {
+            // Without an open batch the deadline branch is disabled, so its value does not matter
+            let deadline = self.batcher.next_deadline();
+            let wake_at = tokio::time::Instant::from_std(deadline.unwrap_or_else(Instant::now));
+            tokio::select! {
+                settlement_id = queue.recv() => match settlement_id {
+                    Some(settlement_id) => self.enqueue(&engine, settlement_id).await,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(wake_at), if deadline.is_some() => {
+                    for batch in self.batcher.due(Instant::now()) {
+                        self.submit(&engine, batch).await;
+                    }
+                }
+                _ = shutdown.notified() => break,
+            }
+        }
+        for batch in self.batcher.drain() {
+            self.submit(&engine, batch).await;
+        }
+    }
+
+    /// Adds a Pending settlement to its book's batch, sending the batch if that fills it up.
+    async fn enqueue(&mut self, engine: &Arc<Mutex<MatchingEngine>>, settlement_id: u64) {
+        let book_id = {
+            let engine = engine.lock().await;
+            match engine.settlements.get(settlement_id) {
+                Some(settlement) if settlement.status == SettlementStatus::Pending => settlement.book_id,
+                _ => return,
+            }
+        };
+        if let Some(batch) = self.batcher.push(book_id, settlement_id, Instant::now()) {
+            self.submit(engine, batch).await;
+        }
+    }
+
+    /// Submits the settlements of one book in one transaction, retrying transient errors with
+    /// exponential backoff. Settlements that are no longer Pending, e.g. queued twice, are skipped.
+    async fn submit(&mut self, engine: &Arc<Mutex<MatchingEngine>>, settlement_ids: Vec<u64>) {
+        let (settlements, contract) = {
+            let engine = engine.lock().await;
+            let settlements: Vec<(u64, u32, SettlementOrder)> = settlement_ids
+                .iter()
+                .filter_map(|&settlement_id| engine.settlements.get(settlement_id))
+                .filter(|settlement| settlement.status == SettlementStatus::Pending)
+                .map(|settlement| (settlement.settlement_id, settlement.book_id, settlement.order.clone()))
+                .collect();
+            let Some(&(_, book_id, _)) = settlements.first() else { return };
+            let contract = engine.market_manager.get_config(BookId(book_id)).map(|config| config.verifying_contract);
+            (settlements, contract)
+        };
+        let settlement_ids: Vec<u64> = settlements.iter().map(|(settlement_id, _, _)| *settlement_id).collect();
+        let orders: Vec<SettlementOrder> = settlements.into_iter().map(|(_, _, order)| order).collect();
+        let recredit = self.config.recredit_on_failure;
+        let Some(contract) = contract else {
+            for settlement_id in settlement_ids {
+                fail(engine, settlement_id, "Book has no market configuration".to_string(), recredit).await;
+            }
+            return;
+        };
+
+        let data = match orders.as_slice() {
+            [order] if order.has_fees() => encode_settlement_with_fees(order, self.config.fee_selector),
+            [order] => encode_settlement(order, self.config.selector),
+            _ => encode_netted_batch(&orders, &net_transfers(&orders), self.config.batch_selector),
+        };
+        let mut backoff = self.config.initial_backoff;
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            let result = self.send_transaction(contract, data.clone(), orders[0].chain_id).await;
+            note_connection(&self.connected, &result);
+            match result {
+                Err(RpcError::Unavailable(_)) if attempts <= self.config.max_retries => {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                }
+                result => break result,
+            }
+        };
+
+        match (result, settlement_ids.as_slice()) {
+            (Ok(tx_hash), &[settlement_id]) => {
+                record(engine, WalCommand::SettlementSubmitted { settlement_id, tx_hash }).await;
+                self.spawn_watcher(engine, Watched::Settlement(settlement_id), tx_hash);
+            }
+            (Ok(tx_hash), _) => {
+                let batch_id = {
+                    let mut engine = engine.lock().await;
+                    let batch_id = engine.settlements.next_batch_id();
+                    log_and_apply(&mut engine, &WalCommand::SettlementBatchSubmitted { batch_id, settlement_ids, tx_hash });
+                    batch_id
+                };
+                self.spawn_watcher(engine, Watched::Batch(batch_id), tx_hash);
+            }
+            (Err(error), _) => {
+                let reason = format!("Submission failed on attempt {}: {}", attempts, error);
+                for settlement_id in settlement_ids {
+                    fail(engine, settlement_id, reason.clone(), recredit).await;
+                }
+            }
+        }
+    }
+
+    /// Makes one attempt at sending `data` to `to`.
+    /// A failed attempt forgets the operator's nonce, so the next one starts from the node's view.
+    async fn send_transaction(&mut self, to: [u8; 20], data: Vec<u8>, chain_id: u64) -> Result<[u8; 32], RpcError> {
+        let node_chain_id = match self.chain_id {
+            Some(chain_id) => chain_id,
+            None => *self.chain_id.insert(self.chain.chain_id().await?),
+        };
+        if node_chain_id != chain_id {
+            return Err(RpcError::Reverted(format!(
+                "Market is on chain {} but the node is on chain {}",
+                chain_id, node_chain_id
+            )));
+        }
+
+        let result = async {
+            let gas = self.chain.estimate_gas(self.operator_address, to, data.clone()).await?;
+            let gas_price = self.chain.gas_price().await?;
+            let nonce = match self.next_nonce {
+                Some(nonce) => nonce,
+                None => self.chain.pending_nonce(self.operator_address).await?,
+            };
+            let tx = LegacyTransaction {
+                nonce,
+                gas_price,
+                gas_limit: gas + gas * GAS_LIMIT_MARGIN_PERCENT / 100,
+                to,
+                value: 0,
+                data,
+                chain_id,
+            };
+            let (raw, tx_hash) = tx.sign(&self.operator);
+            self.chain.send_raw_transaction(raw).await?;
+            Ok((nonce, tx_hash))
+        }
+        .await;
+
+        match result {
+            Ok((nonce, tx_hash)) => {
+                self.next_nonce = Some(nonce + 1);
+                Ok(tx_hash)
+            }
+            Err(error) => {
+                self.next_nonce = None;
+                Err(error)
+            }
+        }
+    }
+
+    /// Polls the receipt of `tx_hash` until it is mined, then confirms or fails what it carries.
+    /// Errors while polling are treated as "not mined yet".
+    fn spawn_watcher(&self, engine: &Arc<Mutex<MatchingEngine>>, watched: Watched, tx_hash: [u8; 32]) {
+        let chain = self.chain.clone();
+        let connected = self.connected.clone();
+        let engine = engine.clone();
+        let interval = self.config.confirmation_interval;
+        let recredit = self.config.recredit_on_failure;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let receipt = chain.transaction_receipt(tx_hash).await;
+                note_connection(&connected, &receipt);
+                match receipt {
+                    Ok(Some(receipt)) if receipt.success => {
+                        let command = match watched {
+                            Watched::Settlement(settlement_id) => WalCommand::SettlementConfirmed { settlement_id },
+                            Watched::Batch(batch_id) => WalCommand::SettlementBatchConfirmed { batch_id },
+                        };
+                        record(&engine, command).await;
+                        return;
+                    }
+                    Ok(Some(receipt)) => {
+                        let reason = format!("Transaction reverted in block {}", receipt.block_number);
+                        match watched {
+                            Watched::Settlement(settlement_id) => fail(&engine, settlement_id, reason, recredit).await,
+                            Watched::Batch(batch_id) => fail_batch(&engine, batch_id, reason, recredit).await,
+                        }
+                        return;
+                    }
+                    Ok(None) | Err(_) => {}
+                }
+            }
+        });
+    }
+}
+
+/// Logs a command and applies it to the engine.
+/// A command that cannot be logged is not applied, so the engine never gets ahead of its log.
+fn log_and_apply(engine: &mut MatchingEngine, command: &WalCommand) {
+    match engine.log(command) {
+        Ok(()) => engine.apply(command),
+        Err(error) => tracing::error!(?command, %error, "Failed to log a command"),
+    }
+}
+
+async fn record(engine: &Mutex<MatchingEngine>, command: WalCommand) {
+    log_and_apply(&mut *engine.lock().await, &command);
+}
+
+/// Records whether a call reached the node
+fn note_connection<T>(connected: &AtomicBool, result: &Result<T, RpcError>) {
+    connected.store(!matches!(result, Err(RpcError::Unavailable(_))), Ordering::Relaxed);
+}
+
+/// Marks a settlement Failed, re-crediting the maker under a fresh order ID if asked to.
+async fn fail(engine: &Mutex<MatchingEngine>, settlement_id: u64, reason: String, recredit: bool) {
+    let mut engine = engine.lock().await;
+    let recredit_order_id = recredit.then(|| engine.next_order_id().0);
+    log_and_apply(&mut engine, &WalCommand::SettlementFailed { settlement_id, reason, recredit_order_id });
+}
+
+/// Marks a batch and its settlements Failed, re-crediting each maker under a fresh order ID if asked to.
+async fn fail_batch(engine: &Mutex<MatchingEngine>, batch_id: u64, reason: String, recredit: bool) {
+    let mut engine = engine.lock().await;
+    let size = engine.settlements.batch(batch_id).map_or(0, |batch| batch.settlement_ids.len());
+    let recredit_order_ids = if recredit { (0..size).map(|_| engine.next_order_id().0).collect() } else { Vec::new() };
+    log_and_apply(&mut engine, &WalCommand::SettlementBatchFailed { batch_id, reason, recredit_order_ids });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{market::MarketConfig, quantity::Qty};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A chain that mines every transaction it accepts right away
+    struct MockChain {
+        failed_sends: AtomicUsize, // Sends that fail with a transient error before any is accepted
+        estimate_reverts: bool,
+        receipt_success: bool,
+        nonce_queries: AtomicUsize,
+        sent: std::sync::Mutex<Vec<Vec<u8>>>,
+        mined: std::sync::Mutex<HashSet<[u8; 32]>>,
+    }
+
+    impl MockChain {
+        fn new() -> Self {
+            Self {
+                failed_sends: AtomicUsize::new(0),
+                estimate_reverts: false,
+                receipt_success: true,
+                nonce_queries: AtomicUsize::new(0),
+                sent: std::sync::Mutex::new(Vec::new()),
+                mined: std::sync::Mutex::new(HashSet::new()),
+            }
+        }
+    }
+
+    impl ChainClient for MockChain {
+        fn chain_id(&self) -> ChainFuture<'_, u64> {
+            Box::pin(async { Ok(1) })
+        }
+
+        fn pending_nonce(&self, _address: [u8; 20]) -> ChainFuture<'_, u64> {
+            self.nonce_queries.fetch_add(1, Ordering::SeqCst);
+            let nonce = 7 + self.sent.lock().unwrap().len() as u64;
+            Box::pin(async move { Ok(nonce) })
+        }
+
+        fn gas_price(&self) -> ChainFuture<'_, u128> {
+            Box::pin(async { Ok(1_000_000_000) })
+        }
+
+        fn estimate_gas(&self, _from: [u8; 20], _to: [u8; 20], _data: Vec<u8>) -> ChainFuture<'_, u64> {
+            let result = if self.estimate_reverts {
+                Err(RpcError::Reverted("execution reverted: insufficient balance".to_string()))
+            } else {
+                Ok(100_000)
+            };
+            Box::pin(async move { result })
+        }
+
+        fn send_raw_transaction(&self, raw: Vec<u8>) -> ChainFuture<'_, [u8; 32]> {
+            let result = if self.failed_sends.load(Ordering::SeqCst) > 0 {
+                self.failed_sends.fetch_sub(1, Ordering::SeqCst);
+                Err(RpcError::Unavailable("connection reset".to_string()))
+            } else {
+                let tx_hash: [u8; 32] = Keccak256::digest(&raw).into();
+                self.mined.lock().unwrap().insert(tx_hash);
+                self.sent.lock().unwrap().push(raw);
+                Ok(tx_hash)
+            };
+            Box::pin(async move { result })
+        }
+
+        fn transaction_receipt(&self, tx_hash: [u8; 32]) -> ChainFuture<'_, Option<TransactionReceipt>> {
+            let receipt = self.mined.lock().unwrap().contains(&tx_hash).then_some(TransactionReceipt {
+                success: self.receipt_success,
+                block_number: 1,
+            });
+            Box::pin(async move { Ok(receipt) })
+        }
+    }
+
+    fn fast_config() -> SubmitterConfig {
+        SubmitterConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            confirmation_interval: Duration::from_millis(1),
+            batch_window: Duration::from_millis(1),
+            ..SubmitterConfig::default()
+        }
+    }
+
+    /// Fills 10 of a resting ask of 100 with a new bid, which registers a Pending settlement
+    fn fill(engine: &mut MatchingEngine) {
+        let order_id = engine.next_order_id();
+        engine.match_order(order_id, BookId(0), Qty(10), 100, true, Some([7; 20]), Some(order_id.0), Some(u64::MAX), Some([2; 65])).unwrap();
+    }
+
+    fn engine_with_settlements(fills: usize) -> Arc<Mutex<MatchingEngine>> {
+        let mut engine = MatchingEngine::auto_creating();
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).verifying_contract([9; 20]).build();
+        engine.market_manager.add_market(BookId(0), market, false).unwrap();
+        let maker_order_id = engine.next_order_id();
+        engine.orderbook_manager.add_order(
+            maker_order_id, BookId(0), Qty(100), 100, false, Some([5; 20]), Some(1), Some(u64::MAX), Some([1; 65]),
+        ).unwrap();
+        for _ in 0..fills {
+            fill(&mut engine);
+        }
+        Arc::new(Mutex::new(engine))
+    }
+
+    /// Waits until every settlement has finished and returns their statuses in ID order
+    async fn finished(engine: &Mutex<MatchingEngine>) -> Vec<SettlementStatus> {
+        for _ in 0..1_000 {
+            let statuses: Vec<SettlementStatus> =
+                engine.lock().await.settlements.settlements().map(|settlement| settlement.status.clone()).collect();
+            if statuses.iter().all(SettlementStatus::is_finished) {
+                return statuses;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("Settlements did not finish");
+    }
+
+    #[test]
+    fn test_eip155_example() {
+        // The example transaction of EIP-155
+        let tx = LegacyTransaction {
+            nonce: 9,
+            gas_price: 20_000_000_000,
+            gas_limit: 21_000,
+            to: [0x35; 20],
+            value: 1_000_000_000_000_000_000,
+            data: Vec::new(),
+            chain_id: 1,
+        };
+        assert_eq!(
+            hex::encode(tx.signing_hash()),
+            "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+        let (raw, _) = tx.sign(&SigningKey::from_slice(&[0x46; 32]).unwrap());
+        assert_eq!(
+            hex::encode(raw),
+            concat!(
+                "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000",
+                "8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f",
+                "761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submit_and_confirm() {
+        let engine = engine_with_settlements(2);
+        // Settlement 1 was already submitted before a restart
+        engine.lock().await.mark_settlement_submitted(1, [0xab; 32]).unwrap();
+        let chain = Arc::new(MockChain::new());
+        chain.mined.lock().unwrap().insert([0xab; 32]);
+
+        let submitter = SettlementSubmitter::new(chain.clone(), SigningKey::from_slice(&[3; 32]).unwrap(), fast_config());
+        let connected = submitter.connection();
+        submitter.start(engine.clone()).await;
+        finished(&engine).await;
+        assert!(connected.load(Ordering::Relaxed));
+        // Settlements matched while the submitter runs are queued straight away
+        fill(&mut *engine.lock().await);
+        let statuses = finished(&engine).await;
+        println!("Statuses: {:?}", statuses);
+
+        let sent = chain.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        let confirmed = |raw: &[u8]| SettlementStatus::Confirmed { tx_hash: Keccak256::digest(raw).into() };
+        assert_eq!(
+            statuses,
+            vec![SettlementStatus::Confirmed { tx_hash: [0xab; 32] }, confirmed(&sent[0]), confirmed(&sent[1])]
+        );
+        // The operator's nonce is fetched once and then counted locally
+        assert_eq!(chain.nonce_queries.load(Ordering::SeqCst), 1);
+
+        // The transaction calls the market's settlement contract with the encoded settlement
+        let engine = engine.lock().await;
+        let calldata = encode_settlement(&engine.settlements.get(2).unwrap().order, SETTLE_SELECTOR);
+        assert!(sent[0].windows(calldata.len()).any(|window| window == calldata));
+        assert!(sent[0].windows(21).any(|window| window == [[0x94].as_slice(), &[9; 20]].concat()));
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let engine = engine_with_settlements(1);
+        let chain = Arc::new(MockChain::new());
+        chain.failed_sends.store(2, Ordering::SeqCst);
+
+        SettlementSubmitter::new(chain.clone(), SigningKey::from_slice(&[3; 32]).unwrap(), fast_config())
+            .start(engine.clone())
+            .await;
+        let statuses = finished(&engine).await;
+        assert!(matches!(statuses[0], SettlementStatus::Confirmed { .. }));
+        // Every failed send makes the next attempt refetch the nonce
+        assert_eq!(chain.nonce_queries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failures() {
+        // A settlement that would revert is not retried
+        let engine = engine_with_settlements(1);
+        let chain = Arc::new(MockChain { estimate_reverts: true, ..MockChain::new() });
+        SettlementSubmitter::new(chain, SigningKey::from_slice(&[3; 32]).unwrap(), fast_config())
+            .start(engine.clone())
+            .await;
+        let statuses = finished(&engine).await;
+        println!("Reverting: {:?}", statuses);
+        assert_eq!(
+            statuses,
+            vec![SettlementStatus::Failed {
+                reason: "Submission failed on attempt 1: Call reverted: execution reverted: insufficient balance"
+                    .to_string(),
+            }]
+        );
+
+        // A node that stays down exhausts the retries
+        let engine = engine_with_settlements(1);
+        let chain = Arc::new(MockChain::new());
+        chain.failed_sends.store(100, Ordering::SeqCst);
+        let config = SubmitterConfig { max_retries: 2, ..fast_config() };
+        let submitter = SettlementSubmitter::new(chain, SigningKey::from_slice(&[3; 32]).unwrap(), config);
+        let connected = submitter.connection();
+        submitter.start(engine.clone()).await;
+        let statuses = finished(&engine).await;
+        println!("Unavailable: {:?}", statuses);
+        assert!(!connected.load(Ordering::Relaxed));
+        assert_eq!(
+            statuses,
+            vec![SettlementStatus::Failed {
+                reason: "Submission failed on attempt 3: Ethereum RPC unavailable: connection reset".to_string(),
+            }]
+        );
+
+        // A transaction that reverts on chain fails the settlement and can re-credit the maker
+        let engine = engine_with_settlements(1);
+        let chain = Arc::new(MockChain { receipt_success: false, ..MockChain::new() });
+        let config = SubmitterConfig { recredit_on_failure: true, ..fast_config() };
+        SettlementSubmitter::new(chain, SigningKey::from_slice(&[3; 32]).unwrap(), config)
+            .start(engine.clone())
+            .await;
+        let statuses = finished(&engine).await;
+        assert_eq!(statuses, vec![SettlementStatus::Failed { reason: "Transaction reverted in block 1".to_string() }]);
+        let engine = engine.lock().await;
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(100)));
+    }
+
+    #[tokio::test]
+    async fn test_batches() {
+        // Two settlements fill a batch; the third waits for a window that never passes
+        let engine = engine_with_settlements(3);
+        let chain = Arc::new(MockChain::new());
+        let config = SubmitterConfig { batch_window: Duration::from_secs(3_600), max_batch_size: 2, ..fast_config() };
+        let handle = SettlementSubmitter::new(chain.clone(), SigningKey::from_slice(&[3; 32]).unwrap(), config)
+            .start(engine.clone())
+            .await;
+        for _ in 0..1_000 {
+            if !chain.sent.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(engine.lock().await.settlements.get(3).unwrap().status, SettlementStatus::Pending);
+        // Shutting down sends it anyway
+        handle.shutdown().await;
+        let statuses = finished(&engine).await;
+        println!("Statuses: {:?}", statuses);
+
+        let sent = chain.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        let confirmed = |raw: &[u8]| SettlementStatus::Confirmed { tx_hash: Keccak256::digest(raw).into() };
+        assert_eq!(statuses, vec![confirmed(&sent[0]), confirmed(&sent[0]), confirmed(&sent[1])]);
+
+        let engine = engine.lock().await;
+        let batch = engine.settlements.batch(1).unwrap();
+        assert_eq!((batch.settlement_ids.clone(), batch.status.clone()), (vec![1, 2], confirmed(&sent[0])));
+        assert_eq!(engine.settlements.get(3).unwrap().batch_id, None);
+        assert!(engine.settlements.batch(2).is_none());
+
+        // The batch transaction carries both orders and one netted transfer per token
+        let orders: Vec<SettlementOrder> =
+            [1, 2].iter().map(|&settlement_id| engine.settlements.get(settlement_id).unwrap().order.clone()).collect();
+        let transfers = net_transfers(&orders);
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers.iter().map(|transfer| transfer.amount).sum::<u128>(), orders.iter().map(|order| order.maker_amount + order.taker_amount).sum::<u128>());
+        let calldata = encode_netted_batch(&orders, &transfers, SETTLE_BATCH_SELECTOR);
+        assert!(sent[0].windows(calldata.len()).any(|window| window == calldata));
+    }
+
+    #[tokio::test]
+    async fn test_batch_failure() {
+        // A reverted batch fails every settlement in it and can re-credit every maker
+        let engine = engine_with_settlements(2);
+        let chain = Arc::new(MockChain { receipt_success: false, ..MockChain::new() });
+        let config = SubmitterConfig { recredit_on_failure: true, max_batch_size: 2, ..fast_config() };
+        SettlementSubmitter::new(chain, SigningKey::from_slice(&[3; 32]).unwrap(), config)
+            .start(engine.clone())
+            .await;
+        let statuses = finished(&engine).await;
+        let reverted = SettlementStatus::Failed { reason: "Transaction reverted in block 1".to_string() };
+        assert_eq!(statuses, vec![reverted.clone(), reverted.clone()]);
+        let engine = engine.lock().await;
+        assert_eq!(engine.settlements.batch(1).unwrap().status, reverted);
+        assert_eq!(engine.orderbook_manager.get_best_ask_size(BookId(0)), Some(Qty(100)));
+    }
+}
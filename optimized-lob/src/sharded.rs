@@ -0,0 +1,238 @@
+// sharded.rs
+
+use crate::{
+    command_queue::{CommandQueue, Lane},
+    matching::{MarketOrderFill, MatchDetails, MatchingEngine},
+    order::{Order, OrderId},
+    order_updates::OrderStatus,
+    orderbook_manager::OrderBookError,
+    quantity::Qty,
+    stats::BookStats,
+    utils::BookId,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Books spread over several engines, each run by its own matching worker
+/// Book `b` lives in shard `b % shards`, so activity in one book never waits on a book of
+/// another shard. Each shard hands out the order IDs `k * shards + shard`, so an order's ID
+/// alone finds its shard; orders must take their IDs from next_order_id to be found by ID.
+/// It forwards only order entry, replace, cancel, and lookups, run on the owning shard; those
+/// spanning books fan out to every shard and merge the answers. Anything else reaches a book's
+/// engine through with_book.
+///
+/// This does not take the engine lock off the API server: it still runs a single
+/// Mutex<MatchingEngine>, and every request waits on it. What MatchingEngine keeps across
+/// books, such as nonces, positions, settlements, and the WAL, would be kept per shard here.
+pub struct ShardedEngine {
+    shards: Vec<Arc<CommandQueue>>, // Each shard's queue, whose worker owns its engine
+}
+
+impl ShardedEngine {
+    /// Creates `shards` empty engines and spawns their workers onto the current Tokio runtime
+    /// There is no registry here to set books up in, so each engine creates a book on its first
+    /// order and matches it without a market, see MatchingEngine::auto_creating.
+    pub fn new(shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            // Library callers get no backpressure: a command is only refused once its worker has stopped
+            .map(|_| CommandQueue::spawn(Arc::new(Mutex::new(MatchingEngine::auto_creating())), usize::MAX))
+            .collect();
+        Self { shards }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Gets the shard a book lives in
+    #[inline]
+    pub fn shard_of_book(&self, book_id: BookId) -> usize {
+        book_id.value() as usize % self.shards.len()
+    }
+
+    /// Gets the shard that handed out an order ID
+    #[inline]
+    pub fn shard_of_order(&self, order_id: OrderId) -> usize {
+        (order_id.0 % self.shards.len() as u64) as usize
+    }
+
+    /// Runs `command` on the shard of `book_id`, failing with ShuttingDown if its worker has stopped
+    pub async fn with_book<T: Send + 'static>(
+        &self,
+        book_id: BookId,
+        command: impl FnOnce(&mut MatchingEngine) -> T + Send + 'static,
+    ) -> Result<T, OrderBookError> {
+        self.run(self.shard_of_book(book_id), Lane::New, command).await
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        shard: usize,
+        lane: Lane,
+        command: impl FnOnce(&mut MatchingEngine) -> T + Send + 'static,
+    ) -> Result<T, OrderBookError> {
+        self.shards[shard].run(lane, command).await.map_err(|_| OrderBookError::ShuttingDown)
+    }
+
+    /// Runs `command` on every shard at once, returning each shard's result in shard order
+    async fn fan_out<T: Send + 'static>(
+        &self,
+        lane: Lane,
+        command: impl FnOnce(&mut MatchingEngine) -> T + Clone + Send + 'static,
+    ) -> Result<Vec<T>, OrderBookError> {
+        let pending: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| {
+                let (shard, command) = (shard.clone(), command.clone());
+                tokio::spawn(async move { shard.run(lane, command).await })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(pending.len());
+        for result in pending {
+            let result = result.await.map_err(|_| OrderBookError::ShuttingDown)?;
+            results.push(result.map_err(|_| OrderBookError::ShuttingDown)?);
+        }
+        Ok(results)
+    }
+
+    /// Assigns the next order ID for an order going into `book_id`
+    pub async fn next_order_id(&self, book_id: BookId) -> Result<OrderId, OrderBookError> {
+        let (shard, shards) = (self.shard_of_book(book_id), self.shards.len() as u64);
+        self.run(shard, Lane::New, move |engine| OrderId(engine.next_order_id().0 * shards + shard as u64)).await
+    }
+
+    /// Matches an order at limit `price` in its book's shard, see MatchingEngine::match_limit_order
+    pub async fn match_limit_order(
+        &self,
+        order_id: OrderId,
+        order: Order,
+        price: u32,
+        is_bid: bool,
+    ) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        self.with_book(order.book_id(), move |engine| engine.match_limit_order(order_id, order, price, is_bid)).await?
+    }
+
+    /// Executes a market order in its book's shard, see MatchingEngine::match_market_order
+    pub async fn match_market_order(&self, order_id: OrderId, order: Order, is_bid: bool) -> Result<MarketOrderFill, OrderBookError> {
+        self.with_book(order.book_id(), move |engine| engine.match_market_order(order_id, order, is_bid)).await?
+    }
+
+    /// Replaces a resting order in the shard that handed out its ID, see MatchingEngine::replace_order
+    /// `new_order_id` must come from next_order_id for the same book, or the replacement can't be found by ID.
+    pub async fn replace_order(
+        &self,
+        order_id: OrderId,
+        new_order_id: OrderId,
+        new_qty: Qty,
+        new_price: u32,
+    ) -> Result<(Qty, Vec<MatchDetails>), OrderBookError> {
+        let replace = move |engine: &mut MatchingEngine| engine.replace_order(order_id, new_order_id, new_qty, new_price);
+        self.run(self.shard_of_order(order_id), Lane::New, replace).await?
+    }
+
+    /// Cancels a resting or parked order in the shard that handed out its ID
+    pub async fn cancel_resting(&self, order_id: OrderId, status: OrderStatus) -> Result<(), OrderBookError> {
+        self.run(self.shard_of_order(order_id), Lane::Cancel, move |engine| engine.cancel_resting(order_id, status)).await?
+    }
+
+    /// Gets the status and remaining quantity of a working order, or None, also once its shard has stopped
+    pub async fn order_status(&self, order_id: OrderId) -> Option<(OrderStatus, Qty)> {
+        self.run(self.shard_of_order(order_id), Lane::New, move |engine| engine.order_status(order_id)).await.ok()?
+    }
+
+    /// Gets the price an order rests at, or None
+    pub async fn order_price(&self, order_id: OrderId) -> Option<u32> {
+        self.run(self.shard_of_order(order_id), Lane::New, move |engine| engine.order_price(order_id)).await.ok()?
+    }
+
+    /// Cancels every working order of a trader, in one book or across every shard
+    /// Returns the cancelled IDs shard by shard, each shard's in MatchingEngine's order.
+    pub async fn cancel_all_for_trader(&self, trader: [u8; 20], book_id: Option<BookId>) -> Vec<OrderId> {
+        let cancelled = match book_id {
+            Some(book_id) => {
+                let cancel = move |engine: &mut MatchingEngine| engine.cancel_all_for_trader(trader, Some(book_id));
+                self.run(self.shard_of_book(book_id), Lane::Cancel, cancel).await.map(|cancelled| vec![cancelled])
+            }
+            None => self.fan_out(Lane::Cancel, move |engine| engine.cancel_all_for_trader(trader, None)).await,
+        };
+        cancelled.unwrap_or_default().into_iter().flatten().collect()
+    }
+
+    /// Gets every book of every shard, by ID
+    pub async fn books(&self) -> Vec<BookId> {
+        let books = self.fan_out(Lane::New, |engine| engine.orderbook_manager.books().map(|(book_id, _)| book_id).collect::<Vec<_>>());
+        let mut books: Vec<BookId> = books.await.unwrap_or_default().into_iter().flatten().collect();
+        books.sort_unstable_by_key(BookId::value);
+        books
+    }
+
+    /// Gets the statistics of a book, or None if it has never traded
+    pub async fn book_stats(&self, book_id: BookId) -> Option<BookStats> {
+        self.with_book(book_id, move |engine| engine.stats().get(book_id).cloned()).await.ok()?
+    }
+
+    /// Halts or resumes matching in every shard
+    pub async fn set_halted(&self, halted: bool) -> Result<(), OrderBookError> {
+        self.fan_out(Lane::Cancel, move |engine| engine.set_halted(halted)).await.map(drop)
+    }
+
+    /// Tells whether any shard is halted
+    pub async fn is_halted(&self) -> bool {
+        self.fan_out(Lane::New, |engine| engine.is_halted()).await.map_or(true, |halted| halted.contains(&true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level::LevelId;
+
+    fn order(book_id: u32, qty: u64, trader: Option<[u8; 20]>) -> Order {
+        Order::new(Qty(qty), LevelId(0), BookId(book_id), trader, Some(1), Some(u64::MAX), [0; 65])
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sharded_engine() {
+        let engine = ShardedEngine::new(2);
+        let (trader, other) = (Some([1; 20]), Some([2; 20]));
+
+        // Books 0 and 1 land in different shards, each handing out its own IDs
+        let ask = engine.next_order_id(BookId(0)).await.unwrap();
+        let bid = engine.next_order_id(BookId(1)).await.unwrap();
+        println!("Order IDs: {:?} and {:?}", ask, bid);
+        assert_eq!((engine.shard_of_order(ask), engine.shard_of_order(bid)), (0, 1));
+        engine.match_limit_order(ask, order(0, 10, trader), 1000, false).await.unwrap();
+        engine.match_limit_order(bid, order(1, 10, trader), 990, true).await.unwrap();
+        assert_eq!(engine.order_status(ask).await, Some((OrderStatus::New, Qty(10))));
+        assert_eq!(engine.order_price(bid).await, Some(990));
+        assert_eq!(engine.books().await, vec![BookId(0), BookId(1)]);
+
+        // A replace stays in the order's shard
+        let ask = {
+            let new_ask = engine.next_order_id(BookId(0)).await.unwrap();
+            engine.replace_order(ask, new_ask, Qty(10), 1001).await.unwrap();
+            assert_eq!((engine.order_price(ask).await, engine.order_price(new_ask).await), (None, Some(1001)));
+            new_ask
+        };
+
+        // A fill in one book leaves the other alone
+        let taker = engine.next_order_id(BookId(0)).await.unwrap();
+        let (remaining, fills) = engine.match_limit_order(taker, order(0, 4, other), 1001, true).await.unwrap();
+        assert_eq!((remaining, fills.len()), (Qty(0), 1));
+        assert_eq!(engine.book_stats(BookId(0)).await.map(|stats| stats.last_price()), Some(Some(1001)));
+        assert!(engine.book_stats(BookId(1)).await.is_none());
+
+        // Cancelling everything of a trader reaches every shard
+        let mut cancelled = engine.cancel_all_for_trader(trader.unwrap(), None).await;
+        cancelled.sort_unstable_by_key(|order_id| order_id.0);
+        assert_eq!(cancelled, vec![bid, ask]);
+        assert_eq!(engine.cancel_resting(ask, OrderStatus::Cancelled).await, Err(OrderBookError::UnknownOrder));
+
+        engine.set_halted(true).await.unwrap();
+        assert!(engine.is_halted().await);
+        let order_id = engine.next_order_id(BookId(1)).await.unwrap();
+        let result = engine.match_limit_order(order_id, order(1, 1, other), 990, true).await;
+        assert_eq!(result.map(|(remaining, _)| remaining), Err(OrderBookError::Halted));
+    }
+}
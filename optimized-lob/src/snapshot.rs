@@ -0,0 +1,345 @@
+// snapshot.rs
+
+use crate::{
+    client_order_ids::ClientOrderEntry,
+    market::{MarketConfig, MarketError},
+    nonce_registry::TraderNonces,
+    order::Signature,
+    orderbook_manager::OrderBookError,
+    mass_quote::QuoteSet,
+    oco::OcoGroup,
+    pegs::PeggedOrder,
+    positions::Position,
+    settlement_manager::{SettlementBatch, TrackedSettlement},
+    stops::StopOrder,
+    utils::hex_bytes,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Snapshot files are named `snapshot-<taken_at>.json`, zero-padded so the newest sorts last.
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".json";
+
+/// A resting order with everything needed to put it back on the book.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderSnapshot {
+    pub order_id: u64,
+    pub qty: u64,
+    #[serde(with = "hex_bytes")]
+    pub trader: Option<[u8; 20]>,
+    pub nonce: Option<u64>,
+    pub expiry: Option<u64>,
+    pub signature: Signature,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<u64>, // Display quantity of an iceberg; `qty` is its displayed slice
+    #[serde(default)]
+    pub reserve: u64, // Hidden quantity of an iceberg
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reduce_only: bool,
+    #[serde(default)]
+    pub received_at: u64, // When the order arrived, in nanoseconds since the Unix epoch
+}
+
+/// What is left of an order cut short by the fill limit, waiting to go on matching at its limit.
+/// Its `qty` is all that is left; it has no reserve until it rests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContinuationSnapshot {
+    pub book_id: u32,
+    pub price: u32,
+    pub is_bid: bool,
+    pub order: OrderSnapshot,
+}
+
+/// A price level and its orders in time priority.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelSnapshot {
+    pub price: u32,
+    pub size: u64,
+    pub orders: Vec<OrderSnapshot>,
+}
+
+/// One book, best levels first on both sides.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookSnapshot {
+    pub book_id: u32,
+    #[serde(default)]
+    pub seq: u64, // Sequence number of the book's last change.
+    #[serde(default)]
+    pub last_price: Option<u32>, // Price of the book's last trade, the center of its price band.
+    pub bids: Vec<LevelSnapshot>,
+    pub asks: Vec<LevelSnapshot>,
+}
+
+/// The full state of a MatchingEngine at one point in time.
+/// Restoring it into an empty engine gives identical depth, resting orders, and ID counters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub taken_at: u64, // Nanoseconds since the Unix epoch.
+    pub next_order_id: u64,
+    pub next_trade_id: u64,
+    pub event_seq: u64,
+    pub books: Vec<BookSnapshot>,
+    pub markets: Vec<(u32, MarketConfig)>,
+    #[serde(default)]
+    pub nonces: Vec<TraderNonces>,
+    #[serde(default)]
+    pub settlements: Vec<TrackedSettlement>, // Every tracked settlement, in-flight ones included.
+    #[serde(default = "first_settlement_id")]
+    pub next_settlement_id: u64,
+    #[serde(default)]
+    pub settlement_batches: Vec<SettlementBatch>,
+    #[serde(default = "first_settlement_id")]
+    pub next_settlement_batch_id: u64,
+    #[serde(default)]
+    pub auctions: Vec<u32>, // Books in an auction.
+    #[serde(default)]
+    pub book_only: Option<Vec<u32>>, // Books that match without a market; left out, every book without one.
+    #[serde(default)]
+    pub stops: Vec<StopOrder>, // Stops waiting for their trigger.
+    #[serde(default)]
+    pub triggered_stops: Vec<u64>, // Stop-limits that triggered and may still rest.
+    #[serde(default)]
+    pub pegs: Vec<PeggedOrder>, // Pegged orders; the resting ones are also in their books.
+    #[serde(default)]
+    pub oco_groups: Vec<OcoGroup>, // OCO pairs still linked.
+    #[serde(default)]
+    pub quote_sets: Vec<QuoteSet>, // Each trader's last mass quote in each book.
+    #[serde(default)]
+    pub positions: Vec<Position>, // Open positions in books whose market tracks them.
+    #[serde(default)]
+    pub client_order_ids: Vec<ClientOrderEntry>, // Client order IDs of working orders.
+    #[serde(default)]
+    pub continuations: Vec<ContinuationSnapshot>, // Orders cut short by the fill limit, oldest first.
+    #[serde(default)]
+    pub halted: bool, // The kill switch was engaged.
+    pub registry: Vec<(String, u32)>, // Book names and their BookIds, filled in by the API layer.
+    pub wal_segment: Option<u64>, // First WAL segment not covered by this snapshot.
+}
+
+/// Why a snapshot couldn't be restored: it doesn't describe a state an engine could have been in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    Book(OrderBookError),
+    Market(MarketError),
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestoreError::Book(error) => write!(f, "{}", error),
+            RestoreError::Market(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl From<OrderBookError> for RestoreError {
+    fn from(error: OrderBookError) -> Self {
+        RestoreError::Book(error)
+    }
+}
+
+impl From<MarketError> for RestoreError {
+    fn from(error: MarketError) -> Self {
+        RestoreError::Market(error)
+    }
+}
+
+impl EngineSnapshot {
+    /// Gets the number of resting orders in the snapshot.
+    pub fn order_count(&self) -> usize {
+        self.books
+            .iter()
+            .flat_map(|book| book.bids.iter().chain(&book.asks))
+            .map(|level| level.orders.len())
+            .sum()
+    }
+
+    /// Writes the snapshot into `dir` and returns its path.
+    /// The file is written under a temporary name and renamed once synced, so a crash
+    /// never leaves a partial snapshot behind under a real name.
+    pub fn write_to(&self, dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}{:020}{}", SNAPSHOT_PREFIX, self.taken_at, SNAPSHOT_SUFFIX));
+        let tmp = path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+
+    /// Reads a snapshot file.
+    pub fn read_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Reads the newest snapshot in `dir`, or None if there is none.
+    pub fn load_latest(dir: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            return Ok(None);
+        }
+        let latest = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX))
+            .max();
+        latest.map(|name| Self::read_from(dir.join(name))).transpose()
+    }
+}
+
+/// Settlement and batch IDs start at 1; snapshots from before they were tracked have none.
+fn first_settlement_id() -> u64 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        matching::MatchingEngine,
+        order::OrderId,
+        quantity::Qty,
+        utils::BookId,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Book, quantity, side, trader, nonce, expiry, and signature of a resting order
+    type Resting = (u32, u64, bool, Option<[u8; 20]>, Option<u64>, Option<u64>, Signature);
+
+    fn resting(engine: &MatchingEngine, order_id: OrderId) -> Option<Resting> {
+        let manager = &engine.orderbook_manager;
+        let order = manager.oid_map.get_order(order_id)?;
+        Some((
+            order.book_id().value(),
+            order.qty().value(),
+            manager.is_bid(order_id)?,
+            order.trader(),
+            order.nonce(),
+            order.expiry(),
+            order.signature(),
+        ))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut engine = MatchingEngine::auto_creating();
+        engine.market_manager.add_market(
+            BookId(2),
+            MarketConfig::builder()
+                .base_token([1; 20])
+                .security_token([2; 20])
+                .fee_recipient([3; 20])
+                .pool([4; 20])
+                .signature_type(1)
+                .chain_id(8453)
+                .verifying_contract([5; 20])
+                .build(),
+            false,
+        ).unwrap();
+
+        // Bids below 1000 and asks above it, so nothing crosses while populating
+        for id in 0..10_000u32 {
+            let is_bid = rng.gen_bool(0.5);
+            let price = if is_bid { rng.gen_range(900..1000) } else { rng.gen_range(1001..1100) };
+            let order_id = engine.next_order_id();
+            engine.orderbook_manager.add_order(
+                order_id,
+                BookId(id % 5),
+                Qty(rng.gen_range(1..500)),
+                price,
+                is_bid,
+                Some([(id % 7) as u8; 20]),
+                Some(id as u64),
+                Some(u64::MAX - id as u64),
+                Some([(id % 251) as u8; 65]),
+            ).unwrap();
+        }
+        // Some partial fills and cancels, so quantities and levels are not pristine
+        for book in 0..5 {
+            engine.match_order(OrderId(20_000 + book as u64), BookId(book), Qty(700), 1010, true, None, None, None, None).unwrap();
+        }
+        for id in (0..10_000).step_by(13) {
+            // Some of these were filled by the sweeps
+            let _ = engine.orderbook_manager.remove_order(OrderId(id));
+        }
+        for nonce in [3, 700, 701] {
+            engine.nonces.consume([9; 20], nonce).unwrap();
+        }
+        engine.nonces.bump([8; 20], 512);
+        // A signed sweep of the book with a market leaves settlements in flight
+        engine.match_order(OrderId(20_010), BookId(2), Qty(600), 1050, true, Some([6; 20]), Some(1), Some(u64::MAX), Some([6; 65])).unwrap();
+        assert!(engine.settlements.settlements().count() >= 2);
+        engine.mark_settlement_submitted(1, [0xee; 32]).unwrap();
+        engine.mark_settlement_failed(2, "reverted".to_string(), None).unwrap();
+        engine.mark_settlement_batch_submitted(1, &[1], [0xef; 32]).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = engine.snapshot();
+        println!("Snapshot of {} orders across {} books", snapshot.order_count(), snapshot.books.len());
+        assert_eq!(snapshot.books.len(), 5);
+        snapshot.write_to(dir.path()).unwrap();
+
+        let loaded = EngineSnapshot::load_latest(dir.path()).unwrap().unwrap();
+        assert_eq!(loaded, snapshot);
+        let restored = MatchingEngine::restore(loaded).unwrap();
+
+        // Depth, resting orders, and counters all match
+        let mut resnapshot = restored.snapshot();
+        resnapshot.taken_at = snapshot.taken_at;
+        // The books the auto-creating engine matched without a market are book-only once restored
+        let marketless: Vec<u32> = (0..5).filter(|&book| restored.market_manager.get_config(BookId(book)).is_none()).collect();
+        assert_eq!((snapshot.book_only.as_ref(), resnapshot.book_only.take()), (None, Some(marketless)));
+        assert_eq!(resnapshot, snapshot);
+        for id in 0..20_005 {
+            assert_eq!(resting(&restored, OrderId(id)), resting(&engine, OrderId(id)), "order {}", id);
+        }
+        for book in 0..5 {
+            let book_id = BookId(book);
+            let manager = &engine.orderbook_manager;
+            let restored_manager = &restored.orderbook_manager;
+            assert_eq!(restored_manager.get_best_bid(book_id), manager.get_best_bid(book_id));
+            assert_eq!(restored_manager.get_best_ask(book_id), manager.get_best_ask(book_id));
+            assert_eq!(restored_manager.get_best_bid_size(book_id), manager.get_best_bid_size(book_id));
+        }
+        assert_eq!(restored.market_manager.get_config(BookId(2)), engine.market_manager.get_config(BookId(2)));
+        assert!(restored.nonces.check([9; 20], 700).is_err());
+        assert_eq!(restored.nonces.min_nonce([8; 20]), 512);
+        assert_eq!(restored.settlements.entries(), engine.settlements.entries());
+        assert_eq!(restored.settlements.next_settlement_id(), engine.settlements.next_settlement_id());
+        assert_eq!(restored.settlements.batch_entries(), engine.settlements.batch_entries());
+        assert_eq!(restored.settlements.next_batch_id(), 2);
+
+        // Queue priority survives too: the same sweep fills the same makers
+        let mut engine = engine;
+        let mut restored = restored;
+        let (_, original_fills) = engine.match_order(OrderId(30_000), BookId(3), Qty(2_000), 1050, true, None, None, None, None).unwrap();
+        let (_, restored_fills) = restored.match_order(OrderId(30_000), BookId(3), Qty(2_000), 1050, true, None, None, None, None).unwrap();
+        let makers = |fills: &[crate::matching::MatchDetails]| -> Vec<(u64, u64)> {
+            fills.iter().map(|fill| (fill.maker.nonce.unwrap(), fill.exec_qty.value())).collect()
+        };
+        assert_eq!(makers(&restored_fills), makers(&original_fills));
+    }
+
+    #[test]
+    fn test_restore_rejects_duplicate_order_id() {
+        let mut engine = MatchingEngine::auto_creating();
+        engine.match_order(OrderId(1), BookId(0), Qty(100), 990, true, None, None, None, None).unwrap();
+        engine.match_order(OrderId(2), BookId(0), Qty(100), 980, true, None, None, None, None).unwrap();
+        let mut snapshot = engine.snapshot();
+        snapshot.books[0].bids[1].orders[0].order_id = 1;
+
+        assert_eq!(
+            MatchingEngine::restore(snapshot).err(),
+            Some(RestoreError::Book(OrderBookError::DuplicateOrder(OrderId(1))))
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// stats.rs
+
+use crate::{
+    trade_tape::Trade,
+    utils::{BookId, STATS_BUCKET, STATS_WINDOW},
+};
+use std::collections::{HashMap, VecDeque};
+
+/// Rolling statistics of one book's trades.
+/// Volume is kept in STATS_BUCKET buckets covering the last STATS_WINDOW, so recording a trade
+/// drops whole expired buckets instead of rescanning the tape. High and low cover the session,
+/// i.e. every trade since the engine started.
+#[derive(Debug, Clone, Default)]
+pub struct BookStats {
+    last_price: Option<u32>,
+    high: Option<u32>,
+    low: Option<u32>,
+    trade_count: u64,
+    improved_fills: u64,    // Fills of limit orders, which have a price improvement
+    improvement_ticks: i64, // Their improvements summed
+    buckets: VecDeque<(u64, u64)>, // Bucket index since the epoch and the volume traded in it, oldest first
+    window_volume: u64,            // Sum of the buckets' volumes
+}
+
+impl BookStats {
+    /// Adds a trade to the statistics.
+    /// A trade stamped before the newest bucket, e.g. after a clock step back, counts toward that bucket.
+    pub fn record(&mut self, trade: &Trade) {
+        let bucket = Self::bucket(trade.timestamp);
+        self.expire(bucket);
+        match self.buckets.back_mut() {
+            Some((newest, volume)) if *newest >= bucket => *volume = volume.saturating_add(trade.qty.value()),
+            _ => self.buckets.push_back((bucket, trade.qty.value())),
+        }
+        self.window_volume = self.window_volume.saturating_add(trade.qty.value());
+
+        self.last_price = Some(trade.price);
+        self.high = Some(self.high.map_or(trade.price, |high| high.max(trade.price)));
+        self.low = Some(self.low.map_or(trade.price, |low| low.min(trade.price)));
+        self.trade_count += 1;
+    }
+
+    /// Gets the price of the most recent trade.
+    #[inline]
+    pub fn last_price(&self) -> Option<u32> {
+        self.last_price
+    }
+
+    /// Gets the highest trade price of the session.
+    #[inline]
+    pub fn high(&self) -> Option<u32> {
+        self.high
+    }
+
+    /// Gets the lowest trade price of the session.
+    #[inline]
+    pub fn low(&self) -> Option<u32> {
+        self.low
+    }
+
+    /// Gets the number of trades of the session.
+    #[inline]
+    pub fn trade_count(&self) -> u64 {
+        self.trade_count
+    }
+
+    /// Gets the average price improvement of the session's limit order fills, in ticks, or None
+    /// if there were none.
+    pub fn average_improvement(&self) -> Option<f64> {
+        (self.improved_fills > 0).then(|| self.improvement_ticks as f64 / self.improved_fills as f64)
+    }
+
+    /// Gets the volume traded in the STATS_WINDOW before `now`, in nanoseconds since the epoch.
+    /// Buckets that expired since the last trade are skipped rather than dropped.
+    pub fn volume(&self, now: u64) -> u64 {
+        let oldest = Self::oldest_bucket(Self::bucket(now));
+        let expired: u64 = self
+            .buckets
+            .iter()
+            .take_while(|&&(bucket, _)| bucket < oldest)
+            .map(|&(_, volume)| volume)
+            .sum();
+        self.window_volume.saturating_sub(expired)
+    }
+
+    /// Drops the buckets that fall out of the window ending in bucket `current`.
+    fn expire(&mut self, current: u64) {
+        let oldest = Self::oldest_bucket(current);
+        while let Some(&(bucket, volume)) = self.buckets.front() {
+            if bucket >= oldest {
+                break;
+            }
+            self.window_volume = self.window_volume.saturating_sub(volume);
+            self.buckets.pop_front();
+        }
+    }
+
+    #[inline]
+    fn bucket(timestamp: u64) -> u64 {
+        timestamp / STATS_BUCKET.as_nanos() as u64
+    }
+
+    /// Gets the first bucket still in the window ending in bucket `current`.
+    #[inline]
+    fn oldest_bucket(current: u64) -> u64 {
+        let buckets = (STATS_WINDOW.as_nanos() / STATS_BUCKET.as_nanos()) as u64;
+        (current + 1).saturating_sub(buckets)
+    }
+}
+
+/// Rolling statistics of every book that has traded.
+#[derive(Debug, Default)]
+pub struct StatsTracker {
+    books: HashMap<BookId, BookStats>,
+}
+
+impl StatsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a trade of `book_id` to its book's statistics.
+    #[inline]
+    pub fn record(&mut self, book_id: BookId, trade: &Trade) {
+        self.books.entry(book_id).or_default().record(trade);
+    }
+
+    /// Adds the price improvement of a limit order's fill, in ticks, to its book's statistics.
+    pub fn record_improvement(&mut self, book_id: BookId, ticks: i64) {
+        let stats = self.books.entry(book_id).or_default();
+        stats.improved_fills += 1;
+        stats.improvement_ticks = stats.improvement_ticks.saturating_add(ticks);
+    }
+
+    /// Sets the last trade price of a book restored from a snapshot, which keeps no trades.
+    pub fn restore_last_price(&mut self, book_id: BookId, price: u32) {
+        self.books.entry(book_id).or_default().last_price = Some(price);
+    }
+
+    /// Gets the statistics of a book, or None if it has never traded.
+    #[inline]
+    pub fn get(&self, book_id: BookId) -> Option<&BookStats> {
+        self.books.get(&book_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{order::OrderId, quantity::Qty};
+
+    const MINUTE: u64 = 60_000_000_000;
+    const HOUR: u64 = 60 * MINUTE;
+
+    fn trade(timestamp: u64, price: u32, qty: u64) -> Trade {
+        Trade {
+            trade_id: 0,
+            timestamp,
+            price,
+            qty: Qty(qty),
+            aggressor_is_bid: true,
+            maker_order_id: OrderId(0),
+            taker_order_id: OrderId(1),
+        }
+    }
+
+    #[test]
+    fn test_window_rolls_off() {
+        let mut tracker = StatsTracker::new();
+        assert!(tracker.get(BookId(0)).is_none());
+
+        let start = 1_000 * HOUR;
+        tracker.record(BookId(0), &trade(start, 100, 10));
+        tracker.record(BookId(0), &trade(start + 30 * MINUTE, 120, 5));
+        tracker.record(BookId(0), &trade(start + 12 * HOUR, 90, 7));
+        tracker.record(BookId(1), &trade(start, 500, 1));
+
+        let stats = tracker.get(BookId(0)).unwrap();
+        assert_eq!((stats.last_price(), stats.high(), stats.low(), stats.trade_count()), (Some(90), Some(120), Some(90), 3));
+        assert_eq!(stats.volume(start + 12 * HOUR), 22);
+
+        // A full day after the first trade, it has left the window; the second one leaves half an hour later
+        assert_eq!(stats.volume(start + 24 * HOUR - MINUTE), 22);
+        assert_eq!(stats.volume(start + 24 * HOUR), 12);
+        assert_eq!(stats.volume(start + 24 * HOUR + 30 * MINUTE), 7);
+        assert_eq!(stats.volume(start + 48 * HOUR), 0);
+
+        // Recording drops the expired buckets; the session high and low stay
+        tracker.record(BookId(0), &trade(start + 36 * HOUR, 95, 3));
+        let stats = tracker.get(BookId(0)).unwrap();
+        println!("Stats: {:?}", stats);
+        assert_eq!(stats.buckets.len(), 1);
+        assert_eq!(stats.volume(start + 36 * HOUR), 3);
+        assert_eq!((stats.last_price(), stats.high(), stats.low(), stats.trade_count()), (Some(95), Some(120), Some(90), 4));
+        assert_eq!(tracker.get(BookId(1)).unwrap().volume(start), 1);
+
+        assert_eq!(tracker.get(BookId(1)).unwrap().average_improvement(), None);
+        tracker.record_improvement(BookId(1), 5);
+        tracker.record_improvement(BookId(1), 4);
+        assert_eq!(tracker.get(BookId(1)).unwrap().average_improvement(), Some(4.5));
+    }
+
+    #[test]
+    fn test_trades_in_one_bucket() {
+        let mut stats = BookStats::default();
+        for second in 0..60 {
+            stats.record(&trade(HOUR + second * 1_000_000_000, 100, 1));
+        }
+        // A late timestamp joins the newest bucket
+        stats.record(&trade(HOUR - MINUTE, 100, 1));
+        assert_eq!(stats.buckets.len(), 1);
+        assert_eq!(stats.volume(HOUR), 61);
+    }
+}
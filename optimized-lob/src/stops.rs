@@ -0,0 +1,215 @@
+// stops.rs
+
+use crate::{
+    level::LevelId,
+    order::{Order, OrderId, Signature},
+    orderbook_manager::OrderBookError,
+    quantity::Qty,
+    utils::{hex_bytes, BookId},
+};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+
+/// A stop or stop-limit order waiting for its trigger
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StopOrder {
+    pub order_id: u64,
+    pub book_id: u32,
+    pub qty: u64,
+    pub is_bid: bool,
+    pub trigger: u32,
+    pub limit: Option<u32>, // Limit price of a stop-limit; a plain stop goes in as a market order
+    #[serde(with = "hex_bytes")]
+    pub trader: Option<[u8; 20]>,
+    pub nonce: Option<u64>,
+    pub expiry: Option<u64>,
+    pub signature: Signature,
+}
+
+impl StopOrder {
+    /// Gets the order the stop goes in as once triggered
+    pub fn order(&self) -> Order {
+        Order::new(
+            Qty(self.qty),
+            LevelId(0),
+            BookId(self.book_id),
+            self.trader,
+            self.nonce,
+            self.expiry,
+            self.signature,
+        )
+    }
+
+    /// Returns true if a trade at `price` is at or through the trigger
+    #[inline]
+    pub fn is_triggered_by(&self, price: u32) -> bool {
+        if self.is_bid {
+            price >= self.trigger
+        } else {
+            price <= self.trigger
+        }
+    }
+}
+
+/// Untriggered stop orders of every book.
+/// Stops are kept apart from the limit books, so they never show in depth or match before
+/// they trigger. Each side is indexed by trigger, nearest to the market first.
+#[derive(Debug, Default)]
+pub struct StopBook {
+    orders: HashMap<OrderId, StopOrder>,
+    buys: BTreeSet<(BookId, u32, OrderId)>,           // Lowest trigger first
+    sells: BTreeSet<(BookId, Reverse<u32>, OrderId)>, // Highest trigger first
+}
+
+impl StopBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a stop; fails with DuplicateOrder if its order ID is already waiting.
+    pub fn insert(&mut self, stop: StopOrder) -> Result<(), OrderBookError> {
+        let order_id = OrderId(stop.order_id);
+        if self.orders.contains_key(&order_id) {
+            return Err(OrderBookError::DuplicateOrder(order_id));
+        }
+        let book_id = BookId(stop.book_id);
+        if stop.is_bid {
+            self.buys.insert((book_id, stop.trigger, order_id));
+        } else {
+            self.sells.insert((book_id, Reverse(stop.trigger), order_id));
+        }
+        self.orders.insert(order_id, stop);
+        Ok(())
+    }
+
+    /// Removes a stop, returning it if it was waiting.
+    pub fn remove(&mut self, order_id: OrderId) -> Option<StopOrder> {
+        let stop = self.orders.remove(&order_id)?;
+        let book_id = BookId(stop.book_id);
+        if stop.is_bid {
+            self.buys.remove(&(book_id, stop.trigger, order_id));
+        } else {
+            self.sells.remove(&(book_id, Reverse(stop.trigger), order_id));
+        }
+        Some(stop)
+    }
+
+    #[inline]
+    pub fn get(&self, order_id: OrderId) -> Option<&StopOrder> {
+        self.orders.get(&order_id)
+    }
+
+    /// Changes how much a waiting stop goes in with once triggered
+    pub(crate) fn set_qty(&mut self, order_id: OrderId, qty: u64) {
+        if let Some(stop) = self.orders.get_mut(&order_id) {
+            stop.qty = qty;
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    /// Lists the waiting stops in order ID order
+    pub fn entries(&self) -> Vec<StopOrder> {
+        let mut stops: Vec<StopOrder> = self.orders.values().cloned().collect();
+        stops.sort_unstable_by_key(|stop| stop.order_id);
+        stops
+    }
+
+    /// Removes the stops matching `filter`, returning their order IDs in order ID order
+    pub fn remove_where(&mut self, mut filter: impl FnMut(&StopOrder) -> bool) -> Vec<OrderId> {
+        let mut order_ids: Vec<OrderId> = self
+            .orders
+            .iter()
+            .filter(|(_, stop)| filter(stop))
+            .map(|(&order_id, _)| order_id)
+            .collect();
+        order_ids.sort_unstable();
+        for &order_id in &order_ids {
+            self.remove(order_id);
+        }
+        order_ids
+    }
+
+    /// Removes and returns the stops of `book_id` that trades between `low` and `high` trigger.
+    /// Buy stops come first, lowest trigger first, then sell stops, highest trigger first;
+    /// stops with the same trigger come in order ID order.
+    pub fn take_triggered(&mut self, book_id: BookId, low: u32, high: u32) -> Vec<StopOrder> {
+        let buys: Vec<OrderId> = self
+            .buys
+            .range((book_id, 0, OrderId(0))..=(book_id, high, OrderId(u64::MAX)))
+            .map(|&(_, _, order_id)| order_id)
+            .collect();
+        let sells: Vec<OrderId> = self
+            .sells
+            .range((book_id, Reverse(u32::MAX), OrderId(0))..=(book_id, Reverse(low), OrderId(u64::MAX)))
+            .map(|&(_, _, order_id)| order_id)
+            .collect();
+        buys.into_iter()
+            .chain(sells)
+            .filter_map(|order_id| self.remove(order_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(order_id: u64, book_id: u32, is_bid: bool, trigger: u32) -> StopOrder {
+        StopOrder {
+            order_id,
+            book_id,
+            qty: 10,
+            is_bid,
+            trigger,
+            limit: None,
+            trader: None,
+            nonce: None,
+            expiry: None,
+            signature: Signature::None,
+        }
+    }
+
+    #[test]
+    fn test_take_triggered() {
+        let mut stops = StopBook::new();
+        for stop in [
+            stop(0, 0, true, 105),
+            stop(1, 0, true, 101),
+            stop(2, 0, true, 101),
+            stop(3, 0, false, 95),
+            stop(4, 0, false, 99),
+            stop(5, 1, true, 100), // Another book
+        ] {
+            stops.insert(stop).unwrap();
+        }
+        assert!(matches!(stops.insert(stop(1, 0, true, 200)), Err(OrderBookError::DuplicateOrder(OrderId(1)))));
+        assert!(stop(1, 0, true, 101).is_triggered_by(101) && !stop(3, 0, false, 95).is_triggered_by(96));
+
+        // Trades between 99 and 102 trigger the buys at 101 and the sell at 99
+        let triggered: Vec<u64> = stops.take_triggered(BookId(0), 99, 102).iter().map(|stop| stop.order_id).collect();
+        println!("Triggered: {:?}", triggered);
+        assert_eq!(triggered, vec![1, 2, 4]);
+        assert_eq!(stops.len(), 3);
+        assert!(stops.take_triggered(BookId(0), 99, 102).is_empty());
+
+        // Sells come highest trigger first
+        stops.insert(stop(6, 0, false, 97)).unwrap();
+        let triggered: Vec<u64> = stops.take_triggered(BookId(0), 90, 90).iter().map(|stop| stop.order_id).collect();
+        assert_eq!(triggered, vec![6, 3]);
+
+        assert_eq!(stops.remove_where(|stop| stop.book_id == 1), vec![OrderId(5)]);
+        assert_eq!(stops.remove(OrderId(0)).map(|stop| stop.trigger), Some(105));
+        assert!(stops.is_empty());
+        assert!(stops.take_triggered(BookId(0), 0, u32::MAX).is_empty());
+    }
+}
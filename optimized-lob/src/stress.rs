@@ -0,0 +1,359 @@
+// stress.rs
+
+use crate::{
+    events::{OrderBookEvent, VecSink},
+    level::LevelId,
+    matching::MatchingEngine,
+    order::{Order, OrderId},
+    order_updates::OrderStatus,
+    quantity::Qty,
+    sharded::ShardedEngine,
+    utils::BookId,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How a stress run loads the engine
+#[derive(Debug, Clone)]
+pub struct StressConfig {
+    pub producers: usize,   // Concurrent tasks sending orders
+    pub books: u32,         // Books the orders are spread over, from book 0
+    pub shards: usize,      // Matching workers of the sharded engine
+    pub duration: Duration, // How long the producers keep sending
+    pub seed: u64,          // Producer `p` draws its orders from seed + p
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self { producers: 8, books: 4, shards: 2, duration: Duration::from_secs(10), seed: 0 }
+    }
+}
+
+/// What the producers of a stress run sent, and what rested at the end
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StressReport {
+    pub adds: u64,
+    pub cancels: u64,
+    pub replaces: u64,
+    pub market_orders: u64,
+    pub rejected: u64,        // Commands the engine refused, such as cancels of filled orders
+    pub traded_qty: u64,
+    pub resting_qty: u64,
+}
+
+impl StressReport {
+    fn merge(&mut self, other: &StressReport) {
+        self.adds += other.adds;
+        self.cancels += other.cancels;
+        self.replaces += other.replaces;
+        self.market_orders += other.market_orders;
+        self.rejected += other.rejected;
+    }
+}
+
+impl fmt::Display for StressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Adds:          {}", self.adds)?;
+        writeln!(f, "Cancels:       {}", self.cancels)?;
+        writeln!(f, "Replaces:      {}", self.replaces)?;
+        writeln!(f, "Market orders: {}", self.market_orders)?;
+        writeln!(f, "Rejected:      {}", self.rejected)?;
+        writeln!(f, "Traded qty:    {}", self.traded_qty)?;
+        writeln!(f, "Resting qty:   {}", self.resting_qty)
+    }
+}
+
+/// A book that broke an invariant, with every event it emitted during the run
+#[derive(Debug)]
+pub struct InvariantViolation {
+    pub book_id: BookId,
+    pub message: String,
+    pub events: Vec<OrderBookEvent>,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Book {} broke an invariant: {}", self.book_id.value(), self.message)?;
+        writeln!(f, "Event log ({} events):", self.events.len())?;
+        for event in &self.events {
+            writeln!(f, "  {:?}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Runs `config.producers` concurrent producers against a sharded engine for `config.duration`,
+/// then checks every book's invariants against its state and its event log.
+/// Producers add, cancel, and replace their own orders and send market orders, all at prices
+/// around 1000 so limit orders often cross. Must be called from within a multi-threaded runtime.
+pub async fn run_stress(config: &StressConfig) -> Result<StressReport, InvariantViolation> {
+    let engine = std::sync::Arc::new(ShardedEngine::new(config.shards));
+    let books = config.books.max(1);
+
+    // One sink per shard, installed through a book the shard owns
+    let mut sinks = Vec::with_capacity(engine.shard_count());
+    for shard in 0..engine.shard_count() {
+        let sink = VecSink::new();
+        let installed = sink.clone();
+        engine
+            .with_book(BookId(shard as u32), move |engine| engine.orderbook_manager.set_event_sink(Box::new(installed)))
+            .await
+            .expect("shard worker stopped");
+        sinks.push(sink);
+    }
+
+    let deadline = Instant::now() + config.duration;
+    let producers: Vec<_> = (0..config.producers)
+        .map(|producer| {
+            let engine = engine.clone();
+            let seed = config.seed + producer as u64;
+            tokio::spawn(async move { produce(&engine, producer, seed, books, deadline).await })
+        })
+        .collect();
+    let mut report = StressReport::default();
+    for producer in producers {
+        report.merge(&producer.await.expect("producer panicked"));
+    }
+
+    for book in 0..books {
+        let book_id = BookId(book);
+        let events: Vec<OrderBookEvent> = sinks[engine.shard_of_book(book_id)]
+            .events()
+            .into_iter()
+            .filter(|event| event.book_id() == book_id)
+            .collect();
+        let log = events.clone();
+        let checked = engine
+            .with_book(book_id, move |engine| check_book(engine, book_id, &log))
+            .await
+            .expect("shard worker stopped");
+        match checked {
+            Ok((traded_qty, resting_qty)) => {
+                report.traded_qty += traded_qty;
+                report.resting_qty += resting_qty;
+            }
+            Err(message) => return Err(InvariantViolation { book_id, message, events }),
+        }
+    }
+    Ok(report)
+}
+
+/// Sends random commands until `deadline`, tracking the orders this producer has resting
+async fn produce(engine: &ShardedEngine, producer: usize, seed: u64, books: u32, deadline: Instant) -> StressReport {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let trader = [producer as u8 + 1; 20];
+    let mut report = StressReport::default();
+    let mut live: Vec<(OrderId, BookId)> = Vec::new();
+    let mut nonce = 0;
+
+    while Instant::now() < deadline {
+        let book_id = BookId(rng.gen_range(0..books));
+        let is_bid = rng.gen_bool(0.5);
+        let qty = Qty(rng.gen_range(1..=100));
+        let price = rng.gen_range(990..=1010);
+        nonce += 1;
+        let order = Order::new(qty, LevelId(0), book_id, Some(trader), Some(nonce), Some(u64::MAX), [0; 65]);
+
+        let result = match rng.gen_range(0..100) {
+            0..=49 => {
+                report.adds += 1;
+                match engine.next_order_id(book_id).await {
+                    Ok(order_id) => engine.match_limit_order(order_id, order, price, is_bid).await.map(|(remaining, _)| {
+                        if remaining.value() > 0 {
+                            live.push((order_id, book_id));
+                        }
+                    }),
+                    Err(error) => Err(error),
+                }
+            }
+            50..=69 if !live.is_empty() => {
+                report.cancels += 1;
+                let (order_id, _) = live.swap_remove(rng.gen_range(0..live.len()));
+                engine.cancel_resting(order_id, OrderStatus::Cancelled).await
+            }
+            70..=84 if !live.is_empty() => {
+                report.replaces += 1;
+                // The new order rests in the old one's book, so takes its ID from that book's shard
+                let (order_id, book_id) = live.swap_remove(rng.gen_range(0..live.len()));
+                match engine.next_order_id(book_id).await {
+                    Ok(new_order_id) => engine
+                        .with_book(book_id, move |engine| engine.replace_order(order_id, new_order_id, qty, price))
+                        .await
+                        .and_then(|replaced| replaced)
+                        .map(|(remaining, _)| {
+                            if remaining.value() > 0 {
+                                live.push((new_order_id, book_id));
+                            }
+                        }),
+                    Err(error) => Err(error),
+                }
+            }
+            _ => {
+                report.market_orders += 1;
+                match engine.next_order_id(book_id).await {
+                    Ok(order_id) => engine.match_market_order(order_id, order, is_bid).await.map(|_| ()),
+                    Err(error) => Err(error),
+                }
+            }
+        };
+        if result.is_err() {
+            report.rejected += 1;
+        }
+    }
+    report
+}
+
+/// Checks one book's levels and resting orders against each other and against its event log,
+/// returning the quantity traded and the quantity resting, or what is wrong
+pub fn check_book(engine: &MatchingEngine, book_id: BookId, events: &[OrderBookEvent]) -> Result<(u64, u64), String> {
+    let manager = &engine.orderbook_manager;
+    let Some(book) = manager.books().find(|(id, _)| *id == book_id).map(|(_, book)| book) else {
+        return if events.is_empty() { Ok((0, 0)) } else { Err("book has events but doesn't exist".to_string()) };
+    };
+
+    // Every level's size is the sum of its queue, and every queued order points back at it
+    let mut queued: HashMap<OrderId, LevelId> = HashMap::new();
+    let mut level_total = 0;
+    for (is_bid, levels) in [(true, &book.bids), (false, &book.asks)] {
+        for price_level in levels.iter() {
+            let level_id = price_level.level_id();
+            let level = book
+                .level_pool
+                .get(level_id)
+                .ok_or_else(|| format!("level {} at {} isn't allocated", level_id.0, price_level.price().absolute()))?;
+            if level.price() != price_level.price() || level.price().is_bid() != is_bid {
+                return Err(format!("level {} has price {:?} but is sorted at {:?}", level_id.0, level.price(), price_level.price()));
+            }
+            let mut queue_total = 0;
+            for (order_id, order) in manager.oid_map.pool().queue(level) {
+                if order.qty().value() == 0 {
+                    return Err(format!("order {} rests with no quantity", order_id.0));
+                }
+                if order.level_id() != level_id || order.book_id() != book_id {
+                    return Err(format!("order {} is queued on level {} but points at level {}", order_id.0, level_id.0, order.level_id().0));
+                }
+                queue_total += order.qty().value();
+                queued.insert(order_id, level_id);
+            }
+            if level.size().value() == 0 || level.size().value() != queue_total {
+                return Err(format!("level {} has size {} but its queue holds {}", level_id.0, level.size().value(), queue_total));
+            }
+            level_total += queue_total;
+        }
+    }
+
+    // Every order the map has in this book is queued on the level it names
+    let mut resting_total = 0;
+    let mut resting: HashMap<OrderId, u64> = HashMap::new();
+    for (order_id, order) in manager.oid_map.iter().filter(|(_, order)| order.book_id() == book_id) {
+        if queued.get(&order_id) != Some(&order.level_id()) {
+            return Err(format!("order {} names level {} but isn't queued there", order_id.0, order.level_id().0));
+        }
+        resting_total += order.qty().value();
+        resting.insert(order_id, manager.oid_map.total_qty(order_id).unwrap_or(order.qty()).value());
+    }
+    if resting.len() != queued.len() || resting_total != level_total {
+        return Err(format!(
+            "{} orders with {} qty rest in the map but {} with {} qty are queued",
+            resting.len(),
+            resting_total,
+            queued.len(),
+            level_total
+        ));
+    }
+
+    if let (Some(bid), Some(ask)) = (book.get_best_bid(), book.get_best_ask()) {
+        if bid.absolute() >= ask.absolute() {
+            return Err(format!("best bid {} crosses best ask {}", bid.absolute(), ask.absolute()));
+        }
+    }
+
+    // Replaying the event log gives back exactly the orders resting, and trades match executions
+    let mut shadow: HashMap<OrderId, u64> = HashMap::new();
+    let (mut traded, mut executed) = (0, 0);
+    for event in events {
+        match event {
+            OrderBookEvent::OrderAdded { order_id, qty, .. } => {
+                shadow.insert(*order_id, qty.value());
+            }
+            OrderBookEvent::OrderExecuted { order_id, exec_qty, .. } => {
+                executed += exec_qty.value();
+                take_qty(&mut shadow, *order_id, exec_qty.value())?;
+            }
+            OrderBookEvent::OrderCancelled { order_id, cancelled_qty, .. } => take_qty(&mut shadow, *order_id, cancelled_qty.value())?,
+            OrderBookEvent::OrderExpired { order_id, qty, .. } => take_qty(&mut shadow, *order_id, qty.value())?,
+            OrderBookEvent::OrderReplaced { order_id, .. } => {
+                shadow.remove(order_id).ok_or_else(|| format!("order {} was replaced but never added", order_id.0))?;
+            }
+            OrderBookEvent::Trade { trade, .. } => traded += trade.qty.value(),
+            _ => {}
+        }
+    }
+    shadow.retain(|_, qty| *qty > 0);
+    if traded != executed {
+        return Err(format!("trades total {} but executions total {}", traded, executed));
+    }
+    if shadow != resting {
+        let missing: HashSet<_> = shadow.keys().filter(|id| !resting.contains_key(id)).map(|id| id.0).collect();
+        let extra: HashSet<_> = resting.keys().filter(|id| !shadow.contains_key(id)).map(|id| id.0).collect();
+        return Err(format!(
+            "the event log leaves {} orders resting but the book has {}; only in the log: {:?}, only in the book: {:?}",
+            shadow.len(),
+            resting.len(),
+            missing,
+            extra
+        ));
+    }
+    Ok((traded, resting_total))
+}
+
+/// Takes `qty` off an order replayed from the event log
+fn take_qty(shadow: &mut HashMap<OrderId, u64>, order_id: OrderId, qty: u64) -> Result<(), String> {
+    let remaining = shadow.get_mut(&order_id).ok_or_else(|| format!("order {} lost qty but never rested", order_id.0))?;
+    *remaining = remaining
+        .checked_sub(qty)
+        .ok_or_else(|| format!("order {} lost {} qty but only had {}", order_id.0, qty, remaining))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_stress_invariants() {
+        let config = StressConfig { producers: 4, books: 3, shards: 2, duration: Duration::from_millis(500), seed: 1591 };
+        let report = match run_stress(&config).await {
+            Ok(report) => report,
+            Err(violation) => panic!("{}", violation),
+        };
+        println!("{}", report);
+        assert!(report.adds > 0 && report.cancels > 0 && report.replaces > 0 && report.market_orders > 0);
+        assert!(report.traded_qty > 0);
+    }
+
+    #[test]
+    fn test_check_book_catches_corruption() {
+        let mut engine = MatchingEngine::auto_creating();
+        let order = Order::new(Qty(10), LevelId(0), BookId(0), Some([1; 20]), Some(1), Some(u64::MAX), [0; 65]);
+        engine.match_limit_order(OrderId(1), order, 1000, true).unwrap();
+        assert!(check_book(&engine, BookId(0), &[]).is_err(), "the empty log doesn't add the order");
+
+        let sink = VecSink::new();
+        let mut engine = MatchingEngine::auto_creating();
+        engine.orderbook_manager.set_event_sink(Box::new(sink.clone()));
+        let order = Order::new(Qty(10), LevelId(0), BookId(0), Some([1; 20]), Some(1), Some(u64::MAX), [0; 65]);
+        engine.match_limit_order(OrderId(1), order, 1000, true).unwrap();
+        assert_eq!(check_book(&engine, BookId(0), &sink.events()), Ok((0, 10)));
+
+        // An order whose quantity changes behind its level's back
+        engine.orderbook_manager.oid_map.update_qty(OrderId(1), Qty(3));
+        let error = check_book(&engine, BookId(0), &sink.events()).unwrap_err();
+        println!("{}", error);
+        assert!(error.contains("level"));
+    }
+}
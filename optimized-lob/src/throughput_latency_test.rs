@@ -1,23 +1,100 @@
 use crate::{
+    auth::address_of,
+    book_registry::BookRegistry,
+    command_queue::{CommandQueue, Lane},
+    eip712::{Eip712Domain, Eip712Order},
+    level::LevelId,
     matching::MatchingEngine,
-    order::OrderId,
+    order::{OidMap, Order, OrderId, RestingOrder},
+    orderbook_manager::OrderBookManager,
+    price::Price,
     quantity::Qty,
+    sharded::ShardedEngine,
     utils::BookId,
     market::MarketConfig,
-    translator::translate_matches,
+    order_intake::{OrderIntake, OrderSubmission, VerifiedOrder, NO_EXPIRY},
+    settlement_manager::TrackedSettlement,
+    translator::collect_settlements,
 };
-use rand::Rng;
+use hdrhistogram::Histogram;
+use k256::ecdsa::SigningKey;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use hex;
 
 #[derive(Debug)]
 pub struct TestOrder {
     pub order_id: OrderId,
+    pub book_id: BookId,
     pub price: u32,
-    pub quantity: u32,
+    pub quantity: u64,
     pub is_bid: bool,
 }
 
+/// The orders run_matching_test generates, and how it reports on them
+/// The same configuration generates the same orders, so two runs differ only in their timings.
+#[derive(Debug, Clone)]
+pub struct MatchingTestConfig {
+    pub order_count: usize,
+    pub book_count: u32,                  // Orders are spread evenly over books 0 to book_count - 1
+    pub price_range: RangeInclusive<u32>, // Prices are drawn uniformly from this range
+    pub seed: u64,
+    pub verbose: bool, // Logs every order and settlement at debug level, which adds to the measured time
+}
+
+impl Default for MatchingTestConfig {
+    fn default() -> Self {
+        Self {
+            order_count: 1000,
+            book_count: 1,
+            price_range: 90..=110,
+            seed: 0,
+            verbose: false,
+        }
+    }
+}
+
+/// Latency percentiles of a set of orders, read from an HDR histogram with 3 significant digits
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyPercentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    pub(crate) fn of(histogram: &Histogram<u64>) -> Self {
+        let at = |quantile| Duration::from_nanos(histogram.value_at_quantile(quantile));
+        Self { p50: at(0.5), p90: at(0.9), p99: at(0.99), p999: at(0.999), max: Duration::from_nanos(histogram.max()) }
+    }
+}
+
+/// The orders of one kind of operation: how many there were, and their latencies
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OperationStats {
+    pub count: usize,
+    pub avg_latency: Duration,
+    pub latency: LatencyPercentiles,
+}
+
+impl OperationStats {
+    fn of(histogram: &Histogram<u64>) -> Self {
+        Self {
+            count: histogram.len() as usize,
+            avg_latency: Duration::from_nanos(histogram.mean() as u64),
+            latency: LatencyPercentiles::of(histogram),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct TestStats {
     pub total_orders: usize,
@@ -25,112 +102,601 @@ pub struct TestStats {
     pub total_time: Duration,
     pub avg_latency: Duration,
     pub throughput: f64,
+    pub matched_volume: u64,        // Quantity traded over every fill
+    pub latency: LatencyPercentiles, // Of every order
+    pub adds: OperationStats,       // Orders that rested without trading
+    pub matches: OperationStats,    // Orders that traded, resting what was left of them
+}
+
+impl fmt::Display for TestStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percentiles = |f: &mut fmt::Formatter<'_>, latency: &LatencyPercentiles| {
+            writeln!(
+                f,
+                "  p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+                latency.p50, latency.p90, latency.p99, latency.p999, latency.max
+            )
+        };
+        writeln!(f, "PERFORMANCE STATISTICS")?;
+        writeln!(f, "=====================")?;
+        writeln!(f, "Total Orders Processed: {}", self.total_matches + self.total_orders)?;
+        writeln!(f, "New Orders: {}", self.total_orders)?;
+        writeln!(f, "Matches: {}", self.total_matches)?;
+        writeln!(f, "Matched Volume: {}", self.matched_volume)?;
+        writeln!(f, "Total Time: {:?}", self.total_time)?;
+        writeln!(f, "Throughput: {:.2} orders/second", self.throughput)?;
+        writeln!(f, "Average Latency: {:?}", self.avg_latency)?;
+        percentiles(f, &self.latency)?;
+        for (name, operation) in [("Adds", &self.adds), ("Matches", &self.matches)] {
+            writeln!(f, "{}: {} orders, average {:?}", name, operation.count, operation.avg_latency)?;
+            percentiles(f, &operation.latency)?;
+        }
+        Ok(())
+    }
 }
 
-pub fn run_matching_test(order_count: usize) {
+/// Matches the orders `config` generates on a fresh engine and measures each one
+/// Throughput counts both the orders and their fills over the whole run.
+pub fn run_matching_test(config: &MatchingTestConfig) -> TestStats {
     let start_time = Instant::now();
-    let mut total_matches = 0;
-    let mut latencies = Vec::new();
+    let orders = generate_orders(config);
+    let outcomes = process_orders(&orders, config.book_count, config.verbose);
+    let total_time = start_time.elapsed();
+
+    // Latencies are recorded in nanoseconds, up to an hour
+    let mut all = Histogram::<u64>::new_with_bounds(1, 3_600_000_000_000, 3).unwrap();
+    let (mut adds, mut matches) = (all.clone(), all.clone());
+    for outcome in &outcomes {
+        let nanos = outcome.latency.as_nanos().max(1) as u64;
+        all.saturating_record(nanos);
+        if outcome.fills == 0 { &mut adds } else { &mut matches }.saturating_record(nanos);
+    }
+    let total_matches = outcomes.iter().map(|outcome| outcome.fills).sum::<usize>();
+    TestStats {
+        total_orders: orders.len(),
+        total_matches,
+        total_time,
+        avg_latency: Duration::from_nanos(all.mean() as u64),
+        throughput: (total_matches + orders.len()) as f64 / total_time.as_secs_f64(),
+        matched_volume: outcomes.iter().map(|outcome| outcome.volume).sum(),
+        latency: LatencyPercentiles::of(&all),
+        adds: OperationStats::of(&adds),
+        matches: OperationStats::of(&matches),
+    }
+}
+
+/// Generates `config.order_count` random orders from `config.seed`
+fn generate_orders(config: &MatchingTestConfig) -> Vec<TestOrder> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    (0..config.order_count)
+        .map(|i| TestOrder {
+            order_id: OrderId(i as u64),
+            book_id: BookId(i as u32 % config.book_count.max(1)),
+            price: rng.gen_range(config.price_range.clone()),
+            quantity: rng.gen_range(1..=100),
+            is_bid: rng.gen_bool(0.5),
+        })
+        .collect()
+}
+
+/// Generates `order_count` orders in one book, for the benchmarks that need no other settings
+fn generate_default_orders(order_count: usize) -> Vec<TestOrder> {
+    generate_orders(&MatchingTestConfig { order_count, ..MatchingTestConfig::default() })
+}
+
+/// How one order went: its latency, and the fills and quantity it traded
+struct OrderOutcome {
+    latency: Duration,
+    fills: usize,
+    volume: u64,
+}
+
+/// Matches the orders on a fresh engine with `book_count` settling books and measures each one
+/// With `verbose`, each order and the settlements of its fills are logged at debug level.
+fn process_orders(orders: &[TestOrder], book_count: u32, verbose: bool) -> Vec<OrderOutcome> {
+    let mut outcomes = Vec::with_capacity(orders.len());
 
     // 1. Setup
-    let mut engine = MatchingEngine::new();
+    let mut engine = MatchingEngine::auto_creating();
     
     // Setup market config
-    let market_config = MarketConfig {
-        base_token: [1; 20],      // Example USDC
-        security_token: [2; 20],   // Example ETH
-        fee_recipient: [3; 20],
-        pool: [4; 20],
-        signature_type: 1,
+    let market_config = MarketConfig::builder()
+        .base_token([1; 20])      // Example USDC
+        .security_token([2; 20])   // Example ETH
+        .fee_recipient([3; 20])
+        .pool([4; 20])
+        .signature_type(1)
+        .build();
+    for book_id in 0..book_count.max(1) {
+        engine.market_manager.add_market(BookId(book_id), market_config.clone(), false).unwrap();
+    }
+
+    let mut fills = Vec::new();
+    for (i, order) in orders.iter().enumerate() {
+        if verbose {
+            tracing::debug!(
+                order_id = order.order_id.0,
+                side = if order.is_bid { "BUY" } else { "SELL" },
+                qty = order.quantity,
+                price = order.price,
+                "Order"
+            );
+        }
+
+        let taker = Order::new(
+            Qty(order.quantity),
+            LevelId(0),
+            order.book_id,
+            Some([(i % 251) as u8; 20]),
+            Some(i as u64),
+            Some(u64::MAX),
+            [0; 65],
+        );
+        let order_start = Instant::now();
+        engine.match_limit_order_into(order.order_id, taker, order.price, order.is_bid, &mut fills).unwrap();
+        let latency = order_start.elapsed();
+        let volume = fills.iter().map(|fill| fill.exec_qty.value()).sum();
+        outcomes.push(OrderOutcome { latency, fills: fills.len(), volume });
+
+        if verbose && !fills.is_empty() {
+            let translated = collect_settlements(&fills, &engine.settlements);
+            for failure in &translated.failures {
+                tracing::debug!(trade_id = failure.trade_id, error = ?failure.error, "Trade not settled");
+            }
+
+            for TrackedSettlement { trade_id, order: settlement, .. } in translated.settlements {
+                tracing::debug!(
+                    trade_id,
+                    maker = %hex::encode(settlement.maker),
+                    taker = %hex::encode(settlement.taker),
+                    maker_amount = %settlement.maker_amount,
+                    taker_amount = %settlement.taker_amount,
+                    maker_is_buyer = settlement.maker_is_buyer,
+                    "Settlement"
+                );
+            }
+        }
+    }
+    outcomes
+}
+
+/// Formats every record it is given and throws the text away, so logging costs what it would
+/// with a real logger short of the I/O
+struct DiscardLogger;
+
+impl log::Log for DiscardLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = write!(std::io::sink(), "{}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static DISCARD_LOGGER: DiscardLogger = DiscardLogger;
+
+/// Matches and translates the same `order_count` orders with logging off and with every level
+/// on, and reports the average latency of each
+/// Off is how the engine runs by default: the events of the matching hot path then cost a
+/// level check each, where the println! calls they replaced always formatted and wrote.
+pub fn run_logging_comparison(order_count: usize) {
+    // Another logger already installed is used as is
+    let _ = log::set_logger(&DISCARD_LOGGER);
+    let previous = log::max_level();
+    let orders = generate_default_orders(order_count);
+    let average = |level| {
+        log::set_max_level(level);
+        let outcomes = process_orders(&orders, 1, true);
+        outcomes.iter().map(|outcome| outcome.latency).sum::<Duration>() / outcomes.len().max(1) as u32
     };
-    engine.market_manager.add_market(BookId(0), market_config);
+    let off = average(log::LevelFilter::Off);
+    let on = average(log::LevelFilter::Trace);
+    log::set_max_level(previous);
 
-    println!("\nORDER MATCHING TEST");
-    println!("===================");
-    println!("Generating and processing {} orders...\n", order_count);
-    
+    println!("\nLOGGING OVERHEAD");
+    println!("================");
+    println!("Orders: {}", order_count);
+    println!("Average latency, logging off: {:?}", off);
+    println!("Average latency, logging at trace: {:?}", on);
+    println!("Overhead: {:.1}%", (on.as_secs_f64() / off.as_secs_f64().max(f64::EPSILON) - 1.0) * 100.0);
+}
+
+/// Compares OidMap lookups with the Vec indexed by OrderId that it replaced,
+/// for dense IDs as the engine assigns them and for sparse 64-bit IDs the Vec can't hold.
+pub fn run_oid_map_benchmark(order_count: usize) {
+    let order = Order::new(Qty(10), LevelId(0), BookId(0), None, None, None, None);
     let mut rng = rand::thread_rng();
-    
-    for i in 0..order_count {
-        let order = TestOrder {
-            order_id: OrderId(i as u32),
-            price: rng.gen_range(90..=110),
-            quantity: rng.gen_range(1..=100),
-            is_bid: rng.gen_bool(0.5),
-        };
+    let mut dense: Vec<OrderId> = (0..order_count as u64).map(OrderId).collect();
+    let mut sparse: Vec<OrderId> = (0..order_count).map(|_| OrderId(rng.gen())).collect();
 
-        println!("Order {}", order.order_id.0);
-        println!("---------------");
-        println!("Type: {}", if order.is_bid { "BUY" } else { "SELL" });
-        println!("Quantity: {}", order.quantity);
-        println!("Price: {}", order.price);
+    let indexed: Vec<Option<Order>> = vec![Some(order.clone()); order_count];
+    let mut dense_map = OidMap::new();
+    for &order_id in &dense {
+        dense_map.insert(order_id, order.clone());
+    }
+    let mut sparse_map = OidMap::new();
+    for &order_id in &sparse {
+        sparse_map.insert(order_id, order.clone());
+    }
 
-        let order_start = Instant::now();
-        let (_, matches) = engine.match_order(
-            order.order_id,
+    // Look the orders up in random order, so neither layout gets sequential access for free
+    dense.shuffle(&mut rng);
+    sparse.shuffle(&mut rng);
+    let time = |order_ids: &[OrderId], lookup: &dyn Fn(OrderId) -> Option<Qty>| -> Duration {
+        let start = Instant::now();
+        let mut total = 0;
+        for &order_id in order_ids {
+            total += lookup(order_id).map_or(0, |qty| qty.value());
+        }
+        assert_eq!(total, 10 * order_ids.len() as u64);
+        start.elapsed() / order_ids.len() as u32
+    };
+    let indexed_latency = time(&dense, &|order_id| {
+        indexed.get(order_id.0 as usize)?.as_ref().map(|order| order.qty())
+    });
+    let dense_latency = time(&dense, &|order_id| dense_map.get(order_id).map(|order| order.qty()));
+    let sparse_latency = time(&sparse, &|order_id| sparse_map.get(order_id).map(|order| order.qty()));
+
+    println!("\nORDER LOOKUP LATENCY");
+    println!("====================");
+    println!("Orders: {}", order_count);
+    println!("Vec indexed by OrderId: {:?}", indexed_latency);
+    println!("OidMap, sequential IDs: {:?}", dense_latency);
+    println!("OidMap, random 64-bit IDs: {:?}", sparse_latency);
+}
+
+/// Rests `order_count` orders on one book and reports the latency of add_order and the memory
+/// the OidMap holds for them. Orders spread over 200 levels a side and carry full signatures.
+pub fn run_add_order_benchmark(order_count: usize) {
+    let mut manager = OrderBookManager::new();
+    manager.create_book(BookId(0)).unwrap();
+    let mut rng = rand::thread_rng();
+    let start = Instant::now();
+    for i in 0..order_count as u64 {
+        let is_bid = rng.gen_bool(0.5);
+        let price = if is_bid { rng.gen_range(800..1000) } else { rng.gen_range(1001..1201) };
+        manager.add_order(
+            OrderId(i),
             BookId(0),
-            Qty(order.quantity),
-            order.price,
-            order.is_bid,
-            Some([rng.gen::<u8>(); 20]),
-            Some(i as u64),
+            Qty(rng.gen_range(1..=100)),
+            price,
+            is_bid,
+            Some([1; 20]),
+            Some(i),
             Some(u64::MAX),
-            Some([0; 65]),
-        );
-        latencies.push(order_start.elapsed());
-
-        if !matches.is_empty() {
-            total_matches += matches.len();
-            println!("\nMATCHES FOUND: {}", matches.len());
-            
-            let market_config = engine.market_manager.get_config(BookId(0)).unwrap();
-            let settlements = translate_matches(matches, market_config);
-            
-            for settlement in settlements {
-                println!("\nSETTLEMENT DETAILS");
-                println!("------------------");
-                println!("Maker: 0x{}", hex::encode(settlement.maker));
-                println!("Taker: 0x{}", hex::encode(settlement.taker));
-                println!("Maker Token: {} {}", 
-                    settlement.maker_amount,
-                    if settlement.maker_is_buyer { "USDC" } else { "ETH" });
-                println!("Taker Token: {} {}", 
-                    settlement.taker_amount,
-                    if settlement.maker_is_buyer { "ETH" } else { "USDC" });
-                println!("Price: {} USDC/ETH", 
-                    if settlement.maker_is_buyer {
-                        settlement.maker_amount / settlement.taker_amount
-                    } else {
-                        settlement.taker_amount / settlement.maker_amount
-                    });
+            [2; 65],
+        ).unwrap();
+    }
+    let avg_latency = start.elapsed() / order_count.max(1) as u32;
+    let bytes = manager.oid_map.allocated_bytes();
+
+    println!("\nADD ORDER LATENCY AND MEMORY");
+    println!("============================");
+    println!("Orders: {}", order_count);
+    println!("Average add_order latency: {:?}", avg_latency);
+    println!("Resting order size: {} bytes", std::mem::size_of::<RestingOrder>());
+    println!("OidMap memory: {:.1} MiB ({} bytes per order)", bytes as f64 / (1 << 20) as f64, bytes / order_count.max(1));
+}
+
+/// Rests `order_count` signed asks over 10 levels, walks every level's queue, then matches
+/// bids against the book until it is empty. Reports the walk time and matching throughput.
+pub fn run_deep_book_benchmark(order_count: usize) {
+    let mut engine = MatchingEngine::auto_creating();
+    for i in 0..order_count as u64 {
+        engine.orderbook_manager.add_order(
+            OrderId(i),
+            BookId(0),
+            Qty(10),
+            1001 + (i % 10) as u32,
+            false,
+            Some([1; 20]),
+            Some(i),
+            Some(u64::MAX),
+            [2; 65],
+        ).unwrap();
+    }
+
+    let manager = &engine.orderbook_manager;
+    let book = manager.book(BookId(0)).unwrap();
+    let start = Instant::now();
+    let mut resting = 0;
+    for level in book.iter_asks() {
+        resting += manager.oid_map.pool().queue(level).map(|(_, order)| order.qty().value()).sum::<u64>();
+    }
+    let walk_time = start.elapsed();
+    assert_eq!(resting, 10 * order_count as u64);
+
+    let start = Instant::now();
+    let mut fills = 0;
+    let mut order_id = order_count as u64;
+    while engine.orderbook_manager.get_best_ask(BookId(0)).is_some() {
+        let (_, matches) = engine
+            .match_order(OrderId(order_id), BookId(0), Qty(100), 1010, true, Some([3; 20]), Some(order_id), Some(u64::MAX), [4; 65])
+            .unwrap();
+        fills += matches.len();
+        order_id += 1;
+    }
+    let match_time = start.elapsed();
+    assert_eq!(fills, order_count);
+
+    println!("\nDEEP BOOK MATCHING");
+    println!("==================");
+    println!("Resting orders: {}", order_count);
+    println!("Queue walk: {:?} ({:?} per order)", walk_time, walk_time / order_count.max(1) as u32);
+    println!("Matching: {:?} for {} fills", match_time, fills);
+    println!("Throughput: {:.2} fills/second", fills as f64 / match_time.as_secs_f64());
+}
+
+/// Matches `orders_per_book` random orders in each of 1, 2, and 4 books, every book in its own
+/// shard of a ShardedEngine on a 4-thread runtime, with one client per book sending its orders
+/// one at a time. Reports each run's throughput and how it scales over the single book's; with
+/// a core per shard, books share nothing and it should come close to the number of books.
+pub fn run_sharded_benchmark(orders_per_book: usize) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    let orders = Arc::new(generate_default_orders(orders_per_book));
+
+    println!("\nSHARDED MATCHING");
+    println!("================");
+    println!("Orders per book: {}", orders_per_book);
+    let mut single = None;
+    for books in [1, 2, 4] {
+        let elapsed = runtime.block_on(async {
+            let engine = Arc::new(ShardedEngine::new(books));
+            let start = Instant::now();
+            let clients: Vec<_> = (0..books as u32)
+                .map(|book_id| {
+                    let (engine, orders) = (engine.clone(), orders.clone());
+                    tokio::spawn(async move {
+                        for (i, order) in orders.iter().enumerate() {
+                            let order_id = engine.next_order_id(BookId(book_id)).await.unwrap();
+                            let taker = Order::new(
+                                Qty(order.quantity),
+                                LevelId(0),
+                                BookId(book_id),
+                                Some([i as u8; 20]),
+                                Some(i as u64),
+                                Some(u64::MAX),
+                                [0; 65],
+                            );
+                            engine.match_limit_order(order_id, taker, order.price, order.is_bid).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for client in clients {
+                client.await.unwrap();
+            }
+            start.elapsed()
+        });
+        let throughput = (books * orders_per_book) as f64 / elapsed.as_secs_f64();
+        let single = *single.get_or_insert(throughput);
+        println!("{} books: {:?}, {:.2} orders/second, {:.2}x one book", books, elapsed, throughput, throughput / single);
+    }
+}
+
+/// Matches `order_count` orders through a matching worker twice: once unsigned, once signed and
+/// verified on the blocking pool on their way in, as the server does. Reports how long the worker
+/// spends matching each order in both runs, which verification should leave unchanged, and the
+/// verification time it keeps off the worker.
+pub fn run_verification_benchmark(order_count: usize) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    let registry = BookRegistry::new();
+    registry.register_book("ETH-USD".to_string()).unwrap();
+    let intake = Arc::new(OrderIntake::new().with_registry(Arc::new(registry)));
+
+    // Signing is the client's work, so it is done before either run starts
+    let key = SigningKey::from_slice(&[7; 32]).unwrap();
+    let trader = address_of(key.verifying_key());
+    let submissions: Vec<OrderSubmission> = generate_default_orders(order_count)
+        .iter()
+        .enumerate()
+        .map(|(i, order)| {
+            let price = if order.is_bid { order.price as i32 } else { -(order.price as i32) };
+            let digest = Eip712Domain::default().hash_order(&Eip712Order {
+                book: "ETH-USD",
+                trader,
+                price,
+                quantity: order.quantity,
+                nonce: i as u64,
+                expiry: NO_EXPIRY,
+            });
+            let (signature, recovery_id) = key.sign_prehash_recoverable(&digest).unwrap();
+            let mut signature = signature.to_bytes().to_vec();
+            signature.push(recovery_id.to_byte() + 27);
+            OrderSubmission {
+                book_id: "ETH-USD".to_string(),
+                price,
+                side: None,
+                quantity: order.quantity,
+                trader: format!("0x{}", hex::encode(trader)),
+                nonce: i as u64,
+                expiry: None,
+                signature: format!("0x{}", hex::encode(signature)),
             }
-        } else {
-            println!("No matches found");
+        })
+        .collect();
+
+    let (unsigned, signed, verifying) = runtime.block_on(async move {
+        let commands = CommandQueue::spawn(Arc::new(tokio::sync::Mutex::new(MatchingEngine::auto_creating())), usize::MAX);
+        let mut unsigned = Vec::with_capacity(order_count);
+        for (i, submission) in submissions.iter().enumerate() {
+            let price = Price(submission.price);
+            let order = Order::new(Qty(submission.quantity), LevelId(0), BookId(0), Some(trader), Some(i as u64), None, None);
+            unsigned.push(commands.run(Lane::New, move |engine| time_match(engine, order, price)).await.unwrap());
+        }
+
+        // Every order is verified at once on the blocking pool; each goes to the worker in turn
+        let commands = CommandQueue::spawn(Arc::new(tokio::sync::Mutex::new(MatchingEngine::auto_creating())), usize::MAX);
+        let verifications: Vec<_> = submissions
+            .into_iter()
+            .map(|submission| {
+                let intake = intake.clone();
+                tokio::task::spawn_blocking(move || {
+                    let started = Instant::now();
+                    let order = intake.process_submission(submission).unwrap();
+                    (order, started.elapsed())
+                })
+            })
+            .collect();
+        let (mut signed, mut verifying) = (Vec::with_capacity(order_count), Duration::ZERO);
+        for verification in verifications {
+            let (order, elapsed): (VerifiedOrder, Duration) = verification.await.unwrap();
+            verifying += elapsed;
+            let order = order.into_order();
+            let price = order.price();
+            signed.push(commands.run(Lane::New, move |engine| time_match(engine, order, price)).await.unwrap());
         }
-        println!("");
+        (unsigned, signed, verifying)
+    });
+
+    let average = |latencies: &[Duration]| latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+    println!("\nVERIFICATION OFF THE MATCHING WORKER");
+    println!("====================================");
+    println!("Orders: {}", order_count);
+    println!("Average match latency, unsigned: {:?}", average(&unsigned));
+    println!("Average match latency, verified: {:?}", average(&signed));
+    println!("Average verification, on the blocking pool: {:?}", verifying / order_count.max(1) as u32);
+}
+
+/// Counts the allocations made on each thread, when installed as the global allocator
+/// This module's tests install it; anywhere else allocations() stays at zero.
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+impl CountingAllocator {
+    /// Gets the number of allocations and reallocations the current thread has made
+    pub fn allocations() -> u64 {
+        ALLOCATIONS.with(Cell::get)
     }
 
-    // Calculate and print performance stats
-    let total_time = start_time.elapsed();
-    let avg_latency = latencies.iter().sum::<Duration>() / latencies.len() as u32;
-    let throughput = (total_matches + order_count) as f64 / total_time.as_secs_f64();
+    fn count() {
+        // A thread being torn down has no counter left, and its allocations aren't measured
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+    }
+}
 
-    println!("\nPERFORMANCE STATISTICS");
-    println!("=====================");
-    println!("Total Orders Processed: {}", total_matches + order_count);
-    println!("New Orders: {}", order_count);
-    println!("Matches: {}", total_matches);
-    println!("Total Time: {:?}", total_time);
-    println!("Average Latency: {:?}", avg_latency);
-    println!("Throughput: {:.2} orders/second", throughput);
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::count();
+        System.realloc(ptr, layout, new_size)
+    }
 }
 
-#[cfg(test)]
+/// Matches the same `order_count` orders twice, once through match_limit_order, which returns
+/// each order's fills in a new Vec, and once through match_limit_order_into with one buffer
+/// reused throughout. Reports the allocations per order of each, counted by CountingAllocator,
+/// and their average and p99 latency.
+pub fn run_match_buffer_benchmark(order_count: usize) {
+    let orders = generate_default_orders(order_count);
+    let taker = |i: usize, order: &TestOrder| {
+        Order::new(Qty(order.quantity), LevelId(0), BookId(0), Some([i as u8; 20]), Some(i as u64), Some(u64::MAX), [0; 65])
+    };
+    let run = |matching: &mut dyn FnMut(&mut MatchingEngine, usize, &TestOrder)| {
+        let mut engine = MatchingEngine::auto_creating();
+        let (mut allocations, mut latencies) = (0, Vec::with_capacity(order_count));
+        for (i, order) in orders.iter().enumerate() {
+            let (allocated, started) = (CountingAllocator::allocations(), Instant::now());
+            matching(&mut engine, i, order);
+            latencies.push(started.elapsed());
+            allocations += CountingAllocator::allocations() - allocated;
+        }
+        latencies.sort_unstable();
+        let p99 = latencies.get(latencies.len() * 99 / 100).copied().unwrap_or_default();
+        let average = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+        (allocations as f64 / order_count.max(1) as f64, average, p99)
+    };
+    let fresh = run(&mut |engine, i, order| {
+        engine.match_limit_order(order.order_id, taker(i, order), order.price, order.is_bid).unwrap();
+    });
+    let mut fills = Vec::new();
+    let reused = run(&mut |engine, i, order| {
+        engine.match_limit_order_into(order.order_id, taker(i, order), order.price, order.is_bid, &mut fills).unwrap();
+    });
+
+    println!("\nREUSED MATCH BUFFER");
+    println!("===================");
+    println!("Orders: {}", order_count);
+    for (name, (allocations, average, p99)) in [("New Vec per order", fresh), ("Reused buffer", reused)] {
+        println!("{}: {:.2} allocations per order, average {:?}, p99 {:?}", name, allocations, average, p99);
+    }
+}
+
+/// Matches an order on the worker and times only the match
+fn time_match(engine: &mut MatchingEngine, order: Order, price: Price) -> Duration {
+    let order_id = engine.next_order_id();
+    let started = Instant::now();
+    engine.match_limit_order(order_id, order, price.absolute() as u32, price.is_bid()).unwrap();
+    started.elapsed()
+}
+
+// Each of these prints a report and takes a while, so they only run with the perf-tests feature
+#[cfg(all(test, feature = "perf-tests"))]
 mod tests {
     use super::*;
 
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
     #[test]
     fn test_matching_and_settlement() {
-        run_matching_test(1000);
+        let config = MatchingTestConfig { book_count: 2, seed: 42, ..MatchingTestConfig::default() };
+        let stats = run_matching_test(&config);
+        println!("\n{}", stats);
+        assert_eq!((stats.total_orders, stats.adds.count + stats.matches.count), (1000, 1000));
+        assert!(stats.latency.p50 <= stats.latency.p99 && stats.latency.p99 <= stats.latency.max);
+
+        // The same seed generates the same orders, so they trade the same way again
+        let again = run_matching_test(&config);
+        assert_eq!((again.total_matches, again.matched_volume), (stats.total_matches, stats.matched_volume));
+    }
+
+    #[test]
+    fn test_logging_overhead() {
+        run_logging_comparison(2_000);
+    }
+
+    #[test]
+    fn test_oid_map_lookups() {
+        run_oid_map_benchmark(100_000);
+    }
+
+    #[test]
+    fn test_add_order_latency() {
+        run_add_order_benchmark(100_000);
+    }
+
+    #[test]
+    fn test_deep_book_matching() {
+        run_deep_book_benchmark(1_000_000);
+    }
+
+    #[test]
+    fn test_verification_off_the_worker() {
+        run_verification_benchmark(200);
+    }
+
+    #[test]
+    fn test_match_buffer_allocations() {
+        run_match_buffer_benchmark(100_000);
+    }
+
+    #[test]
+    fn test_sharded_scaling() {
+        run_sharded_benchmark(20_000);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,491 @@
+// trade_history.rs
+
+use crate::{
+    events::{CancelReason, EventSink, OrderBookEvent},
+    matching::MatchingEngine,
+    order::OrderId,
+    quantity::Qty,
+    trade_tape::Trade,
+    utils::{BookId, Clock},
+};
+use rusqlite::{params, Connection, Row};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+
+/// Most rows written in one transaction
+const MAX_BATCH: usize = 1_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS trades (
+        trade_id INTEGER PRIMARY KEY,
+        book_id INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        price INTEGER NOT NULL,
+        qty INTEGER NOT NULL,
+        aggressor_is_bid INTEGER NOT NULL,
+        maker_order_id INTEGER NOT NULL,
+        taker_order_id INTEGER NOT NULL,
+        maker_trader BLOB,
+        taker_trader BLOB
+    );
+    CREATE INDEX IF NOT EXISTS trades_by_book ON trades (book_id, timestamp);
+    CREATE INDEX IF NOT EXISTS trades_by_maker ON trades (maker_trader, timestamp);
+    CREATE INDEX IF NOT EXISTS trades_by_taker ON trades (taker_trader, timestamp);
+    CREATE TABLE IF NOT EXISTS closed_orders (
+        order_id INTEGER PRIMARY KEY,
+        book_id INTEGER NOT NULL,
+        trader BLOB,
+        status TEXT NOT NULL,
+        closed_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS closed_orders_by_trader ON closed_orders (trader, closed_at);
+";
+
+const TRADE_COLUMNS: &str =
+    "book_id, trade_id, timestamp, price, qty, aggressor_is_bid, maker_order_id, taker_order_id, maker_trader, taker_trader";
+
+/// A trade as kept in the history, with the traders on both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredTrade {
+    pub book_id: BookId,
+    pub trade: Trade,
+    pub maker_trader: Option<[u8; 20]>,
+    pub taker_trader: Option<[u8; 20]>,
+}
+
+/// How an order that rested on a book left it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosedStatus {
+    Filled,
+    Cancelled,
+    Expired,
+    BookClosed,
+    Replaced, // Its replacement goes on under a new order ID
+}
+
+impl ClosedStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClosedStatus::Filled => "filled",
+            ClosedStatus::Cancelled => "cancelled",
+            ClosedStatus::Expired => "expired",
+            ClosedStatus::BookClosed => "book_closed",
+            ClosedStatus::Replaced => "replaced",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        [ClosedStatus::Filled, ClosedStatus::Cancelled, ClosedStatus::Expired, ClosedStatus::BookClosed, ClosedStatus::Replaced]
+            .into_iter()
+            .find(|status| status.as_str() == text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosedOrder {
+    pub order_id: OrderId,
+    pub book_id: BookId,
+    pub trader: Option<[u8; 20]>,
+    pub status: ClosedStatus,
+    pub closed_at: u64, // Nanoseconds since the Unix epoch
+}
+
+/// Which rows a query returns: those from `from` to `to`, inclusive, oldest first and at most `limit`
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryRange {
+    pub from: Option<u64>, // Nanoseconds since the Unix epoch
+    pub to: Option<u64>,
+    pub limit: usize,
+}
+
+impl HistoryRange {
+    fn bounds(&self) -> (i64, i64, i64) {
+        let clamp = |value: u64| value.min(i64::MAX as u64) as i64;
+        (clamp(self.from.unwrap_or(0)), clamp(self.to.unwrap_or(u64::MAX)), clamp(self.limit as u64))
+    }
+}
+
+enum HistoryMessage {
+    Trade(StoredTrade),
+    Closed(ClosedOrder),
+    Flush(mpsc::Sender<()>), // Answered once every row before it is committed
+}
+
+/// Trades and finished orders, kept in a SQLite database so their history outlives restarts
+/// Rows come from the engine's event stream through a HistorySink and are inserted in batches
+/// on a writer thread of their own; the matching path only queues them.
+pub struct TradeHistory {
+    sender: mpsc::Sender<HistoryMessage>,
+    reader: Mutex<Connection>,
+}
+
+impl TradeHistory {
+    /// Opens the database at `path`, creating it and its tables if needed, and starts its writer
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let path = path.as_ref();
+        let writer = Connection::open(path)?;
+        // Readers see committed batches while the writer appends the next
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.execute_batch(SCHEMA)?;
+        let reader = Connection::open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("trade-history".to_string())
+            .spawn(move || write_history(writer, receiver))
+            .expect("failed to spawn the trade history writer");
+        Ok(Self { sender, reader: Mutex::new(reader) })
+    }
+
+    /// Creates the sink feeding the history from `engine`'s event stream
+    /// Knows the traders of the orders already resting, so their closing is attributed too.
+    pub fn sink(&self, engine: &MatchingEngine) -> HistorySink {
+        let oid_map = &engine.orderbook_manager.oid_map;
+        let traders = oid_map.iter().filter_map(|(order_id, order)| Some((order_id, order.trader()?))).collect();
+        HistorySink { sender: self.sender.clone(), traders, clock: Clock::System }
+    }
+
+    /// Returns once every row queued so far is committed
+    pub fn flush(&self) {
+        let (done, committed) = mpsc::channel();
+        if self.sender.send(HistoryMessage::Flush(done)).is_ok() {
+            let _ = committed.recv();
+        }
+    }
+
+    /// Gets the ID of the last trade kept, or None if there is none
+    pub fn last_trade_id(&self) -> rusqlite::Result<Option<u64>> {
+        let reader = self.reader();
+        reader.query_row("SELECT MAX(trade_id) FROM trades", [], |row| row.get::<_, Option<i64>>(0)).map(|id| id.map(|id| id as u64))
+    }
+
+    /// Gets the trades of a book in `range`
+    pub fn trades(&self, book_id: BookId, range: HistoryRange) -> rusqlite::Result<Vec<StoredTrade>> {
+        self.trades_after(book_id, None, range)
+    }
+
+    /// Gets the trades of a book in `range` with IDs after `after`, oldest first, for reading
+    /// it a page at a time
+    pub fn trades_after(&self, book_id: BookId, after: Option<u64>, range: HistoryRange) -> rusqlite::Result<Vec<StoredTrade>> {
+        let (from, to, limit) = range.bounds();
+        let after = after.map_or(-1, |after| after.min(i64::MAX as u64) as i64);
+        let sql = format!(
+            "SELECT {} FROM trades WHERE book_id = ?1 AND timestamp BETWEEN ?2 AND ?3 AND trade_id > ?5 \
+             ORDER BY trade_id LIMIT ?4",
+            TRADE_COLUMNS
+        );
+        let reader = self.reader();
+        let mut statement = reader.prepare_cached(&sql)?;
+        let trades = statement.query_map(params![book_id.value(), from, to, limit, after], stored_trade)?;
+        trades.collect()
+    }
+
+    /// Gets up to `limit` trades of every book with IDs after `after`, oldest first, for reading
+    /// the whole history a page at a time
+    pub fn all_trades_after(&self, after: Option<u64>, limit: usize) -> rusqlite::Result<Vec<StoredTrade>> {
+        let after = after.map_or(-1, |after| after.min(i64::MAX as u64) as i64);
+        let sql = format!("SELECT {} FROM trades WHERE trade_id > ?1 ORDER BY trade_id LIMIT ?2", TRADE_COLUMNS);
+        let reader = self.reader();
+        let mut statement = reader.prepare_cached(&sql)?;
+        let trades = statement.query_map(params![after, limit.min(i64::MAX as usize) as i64], stored_trade)?;
+        trades.collect()
+    }
+
+    /// Gets the trades in `range` a trader was on either side of
+    pub fn trader_fills(&self, trader: [u8; 20], range: HistoryRange) -> rusqlite::Result<Vec<StoredTrade>> {
+        let (from, to, limit) = range.bounds();
+        let sql = format!(
+            "SELECT {} FROM trades WHERE (maker_trader = ?1 OR taker_trader = ?1) AND timestamp BETWEEN ?2 AND ?3 \
+             ORDER BY trade_id LIMIT ?4",
+            TRADE_COLUMNS
+        );
+        let reader = self.reader();
+        let mut statement = reader.prepare_cached(&sql)?;
+        let trades = statement.query_map(params![&trader[..], from, to, limit], stored_trade)?;
+        trades.collect()
+    }
+
+    /// Gets the orders of a trader that closed in `range`
+    pub fn closed_orders(&self, trader: [u8; 20], range: HistoryRange) -> rusqlite::Result<Vec<ClosedOrder>> {
+        let (from, to, limit) = range.bounds();
+        let reader = self.reader();
+        let mut statement = reader.prepare_cached(
+            "SELECT order_id, book_id, trader, status, closed_at FROM closed_orders \
+             WHERE trader = ?1 AND closed_at BETWEEN ?2 AND ?3 ORDER BY closed_at, order_id LIMIT ?4",
+        )?;
+        let orders = statement.query_map(params![&trader[..], from, to, limit], |row| {
+            let status: String = row.get(3)?;
+            Ok(ClosedOrder {
+                order_id: OrderId(row.get::<_, i64>(0)? as u64),
+                book_id: BookId(row.get(1)?),
+                trader: address(row, 2)?,
+                status: ClosedStatus::parse(&status).ok_or_else(|| {
+                    rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, format!("unknown status {:?}", status).into())
+                })?,
+                closed_at: row.get::<_, i64>(4)? as u64,
+            })
+        })?;
+        orders.collect()
+    }
+
+    fn reader(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.reader.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Turns the engine's events into history rows and queues them for the writer
+/// Installed with OrderBookManager::set_event_sink; queuing never blocks.
+pub struct HistorySink {
+    sender: mpsc::Sender<HistoryMessage>,
+    traders: HashMap<OrderId, [u8; 20]>, // Of the orders resting, for when they close
+    clock: Clock, // Stamps closings, which events carry no time for
+}
+
+impl EventSink for HistorySink {
+    fn on_event(&mut self, event: &OrderBookEvent) {
+        let (order_id, book_id, status) = match *event {
+            OrderBookEvent::OrderAdded { order_id, trader: Some(trader), .. } => {
+                self.traders.insert(order_id, trader);
+                return;
+            }
+            OrderBookEvent::Trade { book_id, trade, maker_trader, taker_trader, .. } => {
+                let _ = self.sender.send(HistoryMessage::Trade(StoredTrade { book_id, trade, maker_trader, taker_trader }));
+                return;
+            }
+            OrderBookEvent::OrderExecuted { order_id, book_id, remaining_qty: Qty(0), .. } => {
+                (order_id, book_id, ClosedStatus::Filled)
+            }
+            OrderBookEvent::OrderCancelled { order_id, book_id, remaining_qty: Qty(0), reason, .. } => {
+                let status = match reason {
+                    CancelReason::Requested => ClosedStatus::Cancelled,
+                    CancelReason::BookClosed => ClosedStatus::BookClosed,
+                };
+                (order_id, book_id, status)
+            }
+            OrderBookEvent::OrderExpired { order_id, book_id, .. } => (order_id, book_id, ClosedStatus::Expired),
+            OrderBookEvent::OrderReplaced { order_id, book_id, .. } => (order_id, book_id, ClosedStatus::Replaced),
+            _ => return,
+        };
+        let trader = self.traders.remove(&order_id);
+        let closed = ClosedOrder { order_id, book_id, trader, status, closed_at: self.clock.now() };
+        let _ = self.sender.send(HistoryMessage::Closed(closed));
+    }
+
+    fn flush(&mut self) {
+        let (done, committed) = mpsc::channel();
+        if self.sender.send(HistoryMessage::Flush(done)).is_ok() {
+            let _ = committed.recv();
+        }
+    }
+}
+
+/// Writes what is queued in batches, until every sender is gone
+fn write_history(mut connection: Connection, receiver: mpsc::Receiver<HistoryMessage>) {
+    while let Some(batch) = next_batch(&receiver, MAX_BATCH) {
+        if let Err(error) = write_batch(&mut connection, &batch) {
+            tracing::error!(%error, rows = batch.len(), "Failed to write trade history");
+        }
+        for message in batch {
+            if let HistoryMessage::Flush(done) = message {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Waits for a message, then takes whatever else is already queued, up to `max` in all
+fn next_batch(receiver: &mpsc::Receiver<HistoryMessage>, max: usize) -> Option<Vec<HistoryMessage>> {
+    let mut batch = vec![receiver.recv().ok()?];
+    while batch.len() < max {
+        match receiver.try_recv() {
+            Ok(message) => batch.push(message),
+            Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+/// Inserts a batch in one transaction; a trade already kept is left as it is
+fn write_batch(connection: &mut Connection, batch: &[HistoryMessage]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut insert_trade = transaction.prepare_cached(&format!(
+            "INSERT OR IGNORE INTO trades ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            TRADE_COLUMNS
+        ))?;
+        let mut insert_closed = transaction.prepare_cached(
+            "INSERT OR REPLACE INTO closed_orders (order_id, book_id, trader, status, closed_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for message in batch {
+            match message {
+                HistoryMessage::Trade(StoredTrade { book_id, trade, maker_trader, taker_trader }) => {
+                    insert_trade.execute(params![
+                        book_id.value(),
+                        trade.trade_id as i64,
+                        trade.timestamp as i64,
+                        trade.price,
+                        trade.qty.value() as i64,
+                        trade.aggressor_is_bid,
+                        trade.maker_order_id.0 as i64,
+                        trade.taker_order_id.0 as i64,
+                        maker_trader.as_ref().map(|trader| &trader[..]),
+                        taker_trader.as_ref().map(|trader| &trader[..]),
+                    ])?;
+                }
+                HistoryMessage::Closed(closed) => {
+                    insert_closed.execute(params![
+                        closed.order_id.0 as i64,
+                        closed.book_id.value(),
+                        closed.trader.as_ref().map(|trader| &trader[..]),
+                        closed.status.as_str(),
+                        closed.closed_at as i64,
+                    ])?;
+                }
+                HistoryMessage::Flush(_) => {}
+            }
+        }
+    }
+    transaction.commit()
+}
+
+fn stored_trade(row: &Row) -> rusqlite::Result<StoredTrade> {
+    Ok(StoredTrade {
+        book_id: BookId(row.get(0)?),
+        trade: Trade {
+            trade_id: row.get::<_, i64>(1)? as u64,
+            timestamp: row.get::<_, i64>(2)? as u64,
+            price: row.get(3)?,
+            qty: Qty(row.get::<_, i64>(4)? as u64),
+            aggressor_is_bid: row.get(5)?,
+            maker_order_id: OrderId(row.get::<_, i64>(6)? as u64),
+            taker_order_id: OrderId(row.get::<_, i64>(7)? as u64),
+        },
+        maker_trader: address(row, 8)?,
+        taker_trader: address(row, 9)?,
+    })
+}
+
+fn address(row: &Row, index: usize) -> rusqlite::Result<Option<[u8; 20]>> {
+    let bytes: Option<Vec<u8>> = row.get(index)?;
+    Ok(bytes.and_then(|bytes| bytes.try_into().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(trade_id: u64, timestamp: u64) -> StoredTrade {
+        StoredTrade {
+            book_id: BookId(1),
+            trade: Trade {
+                trade_id,
+                timestamp,
+                price: 100,
+                qty: Qty(5),
+                aggressor_is_bid: true,
+                maker_order_id: OrderId(trade_id * 10),
+                taker_order_id: OrderId(trade_id * 10 + 1),
+            },
+            maker_trader: Some([1; 20]),
+            taker_trader: Some([trade_id as u8; 20]),
+        }
+    }
+
+    fn range(from: Option<u64>, to: Option<u64>, limit: usize) -> HistoryRange {
+        HistoryRange { from, to, limit }
+    }
+
+    #[test]
+    fn test_writes_are_batched() {
+        let (sender, receiver) = mpsc::channel();
+        for trade_id in 1..=5 {
+            sender.send(HistoryMessage::Trade(trade(trade_id, trade_id))).unwrap();
+        }
+        // Whatever is queued goes in together, up to the batch size
+        assert_eq!(next_batch(&receiver, 3).map(|batch| batch.len()), Some(3));
+        assert_eq!(next_batch(&receiver, 3).map(|batch| batch.len()), Some(2));
+        drop(sender);
+        assert!(next_batch(&receiver, 3).is_none());
+
+        // A batch is one transaction, so a failing row leaves none of it behind
+        let dir = tempfile::tempdir().unwrap();
+        let mut connection = Connection::open(dir.path().join("history.db")).unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection.execute_batch("CREATE TRIGGER refuse_third BEFORE INSERT ON trades WHEN NEW.trade_id = 3 BEGIN SELECT RAISE(ABORT, 'refused'); END;").unwrap();
+        let batch: Vec<HistoryMessage> = (1..=3).map(|trade_id| HistoryMessage::Trade(trade(trade_id, trade_id))).collect();
+        assert!(write_batch(&mut connection, &batch).is_err());
+        let batch: Vec<HistoryMessage> = [1, 2, 4].map(|trade_id| HistoryMessage::Trade(trade(trade_id, trade_id))).into();
+        write_batch(&mut connection, &batch).unwrap();
+        let count: i64 = connection.query_row("SELECT COUNT(*) FROM trades", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_range_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = TradeHistory::open(dir.path().join("history.db")).unwrap();
+        assert_eq!(history.last_trade_id().unwrap(), None);
+        for trade_id in 1..=6 {
+            history.sender.send(HistoryMessage::Trade(trade(trade_id, trade_id * 100))).unwrap();
+        }
+        let mut other_book = trade(7, 700);
+        other_book.book_id = BookId(2);
+        history.sender.send(HistoryMessage::Trade(other_book)).unwrap();
+        history.flush();
+
+        let ids = |trades: Vec<StoredTrade>| trades.iter().map(|stored| stored.trade.trade_id).collect::<Vec<_>>();
+        assert_eq!(ids(history.trades(BookId(1), range(None, None, 100)).unwrap()), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(history.trades(BookId(1), range(Some(200), Some(400), 100)).unwrap()), vec![2, 3, 4]);
+        assert_eq!(ids(history.trades(BookId(1), range(Some(200), None, 2)).unwrap()), vec![2, 3]);
+        assert_eq!(history.trades(BookId(1), range(None, None, 1)).unwrap()[0], trade(1, 100));
+        // Pages pick up after the last trade of the one before
+        assert_eq!(ids(history.trades_after(BookId(1), Some(3), range(Some(200), None, 2)).unwrap()), vec![4, 5]);
+        assert_eq!(ids(history.trades_after(BookId(1), Some(6), range(None, None, 2)).unwrap()), Vec::<u64>::new());
+        // Trader 1 made every trade; trader 3 took one
+        assert_eq!(ids(history.trader_fills([1; 20], range(Some(600), None, 100)).unwrap()), vec![6, 7]);
+        assert_eq!(ids(history.trader_fills([3; 20], range(None, None, 100)).unwrap()), vec![3]);
+        assert_eq!(history.last_trade_id().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn test_history_from_the_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let history = TradeHistory::open(&path).unwrap();
+        let mut engine = MatchingEngine::auto_creating();
+        let (maker, taker) = ([1; 20], [2; 20]);
+        let submit = |engine: &mut MatchingEngine, order_id: u64, qty: u64, price: u32, is_bid: bool, trader: [u8; 20]| {
+            engine.match_order(OrderId(order_id), BookId(0), Qty(qty), price, is_bid, Some(trader), Some(order_id), None, None).unwrap();
+        };
+        submit(&mut engine, 1, 10, 100, false, maker);
+        // Resting before the sink is installed, and still attributed when it closes
+        engine.orderbook_manager.set_event_sink(Box::new(history.sink(&engine)));
+        submit(&mut engine, 2, 10, 101, false, maker);
+        submit(&mut engine, 3, 15, 101, true, taker);
+        engine.orderbook_manager.cancel_remaining(OrderId(2)).unwrap();
+        engine.finalize().unwrap();
+
+        let fills = history.trader_fills(taker, range(None, None, 100)).unwrap();
+        let fills: Vec<(u64, u64, Option<[u8; 20]>)> =
+            fills.iter().map(|stored| (stored.trade.maker_order_id.0, stored.trade.qty.value(), stored.maker_trader)).collect();
+        assert_eq!(fills, vec![(1, 10, Some(maker)), (2, 5, Some(maker))]);
+        let closed: Vec<(u64, ClosedStatus)> = history
+            .closed_orders(maker, range(None, None, 100))
+            .unwrap()
+            .iter()
+            .map(|closed| (closed.order_id.0, closed.status))
+            .collect();
+        assert_eq!(closed, vec![(1, ClosedStatus::Filled), (2, ClosedStatus::Cancelled)]);
+
+        // Reopened, the history gives the last trade ID, so numbering goes on after it
+        drop(history);
+        let history = TradeHistory::open(&path).unwrap();
+        let mut engine = MatchingEngine::auto_creating();
+        engine.continue_trade_ids_after(history.last_trade_id().unwrap().unwrap());
+        engine.orderbook_manager.set_event_sink(Box::new(history.sink(&engine)));
+        submit(&mut engine, 1, 10, 100, false, maker);
+        submit(&mut engine, 2, 10, 100, true, taker);
+        history.flush();
+        assert_eq!(history.last_trade_id().unwrap(), Some(3));
+    }
+}
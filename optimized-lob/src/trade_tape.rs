@@ -0,0 +1,156 @@
+// trade_tape.rs
+
+use crate::{order::OrderId, quantity::Qty};
+use serde::{Deserialize, Serialize};
+
+/// A single execution recorded on the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Trade {
+    pub trade_id: u64,
+    pub timestamp: u64,          // Nanoseconds since the Unix epoch
+    pub price: u32,
+    pub qty: Qty,
+    pub aggressor_is_bid: bool,  // True if the taker was buying
+    pub maker_order_id: OrderId,
+    pub taker_order_id: OrderId,
+}
+
+/// How a limit order's fill compared with its limit and with the book it arrived at.
+/// Limit orders trade at their own limit, so the improvement is measured from the price the
+/// maker rested at: how far inside the taker's limit the liquidity it took was quoted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceImprovement {
+    pub taker_limit: u32,
+    pub maker_price: u32,
+    pub ticks: i64,              // Positive when the maker's price was better for the taker than its limit
+    pub quoted_bid: Option<u32>, // The best bid and ask as the taker arrived, before any of its fills
+    pub quoted_ask: Option<u32>,
+}
+
+impl PriceImprovement {
+    /// Measures a fill of a taker at `taker_limit` against a maker resting at `maker_price`,
+    /// in ticks of `tick_size`, and keeps the book's best prices from before the taker's fills.
+    ///
+    /// ## Example:
+    /// ```
+    /// # use optimized_lob::trade_tape::PriceImprovement;
+    /// let improvement = PriceImprovement::new(105, 100, true, 1, (Some(98), Some(100)));
+    /// assert_eq!((improvement.ticks, improvement.quoted_spread()), (5, Some(2)));
+    /// assert_eq!(PriceImprovement::new(100, 110, false, 5, (Some(110), None)).ticks, 2);
+    /// ```
+    pub fn new(taker_limit: u32, maker_price: u32, taker_is_bid: bool, tick_size: u32, (quoted_bid, quoted_ask): (Option<u32>, Option<u32>)) -> Self {
+        let better = if taker_is_bid {
+            i64::from(taker_limit) - i64::from(maker_price)
+        } else {
+            i64::from(maker_price) - i64::from(taker_limit)
+        };
+        Self { taker_limit, maker_price, ticks: better / i64::from(tick_size.max(1)), quoted_bid, quoted_ask }
+    }
+
+    /// Gets the spread quoted as the taker arrived, or None if either side of the book was empty.
+    #[inline]
+    pub fn quoted_spread(&self) -> Option<u32> {
+        self.quoted_ask?.checked_sub(self.quoted_bid?)
+    }
+}
+
+/// Bounded ring buffer of the most recent trades of a book.
+/// Storage is allocated once up front so recording a trade never allocates.
+pub struct TradeTape {
+    trades: Vec<Trade>,
+    capacity: usize,
+    head: usize, // Slot the next trade is written to
+}
+
+impl TradeTape {
+    /// Creates an empty tape holding at most `capacity` trades.
+    #[inline]
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "trade tape capacity must be positive");
+        Self {
+            trades: Vec::with_capacity(capacity),
+            capacity,
+            head: 0,
+        }
+    }
+
+    /// Records a trade, overwriting the oldest one once the tape is full.
+    #[inline]
+    pub fn push(&mut self, trade: Trade) {
+        if self.trades.len() < self.capacity {
+            self.trades.push(trade);
+        } else {
+            self.trades[self.head] = trade;
+        }
+        self.head = (self.head + 1) % self.capacity;
+    }
+
+    /// Number of trades currently retained.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.trades.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.trades.is_empty()
+    }
+
+    /// Iterates the retained trades from newest to oldest.
+    pub fn iter_newest(&self) -> impl Iterator<Item = &Trade> {
+        let (older, newer) = self.trades.split_at(self.head);
+        older.iter().rev().chain(newer.iter().rev())
+    }
+
+    /// Returns up to `limit` trades, newest first, strictly older than `before` when given.
+    pub fn recent(&self, limit: usize, before: Option<u64>) -> Vec<Trade> {
+        self.iter_newest()
+            .filter(|trade| before.is_none_or(|before| trade.trade_id < before))
+            .take(limit)
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(trade_id: u64) -> Trade {
+        Trade {
+            trade_id,
+            timestamp: trade_id * 1_000,
+            price: 100,
+            qty: Qty(1),
+            aggressor_is_bid: true,
+            maker_order_id: OrderId(0),
+            taker_order_id: OrderId(trade_id),
+        }
+    }
+
+    #[test]
+    fn test_tape_wraps_and_keeps_newest() {
+        let mut tape = TradeTape::new(4);
+        for trade_id in 1..=6 {
+            tape.push(trade(trade_id));
+        }
+
+        assert_eq!(tape.len(), 4);
+        let ids: Vec<u64> = tape.iter_newest().map(|trade| trade.trade_id).collect();
+        assert_eq!(ids, vec![6, 5, 4, 3]);
+    }
+
+    #[test]
+    fn test_tape_pagination() {
+        let mut tape = TradeTape::new(8);
+        for trade_id in 1..=5 {
+            tape.push(trade(trade_id));
+        }
+
+        let ids: Vec<u64> = tape.recent(2, None).iter().map(|trade| trade.trade_id).collect();
+        assert_eq!(ids, vec![5, 4]);
+        let ids: Vec<u64> = tape.recent(2, Some(4)).iter().map(|trade| trade.trade_id).collect();
+        assert_eq!(ids, vec![3, 2]);
+        assert!(tape.recent(2, Some(1)).is_empty());
+    }
+}
@@ -1,56 +1,248 @@
 // translates match results into settlement format
 
 use crate::{
-    order::Order,
+    order::{Order, Signature},
     quantity::Qty,
     market::MarketConfig,
     matching::MatchDetails,
+    settlement_manager::{SettlementTracker, TrackedSettlement},
+    utils::hex_array,
 };
+use alloy_primitives::U256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why a fill could not be translated into a settlement
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TranslationError {
+    MissingMakerSignature,
+    MissingTakerSignature,
+    MissingMakerAddress,
+    MissingTakerAddress,
+    /// An order has no expiry for its side of the settlement.
+    MissingMakerExpiry,
+    MissingTakerExpiry,
+    /// An order has no nonce to derive its side's salt from.
+    MissingMakerNonce,
+    MissingTakerNonce,
+    /// The maker order has no price to tell which side of the trade it is on.
+    MissingMakerPrice,
+    /// An amount does not fit a u128 once scaled to token base units.
+    AmountOverflow,
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranslationError::MissingMakerSignature => write!(f, "Maker order has no signature"),
+            TranslationError::MissingTakerSignature => write!(f, "Taker order has no signature"),
+            TranslationError::MissingMakerAddress => write!(f, "Maker order has no trader address"),
+            TranslationError::MissingTakerAddress => write!(f, "Taker order has no trader address"),
+            TranslationError::MissingMakerExpiry => write!(f, "Maker order has no expiry"),
+            TranslationError::MissingTakerExpiry => write!(f, "Taker order has no expiry"),
+            TranslationError::MissingMakerNonce => write!(f, "Maker order has no nonce"),
+            TranslationError::MissingTakerNonce => write!(f, "Taker order has no nonce"),
+            TranslationError::MissingMakerPrice => write!(f, "Maker order has no price"),
+            TranslationError::AmountOverflow => write!(f, "Settlement amount overflows"),
+        }
+    }
+}
+
+/// A fill that could not be translated, for the caller to alert on and compensate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationFailure {
+    pub trade_id: u64,
+    pub error: TranslationError,
+}
+
+/// The outcome of translating a batch of matches: every match that settles ends up in exactly one list
+/// Settlements come as they are tracked, naming the trade and the maker and taker orders they settle.
+#[derive(Debug, Default)]
+pub struct TranslatedMatches {
+    pub settlements: Vec<TrackedSettlement>,
+    pub failures: Vec<TranslationFailure>,
+}
 
 /// Represents a signature for settlement
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SettlementSignature {
     pub signature_type: u8,
     pub v: u8,
+    #[serde(with = "hex_array")]
     pub r: [u8; 32],
+    #[serde(with = "hex_array")]
     pub s: [u8; 32],
 }
 
+impl SettlementSignature {
+    /// Gets the signature back as r || s || v, the way orders store it
+    pub fn to_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[..32].copy_from_slice(&self.r);
+        bytes[32..64].copy_from_slice(&self.s);
+        bytes[64] = self.v;
+        bytes
+    }
+}
+
 /// Represents an order ready for settlement
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SettlementOrder {
+    #[serde(with = "hex_array")]
     pub maker_token: [u8; 20],      // Address of token maker is selling/buying
+    #[serde(with = "hex_array")]
     pub taker_token: [u8; 20],      // Address of token taker is selling/buying
     pub maker_amount: u128,         // Amount of maker_token
     pub taker_amount: u128,         // Amount of taker_token
+    #[serde(default)]
+    pub fee_amount: u128,           // Base token the buyer pays fee_recipient on top of the base amount
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub maker_fee: u128,            // Taker token withheld from the maker's taker_amount for fee_recipient
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub taker_fee: u128,            // Maker token withheld from the taker's maker_amount for fee_recipient
+    #[serde(with = "hex_array")]
     pub maker: [u8; 20],           // Maker's address
+    #[serde(with = "hex_array")]
     pub taker: [u8; 20],           // Taker's address
+    #[serde(with = "hex_array")]
     pub fee_recipient: [u8; 20],    // Address receiving fees
+    #[serde(with = "hex_array")]
     pub pool: [u8; 20],            // Liquidity pool address if applicable
-    pub expiration: u64,           // Order expiration timestamp
-    pub salt: u128,                // Unique order identifier
+    #[serde(alias = "expiration")]
+    pub maker_expiration: u64,     // Maker order expiration timestamp
+    #[serde(alias = "salt")]
+    pub maker_salt: u128,          // Unique per fill, see settlement_salt
+    #[serde(default)]
+    pub taker_expiration: u64,     // Taker order expiration timestamp
+    #[serde(default)]
+    pub taker_salt: u128,          // The taker's salt, derived the same way
     pub maker_is_buyer: bool,      // True if maker is buying taker_token
     pub maker_signature: SettlementSignature,
     pub taker_signature: SettlementSignature,
+    pub chain_id: u64,             // Chain the settlement contract lives on
+    #[serde(with = "hex_array")]
+    pub domain_separator: [u8; 32], // EIP-712 domain separator of the settlement contract
+}
+
+impl SettlementOrder {
+    /// Whether maker or taker pays a trading fee, which needs the fee-aware settlement function
+    pub fn has_fees(&self) -> bool {
+        self.maker_fee != 0 || self.taker_fee != 0
+    }
+}
+
+fn is_zero(amount: &u128) -> bool {
+    *amount == 0
+}
+
+/// Computes the fee on `amount` at `bps` basis points
+/// Fractions of a base unit are rounded up, so a fee is never below its rate; the fee never
+/// exceeds `amount`.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::translator::fee_for;
+/// assert_eq!(fee_for(1_000_000, 25), 2_500);
+/// assert_eq!(fee_for(3, 5), 1); // 0.0015 of a unit
+/// assert_eq!(fee_for(3, 0), 0);
+/// ```
+pub fn fee_for(amount: u128, bps: u16) -> u128 {
+    let fee = (U256::from(amount) * U256::from(bps)).div_ceil(U256::from(10_000u16));
+    u128::try_from(fee).unwrap_or(u128::MAX).min(amount)
+}
+
+/// Derives one side's salt of a fill's settlement from its nonce and the fill's trade ID
+/// The nonce goes in the high 64 bits and the trade ID in the low 64 bits. Trade IDs are unique
+/// and assigned in sequence, so every fill gets its own salts, including partial fills of one
+/// order, and replaying the same commands reproduces the same salts. The clock is left
+/// out on purpose: it is not replayed.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::translator::{salt_nonce, settlement_salt};
+/// let salt = settlement_salt(7, 42);
+/// assert_eq!(salt, (7 << 64) | 42);
+/// assert_eq!(salt_nonce(salt), 7);
+/// ```
+pub fn settlement_salt(nonce: u64, trade_id: u64) -> u128 {
+    (u128::from(nonce) << 64) | u128::from(trade_id)
+}
+
+/// Gets the nonce back from a salt made by settlement_salt
+pub fn salt_nonce(salt: u128) -> u64 {
+    (salt >> 64) as u64
+}
+
+/// On-chain amounts of one fill, in token base units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScaledAmounts {
+    pub security_amount: u128, // Moved from seller to buyer
+    pub base_amount: u128,     // Moved from buyer to seller
+    pub dust: u128,            // Base moved from buyer to the fee recipient
+}
+
+/// Scales a fill from book units into on-chain base units of the market's tokens
+/// The security amount is exact. The base notional `qty * price * 10^base_decimals / 10^price_decimals`
+/// can have a fractional base unit: the seller receives it rounded down, the buyer pays it rounded
+/// up, and the difference of at most one base unit goes to the fee recipient as dust.
+/// Intermediate math is done in 256 bits; None if an amount does not fit a u128.
+///
+/// ## Arguments:
+/// - `exec_qty`: The filled quantity, in whole security tokens.
+/// - `exec_price`: The execution price, with `price_decimals` decimal places.
+/// - `market_config`: The market, for its decimals.
+///
+/// ## Example:
+/// ```
+/// # use optimized_lob::{market::MarketConfig, quantity::Qty, translator::scale_amounts};
+/// // 3 tokens of 18 decimals at 0.333333 USDC with 6-decimal prices
+/// let config = MarketConfig::builder().base_decimals(6).security_decimals(18).price_decimals(6).build();
+/// let amounts = scale_amounts(Qty(3), 333_333, &config).unwrap();
+/// assert_eq!(amounts.security_amount, 3_000_000_000_000_000_000);
+/// assert_eq!((amounts.base_amount, amounts.dust), (999_999, 0));
+/// ```
+pub fn scale_amounts(exec_qty: Qty, exec_price: u32, market_config: &MarketConfig) -> Option<ScaledAmounts> {
+    let pow10 = |exp: u8| U256::from(10u8).checked_pow(U256::from(exp));
+    let qty = U256::from(exec_qty.value());
+
+    let security_amount = qty.checked_mul(pow10(market_config.security_decimals)?)?;
+    let notional = qty
+        .checked_mul(U256::from(exec_price))?
+        .checked_mul(pow10(market_config.base_decimals)?)?;
+    let (base_amount, remainder) = notional.div_rem(pow10(market_config.price_decimals)?);
+    let dust = if remainder.is_zero() { U256::ZERO } else { U256::from(1u8) };
+
+    Some(ScaledAmounts {
+        security_amount: u128::try_from(security_amount).ok()?,
+        base_amount: u128::try_from(base_amount).ok()?,
+        dust: u128::try_from(dust).ok()?,
+    })
 }
 
 /// Translates a matched order pair into settlement format
-#[allow(clippy::cast_possible_truncation)]  // Allow u32 to u128 casts
+/// Amounts are in on-chain base units, see scale_amounts. Each side's fee is taken out of what
+/// it receives: the maker's out of taker_amount and the taker's out of maker_amount, see fee_for.
+/// The maker's side is the side of the price it rests at.
 pub fn translate_to_settlement(
     maker_order: &Order,
     taker_order: &Order,
     exec_qty: Qty,
     exec_price: u32,
-    maker_is_buyer: bool,
+    trade_id: u64,
     market_config: &MarketConfig,
-) -> Option<SettlementOrder> {
+) -> Result<SettlementOrder, TranslationError> {
     // Extract signatures if available with market's signature type
-    let maker_signature = maker_order.signature()
-        .map(|sig| extract_signature(sig, market_config.signature_type))?;
-    let taker_signature = taker_order.signature()
-        .map(|sig| extract_signature(sig, market_config.signature_type))?;
+    let maker_signature = extract_signature(maker_order.signature(), market_config.signature_type)
+        .ok_or(TranslationError::MissingMakerSignature)?;
+    let taker_signature = extract_signature(taker_order.signature(), market_config.signature_type)
+        .ok_or(TranslationError::MissingTakerSignature)?;
 
     // Determine maker/taker tokens based on who is buying
+    if maker_order.price().value() == 0 {
+        return Err(TranslationError::MissingMakerPrice);
+    }
+    let maker_is_buyer = maker_order.price().is_bid();
     let (maker_token, taker_token) = if maker_is_buyer {
         (market_config.base_token, market_config.security_token)
     } else {
@@ -58,69 +250,79 @@ pub fn translate_to_settlement(
     };
 
     // Calculate amounts based on executed quantity and price
+    let amounts = scale_amounts(exec_qty, exec_price, market_config).ok_or(TranslationError::AmountOverflow)?;
     let (maker_amount, taker_amount) = if maker_is_buyer {
-        (u128::from(exec_price) * u128::from(exec_qty.value()), 
-         u128::from(exec_qty.value()))
+        (amounts.base_amount, amounts.security_amount)
     } else {
-        (u128::from(exec_qty.value()), 
-         u128::from(exec_price) * u128::from(exec_qty.value()))
+        (amounts.security_amount, amounts.base_amount)
     };
 
     // Get trader addresses
-    let maker = maker_order.trader()?;
-    let taker = taker_order.trader()?;
-
-    // Get expiration (use maker's expiry)
-    let expiration = maker_order.expiry()?;
+    let maker = maker_order.trader().ok_or(TranslationError::MissingMakerAddress)?;
+    let taker = taker_order.trader().ok_or(TranslationError::MissingTakerAddress)?;
 
-    // Get salt from maker's nonce
-    let salt = u128::from(maker_order.nonce()?);
+    // Each side's signed expiry, and a salt unique to the fill from each side's nonce
+    let maker_expiration = maker_order.expiry().ok_or(TranslationError::MissingMakerExpiry)?;
+    let taker_expiration = taker_order.expiry().ok_or(TranslationError::MissingTakerExpiry)?;
+    let maker_salt = settlement_salt(maker_order.nonce().ok_or(TranslationError::MissingMakerNonce)?, trade_id);
+    let taker_salt = settlement_salt(taker_order.nonce().ok_or(TranslationError::MissingTakerNonce)?, trade_id);
 
-    Some(SettlementOrder {
+    Ok(SettlementOrder {
         maker_token,
         taker_token,
         maker_amount,
         taker_amount,
+        fee_amount: amounts.dust,
+        maker_fee: fee_for(taker_amount, market_config.maker_fee_bps),
+        taker_fee: fee_for(maker_amount, market_config.taker_fee_bps),
         maker,
         taker,
         fee_recipient: market_config.fee_recipient,
         pool: market_config.pool,
-        expiration,
-        salt,
+        maker_expiration,
+        maker_salt,
+        taker_expiration,
+        taker_salt,
         maker_is_buyer,
         maker_signature,
         taker_signature,
+        chain_id: market_config.chain_id,
+        domain_separator: market_config.domain().separator(),
     })
 }
 
 /// Extracts signature components from raw bytes
-fn extract_signature(sig: [u8; 65], sig_type: u8) -> SettlementSignature {
-    SettlementSignature {
+/// Compact signatures are expanded, so settlements always carry v, r and s.
+fn extract_signature(sig: Signature, sig_type: u8) -> Option<SettlementSignature> {
+    let (v, r, s) = sig.to_vrs()?;
+    Some(SettlementSignature {
         signature_type: sig_type,  // Use the market config's signature type
-        v: sig[64],
-        r: sig[..32].try_into().unwrap(),
-        s: sig[32..64].try_into().unwrap(),
-    }
+        v,
+        r,
+        s,
+    })
 }
 
-/// Translates a batch of matches into settlement orders
-pub fn translate_matches(
-    matches: Vec<MatchDetails>,
-    market_config: &MarketConfig,
-) -> Vec<SettlementOrder> {
-    matches
-        .into_iter()
-        .filter_map(|match_details| {
-            translate_to_settlement(
-                &match_details.maker_order,
-                &match_details.taker_order,
-                match_details.exec_qty,
-                match_details.exec_price,
-                match_details.maker_is_buyer,
-                market_config,
-            )
-        })
-        .collect()
+/// Gathers the settlement orders the engine translated for a batch of matches
+/// Fills carry only what identifies their orders, so they are translated as they happen, while
+/// the maker is still in the book, and tracked in `settlements`; this looks them up by settlement
+/// ID. Matches that couldn't be translated are returned as failures, by trade ID, rather than
+/// dropped; those in books without a market configuration don't settle and are left out.
+#[tracing::instrument(name = "settlement_translation", level = "debug", skip_all, fields(matches = matches.len()))]
+pub fn collect_settlements(matches: &[MatchDetails], settlements: &SettlementTracker) -> TranslatedMatches {
+    let mut translated = TranslatedMatches::default();
+    for match_details in matches {
+        if let Some(settlement) = match_details.settlement_id.and_then(|settlement_id| settlements.get(settlement_id)) {
+            translated.settlements.push(settlement.clone());
+        } else if let Some(error) = match_details.settlement_error.clone() {
+            tracing::debug!(trade_id = match_details.trade_id, ?error, "Match not translated");
+            translated.failures.push(TranslationFailure {
+                trade_id: match_details.trade_id,
+                error,
+            });
+        }
+    }
+    translated
 }
 
 #[cfg(test)]
@@ -129,23 +331,26 @@ mod tests {
     use crate::{
         matching::MatchingEngine,
         order::OrderId,
+        price::Price,
         utils::BookId,
     };
 
     #[test]
     fn test_full_match_and_translate_flow() {
         // Setup matching engine with market config
-        let mut engine = MatchingEngine::new();
+        let mut engine = MatchingEngine::auto_creating();
         
         // Create and add market config for BookId(0)
-        let market_config = MarketConfig {
-            base_token: [1; 20],      // Example USDC address
-            security_token: [2; 20],   // Example ETH address
-            fee_recipient: [3; 20],
-            pool: [4; 20],
-            signature_type: 1,
-        };
-        engine.market_manager.add_market(BookId(0), market_config.clone());
+        let market_config = MarketConfig::builder()
+            .base_token([1; 20])      // Example USDC address
+            .security_token([2; 20])   // Example ETH address
+            .fee_recipient([3; 20])
+            .pool([4; 20])
+            .signature_type(1)
+            .chain_id(8453)
+            .verifying_contract([5; 20])
+            .build();
+        engine.market_manager.add_market(BookId(0), market_config.clone(), false).unwrap();
 
         // Add a resting sell order
         engine.orderbook_manager.add_order(
@@ -158,7 +363,7 @@ mod tests {
             Some(1),        // nonce
             Some(u64::MAX), // expiry
             Some([1; 65]),  // signature
-        );
+        ).unwrap();
 
         // Execute matching buy order
         let (remaining, matches) = engine.match_order(
@@ -171,12 +376,16 @@ mod tests {
             Some(3),        // nonce
             Some(u64::MAX), // expiry
             Some([3; 65]),  // signature
-        );
+        ).unwrap();
 
-        // Get market config and translate matches
+        // Get market config and the settlements of the matches
         let market_config = engine.market_manager.get_config(BookId(0))
             .expect("Market config should exist");
-        let settlements = translate_matches(matches, market_config);
+        let translated = collect_settlements(&matches, &engine.settlements);
+        assert!(translated.failures.is_empty());
+        let tracked = &translated.settlements[0];
+        assert_eq!((tracked.trade_id, tracked.maker_order_id, tracked.taker_order_id), (matches[0].trade_id, 1, 3));
+        let settlements: Vec<SettlementOrder> = translated.settlements.into_iter().map(|settlement| settlement.order).collect();
 
         // Print and verify settlements
         println!("\nSETTLEMENT DETAILS:");
@@ -192,6 +401,8 @@ mod tests {
             println!("Maker Amount: {}", settlement.maker_amount);
             println!("Taker Amount: {}", settlement.taker_amount);
             println!("Maker is Buyer: {}", settlement.maker_is_buyer);
+            println!("Chain ID: {}", settlement.chain_id);
+            println!("Domain Separator: 0x{}", hex::encode(settlement.domain_separator));
             println!("\nMaker Signature:");
             println!("  Type: {}", settlement.maker_signature.signature_type);
             println!("  v: {}", settlement.maker_signature.v);
@@ -212,10 +423,12 @@ mod tests {
         let settlement = &settlements[0];
         assert_eq!(settlement.maker_amount, 30); // Security amount
         assert_eq!(settlement.taker_amount, 3000); // Base amount (30 * 100)
-        assert_eq!(settlement.maker_is_buyer, false);
+        assert!(!settlement.maker_is_buyer);
         assert_eq!(settlement.maker_signature.signature_type, 1);
         assert_eq!(settlement.fee_recipient, [3; 20]);
         assert_eq!(settlement.pool, [4; 20]);
+        assert_eq!(settlement.chain_id, 8453);
+        assert_eq!(settlement.domain_separator, market_config.domain().separator());
         assert_eq!(settlement.maker_signature.v, 1);  // From [1; 65] signature
         assert_eq!(settlement.maker_signature.r, [1; 32]);
         assert_eq!(settlement.maker_signature.s, [1; 32]);
@@ -223,5 +436,308 @@ mod tests {
         assert_eq!(settlement.taker_signature.r, [3; 32]);
         assert_eq!(settlement.taker_signature.s, [3; 32]);
     }
-}
 
+    #[test]
+    fn test_trade_ids_follow_execution() {
+        let mut engine = MatchingEngine::auto_creating();
+        let market = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        for book in 0..2 {
+            engine.market_manager.add_market(BookId(book), market.clone(), false).unwrap();
+        }
+        let rest = |engine: &mut MatchingEngine, order_id: u64, book: u32, price: u32| {
+            let maker = Some([order_id as u8; 20]);
+            engine.orderbook_manager.add_order(
+                OrderId(order_id), BookId(book), Qty(10), price, false, maker, Some(order_id), Some(u64::MAX), Some([1; 65]),
+            ).unwrap();
+        };
+        for (order_id, price) in [(1, 102), (2, 100), (3, 101), (4, 100), (5, 103)] {
+            rest(&mut engine, order_id, 0, price);
+        }
+        rest(&mut engine, 6, 1, 100);
+
+        // A sweep takes the makers in price-time order, numbering its trades as it goes
+        let (_, sweep) = engine
+            .match_order(OrderId(10), BookId(0), Qty(45), 103, true, Some([9; 20]), Some(10), Some(u64::MAX), Some([3; 65]))
+            .unwrap();
+        let (_, other) = engine
+            .match_order(OrderId(11), BookId(1), Qty(5), 100, true, Some([9; 20]), Some(11), Some(u64::MAX), Some([3; 65]))
+            .unwrap();
+        let fills: Vec<(u64, u64, u64)> =
+            sweep.iter().chain(&other).map(|fill| (fill.trade_id, fill.maker.order_id.0, fill.taker.order_id.0)).collect();
+        println!("Fills: {:?}", fills);
+        assert_eq!(fills.iter().map(|&(_, maker, taker)| (maker, taker)).collect::<Vec<_>>(), [(2, 10), (4, 10), (3, 10), (1, 10), (5, 10), (6, 11)]);
+        assert!(fills.windows(2).all(|pair| pair[0].0 < pair[1].0), "trade IDs must strictly increase");
+
+        // Every partial fill describes the taker as submitted, and how much of it was still to fill
+        for (fill, remaining) in sweep.iter().zip([35, 25, 15, 5, 0]) {
+            let taker = fill.taker;
+            assert_eq!((taker.trader, taker.nonce, taker.expiry), (Some([9; 20]), Some(10), Some(u64::MAX)));
+            assert_eq!((taker.qty, taker.remaining_qty), (Qty(45), Qty(remaining)));
+        }
+
+        // The tape and the tracked settlements carry the same IDs
+        let taped: Vec<u64> = engine.trade_tape(BookId(0)).unwrap().recent(10, None).iter().map(|trade| trade.trade_id).rev().collect();
+        assert_eq!(taped, fills[..5].iter().map(|&(trade_id, _, _)| trade_id).collect::<Vec<_>>());
+        let translated = collect_settlements(&sweep, &engine.settlements);
+        let settled: Vec<(u64, u64, u64)> =
+            translated.settlements.iter().map(|settlement| (settlement.trade_id, settlement.maker_order_id, settlement.taker_order_id)).collect();
+        assert_eq!(settled, fills[..5]);
+    }
+
+    #[test]
+    fn test_decimal_scaling() {
+        // USDC has 6 decimals, the security 18; prices have 2 decimal places
+        let cents = MarketConfig::builder().base_decimals(6).security_decimals(18).price_decimals(2).build();
+        let amounts = scale_amounts(Qty(3), 12_345, &cents).unwrap();
+        println!("3 at 123.45: {:?}", amounts);
+        assert_eq!(
+            amounts,
+            ScaledAmounts { security_amount: 3_000_000_000_000_000_000, base_amount: 370_350_000, dust: 0 }
+        );
+
+        // Prices finer than the base token: the seller gets the notional rounded down and the
+        // fee recipient the unit the buyer pays on top
+        let fine = MarketConfig::builder().base_decimals(6).security_decimals(18).price_decimals(8).build();
+        for (qty, price, base_amount, dust) in [
+            (3, 33_333_333, 999_999, 1), // 3 * 0.33333333 = 0.99999999
+            (7, 1, 0, 1),                // 7e-8 USDC is less than a base unit
+            (100, 12_345_678, 12_345_678, 0),
+            (u32::MAX as u64, u32::MAX, 184_467_440_651_196_170, 1),
+        ] {
+            let amounts = scale_amounts(Qty(qty), price, &fine).unwrap();
+            assert_eq!((amounts.base_amount, amounts.dust), (base_amount, dust), "{} at {}", qty, price);
+        }
+
+        // Without decimals amounts stay in book units
+        let raw = scale_amounts(Qty(30), 100, &MarketConfig::default()).unwrap();
+        assert_eq!(raw, ScaledAmounts { security_amount: 30, base_amount: 3_000, dust: 0 });
+
+        // Amounts that don't fit a u128 are refused rather than wrapped
+        let huge = MarketConfig::builder().security_decimals(30).build();
+        assert!(scale_amounts(Qty(u64::MAX), 1, &huge).is_none());
+        assert!(scale_amounts(Qty(1), 1, &MarketConfig::builder().base_decimals(255).build()).is_none());
+    }
+
+    #[test]
+    fn test_translate_with_decimals() {
+        let mut engine = MatchingEngine::auto_creating();
+        let market_config = MarketConfig::builder()
+            .base_token([1; 20])
+            .security_token([2; 20])
+            .fee_recipient([3; 20])
+            .base_decimals(6)
+            .security_decimals(18)
+            .price_decimals(8)
+            .build();
+        engine.market_manager.add_market(BookId(0), market_config, false).unwrap();
+
+        // A resting bid at 0.33333333 is hit for 3
+        engine.orderbook_manager.add_order(
+            OrderId(1), BookId(0), Qty(10), 33_333_333, true, Some([5; 20]), Some(1), Some(u64::MAX), Some([1; 65]),
+        ).unwrap();
+        engine.match_order(
+            OrderId(2), BookId(0), Qty(3), 33_333_333, false, Some([7; 20]), Some(2), Some(u64::MAX), Some([2; 65]),
+        ).unwrap();
+
+        let settlement = &engine.settlements.get(1).unwrap().order;
+        println!("Settlement: {:?}", settlement);
+        assert!(settlement.maker_is_buyer);
+        assert_eq!((settlement.maker_token, settlement.taker_token), ([1; 20], [2; 20]));
+        assert_eq!(settlement.maker_amount, 999_999);
+        assert_eq!(settlement.taker_amount, 3_000_000_000_000_000_000);
+        assert_eq!(settlement.fee_amount, 1);
+    }
+
+    #[test]
+    fn test_fees() {
+        use crate::abi::{encode_settlement, SETTLE_SELECTOR};
+
+        // A resting ask of 100 is lifted by 1 and then 99: maker pays 10 bps, taker 25
+        let fills = |config: MarketConfig| {
+            let mut engine = MatchingEngine::auto_creating();
+            engine.market_manager.add_market(BookId(0), config, false).unwrap();
+            engine.orderbook_manager.add_order(
+                OrderId(1), BookId(0), Qty(100), 3, false, Some([5; 20]), Some(1), Some(u64::MAX), Some([1; 65]),
+            ).unwrap();
+            for (order_id, qty) in [(2, 1), (3, 99)] {
+                engine.match_order(
+                    OrderId(order_id), BookId(0), Qty(qty), 3, true, Some([7; 20]), Some(2), Some(u64::MAX), Some([2; 65]),
+                ).unwrap();
+            }
+            engine.settlements.settlements().map(|settlement| settlement.order.clone()).collect::<Vec<_>>()
+        };
+        let base = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).fee_recipient([3; 20]);
+        let free = fills(base.clone().build());
+        let charged = fills(base.clone().maker_fee_bps(10).taker_fee_bps(25).build());
+
+        // Sub-unit fees on the small fill round up to one unit
+        println!("Small fill: {:?}", charged[0]);
+        assert_eq!((charged[0].maker_amount, charged[0].taker_amount), (1, 3));
+        assert_eq!((charged[0].maker_fee, charged[0].taker_fee), (1, 1));
+        // 10 bps of 297 is 0.297, 25 bps of 99 is 0.2475
+        assert_eq!((charged[1].maker_fee, charged[1].taker_fee), (1, 1));
+        assert!(charged.iter().all(SettlementOrder::has_fees));
+
+        // What each side receives plus the fees is always the gross amount of the other side
+        for config in [
+            base.clone().maker_fee_bps(10).taker_fee_bps(25).build(),
+            base.maker_fee_bps(10_000).taker_fee_bps(1).base_decimals(6).price_decimals(4).build(),
+        ] {
+            for order in fills(config) {
+                let maker_receives = order.taker_amount - order.maker_fee;
+                let taker_receives = order.maker_amount - order.taker_fee;
+                assert_eq!(maker_receives + order.maker_fee, order.taker_amount);
+                assert_eq!(taker_receives + order.taker_fee, order.maker_amount);
+                assert!(order.maker_fee <= order.taker_amount && order.taker_fee <= order.maker_amount);
+            }
+        }
+
+        // Zero-fee markets settle exactly as before fees existed
+        assert!(!free.iter().any(SettlementOrder::has_fees));
+        let json = serde_json::to_string(&free[1]).unwrap();
+        assert!(!json.contains("maker_fee") && !json.contains("taker_fee"));
+        let mut without_fees = charged[1].clone();
+        without_fees.maker_fee = 0;
+        without_fees.taker_fee = 0;
+        assert_eq!(encode_settlement(&free[1], SETTLE_SELECTOR), encode_settlement(&without_fees, SETTLE_SELECTOR));
+        assert_eq!(serde_json::from_str::<SettlementOrder>(&json).unwrap(), free[1]);
+    }
+
+    #[test]
+    fn test_translation_errors() {
+        use crate::level::LevelId;
+
+        let config = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        let order = |trader: Option<[u8; 20]>, nonce, expiry, signature| {
+            Order::new(Qty(10), LevelId(0), BookId(0), trader, nonce, expiry, signature).with_price(Price(-100))
+        };
+        let maker = order(Some([5; 20]), Some(1), Some(u64::MAX), Some([1; 65]));
+        let taker = order(Some([7; 20]), Some(2), Some(u64::MAX), Some([2; 65]));
+        let translate = |maker: &Order, taker: &Order, config: &MarketConfig| {
+            translate_to_settlement(maker, taker, Qty(10), 100, 1, config)
+        };
+        assert!(translate(&maker, &taker, &config).is_ok());
+
+        let cases = [
+            (order(Some([5; 20]), Some(1), Some(u64::MAX), None), taker.clone(), TranslationError::MissingMakerSignature),
+            (maker.clone(), order(Some([7; 20]), Some(2), Some(u64::MAX), None), TranslationError::MissingTakerSignature),
+            (order(None, Some(1), Some(u64::MAX), Some([1; 65])), taker.clone(), TranslationError::MissingMakerAddress),
+            (maker.clone(), order(None, Some(2), Some(u64::MAX), Some([2; 65])), TranslationError::MissingTakerAddress),
+            (order(Some([5; 20]), Some(1), None, Some([1; 65])), taker.clone(), TranslationError::MissingMakerExpiry),
+            (maker.clone(), order(Some([7; 20]), Some(2), None, Some([2; 65])), TranslationError::MissingTakerExpiry),
+            (order(Some([5; 20]), None, Some(u64::MAX), Some([1; 65])), taker.clone(), TranslationError::MissingMakerNonce),
+            (maker.clone(), order(Some([7; 20]), None, Some(u64::MAX), Some([2; 65])), TranslationError::MissingTakerNonce),
+        ];
+        for (maker, taker, expected) in cases {
+            let error = translate(&maker, &taker, &config).unwrap_err();
+            println!("{:?}: {}", error, error);
+            assert_eq!(error, expected);
+        }
+        let unpriced = Order::new(Qty(10), LevelId(0), BookId(0), Some([5; 20]), Some(1), Some(u64::MAX), Some([1; 65]));
+        assert_eq!(translate(&unpriced, &taker, &config).unwrap_err(), TranslationError::MissingMakerPrice);
+        let overflowing = MarketConfig::builder().base_decimals(40).build();
+        assert_eq!(translate(&maker, &taker, &overflowing).unwrap_err(), TranslationError::AmountOverflow);
+    }
+
+    #[test]
+    fn test_no_match_is_dropped() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut engine = MatchingEngine::auto_creating();
+        let config = MarketConfig::builder().base_token([1; 20]).security_token([2; 20]).build();
+        engine.market_manager.add_market(BookId(0), config, false).unwrap();
+
+        // Random orders, some missing what a settlement needs
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut matches = Vec::new();
+        for order_id in 0..2_000 {
+            let is_bid = rng.gen_bool(0.5);
+            let price = rng.gen_range(95..105);
+            let trader = rng.gen_bool(0.9).then_some([rng.gen_range(1..5); 20]);
+            let nonce = rng.gen_bool(0.9).then_some(order_id);
+            let expiry = rng.gen_bool(0.9).then_some(u64::MAX);
+            let signature = rng.gen_bool(0.9).then_some([3; 65]);
+            let (_, fills) = engine.match_order(
+                OrderId(order_id), BookId(0), Qty(rng.gen_range(1..50)), price, is_bid, trader, nonce, expiry, signature,
+            ).unwrap();
+            matches.extend(fills);
+        }
+
+        // The engine registers a settlement or records an error for every fill
+        for fill in &matches {
+            assert!(fill.settlement_id.is_some() != fill.settlement_error.is_some(), "trade {}", fill.trade_id);
+        }
+        let registered = matches.iter().filter(|fill| fill.settlement_id.is_some()).count();
+        assert_eq!(engine.settlements.settlements().count(), registered);
+
+        let trade_ids: Vec<u64> = matches.iter().map(|fill| fill.trade_id).collect();
+        let expected_failures: Vec<TranslationFailure> = matches
+            .iter()
+            .filter_map(|fill| Some(TranslationFailure { trade_id: fill.trade_id, error: fill.settlement_error.clone()? }))
+            .collect();
+        let translated = collect_settlements(&matches, &engine.settlements);
+        println!("{} settled, {} failed", translated.settlements.len(), translated.failures.len());
+        assert!(!translated.failures.is_empty());
+        assert_eq!(translated.settlements.len() + translated.failures.len(), trade_ids.len());
+        assert_eq!(translated.failures, expected_failures);
+    }
+
+    #[test]
+    fn test_salts_are_unique_per_fill() {
+        // One maker order is partially filled twice
+        let salts = || {
+            let mut engine = MatchingEngine::auto_creating();
+            engine.market_manager.add_market(BookId(0), MarketConfig::builder().base_token([1; 20]).build(), false).unwrap();
+            engine.orderbook_manager.add_order(
+                OrderId(1), BookId(0), Qty(100), 100, false, Some([5; 20]), Some(9), Some(u64::MAX), Some([1; 65]),
+            ).unwrap();
+            for order_id in [2, 3] {
+                engine.match_order(
+                    OrderId(order_id), BookId(0), Qty(10), 100, true, Some([7; 20]), Some(1), Some(u64::MAX), Some([2; 65]),
+                ).unwrap();
+            }
+            engine.settlements.settlements().map(|settlement| settlement.order.maker_salt).collect::<Vec<_>>()
+        };
+        let first = salts();
+        println!("Salts: {:x?}", first);
+        assert_eq!(first.len(), 2);
+        assert_ne!(first[0], first[1]);
+        assert!(first.iter().all(|&salt| salt_nonce(salt) == 9));
+
+        // The same fills get the same salts again, as on replay
+        assert_eq!(salts(), first);
+    }
+
+    #[test]
+    fn test_both_sides_round_trip() {
+        use crate::abi::{encode_settlement, settleCall, SETTLE_SELECTOR};
+        use alloy_sol_types::SolCall;
+
+        let mut engine = MatchingEngine::auto_creating();
+        engine.market_manager.add_market(BookId(0), MarketConfig::builder().base_token([1; 20]).build(), false).unwrap();
+        engine.orderbook_manager.add_order(
+            OrderId(1), BookId(0), Qty(50), 100, false, Some([5; 20]), Some(11), Some(1_800_000_000), Some([1; 65]),
+        ).unwrap();
+        let (_, matches) = engine.match_order(
+            OrderId(2), BookId(0), Qty(20), 100, true, Some([7; 20]), Some(22), Some(1_900_000_000), Some([2; 65]),
+        ).unwrap();
+
+        // The taker order of the match keeps everything the taker signed
+        let taker = matches[0].taker;
+        assert_eq!((taker.order_id, taker.nonce, taker.expiry), (OrderId(2), Some(22), Some(1_900_000_000)));
+
+        let settlement = engine.settlements.get(1).unwrap().order.clone();
+        println!("Settlement: {:?}", settlement);
+        assert_eq!((settlement.maker_expiration, salt_nonce(settlement.maker_salt)), (1_800_000_000, 11));
+        assert_eq!((settlement.taker_expiration, salt_nonce(settlement.taker_salt)), (1_900_000_000, 22));
+
+        let json = serde_json::to_string(&settlement).unwrap();
+        assert_eq!(serde_json::from_str::<SettlementOrder>(&json).unwrap(), settlement);
+
+        let decoded = settleCall::abi_decode(&encode_settlement(&settlement, SETTLE_SELECTOR)).unwrap();
+        assert_eq!(decoded.order.makerExpiry, 1_800_000_000);
+        assert_eq!(decoded.order.takerExpiry, 1_900_000_000);
+        assert_eq!(decoded.order.makerSalt, U256::from(settlement.maker_salt));
+        assert_eq!(decoded.order.takerSalt, U256::from(settlement.taker_salt));
+    }
+}
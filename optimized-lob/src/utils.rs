@@ -1,12 +1,75 @@
 // utils.rs
 
-use crate::order_intake::OrderIntakeError;
+use crate::{book_registry::BookRegistry, order_intake::OrderIntakeError};
+use serde::{Deserialize, Serialize};
 
 pub const INITIAL_ORDER_COUNT: usize = 1 << 20;
 pub const MAX_BOOKS: usize = 1 << 14;
 pub const MAX_LEVELS: usize = 1 << 20;
+pub const DEFAULT_TRADE_TAPE_CAPACITY: usize = 1 << 12;
+pub const MARKET_DATA_CHANNEL_CAPACITY: usize = 1 << 14;
+pub const ORDER_UPDATE_CHANNEL_CAPACITY: usize = 1 << 14;
+pub const CONTRACT_SIGNATURE_CACHE_CAPACITY: usize = 1 << 12;
+pub const SETTLEMENT_HISTORY_CAPACITY: usize = 1 << 16;
+pub const RPC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+pub const FUNDS_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(2);
+pub const SETTLEMENT_MAX_RETRIES: u32 = 5;
+pub const SETTLEMENT_INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+pub const SETTLEMENT_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+pub const SETTLEMENT_CONFIRMATION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+pub const SETTLEMENT_BATCH_WINDOW: std::time::Duration = std::time::Duration::from_millis(250);
+pub const SETTLEMENT_MAX_BATCH_SIZE: usize = 32;
+pub const STATS_WINDOW: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+pub const STATS_BUCKET: std::time::Duration = std::time::Duration::from_secs(60);
+pub const CANDLE_HISTORY_CAPACITY: usize = 1 << 10;
+pub const CHECKSUM_DEPTH: usize = 25;
+pub const CHECKSUM_INTERVAL: u32 = 100;
+pub const MAX_STOP_ROUNDS: usize = 16;
+pub const DEFAULT_MAX_OPEN_ORDERS: u32 = 1_000;
+pub const EXPIRY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+pub const MAX_CLIENT_ORDER_ID_LEN: usize = 36;
+pub const MAX_CANCELS_IN_A_ROW: usize = 64;
+pub const MAX_COMMANDS_PER_CYCLE: usize = 256;
+pub const RFQ_NOTICE_CHANNEL_CAPACITY: usize = 1 << 10;
+pub const RFQ_RETENTION_SECS: u64 = 60 * 60;
+pub const MAX_MASS_QUOTE_ENTRIES: usize = 256;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+/// Source of the timestamps the engine stamps on orders and trades.
+/// Matching never reads the wall clock directly, so a replay can pin time to recorded values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// The wall clock as of the first read, advanced by the monotonic clock since. Never goes
+    /// backwards when the wall clock is stepped, and a read costs no more than Instant::now.
+    #[default]
+    System,
+    Fixed(u64), // Nanoseconds since the Unix epoch.
+}
+
+/// Wall time and monotonic time taken together on the first read of Clock::System
+static CLOCK_ANCHOR: std::sync::OnceLock<(u64, std::time::Instant)> = std::sync::OnceLock::new();
+
+impl Clock {
+    /// Gets the current time in nanoseconds since the Unix epoch.
+    /// Callers stamping several fields read it once and reuse the value.
+    #[inline]
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::System => {
+                let (wall, instant) = CLOCK_ANCHOR.get_or_init(|| {
+                    let wall = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+                    (wall, std::time::Instant::now())
+                });
+                wall.saturating_add(instant.elapsed().as_nanos() as u64)
+            }
+            Clock::Fixed(timestamp) => *timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct BookId(pub u32);
 
 impl BookId {
@@ -15,15 +78,87 @@ impl BookId {
         self.0
     }
 
-    pub fn from_str(s: &str) -> Result<Self, OrderIntakeError> {
-        // You can implement your own logic here for converting strings to BookId
-        // For example, you could use a hash function or maintain a mapping
-        // This is a simple example that just hashes the string to a u32
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        
-        let mut hasher = DefaultHasher::new();
-        s.hash(&mut hasher);
-        Ok(BookId(hasher.finish() as u32))
+    /// Looks up the BookId `s` was registered under; unregistered books have none.
+    pub fn from_str(s: &str, registry: &BookRegistry) -> Result<Self, OrderIntakeError> {
+        registry.get_book_id(s).map_err(|_| OrderIntakeError::InvalidBookId)
+    }
+}
+
+/// A UTC calendar breakdown of a nanosecond timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtcTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    pub nanos: u64,
+}
+
+impl UtcTime {
+    pub fn from_nanos(timestamp: u64) -> Self {
+        let seconds = timestamp / 1_000_000_000;
+        let (days, time) = (seconds / 86_400, seconds % 86_400);
+        // Civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse
+        let shifted = days + 719_468;
+        let era = shifted / 146_097;
+        let day_of_era = shifted % 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153; // Counted from March
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        Self {
+            year: year_of_era + era * 400 + u64::from(month <= 2),
+            month,
+            day,
+            hour: time / 3_600,
+            minute: time % 3_600 / 60,
+            second: time % 60,
+            nanos: timestamp % 1_000_000_000,
+        }
+    }
+}
+
+/// Fixed-size byte arrays as optional 0x-prefixed hex strings.
+pub mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &Option<[u8; N]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&format!("0x{}", hex::encode(bytes))),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<Option<[u8; N]>, D::Error> {
+        let Some(text) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let bytes = hex::decode(text.trim_start_matches("0x")).map_err(serde::de::Error::custom)?;
+        bytes
+            .try_into()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("expected {} bytes", N)))
+    }
+}
+
+/// Fixed-size byte arrays as 0x-prefixed hex strings.
+pub mod hex_array {
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+        super::hex_bytes::serialize(&Some(*bytes), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+        super::hex_bytes::deserialize(deserializer)?
+            .ok_or_else(|| serde::de::Error::custom("missing bytes"))
     }
 }
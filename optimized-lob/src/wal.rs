@@ -0,0 +1,684 @@
+// wal.rs
+
+use crate::{
+    client_order_ids::ClientOrderId,
+    metrics::Histogram,
+    market::{MarketConfig, PriceBand, RiskLimits, SizeRules},
+    mass_quote::QuoteEntry,
+    order::{OrderId, Signature},
+    oco::{OcoLeg, OcoPolicy},
+    pegs::PeggedOrder,
+    rfq::BlockTrade,
+    stops::StopOrder,
+    utils::{hex_array, hex_bytes, BookId},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Segment files are named `wal-<index>.log`, with the index zero-padded so they sort.
+const SEGMENT_PREFIX: &str = "wal-";
+const SEGMENT_SUFFIX: &str = ".log";
+
+/// A command accepted by the engine, logged before it is applied.
+/// Replaying the commands of a log in order onto an empty engine rebuilds its state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WalCommand {
+    /// A book was registered under a name and given the next dense BookId.
+    /// Logs from before books could be book-only leave that out; a book without a market was one.
+    RegisterBook {
+        name: String,
+        book_id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        market: Option<MarketConfig>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        book_only: Option<bool>,
+    },
+    /// A book was closed: its working orders were cancelled and it was dropped with its market.
+    CloseBook { book_id: u32 },
+    /// The price band of a book's market was replaced; None lifts it.
+    SetPriceBand {
+        book_id: u32,
+        price_band: Option<PriceBand>,
+    },
+    /// The per-trader risk limits of a book's market were replaced.
+    SetRiskLimits {
+        book_id: u32,
+        risk_limits: RiskLimits,
+    },
+    /// The size rules of a book's market were replaced.
+    SetSizeRules {
+        book_id: u32,
+        size_rules: SizeRules,
+    },
+    /// The kill switch was engaged, halting everything but cancels, or released.
+    SetKillSwitch { engaged: bool },
+    /// A book went into an auction, where orders rest without matching.
+    EnterAuction { book_id: u32 },
+    /// A book's auction was uncrossed at its clearing price and the book went back to continuous trading.
+    Uncross { book_id: u32 },
+    /// A book halted for being found crossed was uncrossed at its resting orders' prices and trades again.
+    RepairCrossed { book_id: u32 },
+    /// An incoming order, run through matching. Any unfilled quantity rests, only `display`
+    /// of it showing at once when set. A reduce-only order is held to its trader's position.
+    Submit {
+        order_id: u64,
+        book_id: u32,
+        qty: u64,
+        price: u32,
+        is_bid: bool,
+        #[serde(with = "hex_bytes")]
+        trader: Option<[u8; 20]>,
+        nonce: Option<u64>,
+        expiry: Option<u64>,
+        signature: Signature,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        display: Option<u64>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        reduce_only: bool,
+        #[serde(default)]
+        received_at: u64, // When the order arrived, so a recovered order keeps its arrival time
+    },
+    /// A stop or stop-limit order, waiting for its trigger.
+    SubmitStop(StopOrder),
+    /// Removes a stop that has not triggered.
+    CancelStop { order_id: u64 },
+    /// A pegged order, resting at the price it pegs to or parked.
+    SubmitPegged(PeggedOrder),
+    /// Two orders linked as a one-cancels-other pair.
+    SubmitOco { legs: [OcoLeg; 2], policy: OcoPolicy, cancel_together: bool },
+    /// A trader's quotes replaced book by book: each entry's book's previous quote set was
+    /// cancelled and its bid and ask went in as limit orders.
+    MassQuote {
+        #[serde(with = "hex_array")]
+        trader: [u8; 20],
+        entries: Vec<QuoteEntry>,
+        #[serde(default)]
+        received_at: u64,
+    },
+    /// Cancels every quote set of a trader, optionally only the one in one book.
+    CancelQuotes {
+        #[serde(with = "hex_array")]
+        trader: [u8; 20],
+        book_id: Option<u32>,
+    },
+    /// A quote of an RFQ taken by its requester: the two signed orders traded off the book.
+    BlockTrade(BlockTrade),
+    /// An order placed directly on the book without matching.
+    Add {
+        order_id: u64,
+        book_id: u32,
+        qty: u64,
+        price: u32,
+        is_bid: bool,
+        #[serde(with = "hex_bytes")]
+        trader: Option<[u8; 20]>,
+        nonce: Option<u64>,
+        expiry: Option<u64>,
+        signature: Signature,
+    },
+    /// Reduces a resting order by `qty`.
+    Cancel { order_id: u64, qty: u64 },
+    /// Removes a resting order.
+    Remove { order_id: u64 },
+    /// Executes `qty` of a resting order.
+    Execute { order_id: u64, qty: u64 },
+    /// Cancels a resting order and re-submits it under a new ID, price and quantity.
+    Replace {
+        order_id: u64,
+        new_order_id: u64,
+        new_qty: u64,
+        new_price: u32,
+    },
+    /// Removes every resting order of a trader, optionally in one book.
+    CancelAll {
+        #[serde(with = "hex_array")]
+        trader: [u8; 20],
+        book_id: Option<u32>,
+    },
+    /// Purges a working order, resting, parked, or a waiting stop, whose expiry has passed.
+    Expire { order_id: u64 },
+    /// The next pass of an order cut short by the fill limit, which waited to go on matching.
+    Resume { order_id: u64 },
+    /// Invalidates a trader's nonces below `min_nonce` and cancels their resting orders signed with them.
+    BumpNonce {
+        #[serde(with = "hex_array")]
+        trader: [u8; 20],
+        min_nonce: u64,
+    },
+    /// A trader's client order ID was bound to the order about to be submitted under `order_id`.
+    BindClientOrderId {
+        order_id: u64,
+        #[serde(with = "hex_array")]
+        trader: [u8; 20],
+        client_order_id: ClientOrderId,
+    },
+    /// A settlement was sent to the settlement contract in transaction `tx_hash`.
+    SettlementSubmitted {
+        settlement_id: u64,
+        #[serde(with = "hex_array")]
+        tx_hash: [u8; 32],
+    },
+    /// The transaction of a submitted settlement was confirmed.
+    SettlementConfirmed { settlement_id: u64 },
+    /// A settlement failed; with `recredit_order_id`, the maker's quantity went back on the book under it.
+    SettlementFailed {
+        settlement_id: u64,
+        reason: String,
+        recredit_order_id: Option<u64>,
+    },
+    /// Settlements were sent together as batch `batch_id` in transaction `tx_hash`.
+    SettlementBatchSubmitted {
+        batch_id: u64,
+        settlement_ids: Vec<u64>,
+        #[serde(with = "hex_array")]
+        tx_hash: [u8; 32],
+    },
+    /// The transaction of a submitted batch was confirmed.
+    SettlementBatchConfirmed { batch_id: u64 },
+    /// A batch failed; `recredit_order_ids` is empty or holds one order ID per settlement of the batch.
+    SettlementBatchFailed {
+        batch_id: u64,
+        reason: String,
+        recredit_order_ids: Vec<u64>,
+    },
+}
+
+impl WalCommand {
+    /// Gets the highest order ID the command introduces, if any.
+    pub fn max_order_id(&self) -> Option<OrderId> {
+        match self {
+            WalCommand::Submit { order_id, .. } | WalCommand::Add { order_id, .. } => Some(OrderId(*order_id)),
+            WalCommand::SubmitStop(stop) => Some(OrderId(stop.order_id)),
+            WalCommand::SubmitPegged(peg) => Some(OrderId(peg.order_id)),
+            WalCommand::SubmitOco { legs, .. } => legs.iter().map(|leg| leg.order_id()).max(),
+            WalCommand::BlockTrade(block) => Some(OrderId(block.maker.order_id.max(block.taker.order_id))),
+            WalCommand::MassQuote { entries, .. } => {
+                entries.iter().flat_map(|entry| entry.sides()).map(|(side, _)| OrderId(side.order_id)).max()
+            }
+            WalCommand::Replace { new_order_id, .. } => Some(OrderId(*new_order_id)),
+            WalCommand::SettlementFailed { recredit_order_id, .. } => recredit_order_id.map(OrderId),
+            WalCommand::SettlementBatchFailed { recredit_order_ids, .. } => {
+                recredit_order_ids.iter().max().copied().map(OrderId)
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets the book a RegisterBook command creates.
+    pub fn registered_book(&self) -> Option<(&str, BookId)> {
+        match self {
+            WalCommand::RegisterBook { name, book_id, .. } => Some((name, BookId(*book_id))),
+            _ => None,
+        }
+    }
+
+    /// Gets the book a CloseBook command closes.
+    pub fn closed_book(&self) -> Option<BookId> {
+        match self {
+            WalCommand::CloseBook { book_id } => Some(BookId(*book_id)),
+            _ => None,
+        }
+    }
+}
+
+/// When appended records are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every record.
+    Always,
+    /// fsync once every `n` records. Up to `n - 1` records can be lost on power failure,
+    /// but not on a process crash, since every record is written to the OS immediately.
+    Batch(usize),
+    /// Never fsync explicitly; the OS flushes when it decides to.
+    Never,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WalConfig {
+    pub sync: SyncPolicy,
+    pub segment_bytes: u64, // A new segment is started once the current one reaches this size.
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::Always,
+            segment_bytes: 64 << 20,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(io::Error),
+    Corrupt { segment: u64, offset: u64 },
+}
+
+impl fmt::Display for WalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalError::Io(error) => write!(f, "WAL I/O error: {}", error),
+            WalError::Corrupt { segment, offset } => {
+                write!(f, "Corrupt WAL record in segment {} at offset {}", segment, offset)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for WalError {
+    fn from(error: io::Error) -> Self {
+        WalError::Io(error)
+    }
+}
+
+/// Append-only log of WalCommands, split into numbered segment files.
+/// Each record is a little-endian u32 length followed by the command as JSON.
+pub struct Wal {
+    dir: PathBuf,
+    config: WalConfig,
+    segment: u64,
+    file: File,
+    written: u64,
+    unsynced: usize,
+    sync_latency: Histogram,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, creating the directory if needed, and positions it for appending.
+    /// A record torn by a crash at the end of the last segment is cut off.
+    pub fn open(dir: impl AsRef<Path>, config: WalConfig) -> Result<Self, WalError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let segment = segments(&dir)?.last().copied().unwrap_or(0);
+        let path = segment_path(&dir, segment);
+
+        let valid = if path.exists() {
+            read_segment(&path, segment, &mut Vec::new(), true)?
+        } else {
+            0
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid)?;
+
+        Ok(Self {
+            dir,
+            config,
+            segment,
+            file,
+            written: valid,
+            unsynced: 0,
+            sync_latency: Histogram::default(),
+        })
+    }
+
+    /// Reads every command in `dir`, oldest first.
+    /// Only the last segment may end in a torn record; it is ignored.
+    pub fn read_all(dir: impl AsRef<Path>) -> Result<Vec<WalCommand>, WalError> {
+        Self::read_from(dir, 0)
+    }
+
+    /// Reads the commands of every segment from `first_segment` on, e.g. those not covered by a snapshot.
+    pub fn read_from(dir: impl AsRef<Path>, first_segment: u64) -> Result<Vec<WalCommand>, WalError> {
+        let dir = dir.as_ref();
+        let mut commands = Vec::new();
+        if !dir.exists() {
+            return Ok(commands);
+        }
+        let mut segments = segments(dir)?;
+        segments.retain(|&segment| segment >= first_segment);
+        for (i, &segment) in segments.iter().enumerate() {
+            let is_last = i + 1 == segments.len();
+            read_segment(&segment_path(dir, segment), segment, &mut commands, is_last)?;
+        }
+        Ok(commands)
+    }
+
+    /// Appends a command, syncing according to the configured policy.
+    pub fn append(&mut self, command: &WalCommand) -> Result<(), WalError> {
+        let payload = serde_json::to_vec(command).map_err(io::Error::from)?;
+        let mut record = Vec::with_capacity(4 + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.file.write_all(&record)?;
+        self.written += record.len() as u64;
+        self.unsynced += 1;
+
+        match self.config.sync {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Batch(n) if self.unsynced >= n => self.sync()?,
+            _ => {}
+        }
+        if self.written >= self.config.segment_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// Forces every appended record to stable storage.
+    pub fn sync(&mut self) -> Result<(), WalError> {
+        let started = Instant::now();
+        self.file.sync_data()?;
+        self.sync_latency.observe(started.elapsed());
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Gets how long each sync took
+    pub fn sync_latency(&self) -> &Histogram {
+        &self.sync_latency
+    }
+
+    /// Starts a new segment for a snapshot boundary and returns its index.
+    /// A snapshot of the state before this call makes every older segment redundant,
+    /// so once the snapshot is durable, pass the index to `purge_before`.
+    pub fn checkpoint(&mut self) -> Result<u64, WalError> {
+        self.rotate()?;
+        Ok(self.segment)
+    }
+
+    /// Deletes every segment older than `segment`.
+    pub fn purge_before(&mut self, segment: u64) -> Result<(), WalError> {
+        for old in segments(&self.dir)? {
+            if old < segment.min(self.segment) {
+                fs::remove_file(segment_path(&self.dir, old))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the index of the segment being appended to.
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    fn rotate(&mut self) -> Result<(), WalError> {
+        self.sync()?;
+        self.segment += 1;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.segment))?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", SEGMENT_PREFIX, segment, SEGMENT_SUFFIX))
+}
+
+/// Lists the segment indexes present in `dir`, ascending.
+fn segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments: Vec<u64> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_prefix(SEGMENT_PREFIX)?
+                .strip_suffix(SEGMENT_SUFFIX)?
+                .parse()
+                .ok()
+        })
+        .collect();
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Decodes the records of one segment into `commands` and returns the length of its valid prefix.
+/// A truncated or undecodable record is tolerated only at the end of the last segment.
+fn read_segment(
+    path: &Path,
+    segment: u64,
+    commands: &mut Vec<WalCommand>,
+    allow_torn_tail: bool,
+) -> Result<u64, WalError> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut offset = 0usize;
+    while offset < data.len() {
+        let record = data
+            .get(offset..offset + 4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .and_then(|len| {
+                let payload = data.get(offset + 4..offset + 4 + len)?;
+                Some((len, serde_json::from_slice::<WalCommand>(payload).ok()?))
+            });
+        match record {
+            Some((len, command)) => {
+                offset += 4 + len;
+                commands.push(command);
+            }
+            None if allow_torn_tail => break,
+            None => {
+                return Err(WalError::Corrupt {
+                    segment,
+                    offset: offset as u64,
+                })
+            }
+        }
+    }
+    Ok(offset as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{matching::MatchingEngine, orderbook_manager::OrderBookError, quantity::Qty};
+
+    fn submit(order_id: u64, qty: u64, price: u32, is_bid: bool, trader: u8) -> WalCommand {
+        WalCommand::Submit {
+            order_id,
+            book_id: 0,
+            qty,
+            price,
+            is_bid,
+            trader: Some([trader; 20]),
+            nonce: Some(order_id),
+            expiry: Some(u64::MAX),
+            signature: Signature::Full65([trader; 65]),
+            display: None,
+            reduce_only: false,
+            received_at: order_id,
+        }
+    }
+
+    /// Per-order state of the first `count` order IDs, for comparing engines
+    fn order_state(engine: &MatchingEngine, count: u64) -> Vec<Option<(u64, bool)>> {
+        (0..count)
+            .map(|id| {
+                let order = engine.orderbook_manager.oid_map.get(OrderId(id))?;
+                Some((order.qty().value(), engine.orderbook_manager.is_bid(OrderId(id))?))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_rebuilds_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let market = MarketConfig::builder().chain_id(8453).build();
+        let commands = vec![
+            WalCommand::RegisterBook {
+                name: "ETH-USD".to_string(),
+                book_id: 0,
+                market: Some(market.clone()),
+                book_only: Some(false),
+            },
+            submit(0, 100, 99, true, 1),
+            submit(1, 50, 98, true, 1),
+            submit(2, 80, 101, false, 2),
+            submit(3, 40, 102, false, 2),
+            submit(4, 30, 101, true, 3), // Partially fills order 2
+            WalCommand::Cancel { order_id: 0, qty: 25 },
+            WalCommand::Execute { order_id: 3, qty: 10 },
+            WalCommand::Replace { order_id: 1, new_order_id: 5, new_qty: 60, new_price: 97 },
+            submit(6, 20, 95, true, 4),
+            WalCommand::Expire { order_id: 6 },
+            submit(7, 15, 103, false, 5),
+            WalCommand::CancelAll { trader: [5; 20], book_id: Some(0) },
+            WalCommand::BumpNonce { trader: [2; 20], min_nonce: 2 }, // Order 2 has nonce 2 and keeps resting
+            WalCommand::Add {
+                order_id: 8,
+                book_id: 0,
+                qty: 5,
+                price: 100,
+                is_bid: true,
+                trader: None,
+                nonce: None,
+                expiry: None,
+                signature: Signature::None,
+            },
+            WalCommand::Remove { order_id: 8 },
+            // The fill of order 4 against order 2 fails and order 2's 30 go back on the book as order 9
+            WalCommand::SettlementSubmitted { settlement_id: 1, tx_hash: [0xab; 32] },
+            WalCommand::SettlementFailed {
+                settlement_id: 1,
+                reason: "reverted".to_string(),
+                recredit_order_id: Some(9),
+            },
+            // Trades last went off at 101, so a bid at 150 is refused
+            WalCommand::SetPriceBand { book_id: 0, price_band: Some(PriceBand::Ticks(10)) },
+            submit(10, 5, 150, true, 6),
+        ];
+
+        let (best_bid, best_ask, orders) = {
+            let mut engine = MatchingEngine::auto_creating();
+            engine.wal = Some(Wal::open(dir.path(), WalConfig::default()).unwrap());
+            for command in &commands {
+                engine.log(command).unwrap();
+                engine.apply(command);
+            }
+            (
+                engine.orderbook_manager.get_best_bid(BookId(0)),
+                engine.orderbook_manager.get_best_ask(BookId(0)),
+                order_state(&engine, 11),
+            )
+        };
+        println!("Best bid: {:?}, best ask: {:?}", best_bid, best_ask);
+        println!("Orders: {:?}", orders);
+        assert_eq!(orders[0], Some((75, true)));
+        assert_eq!(orders[2], Some((50, false)));
+        assert_eq!(orders[1], None);
+        assert_eq!(orders[5], Some((60, true)));
+        assert_eq!(orders[9], Some((30, false)));
+        assert_eq!(orders[10], None);
+
+        let replayed_commands = Wal::read_all(dir.path()).unwrap();
+        assert_eq!(replayed_commands, commands);
+
+        let mut replayed = MatchingEngine::auto_creating();
+        for command in &replayed_commands {
+            replayed.apply(command);
+        }
+        assert_eq!(replayed.orderbook_manager.get_best_bid(BookId(0)), best_bid);
+        assert_eq!(replayed.orderbook_manager.get_best_ask(BookId(0)), best_ask);
+        assert_eq!(
+            replayed.orderbook_manager.get_best_bid_size(BookId(0)),
+            Some(Qty(75))
+        );
+        assert_eq!(order_state(&replayed, 11), orders);
+        assert_eq!(replayed.next_order_id(), OrderId(11));
+        assert_eq!(replayed.settlements.get(1).map(|s| s.status.name()), Some("failed"));
+        let banded = MarketConfig { price_band: Some(PriceBand::Ticks(10)), ..market };
+        assert_eq!(replayed.market_manager.get_config(BookId(0)), Some(&banded));
+    }
+
+    #[test]
+    fn test_torn_tail_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut wal = Wal::open(dir.path(), WalConfig::default()).unwrap();
+            wal.append(&WalCommand::Remove { order_id: 1 }).unwrap();
+            wal.append(&WalCommand::Remove { order_id: 2 }).unwrap();
+        }
+
+        // A crash in the middle of a write leaves a partial record behind
+        let path = segment_path(dir.path(), 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 0, 0, 0, b'{']).unwrap();
+        drop(file);
+        assert_eq!(Wal::read_all(dir.path()).unwrap().len(), 2);
+
+        let mut wal = Wal::open(dir.path(), WalConfig::default()).unwrap();
+        wal.append(&WalCommand::Remove { order_id: 3 }).unwrap();
+        assert_eq!(
+            Wal::read_all(dir.path()).unwrap(),
+            vec![
+                WalCommand::Remove { order_id: 1 },
+                WalCommand::Remove { order_id: 2 },
+                WalCommand::Remove { order_id: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rotation_and_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WalConfig {
+            sync: SyncPolicy::Batch(8),
+            segment_bytes: 64,
+        };
+        let mut wal = Wal::open(dir.path(), config).unwrap();
+        for order_id in 0..10 {
+            wal.append(&WalCommand::Remove { order_id }).unwrap();
+        }
+        println!("Segments after 10 records: {:?}", segments(dir.path()).unwrap());
+        assert!(wal.segment() > 1);
+        assert_eq!(Wal::read_all(dir.path()).unwrap().len(), 10);
+
+        // Everything before the checkpoint is covered by a snapshot and can go
+        let checkpoint = wal.checkpoint().unwrap();
+        wal.append(&WalCommand::Remove { order_id: 10 }).unwrap();
+        wal.purge_before(checkpoint).unwrap();
+        assert_eq!(segments(dir.path()).unwrap()[0], checkpoint);
+        assert_eq!(
+            Wal::read_all(dir.path()).unwrap(),
+            vec![WalCommand::Remove { order_id: 10 }]
+        );
+
+        // Reopening continues in the newest segment
+        drop(wal);
+        let wal = Wal::open(dir.path(), config).unwrap();
+        assert_eq!(wal.segment(), checkpoint);
+    }
+
+    #[test]
+    fn test_mid_log_corruption_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WalConfig {
+            sync: SyncPolicy::Never,
+            segment_bytes: 32,
+        };
+        let mut wal = Wal::open(dir.path(), config).unwrap();
+        for order_id in 0..4 {
+            wal.append(&WalCommand::Remove { order_id }).unwrap();
+        }
+        fs::write(segment_path(dir.path(), 0), [1, 0, 0, 0, b'x']).unwrap();
+        assert!(matches!(
+            Wal::read_all(dir.path()),
+            Err(WalError::Corrupt { segment: 0, offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_register_book_from_older_logs() {
+        // Before books could be book-only, every book registered without a market matched
+        let older = r#"{"type":"register_book","name":"BTC-USD","book_id":0}"#;
+        let command: WalCommand = serde_json::from_str(older).unwrap();
+        assert!(matches!(command, WalCommand::RegisterBook { book_only: None, .. }));
+        let mut engine = MatchingEngine::new();
+        engine.apply(&command);
+        assert!(engine.is_book_only(BookId(0)));
+        engine.match_order(OrderId(0), BookId(0), Qty(5), 100, true, None, None, None, None).unwrap();
+
+        // Newer logs say so either way
+        let register = WalCommand::RegisterBook { name: "ETH-USD".to_string(), book_id: 1, market: None, book_only: Some(false) };
+        engine.apply(&register);
+        assert!(!engine.is_book_only(BookId(1)));
+        let refused = engine.match_order(OrderId(1), BookId(1), Qty(5), 100, true, None, None, None, None);
+        assert_eq!(refused.err(), Some(OrderBookError::NoMarket(BookId(1))));
+    }
+}
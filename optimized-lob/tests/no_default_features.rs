@@ -0,0 +1,44 @@
+// no_default_features.rs
+
+//! Guards the library facade against pulling the HTTP server back in: with the
+//! `server` feature off, none of the web stack may appear in the dependency graph.
+
+use std::process::Command;
+
+const SERVER_ONLY_CRATES: [&str; 4] = ["actix-web", "actix-ws", "actix-cors", "reqwest"];
+
+fn dependency_tree(extra: &[&str]) -> String {
+    let output = Command::new(env!("CARGO"))
+        .args(["tree", "-e", "normal", "--prefix", "none", "-p", "optimized-lob"])
+        .args(extra)
+        .arg("--manifest-path")
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .output()
+        .expect("failed to run cargo tree");
+    assert!(
+        output.status.success(),
+        "cargo tree failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("cargo tree output is not UTF-8")
+}
+
+fn depends_on(tree: &str, krate: &str) -> bool {
+    tree.lines()
+        .any(|line| line.split_whitespace().next() == Some(krate))
+}
+
+#[test]
+fn test_server_stack_is_optional() {
+    let tree = dependency_tree(&["--no-default-features"]);
+    for krate in SERVER_ONLY_CRATES {
+        assert!(
+            !depends_on(&tree, krate),
+            "{} is reachable without the server feature",
+            krate
+        );
+    }
+
+    let tree = dependency_tree(&[]);
+    assert!(depends_on(&tree, "actix-web"));
+}
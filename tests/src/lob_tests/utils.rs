@@ -2,6 +2,7 @@
 use optimized_lob::level::LevelId;
 use optimized_lob::orderbook_manager::OrderBookManager;
 use optimized_lob::quantity::Qty;
+use optimized_lob::utils::BookId;
 
 // A few helper functions for the tests
 
@@ -12,10 +13,7 @@ pub(crate) fn get_level_capacity(
     level_id: u32,
 ) -> Qty {
     orderbook_manager
-        .books
-        .get(book_id)
-        .unwrap()
-        .clone()
+        .book(BookId(book_id as u32))
         .unwrap()
         .level_pool
         .get(LevelId(level_id))